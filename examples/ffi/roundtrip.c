@@ -0,0 +1,65 @@
+/* Creates a matrix through the C interface, fills it through the raw data pointer, then reopens
+ * it and checks the values. Build and run with:
+ *
+ *   cargo build --release
+ *   cc -Iinclude examples/ffi/roundtrip.c -Ltarget/release -looc -o roundtrip
+ *   LD_LIBRARY_PATH=target/release ./roundtrip /tmp/roundtrip.mat
+ */
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "ooc.h"
+
+#define CHECK(call)                                                                          \
+    do {                                                                                      \
+        int status = (call);                                                                  \
+        if (status != OOC_OK) {                                                               \
+            fprintf(stderr, "%s failed (%d): %s\n", #call, status, ooc_last_error_message()); \
+            return EXIT_FAILURE;                                                              \
+        }                                                                                     \
+    } while (0)
+
+int main(int argc, char **argv) {
+    const char *path = argc > 1 ? argv[1] : "roundtrip.mat";
+    OocDense *a;
+    void *data;
+    uint64_t rows, cols, lda, i, j;
+    size_t element_size;
+    uint32_t dtype;
+    int transposed;
+
+    CHECK(ooc_dense_create(path, 3, 4, OOC_DTYPE_F64, &a));
+    CHECK(ooc_dense_data_ptr(a, &data, &lda, &element_size, &dtype, &transposed));
+    for (i = 0; i < 3; ++i) {
+        for (j = 0; j < 4; ++j) {
+            ((double *) data)[i * lda + j] = 10.0 * i + j;
+        }
+    }
+    CHECK(ooc_dense_flush(a));
+    CHECK(ooc_dense_close(a));
+
+    CHECK(ooc_dense_open(path, &a));
+    CHECK(ooc_dense_rows(a, &rows));
+    CHECK(ooc_dense_cols(a, &cols));
+    CHECK(ooc_dense_data_ptr(a, &data, &lda, &element_size, &dtype, &transposed));
+    if (rows != 3 || cols != 4 || dtype != OOC_DTYPE_F64 || element_size != sizeof(double) || transposed) {
+        fprintf(stderr, "unexpected shape or layout\n");
+        return EXIT_FAILURE;
+    }
+    for (i = 0; i < rows; ++i) {
+        for (j = 0; j < cols; ++j) {
+            if (((double *) data)[i * lda + j] != 10.0 * i + j) {
+                fprintf(stderr, "element (%lu, %lu) differs\n", (unsigned long) i, (unsigned long) j);
+                return EXIT_FAILURE;
+            }
+        }
+    }
+    CHECK(ooc_dense_close(a));
+
+    if (ooc_dense_open("/nonexistent/matrix.mat", &a) != OOC_ERR_IO) {
+        fprintf(stderr, "opening a missing file should fail with OOC_ERR_IO\n");
+        return EXIT_FAILURE;
+    }
+    printf("ok: %s\n", ooc_last_error_message());
+    return EXIT_SUCCESS;
+}
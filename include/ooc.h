@@ -0,0 +1,62 @@
+/* C interface to the ooc out-of-core matrix library. Link against the library built with
+ * `cargo build --release` (libooc.so). Every function returns one of the OOC_* status codes; after
+ * a failure, ooc_last_error_message() describes it for the calling thread. */
+#ifndef OOC_H
+#define OOC_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define OOC_OK 0
+#define OOC_ERR_IO 1
+#define OOC_ERR_SYSTEM 2
+#define OOC_ERR_SHAPE 3
+#define OOC_ERR_INVALID_ARGUMENT 4
+#define OOC_ERR_SIZE_OVERFLOW 5
+#define OOC_ERR_INVALID_FORMAT 6
+#define OOC_ERR_TYPE_MISMATCH 7
+#define OOC_ERR_NUMERICAL 8
+#define OOC_ERR_PANIC 9
+
+/* Element representations, as recorded in the matrix header. */
+#define OOC_DTYPE_F32 0
+#define OOC_DTYPE_F64 1
+#define OOC_DTYPE_U32 2
+#define OOC_DTYPE_U64 3
+#define OOC_DTYPE_I8 4
+
+typedef struct OocDense OocDense;
+
+/* Valid until the calling thread's next failing call. */
+const char *ooc_last_error_message(void);
+
+/* Creates a zeroed rows x cols matrix at path. */
+int ooc_dense_create(const char *path, uint64_t rows, uint64_t cols, uint32_t dtype, OocDense **out);
+
+/* Opens an existing matrix of any element type. */
+int ooc_dense_open(const char *path, OocDense **out);
+
+int ooc_dense_rows(OocDense *handle, uint64_t *rows);
+int ooc_dense_cols(OocDense *handle, uint64_t *cols);
+
+/* Element (i, j) is at data + (i * lda + j) * element_size, or data + (j * lda + i) * element_size
+ * when transposed is non-zero. Any output pointer may be NULL. data stays valid until the handle
+ * is closed. */
+int ooc_dense_data_ptr(OocDense *handle, void **data, uint64_t *lda, size_t *element_size, uint32_t *dtype,
+                       int *transposed);
+
+/* Writes modified elements back to the file and waits for them to reach the disk. */
+int ooc_dense_flush(OocDense *handle);
+
+/* Unmaps the matrix and frees the handle. Closing NULL does nothing. */
+int ooc_dense_close(OocDense *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,390 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use shared::SharedDense;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::panic;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tile::Tile;
+use tokio::task::{self, JoinHandle};
+
+type TileKey = (u64, u64);
+
+enum FlightState<T> {
+    Waiting(Vec<Waker>),
+    Done(Arc<Tile<T>>),
+    Failed,
+}
+
+// A read of one tile in progress, which every request for the tile waits on until it finishes.
+struct Flight<T> {
+    state: Mutex<FlightState<T>>,
+}
+
+struct TileCache<T> {
+    // Each tile with the tick of its last use, the least recent being evicted first.
+    tiles: HashMap<TileKey, (Arc<Tile<T>>, u64)>,
+    tick: u64,
+    pending: HashMap<TileKey, Arc<Flight<T>>>,
+}
+
+impl<T> TileCache<T> {
+    fn insert(&mut self, capacity: usize, key: TileKey, tile: Arc<Tile<T>>) {
+        if capacity == 0 {
+            return;
+        }
+        if self.tiles.len() == capacity {
+            let oldest = *self.tiles.iter().min_by_key(|entry| (entry.1).1).unwrap().0;
+            self.tiles.remove(&oldest);
+        }
+        self.tick += 1;
+        self.tiles.insert(key, (tile, self.tick));
+    }
+}
+
+struct Inner<T> {
+    matrix: SharedDense<T>,
+    tile_size: usize,
+    capacity: usize,
+    cache: Mutex<TileCache<T>>,
+}
+
+// Finishes a flight when dropped, from the blocking task that read the tile: caching the tile
+// and waking its waiters, or if the task panicked or never ran, failing them and clearing the
+// flight so the next request for the tile reads it afresh.
+struct Completion<T> {
+    inner: Arc<Inner<T>>,
+    key: TileKey,
+    flight: Arc<Flight<T>>,
+    tile: Option<Arc<Tile<T>>>,
+}
+
+impl<T: StorageType> Completion<T> {
+    fn read(&mut self) {
+        let a = &self.inner.matrix;
+        let size = self.inner.tile_size as u64;
+        let (row, col) = (self.key.0 * size, self.key.1 * size);
+        let rows = (a.num_rows() - row).min(size) as usize;
+        let cols = (a.num_cols() - col).min(size) as usize;
+        self.tile = Some(Arc::new(a.read_tile(row, col, rows, cols)));
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if let Ok(mut cache) = self.inner.cache.lock() {
+            if cache.pending.get(&self.key).is_some_and(|pending| Arc::ptr_eq(pending, &self.flight)) {
+                cache.pending.remove(&self.key);
+            }
+            if let Some(ref tile) = self.tile {
+                cache.insert(self.inner.capacity, self.key, tile.clone());
+            }
+        }
+        let finished = match self.tile.take() {
+            Some(tile) => FlightState::Done(tile),
+            None => FlightState::Failed,
+        };
+        let waiting = match self.flight.state.lock() {
+            Ok(mut state) => mem::replace(&mut *state, finished),
+            Err(_) => return,
+        };
+        if let FlightState::Waiting(wakers) = waiting {
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// Reads tiles and rows of a matrix from async code without blocking the executor on page faults:
+// each read runs on tokio's blocking pool. The most recently used `capacity` tiles are kept, and
+// concurrent requests for a tile that isn't cached share a single read. A read that starts runs
+// to completion and is cached even if every request waiting on it is dropped. Clones share the
+// matrix and the cache.
+pub struct AsyncTileReader<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for AsyncTileReader<T> {
+    fn clone(&self) -> AsyncTileReader<T> {
+        AsyncTileReader { inner: self.inner.clone() }
+    }
+}
+
+impl<T: StorageType + Send + Sync + 'static> AsyncTileReader<T> {
+    // Takes ownership of `a`, which is shared read-only between the blocking tasks, as by
+    // Dense::into_shared, and read in square tiles of `tile_size`, those at the bottom and right
+    // edges being cut short.
+    pub fn new(a: Dense<T>, tile_size: usize, capacity: usize) -> Result<AsyncTileReader<T>, OoclaError> {
+        if tile_size == 0 {
+            return Err(OoclaError::InvalidArgument("tile size must be positive".to_string()));
+        }
+        Ok(AsyncTileReader {
+            inner: Arc::new(Inner {
+                matrix: a.into_shared()?,
+                tile_size,
+                capacity,
+                cache: Mutex::new(TileCache { tiles: HashMap::new(), tick: 0, pending: HashMap::new() }),
+            }),
+        })
+    }
+
+    pub fn num_rows(&self) -> u64 {
+        self.inner.matrix.num_rows()
+    }
+
+    pub fn num_cols(&self) -> u64 {
+        self.inner.matrix.num_cols()
+    }
+
+    pub fn tile_size(&self) -> usize {
+        self.inner.tile_size
+    }
+
+    // The number of rows and columns of tiles.
+    pub fn tile_grid(&self) -> (u64, u64) {
+        let size = self.inner.tile_size as u64;
+        (self.num_rows().div_ceil(size), self.num_cols().div_ceil(size))
+    }
+
+    // The tile in row `row_tile` and column `col_tile` of the grid, from the cache if it is there.
+    // Otherwise the read starts now, on the blocking pool of the runtime this is called from,
+    // unless another request already started it.
+    pub fn read_tile(&self, row_tile: u64, col_tile: u64) -> TileRead<T> {
+        let (grid_rows, grid_cols) = self.tile_grid();
+        assert!(row_tile < grid_rows && col_tile < grid_cols, "tile ({}, {}) out of bounds for a {}x{} grid",
+                row_tile, col_tile, grid_rows, grid_cols);
+        let key = (row_tile, col_tile);
+        let mut cache = self.inner.cache.lock().unwrap();
+        cache.tick += 1;
+        let tick = cache.tick;
+        if let Some(entry) = cache.tiles.get_mut(&key) {
+            entry.1 = tick;
+            return TileRead { key, state: TileReadState::Ready(Some(entry.0.clone())) };
+        }
+        if let Some(flight) = cache.pending.get(&key) {
+            return TileRead { key, state: TileReadState::Waiting(flight.clone()) };
+        }
+        let flight = Arc::new(Flight { state: Mutex::new(FlightState::Waiting(Vec::new())) });
+        cache.pending.insert(key, flight.clone());
+        drop(cache);
+        let mut completion = Completion { inner: self.inner.clone(), key, flight: flight.clone(), tile: None };
+        task::spawn_blocking(move || completion.read());
+        TileRead { key, state: TileReadState::Waiting(flight) }
+    }
+
+    // The elements of the logical rows in `rows`, in row-major order. Rows aren't cached.
+    pub fn read_rows(&self, rows: Range<u64>) -> RowsRead<T> {
+        assert!(rows.start <= rows.end && rows.end <= self.num_rows(), "rows {:?} out of bounds for {} rows", rows,
+                self.num_rows());
+        let inner = self.inner.clone();
+        RowsRead {
+            handle: task::spawn_blocking(move || {
+                let a = &inner.matrix;
+                let cols = a.num_cols() as usize;
+                let mut values = vec![T::default(); (rows.end - rows.start) as usize * cols];
+                if cols > 0 {
+                    for (row, dst) in rows.zip(values.chunks_mut(cols)) {
+                        a.read_row(row, dst);
+                    }
+                }
+                values
+            }),
+        }
+    }
+}
+
+enum TileReadState<T> {
+    Ready(Option<Arc<Tile<T>>>),
+    Waiting(Arc<Flight<T>>),
+}
+
+// A pending AsyncTileReader::read_tile. Dropping it leaves the read running for other requests.
+pub struct TileRead<T> {
+    key: TileKey,
+    state: TileReadState<T>,
+}
+
+impl<T> Future for TileRead<T> {
+    type Output = Result<Arc<Tile<T>>, OoclaError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Arc<Tile<T>>, OoclaError>> {
+        let this = self.get_mut();
+        let flight = match this.state {
+            TileReadState::Ready(ref mut tile) => {
+                return Poll::Ready(Ok(tile.take().expect("TileRead polled after completion")));
+            }
+            TileReadState::Waiting(ref flight) => flight,
+        };
+        let mut state = flight.state.lock().unwrap();
+        match *state {
+            FlightState::Waiting(ref mut wakers) => {
+                if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            FlightState::Done(ref tile) => Poll::Ready(Ok(tile.clone())),
+            FlightState::Failed => {
+                Poll::Ready(Err(OoclaError::Io(io::Error::other(format!("read of tile ({}, {}) panicked or was \
+                                                                         cancelled", this.key.0, this.key.1)))))
+            }
+        }
+    }
+}
+
+// A pending AsyncTileReader::read_rows.
+pub struct RowsRead<T> {
+    handle: JoinHandle<Vec<T>>,
+}
+
+impl<T> Future for RowsRead<T> {
+    type Output = Result<Vec<T>, OoclaError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Vec<T>, OoclaError>> {
+        match Pin::new(&mut self.get_mut().handle).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(values)) => Poll::Ready(Ok(values)),
+            Poll::Ready(Err(e)) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+            Poll::Ready(Err(_)) => {
+                Poll::Ready(Err(OoclaError::Io(io::Error::other("read of rows was cancelled by the runtime shutting \
+                                                                 down"))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::runtime::{Builder, Runtime};
+
+    fn runtime() -> Runtime {
+        Builder::new_multi_thread().worker_threads(4).enable_time().build().unwrap()
+    }
+
+    fn value(i: u64, j: u64) -> f64 {
+        (i * 1000 + j) as f64
+    }
+
+    fn reader(rows: u64, cols: u64, tile_size: usize, capacity: usize) -> AsyncTileReader<f64> {
+        AsyncTileReader::new(Dense::anonymous_from_fn(rows, cols, value), tile_size, capacity).unwrap()
+    }
+
+    fn check_tile(tile: &Tile<f64>, tile_size: u64, key: TileKey) {
+        assert_eq!((tile.row, tile.col), (key.0 * tile_size, key.1 * tile_size));
+        for i in 0..tile.rows {
+            for j in 0..tile.cols {
+                assert_eq!(tile.get(i, j), value(tile.row + i as u64, tile.col + j as u64));
+            }
+        }
+    }
+
+    // A task reading `keys` in turn and checking each tile.
+    struct Walk {
+        reader: AsyncTileReader<f64>,
+        keys: Vec<TileKey>,
+        next: usize,
+        current: Option<TileRead<f64>>,
+    }
+
+    impl Future for Walk {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            let this = self.get_mut();
+            loop {
+                if this.current.is_none() {
+                    match this.keys.get(this.next) {
+                        Some(&key) => this.current = Some(this.reader.read_tile(key.0, key.1)),
+                        None => return Poll::Ready(()),
+                    }
+                }
+                match Pin::new(this.current.as_mut().unwrap()).poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(tile) => {
+                        check_tile(&tile.unwrap(), this.reader.tile_size() as u64, this.keys[this.next]);
+                        this.current = None;
+                        this.next += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_requests_from_many_tasks() {
+        let rt = runtime();
+        // Reads start as they are requested, on the blocking pool of the runtime entered.
+        let _entered = rt.enter();
+        // A 7x6 grid of tiles, more than the cache holds, with short tiles at the edges.
+        let reader = reader(100, 90, 15, 5);
+        assert_eq!(reader.tile_grid(), (7, 6));
+        let tasks: Vec<_> = (0..32u64).map(|t| {
+            // Each task walks its own path through the grid, crossing the others'.
+            let keys = (0..40).map(|k| ((t + k) % 7, (t * 3 + k * 5) % 6)).collect();
+            rt.spawn(Walk { reader: reader.clone(), keys, next: 0, current: None })
+        }).collect();
+        for first in (0..100).step_by(15) {
+            let rows = rt.block_on(reader.read_rows(first..first + 2)).unwrap();
+            assert_eq!(rows.len(), 2 * 90);
+            assert_eq!((rows[0], rows[90 + 89]), (value(first, 0), value(first + 1, 89)));
+        }
+        for task in tasks {
+            rt.block_on(task).unwrap();
+        }
+        let cache = reader.inner.cache.lock().unwrap();
+        assert_eq!(cache.tiles.len(), 5);
+        assert!(cache.pending.is_empty());
+    }
+
+    #[test]
+    fn concurrent_requests_for_a_tile_share_one_read() {
+        let rt = runtime();
+        let _entered = rt.enter();
+        // Without a cache, only sharing the read in flight gives every request the same tile.
+        let reader = reader(1000, 1000, 1000, 0);
+        let reads: Vec<TileRead<f64>> = (0..8).map(|_| reader.read_tile(0, 0)).collect();
+        let tiles: Vec<Arc<Tile<f64>>> = reads.into_iter().map(|read| rt.block_on(read).unwrap()).collect();
+        assert!(tiles.iter().all(|tile| Arc::ptr_eq(tile, &tiles[0])));
+        check_tile(&tiles[0], 1000, (0, 0));
+        let again = rt.block_on(reader.read_tile(0, 0)).unwrap();
+        assert!(!Arc::ptr_eq(&again, &tiles[0]));
+    }
+
+    #[test]
+    fn cancelled_reads_leave_the_cache_usable() {
+        let rt = runtime();
+        let _entered = rt.enter();
+        let reader = reader(600, 600, 300, 2);
+        // Dropped at once, and timed out before the blocking read can finish.
+        drop(reader.read_tile(0, 1));
+        let _ = rt.block_on(tokio::time::timeout(Duration::from_nanos(1), reader.read_tile(1, 1)));
+        for &key in &[(0, 1), (1, 1), (1, 0)] {
+            check_tile(&rt.block_on(reader.read_tile(key.0, key.1)).unwrap(), 300, key);
+        }
+        let cache = reader.inner.cache.lock().unwrap();
+        assert!(cache.pending.is_empty());
+        assert_eq!(cache.tiles.len(), 2);
+        assert!(!cache.tiles.contains_key(&(0, 1)));
+    }
+
+    #[test]
+    fn empty_ranges_and_bad_tile_sizes() {
+        let rt = runtime();
+        let _entered = rt.enter();
+        let reader = reader(3, 0, 2, 1);
+        assert_eq!(reader.tile_grid(), (2, 0));
+        assert!(rt.block_on(reader.read_rows(0..3)).unwrap().is_empty());
+        match AsyncTileReader::new(Dense::<f64>::create_anonymous(2, 2).unwrap(), 0, 1) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected an invalid argument, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -1,12 +1,301 @@
 extern crate ooc;
+extern crate rand;
 
-use std::env;
-use std::path::Path;
-use ooc::dense_matrix::Dense;
+use ooc::dense_matrix::{Dense, Distribution, SupportedType};
+use ooc::error::OoclaError;
+use rand::Rand;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{env, fmt, fs, process};
+
+const USAGE: &str = "usage: make-matrix [options] OUTPUT
+
+Creates a matrix at OUTPUT, of random elements unless --fill says otherwise.
+
+options:
+    --rows N          rows of the matrix (default 1000)
+    --cols N          columns of the matrix (default 1000)
+    --dtype TYPE      element type, f32 or f64 (default f32)
+    --fill MODE       what to fill the matrix with (default random):
+                        random      random elements, as the options below say
+                        zeros       zero everywhere
+                        ones        one everywhere
+                        constant=V  V everywhere
+                        identity    ones on the leading diagonal and zeros elsewhere
+                        index       i * cols + j at row i and column j, exact in f32 up to 2^24
+    --force           replace OUTPUT if it exists
+    --quiet           print nothing on success
+    --help            print this message
+
+random fill options:
+    --seed N          draw the elements from seed N, so that they depend only on it, the shape and
+                      the distribution
+    --distribution D  uniform or normal; without one, elements are uniform over [0, 1)
+    --range A,B       bounds of a uniform distribution (default 0,1)
+    --mean M          mean of a normal distribution (default 0)
+    --std S           standard deviation of a normal distribution (default 1)";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Dtype {
+    F32,
+    F64,
+}
+
+impl Dtype {
+    fn name(self) -> &'static str {
+        match self {
+            Dtype::F32 => "f32",
+            Dtype::F64 => "f64",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fill {
+    Random,
+    Zeros,
+    Ones,
+    Constant(f64),
+    Identity,
+    Index,
+}
+
+impl Fill {
+    fn name(self) -> &'static str {
+        match self {
+            Fill::Random => "random",
+            Fill::Zeros => "zeros",
+            Fill::Ones => "ones",
+            Fill::Constant(_) => "constant",
+            Fill::Identity => "identity",
+            Fill::Index => "index",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DistributionKind {
+    Uniform,
+    Normal,
+}
+
+struct Options {
+    output: PathBuf,
+    rows: u64,
+    cols: u64,
+    dtype: Dtype,
+    fill: Fill,
+    seed: Option<u64>,
+    // None for the elements randomise gives, uniform over [0, 1).
+    distribution: Option<Distribution>,
+    force: bool,
+    quiet: bool,
+}
+
+enum Command {
+    Make(Options),
+    Help,
+}
+
+// The value following `flag`, or the value it was joined to with an equals sign.
+fn flag_value(flag: &str, inline: Option<String>, args: &mut dyn Iterator<Item = String>) -> Result<String, String> {
+    inline.or_else(|| args.next()).ok_or_else(|| format!("{} needs a value", flag))
+}
+
+fn parse_number<N: FromStr>(flag: &str, value: &str) -> Result<N, String> {
+    value.parse().map_err(|_| format!("{} takes a non-negative integer, not '{}'", flag, value))
+}
+
+fn parse_real(flag: &str, value: &str) -> Result<f64, String> {
+    value.parse().map_err(|_| format!("{} takes a number, not '{}'", flag, value))
+}
+
+fn parse_fill(value: &str) -> Result<Fill, String> {
+    Ok(match value.split_once('=') {
+        Some(("constant", constant)) => Fill::Constant(parse_real("--fill constant", constant)?),
+        None if value == "random" => Fill::Random,
+        None if value == "zeros" => Fill::Zeros,
+        None if value == "ones" => Fill::Ones,
+        None if value == "identity" => Fill::Identity,
+        None if value == "index" => Fill::Index,
+        _ => {
+            return Err(format!("--fill takes random, zeros, ones, constant=V, identity or index, not '{}'", value));
+        }
+    })
+}
+
+fn parse_range(value: &str) -> Result<(f64, f64), String> {
+    let (low, high) = value.split_once(',').ok_or_else(|| format!("--range takes A,B, not '{}'", value))?;
+    let (low, high) = (parse_real("--range", low)?, parse_real("--range", high)?);
+    if low > high {
+        return Err(format!("--range needs A no greater than B, not {},{}", low, high));
+    }
+    Ok((low, high))
+}
+
+// The distribution random elements are drawn from, given the options that describe one, which
+// imply its kind when --distribution doesn't say.
+fn distribution(kind: Option<DistributionKind>, range: Option<(f64, f64)>, mean: Option<f64>, std_dev: Option<f64>)
+    -> Result<Option<Distribution>, String> {
+    let normal_flags = mean.is_some() || std_dev.is_some();
+    let kind = match (kind, range.is_some(), normal_flags) {
+        (_, true, true) => return Err("--range describes a uniform distribution and can't be given with \
+                                       --mean or --std".to_string()),
+        (Some(DistributionKind::Normal), true, _) => {
+            return Err("--range applies to the uniform distribution, not the normal".to_string());
+        }
+        (Some(DistributionKind::Uniform), _, true) => {
+            return Err("--mean and --std apply to the normal distribution, not the uniform".to_string());
+        }
+        (Some(kind), _, _) => kind,
+        (None, true, _) => DistributionKind::Uniform,
+        (None, _, true) => DistributionKind::Normal,
+        (None, false, false) => return Ok(None),
+    };
+    Ok(Some(match kind {
+        DistributionKind::Uniform => {
+            let (low, high) = range.unwrap_or((0.0, 1.0));
+            Distribution::Uniform { low, high }
+        }
+        DistributionKind::Normal => {
+            let std_dev = std_dev.unwrap_or(1.0);
+            if std_dev < 0.0 {
+                return Err(format!("--std must not be negative, not {}", std_dev));
+            }
+            Distribution::Normal { mean: mean.unwrap_or(0.0), std_dev }
+        }
+    }))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let (mut rows, mut cols, mut dtype, mut fill, mut seed) = (1000, 1000, Dtype::F32, Fill::Random, None);
+    let (mut kind, mut range, mut mean, mut std_dev) = (None, None, None, None);
+    // The last option given that only applies to random fills, for complaining about.
+    let mut random_flag = None;
+    let (mut force, mut quiet) = (false, false);
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        // A path starting with a dash is given after --.
+        if !arg.starts_with('-') || arg == "--" {
+            let path = if arg == "--" {
+                args.next().ok_or_else(|| "-- must be followed by the output path".to_string())?
+            } else {
+                arg
+            };
+            if output.is_some() {
+                return Err(format!("unexpected argument '{}': only one output path is taken", path));
+            }
+            output = Some(PathBuf::from(path));
+            continue;
+        }
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        match flag.as_str() {
+            "--rows" => rows = parse_number(&flag, &flag_value(&flag, inline, &mut args)?)?,
+            "--cols" => cols = parse_number(&flag, &flag_value(&flag, inline, &mut args)?)?,
+            "--fill" => fill = parse_fill(&flag_value(&flag, inline, &mut args)?)?,
+            "--seed" => seed = Some(parse_number(&flag, &flag_value(&flag, inline, &mut args)?)?),
+            "--distribution" => {
+                kind = match flag_value(&flag, inline, &mut args)?.as_str() {
+                    "uniform" => Some(DistributionKind::Uniform),
+                    "normal" => Some(DistributionKind::Normal),
+                    other => return Err(format!("--distribution takes uniform or normal, not '{}'", other)),
+                }
+            }
+            "--range" => range = Some(parse_range(&flag_value(&flag, inline, &mut args)?)?),
+            "--mean" => mean = Some(parse_real(&flag, &flag_value(&flag, inline, &mut args)?)?),
+            "--std" => std_dev = Some(parse_real(&flag, &flag_value(&flag, inline, &mut args)?)?),
+            "--dtype" => {
+                dtype = match flag_value(&flag, inline, &mut args)?.as_str() {
+                    "f32" => Dtype::F32,
+                    "f64" => Dtype::F64,
+                    other => return Err(format!("--dtype takes f32 or f64, not '{}'", other)),
+                }
+            }
+            "--force" | "--quiet" | "--help" if inline.is_some() => {
+                return Err(format!("{} takes no value", flag));
+            }
+            "--force" => force = true,
+            "--quiet" => quiet = true,
+            "--help" => return Ok(Command::Help),
+            _ => return Err(format!("unknown option '{}'", flag)),
+        }
+        if ["--seed", "--distribution", "--range", "--mean", "--std"].contains(&flag.as_str()) {
+            random_flag = Some(flag);
+        }
+    }
+    if let Some(flag) = random_flag.filter(|_| fill != Fill::Random) {
+        return Err(format!("{} only applies to --fill random, not {}", flag, fill.name()));
+    }
+    let distribution = distribution(kind, range, mean, std_dev)?;
+    let output = output.ok_or_else(|| "no output path given".to_string())?;
+    Ok(Command::Make(Options { output, rows, cols, dtype, fill, seed, distribution, force, quiet }))
+}
+
+fn make<T: SupportedType + Rand>(options: &Options) -> Result<(), OoclaError> {
+    let (path, rows, cols) = (&options.output, options.rows, options.cols);
+    let matrix = match options.fill {
+        Fill::Index => Dense::from_fn(path, rows, cols, |i, j| T::from_f64((i * cols + j) as f64))?,
+        fill => {
+            // A new matrix is all zeros already.
+            let mut matrix = Dense::create(path, rows, cols)?;
+            match fill {
+                Fill::Random => match (options.distribution, options.seed) {
+                    (Some(distribution), seed) => {
+                        matrix.randomise_seeded_with(seed.unwrap_or_else(rand::random), distribution)?
+                    }
+                    (None, Some(seed)) => matrix.randomise_seeded(seed),
+                    (None, None) => matrix.randomise()?,
+                },
+                Fill::Zeros | Fill::Index => {}
+                Fill::Ones => matrix.fill(T::from_f64(1.0))?,
+                Fill::Constant(value) => matrix.fill(T::from_f64(value))?,
+                Fill::Identity => matrix.set_identity()?,
+            }
+            matrix
+        }
+    };
+    matrix.flush()
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let describe = |e: &dyn fmt::Display| format!("{}: {}", options.output.display(), e);
+    if !options.force && fs::symlink_metadata(&options.output).is_ok() {
+        return Err(describe(&"already exists; pass --force to replace it"));
+    }
+    let made = match options.dtype {
+        Dtype::F32 => make::<f32>(options),
+        Dtype::F64 => make::<f64>(options),
+    };
+    if let Err(e) = made {
+        let _ = fs::remove_file(&options.output);
+        return Err(describe(&e));
+    }
+    let bytes = fs::metadata(&options.output).map_err(|e| describe(&e))?.len();
+    if !options.quiet {
+        println!("{}: {}x{} {} matrix of {}, {} bytes", options.output.display(), options.rows, options.cols,
+                 options.dtype.name(), options.fill.name(), bytes);
+    }
+    Ok(())
+}
+
+fn fail(message: &str, status: i32) -> ! {
+    eprintln!("make-matrix: {}", message);
+    process::exit(status)
+}
 
 fn main() {
-    let args : Vec<String> = env::args().collect();
-    //let output = &args[1];
-    let mut matrix: Dense<f32> = Dense::create(Path::new("/tmp/test.mat"), 1000, 1000).unwrap();
-    matrix.randomise();
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Command::Make(options)) => options,
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(message) => fail(&format!("{}\n\n{}", message, USAGE), 2),
+    };
+    if let Err(message) = run(&options) {
+        fail(&message, 1);
+    }
 }
@@ -0,0 +1,143 @@
+use error::OoclaError;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+// What MemoryBudget::alloc does with a request that would take the bytes in use over the limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BudgetPolicy {
+    // Wait until enough is released.
+    #[default]
+    Block,
+    // Fail at once with OoclaError::BudgetExceeded.
+    Fail,
+}
+
+#[derive(Debug)]
+struct Usage {
+    in_use: usize,
+    peak: usize,
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    limit: Option<usize>,
+    policy: BudgetPolicy,
+    usage: Mutex<Usage>,
+    released: Condvar,
+}
+
+impl BudgetInner {
+    // Only counters are guarded, which a panic elsewhere can't leave inconsistent.
+    fn usage(&self) -> MutexGuard<'_, Usage> {
+        self.usage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// A limit on the bytes of tile and chunk buffers that blocked operations hold at once, shared
+// between every operation given a clone of it, so that operations run together on several
+// threads can't exhaust memory between them. Each operation reserves the whole of what it is
+// about to hold in a single request and releases it before making the next, so operations
+// waiting on one another can't deadlock. A request larger than the whole limit fails whatever
+// the policy, as it could never be granted. The default budget is unlimited.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    inner: Arc<BudgetInner>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit_bytes: usize, policy: BudgetPolicy) -> MemoryBudget {
+        MemoryBudget::with_limit(Some(limit_bytes), policy)
+    }
+
+    // A budget that grants every request, while still tracking the bytes in use.
+    pub fn unlimited() -> MemoryBudget {
+        MemoryBudget::with_limit(None, BudgetPolicy::Block)
+    }
+
+    fn with_limit(limit: Option<usize>, policy: BudgetPolicy) -> MemoryBudget {
+        MemoryBudget {
+            inner: Arc::new(BudgetInner {
+                limit,
+                policy,
+                usage: Mutex::new(Usage { in_use: 0, peak: 0 }),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    // The limit in bytes, or None if the budget is unlimited.
+    pub fn limit(&self) -> Option<usize> {
+        self.inner.limit
+    }
+
+    pub fn policy(&self) -> BudgetPolicy {
+        self.inner.policy
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.inner.usage().in_use
+    }
+
+    // The most bytes that have been in use at once.
+    pub fn peak(&self) -> usize {
+        self.inner.usage().peak
+    }
+
+    // Reserves `bytes` until the returned guard is dropped, waiting or failing as the policy says
+    // if they aren't available yet.
+    pub fn alloc(&self, bytes: usize) -> Result<BudgetGuard, OoclaError> {
+        let mut usage = self.inner.usage();
+        if let Some(limit) = self.inner.limit {
+            let exceeded = |usage: &Usage| OoclaError::BudgetExceeded {
+                requested_bytes: bytes as u64,
+                in_use_bytes: usage.in_use as u64,
+                limit_bytes: limit as u64,
+            };
+            if bytes > limit {
+                return Err(exceeded(&usage));
+            }
+            while usage.in_use > limit - bytes {
+                if self.inner.policy == BudgetPolicy::Fail {
+                    return Err(exceeded(&usage));
+                }
+                usage = self.inner.released.wait(usage).unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+        usage.in_use += bytes;
+        usage.peak = usage.peak.max(usage.in_use);
+        Ok(BudgetGuard { budget: self.clone(), bytes })
+    }
+
+    // The most of `wanted` bytes an operation can plan to hold at once.
+    pub(crate) fn cap(&self, wanted: usize) -> usize {
+        self.inner.limit.map_or(wanted, |limit| wanted.min(limit))
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> MemoryBudget {
+        MemoryBudget::unlimited()
+    }
+}
+
+// Bytes reserved from a MemoryBudget, released when dropped.
+#[derive(Debug)]
+pub struct BudgetGuard {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl BudgetGuard {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for BudgetGuard {
+    fn drop(&mut self) {
+        if self.bytes == 0 {
+            return;
+        }
+        self.budget.inner.usage().in_use -= self.bytes;
+        self.budget.inner.released.notify_all();
+    }
+}
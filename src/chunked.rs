@@ -0,0 +1,415 @@
+use dense_matrix::{Dense, FloatType, StorageType, as_bytes, as_bytes_mut};
+use error::OoclaError;
+use io::npy::read_exact_or_truncated;
+use ops::hash::xxh64;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+// "OOCCHUNK" when read as little-endian bytes.
+const CHUNKED_MAGIC: u64 = 0x4b4e_5548_4343_4f4f;
+const CHUNKED_VERSION: u32 = 1;
+// Magic, version, element type, rows, cols, chunk length and chunk count, all little-endian.
+const CHUNKED_HEADER_LEN: usize = 8 + 4 + 4 + 8 + 8 + 8 + 8;
+// Each chunk's sequence number and the XXH64 of its payload.
+const CHUNK_HEADER_LEN: usize = 16;
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(word)
+}
+
+// A read-only matrix whose elements, in row-major order, are divided into chunks of a fixed
+// number of bytes, each preceded by its sequence number and hash. Every chunk but the last is
+// full, so each lies at a fixed offset and can be checked or rewritten alone. Two copies can be
+// compared chunk by chunk and only those that differ sent between them.
+pub struct ChunkedMatrix<T> {
+    file: File,
+    path: PathBuf,
+    rows: u64,
+    cols: u64,
+    chunk_bytes: u64,
+    num_chunks: u64,
+    phantom: PhantomData<T>,
+}
+
+// The number of chunks of `chunk_bytes` holding a rows x cols matrix, checked against the
+// chunked file fitting in a u64 and a chunk fitting in memory.
+fn checked_chunks<T>(rows: u64, cols: u64, chunk_bytes: u64) -> Result<u64, OoclaError> {
+    let size = mem::size_of::<T>() as u64;
+    if chunk_bytes == 0 || !chunk_bytes.is_multiple_of(size) || chunk_bytes > isize::MAX as u64 {
+        return Err(OoclaError::InvalidArgument(format!("chunk length {} is not a positive multiple of the {} byte \
+                                                        element size", chunk_bytes, size)));
+    }
+    rows.checked_mul(cols).and_then(|n| n.checked_mul(size))
+        .and_then(|data_len| {
+            let chunks = data_len.div_ceil(chunk_bytes);
+            chunks.checked_mul(CHUNK_HEADER_LEN as u64)
+                .and_then(|headers| headers.checked_add(CHUNKED_HEADER_LEN as u64))
+                .and_then(|n| n.checked_add(data_len))
+                .map(|_| chunks)
+        })
+        .ok_or_else(|| OoclaError::SizeOverflow(format!("a {}x{} matrix in chunks of {} bytes", rows, cols,
+                                                        chunk_bytes)))
+}
+
+// The payload of chunk `index` of A, gathered from the rows it spans.
+fn chunk_payload<T: StorageType>(a: &Dense<T>, chunk_bytes: u64, index: u64) -> Vec<u8> {
+    let size = mem::size_of::<T>() as u64;
+    let elements = a.num_rows() * a.num_cols();
+    let begin = index * chunk_bytes / size;
+    let end = cmp::min(begin + chunk_bytes / size, elements);
+    let mut payload = Vec::with_capacity(((end - begin) * size) as usize);
+    let mut scratch = vec![T::default(); a.num_cols() as usize];
+    let mut element = begin;
+    while element < end {
+        let (row, col) = (element / a.num_cols(), element % a.num_cols());
+        let taken = cmp::min(a.num_cols() - col, end - element);
+        let values = a.row_view(row, &mut scratch);
+        payload.extend_from_slice(as_bytes(&values[col as usize..(col + taken) as usize]));
+        element += taken;
+    }
+    payload
+}
+
+fn write_chunked<T: StorageType>(a: &Dense<T>, dst: &Path, chunk_bytes: u64, num_chunks: u64)
+    -> Result<(), OoclaError> {
+    let mut out = BufWriter::new(File::create(dst)?);
+    out.write_all(&CHUNKED_MAGIC.to_le_bytes())?;
+    out.write_all(&CHUNKED_VERSION.to_le_bytes())?;
+    out.write_all(&(T::get_float_type() as u32).to_le_bytes())?;
+    for value in &[a.num_rows(), a.num_cols(), chunk_bytes, num_chunks] {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    for index in 0..num_chunks {
+        let payload = chunk_payload(a, chunk_bytes, index);
+        out.write_all(&index.to_le_bytes())?;
+        out.write_all(&xxh64(&payload, 0).to_le_bytes())?;
+        out.write_all(&payload)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+impl<T: StorageType> ChunkedMatrix<T> {
+    // Writes A to a new chunked file at `dst` in chunks of `chunk_bytes`, which must be a whole
+    // number of elements.
+    pub fn from_dense(a: &Dense<T>, dst: &Path, chunk_bytes: u64) -> Result<ChunkedMatrix<T>, OoclaError> {
+        let num_chunks = checked_chunks::<T>(a.num_rows(), a.num_cols(), chunk_bytes)?;
+        write_chunked(a, dst, chunk_bytes, num_chunks).inspect_err(|_| {
+            let _ = fs::remove_file(dst);
+        })?;
+        Self::open(dst)
+    }
+
+    // Opens a chunked matrix, reading only its header. Chunks missing from the end of a partly
+    // transferred file are not an error here; verify reports them.
+    pub fn open(path: &Path) -> Result<ChunkedMatrix<T>, OoclaError> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; CHUNKED_HEADER_LEN];
+        read_exact_or_truncated(&mut file, &mut header, "chunked matrix header")?;
+        let magic = read_u64(&header);
+        if magic != CHUNKED_MAGIC {
+            return Err(OoclaError::InvalidFormat(format!("bad chunked matrix magic number {:#x}", magic)));
+        }
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version != CHUNKED_VERSION {
+            return Err(OoclaError::InvalidFormat(format!("unsupported chunked matrix version {}", version)));
+        }
+        let code = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        match FloatType::from_code(code) {
+            None => return Err(OoclaError::InvalidFormat(format!("unknown element representation {}", code))),
+            Some(found) if found != T::get_float_type() => {
+                return Err(OoclaError::TypeMismatch { expected: T::get_float_type(), found });
+            }
+            Some(_) => {}
+        }
+        let (rows, cols) = (read_u64(&header[16..]), read_u64(&header[24..]));
+        let (chunk_bytes, num_chunks) = (read_u64(&header[32..]), read_u64(&header[40..]));
+        let expected = checked_chunks::<T>(rows, cols, chunk_bytes)
+            .map_err(|e| OoclaError::InvalidFormat(format!("chunked matrix header: {}", e)))?;
+        if num_chunks != expected {
+            return Err(OoclaError::InvalidFormat(format!("{} chunks recorded for a {}x{} matrix in chunks of {} \
+                                                          bytes, expected {}", num_chunks, rows, cols,
+                                                         chunk_bytes, expected)));
+        }
+        Ok(ChunkedMatrix {
+            file,
+            path: path.to_path_buf(),
+            rows,
+            cols,
+            chunk_bytes,
+            num_chunks,
+            phantom: PhantomData,
+        })
+    }
+
+    pub fn num_rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn num_cols(&self) -> u64 {
+        self.cols
+    }
+
+    pub fn float_type(&self) -> FloatType {
+        T::get_float_type()
+    }
+
+    // The payload length of every chunk but the last, which may be shorter.
+    pub fn chunk_bytes(&self) -> u64 {
+        self.chunk_bytes
+    }
+
+    pub fn num_chunks(&self) -> u64 {
+        self.num_chunks
+    }
+
+    fn chunk_offset(&self, index: u64) -> u64 {
+        CHUNKED_HEADER_LEN as u64 + index * (CHUNK_HEADER_LEN as u64 + self.chunk_bytes)
+    }
+
+    fn payload_len(&self, index: u64) -> u64 {
+        let data_len = self.rows * self.cols * mem::size_of::<T>() as u64;
+        cmp::min(self.chunk_bytes, data_len - index * self.chunk_bytes)
+    }
+
+    // Reads chunk `index`, returning its payload if the file holds all of it with the right
+    // sequence number and hash, or why not.
+    fn read_chunk(&self, index: u64) -> Result<Result<Vec<u8>, String>, OoclaError> {
+        let file_len = self.file.metadata()?.len();
+        let offset = self.chunk_offset(index);
+        let mut chunk = vec![0u8; CHUNK_HEADER_LEN + self.payload_len(index) as usize];
+        if offset + chunk.len() as u64 > file_len {
+            return Ok(Err("missing from the end of the file".to_string()));
+        }
+        self.file.read_exact_at(&mut chunk, offset)?;
+        let (sequence, hash) = (read_u64(&chunk), read_u64(&chunk[8..]));
+        let payload = chunk.split_off(CHUNK_HEADER_LEN);
+        Ok(if sequence != index {
+            Err(format!("sequence number is {}", sequence))
+        } else if xxh64(&payload, 0) != hash {
+            Err("payload does not match its hash".to_string())
+        } else {
+            Ok(payload)
+        })
+    }
+
+    // The hash of every chunk's payload as stored, or None for a chunk missing from the file.
+    // Comparing these with another copy's finds the chunks to send; a chunk whose payload
+    // doesn't match its own recorded hash still has its payload hashed, so it differs too.
+    pub fn chunk_hashes(&self) -> Result<Vec<Option<u64>>, OoclaError> {
+        let file_len = self.file.metadata()?.len();
+        (0..self.num_chunks).map(|index| {
+            let offset = self.chunk_offset(index) + CHUNK_HEADER_LEN as u64;
+            let mut payload = vec![0u8; self.payload_len(index) as usize];
+            if offset + payload.len() as u64 > file_len {
+                return Ok(None);
+            }
+            self.file.read_exact_at(&mut payload, offset)?;
+            Ok(Some(xxh64(&payload, 0)))
+        }).collect()
+    }
+
+    // The indices of chunks that are missing, out of sequence or don't match their hash.
+    pub fn verify(&self) -> Result<Vec<u64>, OoclaError> {
+        let mut bad = Vec::new();
+        for index in 0..self.num_chunks {
+            if self.read_chunk(index)?.is_err() {
+                bad.push(index);
+            }
+        }
+        Ok(bad)
+    }
+
+    // Rewrites the chunks at `indices` from `src`, which must be the matrix this file holds, or
+    // the sender's copy of it. The rest of the file is left as it is, so a partly transferred or
+    // damaged copy can be repaired with just the chunks verify or chunk_hashes singled out.
+    pub fn patch_chunks(&self, src: &Dense<T>, indices: &[u64]) -> Result<(), OoclaError> {
+        if (src.num_rows(), src.num_cols()) != (self.rows, self.cols) {
+            return Err(OoclaError::ShapeMismatch {
+                expected: (self.rows, self.cols),
+                found: (src.num_rows(), src.num_cols()),
+            });
+        }
+        if let Some(&index) = indices.iter().find(|&&index| index >= self.num_chunks) {
+            return Err(OoclaError::InvalidArgument(format!("chunk {} out of bounds for {} chunks", index,
+                                                           self.num_chunks)));
+        }
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        for &index in indices {
+            let payload = chunk_payload(src, self.chunk_bytes, index);
+            let mut chunk = Vec::with_capacity(CHUNK_HEADER_LEN + payload.len());
+            chunk.extend_from_slice(&index.to_le_bytes());
+            chunk.extend_from_slice(&xxh64(&payload, 0).to_le_bytes());
+            chunk.extend_from_slice(&payload);
+            file.write_all_at(&chunk, self.chunk_offset(index))?;
+        }
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // Copies the matrix into a new dense matrix at `dst`, failing on the first chunk that is
+    // missing or corrupt.
+    pub fn to_dense(&self, dst: &Path) -> Result<Dense<T>, OoclaError> {
+        let mut result = Dense::create(dst, self.rows, self.cols)?;
+        let copied = (0..self.num_chunks).try_for_each(|index| {
+            let payload = self.read_chunk(index)?.map_err(|reason| {
+                OoclaError::InvalidFormat(format!("chunk {} of chunked matrix: {}", index, reason))
+            })?;
+            let size = mem::size_of::<T>() as u64;
+            let mut element = index * self.chunk_bytes / size;
+            let mut bytes = &payload[..];
+            while !bytes.is_empty() {
+                let (row, col) = (element / self.cols, element % self.cols);
+                let taken = cmp::min(self.cols - col, bytes.len() as u64 / size);
+                let dst_bytes = as_bytes_mut(&mut result.major_slice_mut(row)[col as usize..(col + taken) as usize]);
+                dst_bytes.copy_from_slice(&bytes[..dst_bytes.len()]);
+                bytes = &bytes[dst_bytes.len()..];
+                element += taken;
+            }
+            Ok(())
+        });
+        if let Err(e) = copied {
+            drop(result);
+            let _ = fs::remove_file(dst);
+            return Err(e);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    fn elements<T: StorageType>(a: &Dense<T>) -> Vec<Vec<T>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    fn random(rows: u64, cols: u64, seed: u64) -> Dense<f64> {
+        let mut a = Dense::create_anonymous(rows, cols).unwrap();
+        a.randomise_seeded(seed);
+        a
+    }
+
+    fn flip_byte(path: &Path, offset: u64) {
+        let file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let mut byte = [0u8];
+        file.read_exact_at(&mut byte, offset).unwrap();
+        file.write_all_at(&[byte[0] ^ 0x10], offset).unwrap();
+    }
+
+    #[test]
+    fn round_trips_with_chunks_across_rows() {
+        let mut a = random(13, 11, 1);
+        for &transposed in &[false, true] {
+            if transposed {
+                a.transpose();
+            }
+            // A chunk per element, chunks spanning rows unevenly, one chunk for everything.
+            for &chunk_bytes in &[8, 56, 88, 200, 13 * 11 * 8, 1 << 20] {
+                let path = TempMatrixPath::new();
+                let c = ChunkedMatrix::from_dense(&a, path.path(), chunk_bytes).unwrap();
+                assert_eq!((c.num_rows(), c.num_cols()), (a.num_rows(), a.num_cols()));
+                assert_eq!(c.num_chunks(), (13 * 11 * 8u64).div_ceil(chunk_bytes));
+                assert!(c.verify().unwrap().is_empty());
+                let dst = TempMatrixPath::new();
+                assert_eq!(elements(&c.to_dense(dst.path()).unwrap()), elements(&a), "chunks of {}", chunk_bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn a_corrupt_chunk_is_found_and_repaired_alone() {
+        let a = random(20, 9, 2);
+        let (path, pristine) = (TempMatrixPath::new(), TempMatrixPath::new());
+        let c = ChunkedMatrix::from_dense(&a, path.path(), 64).unwrap();
+        let reference = ChunkedMatrix::from_dense(&a, pristine.path(), 64).unwrap();
+        let original = fs::read(path.path()).unwrap();
+        // A payload byte of chunk 7.
+        flip_byte(path.path(), c.chunk_offset(7) + CHUNK_HEADER_LEN as u64 + 5);
+        assert_eq!(c.verify().unwrap(), vec![7]);
+        let (ours, theirs) = (c.chunk_hashes().unwrap(), reference.chunk_hashes().unwrap());
+        let differing: Vec<u64> = (0..c.num_chunks()).filter(|&k| ours[k as usize] != theirs[k as usize]).collect();
+        assert_eq!(differing, vec![7]);
+        let dst = TempMatrixPath::new();
+        match c.to_dense(dst.path()) {
+            Err(OoclaError::InvalidFormat(msg)) => assert!(msg.contains("chunk 7"), "{}", msg),
+            other => panic!("expected a corrupt chunk, got {:?}", other.map(|_| ())),
+        }
+        assert!(!dst.path().exists());
+
+        c.patch_chunks(&a, &differing).unwrap();
+        assert!(c.verify().unwrap().is_empty());
+        assert_eq!(fs::read(path.path()).unwrap(), original);
+        assert_eq!(elements(&c.to_dense(dst.path()).unwrap()), elements(&a));
+    }
+
+    #[test]
+    fn bad_sequence_numbers_and_hashes_are_reported() {
+        let a = random(4, 4, 3);
+        let path = TempMatrixPath::new();
+        let c = ChunkedMatrix::from_dense(&a, path.path(), 32).unwrap();
+        flip_byte(path.path(), c.chunk_offset(1));
+        flip_byte(path.path(), c.chunk_offset(2) + 8);
+        assert_eq!(c.verify().unwrap(), vec![1, 2]);
+        // Neither payload changed.
+        let pristine = TempMatrixPath::new();
+        let reference = ChunkedMatrix::from_dense(&a, pristine.path(), 32).unwrap();
+        assert_eq!(c.chunk_hashes().unwrap(), reference.chunk_hashes().unwrap());
+        c.patch_chunks(&a, &[1, 2]).unwrap();
+        assert!(c.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_truncated_transfer_is_completed_from_the_missing_chunks() {
+        let a = random(10, 10, 4);
+        let path = TempMatrixPath::new();
+        let c = ChunkedMatrix::from_dense(&a, path.path(), 96).unwrap();
+        let full = fs::read(path.path()).unwrap();
+        // Cut into the middle of chunk 5 of 9.
+        let cut = c.chunk_offset(5) + 20;
+        OpenOptions::new().write(true).open(path.path()).unwrap().set_len(cut).unwrap();
+        assert_eq!(c.num_chunks(), 9);
+        assert_eq!(c.verify().unwrap(), vec![5, 6, 7, 8]);
+        let hashes = c.chunk_hashes().unwrap();
+        assert!(hashes[..5].iter().all(Option::is_some) && hashes[5..].iter().all(Option::is_none));
+        c.patch_chunks(&a, &[8, 5, 6, 7]).unwrap();
+        assert_eq!(fs::read(path.path()).unwrap(), full);
+    }
+
+    #[test]
+    fn invalid_arguments_and_headers_are_rejected() {
+        let a = random(3, 3, 5);
+        let path = TempMatrixPath::new();
+        for &chunk_bytes in &[0, 12] {
+            match ChunkedMatrix::from_dense(&a, path.path(), chunk_bytes) {
+                Err(OoclaError::InvalidArgument(_)) => {}
+                other => panic!("expected chunks of {} to be rejected, got {:?}", chunk_bytes, other.map(|_| ())),
+            }
+        }
+        let c = ChunkedMatrix::from_dense(&a, path.path(), 16).unwrap();
+        match c.patch_chunks(&random(3, 4, 5), &[0]) {
+            Err(OoclaError::ShapeMismatch { expected: (3, 3), found: (3, 4) }) => {}
+            other => panic!("expected a shape mismatch, got {:?}", other),
+        }
+        match c.patch_chunks(&a, &[0, 5]) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected an out of bounds chunk, got {:?}", other),
+        }
+        match ChunkedMatrix::<f32>::open(path.path()) {
+            Err(OoclaError::TypeMismatch { expected: FloatType::Single, found: FloatType::Double }) => {}
+            other => panic!("expected a type mismatch, got {:?}", other.map(|_| ())),
+        }
+        // The chunk count, inconsistent with the shape.
+        flip_byte(path.path(), 40);
+        assert!(matches!(ChunkedMatrix::<f64>::open(path.path()), Err(OoclaError::InvalidFormat(_))));
+    }
+}
@@ -0,0 +1,365 @@
+use budget::MemoryBudget;
+use dense_matrix::{Dense, FloatType, StorageType, as_bytes, as_bytes_mut};
+use error::OoclaError;
+use io::npy::read_exact_or_truncated;
+use io_stats;
+use std::cmp;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use tile::Tile;
+use zstd;
+
+// "OOCZTILE" when read as little-endian bytes.
+const COMPRESSED_MAGIC: u64 = 0x454c_4954_5a43_4f4f;
+const COMPRESSED_VERSION: u32 = 1;
+// Magic, version, element type, rows, cols and the tile shape, all little-endian.
+const COMPRESSED_HEADER_LEN: usize = 8 + 4 + 4 + 8 + 8 + 8 + 8;
+// Each tile's byte offset and compressed length.
+const INDEX_ENTRY_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+struct TileExtent {
+    offset: u64,
+    len: u64,
+}
+
+// A read-only matrix stored as a grid of fixed-size tiles, each compressed independently with
+// zstd, so reading one tile reads and decompresses only that tile's bytes. Tiles are ordered
+// by rows of tiles and hold their elements in row-major order; those on the bottom and right
+// edges are cut short by the matrix.
+pub struct CompressedMatrix<T> {
+    file: File,
+    rows: u64,
+    cols: u64,
+    tile_rows: usize,
+    tile_cols: usize,
+    index: Vec<TileExtent>,
+    phantom: PhantomData<T>,
+}
+
+fn tile_count(len: u64, tile_len: usize) -> u64 {
+    len.div_ceil(tile_len as u64)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(word)
+}
+
+// The number of tiles in a rows x cols matrix cut into tiles of `tile_shape`, checked against
+// an elementwise tile buffer fitting in memory.
+fn checked_tiles<T>(rows: u64, cols: u64, tile_shape: (usize, usize)) -> Result<usize, OoclaError> {
+    if tile_shape.0 == 0 || tile_shape.1 == 0 {
+        return Err(OoclaError::InvalidArgument(format!("tile shape {}x{} is empty", tile_shape.0, tile_shape.1)));
+    }
+    tile_shape.0.checked_mul(tile_shape.1)
+        .and_then(|elements| elements.checked_mul(mem::size_of::<T>()))
+        .and_then(|_| tile_count(rows, tile_shape.0).checked_mul(tile_count(cols, tile_shape.1)))
+        .and_then(|tiles| tiles.checked_mul(INDEX_ENTRY_LEN as u64))
+        .filter(|&bytes| bytes <= isize::MAX as u64)
+        .map(|bytes| bytes as usize / INDEX_ENTRY_LEN)
+        .ok_or_else(|| OoclaError::SizeOverflow(format!("{}x{} tiles of a {}x{} matrix are too many or too large",
+                                                        tile_shape.0, tile_shape.1, rows, cols)))
+}
+
+fn write_compressed<T: StorageType>(a: &Dense<T>, dst: &Path, tile_shape: (usize, usize), level: i32,
+                                    num_tiles: usize, budget: &MemoryBudget) -> Result<(), OoclaError> {
+    // A tile and the most its compressed form can take.
+    let tile_bytes = tile_shape.0 * tile_shape.1 * mem::size_of::<T>();
+    let footprint = tile_bytes.saturating_add(zstd::zstd_safe::compress_bound(tile_bytes));
+    let mut out = BufWriter::new(File::create(dst)?);
+    let index_len = num_tiles * INDEX_ENTRY_LEN;
+    out.write_all(&COMPRESSED_MAGIC.to_le_bytes())?;
+    out.write_all(&COMPRESSED_VERSION.to_le_bytes())?;
+    out.write_all(&(T::get_float_type() as u32).to_le_bytes())?;
+    for value in &[a.num_rows(), a.num_cols(), tile_shape.0 as u64, tile_shape.1 as u64] {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    // The index is filled in once the compressed lengths are known.
+    out.write_all(&vec![0u8; index_len])?;
+    let mut index = Vec::with_capacity(index_len);
+    let mut offset = (COMPRESSED_HEADER_LEN + index_len) as u64;
+    for row in (0..a.num_rows()).step_by(tile_shape.0) {
+        let rows = cmp::min(tile_shape.0 as u64, a.num_rows() - row) as usize;
+        for col in (0..a.num_cols()).step_by(tile_shape.1) {
+            let cols = cmp::min(tile_shape.1 as u64, a.num_cols() - col) as usize;
+            let _reserved = budget.alloc(footprint)?;
+            let tile = a.read_tile(row, col, rows, cols);
+            let compressed = zstd::bulk::compress(as_bytes(&tile.data), level)?;
+            out.write_all(&compressed)?;
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&(compressed.len() as u64).to_le_bytes());
+            offset += compressed.len() as u64;
+        }
+    }
+    out.seek(SeekFrom::Start(COMPRESSED_HEADER_LEN as u64))?;
+    out.write_all(&index)?;
+    out.flush()?;
+    Ok(())
+}
+
+impl<T: StorageType> CompressedMatrix<T> {
+    // Compresses A into a new file at `dst` in tiles of `tile_shape` (rows, columns), at zstd
+    // compression `level`. Larger tiles compress better but cost more to read singly.
+    pub fn from_dense(a: &Dense<T>, dst: &Path, tile_shape: (usize, usize), level: i32)
+        -> Result<CompressedMatrix<T>, OoclaError> {
+        CompressedMatrix::from_dense_with(a, dst, tile_shape, level, &MemoryBudget::unlimited())
+    }
+
+    // As from_dense, reserving each tile's buffers from `budget` while compressing it.
+    pub fn from_dense_with(a: &Dense<T>, dst: &Path, tile_shape: (usize, usize), level: i32, budget: &MemoryBudget)
+        -> Result<CompressedMatrix<T>, OoclaError> {
+        let num_tiles = checked_tiles::<T>(a.num_rows(), a.num_cols(), tile_shape)?;
+        write_compressed(a, dst, tile_shape, level, num_tiles, budget).inspect_err(|_| {
+            let _ = fs::remove_file(dst);
+        })?;
+        Self::open(dst)
+    }
+
+    // Opens a compressed matrix, reading its header and tile index but no tiles.
+    pub fn open(path: &Path) -> Result<CompressedMatrix<T>, OoclaError> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut header = [0u8; COMPRESSED_HEADER_LEN];
+        read_exact_or_truncated(&mut file, &mut header, "compressed matrix header")?;
+        let magic = read_u64(&header);
+        if magic != COMPRESSED_MAGIC {
+            return Err(OoclaError::InvalidFormat(format!("bad compressed matrix magic number {:#x}", magic)));
+        }
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version != COMPRESSED_VERSION {
+            return Err(OoclaError::InvalidFormat(format!("unsupported compressed matrix version {}", version)));
+        }
+        let code = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        match FloatType::from_code(code) {
+            None => return Err(OoclaError::InvalidFormat(format!("unknown element representation {}", code))),
+            Some(found) if found != T::get_float_type() => {
+                return Err(OoclaError::TypeMismatch { expected: T::get_float_type(), found });
+            }
+            Some(_) => {}
+        }
+        let (rows, cols) = (read_u64(&header[16..]), read_u64(&header[24..]));
+        let (tile_rows, tile_cols) = (read_u64(&header[32..]), read_u64(&header[40..]));
+        let tile_shape = (usize::try_from(tile_rows).unwrap_or(usize::MAX),
+                          usize::try_from(tile_cols).unwrap_or(usize::MAX));
+        let num_tiles = checked_tiles::<T>(rows, cols, tile_shape)?;
+        let mut raw_index = vec![0u8; num_tiles * INDEX_ENTRY_LEN];
+        read_exact_or_truncated(&mut file, &mut raw_index, "compressed matrix tile index")?;
+        let index: Vec<TileExtent> = raw_index.chunks(INDEX_ENTRY_LEN)
+            .map(|entry| TileExtent { offset: read_u64(entry), len: read_u64(&entry[8..]) })
+            .collect();
+        if let Some(t) = index.iter().position(|e| e.offset.checked_add(e.len).is_none_or(|end| end > file_len)) {
+            return Err(OoclaError::InvalidFormat(format!("tile {} lies beyond the end of the {} byte file", t,
+                                                         file_len)));
+        }
+        Ok(CompressedMatrix {
+            file,
+            rows,
+            cols,
+            tile_rows: tile_shape.0,
+            tile_cols: tile_shape.1,
+            index,
+            phantom: PhantomData,
+        })
+    }
+
+    pub fn num_rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn num_cols(&self) -> u64 {
+        self.cols
+    }
+
+    pub fn float_type(&self) -> FloatType {
+        T::get_float_type()
+    }
+
+    // The (rows, columns) of a full tile.
+    pub fn tile_shape(&self) -> (usize, usize) {
+        (self.tile_rows, self.tile_cols)
+    }
+
+    // The number of rows and columns of tiles.
+    pub fn tile_grid(&self) -> (u64, u64) {
+        (tile_count(self.rows, self.tile_rows), tile_count(self.cols, self.tile_cols))
+    }
+
+    // Reads and decompresses the tile in row `row_tile` and column `col_tile` of the grid.
+    pub fn read_tile(&self, row_tile: u64, col_tile: u64) -> Result<Tile<T>, OoclaError> {
+        let (grid_rows, grid_cols) = self.tile_grid();
+        assert!(row_tile < grid_rows && col_tile < grid_cols, "tile ({}, {}) out of bounds for a {}x{} grid",
+                row_tile, col_tile, grid_rows, grid_cols);
+        let row = row_tile * self.tile_rows as u64;
+        let col = col_tile * self.tile_cols as u64;
+        let rows = cmp::min(self.tile_rows as u64, self.rows - row) as usize;
+        let cols = cmp::min(self.tile_cols as u64, self.cols - col) as usize;
+        let extent = self.index[(row_tile * grid_cols + col_tile) as usize];
+        let mut compressed = vec![0u8; extent.len as usize];
+        self.file.read_exact_at(&mut compressed, extent.offset)?;
+        io_stats::count_read(compressed.len());
+        let mut tile = Tile { row, col, rows, cols, data: vec![T::default(); rows * cols] };
+        let expected = tile.data.len() * mem::size_of::<T>();
+        let corrupt = |reason: String| {
+            OoclaError::InvalidFormat(format!("tile ({}, {}) of compressed matrix: {}", row_tile, col_tile, reason))
+        };
+        let written = zstd::bulk::decompress_to_buffer(&compressed, as_bytes_mut(&mut tile.data))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::Other => corrupt(e.to_string()),
+                _ => e.into(),
+            })?;
+        if written != expected {
+            return Err(corrupt(format!("{} bytes decompressed, expected {}", written, expected)));
+        }
+        Ok(tile)
+    }
+
+    // Every tile in turn, along rows of tiles, for blocked algorithms to consume without
+    // decompressing the whole matrix.
+    pub fn tiles(&self) -> CompressedTiles<'_, T> {
+        CompressedTiles { matrix: self, next: 0 }
+    }
+
+    // Decompresses the whole matrix into a new dense matrix at `dst`.
+    pub fn to_dense(&self, dst: &Path) -> Result<Dense<T>, OoclaError> {
+        let mut result = Dense::create(dst, self.rows, self.cols)?;
+        let copied = self.tiles().try_for_each(|tile| {
+            result.write_tile(&tile?);
+            Ok(())
+        });
+        if let Err(e) = copied {
+            drop(result);
+            let _ = fs::remove_file(dst);
+            return Err(e);
+        }
+        Ok(result)
+    }
+}
+
+pub struct CompressedTiles<'a, T: 'a> {
+    matrix: &'a CompressedMatrix<T>,
+    next: usize,
+}
+
+impl<'a, T: StorageType> Iterator for CompressedTiles<'a, T> {
+    type Item = Result<Tile<T>, OoclaError>;
+
+    fn next(&mut self) -> Option<Result<Tile<T>, OoclaError>> {
+        if self.next == self.matrix.index.len() {
+            return None;
+        }
+        let grid_cols = self.matrix.tile_grid().1;
+        let t = self.next as u64;
+        self.next += 1;
+        Some(self.matrix.read_tile(t / grid_cols, t % grid_cols))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use io_stats;
+
+    fn elements<T: StorageType>(a: &Dense<T>) -> Vec<Vec<T>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    fn random(rows: u64, cols: u64, seed: u64) -> Dense<f64> {
+        let mut a = Dense::create_anonymous(rows, cols).unwrap();
+        a.randomise_seeded(seed);
+        a
+    }
+
+    #[test]
+    fn round_trips_for_tiles_cut_by_the_edges() {
+        let a = random(37, 23, 1);
+        for &shape in &[(1, 1), (8, 5), (16, 16), (37, 23), (64, 64), (1, 23), (37, 1)] {
+            let path = TempMatrixPath::new();
+            let c = CompressedMatrix::from_dense(&a, path.path(), shape, 3).unwrap();
+            assert_eq!((c.num_rows(), c.num_cols(), c.tile_shape()), (37, 23, shape));
+            assert_eq!(c.float_type(), FloatType::Double);
+            let dense = TempMatrixPath::new();
+            assert_eq!(elements(&c.to_dense(dense.path()).unwrap()), elements(&a), "tiles of {:?}", shape);
+            let reopened = CompressedMatrix::<f64>::open(path.path()).unwrap();
+            assert_eq!(reopened.tile_grid(), c.tile_grid());
+        }
+    }
+
+    #[test]
+    fn tiles_come_along_rows_of_tiles() {
+        let a = Dense::<f32>::anonymous_from_fn(10, 7, |i, j| (i * 7 + j) as f32);
+        let path = TempMatrixPath::new();
+        let c = CompressedMatrix::from_dense(&a, path.path(), (4, 3), 1).unwrap();
+        assert_eq!(c.tile_grid(), (3, 3));
+        let tiles: Vec<Tile<f32>> = c.tiles().map(Result::unwrap).collect();
+        let origins: Vec<(u64, u64, usize, usize)> = tiles.iter().map(|t| (t.row, t.col, t.rows, t.cols)).collect();
+        assert_eq!(origins, vec![(0, 0, 4, 3), (0, 3, 4, 3), (0, 6, 4, 1), (4, 0, 4, 3), (4, 3, 4, 3), (4, 6, 4, 1),
+                                 (8, 0, 2, 3), (8, 3, 2, 3), (8, 6, 2, 1)]);
+        for tile in &tiles {
+            assert_eq!(tile.data, a.read_tile(tile.row, tile.col, tile.rows, tile.cols).data);
+        }
+    }
+
+    #[test]
+    fn constant_matrices_compress() {
+        let a = Dense::<f64>::anonymous_from_fn(256, 256, |_, _| 1.5);
+        let path = TempMatrixPath::new();
+        CompressedMatrix::from_dense(&a, path.path(), (64, 64), 3).unwrap();
+        assert!(fs::metadata(path.path()).unwrap().len() < 256 * 256 * 8 / 100);
+    }
+
+    #[test]
+    fn reading_a_tile_reads_only_its_bytes() {
+        let a = random(50, 40, 2);
+        let path = TempMatrixPath::new();
+        let c = CompressedMatrix::from_dense(&a, path.path(), (16, 16), 3).unwrap();
+        let (tile, stats) = io_stats::measure(|| c.read_tile(1, 2).unwrap());
+        let extent = c.index[c.tile_grid().1 as usize + 2];
+        assert_eq!(stats.bytes_read, extent.len);
+        assert_eq!(tile.data, a.read_tile(16, 32, 16, 8).data);
+        // With every other tile's bytes overwritten, the tile still reads back.
+        let data_start = (COMPRESSED_HEADER_LEN + c.index.len() * INDEX_ENTRY_LEN) as u64;
+        let file_len = fs::metadata(path.path()).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(path.path()).unwrap();
+        let junk = vec![0xa5u8; (extent.offset - data_start) as usize];
+        file.write_all_at(&junk, data_start).unwrap();
+        let junk = vec![0x5au8; (file_len - extent.offset - extent.len) as usize];
+        file.write_all_at(&junk, extent.offset + extent.len).unwrap();
+        let reopened = CompressedMatrix::<f64>::open(path.path()).unwrap();
+        assert_eq!(reopened.read_tile(1, 2).unwrap().data, tile.data);
+        assert!(reopened.read_tile(0, 0).is_err());
+    }
+
+    #[test]
+    fn bad_shapes_types_and_files_are_rejected() {
+        let a = random(4, 4, 3);
+        let path = TempMatrixPath::new();
+        match CompressedMatrix::from_dense(&a, path.path(), (0, 4), 3) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected an invalid argument, got {:?}", other.map(|_| ())),
+        }
+        assert!(!path.path().exists());
+        CompressedMatrix::from_dense(&a, path.path(), (2, 2), 3).unwrap();
+        match CompressedMatrix::<f32>::open(path.path()) {
+            Err(OoclaError::TypeMismatch { expected: FloatType::Single, found: FloatType::Double }) => {}
+            other => panic!("expected a type mismatch, got {:?}", other.map(|_| ())),
+        }
+        let bytes = fs::read(path.path()).unwrap();
+        fs::write(path.path(), &bytes[..bytes.len() - 1]).unwrap();
+        match CompressedMatrix::<f64>::open(path.path()) {
+            Err(OoclaError::InvalidFormat(msg)) => assert!(msg.contains("beyond the end"), "{}", msg),
+            other => panic!("expected an invalid format, got {:?}", other.map(|_| ())),
+        }
+        let mut corrupt = bytes.clone();
+        corrupt[0] ^= 1;
+        fs::write(path.path(), &corrupt).unwrap();
+        assert!(CompressedMatrix::<f64>::open(path.path()).is_err());
+    }
+}
@@ -1,177 +1,1922 @@
-use std::path::Path;
-use std::io;
-use std::fs::{File, OpenOptions};
-use nix::sys::mman::{MapFlags, ProtFlags, MAP_SHARED, PROT_READ, PROT_WRITE, mmap, munmap};
-use nix::libc::{c_void, size_t};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use nix::sys::mman::{MapFlags, MmapAdvise, ProtFlags, MADV_DONTNEED, MADV_HUGEPAGE, MADV_NORMAL, MADV_RANDOM, MADV_SEQUENTIAL, MADV_WILLNEED,
+                     MAP_POPULATE, MAP_PRIVATE, MAP_SHARED,
+                     MS_SYNC, PROT_READ, PROT_WRITE, madvise, mmap, msync, munlock, munmap};
+use nix::libc::{self, c_void, size_t};
 use std::os::unix::io::AsRawFd;
-use std::error::Error;
-use std::{mem, ptr, slice};
+use std::borrow::Cow;
+use std::{cmp, fmt, io, mem, ptr, slice};
+use std::ops::Range;
+use std::time::{Duration, Instant};
 use std::marker::PhantomData;
+use rand::distributions::normal::StandardNormal;
 use rand::{self, Rand, Rng};
+use direct;
+use error::OoclaError;
+use ops::simd::RunWriter;
+use progress::{Meter, NoProgress, Progress, ProgressUnit};
+use protect;
+use io_stats;
+use ops::rng::Xoshiro256;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "numa")]
+use numa;
+#[cfg(feature = "io-uring")]
+use uring;
 
-const HEADER_SIZE: usize = 64;
+pub(crate) const HEADER_SIZE: usize = 64;
+// "OOCMATRX" when read as little-endian bytes.
+pub(crate) const MAGIC: u64 = 0x5852_5441_4d43_4f4f;
 
-#[derive(Clone, Copy)]
+// The element representations a matrix file can hold. Besides the floating-point types that
+// operations compute with, unsigned integers are stored for labels and row indices and signed
+// bytes for quantised values.
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(C)]
 pub enum FloatType {
     Single,
     Double,
+    UInt32,
+    UInt64,
+    Int8,
 }
 
-pub trait SupportedType: Copy {
+impl FloatType {
+    pub fn from_code(code: u32) -> Option<FloatType> {
+        match code {
+            0 => Some(FloatType::Single),
+            1 => Some(FloatType::Double),
+            2 => Some(FloatType::UInt32),
+            3 => Some(FloatType::UInt64),
+            4 => Some(FloatType::Int8),
+            _ => None,
+        }
+    }
+}
+
+// A type that can be stored in a matrix file.
+pub trait StorageType: Copy + Default {
     fn get_float_type() -> FloatType;
 }
 
-impl SupportedType for f32 {
-    fn get_float_type() -> FloatType {
-        FloatType::Single
+// A run of elements as the primitive float type it holds, if it is one, which the vectorised
+// kernels in ops work on directly. Other types take the scalar path.
+pub enum Lanes<'a, T: 'a> {
+    F32(&'a [f32]),
+    F64(&'a [f64]),
+    Other(&'a [T]),
+}
+
+pub enum LanesMut<'a, T: 'a> {
+    F32(&'a mut [f32]),
+    F64(&'a mut [f64]),
+    Other(&'a mut [T]),
+}
+
+// A storage type that operations can compute with.
+pub trait SupportedType: StorageType {
+    fn to_f64(self) -> f64;
+    fn from_f64(value: f64) -> Self;
+    // Machine epsilon of the stored representation.
+    fn epsilon() -> f64;
+
+    fn lanes(run: &[Self]) -> Lanes<'_, Self> {
+        Lanes::Other(run)
+    }
+
+    fn lanes_mut(run: &mut [Self]) -> LanesMut<'_, Self> {
+        LanesMut::Other(run)
+    }
+}
+
+impl StorageType for f32 {
+    fn get_float_type() -> FloatType {
+        FloatType::Single
+    }
+}
+
+impl SupportedType for f32 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn from_f64(value: f64) -> f32 {
+        value as f32
+    }
+
+    fn epsilon() -> f64 {
+        f32::EPSILON as f64
+    }
+
+    fn lanes(run: &[f32]) -> Lanes<'_, f32> {
+        Lanes::F32(run)
+    }
+
+    fn lanes_mut(run: &mut [f32]) -> LanesMut<'_, f32> {
+        LanesMut::F32(run)
+    }
+}
+
+impl StorageType for f64 {
+    fn get_float_type() -> FloatType {
+        FloatType::Double
+    }
+}
+
+impl SupportedType for f64 {
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    fn from_f64(value: f64) -> f64 {
+        value
+    }
+
+    fn epsilon() -> f64 {
+        f64::EPSILON
+    }
+
+    fn lanes(run: &[f64]) -> Lanes<'_, f64> {
+        Lanes::F64(run)
+    }
+
+    fn lanes_mut(run: &mut [f64]) -> LanesMut<'_, f64> {
+        LanesMut::F64(run)
+    }
+}
+
+impl StorageType for u32 {
+    fn get_float_type() -> FloatType {
+        FloatType::UInt32
+    }
+}
+
+impl StorageType for u64 {
+    fn get_float_type() -> FloatType {
+        FloatType::UInt64
+    }
+}
+
+impl StorageType for i8 {
+    fn get_float_type() -> FloatType {
+        FloatType::Int8
+    }
+}
+
+pub fn page_size() -> usize {
+    unsafe {
+        libc::sysconf(libc::_SC_PAGESIZE) as usize
+    }
+}
+
+// How a matrix's elements are about to be accessed, passed to the kernel as a readahead hint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessPattern {
+    Normal,
+    Sequential,
+    Random,
+    WillNeed,
+}
+
+impl AccessPattern {
+    fn advice(self) -> MmapAdvise {
+        match self {
+            AccessPattern::Normal => MADV_NORMAL,
+            AccessPattern::Sequential => MADV_SEQUENTIAL,
+            AccessPattern::Random => MADV_RANDOM,
+            AccessPattern::WillNeed => MADV_WILLNEED,
+        }
+    }
+}
+
+// How a pass reads a matrix's storage: by faulting in the pages of the mapping, or with reads
+// submitted to an io_uring ring and kept in flight, bypassing the page cache where the filesystem
+// allows, which on fast NVMe devices gets far more of their throughput. Uring needs the io-uring
+// feature. Either way a pass produces the same result.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Engine {
+    #[default]
+    Mmap,
+    Uring,
+}
+
+// Whether fill, set_identity and randomise write a matrix with non-temporal stores, which go to
+// memory without passing through the cache, so that writing a matrix far larger than the cache
+// doesn't evict everything in it for data that won't be read again soon. Auto uses them for
+// writes of at least NON_TEMPORAL_MIN_BYTES. They need AVX, without which stores are ordinary;
+// either way the matrix holds the same bytes afterwards.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NonTemporalStores {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+// Options for operations that make a single pass over a matrix's storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StreamOptions {
+    // Write back and release each block of the matrix from the page cache once the pass is
+    // beyond it, so a pass over a matrix much larger than memory doesn't evict everything else.
+    pub drop_cache_behind: bool,
+    // How copies, exports, sorts and conversion to CSR read the matrix.
+    pub engine: Engine,
+    // Read the matrix and write what the pass produces with O_DIRECT, in aligned blocks, so that
+    // converting a matrix much larger than memory neither fills the page cache nor evicts what
+    // the rest of the system keeps there. Matrices a pass creates are written through their
+    // mappings, so they are released from the cache behind it instead. Where a filesystem
+    // refuses O_DIRECT the pass falls back to buffered I/O, with a warning on stderr.
+    pub direct_io: bool,
+    // Pin the workers of parallel passes (par_apply, par_clip, par_histogram and
+    // par_randomise_seeded) to the node holding each line they reach, as placed by
+    // Dense::interleave_numa, bind_range_to_node or first touch. Needs the numa feature, without
+    // which, as on a system with a single node, it does nothing.
+    pub pin_numa_workers: bool,
+    // Sum in the order the elements are stored, one at a time, so that sums, dot products and
+    // norms are reproducible bit for bit across machines. Otherwise they are vectorised where the
+    // CPU allows, which reassociates them: results may differ by a few ulps of the sum of the
+    // magnitudes of the terms. Elementwise operations give identical results either way.
+    pub deterministic: bool,
+    pub non_temporal_stores: NonTemporalStores,
+}
+
+// Whether a matrix is mapped with huge pages, which cut TLB misses when scanning large matrices.
+// Transparent huge pages only back file mappings on filesystems and kernels that support large
+// folios (or tmpfs mounted with huge=), and older kernels only use them for anonymous memory, so
+// Advise is a hint that may change nothing. Require maps the file with MAP_HUGETLB, which only
+// works for files on a hugetlbfs mount with enough free pages in its pool, and fails otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HugePagePolicy {
+    #[default]
+    Never,
+    Advise,
+    Require,
+}
+
+// Whether a matrix's mapping is locked into memory as soon as it is made, and kept locked when
+// the matrix grows. Resident faults in and locks every page, as Dense::lock_resident does;
+// OnFault (mlock2 with MLOCK_ONFAULT) locks each page only once it is first touched.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LockPolicy {
+    #[default]
+    Never,
+    Resident,
+    OnFault,
+}
+
+// Options for mapping a matrix's file when it is created or opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MapOptions {
+    // Fault in every page when the file is mapped (MAP_POPULATE), so no access pays for a page
+    // fault later. Opening takes as long as reading the whole file.
+    pub populate: bool,
+    pub huge_pages: HugePagePolicy,
+    pub lock: LockPolicy,
+}
+
+// Where mmap takes the log2 of the huge page size requested with MAP_HUGETLB.
+const MAP_HUGE_SHIFT: i32 = 26;
+
+// What Dense::prefault touched and how long it took, for logging the cost of warming up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Prefault {
+    pub pages: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+// How much of a matrix's mapping is in memory, from Dense::residency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResidencyStats {
+    pub resident_pages: u64,
+    pub total_pages: u64,
+    pub resident_bytes: u64,
+}
+
+// Pages whose residency a single mincore call reports, bounding the vector it fills.
+const MINCORE_CHUNK_PAGES: usize = 1 << 20;
+
+// Bytes of storage a pass gets through between releasing what lies behind it.
+const DISCARD_BLOCK_BYTES: u64 = 64 << 20;
+
+// The smallest write NonTemporalStores::Auto makes with non-temporal stores, well beyond the
+// last-level cache of most machines.
+pub const NON_TEMPORAL_MIN_BYTES: u64 = 64 << 20;
+
+// Elements randomise generates at a time before writing them to the matrix.
+const RANDOM_CHUNK_ELEMENTS: usize = 4096;
+
+// Bytes of whole lines the seeded randomise operations fill from each stream of their seed.
+const RANDOM_BAND_BYTES: usize = 1 << 20;
+
+// What randomise_seeded_with draws elements from: uniformly between `low` and `high`, or
+// normally with mean `mean` and standard deviation `std_dev`. Values are drawn as f64 and then
+// converted to the element type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Distribution {
+    Uniform { low: f64, high: f64 },
+    Normal { mean: f64, std_dev: f64 },
+}
+
+impl Distribution {
+    fn check(self) -> Result<(), OoclaError> {
+        let valid = match self {
+            Distribution::Uniform { low, high } => low.is_finite() && high.is_finite() && low <= high,
+            Distribution::Normal { mean, std_dev } => mean.is_finite() && std_dev.is_finite() && std_dev >= 0.0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(OoclaError::InvalidArgument(format!("invalid distribution {:?}", self)))
+        }
+    }
+
+    fn sample<R: Rng>(self, rng: &mut R) -> f64 {
+        match self {
+            Distribution::Uniform { low, high } => low + (high - low) * rng.gen::<f64>(),
+            Distribution::Normal { mean, std_dev } => {
+                let StandardNormal(x) = rng.gen();
+                mean + std_dev * x
+            }
+        }
+    }
+}
+
+// The default huge page size, from /proc/meminfo, or None if the system has no hugetlb support.
+pub fn huge_page_size() -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("Hugepagesize:"))?;
+    let kib: usize = line["Hugepagesize:".len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib << 10).filter(|size| size.is_power_of_two())
+}
+
+// The soft RLIMIT_MEMLOCK in bytes, or None if it is unlimited or can't be read.
+fn memlock_limit() -> Option<u64> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+    Some(limit.rlim_cur)
+}
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn map_shared(file: &File, len: u64, options: MapOptions) -> Result<*mut c_void, OoclaError> {
+    let mut map_flags = MapFlags::empty();
+    map_flags.insert(MAP_SHARED);
+    if options.populate {
+        map_flags.insert(MAP_POPULATE);
+    }
+    let mut prot_flags = ProtFlags::empty();
+    prot_flags.insert(PROT_READ);
+    prot_flags.insert(PROT_WRITE);
+    let offset = 0;
+    let fd = file.as_raw_fd();
+
+    if options.huge_pages == HugePagePolicy::Require {
+        let unsupported = |reason: String| {
+            OoclaError::Unsupported(format!("huge pages were required but {}; the file must be on a hugetlbfs mount \
+                                             with enough free pages in its pool", reason))
+        };
+        let size = huge_page_size().ok_or_else(|| unsupported("the system has no huge page size".to_string()))?;
+        let flags = map_flags.bits() | libc::MAP_HUGETLB | (size.trailing_zeros() as i32) << MAP_HUGE_SHIFT;
+        let start = unsafe {
+            libc::mmap(ptr::null_mut(), len as size_t, prot_flags.bits(), flags, fd, offset)
+        };
+        if start == libc::MAP_FAILED {
+            return Err(unsupported(format!("mapping with MAP_HUGETLB failed: {}", io::Error::last_os_error())));
+        }
+        return Ok(start);
+    }
+    let start = unsafe {
+        mmap(ptr::null_mut(), len as size_t, prot_flags, map_flags, fd, offset)
+    }?;
+    if options.huge_pages == HugePagePolicy::Advise {
+        // Only a hint: kernels without transparent huge pages, or that don't use them for this
+        // file, reject or ignore it and the mapping is used with normal pages.
+        let _ = unsafe {
+            madvise(start, len as size_t, MADV_HUGEPAGE)
+        };
+    }
+    Ok(start)
+}
+
+// A private, writable mapping of a file opened read-only: pages come from the file until written,
+// when the process gets a copy of its own that never reaches the file.
+fn map_private(file: &File, len: u64) -> Result<*mut c_void, OoclaError> {
+    let mut map_flags = MapFlags::empty();
+    map_flags.insert(MAP_PRIVATE);
+    let mut prot_flags = ProtFlags::empty();
+    prot_flags.insert(PROT_READ);
+    prot_flags.insert(PROT_WRITE);
+    let start = unsafe {
+        mmap(ptr::null_mut(), len as size_t, prot_flags, map_flags, file.as_raw_fd(), 0)
+    }?;
+    Ok(start)
+}
+
+// Allocates blocks for the first `len` bytes of a new file, so that running out of space fails
+// here rather than with a SIGBUS on the first store to a page the filesystem can't back.
+// Filesystems that can't preallocate are left with a sparse file. On failure the file is
+// emptied, since the blocks allocated before space ran out would otherwise stay allocated.
+fn allocate(file: &File, len: u64) -> Result<(), OoclaError> {
+    let ret = unsafe {
+        libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t)
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) => {}
+            _ => {
+                let _ = file.set_len(0);
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Half of physical memory, where a copy-on-write matrix warns of its private pages by default.
+fn default_private_warning_bytes() -> Option<u64> {
+    let pages = unsafe {
+        libc::sysconf(libc::_SC_PHYS_PAGES)
+    };
+    if pages <= 0 { None } else { Some(pages as u64 * page_size() as u64 / 2) }
+}
+
+pub(crate) fn as_bytes<T: StorageType>(values: &[T]) -> &[u8] {
+    unsafe {
+        slice::from_raw_parts(values.as_ptr() as *const u8, mem::size_of_val(values))
+    }
+}
+
+pub(crate) fn as_bytes_mut<T: StorageType>(values: &mut [T]) -> &mut [u8] {
+    unsafe {
+        slice::from_raw_parts_mut(values.as_mut_ptr() as *mut u8, mem::size_of_val(values))
+    }
+}
+
+pub(crate) fn temp_matrix_path() -> PathBuf {
+    let id = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    env::temp_dir().join(format!("ooc-{}-{}.mat", process::id(), id))
+}
+
+// A fresh temp_matrix_path that is removed again when dropped, for tests.
+#[cfg(test)]
+pub(crate) struct TempMatrixPath(PathBuf);
+
+#[cfg(test)]
+impl TempMatrixPath {
+    pub(crate) fn new() -> TempMatrixPath {
+        TempMatrixPath(temp_matrix_path())
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempMatrixPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[repr(C)]
+struct MatrixHeader {
+    magic: u64,
+    num_rows: u64,
+    num_cols: u64,
+    representation: FloatType,
+    lda: u64,
+    transposed: bool,
+}
+
+// The name, byte offset and size of each header field, for code that reads headers it can't
+// yet trust.
+pub(crate) const HEADER_FIELDS: [(&str, usize, usize); 6] = [
+    ("magic", mem::offset_of!(MatrixHeader, magic), 8),
+    ("num_rows", mem::offset_of!(MatrixHeader, num_rows), 8),
+    ("num_cols", mem::offset_of!(MatrixHeader, num_cols), 8),
+    ("representation", mem::offset_of!(MatrixHeader, representation), 4),
+    ("lda", mem::offset_of!(MatrixHeader, lda), 8),
+    ("transposed", mem::offset_of!(MatrixHeader, transposed), 1),
+];
+
+impl MatrixHeader {
+    // Sets the row count of the header at `header` with a release store, after the rows it counts,
+    // so that a reader loading it with load_num_rows, even through another mapping of the file,
+    // never sees a count covering rows not yet written. No reference to the header is made, so
+    // other fields may be read meanwhile. `header` must be valid and 8-byte aligned.
+    unsafe fn store_num_rows(header: *mut MatrixHeader, rows: u64) {
+        AtomicU64::from_ptr(ptr::addr_of_mut!((*header).num_rows)).store(rows, Ordering::Release);
+    }
+
+    // The row count of the header at `header`, with the rows it counts visible to the caller.
+    unsafe fn load_num_rows(header: *const MatrixHeader) -> u64 {
+        AtomicU64::from_ptr(ptr::addr_of!((*header).num_rows) as *mut u64).load(Ordering::Acquire)
+    }
+
+    fn get_data_length_elements(&self) -> u64 {
+        self.lda * if self.transposed {
+            self.num_cols
+        } else {
+            self.num_rows
+        }
+    }
+}
+
+// The layout of a matrix's mapped elements, from Dense::raw_parts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RawMatrixDescriptor {
+    pub data_ptr: *mut u8,
+    pub rows: u64,
+    pub cols: u64,
+    pub row_stride_bytes: usize,
+    pub col_stride_bytes: usize,
+    pub element_size: usize,
+    pub read_only: bool,
+}
+
+pub struct Dense<T> {
+    file: File,
+    start: *mut c_void,
+    length: usize,
+    header: *mut MatrixHeader,
+    data: *mut T,
+    map_options: MapOptions,
+    stream_options: StreamOptions,
+    // False for a copy-on-write mapping, whose writes never reach the file.
+    persistent: bool,
+    private_warning_bytes: Option<u64>,
+    // Atomic rather than a Cell so that nothing reached through a shared reference is changed
+    // without synchronisation, which SharedDense relies on.
+    warned_private: AtomicBool,
+    // Where the mapping is registered for protect to attribute faults to, if it could be.
+    protect_slot: Option<usize>,
+    // Whether the mapping has been made read-only, as it is while shared by a SharedDense.
+    read_only: bool,
+}
+
+// A matrix owns its file and its mapping, which the raw pointers point into and which is unmapped
+// only by its own Drop, so moving it to another thread moves everything it refers to. Neither
+// the mapping nor the protect registry is tied to the thread that made them, and the Cell-like
+// state a matrix keeps is atomic. Sync is left to SharedDense, which gives up mutable access.
+unsafe impl<T: Send> Send for Dense<T> {}
+
+impl<T> Dense<T> {
+    pub fn create(path: &Path, rows: u64, cols: u64) -> Result<Dense<T>, OoclaError> where T: StorageType {
+        Self::create_with_metadata(path, rows, cols, 0)
+    }
+
+    pub fn create_with(path: &Path, rows: u64, cols: u64, options: MapOptions) -> Result<Dense<T>, OoclaError>
+        where T: StorageType {
+        Self::create_mapped(path, rows, cols, 0, options)
+    }
+
+    // Creates a rows x cols matrix at `path` with each element f(row, col), as fill_with sets
+    // them. The file is removed if filling fails.
+    pub fn from_fn<F>(path: &Path, rows: u64, cols: u64, f: F) -> Result<Dense<T>, OoclaError>
+        where T: StorageType, F: FnMut(u64, u64) -> T {
+        let mut result = Self::create(path, rows, cols)?;
+        result.fill_with(f).inspect_err(|_| {
+            let _ = fs::remove_file(path);
+        })?;
+        Ok(result)
+    }
+
+    // The anonymous counterpart of from_fn, so tests needn't manage files.
+    #[cfg(test)]
+    pub(crate) fn anonymous_from_fn<F>(rows: u64, cols: u64, f: F) -> Dense<T>
+        where T: StorageType, F: FnMut(u64, u64) -> T {
+        let mut result = Self::create_anonymous(rows, cols).unwrap();
+        result.fill_with(f).unwrap();
+        result
+    }
+
+    pub fn open(path: &Path) -> Result<Dense<T>, OoclaError> where T: StorageType {
+        Self::open_with(path, MapOptions::default())
+    }
+
+    pub fn open_with(path: &Path, options: MapOptions) -> Result<Dense<T>, OoclaError> where T: StorageType {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len();
+        if len < HEADER_SIZE as u64 {
+            return Err(OoclaError::InvalidFormat(format!("{} bytes is too short for a matrix header", len)));
+        }
+        let result = Self::from_file_with(file, len, options)?;
+        result.validate_header()?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(path = %path.display(), rows = result.num_rows(), cols = result.num_cols(), bytes = len,
+                        "opened matrix");
+        Ok(result)
+    }
+
+    // Checks the raw header fields before anything interprets them as typed values.
+    fn validate_header(&self) -> Result<(), OoclaError> where T: StorageType {
+        let raw = self.start as *const u8;
+        let (magic, code, transposed) = unsafe {
+            (ptr::read_unaligned(raw.add(mem::offset_of!(MatrixHeader, magic)) as *const u64),
+             ptr::read_unaligned(raw.add(mem::offset_of!(MatrixHeader, representation)) as *const u32),
+             *raw.add(mem::offset_of!(MatrixHeader, transposed)))
+        };
+        if magic != MAGIC {
+            return Err(OoclaError::InvalidFormat(format!("bad magic number {:#x}", magic)));
+        }
+        match FloatType::from_code(code) {
+            None => return Err(OoclaError::InvalidFormat(format!("unknown element representation {}", code))),
+            Some(found) if found != T::get_float_type() => {
+                return Err(OoclaError::TypeMismatch { expected: T::get_float_type(), found });
+            }
+            Some(_) => {}
+        }
+        if transposed > 1 {
+            return Err(OoclaError::InvalidFormat(format!("invalid transposed flag {}", transposed)));
+        }
+        let header = self.get_header();
+        let (major, minor) = if header.transposed {
+            (header.num_cols, header.num_rows)
+        } else {
+            (header.num_rows, header.num_cols)
+        };
+        if header.lda < minor {
+            return Err(OoclaError::InvalidFormat(format!("leading dimension {} is less than {}", header.lda, minor)));
+        }
+        let required = header.lda.checked_mul(major)
+            .and_then(|elements| elements.checked_mul(mem::size_of::<T>() as u64))
+            .and_then(|bytes| bytes.checked_add(HEADER_SIZE as u64));
+        match required {
+            Some(required) if required <= self.length as u64 => Ok(()),
+            _ => Err(OoclaError::InvalidFormat(format!("{} byte file is too short for a {}x{} matrix",
+                                                       self.length, header.num_rows, header.num_cols))),
+        }
+    }
+
+    pub(crate) fn from_file(file: File, len: u64) -> Result<Dense<T>, OoclaError> {
+        Self::from_file_with(file, len, MapOptions::default())
+    }
+
+    fn from_file_with(file: File, len: u64, options: MapOptions) -> Result<Dense<T>, OoclaError> {
+        let start = map_shared(&file, len, options)?;
+        let result = Self::from_mapping(file, start, len as usize, options);
+        result.apply_lock_policy()?;
+        Ok(result)
+    }
+
+    // Mappings start on a page boundary and elements follow the 64-byte header, so the data is
+    // aligned for every element type, which raw_parts callers rely on.
+    fn from_mapping(file: File, start: *mut c_void, length: usize, map_options: MapOptions) -> Dense<T> {
+        let header = start as *mut MatrixHeader;
+        let data = unsafe {
+            (start as *mut u8).add(HEADER_SIZE)
+        };
+        assert!((data as usize).is_multiple_of(mem::align_of::<T>()), "matrix data at {:p} is misaligned", data);
+        let protect_slot = protect::register(start, length, file.as_raw_fd());
+        Dense {
+            file,
+            start,
+            length,
+            header,
+            data: data as *mut T,
+            map_options,
+            stream_options: StreamOptions::default(),
+            persistent: true,
+            private_warning_bytes: None,
+            warned_private: AtomicBool::new(false),
+            protect_slot,
+            read_only: false,
+        }
+    }
+
+    // Opens a matrix to change without changing its file, for exploring what an operation would
+    // do without copying the file first. The file is opened read-only and mapped privately:
+    // elements are read from it until written, and each page written becomes a private copy in
+    // memory, so nothing ever reaches the disk. Those copies can grow to the size of the matrix,
+    // and the first check to find them above the warning threshold (half of physical memory by
+    // default) warns on stderr. Flushing writes nothing, and the matrix can't grow.
+    pub fn open_cow(path: &Path) -> Result<Dense<T>, OoclaError> where T: StorageType {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len < HEADER_SIZE as u64 {
+            return Err(OoclaError::InvalidFormat(format!("{} bytes is too short for a matrix header", len)));
+        }
+        let start = map_private(&file, len)?;
+        let mut result = Self::from_mapping(file, start, len as usize, MapOptions::default());
+        result.persistent = false;
+        result.private_warning_bytes = default_private_warning_bytes();
+        result.validate_header()?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(path = %path.display(), rows = result.num_rows(), cols = result.num_cols(), bytes = len,
+                        "opened matrix copy-on-write");
+        Ok(result)
+    }
+
+    // Whether writes to the matrix reach its file, which is false for one opened with open_cow.
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    // Sets the bytes of private copies above which a copy-on-write matrix warns, or None to
+    // never warn. Persistent matrices have no private copies and never warn.
+    pub fn set_private_warning_bytes(&mut self, bytes: Option<u64>) {
+        self.private_warning_bytes = bytes;
+        *self.warned_private.get_mut() = false;
+    }
+
+    // The bytes of private copies of written pages that a copy-on-write matrix holds, from
+    // /proc/self/smaps, which is always zero for a persistent matrix. Warns on stderr, once for
+    // each threshold set, if they are above the warning threshold.
+    pub fn private_bytes(&self) -> Result<u64, OoclaError> {
+        if self.persistent {
+            return Ok(0);
+        }
+        let begin = self.start as u64;
+        let end = begin + self.mapped_length().div_ceil(page_size()) as u64 * page_size() as u64;
+        let smaps = fs::read_to_string("/proc/self/smaps")?;
+        let (mut bytes, mut within) = (0, false);
+        for line in smaps.lines() {
+            let mut fields = line.split_whitespace();
+            let first = fields.next().unwrap_or("");
+            if let Some((low, high)) = first.split_once('-') {
+                if let (Ok(low), Ok(high)) = (u64::from_str_radix(low, 16), u64::from_str_radix(high, 16)) {
+                    within = low >= begin && high <= end;
+                    continue;
+                }
+            }
+            if within && first == "Anonymous:" {
+                bytes += fields.next().and_then(|kib| kib.parse::<u64>().ok()).unwrap_or(0) << 10;
+            }
+        }
+        if let Some(limit) = self.private_warning_bytes {
+            if bytes > limit && !self.warned_private.swap(true, Ordering::Relaxed) {
+                eprintln!("warning: a copy-on-write matrix holds {} bytes of private pages, above the warning \
+                           threshold of {}; its changes are in memory only", bytes, limit);
+            }
+        }
+        Ok(bytes)
+    }
+
+    // Describes the mapped elements for bindings that hand them to other code without copying,
+    // such as the buffer protocol. Element (i, j) is at data_ptr + i * row_stride_bytes +
+    // j * col_stride_bytes, and data_ptr is aligned to at least 64 bytes. The mapping doesn't move
+    // when the matrix does, but the pointer must not be used after the matrix is dropped, nor to
+    // write while any Rust reference to the elements exists.
+    pub fn raw_parts(&self) -> RawMatrixDescriptor {
+        let element_size = mem::size_of::<T>();
+        let line = self.lda() as usize * element_size;
+        let (row_stride_bytes, col_stride_bytes) = if self.is_transposed() {
+            (element_size, line)
+        } else {
+            (line, element_size)
+        };
+        RawMatrixDescriptor {
+            data_ptr: self.data as *mut u8,
+            rows: self.num_rows(),
+            cols: self.num_cols(),
+            row_stride_bytes,
+            col_stride_bytes,
+            element_size,
+            read_only: self.read_only,
+        }
+    }
+
+    // Makes the whole mapping readable only, or readable and writable again, so that a write
+    // through a stray pointer to a shared matrix faults rather than racing its readers.
+    pub(crate) fn set_read_only(&mut self, read_only: bool) -> Result<(), OoclaError> {
+        let protection = if read_only {
+            libc::PROT_READ
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE
+        };
+        let ret = unsafe {
+            libc::mprotect(self.start, self.unmap_length(), protection)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        self.read_only = read_only;
+        Ok(())
+    }
+
+    // Gives up the file and mapping without unmapping it, so a binding can own them.
+    pub fn into_raw_parts(self) -> (File, *mut c_void, usize) {
+        if let Some(slot) = self.protect_slot {
+            protect::deregister(slot);
+        }
+        let parts = (unsafe { ptr::read(&self.file) }, self.start, self.length);
+        mem::forget(self);
+        parts
+    }
+
+    // Takes ownership of a mapping, as returned by into_raw_parts, after checking its header.
+    // `start` must be the page-aligned start of a shared, readable and writable mapping of the
+    // first `length` bytes of `file`, which nothing else will unmap: the matrix unmaps it when
+    // dropped. On error the mapping is unmapped and the file closed.
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn from_raw_parts(file: File, start: *mut c_void, length: usize) -> Result<Dense<T>, OoclaError>
+        where T: StorageType {
+        if length < HEADER_SIZE {
+            munmap(start, length)?;
+            return Err(OoclaError::InvalidFormat(format!("{} bytes is too short for a matrix header", length)));
+        }
+        let result = Self::from_mapping(file, start, length, MapOptions::default());
+        result.validate_header()?;
+        Ok(result)
+    }
+
+    pub(crate) fn init_header(&mut self, rows: u64, cols: u64) where T: StorageType {
+        let header = self.get_header_mut();
+        header.magic = MAGIC;
+        header.num_rows = rows;
+        header.num_cols = cols;
+        header.representation = T::get_float_type();
+        header.transposed = false;
+        header.lda = cols;
+    }
+
+    // Creates a matrix whose file carries `metadata_len` zeroed bytes after the element data, for
+    // formats that need to describe their contents further.
+    pub(crate) fn create_with_metadata(path: &Path, rows: u64, cols: u64, metadata_len: u64)
+        -> Result<Dense<T>, OoclaError> where T: StorageType {
+        Self::create_mapped(path, rows, cols, metadata_len, MapOptions::default())
+    }
+
+    fn create_mapped(path: &Path, rows: u64, cols: u64, metadata_len: u64, options: MapOptions)
+        -> Result<Dense<T>, OoclaError> where T: StorageType {
+        let len = Self::compute_length(rows, cols)?.checked_add(metadata_len)
+            .filter(|&bytes| bytes <= isize::MAX as u64)
+            .ok_or_else(|| OoclaError::SizeOverflow(format!("{} bytes of metadata", metadata_len)))?;
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(len)?;
+        allocate(&file, len)?;
+        let mut result = Self::from_file_with(file, len, options)?;
+        result.init_header(rows, cols);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(path = %path.display(), rows, cols, bytes = len, "created matrix");
+        Ok(result)
+    }
+
+    // Whatever follows the element data in the file, which is empty for a plain matrix.
+    pub(crate) fn metadata(&self) -> &[u8] {
+        let start = self.metadata_offset();
+        unsafe {
+            slice::from_raw_parts((self.start as *const u8).add(start), self.length - start)
+        }
+    }
+
+    pub(crate) fn metadata_mut(&mut self) -> &mut [u8] {
+        let start = self.metadata_offset();
+        unsafe {
+            slice::from_raw_parts_mut((self.start as *mut u8).add(start), self.length - start)
+        }
+    }
+
+    // The header as stored, padding included.
+    pub(crate) fn header_bytes(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.start as *const u8, HEADER_SIZE)
+        }
+    }
+
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+
+    fn metadata_offset(&self) -> usize {
+        HEADER_SIZE + self.get_header().get_data_length_elements() as usize * mem::size_of::<T>()
+    }
+
+    // The backing file is unlinked straight away, so the storage lives only as long as the mapping.
+    pub fn create_anonymous(rows: u64, cols: u64) -> Result<Dense<T>, OoclaError> where T: StorageType {
+        let path = temp_matrix_path();
+        let result = Self::create(&path, rows, cols);
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    pub fn create_at(path: Option<&Path>, rows: u64, cols: u64) -> Result<Dense<T>, OoclaError> where T: StorageType {
+        match path {
+            Some(path) => Self::create(path, rows, cols),
+            None => Self::create_anonymous(rows, cols),
+        }
+    }
+
+    // Copies this matrix, keeping its storage layout, to `path` or an anonymous file.
+    pub fn copy_at(&self, path: Option<&Path>) -> Result<Dense<T>, OoclaError> where T: StorageType {
+        self.copy_at_progress(path, &NoProgress)
+    }
+
+    // As copy_at, reporting the bytes of elements copied to `progress`. On cancellation or any
+    // other error the file at `path` is removed.
+    pub fn copy_at_progress(&self, path: Option<&Path>, progress: &dyn Progress) -> Result<Dense<T>, OoclaError>
+        where T: StorageType {
+        let (major, minor) = (self.major_len(), self.minor_len());
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("copy_at", path = ?path, rows = self.num_rows(), cols = self.num_cols(),
+                                         bytes = major * minor * mem::size_of::<T>() as u64).entered();
+        let mut copy = if self.is_transposed() {
+            let mut copy = Self::create_at(path, major, minor)?;
+            copy.transpose();
+            copy
+        } else {
+            Self::create_at(path, major, minor)?
+        };
+        copy.set_stream_options(self.stream_options);
+        let (mut src_pass, mut dst_pass) = (DiscardBehind::new(self), DiscardBehind::new(&copy));
+        let line_bytes = minor * mem::size_of::<T>() as u64;
+        let mut meter = Meter::new(progress, Some(major * line_bytes), ProgressUnit::Bytes);
+        self.stream_major(0..major, |line, values| {
+            copy.major_slice_mut(line)[..minor as usize].copy_from_slice(values);
+            io_stats::count_written(line_bytes as usize);
+            meter.advance(line_bytes)?;
+            src_pass.advance(self, line + 1)?;
+            dst_pass.advance(&copy, line + 1)
+        }).inspect_err(|_| {
+            if let Some(path) = path {
+                let _ = fs::remove_file(path);
+            }
+        })?;
+        meter.finish();
+        Ok(copy)
+    }
+
+    // Appends the rows of `other`, which may have either storage layout, growing the file in
+    // place. See merge_all.
+    pub fn append_matrix(&mut self, other: &Dense<T>) -> Result<(), OoclaError> where T: StorageType {
+        self.merge_all(&[other])
+    }
+
+    // Appends the rows of each source in turn without rewriting the existing rows. The matrix
+    // must be stored by rows and carry no trailing metadata. Disk space for all the new rows is
+    // reserved before any are copied, so running out of space fails up front and leaves the
+    // matrix unchanged; after that the row count in the header is advanced a row at a time, so
+    // it only ever covers rows that have been copied in full.
+    pub fn merge_all(&mut self, sources: &[&Dense<T>]) -> Result<(), OoclaError> where T: StorageType {
+        if self.is_transposed() {
+            return Err(OoclaError::InvalidArgument("cannot append rows to a matrix stored by columns".to_string()));
+        }
+        if !self.persistent {
+            return Err(OoclaError::InvalidArgument("cannot append rows to a copy-on-write matrix".to_string()));
+        }
+        if !self.metadata().is_empty() {
+            return Err(OoclaError::InvalidArgument("cannot append rows to a matrix with trailing metadata".to_string()));
+        }
+        let (rows, cols) = (self.num_rows(), self.num_cols());
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("merge_all", rows, cols, sources = sources.len()).entered();
+        let mut total = rows;
+        for source in sources {
+            if source.num_cols() != cols {
+                return Err(OoclaError::ShapeMismatch {
+                    expected: (source.num_rows(), cols),
+                    found: (source.num_rows(), source.num_cols()),
+                });
+            }
+            total = total.checked_add(source.num_rows())
+                .ok_or_else(|| OoclaError::SizeOverflow(format!("appending {} rows", source.num_rows())))?;
+        }
+        let lda = self.lda();
+        let len = Self::compute_length(total, lda)?;
+        if len > self.length as u64 {
+            self.reserve(len)?;
+            self.remap(len)?;
+        }
+        let mut scratch = vec![T::default(); cols as usize];
+        let mut next = rows;
+        protect::guarded(|| {
+            for source in sources {
+                for i in 0..source.num_rows() {
+                    let row = source.row_view(i, &mut scratch);
+                    unsafe {
+                        ptr::copy_nonoverlapping(row.as_ptr(), self.get_data_mut().add((next * lda) as usize),
+                                                 row.len());
+                    }
+                    io_stats::count_written(mem::size_of_val(row));
+                    next += 1;
+                    self.publish_num_rows(next);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Allocates blocks for the file up to `len` bytes, so that stores into the mapping cannot
+    // fail for lack of space.
+    fn reserve(&self, len: u64) -> Result<(), OoclaError> {
+        let old_len = self.length as u64;
+        let err = unsafe {
+            libc::posix_fallocate(self.file.as_raw_fd(), old_len as libc::off_t, (len - old_len) as libc::off_t)
+        };
+        if err != 0 {
+            let _ = self.file.set_len(old_len);
+            return Err(io::Error::from_raw_os_error(err).into());
+        }
+        Ok(())
+    }
+
+    // Replaces the mapping with one of `len` bytes of the same file.
+    fn remap(&mut self, len: u64) -> Result<(), OoclaError> {
+        let start = map_shared(&self.file, len, self.map_options)?;
+        if let Some(slot) = self.protect_slot.take() {
+            protect::deregister(slot);
+        }
+        unsafe {
+            munmap(self.start, self.unmap_length())
+        }?;
+        self.protect_slot = protect::register(start, len as usize, self.file.as_raw_fd());
+        self.start = start;
+        self.length = len as usize;
+        self.header = start as *mut MatrixHeader;
+        self.data = unsafe {
+            (start as *mut u8).add(HEADER_SIZE)
+        } as *mut T;
+        self.apply_lock_policy()
+    }
+
+    pub fn num_rows(&self) -> u64 {
+        unsafe {
+            MatrixHeader::load_num_rows(self.header)
+        }
+    }
+
+    pub fn num_cols(&self) -> u64 {
+        self.get_header().num_cols
+    }
+
+    pub fn is_transposed(&self) -> bool {
+        self.get_header().transposed
+    }
+
+    fn element_offset(&self, row: u64, col: u64) -> usize {
+        let header = self.get_header();
+        assert!(row < header.num_rows && col < header.num_cols,
+                "index ({}, {}) out of bounds for {}x{} matrix", row, col, header.num_rows, header.num_cols);
+        if header.transposed {
+            (col * header.lda + row) as usize
+        } else {
+            (row * header.lda + col) as usize
+        }
+    }
+
+    pub fn get(&self, row: u64, col: u64) -> T where T: Copy {
+        let offset = self.element_offset(row, col);
+        unsafe {
+            *self.get_data().add(offset)
+        }
+    }
+
+    pub fn set(&mut self, row: u64, col: u64, value: T) {
+        let offset = self.element_offset(row, col);
+        unsafe {
+            *self.get_data_mut().add(offset) = value;
+        }
+    }
+
+    // Distance in elements between logically adjacent elements of a row.
+    fn row_step(&self) -> usize {
+        let header = self.get_header();
+        if header.transposed {
+            header.lda as usize
+        } else {
+            1
+        }
+    }
+
+    pub fn read_row(&self, row: u64, dst: &mut [T]) where T: Copy {
+        assert_eq!(dst.len() as u64, self.num_cols(), "row buffer length does not match column count");
+        if dst.is_empty() {
+            return;
+        }
+        io_stats::count_read(mem::size_of_val(dst));
+        if !self.is_transposed() {
+            dst.copy_from_slice(self.major_slice(row));
+            return;
+        }
+        let start = self.element_offset(row, 0);
+        let step = self.row_step();
+        let data = self.get_data();
+        for (col, value) in dst.iter_mut().enumerate() {
+            *value = unsafe { *data.add(start + col * step) };
+        }
+    }
+
+    // Row `row` as a contiguous slice: borrowed from the mapping when rows are stored
+    // contiguously, otherwise gathered into `scratch`, which must hold num_cols elements.
+    pub(crate) fn row_view<'a>(&'a self, row: u64, scratch: &'a mut [T]) -> &'a [T] where T: Copy {
+        if self.is_transposed() || self.num_cols() == 0 {
+            self.read_row(row, scratch);
+            scratch
+        } else {
+            let line = self.major_slice(row);
+            io_stats::count_read(mem::size_of_val(line));
+            line
+        }
+    }
+
+    pub fn write_row(&mut self, row: u64, src: &[T]) where T: Copy {
+        assert_eq!(src.len() as u64, self.num_cols(), "row buffer length does not match column count");
+        if src.is_empty() {
+            return;
+        }
+        io_stats::count_written(mem::size_of_val(src));
+        if !self.is_transposed() {
+            self.major_slice_mut(row).copy_from_slice(src);
+            return;
+        }
+        let start = self.element_offset(row, 0);
+        let step = self.row_step();
+        let data = self.get_data_mut();
+        for (col, value) in src.iter().enumerate() {
+            unsafe {
+                *data.add(start + col * step) = *value;
+            }
+        }
+    }
+
+    pub fn swap_rows(&mut self, a: u64, b: u64) where T: Copy {
+        if a == b {
+            return;
+        }
+        if self.is_transposed() {
+            for col in 0..self.num_cols() {
+                let (x, y) = (self.element_offset(a, col), self.element_offset(b, col));
+                unsafe {
+                    ptr::swap(self.get_data_mut().add(x), self.get_data_mut().add(y));
+                }
+            }
+        } else {
+            let (first, second) = (self.element_offset(a, 0), self.element_offset(b, 0));
+            let cols = self.num_cols() as usize;
+            unsafe {
+                ptr::swap_nonoverlapping(self.get_data_mut().add(first), self.get_data_mut().add(second), cols);
+            }
+        }
+    }
+
+    // Storage is a sequence of contiguous major lines: logical rows normally, logical columns
+    // when transposed.
+    pub(crate) fn major_len(&self) -> u64 {
+        let header = self.get_header();
+        if header.transposed {
+            header.num_cols
+        } else {
+            header.num_rows
+        }
+    }
+
+    pub(crate) fn minor_len(&self) -> u64 {
+        let header = self.get_header();
+        if header.transposed {
+            header.num_rows
+        } else {
+            header.num_cols
+        }
+    }
+
+    pub(crate) fn major_slice(&self, major: u64) -> &[T] {
+        assert!(major < self.major_len(), "major index {} out of bounds", major);
+        let offset = (major * self.get_header().lda) as usize;
+        unsafe {
+            slice::from_raw_parts(self.get_data().add(offset), self.minor_len() as usize)
+        }
+    }
+
+    pub(crate) fn major_slice_mut(&mut self, major: u64) -> &mut [T] {
+        assert!(major < self.major_len(), "major index {} out of bounds", major);
+        let offset = (major * self.get_header().lda) as usize;
+        let len = self.minor_len() as usize;
+        unsafe {
+            slice::from_raw_parts_mut(self.get_data_mut().add(offset), len)
+        }
+    }
+
+    pub(crate) fn lda(&self) -> u64 {
+        self.get_header().lda
+    }
+
+    // All major lines including any padding between them.
+    pub(crate) fn storage(&self) -> &[T] {
+        let len = self.get_header().get_data_length_elements() as usize;
+        unsafe {
+            slice::from_raw_parts(self.get_data(), len)
+        }
+    }
+
+    pub(crate) fn storage_mut(&mut self) -> &mut [T] {
+        let len = self.get_header().get_data_length_elements() as usize;
+        unsafe {
+            slice::from_raw_parts_mut(self.get_data_mut(), len)
+        }
+    }
+
+    // Writes modified pages of the mapping back to the file, returning once they are on disk.
+    // A copy-on-write matrix has nothing it may write, so flushing one only checks its private
+    // pages against the warning threshold.
+    pub fn flush(&self) -> Result<(), OoclaError> {
+        if !self.persistent {
+            self.private_bytes()?;
+            return Ok(());
+        }
+        #[cfg(feature = "tracing")]
+        let started = Instant::now();
+        unsafe {
+            msync(self.start, self.mapped_length(), MS_SYNC)
+        }?;
+        io_stats::count_msync();
+        #[cfg(feature = "tracing")]
+        tracing::trace!(bytes = self.mapped_length(), micros = started.elapsed().as_micros() as u64, "msync");
+        Ok(())
+    }
+
+    pub fn transpose(&mut self) {
+        let header = self.get_header_mut();
+        header.transposed ^= true;
+        mem::swap(&mut header.num_rows, &mut header.num_cols);
+    }
+
+    pub(crate) fn compute_length(rows: u64, cols: u64) -> Result<u64, OoclaError> {
+        rows.checked_mul(cols)
+            .and_then(|elements| elements.checked_mul(mem::size_of::<T>() as u64))
+            .and_then(|bytes| bytes.checked_add(HEADER_SIZE as u64))
+            .filter(|&bytes| bytes <= isize::MAX as u64)
+            .ok_or_else(|| OoclaError::SizeOverflow(format!("a {}x{} matrix is too large to map", rows, cols)))
+    }
+
+    // Passes each of the major lines `lines` to `f` in order, read as the stream options' engine
+    // says.
+    pub(crate) fn stream_major<F>(&self, lines: Range<u64>, mut f: F) -> Result<(), OoclaError>
+        where T: StorageType, F: FnMut(u64, &[T]) -> Result<(), OoclaError> {
+        let mut f = |line, values: &[T]| {
+            io_stats::count_read(mem::size_of_val(values));
+            f(line, values)
+        };
+        protect::guarded(|| {
+            // The file of a copy-on-write matrix doesn't hold what has been written to it.
+            if !self.persistent {
+                return lines.into_iter().try_for_each(|line| f(line, self.major_slice(line)));
+            }
+            match self.stream_options.engine {
+                Engine::Mmap if self.stream_options.direct_io => direct::stream_lines(self, lines, f),
+                Engine::Mmap => lines.into_iter().try_for_each(|line| f(line, self.major_slice(line))),
+                #[cfg(feature = "io-uring")]
+                Engine::Uring => uring::stream_lines(self, lines, f),
+                #[cfg(not(feature = "io-uring"))]
+                Engine::Uring => {
+                    Err(OoclaError::Unsupported("the io_uring engine needs the io-uring feature".to_string()))
+                }
+            }
+        })
+    }
+
+    // As stream_major for the logical rows `rows`. The rows of a matrix stored by columns are
+    // gathered from the mapping whatever the engine, since reading each would read all of its
+    // storage.
+    pub(crate) fn stream_rows<F>(&self, rows: Range<u64>, mut f: F) -> Result<(), OoclaError>
+        where T: StorageType, F: FnMut(u64, &[T]) -> Result<(), OoclaError> {
+        if !self.is_transposed() {
+            return self.stream_major(rows, f);
+        }
+        let mut row = vec![T::default(); self.num_cols() as usize];
+        rows.into_iter().try_for_each(|r| {
+            self.read_row(r, &mut row);
+            f(r, &row)
+        })
+    }
+
+    fn mapped_length(&self) -> usize {
+        self.length
+    }
+
+    // A hugetlb mapping can only be unmapped in whole huge pages.
+    fn unmap_length(&self) -> usize {
+        match (self.map_options.huge_pages, huge_page_size()) {
+            (HugePagePolicy::Require, Some(size)) => self.length.div_ceil(size) * size,
+            _ => self.length,
+        }
+    }
+
+    fn get_header(&self) -> &MatrixHeader {
+        unsafe {
+            self.header.as_ref().unwrap()
+        }
+    }
+
+    fn get_data(&self) -> *const T {
+        self.data
+    }
+
+    fn get_data_mut(&mut self) -> *mut T {
+        self.data
+    }
+
+    // The header is changed only through this, and so only through &mut self, which no reference
+    // from get_header can outlive.
+    fn get_header_mut(&mut self) -> &mut MatrixHeader {
+        unsafe {
+            self.header.as_mut().unwrap()
+        }
+    }
+
+    // Sets the row count as rows are appended. The header starts the mapping, so is aligned.
+    fn publish_num_rows(&mut self, rows: u64) {
+        unsafe {
+            MatrixHeader::store_num_rows(self.header, rows)
+        }
+    }
+
+    // Hints to the kernel how the elements are about to be accessed, until the next hint. Hints
+    // are advisory, so a refused one leaves the default behaviour and the error can be ignored.
+    pub fn advise(&self, pattern: AccessPattern) -> Result<(), OoclaError> {
+        self.advise_bytes(HEADER_SIZE, self.mapped_length() - HEADER_SIZE, pattern)
+    }
+
+    // As advise, for the logical rows in `rows` alone. The rows of a matrix stored by columns are
+    // spread across all of its storage, so for one of those the hint covers every element.
+    pub fn advise_range(&self, rows: Range<u64>, pattern: AccessPattern) -> Result<(), OoclaError> {
+        assert!(rows.start <= rows.end && rows.end <= self.num_rows(), "rows {:?} out of bounds for {} rows", rows,
+                self.num_rows());
+        if self.is_transposed() {
+            return self.advise(pattern);
+        }
+        let line = self.lda() as usize * mem::size_of::<T>();
+        self.advise_bytes(HEADER_SIZE + rows.start as usize * line, (rows.end - rows.start) as usize * line, pattern)
+    }
+
+    // The bytes of the mapping from `offset` rounded out to whole pages, or None if there are none.
+    fn page_span(&self, offset: usize, len: usize) -> Option<(usize, usize)> {
+        if len == 0 {
+            return None;
+        }
+        let page = page_size();
+        Some((offset / page * page, cmp::min((offset + len).div_ceil(page) * page, self.mapped_length())))
+    }
+
+    // Advises the bytes of the mapping from `offset`, rounded out to whole pages.
+    fn advise_bytes(&self, offset: usize, len: usize, pattern: AccessPattern) -> Result<(), OoclaError> {
+        let (begin, end) = match self.page_span(offset, len) {
+            Some(span) => span,
+            None => return Ok(()),
+        };
+        unsafe {
+            madvise((self.start as *const u8).add(begin) as *const c_void, (end - begin) as size_t, pattern.advice())
+        }?;
+        #[cfg(feature = "tracing")]
+        tracing::trace!(offset = begin, bytes = end - begin, ?pattern, "madvise");
+        Ok(())
+    }
+
+    // The options that passes over this matrix follow, which are not stored in the file.
+    pub fn stream_options(&self) -> StreamOptions {
+        self.stream_options
+    }
+
+    pub fn set_stream_options(&mut self, options: StreamOptions) {
+        self.stream_options = options;
+    }
+
+    // Locks the whole mapping into memory, faulting in any pages not yet resident, so that no
+    // access faults until unlock is called or the matrix is dropped. Locked memory counts
+    // against RLIMIT_MEMLOCK unless the process has CAP_IPC_LOCK.
+    pub fn lock_resident(&self) -> Result<(), OoclaError> {
+        self.lock_bytes(0, self.mapped_length(), LockPolicy::Resident)
+    }
+
+    // As lock_resident, but each page is locked once it is first touched rather than faulted in
+    // now (mlock2 with MLOCK_ONFAULT).
+    pub fn lock_on_fault(&self) -> Result<(), OoclaError> {
+        self.lock_bytes(0, self.mapped_length(), LockPolicy::OnFault)
+    }
+
+    // Locks the pages holding the logical rows in `rows`, as lock_resident does for the whole
+    // mapping. The rows of a matrix stored by columns span all of its storage, which is locked in
+    // full.
+    pub fn lock_range(&self, rows: Range<u64>) -> Result<(), OoclaError> {
+        assert!(rows.start <= rows.end && rows.end <= self.num_rows(), "rows {:?} out of bounds for {} rows", rows,
+                self.num_rows());
+        if self.is_transposed() {
+            return self.lock_resident();
+        }
+        let line = self.lda() as usize * mem::size_of::<T>();
+        self.lock_bytes(HEADER_SIZE + rows.start as usize * line, (rows.end - rows.start) as usize * line,
+                        LockPolicy::Resident)
+    }
+
+    // Unlocks the whole mapping, whatever parts of it were locked.
+    pub fn unlock(&self) -> Result<(), OoclaError> {
+        unsafe {
+            munlock(self.start, self.mapped_length())
+        }?;
+        Ok(())
+    }
+
+    fn apply_lock_policy(&self) -> Result<(), OoclaError> {
+        match self.map_options.lock {
+            LockPolicy::Never => Ok(()),
+            policy => self.lock_bytes(0, self.mapped_length(), policy),
+        }
+    }
+
+    fn lock_bytes(&self, offset: usize, len: usize, policy: LockPolicy) -> Result<(), OoclaError> {
+        let (begin, end) = match self.page_span(offset, len) {
+            Some(span) => span,
+            None => return Ok(()),
+        };
+        let start = unsafe {
+            (self.start as *const u8).add(begin) as *const c_void
+        };
+        let ret = unsafe {
+            match policy {
+                LockPolicy::OnFault => libc::mlock2(start, end - begin, libc::MLOCK_ONFAULT),
+                _ => libc::mlock(start, end - begin),
+            }
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::ENOMEM) | Some(libc::EPERM) | Some(libc::EAGAIN) => {
+                Err(OoclaError::MemLockFailed { requested_bytes: (end - begin) as u64, rlimit: memlock_limit() })
+            }
+            _ => Err(err.into()),
+        }
+    }
+
+    // Counts the pages of the whole mapping, header and metadata included, that are in memory.
+    pub fn residency(&self) -> Result<ResidencyStats, OoclaError> {
+        let (resident_pages, total_pages) = self.count_resident(0, self.mapped_length())?;
+        Ok(ResidencyStats { resident_pages, total_pages, resident_bytes: resident_pages * page_size() as u64 })
+    }
+
+    // The fraction of pages in memory for each band of `granularity_rows` logical rows, the last
+    // band taking whatever rows remain. For a matrix stored by columns, a band's pages are those
+    // holding its part of every column.
+    pub fn residency_map(&self, granularity_rows: u64) -> Result<Vec<f32>, OoclaError> {
+        if granularity_rows == 0 {
+            return Err(OoclaError::InvalidArgument("residency bands must hold at least one row".to_string()));
+        }
+        let element = mem::size_of::<T>();
+        let line = self.lda() as usize * element;
+        (0..self.num_rows()).step_by(granularity_rows as usize).map(|first| {
+            let last = cmp::min(first + granularity_rows, self.num_rows());
+            let (resident, total) = if self.is_transposed() {
+                let mut counts = (0, 0);
+                for major in 0..self.major_len() {
+                    let offset = HEADER_SIZE + major as usize * line + first as usize * element;
+                    let (begin, end) = self.page_span(offset, (last - first) as usize * element).unwrap_or((0, 0));
+                    let (resident, total) = self.count_resident(begin, end)?;
+                    counts = (counts.0 + resident, counts.1 + total);
+                }
+                counts
+            } else {
+                let offset = HEADER_SIZE + first as usize * line;
+                let (begin, end) = self.page_span(offset, (last - first) as usize * line).unwrap_or((0, 0));
+                self.count_resident(begin, end)?
+            };
+            Ok(if total == 0 { 0.0 } else { resident as f32 / total as f32 })
+        }).collect()
+    }
+
+    // The number of pages from `begin`, which is page-aligned, to `end` that are in memory, and
+    // the number of pages in all.
+    fn count_resident(&self, begin: usize, end: usize) -> Result<(u64, u64), OoclaError> {
+        let page = page_size();
+        let total = (end - begin).div_ceil(page);
+        let mut vec = vec![0u8; cmp::min(total, MINCORE_CHUNK_PAGES)];
+        let mut resident = 0;
+        for first in (0..total).step_by(MINCORE_CHUNK_PAGES) {
+            let pages = cmp::min(MINCORE_CHUNK_PAGES, total - first);
+            let start = begin + first * page;
+            let len = cmp::min(pages * page, end - start);
+            let ret = unsafe {
+                libc::mincore((self.start as *mut u8).add(start) as *mut c_void, len, vec.as_mut_ptr())
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            resident += vec[..pages].iter().filter(|&&flags| flags & 1 != 0).count() as u64;
+        }
+        Ok((resident, total as u64))
+    }
+
+    // Interleaves the pages of the whole mapping across every node with memory, so that a scan
+    // from threads on all nodes spreads its traffic over all their memory controllers rather
+    // than saturating one. Call it before the matrix is first touched: pages already in memory
+    // are migrated where the kernel can, which is far slower than placing them at first touch.
+    // Files on tmpfs follow the policy as their pages are allocated; the page cache of other
+    // filesystems is placed by the policy of the thread that faults it in. A matrix that grows
+    // is remapped without the policy. Does nothing on a system with a single node.
+    #[cfg(feature = "numa")]
+    pub fn interleave_numa(&self) -> Result<(), OoclaError> {
+        if !numa::is_numa() {
+            return Ok(());
+        }
+        numa::mbind(self.start as *const c_void, self.mapped_length(), libc::MPOL_INTERLEAVE,
+                    &numa::nodes_with_memory())?;
+        Ok(())
+    }
+
+    // Binds the pages holding the logical rows in `rows` to `node`, under the same conditions as
+    // interleave_numa, so that a parallel scan partitioned by rows can place each partition on
+    // the node of the threads that scan it. Pages straddling the ends of the range are bound
+    // along with it. The rows of a matrix stored by columns span all of its storage, which is
+    // bound in full. Does nothing on a system with a single node beyond checking `node`.
+    #[cfg(feature = "numa")]
+    pub fn bind_range_to_node(&self, rows: Range<u64>, node: usize) -> Result<(), OoclaError> {
+        assert!(rows.start <= rows.end && rows.end <= self.num_rows(), "rows {:?} out of bounds for {} rows", rows,
+                self.num_rows());
+        numa::check_node(node)?;
+        if !numa::is_numa() {
+            return Ok(());
+        }
+        let line = self.lda() as usize * mem::size_of::<T>();
+        let (offset, len) = if self.is_transposed() {
+            (0, self.mapped_length())
+        } else {
+            (HEADER_SIZE + rows.start as usize * line, (rows.end - rows.start) as usize * line)
+        };
+        let (begin, end) = match self.page_span(offset, len) {
+            Some(span) => span,
+            None => return Ok(()),
+        };
+        let start = unsafe {
+            (self.start as *const u8).add(begin) as *const c_void
+        };
+        numa::mbind(start, end - begin, libc::MPOL_BIND, &[node])?;
+        Ok(())
+    }
+
+    // The number of pages of the whole mapping in memory on each node, indexed by node. Only
+    // pages touched through this mapping are counted, not those of the file the page cache
+    // holds that it hasn't reached yet. On a system with a single node it is the one count.
+    #[cfg(feature = "numa")]
+    pub fn numa_residency(&self) -> Result<Vec<u64>, OoclaError> {
+        match numa::count_pages_by_node(self.start as *const c_void, self.mapped_length()) {
+            Ok(counts) => Ok(counts),
+            // A kernel built without NUMA support has no move_pages, and all its memory is node 0.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => Ok(vec![self.residency()?.resident_pages]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Faults in the pages holding the logical rows in `rows` by reading a byte of each, for when
+    // only part of a matrix is needed soon or MAP_POPULATE is unavailable. As with advise_range,
+    // the rows of a matrix stored by columns span all of its storage, which is touched in full.
+    pub fn prefault(&self, rows: Range<u64>) -> Prefault {
+        assert!(rows.start <= rows.end && rows.end <= self.num_rows(), "rows {:?} out of bounds for {} rows", rows,
+                self.num_rows());
+        let began = Instant::now();
+        let line = self.lda() as usize * mem::size_of::<T>();
+        let (lines, len) = if self.is_transposed() {
+            (0..self.major_len(), self.major_len() as usize * line)
+        } else {
+            (rows.clone(), (rows.end - rows.start) as usize * line)
+        };
+        let (begin, end) = self.page_span(HEADER_SIZE + lines.start as usize * line, len).unwrap_or((0, 0));
+        let page = page_size();
+        for offset in (begin..end).step_by(page) {
+            unsafe {
+                ptr::read_volatile((self.start as *const u8).add(offset));
+            }
+        }
+        Prefault {
+            pages: (end - begin).div_ceil(page) as u64,
+            bytes: (end - begin) as u64,
+            elapsed: began.elapsed(),
+        }
+    }
+
+    // Releases the pages holding the logical rows in `rows` from this mapping and the page cache,
+    // after writing back any that are modified, so they are read from the file when next used.
+    // The rows of a matrix stored by columns share their pages, so for one of those nothing is
+    // released unless `rows` covers every row.
+    pub fn discard_range(&self, rows: Range<u64>) -> Result<(), OoclaError> {
+        assert!(rows.start <= rows.end && rows.end <= self.num_rows(), "rows {:?} out of bounds for {} rows", rows,
+                self.num_rows());
+        if !self.is_transposed() {
+            self.discard_major(rows)
+        } else if rows.end - rows.start == self.num_rows() {
+            self.discard_major(0..self.major_len())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Dropping the pages of a copy-on-write matrix would lose what has been written to them, so
+    // nothing of one is released.
+    pub(crate) fn discard_major(&self, lines: Range<u64>) -> Result<(), OoclaError> {
+        if !self.persistent {
+            return Ok(());
+        }
+        let line = self.lda() as usize * mem::size_of::<T>();
+        let (begin, end) = match self.page_span(HEADER_SIZE + lines.start as usize * line,
+                                                (lines.end - lines.start) as usize * line) {
+            Some(span) => span,
+            None => return Ok(()),
+        };
+        let start = unsafe {
+            (self.start as *mut u8).add(begin) as *mut c_void
+        };
+        // Dropping a shared mapping's pages keeps their contents in the page cache, but the cache
+        // only gives up pages that are clean, so modified ones are written back first.
+        #[cfg(feature = "tracing")]
+        let started = Instant::now();
+        unsafe {
+            msync(start, end - begin, MS_SYNC)?;
+            madvise(start, end - begin, MADV_DONTNEED)?;
+        }
+        io_stats::count_msync();
+        #[cfg(feature = "tracing")]
+        tracing::trace!(offset = begin, bytes = end - begin, micros = started.elapsed().as_micros() as u64,
+                        "msync and madvise(DONTNEED)");
+        match unsafe { libc::posix_fadvise(self.file.as_raw_fd(), begin as libc::off_t, (end - begin) as libc::off_t,
+                                           libc::POSIX_FADV_DONTNEED) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno).into()),
+        }
+    }
+
+    // Applies `pattern` until the guard is dropped, when the default is restored.
+    pub(crate) fn advise_scoped(&self, pattern: AccessPattern) -> AdviceGuard<'_, T> {
+        let _ = self.advise(pattern);
+        AdviceGuard {
+            matrix: self,
+        }
+    }
+
+    // Advises sequential access for a pass over the rows in order, which walks the storage in
+    // order unless the matrix is stored by columns.
+    pub(crate) fn advise_row_scan(&self) -> Option<AdviceGuard<'_, T>> {
+        if self.is_transposed() {
+            None
+        } else {
+            Some(self.advise_scoped(AccessPattern::Sequential))
+        }
+    }
+
+    fn create_index_generator(&self) -> ElementIterCommon {
+        let header = self.get_header();
+        ElementIterCommon::new(header.num_rows as usize, header.num_cols as usize, header.lda as usize,
+                               header.transposed)
+    }
+
+    pub fn element_iter(&self) -> ElementIter<'_, T> {
+        let generator = self.create_index_generator();
+        ElementIter {
+            lifetime: PhantomData,
+            generator,
+            data: self.get_data(),
+        }
+    }
+
+    pub fn element_iter_mut(&mut self) -> ElementIterMut<'_, T> {
+        let generator = self.create_index_generator();
+        ElementIterMut {
+            lifetime: PhantomData,
+            generator,
+            data: self.get_data_mut(),
+        }
     }
-}
 
-#[repr(C)]
-struct MatrixHeader {
-    magic: u64,
-    num_rows: u64,
-    num_cols: u64,
-    representation: FloatType,
-    lda: u64,
-    transposed: bool,
-}
+    // Each logical row in order, borrowed from the mapping when rows are stored contiguously and
+    // otherwise gathered into a new buffer.
+    pub fn row_iter(&self) -> RowIter<'_, T> where T: Copy {
+        self.row_range_iter(0..self.num_rows())
+    }
 
-impl MatrixHeader {
-    fn get_data_length_elements(&self) -> u64 {
-        self.lda * if self.transposed {
-            self.num_rows
-        } else {
-            self.num_cols
+    pub(crate) fn row_range_iter(&self, rows: Range<u64>) -> RowIter<'_, T> where T: Copy {
+        assert!(rows.start <= rows.end && rows.end <= self.num_rows(), "rows {:?} out of bounds for {} rows", rows,
+                self.num_rows());
+        RowIter {
+            matrix: self,
+            rows,
         }
     }
-}
 
-pub struct Dense<T> {
-    file: File,
-    start: *mut c_void,
-    header: *mut MatrixHeader,
-    data: *mut T,
-}
+    pub fn diagonal_len(&self) -> u64 {
+        let header = self.get_header();
+        ::std::cmp::min(header.num_rows, header.num_cols)
+    }
 
-impl<T> Dense<T> {
-    pub fn create(path: &Path, rows: u64, cols: u64) -> Result<Dense<T>, Box<Error>> where T: SupportedType {
-        let file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
-        let len = Self::compute_length(rows, cols);
-        file.set_len(len)?;
+    // Element (k, k) lives at k * (lda + 1) regardless of whether the matrix is transposed.
+    fn diagonal_stride(&self) -> usize {
+        self.get_header().lda as usize + 1
+    }
 
-        let mut map_flags = MapFlags::empty();
-        map_flags.insert(MAP_SHARED);
-        let mut prot_flags = ProtFlags::empty();
-        prot_flags.insert(PROT_READ);
-        prot_flags.insert(PROT_WRITE);
-        let offset = 0;
-        let fd = file.as_raw_fd();
+    pub fn diagonal_iter(&self) -> DiagonalIter<'_, T> {
+        DiagonalIter {
+            lifetime: PhantomData,
+            data: self.get_data(),
+            stride: self.diagonal_stride(),
+            index: 0,
+            len: self.diagonal_len() as usize,
+        }
+    }
 
-        let start = unsafe {
-            mmap(ptr::null_mut(), len as size_t, prot_flags, map_flags, fd, offset)
-        }?;
-        let header = start as *mut MatrixHeader;
-        let data = unsafe {
-            (start as *mut u8).offset(HEADER_SIZE as isize)
-        };
-        let result = Dense {
-            file: file,
-            start: start,
-            header: header,
-            data: data as *mut T,
-        };
-        {
-            let header = result.get_header_mut();
-            header.num_rows = rows;
-            header.num_cols = cols;
-            header.representation = T::get_float_type();
-            header.transposed = false;
-            header.lda = cols;
+    pub fn diagonal_iter_mut(&mut self) -> DiagonalIterMut<'_, T> {
+        DiagonalIterMut {
+            lifetime: PhantomData,
+            stride: self.diagonal_stride(),
+            index: 0,
+            len: self.diagonal_len() as usize,
+            data: self.get_data_mut(),
         }
-        Ok(result)
     }
 
-    pub fn num_rows(&self) -> u64 {
-        self.get_header().num_rows
+    pub(crate) fn diagonal_is_sparse_in_pages(&self) -> bool {
+        self.diagonal_stride() * mem::size_of::<T>() >= page_size()
     }
 
-    pub fn num_cols(&self) -> u64 {
-        self.get_header().num_cols
+    // Whether a write of every element of the matrix uses non-temporal stores.
+    fn non_temporal_writes(&self) -> bool {
+        match self.stream_options().non_temporal_stores {
+            NonTemporalStores::Auto => {
+                self.major_len() * self.minor_len() * mem::size_of::<T>() as u64 >= NON_TEMPORAL_MIN_BYTES
+            }
+            NonTemporalStores::Always => true,
+            NonTemporalStores::Never => false,
+        }
     }
 
-    pub fn transpose(&mut self) {
-        let header = self.get_header_mut();
-        header.transposed ^= true;
-        mem::swap(&mut header.num_rows, &mut header.num_cols);
+    // Passes each run of storage holding elements to `f`, which is the whole of it when there is
+    // no padding between lines.
+    fn for_each_run_mut<F>(&mut self, mut f: F) where F: FnMut(&mut [T]) {
+        if self.lda() == self.minor_len() {
+            f(self.storage_mut());
+        } else {
+            for line in 0..self.major_len() {
+                f(self.major_slice_mut(line));
+            }
+        }
     }
 
-    fn compute_length(rows: u64, cols: u64) -> u64 {
-        HEADER_SIZE as u64 + rows * cols * mem::size_of::<T>() as u64
+    // Fails only with a bus error caught by protect.
+    pub fn fill(&mut self, value: T) -> Result<(), OoclaError> where T: Copy {
+        let writer = RunWriter::new(self.non_temporal_writes());
+        protect::guarded(|| {
+            self.for_each_run_mut(|run| writer.fill(run, value));
+            Ok(())
+        })
     }
 
-    fn get_header(&self) -> &MatrixHeader {
-        unsafe {
-            self.header.as_ref().unwrap()
-        }
+    // Sets each element to f(row, col), visiting them in storage order. Fails only with a bus error
+    // caught by protect.
+    pub fn fill_with<F>(&mut self, mut f: F) -> Result<(), OoclaError> where F: FnMut(u64, u64) -> T {
+        let transposed = self.is_transposed();
+        protect::guarded(|| {
+            for major in 0..self.major_len() {
+                for (minor, value) in self.major_slice_mut(major).iter_mut().enumerate() {
+                    let minor = minor as u64;
+                    *value = if transposed { f(minor, major) } else { f(major, minor) };
+                }
+            }
+            Ok(())
+        })
     }
 
-    fn get_data(&self) -> *const T {
-        self.data
+    // Ones on the leading diagonal and zeros elsewhere, which for a square matrix is the identity.
+    pub fn set_identity(&mut self) -> Result<(), OoclaError> where T: SupportedType {
+        protect::guarded(|| {
+            self.fill(T::from_f64(0.0))?;
+            for value in self.diagonal_iter_mut() {
+                *value = T::from_f64(1.0);
+            }
+            Ok(())
+        })
     }
 
-    fn get_data_mut(& mut self) -> *mut T {
-        self.data
+    // Fails only with a bus error caught by protect.
+    pub fn randomise(&mut self) -> Result<(), OoclaError> where T: Rand + Copy {
+        let mut rng = rand::thread_rng();
+        let writer = RunWriter::new(self.non_temporal_writes());
+        let mut values = Vec::with_capacity(RANDOM_CHUNK_ELEMENTS);
+        protect::guarded(|| {
+            self.for_each_run_mut(|run| {
+                for chunk in run.chunks_mut(RANDOM_CHUNK_ELEMENTS) {
+                    values.clear();
+                    values.extend((0..chunk.len()).map(|_| rng.gen::<T>()));
+                    writer.copy(&values, chunk);
+                }
+            });
+            Ok(())
+        })
     }
 
+    // Lines of storage in each band the seeded randomise operations fill from one stream.
+    fn random_band_lines(&self) -> usize {
+        cmp::max(1, RANDOM_BAND_BYTES / cmp::max(1, self.lda() as usize * mem::size_of::<T>()))
+    }
 
-    fn get_header_mut(&self) -> &mut MatrixHeader {
-        unsafe {
-            self.header.as_mut().unwrap()
+    // As randomise, but with values that depend only on `seed` and the shape and layout of the
+    // matrix, the same as par_randomise_seeded gives.
+    pub fn randomise_seeded(&mut self, seed: u64) where T: Rand + Copy {
+        let (lda, minor) = (self.lda() as usize, self.minor_len() as usize);
+        if lda == 0 {
+            return;
+        }
+        let band_lines = self.random_band_lines();
+        let non_temporal = self.non_temporal_writes();
+        for (band, lines) in self.storage_mut().chunks_mut(band_lines * lda).enumerate() {
+            randomise_band(lines, lda, minor, Xoshiro256::stream(seed, band as u64), non_temporal, |rng| rng.gen());
         }
     }
 
-    fn create_index_generator(&self) -> ElementIterCommon {
-        let header = self.get_header();
-        let (mut major_size, mut minor_size) = (header.num_rows as usize, header.num_cols as usize);
-        if header.transposed {
-            mem::swap(&mut major_size, &mut minor_size);
+    // As randomise_seeded, drawing from `distribution`, which fails if its parameters aren't
+    // finite or its range or standard deviation is negative. The values differ from
+    // randomise_seeded's even for a uniform distribution over [0, 1).
+    pub fn randomise_seeded_with(&mut self, seed: u64, distribution: Distribution) -> Result<(), OoclaError>
+        where T: SupportedType {
+        distribution.check()?;
+        let (lda, minor) = (self.lda() as usize, self.minor_len() as usize);
+        if lda == 0 {
+            return Ok(());
         }
-        ElementIterCommon {
-            major_size: major_size as usize,
-            minor_size: minor_size as usize,
-            major_index: 0,
-            major_offset: 0,
-            minor_offset: 0,
-            lda: header.lda as usize,
-            transposed: header.transposed,
+        let band_lines = self.random_band_lines();
+        let non_temporal = self.non_temporal_writes();
+        for (band, lines) in self.storage_mut().chunks_mut(band_lines * lda).enumerate() {
+            randomise_band(lines, lda, minor, Xoshiro256::stream(seed, band as u64), non_temporal,
+                           |rng| T::from_f64(distribution.sample(rng)));
         }
+        Ok(())
     }
 
-    pub fn element_iter<'a>(&'a self) -> ElementIter<'a, T> {
-        let generator = self.create_index_generator();
-        ElementIter {
-            lifetime: PhantomData,
-            generator: generator,
-            data: self.get_data(),
+    // As randomise, but filling bands of lines in parallel, each from its own stream of `seed`.
+    // Bands are a fixed number of lines for a given line length, so the result depends only on
+    // the seed and the shape and layout of the matrix, whatever the number of threads. The
+    // generator is Xoshiro256++, so the values differ from those of any other seeded operation.
+    #[cfg(feature = "rayon")]
+    pub fn par_randomise_seeded(&mut self, seed: u64) where T: Rand + Copy + Send {
+        let (lda, minor) = (self.lda() as usize, self.minor_len() as usize);
+        if lda == 0 {
+            return;
         }
+        let band_lines = self.random_band_lines();
+        let non_temporal = self.non_temporal_writes();
+        #[cfg(feature = "numa")]
+        let pinner = numa::NodePinner::new(self.storage(), self.stream_options().pin_numa_workers);
+        self.storage_mut().par_chunks_mut(band_lines * lda).enumerate().for_each(|(band, lines)| {
+            #[cfg(feature = "numa")]
+            pinner.pin(lines);
+            randomise_band(lines, lda, minor, Xoshiro256::stream(seed, band as u64), non_temporal, |rng| rng.gen());
+        });
     }
+}
 
-    pub fn element_iter_mut<'a>(&'a mut self) -> ElementIterMut<'a, T> {
-        let generator = self.create_index_generator();
-        ElementIterMut {
-            lifetime: PhantomData,
-            generator: generator,
-            data: self.get_data_mut(),
+// Fills the first `minor` elements of each line of `lines`, `lda` apart, with values `sample`
+// draws from `rng`.
+fn randomise_band<T: Copy, F>(lines: &mut [T], lda: usize, minor: usize, mut rng: Xoshiro256, non_temporal: bool,
+                              mut sample: F) where F: FnMut(&mut Xoshiro256) -> T {
+    let writer = RunWriter::new(non_temporal);
+    let mut values = Vec::with_capacity(minor);
+    for line in lines.chunks_mut(lda) {
+        values.clear();
+        values.extend((0..minor).map(|_| sample(&mut rng)));
+        writer.copy(&values, &mut line[..minor]);
+    }
+}
+
+pub(crate) struct AdviceGuard<'a, T> where T: 'a {
+    matrix: &'a Dense<T>,
+}
+
+impl<'a, T> Drop for AdviceGuard<'a, T> {
+    fn drop(&mut self) {
+        let _ = self.matrix.advise(AccessPattern::Normal);
+    }
+}
+
+// Follows a pass along the major lines of a matrix, releasing each block of lines from the page
+// cache once the pass is beyond it when the matrix's stream options ask for that.
+pub(crate) struct DiscardBehind {
+    enabled: bool,
+    block_lines: u64,
+    discarded: u64,
+}
+
+impl DiscardBehind {
+    pub(crate) fn new<T>(a: &Dense<T>) -> DiscardBehind {
+        let line = cmp::max(1, a.lda() * mem::size_of::<T>() as u64);
+        DiscardBehind {
+            enabled: a.stream_options().drop_cache_behind || a.stream_options().direct_io,
+            block_lines: cmp::max(1, DISCARD_BLOCK_BYTES / line),
+            discarded: 0,
         }
     }
 
-    pub fn randomise(&mut self) where T: Rand {
-        let mut rng = rand::thread_rng();
-        for value in self.element_iter_mut() {
-            *value = rng.gen()
+    // Notes that the lines before `end` are finished with, releasing them a block at a time, and
+    // all that remain once `end` reaches the last line.
+    pub(crate) fn advance<T>(&mut self, a: &Dense<T>, end: u64) -> Result<(), OoclaError> {
+        if self.enabled && (end - self.discarded >= self.block_lines || end == a.major_len()) {
+            a.discard_major(self.discarded..end)?;
+            self.discarded = end;
         }
+        Ok(())
     }
 }
 
@@ -183,18 +1928,40 @@ pub struct ElementIterCommon {
     minor_offset: usize,
     lda: usize,
     transposed: bool,
+    // Whether the position has been yielded yet, as it starts on the first element.
+    started: bool,
 }
 
 impl ElementIterCommon {
+    // Positions in a rows x cols matrix whose major lines are `lda` elements apart, the columns
+    // if `transposed` and otherwise the rows.
+    fn new(rows: usize, cols: usize, lda: usize, transposed: bool) -> ElementIterCommon {
+        let (major_size, minor_size) = if transposed { (cols, rows) } else { (rows, cols) };
+        ElementIterCommon {
+            major_size,
+            minor_size,
+            major_index: 0,
+            major_offset: 0,
+            minor_offset: 0,
+            lda,
+            transposed,
+            started: false,
+        }
+    }
+
+    // The offset of the next element, which is always within the storage: a matrix with no
+    // columns or no rows has no elements to yield.
     fn next_index(&mut self) -> Option<usize> {
-        if self.minor_offset + 1 < self.minor_size {
+        if !self.started {
+            self.started = true;
+        } else if self.minor_offset + 1 < self.minor_size {
             self.minor_offset += 1
         } else {
             self.minor_offset = 0;
             self.major_index += 1;
             self.major_offset += self.lda;
         }
-        if self.major_index < self.major_size {
+        if self.major_index < self.major_size && self.minor_offset < self.minor_size {
             Some(self.major_offset + self.minor_offset)
         } else {
             None
@@ -230,7 +1997,7 @@ impl <'a, T> Iterator for ElementIter<'a, T> {
     fn next(&mut self) -> Option<&'a T> {
         match self.generator.next_index() {
             None => None,
-            Some(idx) => unsafe { self.data.offset(idx as isize).as_ref() },
+            Some(idx) => unsafe { self.data.add(idx).as_ref() },
         }
     }
 }
@@ -245,8 +2012,10 @@ impl <'a, T> ElementIter<'a, T> {
     }
 }
 
+// Invariant in T, as a mutable reference is, so that values of a shorter-lived T can't be
+// written through it.
 pub struct ElementIterMut<'a, T> where T: 'a {
-    lifetime: PhantomData<&'a T>,
+    lifetime: PhantomData<&'a mut T>,
     data: *mut T,
     generator: ElementIterCommon,
 }
@@ -257,7 +2026,7 @@ impl <'a, T> Iterator for ElementIterMut<'a, T> {
     fn next(&mut self) -> Option<&'a mut T> {
         let result:Option<&'a mut T> = match self.generator.next_index() {
             None => None,
-            Some(idx) => unsafe { self.data.offset(idx as isize).as_mut() },
+            Some(idx) => unsafe { self.data.add(idx).as_mut() },
         };
         result
     }
@@ -273,12 +2042,757 @@ impl <'a, T> ElementIterMut<'a, T> {
     }
 }
 
+pub struct RowIter<'a, T> where T: 'a {
+    matrix: &'a Dense<T>,
+    rows: Range<u64>,
+}
+
+impl<'a, T: Copy> RowIter<'a, T> {
+    fn row(&self, row: u64) -> Cow<'a, [T]> {
+        let matrix = self.matrix;
+        if matrix.is_transposed() || matrix.num_cols() == 0 {
+            let mut values = Vec::with_capacity(matrix.num_cols() as usize);
+            values.extend((0..matrix.num_cols()).map(|col| matrix.get(row, col)));
+            io_stats::count_read(mem::size_of_val(values.as_slice()));
+            Cow::Owned(values)
+        } else {
+            let line = matrix.major_slice(row);
+            io_stats::count_read(mem::size_of_val(line));
+            Cow::Borrowed(line)
+        }
+    }
+}
+
+impl<'a, T: Copy> Iterator for RowIter<'a, T> {
+    type Item = Cow<'a, [T]>;
+
+    fn next(&mut self) -> Option<Cow<'a, [T]>> {
+        self.rows.next().map(|row| self.row(row))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rows.size_hint()
+    }
+}
+
+impl<'a, T: Copy> DoubleEndedIterator for RowIter<'a, T> {
+    fn next_back(&mut self) -> Option<Cow<'a, [T]>> {
+        self.rows.next_back().map(|row| self.row(row))
+    }
+}
+
+pub struct DiagonalIter<'a, T> where T: 'a {
+    lifetime: PhantomData<&'a T>,
+    data: *const T,
+    stride: usize,
+    index: usize,
+    len: usize,
+}
+
+impl <'a, T> Iterator for DiagonalIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.index < self.len {
+            let offset = self.index * self.stride;
+            self.index += 1;
+            unsafe { self.data.add(offset).as_ref() }
+        } else {
+            None
+        }
+    }
+}
+
+pub struct DiagonalIterMut<'a, T> where T: 'a {
+    lifetime: PhantomData<&'a mut T>,
+    data: *mut T,
+    stride: usize,
+    index: usize,
+    len: usize,
+}
+
+impl <'a, T> Iterator for DiagonalIterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<&'a mut T> {
+        if self.index < self.len {
+            let offset = self.index * self.stride;
+            self.index += 1;
+            unsafe { self.data.add(offset).as_mut() }
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> fmt::Debug for Dense<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dense")
+            .field("rows", &self.num_rows())
+            .field("cols", &self.num_cols())
+            .field("transposed", &self.is_transposed())
+            .field("persistent", &self.persistent)
+            .finish()
+    }
+}
+
 impl<T> Drop for Dense<T> {
     fn drop(&mut self) {
-        let header = self.get_header();
-        let length = Self::compute_length(header.num_rows, header.num_cols);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(rows = self.num_rows(), cols = self.num_cols(), bytes = self.length, "closed matrix");
+        if let Some(slot) = self.protect_slot {
+            protect::deregister(slot);
+        }
+        let length = self.unmap_length();
         unsafe {
-            munmap(self.start, length as usize)
+            munmap(self.start, length)
         }.unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io_stats::measure;
+    use ops::gemv;
+    use progress::CancelToken;
+    use std::cell::Cell;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn elements<T: StorageType>(a: &Dense<T>) -> Vec<Vec<T>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    // 4096 rows of 4 KiB, filled and so resident, at a path of the caller's.
+    fn resident_matrix(path: &Path) -> Dense<f64> {
+        Dense::from_fn(path, 4096, 512, |i, j| (i * 512 + j) as f64).unwrap()
+    }
+
+    #[test]
+    fn discarding_rows_writes_them_back_and_releases_them() {
+        let path = TempMatrixPath::new();
+        let a = resident_matrix(path.path());
+        assert_eq!(a.residency_map(1024).unwrap(), vec![1.0; 4]);
+        a.discard_range(1024..3072).unwrap();
+        let bands = a.residency_map(1024).unwrap();
+        assert!(bands[1] < 0.05 && bands[2] < 0.05, "{:?}", bands);
+        assert!(bands[0] > 0.95 && bands[3] > 0.95, "{:?}", bands);
+        // The modified rows were written back before being dropped, so they read back from the file.
+        assert_eq!(a.get(2000, 7), (2000 * 512 + 7) as f64);
+        drop(a);
+        let b = Dense::<f64>::open(path.path()).unwrap();
+        assert!((0..4096).all(|i| b.get(i, 511) == (i * 512 + 511) as f64));
+    }
+
+    #[test]
+    fn only_whole_matrices_stored_by_columns_are_discarded() {
+        let path = TempMatrixPath::new();
+        let mut a = resident_matrix(path.path());
+        a.transpose();
+        a.discard_range(0..100).unwrap();
+        assert_eq!(a.residency_map(512).unwrap(), vec![1.0]);
+        a.discard_range(0..512).unwrap();
+        // residency_map reads the header, faulting it back in along with whatever the kernel reads
+        // ahead of it, so the mapping is counted as a whole.
+        assert!(a.residency().unwrap().resident_pages < 10);
+        assert_eq!(a.get(3, 4000), (4000 * 512 + 3) as f64);
+    }
+
+    #[test]
+    fn streamed_copies_read_back_after_dropping_the_cache_behind() {
+        let (src, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        let mut a = resident_matrix(src.path());
+        a.set_stream_options(StreamOptions { drop_cache_behind: true, ..StreamOptions::default() });
+        let copy = a.copy_at(Some(dst.path())).unwrap();
+        assert_eq!(copy.stream_options(), a.stream_options());
+        assert!(a.residency().unwrap().resident_pages < 10);
+        assert!(copy.residency().unwrap().resident_pages < 10);
+        assert_eq!(elements(&copy), elements(&a));
+        drop(copy);
+        assert_eq!(elements(&Dense::<f64>::open(dst.path()).unwrap()), elements(&a));
+    }
+
+    #[test]
+    fn passes_give_the_same_results_when_dropping_the_cache_behind() {
+        let path = TempMatrixPath::new();
+        let mut a = resident_matrix(path.path());
+        let x: Vec<f64> = (0..512).map(|j| 1.0 / (j + 1) as f64).collect();
+        let (mut plain, mut dropped) = (vec![0.0; 4096], vec![0.0; 4096]);
+        gemv(1.0, &a, &x, 0.0, &mut plain).unwrap();
+        a.set_stream_options(StreamOptions { drop_cache_behind: true, ..StreamOptions::default() });
+        gemv(1.0, &a, &x, 0.0, &mut dropped).unwrap();
+        assert_eq!(plain, dropped);
+        assert!(a.residency().unwrap().resident_pages < 10);
+    }
+
+    // A pass that drops the cache behind it leaves the next pass to read the matrix from disk
+    // again, while passes that don't find it all in memory.
+    #[test]
+    fn dropping_the_cache_behind_makes_the_next_pass_fault() {
+        let path = TempMatrixPath::new();
+        let mut a = resident_matrix(path.path());
+        let x = vec![1.0; 512];
+        let mut y = vec![0.0; 4096];
+        let mut pass = |a: &Dense<f64>| measure(|| gemv(1.0, a, &x, 0.0, &mut y).unwrap()).1;
+        let (first, second) = (pass(&a), pass(&a));
+        a.set_stream_options(StreamOptions { drop_cache_behind: true, ..StreamOptions::default() });
+        let dropping = pass(&a);
+        let after = pass(&a);
+        for stats in [first, second, dropping].iter() {
+            assert!(stats.major_faults == 0 && stats.minor_faults < 10, "{:?}", stats);
+        }
+        // Read ahead sequentially, the pages come from disk in a few large reads and are mapped a
+        // batch at a time, so there are few major faults but every batch faults again.
+        assert!(after.major_faults > 0 && after.minor_faults > 100, "{:?}", after);
+    }
+
+    #[test]
+    fn prefaulted_rows_are_resident() {
+        let path = TempMatrixPath::new();
+        let a = resident_matrix(path.path());
+        a.discard_range(0..4096).unwrap();
+        // Without readahead, so that only what prefault touches is read in.
+        a.advise(AccessPattern::Random).unwrap();
+        let touched = a.prefault(2048..3072);
+        // 1024 rows of 4 KiB, offset by the header into one more page.
+        assert_eq!(touched.pages, 1025);
+        assert_eq!(touched.bytes, 1025 * page_size() as u64);
+        let bands = a.residency_map(1024).unwrap();
+        assert_eq!(bands[2], 1.0, "{:?}", bands);
+        assert!(bands[1] < 0.5 && bands[3] < 0.5, "{:?}", bands);
+        assert_eq!(a.prefault(5..5).pages, 0);
+        // The prefaulted rows are read without waiting on the disk, and the rest with it.
+        let sum_rows = |rows: Range<u64>| measure(|| rows.map(|i| a.get(i, 0) + a.get(i, 511)).sum::<f64>()).1;
+        let prefaulted = sum_rows(2049..3071);
+        assert_eq!(prefaulted.major_faults, 0, "{:?}", prefaulted);
+        let cold = sum_rows(3200..3700);
+        assert!(cold.major_faults >= 500, "{:?}", cold);
+    }
+
+    #[test]
+    fn populated_mappings_are_resident_when_opened() {
+        let path = TempMatrixPath::new();
+        resident_matrix(path.path()).discard_range(0..4096).unwrap();
+        let a = Dense::<f64>::open_with(path.path(), MapOptions { populate: true, ..MapOptions::default() }).unwrap();
+        let residency = a.residency().unwrap();
+        assert_eq!(residency.resident_pages, residency.total_pages);
+        assert_eq!(a.get(4095, 511), (4096 * 512 - 1) as f64);
+    }
+
+
+    #[test]
+    fn huge_pages_degrade_gracefully_on_ordinary_files() {
+        // Whether or not the kernel backs this file with transparent huge pages, advising them
+        // leaves a working matrix.
+        let path = TempMatrixPath::new();
+        let advise = MapOptions { huge_pages: HugePagePolicy::Advise, ..MapOptions::default() };
+        let mut a = Dense::<f64>::create_with(path.path(), 300, 200, advise).unwrap();
+        a.fill_with(|i, j| (i * 200 + j) as f64).unwrap();
+        a.flush().unwrap();
+        drop(a);
+        let a = Dense::<f64>::open_with(path.path(), advise).unwrap();
+        assert_eq!(a.get(299, 199), (300 * 200 - 1) as f64);
+        drop(a);
+
+        // A file outside hugetlbfs can't be mapped with MAP_HUGETLB, so requiring huge pages fails
+        // with an explanation rather than a bare errno, and leaves the file as it was.
+        let require = MapOptions { huge_pages: HugePagePolicy::Require, ..MapOptions::default() };
+        let unsupported = |result: Result<Dense<f64>, OoclaError>| match result {
+            Err(OoclaError::Unsupported(msg)) => assert!(msg.contains("hugetlbfs"), "{}", msg),
+            other => panic!("expected huge pages to be unsupported, got {:?}", other.map(|_| ())),
+        };
+        unsupported(Dense::open_with(path.path(), require));
+        assert_eq!(Dense::<f64>::open(path.path()).unwrap().get(1, 2), 202.0);
+        let other = TempMatrixPath::new();
+        unsupported(Dense::create_with(other.path(), 10, 10, require));
+    }
+
+
+    // The process's locked memory in KiB, from the VmLck line of /proc/self/status.
+    fn locked_kib() -> u64 {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|line| line.starts_with("VmLck:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    // Runs `lock`, or says why the test is being skipped if the process may not lock memory.
+    fn locked(lock: Result<(), OoclaError>) -> bool {
+        match lock {
+            Ok(()) => true,
+            Err(e @ OoclaError::MemLockFailed { .. }) => {
+                eprintln!("skipping: {}", e);
+                false
+            }
+            Err(e) => panic!("locking failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn locking_is_accounted_and_undone() {
+        // 256 rows of 4 KiB.
+        let a = Dense::<f64>::create_anonymous(256, 512).unwrap();
+        let before = locked_kib();
+        if !locked(a.lock_resident()) {
+            return;
+        }
+        let mapped_kib = a.mapped_length() as u64 / 1024;
+        assert!(locked_kib() >= before + mapped_kib, "{} KiB locked, {} before", locked_kib(), before);
+        assert_eq!(a.residency().unwrap().resident_pages, a.residency().unwrap().total_pages);
+        a.unlock().unwrap();
+        assert_eq!(locked_kib(), before);
+
+        assert!(locked(a.lock_range(64..128)));
+        let rows_kib = locked_kib() - before;
+        assert!((256..=264).contains(&rows_kib), "{} KiB locked for 64 rows", rows_kib);
+        a.unlock().unwrap();
+        assert_eq!(locked_kib(), before);
+
+        // Locked as the mapping is made, and unlocked with it.
+        let path = TempMatrixPath::new();
+        let lock = MapOptions { lock: LockPolicy::Resident, ..MapOptions::default() };
+        let b = match Dense::<f64>::create_with(path.path(), 256, 512, lock) {
+            Err(e @ OoclaError::MemLockFailed { .. }) => return eprintln!("skipping: {}", e),
+            b => b.unwrap(),
+        };
+        assert!(locked_kib() >= before + mapped_kib);
+        drop(b);
+        assert_eq!(locked_kib(), before);
+    }
+
+
+    #[test]
+    fn residency_is_counted_beyond_a_single_mincore_call() {
+        // A sparse 4 GiB file of rows of one page each, more pages than one mincore call covers.
+        let rows = MINCORE_CHUNK_PAGES as u64 + 100;
+        let path = TempMatrixPath::new();
+        let mut a = Dense::<f64>::create(path.path(), rows, 512).unwrap();
+        a.advise(AccessPattern::Random).unwrap();
+        // Touching a page may bring in the rest of a large folio, but never much of the file.
+        a.set(rows - 50, 0, 1.0);
+        let residency = a.residency().unwrap();
+        assert_eq!(residency.total_pages, rows + 1);
+        assert!(residency.resident_pages >= 2 && residency.resident_pages < rows / 100, "{:?}", residency);
+        assert_eq!(residency.resident_bytes, residency.resident_pages * page_size() as u64);
+        let bands = a.residency_map(MINCORE_CHUNK_PAGES as u64).unwrap();
+        assert_eq!(bands.len(), 2);
+        assert!(bands[0] < 0.01 && bands[1] > 0.0, "{:?}", bands);
+    }
+
+    #[test]
+    fn residency_bands_follow_the_logical_rows() {
+        let path = TempMatrixPath::new();
+        let mut a = resident_matrix(path.path());
+        a.discard_range(1024..2048).unwrap();
+        let bands = a.residency_map(1024).unwrap();
+        assert!(bands[1] < 0.05 && bands[2] == 1.0, "{:?}", bands);
+        // Stored by columns, every band of rows has part of each of the 4096 columns, a quarter of
+        // which were released.
+        a.transpose();
+        let bands = a.residency_map(128).unwrap();
+        assert_eq!(bands.len(), 4);
+        assert!(bands.iter().all(|&band| band > 0.7 && band < 0.8), "{:?}", bands);
+        match a.residency_map(0) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected an invalid argument, got {:?}", other),
+        }
+    }
+
+    // Writes `write` makes to a new matrix of each shape, once with non-temporal stores and once
+    // without, and checks the files are byte for byte the same. Odd line lengths start most
+    // lines of storage part way into a vector, and leave the ends of runs unaligned.
+    fn check_non_temporal_writes<T: SupportedType, F>(write: F) where F: Fn(&mut Dense<T>) {
+        for &(rows, cols) in [(1, 1), (3, 5), (17, 1021), (64, 4096)].iter() {
+            let files: Vec<Vec<u8>> = [NonTemporalStores::Never, NonTemporalStores::Always].iter().map(|&stores| {
+                let path = TempMatrixPath::new();
+                let mut a = Dense::<T>::create(path.path(), rows, cols).unwrap();
+                a.set_stream_options(StreamOptions { non_temporal_stores: stores, ..StreamOptions::default() });
+                write(&mut a);
+                a.flush().unwrap();
+                fs::read(path.path()).unwrap()
+            }).collect();
+            assert!(files[0] == files[1], "{}x{} matrices differ", rows, cols);
+        }
+    }
+
+    #[test]
+    fn non_temporal_fills_match_ordinary_ones() {
+        check_non_temporal_writes::<f32, _>(|a| a.fill(-1.5).unwrap());
+        check_non_temporal_writes::<f64, _>(|a| a.fill(0.1).unwrap());
+        check_non_temporal_writes::<f32, _>(|a| a.set_identity().unwrap());
+        check_non_temporal_writes::<f64, _>(|a| {
+            a.transpose();
+            a.set_identity().unwrap()
+        });
+    }
+
+    #[test]
+    fn non_temporal_randomisation_matches_ordinary_randomisation() {
+        check_non_temporal_writes::<f32, _>(|a| a.randomise_seeded(11));
+        check_non_temporal_writes::<f64, _>(|a| a.randomise_seeded(12));
+        let normal = Distribution::Normal { mean: 3.0, std_dev: 0.5 };
+        check_non_temporal_writes::<f64, _>(|a| a.randomise_seeded_with(13, normal).unwrap());
+        #[cfg(feature = "rayon")]
+        check_non_temporal_writes::<f32, _>(|a| a.par_randomise_seeded(14));
+    }
+
+    #[test]
+    fn only_large_writes_are_non_temporal_by_default() {
+        let a = Dense::<f32>::create_anonymous(4, 4).unwrap();
+        assert!(!a.non_temporal_writes());
+        let big = NON_TEMPORAL_MIN_BYTES / 4 / 1024;
+        let path = TempMatrixPath::new();
+        let b = Dense::<f32>::create(path.path(), big, 1024).unwrap();
+        assert!(b.non_temporal_writes());
+        let c = Dense::<f32>::create(path.path(), big - 1, 1024).unwrap();
+        assert!(!c.non_temporal_writes());
+    }
+
+    #[test]
+    fn copy_on_write_changes_never_reach_the_file() {
+        let path = TempMatrixPath::new();
+        drop(Dense::<f64>::from_fn(path.path(), 300, 200, |i, j| (i * 200 + j) as f64).unwrap());
+        let original = fs::read(path.path()).unwrap();
+        let mut cow = Dense::<f64>::open_cow(path.path()).unwrap();
+        assert!(!cow.is_persistent());
+        assert!(format!("{:?}", cow).contains("persistent: false"), "{:?}", cow);
+        cow.set(0, 0, -1.0);
+        cow.fill(7.0).unwrap();
+        cow.set(299, 199, -2.0);
+        cow.transpose();
+        cow.flush().unwrap();
+        assert!(cow.private_bytes().unwrap() >= 300 * 200 * 8);
+        // A matrix sharing the file sees what it holds, not the private copies.
+        let shared = Dense::<f64>::open(path.path()).unwrap();
+        assert!(shared.is_persistent());
+        assert!(format!("{:?}", shared).contains("persistent: true"), "{:?}", shared);
+        assert_eq!(shared.private_bytes().unwrap(), 0);
+        assert_eq!((shared.num_rows(), shared.get(299, 199)), (300, (299 * 200 + 199) as f64));
+        // Reads, copies and discards of the copy-on-write matrix see its own writes.
+        assert_eq!((cow.num_rows(), cow.get(199, 299), cow.get(5, 6)), (200, -2.0, 7.0));
+        cow.discard_range(0..200).unwrap();
+        let copy = cow.copy_at(None).unwrap();
+        assert_eq!((copy.get(199, 299), copy.get(0, 0)), (-2.0, 7.0));
+        drop(cow);
+        drop(shared);
+        assert!(fs::read(path.path()).unwrap() == original);
+        let reopened = Dense::<f64>::open(path.path()).unwrap();
+        assert!(!reopened.is_transposed());
+        assert!((0..300).all(|i| (0..200).all(|j| reopened.get(i, j) == (i * 200 + j) as f64)));
+    }
+
+    #[test]
+    fn copy_on_write_matrices_cannot_grow() {
+        let (path, other) = (TempMatrixPath::new(), TempMatrixPath::new());
+        drop(Dense::<f32>::create(path.path(), 4, 3).unwrap());
+        let source = Dense::<f32>::create(other.path(), 2, 3).unwrap();
+        let mut cow = Dense::<f32>::open_cow(path.path()).unwrap();
+        match cow.merge_all(&[&source]) {
+            Err(OoclaError::InvalidArgument(message)) => assert!(message.contains("copy-on-write"), "{}", message),
+            other => panic!("expected an invalid argument, got {:?}", other),
+        }
+        assert_eq!(cow.num_rows(), 4);
+        assert_eq!(fs::metadata(path.path()).unwrap().len(), HEADER_SIZE as u64 + 4 * 3 * 4);
+    }
+
+    #[test]
+    fn private_pages_are_counted_as_they_are_written() {
+        let path = TempMatrixPath::new();
+        drop(Dense::<f64>::create(path.path(), 1024, 512).unwrap());
+        let mut cow = Dense::<f64>::open_cow(path.path()).unwrap();
+        cow.set_private_warning_bytes(Some(1 << 40));
+        let before = cow.private_bytes().unwrap();
+        for i in (0..1024).step_by(8) {
+            cow.set(i, 0, 1.0);
+        }
+        let written = 128 * page_size() as u64;
+        let after = cow.private_bytes().unwrap();
+        assert!(after >= before + written && after <= before + written + 2 * page_size() as u64, "{} -> {}",
+                before, after);
+    }
+
+    fn storage_bits<T: SupportedType>(a: &Dense<T>) -> Vec<u64> {
+        a.storage().iter().map(|x| x.to_f64().to_bits()).collect()
+    }
+
+    // Matrices of several bands of lines each, one with a partial last band, filled on pools of
+    // 1, 2 and 8 threads.
+    #[cfg(feature = "rayon")]
+    fn check_parallel_randomisation<T: SupportedType + Rand + Send>(rows: u64, cols: u64, transposed: bool) {
+        let mut expected = Dense::<T>::create_anonymous(rows, cols).unwrap();
+        if transposed {
+            expected.transpose();
+        }
+        assert!(expected.major_len() > 2 * expected.random_band_lines() as u64);
+        expected.randomise_seeded(21);
+        let expected = storage_bits(&expected);
+        for &threads in [1, 2, 8].iter() {
+            let pool = ::rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let mut a = Dense::<T>::create_anonymous(rows, cols).unwrap();
+            if transposed {
+                a.transpose();
+            }
+            pool.install(|| a.par_randomise_seeded(21));
+            assert!(storage_bits(&a) == expected, "{}x{} differs on {} threads", rows, cols, threads);
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_randomisation_is_the_same_on_any_number_of_threads() {
+        check_parallel_randomisation::<f64>(700, 1000, false);
+        check_parallel_randomisation::<f32>(3000, 333, false);
+        check_parallel_randomisation::<f32>(1000, 2500, true);
+    }
+
+    #[test]
+    fn seeded_randomisation_depends_on_the_seed_and_band() {
+        let randomised = |seed| {
+            let mut a = Dense::<f64>::create_anonymous(400, 1000).unwrap();
+            a.randomise_seeded(seed);
+            a
+        };
+        let a = randomised(5);
+        assert!(storage_bits(&a) == storage_bits(&randomised(5)));
+        assert!(storage_bits(&a) != storage_bits(&randomised(6)));
+        // Each band draws from a stream of its own, so no band repeats the first.
+        let band = a.random_band_lines() * 1000;
+        let bands: Vec<&[f64]> = a.storage().chunks(band).collect();
+        assert!(bands.len() > 2);
+        assert!(bands[1..].iter().all(|other| other[..16] != bands[0][..16]));
+        assert!(a.storage().iter().all(|&x| (0.0..1.0).contains(&x)));
+    }
+
+    // A sink that, when asked for the `at`th time whether to stop, hands over to another thread
+    // and waits for it to say it has done what it wanted, so that thread acts midway through an
+    // operation however fast it runs.
+    struct Rendezvous {
+        checks: Cell<u64>,
+        at: u64,
+        reached: mpsc::Sender<()>,
+        resume: mpsc::Receiver<()>,
+    }
+
+    impl Progress for Rendezvous {
+        fn report(&self, _done: u64, _total: Option<u64>, _unit: ProgressUnit) {}
+
+        fn is_cancelled(&self) -> bool {
+            self.checks.set(self.checks.get() + 1);
+            if self.checks.get() == self.at {
+                self.reached.send(()).unwrap();
+                self.resume.recv().unwrap();
+            }
+            false
+        }
+    }
+
+    // Copies `a` to `path`, cancelling the copy from another thread about 16 MiB into it.
+    fn copy_cancelled_midway(a: &Dense<f64>, path: Option<&Path>) -> Result<Dense<f64>, OoclaError> {
+        let token = CancelToken::new();
+        let ((reached, on_reached), (resumed, resume)) = (mpsc::channel(), mpsc::channel());
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                if on_reached.recv().is_ok() {
+                    token.cancel();
+                    resumed.send(()).unwrap();
+                }
+            })
+        };
+        let sink = token.with_progress(Rendezvous { checks: Cell::new(0), at: 16, reached, resume });
+        let result = a.copy_at_progress(path, &sink);
+        drop(sink);
+        canceller.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn copies_cancelled_from_another_thread_leave_no_destination() {
+        let path = TempMatrixPath::new();
+        let mut a = Dense::<f64>::from_fn(path.path(), 4096, 2048, |i, j| (i ^ j) as f64).unwrap();
+        let original = fs::read(path.path()).unwrap();
+        for &transposed in [false, true].iter() {
+            if transposed {
+                a.transpose();
+            }
+            let destination = TempMatrixPath::new();
+            match copy_cancelled_midway(&a, Some(destination.path())) {
+                Err(OoclaError::Cancelled) => {}
+                other => panic!("expected cancellation, got {:?}", other.map(|_| ())),
+            }
+            assert!(fs::symlink_metadata(destination.path()).is_err(), "the destination was left behind");
+            match copy_cancelled_midway(&a, None) {
+                Err(OoclaError::Cancelled) => {}
+                other => panic!("expected cancellation, got {:?}", other.map(|_| ())),
+            }
+        }
+        a.transpose();
+        a.flush().unwrap();
+        assert!(fs::read(path.path()).unwrap() == original);
+        // A token nobody cancels lets the copy finish.
+        let destination = TempMatrixPath::new();
+        let copy = a.copy_at_progress(Some(destination.path()), &CancelToken::new()).unwrap();
+        assert_eq!(copy.get(4095, 2047), (4095 ^ 2047) as f64);
+    }
+
+    #[test]
+    fn matrices_move_between_threads() {
+        let path = TempMatrixPath::new();
+        let a = Dense::<f64>::create(path.path(), 500, 300).unwrap();
+        // Written on one thread and read back on the one that made it.
+        let a = thread::spawn(move || {
+            let mut a = a;
+            a.fill_with(|i, j| (i * 300 + j) as f64).unwrap();
+            a
+        }).join().unwrap();
+        assert_eq!(a.get(499, 299), (499 * 300 + 299) as f64);
+        // A copy in anonymous memory, and the matrix itself, are both unmapped on another thread.
+        let copy = a.copy_at(None).unwrap();
+        let sums = thread::spawn(move || {
+            let sums = (a.row_iter().map(|row| row[1]).sum::<f64>(), copy.row_iter().map(|row| row[1]).sum::<f64>());
+            drop((a, copy));
+            sums
+        }).join().unwrap();
+        let expected = (0..500).map(|i| (i * 300 + 1) as f64).sum::<f64>();
+        assert_eq!(sums, (expected, expected));
+        let b = Dense::<f64>::open(path.path()).unwrap();
+        assert!(elements(&b).iter().enumerate().all(|(i, row)| row[299] == (i * 300 + 299) as f64));
+    }
+
+    // The header and rows of a matrix being appended to, in ordinary memory rather than a mapping,
+    // which miri can't make, so that the test below also runs under it and checks the ordering.
+    #[derive(Clone, Copy)]
+    struct Appending {
+        header: *mut MatrixHeader,
+        rows: *mut u64,
+    }
+
+    unsafe impl Send for Appending {}
+
+    #[test]
+    fn header_reads_race_row_appends_safely() {
+        const ROWS: u64 = 64;
+        const COLS: u64 = 3;
+        let mut header = Box::new(MatrixHeader {
+            magic: 0,
+            num_rows: 0,
+            num_cols: COLS,
+            representation: FloatType::UInt64,
+            lda: COLS,
+            transposed: false,
+        });
+        let mut rows = vec![0u64; (ROWS * COLS) as usize];
+        // Both threads use copies of the same pointers, so neither's accesses invalidate the other's.
+        let writer = Appending { header: &mut *header, rows: rows.as_mut_ptr() };
+        let reader = writer;
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                for i in 0..ROWS {
+                    for j in 0..COLS {
+                        unsafe {
+                            writer.rows.add((i * COLS + j) as usize).write(i * COLS + j + 1);
+                        }
+                    }
+                    unsafe {
+                        MatrixHeader::store_num_rows(writer.header, i + 1);
+                    }
+                }
+            });
+            scope.spawn(move || {
+                let mut seen = 0;
+                while seen < ROWS {
+                    let count = unsafe {
+                        MatrixHeader::load_num_rows(reader.header)
+                    };
+                    assert!(count >= seen && count <= ROWS, "{} rows after {}", count, seen);
+                    // The other fields are read as the row count changes beside them.
+                    let (cols, lda) = unsafe {
+                        ((*reader.header).num_cols, (*reader.header).lda)
+                    };
+                    assert_eq!((cols, lda), (COLS, COLS));
+                    for k in 0..count * COLS {
+                        assert_eq!(unsafe { reader.rows.add(k as usize).read() }, k + 1);
+                    }
+                    seen = count;
+                    thread::yield_now();
+                }
+            });
+        });
+        assert_eq!(header.num_rows, ROWS);
+        assert!(rows.iter().enumerate().all(|(k, &x)| x == k as u64 + 1));
+    }
+
+    // The shapes the element iterators are tested on, as (rows, cols), each laid out by rows and by
+    // columns, with and without padding between the lines.
+    const ITERATED_SHAPES: [(usize, usize); 8] = [(0, 0), (0, 3), (3, 0), (1, 1), (1, 4), (4, 1), (3, 5), (6, 2)];
+
+    // Storage for a rows x cols matrix laid out as a Dense lays it out, each element holding its
+    // offset, with the lda and logical position of every element in storage order. Iterators are
+    // made over it directly rather than through a mapping, which miri can't make, so that these
+    // tests also run under miri and check that every offset stays within the storage.
+    fn iterated_storage(rows: usize, cols: usize, padding: usize, transposed: bool)
+        -> (Vec<usize>, usize, Vec<(usize, usize)>) {
+        let (major, minor) = if transposed { (cols, rows) } else { (rows, cols) };
+        let lda = minor + padding;
+        let positions = (0..major).flat_map(|m| (0..minor).map(move |n| if transposed { (n, m) } else { (m, n) }));
+        ((0..major * lda).collect(), lda, positions.collect())
+    }
+
+    #[test]
+    fn element_iterators_visit_every_element_once_from_the_origin() {
+        for &(rows, cols) in ITERATED_SHAPES.iter() {
+            for &(padding, transposed) in [(0, false), (0, true), (2, false), (2, true)].iter() {
+                let (storage, lda, positions) = iterated_storage(rows, cols, padding, transposed);
+                let mut iter = ElementIter {
+                    lifetime: PhantomData,
+                    generator: ElementIterCommon::new(rows, cols, lda, transposed),
+                    data: storage.as_ptr(),
+                };
+                let mut visited = Vec::new();
+                while let Some(&offset) = iter.next() {
+                    let position = (iter.get_row(), iter.get_col());
+                    assert_eq!(offset, position.0 * if transposed { 1 } else { lda } +
+                                       position.1 * if transposed { lda } else { 1 });
+                    visited.push(position);
+                }
+                assert!(iter.next().is_none());
+                let case = format!("{}x{}, padding {}, transposed {}", rows, cols, padding, transposed);
+                assert_eq!(visited.len(), rows * cols, "{}", case);
+                assert_eq!(visited, positions, "{}", case);
+                if rows * cols > 0 {
+                    assert_eq!(visited[0], (0, 0), "{}", case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutable_element_iterators_write_every_element_once_and_no_padding() {
+        for &(rows, cols) in ITERATED_SHAPES.iter() {
+            for &(padding, transposed) in [(0, false), (0, true), (2, false), (2, true)].iter() {
+                let (mut storage, lda, positions) = iterated_storage(rows, cols, padding, transposed);
+                let original = storage.clone();
+                let mut iter = ElementIterMut {
+                    lifetime: PhantomData,
+                    data: storage.as_mut_ptr(),
+                    generator: ElementIterCommon::new(rows, cols, lda, transposed),
+                };
+                let mut visited = Vec::new();
+                while let Some(element) = iter.next() {
+                    *element = usize::MAX - *element;
+                    visited.push((iter.get_row(), iter.get_col()));
+                }
+                assert!(iter.next().is_none());
+                let case = format!("{}x{}, padding {}, transposed {}", rows, cols, padding, transposed);
+                assert_eq!(visited, positions, "{}", case);
+                // Elements are flipped exactly once and padding, at minor offsets past the last
+                // element of a line, is left alone.
+                let minor = if transposed { rows } else { cols };
+                for (k, (&before, &after)) in original.iter().zip(&storage).enumerate() {
+                    let expected = if k % lda < minor { usize::MAX - before } else { before };
+                    assert_eq!(after, expected, "{}, offset {}", case, k);
+                }
+            }
+        }
+    }
+}
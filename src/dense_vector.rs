@@ -0,0 +1,86 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use std::path::Path;
+
+// An on-disk vector, stored as an n x 1 matrix file so that the same file can also be opened
+// as a Dense. Used for per-row outputs, such as cluster labels, that may not fit in memory.
+pub struct DenseVector<T> {
+    inner: Dense<T>,
+}
+
+impl<T: StorageType> DenseVector<T> {
+    pub fn create(path: &Path, len: u64) -> Result<DenseVector<T>, OoclaError> {
+        Ok(DenseVector { inner: Dense::create(path, len, 1)? })
+    }
+
+    pub fn create_anonymous(len: u64) -> Result<DenseVector<T>, OoclaError> {
+        Ok(DenseVector { inner: Dense::create_anonymous(len, 1)? })
+    }
+
+    pub fn create_at(path: Option<&Path>, len: u64) -> Result<DenseVector<T>, OoclaError> {
+        Ok(DenseVector { inner: Dense::create_at(path, len, 1)? })
+    }
+
+    // Opens a single-column matrix file whose elements are contiguous.
+    pub fn open(path: &Path) -> Result<DenseVector<T>, OoclaError> {
+        DenseVector::from_matrix(Dense::open(path)?)
+    }
+
+    pub fn from_matrix(matrix: Dense<T>) -> Result<DenseVector<T>, OoclaError> {
+        if matrix.num_cols() != 1 {
+            return Err(OoclaError::ShapeMismatch {
+                expected: (matrix.num_rows(), 1),
+                found: (matrix.num_rows(), matrix.num_cols()),
+            });
+        }
+        if !matrix.is_transposed() && matrix.num_rows() > 1 && matrix.lda() != 1 {
+            return Err(OoclaError::InvalidFormat(format!("vector elements are {} apart rather than contiguous",
+                                                         matrix.lda())));
+        }
+        Ok(DenseVector { inner: matrix })
+    }
+
+    pub fn into_matrix(self) -> Dense<T> {
+        self.inner
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inner.num_rows()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: u64) -> T {
+        self.inner.get(i, 0)
+    }
+
+    pub fn set(&mut self, i: u64, value: T) {
+        self.inner.set(i, 0, value);
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        let len = self.len() as usize;
+        if len == 0 {
+            return &[];
+        }
+        if self.inner.is_transposed() {
+            &self.inner.major_slice(0)[..len]
+        } else {
+            &self.inner.storage()[..len]
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        let len = self.len() as usize;
+        if len == 0 {
+            return &mut [];
+        }
+        if self.inner.is_transposed() {
+            &mut self.inner.major_slice_mut(0)[..len]
+        } else {
+            &mut self.inner.storage_mut()[..len]
+        }
+    }
+}
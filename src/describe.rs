@@ -0,0 +1,283 @@
+use dense_matrix::{FloatType, HEADER_FIELDS, HEADER_SIZE, MAGIC};
+use error::OoclaError;
+use io::sidecar::{crc32, recorded_int};
+use ops::QuantParams;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// Metadata larger than this is reported by its length alone rather than read and decoded.
+const DESCRIBE_METADATA_LIMIT: u64 = 1 << 20;
+
+// The outcome of checking one aspect of a file. Unchecked says why a check couldn't be made,
+// usually because something it depends on is itself invalid.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Check {
+    Valid,
+    Invalid(String),
+    Unchecked(String),
+}
+
+impl Check {
+    pub fn is_valid(&self) -> bool {
+        *self == Check::Valid
+    }
+}
+
+// The byte order the file appears to have been written in, judged from its magic number.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum Endianness {
+    Little,
+    Big,
+    Unknown,
+}
+
+// One field of the header: the bytes stored for it, those bytes read as a little-endian
+// integer, and what the library takes them to mean.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct HeaderField {
+    pub name: &'static str,
+    pub offset: u64,
+    pub raw: Vec<u8>,
+    pub value: Option<u64>,
+    pub interpreted: Option<String>,
+    pub check: Check,
+}
+
+// A block of the bytes following the element data.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct MetadataEntry {
+    pub offset: u64,
+    pub len: u64,
+    pub kind: String,
+    pub summary: String,
+    pub check: Check,
+}
+
+// Everything the library can tell about a matrix file, valid or not.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Description {
+    pub path: PathBuf,
+    pub file_len: u64,
+    pub header: Vec<HeaderField>,
+    pub endianness: Endianness,
+    pub element_type: Option<String>,
+    pub element_size: Option<u64>,
+    pub data_offset: u64,
+    // The length of the header and elements the header describes. Anything after them is
+    // metadata.
+    pub expected_len: Option<u64>,
+    pub length: Check,
+    pub metadata: Vec<MetadataEntry>,
+    // Matrix files carry no checksum of their own, so this compares the header with the CRC-32
+    // recorded in a sidecar next to the file, when there is one.
+    pub checksum: Check,
+}
+
+impl Description {
+    // Whether every header field and the length were checked and passed, and no metadata is
+    // known to be corrupt.
+    pub fn is_valid(&self) -> bool {
+        self.header.iter().all(|f| f.check.is_valid()) && self.length.is_valid()
+            && self.metadata.iter().all(|m| !matches!(m.check, Check::Invalid(_)))
+    }
+
+    fn field(&self, name: &str) -> Option<&HeaderField> {
+        self.header.iter().find(|f| f.name == name)
+    }
+
+    fn value(&self, name: &str) -> Option<u64> {
+        self.field(name).filter(|f| f.check.is_valid()).and_then(|f| f.value)
+    }
+}
+
+fn element_size(representation: FloatType) -> u64 {
+    match representation {
+        FloatType::Single | FloatType::UInt32 => 4,
+        FloatType::Double | FloatType::UInt64 => 8,
+        FloatType::Int8 => 1,
+    }
+}
+
+fn read_header_fields(header: &[u8]) -> Vec<HeaderField> {
+    HEADER_FIELDS.iter().map(|&(name, offset, size)| {
+        let raw = header.get(offset..offset + size).map_or_else(Vec::new, |bytes| bytes.to_vec());
+        let value = if raw.is_empty() {
+            None
+        } else {
+            Some(raw.iter().rev().fold(0u64, |v, &b| v << 8 | u64::from(b)))
+        };
+        let check = if value.is_some() {
+            Check::Valid
+        } else {
+            Check::Unchecked("the field lies beyond the end of the file".to_string())
+        };
+        HeaderField { name, offset: offset as u64, raw, value, interpreted: value.map(|v| v.to_string()), check }
+    }).collect()
+}
+
+fn interpret_header(d: &mut Description) {
+    let transposed = match d.value("transposed") {
+        Some(0) => Some(false),
+        Some(1) => Some(true),
+        _ => None,
+    };
+    let (major, minor) = match (d.value("num_rows"), d.value("num_cols"), transposed) {
+        (Some(rows), Some(cols), Some(false)) => (Some(rows), Some(cols)),
+        (Some(rows), Some(cols), Some(true)) => (Some(cols), Some(rows)),
+        _ => (None, None),
+    };
+    let swapped_magic = MAGIC.swap_bytes();
+    for field in &mut d.header {
+        let value = match field.value {
+            Some(value) => value,
+            None => continue,
+        };
+        match field.name {
+            "magic" => {
+                field.interpreted = Some(String::from_utf8_lossy(&field.raw).into_owned());
+                if value == MAGIC {
+                    d.endianness = Endianness::Little;
+                } else if value == swapped_magic {
+                    d.endianness = Endianness::Big;
+                    field.check = Check::Invalid("the magic number is byte-swapped, so the file was written on a \
+                                                  big-endian machine".to_string());
+                } else {
+                    field.check = Check::Invalid(format!("expected {:#x}", MAGIC));
+                }
+            }
+            "representation" => match FloatType::from_code(value as u32) {
+                Some(representation) => {
+                    field.interpreted = Some(format!("{:?}", representation));
+                    d.element_type = field.interpreted.clone();
+                    d.element_size = Some(element_size(representation));
+                }
+                None => {
+                    field.interpreted = None;
+                    field.check = Check::Invalid(format!("unknown element type code {}", value));
+                }
+            },
+            "lda" => match minor {
+                Some(minor) if value < minor => {
+                    field.check = Check::Invalid(format!("less than the {} elements of each stored line", minor));
+                }
+                Some(_) => {}
+                None => field.check = Check::Unchecked("the shape or transposed flag is unreadable".to_string()),
+            },
+            "transposed" => match value {
+                0 | 1 => field.interpreted = Some((value == 1).to_string()),
+                _ => {
+                    field.interpreted = None;
+                    field.check = Check::Invalid("expected 0 or 1".to_string());
+                }
+            },
+            _ => {}
+        }
+    }
+    let lda = d.value("lda");
+    d.expected_len = match (major, lda, d.element_size) {
+        (Some(major), Some(lda), Some(size)) => {
+            lda.checked_mul(major).and_then(|n| n.checked_mul(size)).and_then(|n| n.checked_add(HEADER_SIZE as u64))
+        }
+        _ => None,
+    };
+    d.length = match d.expected_len {
+        Some(expected) if d.file_len < expected => {
+            Check::Invalid(format!("{} bytes short of the header and elements", expected - d.file_len))
+        }
+        Some(_) => Check::Valid,
+        None if major.is_some() && lda.is_some() && d.element_size.is_some() => {
+            Check::Invalid("the header describes more bytes than a file can hold".to_string())
+        }
+        None => Check::Unchecked("the shape, leading dimension or element type is invalid".to_string()),
+    };
+}
+
+fn describe_metadata(file: &mut File, d: &Description) -> Result<Vec<MetadataEntry>, OoclaError> {
+    let start = match (d.expected_len, d.length.is_valid()) {
+        (Some(start), true) if start < d.file_len => start,
+        _ => return Ok(Vec::new()),
+    };
+    let len = d.file_len - start;
+    let mut entry = MetadataEntry {
+        offset: start,
+        len,
+        kind: "unknown".to_string(),
+        summary: format!("{} bytes", len),
+        check: Check::Unchecked("the contents are not a kind the library recognises".to_string()),
+    };
+    if len > DESCRIBE_METADATA_LIMIT {
+        entry.check = Check::Unchecked(format!("more than {} bytes were not read", DESCRIBE_METADATA_LIMIT));
+        return Ok(vec![entry]);
+    }
+    let mut bytes = Vec::with_capacity(len as usize);
+    file.seek(SeekFrom::Start(start))?;
+    file.take(len).read_to_end(&mut bytes)?;
+    let cols = d.value("num_cols").unwrap_or(0);
+    match QuantParams::from_metadata(&bytes, cols) {
+        Ok(Some(params)) => {
+            entry.kind = "quantisation parameters".to_string();
+            entry.summary = format!("{} scale and zero point pair(s){}", params.scales.len(),
+                                    if params.per_column { ", one per column" } else { "" });
+            entry.check = Check::Valid;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            entry.kind = "quantisation parameters".to_string();
+            entry.check = Check::Invalid(e.to_string());
+        }
+    }
+    Ok(vec![entry])
+}
+
+fn check_sidecar(path: &Path, header: &[u8]) -> Check {
+    let mut sidecar = OsString::from(path.as_os_str());
+    sidecar.push(".json");
+    let sidecar = PathBuf::from(sidecar);
+    if !sidecar.exists() {
+        return Check::Unchecked("the format stores no checksum and there is no sidecar".to_string());
+    }
+    if header.len() < HEADER_SIZE {
+        return Check::Unchecked("the header is incomplete".to_string());
+    }
+    let found = crc32(&header[..HEADER_SIZE]);
+    match recorded_int(&sidecar, "header_crc32") {
+        Ok(Some(recorded)) if recorded == found => Check::Valid,
+        Ok(Some(recorded)) => Check::Invalid(format!("header CRC-32 is {:#010x} but the sidecar recorded {:#010x}",
+                                                     found, recorded)),
+        Ok(None) => Check::Unchecked("the sidecar records no header checksum".to_string()),
+        Err(e) => Check::Unchecked(format!("the sidecar is unreadable: {}", e)),
+    }
+}
+
+// Describes the matrix file at `path` field by field without opening it as a matrix, so it
+// succeeds on files too damaged to open, reporting each problem it finds instead. Only failing
+// to read the file at all is an error.
+pub fn describe(path: &Path) -> Result<Description, OoclaError> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    (&mut file).take(HEADER_SIZE as u64).read_to_end(&mut header)?;
+    let mut description = Description {
+        path: path.to_path_buf(),
+        file_len,
+        header: read_header_fields(&header),
+        endianness: Endianness::Unknown,
+        element_type: None,
+        element_size: None,
+        data_offset: HEADER_SIZE as u64,
+        expected_len: None,
+        length: Check::Unchecked(String::new()),
+        metadata: Vec::new(),
+        checksum: check_sidecar(path, &header),
+    };
+    interpret_header(&mut description);
+    description.metadata = describe_metadata(&mut file, &description)?;
+    Ok(description)
+}
@@ -0,0 +1,397 @@
+use dense_matrix::{Dense, HEADER_SIZE, StorageType, page_size};
+use error::OoclaError;
+use nix::libc;
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::{cmp, mem, slice};
+
+// The most bytes read or written at a time, and the most of whole lines a pass over a matrix
+// reads at a time.
+pub(crate) const DIRECT_BLOCK_BYTES: usize = 1 << 20;
+
+static REFUSED: Once = Once::new();
+
+// A zeroed buffer whose address and length are multiples of the page size, which satisfies the
+// 512-byte or 4 KiB alignment O_DIRECT needs of buffers, offsets and lengths.
+pub(crate) struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    pub(crate) fn new(len: usize) -> AlignedBuf {
+        let page = page_size();
+        let layout = Layout::from_size_align(cmp::max(len, 1).div_ceil(page) * page, page).unwrap();
+        let ptr = unsafe {
+            alloc::alloc_zeroed(layout)
+        };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    #[cfg(feature = "io-uring")]
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.ptr, self.len())
+        }
+    }
+
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.ptr, self.len())
+        }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe {
+            alloc::dealloc(self.ptr, self.layout);
+        }
+    }
+}
+
+// Opens `path` as `options` say with O_DIRECT added, or without it where the filesystem refuses
+// it, warning on stderr the first time that happens.
+pub(crate) fn open(path: &Path, options: &OpenOptions) -> io::Result<File> {
+    match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            REFUSED.call_once(|| {
+                eprintln!("warning: O_DIRECT refused for {} ({}); falling back to buffered I/O, and not reporting \
+                           further refusals", path.display(), e);
+            });
+            options.open(path)
+        }
+        result => result,
+    }
+}
+
+// A descriptor of its own for `file` to read with O_DIRECT, reopened through /proc since the
+// flags of `file` are shared with everything else using it.
+pub(crate) fn reopen(file: &File) -> io::Result<File> {
+    open(&PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd())), OpenOptions::new().read(true))
+}
+
+// Reads `range` of `file` into `buf` as a read of whole pages, returning its bytes. Whatever of
+// the range lies beyond a short read is filled with an ordinary read from `fallback`, whose
+// offsets needn't be aligned.
+pub(crate) fn read_range<'a>(file: &File, fallback: &File, range: &Range<u64>, buf: &'a mut AlignedBuf)
+    -> io::Result<&'a [u8]> {
+    let page = page_size() as u64;
+    let aligned_start = range.start / page * page;
+    let len = (range.end.div_ceil(page) * page - aligned_start) as usize;
+    if buf.len() < len {
+        *buf = AlignedBuf::new(len);
+    }
+    let read = file.read_at(&mut buf.as_mut_slice()[..len], aligned_start)?;
+    complete(fallback, range, aligned_start, read, buf)
+}
+
+// Returns the bytes of `range` from a buffer that a read from `aligned_start` filled `read`
+// bytes of, first filling whatever of the range lies beyond that with an ordinary read.
+pub(crate) fn complete<'a>(fallback: &File, range: &Range<u64>, aligned_start: u64, read: usize,
+                           buf: &'a mut AlignedBuf) -> io::Result<&'a [u8]> {
+    let (begin, end) = ((range.start - aligned_start) as usize, (range.end - aligned_start) as usize);
+    let bytes = &mut buf.as_mut_slice()[..end];
+    let read = cmp::max(read, begin);
+    if read < end {
+        fallback.read_exact_at(&mut bytes[read..], aligned_start + read as u64)?;
+    }
+    Ok(&bytes[begin..])
+}
+
+// The byte ranges of the file of A that hold the major lines `lines`, in blocks of whole lines.
+pub(crate) fn line_ranges<T>(a: &Dense<T>, lines: Range<u64>) -> Vec<Range<u64>> {
+    let line_bytes = a.lda() * mem::size_of::<T>() as u64;
+    let block_lines = cmp::max(1, DIRECT_BLOCK_BYTES as u64 / cmp::max(1, line_bytes));
+    (lines.start..lines.end).step_by(block_lines as usize)
+        .map(|first| {
+            let end = cmp::min(first + block_lines, lines.end);
+            HEADER_SIZE as u64 + first * line_bytes..HEADER_SIZE as u64 + end * line_bytes
+        })
+        .collect()
+}
+
+// Passes each line held by `bytes`, a block of whole lines read from A's file, to `f`,
+// numbering them from `*line`.
+pub(crate) fn deliver_lines<T, F>(a: &Dense<T>, bytes: &[u8], line: &mut u64, f: &mut F) -> Result<(), OoclaError>
+    where T: StorageType, F: FnMut(u64, &[T]) -> Result<(), OoclaError> {
+    let line_bytes = a.lda() as usize * mem::size_of::<T>();
+    let minor = a.minor_len() as usize;
+    // Each block starts a whole number of lines after the 64-byte header, and its buffer on a
+    // page boundary, so every line is aligned for T.
+    for chunk in bytes.chunks(line_bytes) {
+        let values = unsafe {
+            slice::from_raw_parts(chunk.as_ptr() as *const T, minor)
+        };
+        f(*line, values)?;
+        *line += 1;
+    }
+    Ok(())
+}
+
+// Passes the major lines `lines` of A to `f` in order, reading them from its file with O_DIRECT
+// in blocks of whole lines.
+pub(crate) fn stream_lines<T, F>(a: &Dense<T>, lines: Range<u64>, mut f: F) -> Result<(), OoclaError>
+    where T: StorageType, F: FnMut(u64, &[T]) -> Result<(), OoclaError> {
+    if a.lda() == 0 {
+        return lines.into_iter().try_for_each(|line| f(line, &[]));
+    }
+    // Reads from the file don't see writes still only in the mapping.
+    a.flush()?;
+    let file = reopen(a.file())?;
+    let mut buf = AlignedBuf::new(DIRECT_BLOCK_BYTES);
+    let mut line = lines.start;
+    for range in line_ranges(a, lines) {
+        let bytes = read_range(&file, a.file(), &range, &mut buf)?;
+        deliver_lines(a, bytes, &mut line, &mut f)?;
+    }
+    Ok(())
+}
+
+// Reads a file with O_DIRECT a block at a time from the start, for streaming imports and
+// conversions.
+pub(crate) struct DirectReader {
+    file: File,
+    buf: AlignedBuf,
+    // The file offset of the next block, the bytes of the buffer holding data and those already
+    // consumed, and whether the last block has been read.
+    offset: u64,
+    filled: usize,
+    pos: usize,
+    eof: bool,
+}
+
+impl DirectReader {
+    pub(crate) fn open(path: &Path) -> io::Result<DirectReader> {
+        Ok(DirectReader {
+            file: open(path, OpenOptions::new().read(true))?,
+            buf: AlignedBuf::new(DIRECT_BLOCK_BYTES),
+            offset: 0,
+            filled: 0,
+            pos: 0,
+            eof: false,
+        })
+    }
+
+    // Reads the next block. Only the last block of the file is short, so every read starts at an
+    // aligned offset.
+    fn refill(&mut self) -> io::Result<()> {
+        let (mut filled, len) = (0, self.buf.len());
+        while !self.eof && filled < len {
+            match self.file.read_at(&mut self.buf.as_mut_slice()[filled..], self.offset + filled as u64) {
+                Ok(0) => self.eof = true,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.offset += filled as u64;
+        self.filled = filled;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.filled {
+            self.refill()?;
+        }
+        let n = cmp::min(out.len(), self.filled - self.pos);
+        out[..n].copy_from_slice(&self.buf.as_slice()[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// Writes a file with O_DIRECT a block at a time. Only whole blocks can be written until finish,
+// which pads the last one out and then cuts the file back to the bytes written, so the file can
+// have any length.
+pub(crate) struct DirectWriter {
+    file: File,
+    buf: AlignedBuf,
+    len: usize,
+    written: u64,
+}
+
+impl DirectWriter {
+    pub(crate) fn create(path: &Path) -> io::Result<DirectWriter> {
+        Ok(DirectWriter {
+            file: open(path, OpenOptions::new().read(true).write(true).create(true).truncate(true))?,
+            buf: AlignedBuf::new(DIRECT_BLOCK_BYTES),
+            len: 0,
+            written: 0,
+        })
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<File> {
+        if self.len > 0 {
+            let padded = self.len.div_ceil(page_size()) * page_size();
+            for byte in self.buf.as_mut_slice()[self.len..padded].iter_mut() {
+                *byte = 0;
+            }
+            self.file.write_all_at(&self.buf.as_slice()[..padded], self.written)?;
+            self.written += self.len as u64;
+        }
+        self.file.set_len(self.written)?;
+        Ok(self.file)
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = cmp::min(data.len(), self.buf.len() - self.len);
+        self.buf.as_mut_slice()[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        if self.len == self.buf.len() {
+            self.file.write_all_at(self.buf.as_slice(), self.written)?;
+            self.written += self.len as u64;
+            self.len = 0;
+        }
+        Ok(n)
+    }
+
+    // Only whole blocks can be written with O_DIRECT, so a partial one waits for finish.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{StreamOptions, TempMatrixPath};
+    use io::npy;
+    use std::fs;
+
+    // Bytes that differ from their neighbours at every offset, so a misplaced block shows.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 131 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn aligned_bufs_cover_whole_pages() {
+        let page = page_size();
+        for &len in [0, 1, 511, page, page + 1, 3 * page - 7].iter() {
+            let buf = AlignedBuf::new(len);
+            assert_eq!(buf.as_slice().as_ptr() as usize % page, 0);
+            assert_eq!(buf.len() % page, 0);
+            assert!(buf.len() >= len && buf.len() < len + page + 1, "{} bytes for {}", buf.len(), len);
+            assert!(buf.as_slice().iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn unaligned_ranges_read_their_bytes() {
+        let page = page_size() as u64;
+        let path = TempMatrixPath::new();
+        // A file that ends part way into a page, so the read of its last page is short.
+        let bytes = pattern(5 * page as usize + 700);
+        fs::write(path.path(), &bytes).unwrap();
+        let fallback = File::open(path.path()).unwrap();
+        let file = reopen(&fallback).unwrap();
+        let len = bytes.len() as u64;
+        let ranges = [0..1, 1..page, page - 1..page + 1, 64..2 * page + 3, 3 * page..4 * page, len - 1..len,
+                      page + 5..len, 17..17];
+        let mut buf = AlignedBuf::new(page as usize);
+        for range in ranges.iter() {
+            let read = read_range(&file, &fallback, range, &mut buf).unwrap();
+            assert!(read == &bytes[range.start as usize..range.end as usize], "range {:?} differs", range);
+        }
+    }
+
+    #[test]
+    fn streamed_lines_of_unaligned_data_regions_match_the_mapping() {
+        // Data regions of 60 bytes, and of several blocks of lines ending part way
+        // into a page, neither a multiple of 512 bytes.
+        for &(rows, cols) in [(3, 5), (1000, 333)].iter() {
+            let path = TempMatrixPath::new();
+            let mut a = Dense::<f64>::from_fn(path.path(), rows, cols, |i, j| (i * cols + j) as f64).unwrap();
+            for &transposed in [false, true].iter() {
+                if transposed {
+                    a.transpose();
+                }
+                a.set_stream_options(StreamOptions { direct_io: true, ..StreamOptions::default() });
+                let mut next = 0;
+                a.stream_major(0..a.major_len(), |line, values| {
+                    assert_eq!(line, next);
+                    assert!(values == a.major_slice(line), "line {} of {}x{} differs", line, rows, cols);
+                    next += 1;
+                    Ok(())
+                }).unwrap();
+                assert_eq!(next, a.major_len());
+            }
+        }
+    }
+
+    #[test]
+    fn line_ranges_cover_the_data_region_exactly() {
+        let path = TempMatrixPath::new();
+        let a = Dense::<f32>::create(path.path(), 1001, 777).unwrap();
+        let line_bytes = 777 * 4;
+        for lines in [0..1001, 5..6, 999..1001, 10..10].iter() {
+            let ranges = line_ranges(&a, lines.clone());
+            let mut offset = HEADER_SIZE as u64 + lines.start * line_bytes;
+            for range in ranges.iter() {
+                assert_eq!(range.start, offset);
+                assert_eq!((range.end - range.start) % line_bytes, 0);
+                assert!(range.end - range.start <= DIRECT_BLOCK_BYTES as u64);
+                offset = range.end;
+            }
+            assert_eq!(offset, HEADER_SIZE as u64 + lines.end * line_bytes);
+        }
+    }
+
+    #[test]
+    fn writes_of_any_length_are_cut_back_and_read_again() {
+        let page = page_size();
+        for &len in [0, 1, 511, page, DIRECT_BLOCK_BYTES, DIRECT_BLOCK_BYTES + 4097].iter() {
+            let path = TempMatrixPath::new();
+            let bytes = pattern(len);
+            let mut writer = DirectWriter::create(path.path()).unwrap();
+            // Pieces that straddle both pages and blocks.
+            for piece in bytes.chunks(3001) {
+                writer.write_all(piece).unwrap();
+            }
+            writer.finish().unwrap();
+            assert_eq!(fs::metadata(path.path()).unwrap().len(), len as u64);
+            let mut read = Vec::new();
+            DirectReader::open(path.path()).unwrap().read_to_end(&mut read).unwrap();
+            assert!(read == bytes, "{} bytes differ", len);
+        }
+    }
+
+    #[test]
+    fn direct_exports_and_imports_match_buffered_ones() {
+        // 127x61 f32 leaves a data region of 30988 bytes and a file of 31116 with the header.
+        let path = TempMatrixPath::new();
+        let mut a = Dense::<f32>::from_fn(path.path(), 127, 61, |i, j| (i * 61 + j) as f32).unwrap();
+        let (buffered, direct) = (TempMatrixPath::new(), TempMatrixPath::new());
+        npy::export(&a, buffered.path()).unwrap();
+        a.set_stream_options(StreamOptions { direct_io: true, ..StreamOptions::default() });
+        npy::export(&a, direct.path()).unwrap();
+        assert_eq!(fs::read(direct.path()).unwrap(), fs::read(buffered.path()).unwrap());
+        let imported_path = TempMatrixPath::new();
+        let options = StreamOptions { direct_io: true, ..StreamOptions::default() };
+        let b = npy::import_with::<f32>(direct.path(), imported_path.path(), options).unwrap();
+        assert_eq!((b.num_rows(), b.num_cols()), (127, 61));
+        assert!((0..127).all(|i| b.major_slice(i) == a.major_slice(i)));
+    }
+}
@@ -0,0 +1,327 @@
+use dense_matrix::{FloatType, HEADER_FIELDS, HEADER_SIZE, MAGIC, StreamOptions};
+use direct::{DirectReader, DirectWriter};
+use error::OoclaError;
+use io::npy::read_exact_or_truncated;
+use io_stats;
+use progress::{Meter, NoProgress, Progress, ProgressUnit};
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Bytes of elements swapped per read and write.
+const CONVERT_CHUNK_BYTES: usize = 1 << 20;
+
+fn element_size(representation: FloatType) -> usize {
+    match representation {
+        FloatType::Single | FloatType::UInt32 => 4,
+        FloatType::Double | FloatType::UInt64 => 8,
+        FloatType::Int8 => 1,
+    }
+}
+
+// A header as read from a file, in this machine's byte order, and whether the file's own order
+// is the opposite.
+struct NativeHeader {
+    bytes: [u8; HEADER_SIZE],
+    foreign: bool,
+    element_size: usize,
+    data_len: u64,
+}
+
+fn field(header: &[u8; HEADER_SIZE], name: &str) -> u64 {
+    let &(_, offset, size) = HEADER_FIELDS.iter().find(|f| f.0 == name).unwrap();
+    let mut word = [0u8; 8];
+    word[..size].copy_from_slice(&header[offset..offset + size]);
+    if cfg!(target_endian = "big") {
+        word[..size].reverse();
+    }
+    u64::from_le_bytes(word)
+}
+
+// Reads the header of `src`, swapping each field into native order when the magic number shows
+// the file was written on a machine of the other endianness, and checks it describes a file no
+// longer than `file_len`.
+fn read_native_header<R: Read>(src: &mut R, file_len: u64) -> Result<NativeHeader, OoclaError> {
+    let mut bytes = [0u8; HEADER_SIZE];
+    read_exact_or_truncated(src, &mut bytes, "matrix header")?;
+    let magic = field(&bytes, "magic");
+    let foreign = if magic == MAGIC {
+        false
+    } else if magic.swap_bytes() == MAGIC {
+        true
+    } else {
+        return Err(OoclaError::InvalidFormat(format!("bad magic number {:#x} in either byte order", magic)));
+    };
+    if foreign {
+        for &(_, offset, size) in HEADER_FIELDS.iter() {
+            bytes[offset..offset + size].reverse();
+        }
+    }
+    let code = field(&bytes, "representation") as u32;
+    let representation = FloatType::from_code(code)
+        .ok_or_else(|| OoclaError::InvalidFormat(format!("unknown element representation {}", code)))?;
+    let (rows, cols, lda) = (field(&bytes, "num_rows"), field(&bytes, "num_cols"), field(&bytes, "lda"));
+    let (major, minor) = match field(&bytes, "transposed") {
+        0 => (rows, cols),
+        1 => (cols, rows),
+        t => return Err(OoclaError::InvalidFormat(format!("invalid transposed flag {}", t))),
+    };
+    if lda < minor {
+        return Err(OoclaError::InvalidFormat(format!("leading dimension {} is less than {}", lda, minor)));
+    }
+    let element_size = element_size(representation);
+    let data_len = lda.checked_mul(major).and_then(|n| n.checked_mul(element_size as u64))
+        .filter(|&len| len <= file_len.saturating_sub(HEADER_SIZE as u64))
+        .ok_or_else(|| OoclaError::InvalidFormat(format!("{} byte file is too short for a {}x{} matrix", file_len,
+                                                         rows, cols)))?;
+    Ok(NativeHeader { bytes, foreign, element_size, data_len })
+}
+
+fn write_converted<R: Read, W: Write>(src: &mut R, header: &NativeHeader, writer: &mut W, progress: &dyn Progress)
+    -> Result<(), OoclaError> {
+    let mut meter = Meter::new(progress, Some(header.data_len), ProgressUnit::Bytes);
+    writer.write_all(&header.bytes)?;
+    // A whole number of elements per chunk, so none straddles two.
+    let chunk_len = CONVERT_CHUNK_BYTES / header.element_size * header.element_size;
+    let mut chunk = vec![0u8; chunk_len];
+    let mut remaining = header.data_len;
+    while remaining > 0 {
+        let bytes = &mut chunk[..cmp::min(chunk_len as u64, remaining) as usize];
+        read_exact_or_truncated(src, bytes, "matrix data")?;
+        io_stats::count_read(bytes.len());
+        if header.foreign {
+            for element in bytes.chunks_mut(header.element_size) {
+                element.reverse();
+            }
+        }
+        writer.write_all(bytes)?;
+        io_stats::count_written(bytes.len());
+        remaining -= bytes.len() as u64;
+        meter.advance(bytes.len() as u64)?;
+    }
+    // Metadata formats fix their own byte order, so whatever follows the elements is copied as is.
+    io::copy(src, writer)?;
+    meter.finish();
+    Ok(())
+}
+
+fn write_file<R: Read>(src: &mut R, header: &NativeHeader, dst: &Path, direct_io: bool, progress: &dyn Progress)
+    -> Result<(), OoclaError> {
+    if direct_io {
+        let mut writer = DirectWriter::create(dst)?;
+        write_converted(src, header, &mut writer, progress)?;
+        writer.finish()?.sync_all()?;
+        return Ok(());
+    }
+    let mut writer = BufWriter::with_capacity(CONVERT_CHUNK_BYTES, File::create(dst)?);
+    write_converted(src, header, &mut writer, progress)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+// Writes a copy of the matrix file `src` to `dst` in this machine's byte order, swapping the
+// header fields and every element when `src` was written on a machine of the other
+// endianness. A file already in native order is copied unchanged. The header is checked before
+// `dst` is created, and a partly written `dst` is removed on failure.
+pub fn convert_endianness(src: &Path, dst: &Path) -> Result<(), OoclaError> {
+    convert_endianness_with(src, dst, StreamOptions::default())
+}
+
+// As convert_endianness, with the options the conversion follows. With direct_io both files are
+// accessed with O_DIRECT.
+pub fn convert_endianness_with(src: &Path, dst: &Path, options: StreamOptions) -> Result<(), OoclaError> {
+    convert_endianness_progress(src, dst, options, &NoProgress)
+}
+
+// As convert_endianness_with, reporting the bytes of elements converted to `progress`. On
+// cancellation, as on any other error, the partly written `dst` is removed.
+pub fn convert_endianness_progress(src: &Path, dst: &Path, options: StreamOptions, progress: &dyn Progress)
+    -> Result<(), OoclaError> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("convert_endianness", src = %src.display(), dst = %dst.display()).entered();
+    let file_len = fs::metadata(src)?.len();
+    if options.direct_io {
+        convert_from(&mut DirectReader::open(src)?, file_len, src, dst, true, progress)
+    } else {
+        convert_from(&mut File::open(src)?, file_len, src, dst, false, progress)
+    }
+}
+
+fn convert_from<R: Read>(reader: &mut R, file_len: u64, src: &Path, dst: &Path, direct_io: bool,
+                         progress: &dyn Progress) -> Result<(), OoclaError> {
+    let header = read_native_header(reader, file_len)?;
+    if fs::canonicalize(dst).ok() == Some(fs::canonicalize(src)?) {
+        return Err(OoclaError::InvalidArgument(format!("{} would be overwritten while it is read; use \
+                                                        convert_endianness_inplace", src.display())));
+    }
+    write_file(reader, &header, dst, direct_io, progress).inspect_err(|_| {
+        let _ = fs::remove_file(dst);
+    })
+}
+
+// Converts the matrix file at `path` to this machine's byte order in place. The converted copy
+// is written beside it and renamed over it, so the file is never left half converted.
+pub fn convert_endianness_inplace(path: &Path) -> Result<(), OoclaError> {
+    let mut reader = File::open(path)?;
+    let file_len = reader.metadata()?.len();
+    let header = read_native_header(&mut reader, file_len)?;
+    if !header.foreign {
+        return Ok(());
+    }
+    let mut partial = OsString::from(path.as_os_str());
+    partial.push(format!(".partial-{}", process::id()));
+    let partial = PathBuf::from(partial);
+    write_file(&mut reader, &header, &partial, false, &NoProgress)
+        .and_then(|_| Ok(fs::rename(&partial, path)?))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&partial);
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{Dense, TempMatrixPath};
+    use progress::CancelToken;
+
+    const METADATA: &[u8] = b"trailing bytes";
+
+    // A matrix file as a big-endian machine writes it, built field by field: the header, then
+    // `major` lines of `lda` elements of which the first `minor` are given by `f`, then METADATA.
+    fn big_endian_file(representation: u32, rows: u64, cols: u64, lda: u64, transposed: bool,
+                       element: &dyn Fn(u64, u64) -> Vec<u8>) -> Vec<u8> {
+        let mut bytes = vec![0u8; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&MAGIC.to_be_bytes());
+        bytes[8..16].copy_from_slice(&rows.to_be_bytes());
+        bytes[16..24].copy_from_slice(&cols.to_be_bytes());
+        bytes[24..28].copy_from_slice(&representation.to_be_bytes());
+        bytes[32..40].copy_from_slice(&lda.to_be_bytes());
+        bytes[40] = transposed as u8;
+        let (major, minor) = if transposed { (cols, rows) } else { (rows, cols) };
+        for line in 0..major {
+            for k in 0..lda {
+                let value = element(line, k);
+                bytes.extend(if k < minor { value } else { vec![0xee; value.len()] });
+            }
+        }
+        bytes.extend_from_slice(METADATA);
+        bytes
+    }
+
+    // A 3x4 double matrix with a padded leading dimension.
+    fn doubles() -> Vec<u8> {
+        big_endian_file(1, 3, 4, 5, false, &|i, j| (i as f64 * 10.0 - j as f64 / 4.0).to_be_bytes().to_vec())
+    }
+
+    fn check_doubles(path: &Path) {
+        let a = Dense::<f64>::open(path).unwrap();
+        assert_eq!((a.num_rows(), a.num_cols(), a.is_transposed()), (3, 4, false));
+        for (i, row) in a.row_iter().enumerate() {
+            let expected: Vec<f64> = (0..4).map(|j| i as f64 * 10.0 - j as f64 / 4.0).collect();
+            assert_eq!(&*row, &expected[..], "row {}", i);
+        }
+        assert!(fs::read(path).unwrap().ends_with(METADATA));
+    }
+
+    // Files beside `path` left by an in-place conversion.
+    fn leftovers(path: &Path) -> Vec<PathBuf> {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        fs::read_dir(path.parent().unwrap()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with(&format!("{}.partial", name)))
+            .collect()
+    }
+
+    #[test]
+    fn big_endian_files_are_converted_and_readable() {
+        let (src, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        fs::write(src.path(), doubles()).unwrap();
+        convert_endianness(src.path(), dst.path()).unwrap();
+        check_doubles(dst.path());
+        let direct = TempMatrixPath::new();
+        let options = StreamOptions { direct_io: true, ..StreamOptions::default() };
+        convert_endianness_with(src.path(), direct.path(), options).unwrap();
+        assert_eq!(fs::read(direct.path()).unwrap(), fs::read(dst.path()).unwrap());
+        // Already native, so copied unchanged.
+        let again = TempMatrixPath::new();
+        convert_endianness(dst.path(), again.path()).unwrap();
+        assert_eq!(fs::read(again.path()).unwrap(), fs::read(dst.path()).unwrap());
+    }
+
+    #[test]
+    fn transposed_single_precision_files_are_converted() {
+        // Stored as 3 lines of 2, the columns of a 2x3 matrix.
+        let bytes = big_endian_file(0, 2, 3, 2, true, &|line, k| ((line * 2 + k) as f32 + 0.5).to_be_bytes().to_vec());
+        let (src, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        fs::write(src.path(), bytes).unwrap();
+        convert_endianness(src.path(), dst.path()).unwrap();
+        let a = Dense::<f32>::open(dst.path()).unwrap();
+        assert_eq!((a.num_rows(), a.num_cols(), a.is_transposed()), (2, 3, true));
+        let rows: Vec<Vec<f32>> = a.row_iter().map(|r| r.to_vec()).collect();
+        assert_eq!(rows, vec![vec![0.5, 2.5, 4.5], vec![1.5, 3.5, 5.5]]);
+    }
+
+    #[test]
+    fn inplace_conversion_replaces_the_file() {
+        let path = TempMatrixPath::new();
+        fs::write(path.path(), doubles()).unwrap();
+        convert_endianness_inplace(path.path()).unwrap();
+        check_doubles(path.path());
+        assert!(leftovers(path.path()).is_empty());
+        let converted = fs::read(path.path()).unwrap();
+        convert_endianness_inplace(path.path()).unwrap();
+        assert_eq!(fs::read(path.path()).unwrap(), converted);
+    }
+
+    #[test]
+    fn truncated_and_corrupt_files_are_rejected_without_leftovers() {
+        let good = doubles();
+        let data_end = good.len() - METADATA.len();
+        let corrupt = |offset: usize, value: u8| {
+            let mut bytes = good.clone();
+            bytes[offset] = value;
+            bytes
+        };
+        let cases = [
+            good[..HEADER_SIZE - 1].to_vec(),
+            good[..data_end - 1].to_vec(),
+            corrupt(0, 0x12),
+            corrupt(27, 9),
+            corrupt(40, 2),
+            corrupt(39, 3),
+            corrupt(15, 200),
+        ];
+        for (k, bytes) in cases.iter().enumerate() {
+            let (src, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+            fs::write(src.path(), bytes).unwrap();
+            match convert_endianness(src.path(), dst.path()) {
+                Err(OoclaError::InvalidFormat(_)) => {}
+                other => panic!("case {}: expected an invalid format, got {:?}", k, other),
+            }
+            assert!(!dst.path().exists(), "case {}", k);
+            match convert_endianness_inplace(src.path()) {
+                Err(OoclaError::InvalidFormat(_)) => {}
+                other => panic!("case {}: expected an invalid format in place, got {:?}", k, other),
+            }
+            assert_eq!(&fs::read(src.path()).unwrap(), bytes, "case {}", k);
+            assert!(leftovers(src.path()).is_empty(), "case {}", k);
+        }
+    }
+
+    #[test]
+    fn cancelled_conversions_remove_the_partial_copy() {
+        let (src, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        fs::write(src.path(), doubles()).unwrap();
+        let token = CancelToken::new();
+        token.cancel();
+        match convert_endianness_progress(src.path(), dst.path(), StreamOptions::default(), &token) {
+            Err(OoclaError::Cancelled) => {}
+            other => panic!("expected cancellation, got {:?}", other),
+        }
+        assert!(!dst.path().exists());
+    }
+}
@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use nix;
+#[cfg(feature = "arrow")]
+use arrow::error::ArrowError;
+#[cfg(feature = "hdf5")]
+use hdf5;
+use dense_matrix::FloatType;
+use ops::CgResult;
+
+#[derive(Debug)]
+pub enum OoclaError {
+    Io(io::Error),
+    Nix(nix::Error),
+    #[cfg(feature = "hdf5")]
+    Hdf5(hdf5::Error),
+    #[cfg(feature = "arrow")]
+    Arrow(ArrowError),
+    ShapeMismatch { expected: (u64, u64), found: (u64, u64) },
+    InvalidArgument(String),
+    SizeOverflow(String),
+    InvalidFormat(String),
+    Parse { line: u64, col: u64, reason: String },
+    TypeMismatch { expected: FloatType, found: FloatType },
+    UnsupportedDataset { name: String, reason: String },
+    Unsupported(String),
+    // RLIMIT_MEMLOCK is None when unlimited.
+    MemLockFailed { requested_bytes: u64, rlimit: Option<u64> },
+    BudgetExceeded { requested_bytes: u64, in_use_bytes: u64, limit_bytes: u64 },
+    // An access to a mapped matrix faulted with SIGBUS under protect::install, at `offset` bytes
+    // into the file at `path`, or into a file whose path couldn't be found.
+    BusError { path: Option<PathBuf>, offset: u64 },
+    // The operation stopped because its progress sink, such as a CancelToken, asked it to.
+    Cancelled,
+    InvalidLabel { row: u64, value: f64 },
+    Singular { index: u64 },
+    NotPositiveDefinite { at: u64 },
+    DidNotConverge { last_estimate: f64, iters: usize },
+    CgDidNotConverge(CgResult),
+    CgBreakdown { iteration: usize, best: CgResult },
+}
+
+impl fmt::Display for OoclaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OoclaError::Io(ref e) => write!(f, "I/O error: {}", e),
+            OoclaError::Nix(ref e) => write!(f, "system call failed: {}", e),
+            #[cfg(feature = "hdf5")]
+            OoclaError::Hdf5(ref e) => write!(f, "HDF5 error: {}", e),
+            #[cfg(feature = "arrow")]
+            OoclaError::Arrow(ref e) => write!(f, "Arrow error: {}", e),
+            OoclaError::ShapeMismatch { expected, found } => {
+                write!(f, "shape mismatch: expected {}x{}, found {}x{}", expected.0, expected.1, found.0, found.1)
+            }
+            OoclaError::InvalidArgument(ref msg) => write!(f, "invalid argument: {}", msg),
+            OoclaError::SizeOverflow(ref what) => write!(f, "size overflow: {}", what),
+            OoclaError::InvalidFormat(ref msg) => write!(f, "invalid matrix file: {}", msg),
+            OoclaError::Parse { line, col, ref reason } => write!(f, "line {}, column {}: {}", line, col, reason),
+            OoclaError::TypeMismatch { expected, found } => {
+                write!(f, "element type mismatch: expected {:?}, found {:?}", expected, found)
+            }
+            OoclaError::UnsupportedDataset { ref name, ref reason } => {
+                write!(f, "unsupported dataset {}: {}", name, reason)
+            }
+            OoclaError::Unsupported(ref what) => write!(f, "not supported on this system: {}", what),
+            OoclaError::MemLockFailed { requested_bytes, rlimit } => {
+                write!(f, "could not lock {} bytes in memory (RLIMIT_MEMLOCK is ", requested_bytes)?;
+                match rlimit {
+                    Some(limit) => write!(f, "{} bytes", limit)?,
+                    None => write!(f, "unlimited")?,
+                }
+                write!(f, "); raise it with ulimit -l or grant CAP_IPC_LOCK")
+            }
+            OoclaError::BudgetExceeded { requested_bytes, in_use_bytes, limit_bytes } => {
+                write!(f, "memory budget of {} bytes exceeded: {} bytes requested with {} in use", limit_bytes,
+                       requested_bytes, in_use_bytes)
+            }
+            OoclaError::BusError { ref path, offset } => {
+                match *path {
+                    Some(ref path) => write!(f, "bus error at offset {} of {}", offset, path.display())?,
+                    None => write!(f, "bus error at offset {} of a mapped matrix", offset)?,
+                }
+                write!(f, ": the file was truncated or its filesystem has no space for the page")
+            }
+            OoclaError::Cancelled => write!(f, "operation cancelled"),
+            OoclaError::InvalidLabel { row, value } => write!(f, "invalid label {} in row {}", value, row),
+            OoclaError::Singular { index } => write!(f, "matrix is singular to working precision at index {}", index),
+            OoclaError::NotPositiveDefinite { at } => write!(f, "matrix is not positive definite: non-positive pivot at index {}", at),
+            OoclaError::DidNotConverge { last_estimate, iters } => {
+                write!(f, "did not converge after {} iterations (last estimate {})", iters, last_estimate)
+            }
+            OoclaError::CgDidNotConverge(ref best) => {
+                write!(f, "conjugate gradients did not converge (best residual norm {} after {} iterations)",
+                       best.residual_norm, best.iters)
+            }
+            OoclaError::CgBreakdown { iteration, .. } => {
+                write!(f, "conjugate gradients broke down at iteration {}: matrix is not positive definite", iteration)
+            }
+        }
+    }
+}
+
+impl Error for OoclaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            OoclaError::Io(ref e) => Some(e),
+            OoclaError::Nix(ref e) => Some(e),
+            #[cfg(feature = "hdf5")]
+            OoclaError::Hdf5(ref e) => Some(e),
+            #[cfg(feature = "arrow")]
+            OoclaError::Arrow(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for OoclaError {
+    fn from(e: io::Error) -> OoclaError {
+        OoclaError::Io(e)
+    }
+}
+
+impl From<nix::Error> for OoclaError {
+    fn from(e: nix::Error) -> OoclaError {
+        OoclaError::Nix(e)
+    }
+}
+
+#[cfg(feature = "hdf5")]
+impl From<hdf5::Error> for OoclaError {
+    fn from(e: hdf5::Error) -> OoclaError {
+        OoclaError::Hdf5(e)
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl From<ArrowError> for OoclaError {
+    fn from(e: ArrowError) -> OoclaError {
+        OoclaError::Arrow(e)
+    }
+}
@@ -0,0 +1,236 @@
+// A C interface to the core matrix API, declared in include/ooc.h. Every function returns one of
+// the OOC_* status codes, and on failure the message for the calling thread can be read with
+// ooc_last_error_message. Pointer arguments must be null or valid for the access the function
+// makes, and handles must come from ooc_dense_create or ooc_dense_open and not yet be closed.
+#![allow(clippy::missing_safety_doc)]
+
+use dense_matrix::{Dense, FloatType, StorageType};
+use error::OoclaError;
+use nix::libc::{c_char, c_int, c_void};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+pub const OOC_OK: c_int = 0;
+pub const OOC_ERR_IO: c_int = 1;
+pub const OOC_ERR_SYSTEM: c_int = 2;
+pub const OOC_ERR_SHAPE: c_int = 3;
+pub const OOC_ERR_INVALID_ARGUMENT: c_int = 4;
+pub const OOC_ERR_SIZE_OVERFLOW: c_int = 5;
+pub const OOC_ERR_INVALID_FORMAT: c_int = 6;
+pub const OOC_ERR_TYPE_MISMATCH: c_int = 7;
+pub const OOC_ERR_NUMERICAL: c_int = 8;
+pub const OOC_ERR_PANIC: c_int = 9;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+// An open matrix of any element representation, behind an opaque pointer.
+pub struct OocDense(AnyDense);
+
+enum AnyDense {
+    Single(Dense<f32>),
+    Double(Dense<f64>),
+    UInt32(Dense<u32>),
+    UInt64(Dense<u64>),
+    Int8(Dense<i8>),
+}
+
+macro_rules! with_dense {
+    ($handle:expr, $a:ident => $body:expr) => {
+        match $handle {
+            AnyDense::Single(ref mut $a) => $body,
+            AnyDense::Double(ref mut $a) => $body,
+            AnyDense::UInt32(ref mut $a) => $body,
+            AnyDense::UInt64(ref mut $a) => $body,
+            AnyDense::Int8(ref mut $a) => $body,
+        }
+    };
+}
+
+fn error_code(e: &OoclaError) -> c_int {
+    match *e {
+        OoclaError::Io(_) | OoclaError::BusError { .. } => OOC_ERR_IO,
+        OoclaError::Nix(_) | OoclaError::Unsupported(_) | OoclaError::MemLockFailed { .. }
+        | OoclaError::BudgetExceeded { .. } | OoclaError::Cancelled => OOC_ERR_SYSTEM,
+        OoclaError::ShapeMismatch { .. } => OOC_ERR_SHAPE,
+        OoclaError::InvalidArgument(_) => OOC_ERR_INVALID_ARGUMENT,
+        OoclaError::SizeOverflow(_) => OOC_ERR_SIZE_OVERFLOW,
+        OoclaError::InvalidFormat(_) | OoclaError::Parse { .. } | OoclaError::UnsupportedDataset { .. } => {
+            OOC_ERR_INVALID_FORMAT
+        }
+        OoclaError::TypeMismatch { .. } => OOC_ERR_TYPE_MISMATCH,
+        _ => OOC_ERR_NUMERICAL,
+    }
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+// Runs `f`, turning its error or panic into a status code and the thread's last error message.
+fn guard<F: FnOnce() -> Result<(), OoclaError>>(f: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => OOC_OK,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            error_code(&e)
+        }
+        Err(_) => {
+            set_last_error("panic inside the ooc library".to_string());
+            OOC_ERR_PANIC
+        }
+    }
+}
+
+fn null_argument(name: &str) -> OoclaError {
+    OoclaError::InvalidArgument(format!("{} is null", name))
+}
+
+unsafe fn path_arg<'a>(path: *const c_char) -> Result<&'a Path, OoclaError> {
+    if path.is_null() {
+        return Err(null_argument("path"));
+    }
+    CStr::from_ptr(path).to_str()
+        .map(Path::new)
+        .map_err(|_| OoclaError::InvalidArgument("path is not valid UTF-8".to_string()))
+}
+
+unsafe fn handle_arg<'a>(handle: *mut OocDense) -> Result<&'a mut AnyDense, OoclaError> {
+    handle.as_mut().map(|h| &mut h.0).ok_or_else(|| null_argument("handle"))
+}
+
+unsafe fn store<T>(out: *mut T, value: T) {
+    if !out.is_null() {
+        *out = value;
+    }
+}
+
+fn wrap(a: AnyDense) -> *mut OocDense {
+    Box::into_raw(Box::new(OocDense(a)))
+}
+
+fn create_any(path: &Path, rows: u64, cols: u64, representation: FloatType) -> Result<AnyDense, OoclaError> {
+    Ok(match representation {
+        FloatType::Single => AnyDense::Single(Dense::create(path, rows, cols)?),
+        FloatType::Double => AnyDense::Double(Dense::create(path, rows, cols)?),
+        FloatType::UInt32 => AnyDense::UInt32(Dense::create(path, rows, cols)?),
+        FloatType::UInt64 => AnyDense::UInt64(Dense::create(path, rows, cols)?),
+        FloatType::Int8 => AnyDense::Int8(Dense::create(path, rows, cols)?),
+    })
+}
+
+fn open_any(path: &Path) -> Result<AnyDense, OoclaError> {
+    // Opening as double reports the representation actually stored when it differs.
+    let representation = match Dense::<f64>::open(path) {
+        Ok(a) => return Ok(AnyDense::Double(a)),
+        Err(OoclaError::TypeMismatch { found, .. }) => found,
+        Err(e) => return Err(e),
+    };
+    Ok(match representation {
+        FloatType::Single => AnyDense::Single(Dense::open(path)?),
+        FloatType::Double => AnyDense::Double(Dense::open(path)?),
+        FloatType::UInt32 => AnyDense::UInt32(Dense::open(path)?),
+        FloatType::UInt64 => AnyDense::UInt64(Dense::open(path)?),
+        FloatType::Int8 => AnyDense::Int8(Dense::open(path)?),
+    })
+}
+
+fn representation_code<T: StorageType>(_: &Dense<T>) -> u32 {
+    T::get_float_type() as u32
+}
+
+fn element_size_of<T>(_: &Dense<T>) -> usize {
+    mem::size_of::<T>()
+}
+
+// The message describing the last failure on the calling thread, valid until the thread's next
+// failing call. It is empty when nothing has failed.
+#[no_mangle]
+pub extern "C" fn ooc_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+// Creates a zeroed rows x cols matrix at `path` whose elements have the representation `dtype`,
+// one of the OOC_DTYPE_* codes.
+#[no_mangle]
+pub unsafe extern "C" fn ooc_dense_create(path: *const c_char, rows: u64, cols: u64, dtype: u32,
+                                          out: *mut *mut OocDense) -> c_int {
+    guard(|| {
+        if out.is_null() {
+            return Err(null_argument("out"));
+        }
+        let representation = FloatType::from_code(dtype)
+            .ok_or_else(|| OoclaError::InvalidArgument(format!("unknown element type code {}", dtype)))?;
+        *out = wrap(create_any(path_arg(path)?, rows, cols, representation)?);
+        Ok(())
+    })
+}
+
+// Opens the matrix at `path`, whatever its element representation.
+#[no_mangle]
+pub unsafe extern "C" fn ooc_dense_open(path: *const c_char, out: *mut *mut OocDense) -> c_int {
+    guard(|| {
+        if out.is_null() {
+            return Err(null_argument("out"));
+        }
+        *out = wrap(open_any(path_arg(path)?)?);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ooc_dense_rows(handle: *mut OocDense, rows: *mut u64) -> c_int {
+    guard(|| {
+        store(rows, with_dense!(*handle_arg(handle)?, a => a.num_rows()));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ooc_dense_cols(handle: *mut OocDense, cols: *mut u64) -> c_int {
+    guard(|| {
+        store(cols, with_dense!(*handle_arg(handle)?, a => a.num_cols()));
+        Ok(())
+    })
+}
+
+// Describes the mapped elements so callers can address them directly: element (i, j) is at
+// data + (i * lda + j) * element_size, with i and j swapped when the matrix is transposed. Any
+// output pointer may be null. The pointer is valid until the handle is closed.
+#[no_mangle]
+pub unsafe extern "C" fn ooc_dense_data_ptr(handle: *mut OocDense, data: *mut *mut c_void, lda: *mut u64,
+                                            element_size: *mut usize, dtype: *mut u32, transposed: *mut c_int)
+    -> c_int {
+    guard(|| {
+        with_dense!(*handle_arg(handle)?, a => {
+            store(dtype, representation_code(a));
+            store(lda, a.lda());
+            store(transposed, a.is_transposed() as c_int);
+            store(element_size, element_size_of(a));
+            store(data, a.storage_mut().as_mut_ptr() as *mut c_void);
+        });
+        Ok(())
+    })
+}
+
+// Writes modified elements back to the file, returning once they are on disk.
+#[no_mangle]
+pub unsafe extern "C" fn ooc_dense_flush(handle: *mut OocDense) -> c_int {
+    guard(|| with_dense!(*handle_arg(handle)?, a => a.flush()))
+}
+
+// Unmaps the matrix and frees the handle. Closing a null handle does nothing.
+#[no_mangle]
+pub unsafe extern "C" fn ooc_dense_close(handle: *mut OocDense) -> c_int {
+    guard(|| {
+        if !handle.is_null() {
+            drop(Box::from_raw(handle));
+        }
+        Ok(())
+    })
+}
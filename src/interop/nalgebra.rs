@@ -0,0 +1,109 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use nalgebra::{DMatrix, DMatrixView, DMatrixViewMut, Dyn, Scalar};
+use std::ops::Range;
+use std::path::Path;
+use tile::Tile;
+
+impl<T: Scalar> Tile<T> {
+    // A view of the tile for nalgebra. nalgebra stores by columns, so the view steps a whole row
+    // of the tile between rows and one element between columns.
+    pub fn as_dmatrix_view(&self) -> DMatrixView<'_, T, Dyn, Dyn> {
+        DMatrixView::from_slice_with_strides(&self.data, self.rows, self.cols, self.cols, 1)
+    }
+
+    pub fn as_dmatrix_view_mut(&mut self) -> DMatrixViewMut<'_, T, Dyn, Dyn> {
+        DMatrixViewMut::from_slice_with_strides_mut(&mut self.data, self.rows, self.cols, self.cols, 1)
+    }
+}
+
+impl<T: StorageType + Scalar> Dense<T> {
+    // Copies the block spanning `rows` and `cols` into a new column-major nalgebra matrix.
+    pub fn read_block_dmatrix(&self, rows: Range<u64>, cols: Range<u64>) -> DMatrix<T> {
+        assert!(rows.start <= rows.end && cols.start <= cols.end, "block ranges {:?} and {:?} are reversed",
+                rows, cols);
+        let tile = self.read_tile(rows.start, cols.start, (rows.end - rows.start) as usize,
+                                  (cols.end - cols.start) as usize);
+        DMatrix::from_row_slice(tile.rows, tile.cols, &tile.data)
+    }
+
+    // Writes `m` into the block whose top-left element is at `origin`, transposing nalgebra's
+    // column-major order into rows.
+    pub fn write_block_dmatrix(&mut self, origin: (u64, u64), m: &DMatrix<T>) {
+        self.write_tile(&Tile {
+            row: origin.0,
+            col: origin.1,
+            rows: m.nrows(),
+            cols: m.ncols(),
+            data: m.transpose().as_slice().to_vec(),
+        });
+    }
+
+    // Creates a matrix at `path` holding a copy of `m`. It is stored transposed, by columns as
+    // nalgebra is, so each column is copied in one piece.
+    pub fn from_dmatrix(path: &Path, m: &DMatrix<T>) -> Result<Dense<T>, OoclaError> {
+        let mut result = Dense::create(path, m.ncols() as u64, m.nrows() as u64)?;
+        result.transpose();
+        for (j, column) in m.as_slice().chunks(m.nrows().max(1)).enumerate() {
+            result.major_slice_mut(j as u64).copy_from_slice(column);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    #[test]
+    fn tile_views_see_rows_as_rows() {
+        let mut tile = Tile { row: 0, col: 0, rows: 2, cols: 3, data: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0] };
+        {
+            let view = tile.as_dmatrix_view();
+            assert_eq!(view.shape(), (2, 3));
+            assert_eq!((view[(0, 2)], view[(1, 0)]), (3.0, 4.0));
+        }
+        tile.as_dmatrix_view_mut()[(1, 2)] = -6.0;
+        assert_eq!(tile.data[5], -6.0);
+    }
+
+    #[test]
+    fn a_qr_of_a_tile_round_trips_through_nalgebra() {
+        let mut a = Dense::<f64>::create_anonymous(40, 30).unwrap();
+        a.randomise_seeded(12);
+        let tile = a.read_tile(5, 7, 12, 9);
+        let qr = tile.as_dmatrix_view().clone_owned().qr();
+        let (q, r) = (qr.q(), qr.r());
+        assert!(((&q * &r) - tile.as_dmatrix_view()).amax() < 1e-12);
+        // Write Q over the tile's block and R beside it, then read both back.
+        a.write_block_dmatrix((5, 7), &q);
+        a.write_block_dmatrix((5, 16), &r);
+        assert_eq!(a.read_block_dmatrix(5..17, 7..16), q);
+        assert_eq!(a.read_block_dmatrix(5..14, 16..25), r);
+        assert!((q.transpose() * &q - DMatrix::identity(9, 9)).amax() < 1e-12);
+        // Elements outside the blocks are untouched.
+        let untouched = a.read_tile(0, 0, 5, 30);
+        let original = {
+            let mut b = Dense::<f64>::create_anonymous(40, 30).unwrap();
+            b.randomise_seeded(12);
+            b.read_tile(0, 0, 5, 30)
+        };
+        assert_eq!(untouched.data, original.data);
+    }
+
+    #[test]
+    fn from_dmatrix_round_trips() {
+        let m = DMatrix::from_fn(7, 4, |i, j| (10 * i + j) as f32);
+        let path = TempMatrixPath::new();
+        let a = Dense::from_dmatrix(path.path(), &m).unwrap();
+        assert_eq!((a.num_rows(), a.num_cols()), (7, 4));
+        assert!(a.is_transposed());
+        assert_eq!(a.read_block_dmatrix(0..7, 0..4), m);
+        assert_eq!(a.read_block_dmatrix(2..5, 1..3), m.view((2, 1), (3, 2)).clone_owned());
+        let empty = DMatrix::<f32>::zeros(0, 3);
+        let path = TempMatrixPath::new();
+        let e = Dense::from_dmatrix(path.path(), &empty).unwrap();
+        assert_eq!((e.num_rows(), e.num_cols()), (0, 3));
+    }
+}
@@ -0,0 +1,150 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use ndarray::{ArrayView2, ArrayViewMut2, Ix2, ShapeBuilder, StrideShape};
+use std::path::Path;
+
+impl<T> Dense<T> {
+    // The shape and element strides of the mapping as ndarray describes them. Major lines are
+    // lda elements apart, so a transposed matrix has unit stride down its columns.
+    fn array_shape(&self) -> Result<StrideShape<Ix2>, OoclaError> {
+        let (rows, cols) = (self.num_rows(), self.num_cols());
+        let lda = self.lda();
+        let span = self.major_len().saturating_sub(1).checked_mul(lda)
+            .and_then(|end| end.checked_add(self.minor_len()))
+            .filter(|&end| end <= isize::MAX as u64);
+        if span.is_none() {
+            return Err(OoclaError::SizeOverflow(format!("a {}x{} matrix with leading dimension {} has strides \
+                                                         too large for ndarray", rows, cols, lda)));
+        }
+        let strides = if self.is_transposed() { (1, lda as usize) } else { (lda as usize, 1) };
+        Ok((rows as usize, cols as usize).strides(strides))
+    }
+
+    // A view of the mapped elements without copying them, with the strides of the storage
+    // layout, transposed or padded.
+    pub fn as_array_view(&self) -> Result<ArrayView2<'_, T>, OoclaError> {
+        let shape = self.array_shape()?;
+        ArrayView2::from_shape(shape, self.storage())
+            .map_err(|e| OoclaError::InvalidFormat(format!("matrix storage doesn't fit its layout: {}", e)))
+    }
+
+    // A mutable view of the mapped elements, so ndarray operations write through to the file.
+    pub fn as_array_view_mut(&mut self) -> Result<ArrayViewMut2<'_, T>, OoclaError> {
+        let shape = self.array_shape()?;
+        ArrayViewMut2::from_shape(shape, self.storage_mut())
+            .map_err(|e| OoclaError::InvalidFormat(format!("matrix storage doesn't fit its layout: {}", e)))
+    }
+
+    // Overwrites every element with the corresponding element of `a`, which must have the same
+    // shape. The array may have any layout.
+    pub fn copy_from_array(&mut self, a: ArrayView2<'_, T>) -> Result<(), OoclaError> where T: Clone {
+        let found = (a.nrows() as u64, a.ncols() as u64);
+        if found != (self.num_rows(), self.num_cols()) {
+            return Err(OoclaError::ShapeMismatch { expected: (self.num_rows(), self.num_cols()), found });
+        }
+        self.as_array_view_mut()?.assign(&a);
+        Ok(())
+    }
+
+    // Creates a matrix at `path` holding a copy of `a`.
+    pub fn from_array(path: &Path, a: ArrayView2<'_, T>) -> Result<Dense<T>, OoclaError> where T: StorageType {
+        let mut result = Dense::create(path, a.nrows() as u64, a.ncols() as u64)?;
+        result.copy_from_array(a)?;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{HEADER_FIELDS, TempMatrixPath};
+    use ndarray::{Array2, s};
+    use std::fs;
+
+    fn check_view_matches(a: &Dense<f64>) {
+        let view = a.as_array_view().unwrap();
+        assert_eq!(view.dim(), (a.num_rows() as usize, a.num_cols() as usize));
+        for i in 0..a.num_rows() {
+            for j in 0..a.num_cols() {
+                assert_eq!(view[[i as usize, j as usize]], a.get(i, j));
+            }
+        }
+    }
+
+    // A 3 x 3 matrix whose rows are stored four elements apart, made by narrowing a 3 x 4
+    // matrix in its header.
+    fn padded(path: &Path) -> Dense<f64> {
+        Dense::<f64>::from_fn(path, 3, 4, |i, j| (10 * i + j) as f64).unwrap().flush().unwrap();
+        let mut bytes = fs::read(path).unwrap();
+        let &(_, offset, _) = HEADER_FIELDS.iter().find(|f| f.0 == "num_cols").unwrap();
+        bytes[offset..offset + 8].copy_from_slice(&3u64.to_ne_bytes());
+        fs::write(path, &bytes).unwrap();
+        let a = Dense::open(path).unwrap();
+        assert_eq!((a.num_cols(), a.lda()), (3, 4));
+        a
+    }
+
+    #[test]
+    fn views_follow_the_storage_layout() {
+        let a = Dense::anonymous_from_fn(4, 5, |i, j| (10 * i + j) as f64);
+        check_view_matches(&a);
+        let mut t = Dense::anonymous_from_fn(4, 5, |i, j| (10 * i + j) as f64);
+        t.transpose();
+        check_view_matches(&t);
+        assert_eq!(t.as_array_view().unwrap().strides(), &[1, 5]);
+        let path = TempMatrixPath::new();
+        let p = padded(path.path());
+        check_view_matches(&p);
+        assert_eq!(p.as_array_view().unwrap().strides(), &[4, 1]);
+    }
+
+    #[test]
+    fn operations_through_a_mutable_view_change_the_file() {
+        for &transposed in &[false, true] {
+            let path = TempMatrixPath::new();
+            {
+                let mut a = Dense::<f64>::from_fn(path.path(), 3, 4, |i, j| (i * 4 + j) as f64).unwrap();
+                if transposed {
+                    a.transpose();
+                }
+                {
+                    let mut view = a.as_array_view_mut().unwrap();
+                    view.mapv_inplace(|x| 2.0 * x + 1.0);
+                    view.slice_mut(s![.., 1]).fill(-1.0);
+                }
+                a.flush().unwrap();
+            }
+            let a = Dense::<f64>::open(path.path()).unwrap();
+            let (rows, cols) = (a.num_rows(), a.num_cols());
+            for i in 0..rows {
+                for j in 0..cols {
+                    // Element (i, j) was initially at i * 4 + j of the untransposed matrix.
+                    let (r, c) = if transposed { (j, i) } else { (i, j) };
+                    let expected = if j == 1 { -1.0 } else { 2.0 * (r * 4 + c) as f64 + 1.0 };
+                    assert_eq!(a.get(i, j), expected, "transposed {} ({}, {})", transposed, i, j);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn arrays_of_any_layout_are_copied_in() {
+        let array = Array2::from_shape_fn((6, 5), |(i, j)| (10 * i + j) as f32);
+        let strided = array.slice(s![..;2, ..;-1]);
+        let path = TempMatrixPath::new();
+        let a = Dense::from_array(path.path(), strided.t()).unwrap();
+        assert_eq!((a.num_rows(), a.num_cols()), (5, 3));
+        for i in 0..5 {
+            for j in 0..3 {
+                assert_eq!(a.get(i, j), strided[[j as usize, i as usize]]);
+            }
+        }
+        let mut b = Dense::<f32>::create_anonymous(3, 5).unwrap();
+        match b.copy_from_array(strided.t()) {
+            Err(OoclaError::ShapeMismatch { expected: (3, 5), found: (5, 3) }) => {}
+            other => panic!("expected a shape mismatch, got {:?}", other),
+        }
+        b.copy_from_array(strided).unwrap();
+        assert_eq!(b.get(2, 0), 44.0);
+    }
+}
@@ -0,0 +1,152 @@
+use arrow::array::{Array, ArrayRef, AsArray, Float32Array, Float64Array};
+use arrow::compute;
+use arrow::datatypes::{DataType, Field, Float64Type, Schema};
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use dense_matrix::{Dense, FloatType, SupportedType};
+use error::OoclaError;
+use io::csv::MissingValues;
+use row_writer::RowWriter;
+use std::cmp;
+use std::collections::HashSet;
+use std::f64;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+// The memory spent on the columns of each record batch written by export.
+const ARROW_BATCH_BYTES: usize = 64 << 20;
+
+// The indices of the named columns in the schema, or of every column when none are named.
+// Each one must hold numbers.
+fn select_columns(schema: &Schema, columns: &[&str]) -> Result<Vec<usize>, OoclaError> {
+    let selected = if columns.is_empty() {
+        (0..schema.fields().len()).collect()
+    } else {
+        let mut selected = Vec::with_capacity(columns.len());
+        for &name in columns {
+            match schema.index_of(name) {
+                Ok(index) => selected.push(index),
+                Err(_) => return Err(OoclaError::InvalidArgument(format!("no column named {} in the file", name))),
+            }
+        }
+        selected
+    };
+    for &index in &selected {
+        let field = schema.field(index);
+        if !field.data_type().is_numeric() {
+            return Err(OoclaError::UnsupportedDataset {
+                name: field.name().clone(),
+                reason: format!("column type {} is not numeric", field.data_type()),
+            });
+        }
+    }
+    Ok(selected)
+}
+
+fn append_batch<T: SupportedType>(batch: &RecordBatch, selected: &[usize], names: &[String], first_row: u64,
+                                  missing: MissingValues, writer: &mut RowWriter<T>) -> Result<(), OoclaError> {
+    let cast = selected.iter()
+        .map(|&index| compute::cast(batch.column(index), &DataType::Float64))
+        .collect::<Result<Vec<ArrayRef>, _>>()?;
+    let columns: Vec<&Float64Array> = cast.iter().map(|column| column.as_primitive::<Float64Type>()).collect();
+    let mut row = vec![T::default(); columns.len()];
+    for r in 0..batch.num_rows() {
+        for (c, column) in columns.iter().enumerate() {
+            let value = if !column.is_null(r) {
+                column.value(r)
+            } else if missing == MissingValues::Nan {
+                f64::NAN
+            } else {
+                return Err(OoclaError::InvalidFormat(format!("column {} is null in row {}", names[c],
+                                                             first_row + r as u64)));
+            };
+            row[c] = T::from_f64(value);
+        }
+        writer.write_row(&row)?;
+    }
+    Ok(())
+}
+
+// Imports the named numeric columns of an Arrow IPC (Feather v2) file into a new matrix at
+// `dst`, or every column when `columns` is empty. Record batches are read one at a time and
+// their values cast to T, with nulls read as NaN or rejected according to `missing`.
+pub fn import<T: SupportedType>(file: &Path, columns: &[&str], dst: &Path, missing: MissingValues)
+    -> Result<Dense<T>, OoclaError> {
+    let reader = FileReader::try_new(BufReader::new(File::open(file)?), None)?;
+    let schema = reader.schema();
+    let selected = select_columns(&schema, columns)?;
+    let names: Vec<String> = selected.iter().map(|&index| schema.field(index).name().clone()).collect();
+    let mut writer = RowWriter::create(dst, selected.len() as u64)?;
+    for batch in reader {
+        let batch = batch?;
+        let first_row = writer.rows_written();
+        append_batch(&batch, &selected, &names, first_row, missing, &mut writer)?;
+    }
+    writer.finish()
+}
+
+fn column_array<T: SupportedType>(values: &[f64]) -> ArrayRef {
+    match T::get_float_type() {
+        FloatType::Single => Arc::new(values.iter().map(|&v| v as f32).collect::<Float32Array>()),
+        _ => Arc::new(values.iter().cloned().collect::<Float64Array>()),
+    }
+}
+
+fn write_file<T: SupportedType>(a: &Dense<T>, file: &Path, schema: Arc<Schema>) -> Result<(), OoclaError> {
+    let (rows, cols) = (a.num_rows(), a.num_cols() as usize);
+    let mut writer = FileWriter::try_new(BufWriter::new(File::create(file)?), &schema)?;
+    let batch_rows = cmp::max(1, ARROW_BATCH_BYTES / cmp::max(cols * mem::size_of::<f64>(), 1)) as u64;
+    let mut scratch = vec![T::default(); cols];
+    let mut columns = vec![Vec::new(); cols];
+    let mut first = 0;
+    // A record batch needs at least one column to carry its row count.
+    while cols > 0 && first < rows {
+        let last = cmp::min(first + batch_rows, rows);
+        for column in &mut columns {
+            column.clear();
+        }
+        for row in first..last {
+            for (column, &value) in columns.iter_mut().zip(a.row_view(row, &mut scratch)) {
+                column.push(value.to_f64());
+            }
+        }
+        let arrays = columns.iter().map(|column| column_array::<T>(column)).collect();
+        writer.write(&RecordBatch::try_new(schema.clone(), arrays)?)?;
+        first = last;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+// Writes A as an Arrow IPC (Feather v2) file with one non-nullable float column per matrix
+// column, named by `column_names` or col0, col1 and so on when none are given. Rows are written
+// in record batches of bounded size. A partly written file is removed on failure.
+pub fn export<T: SupportedType>(a: &Dense<T>, file: &Path, column_names: &[&str]) -> Result<(), OoclaError> {
+    let cols = a.num_cols() as usize;
+    if !column_names.is_empty() && column_names.len() != cols {
+        return Err(OoclaError::InvalidArgument(format!("{} column names given for {} columns",
+                                                       column_names.len(), cols)));
+    }
+    let names: Vec<String> = if column_names.is_empty() {
+        (0..cols).map(|col| format!("col{}", col)).collect()
+    } else {
+        column_names.iter().map(|name| name.to_string()).collect()
+    };
+    let mut seen = HashSet::new();
+    if let Some(name) = names.iter().find(|name| !seen.insert(name.as_str())) {
+        return Err(OoclaError::InvalidArgument(format!("duplicate column name {}", name)));
+    }
+    let data_type = match T::get_float_type() {
+        FloatType::Single => DataType::Float32,
+        _ => DataType::Float64,
+    };
+    let schema = Schema::new(names.iter().map(|name| Field::new(name.as_str(), data_type.clone(), false))
+        .collect::<Vec<_>>());
+    write_file(a, file, Arc::new(schema)).inspect_err(|_| {
+        let _ = fs::remove_file(file);
+    })
+}
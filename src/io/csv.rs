@@ -0,0 +1,295 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use progress::{Meter, NoProgress, Progress, ProgressUnit};
+use row_writer::RowWriter;
+use std::{f64, mem};
+use std::fmt::{Display, LowerExp};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloatFormat {
+    // The shortest representation that reads back as the same value.
+    Shortest,
+    // A fixed number of digits after the decimal point.
+    Fixed(usize),
+    // Scientific notation with a fixed number of digits after the decimal point.
+    Scientific(usize),
+}
+
+// What import does with an empty field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingValues {
+    Error,
+    Nan,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsvOptions {
+    // A tab gives TSV.
+    pub delimiter: u8,
+    // Whether the first line names the columns. Import skips it; export writes `column_names`,
+    // or col0, col1 and so on when none are given.
+    pub header: bool,
+    pub column_names: Vec<String>,
+    pub format: FloatFormat,
+    // The rows export writes, all of them when unset.
+    pub rows: Option<Range<u64>>,
+    pub missing: MissingValues,
+}
+
+impl Default for CsvOptions {
+    fn default() -> CsvOptions {
+        CsvOptions {
+            delimiter: b',',
+            header: false,
+            column_names: Vec::new(),
+            format: FloatFormat::Shortest,
+            rows: None,
+            missing: MissingValues::Error,
+        }
+    }
+}
+
+fn write_value<T: Display + LowerExp, W: Write>(out: &mut W, value: T, format: FloatFormat) -> Result<(), OoclaError> {
+    match format {
+        FloatFormat::Shortest => write!(out, "{}", value)?,
+        FloatFormat::Fixed(precision) => write!(out, "{:.*}", precision, value)?,
+        FloatFormat::Scientific(precision) => write!(out, "{:.*e}", precision, value)?,
+    }
+    Ok(())
+}
+
+// Writes the selected rows of A as delimited text, one line per row.
+pub fn export<T, W>(a: &Dense<T>, w: W, opts: CsvOptions) -> Result<(), OoclaError>
+    where T: SupportedType + Display + LowerExp, W: Write {
+    export_progress(a, w, opts, &NoProgress)
+}
+
+// As export, reporting the bytes of elements written out to `progress`. On cancellation `w` has
+// been given the header and a complete line for each row before the one it stopped at.
+pub fn export_progress<T, W>(a: &Dense<T>, w: W, opts: CsvOptions, progress: &dyn Progress) -> Result<(), OoclaError>
+    where T: SupportedType + Display + LowerExp, W: Write {
+    let (rows, cols) = (a.num_rows(), a.num_cols());
+    let range = opts.rows.clone().unwrap_or(0..rows);
+    if range.start > range.end || range.end > rows {
+        return Err(OoclaError::InvalidArgument(format!("rows {:?} are not within the {} rows of the matrix", range,
+                                                       rows)));
+    }
+    if !opts.column_names.is_empty() && opts.column_names.len() as u64 != cols {
+        return Err(OoclaError::InvalidArgument(format!("{} column names given for {} columns",
+                                                       opts.column_names.len(), cols)));
+    }
+    let delimiter = opts.delimiter as char;
+    let mut out = BufWriter::with_capacity(1 << 20, w);
+    if opts.header {
+        for col in 0..cols as usize {
+            if col > 0 {
+                write!(out, "{}", delimiter)?;
+            }
+            match opts.column_names.get(col) {
+                Some(name) => write!(out, "{}", name)?,
+                None => write!(out, "col{}", col)?,
+            }
+        }
+        writeln!(out)?;
+    }
+    let _advice = a.advise_row_scan();
+    let row_bytes = cols * mem::size_of::<T>() as u64;
+    let mut meter = Meter::new(progress, Some((range.end - range.start) * row_bytes), ProgressUnit::Bytes);
+    let mut scratch = vec![T::default(); cols as usize];
+    for row in range {
+        for (col, &value) in a.row_view(row, &mut scratch).iter().enumerate() {
+            if col > 0 {
+                write!(out, "{}", delimiter)?;
+            }
+            write_value(&mut out, value, opts.format)?;
+        }
+        writeln!(out)?;
+        meter.advance(row_bytes)?;
+    }
+    out.flush()?;
+    meter.finish();
+    Ok(())
+}
+
+fn parse_field(field: &[u8], line: u64, col: u64, missing: MissingValues) -> Result<f64, OoclaError> {
+    let text = String::from_utf8_lossy(field);
+    let text = text.trim();
+    let text = if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        text[1..text.len() - 1].trim()
+    } else {
+        text
+    };
+    if text.is_empty() {
+        return match missing {
+            MissingValues::Nan => Ok(f64::NAN),
+            MissingValues::Error => Err(OoclaError::Parse { line, col, reason: "missing value".to_string() }),
+        };
+    }
+    text.parse().map_err(|_| OoclaError::Parse { line, col, reason: format!("'{}' is not a number", text) })
+}
+
+// Reads delimited text into a new matrix at `dst`, a line at a time, so memory use is bounded
+// by the longest line. The first data line fixes the column count and every later line must
+// match it. Blank lines are skipped and fields may be quoted. Errors give the 1-based line and
+// column where parsing failed, and the partial output is removed.
+pub fn import<R: Read>(r: R, dst: &Path, opts: CsvOptions) -> Result<Dense<f64>, OoclaError> {
+    import_progress(r, dst, opts, &NoProgress)
+}
+
+// As import, reporting the rows imported to `progress`, as their number isn't known until the
+// input ends. Cancellation, like any other error, removes the partial output.
+pub fn import_progress<R: Read>(r: R, dst: &Path, opts: CsvOptions, progress: &dyn Progress)
+    -> Result<Dense<f64>, OoclaError> {
+    let mut meter = Meter::new(progress, None, ProgressUnit::Rows);
+    let mut reader = BufReader::with_capacity(1 << 20, r);
+    let mut line = Vec::new();
+    let mut line_no = 0u64;
+    let mut header_cols = None;
+    let mut writer: Option<RowWriter<f64>> = None;
+    let mut row = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        line_no += 1;
+        while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        let fields = line.split(|&b| b == opts.delimiter);
+        if opts.header && header_cols.is_none() {
+            header_cols = Some(fields.count() as u64);
+            continue;
+        }
+        row.clear();
+        for (col, field) in fields.enumerate() {
+            row.push(parse_field(field, line_no, col as u64 + 1, opts.missing)?);
+        }
+        let writer = match writer {
+            Some(ref mut writer) => writer,
+            None => writer.get_or_insert(RowWriter::create(dst, row.len() as u64)?),
+        };
+        if row.len() as u64 != writer.num_cols() {
+            return Err(OoclaError::Parse {
+                line: line_no,
+                col: row.len().min(writer.num_cols() as usize) as u64 + 1,
+                reason: format!("expected {} fields, found {}", writer.num_cols(), row.len()),
+            });
+        }
+        writer.write_row(&row)?;
+        meter.advance(1)?;
+    }
+    let result = match writer {
+        Some(writer) => writer.finish()?,
+        None => Dense::create(dst, 0, header_cols.unwrap_or(0))?,
+    };
+    meter.finish();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    fn exported<T: SupportedType + Display + LowerExp>(a: &Dense<T>, opts: CsvOptions) -> String {
+        let mut out = Vec::new();
+        export(a, &mut out, opts).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn imported(text: &str, opts: CsvOptions) -> Result<Dense<f64>, OoclaError> {
+        let dst = TempMatrixPath::new();
+        let result = import(text.as_bytes(), dst.path(), opts);
+        if result.is_err() {
+            assert!(!dst.path().exists());
+        }
+        result
+    }
+
+    fn elements(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    #[test]
+    fn shortest_format_round_trips_exactly() {
+        let mut a = Dense::<f64>::create_anonymous(30, 7).unwrap();
+        a.randomise_seeded(4);
+        a.set(0, 0, -1e-300);
+        a.set(1, 1, 6.02e23);
+        for &(delimiter, header) in &[(b',', false), (b'\t', true)] {
+            let opts = CsvOptions { delimiter, header, ..CsvOptions::default() };
+            let b = imported(&exported(&a, opts.clone()), opts).unwrap();
+            assert_eq!(elements(&b), elements(&a));
+        }
+    }
+
+    #[test]
+    fn headers_formats_and_row_ranges_are_written() {
+        let a = Dense::<f32>::anonymous_from_fn(3, 2, |i, j| i as f32 + 0.25 * j as f32);
+        let opts = CsvOptions {
+            header: true,
+            column_names: vec!["x".to_string(), "y".to_string()],
+            format: FloatFormat::Fixed(2),
+            rows: Some(1..3),
+            ..CsvOptions::default()
+        };
+        assert_eq!(exported(&a, opts), "x,y\n1.00,1.25\n2.00,2.25\n");
+        let opts = CsvOptions {
+            delimiter: b'\t',
+            header: true,
+            format: FloatFormat::Scientific(1),
+            ..CsvOptions::default()
+        };
+        assert_eq!(exported(&a, opts), "col0\tcol1\n0.0e0\t2.5e-1\n1.0e0\t1.2e0\n2.0e0\t2.2e0\n");
+        let mut out = Vec::new();
+        assert!(export(&a, &mut out, CsvOptions { rows: Some(2..4), ..CsvOptions::default() }).is_err());
+        let names = vec!["x".to_string()];
+        assert!(export(&a, &mut out, CsvOptions { column_names: names, ..CsvOptions::default() }).is_err());
+    }
+
+    #[test]
+    fn quoting_blank_lines_and_crlf_are_accepted() {
+        let text = "a,b,c\r\n\r\n1, \"2.5\" ,-3e2\r\n  \n4,5,6";
+        let a = imported(text, CsvOptions { header: true, ..CsvOptions::default() }).unwrap();
+        assert_eq!(elements(&a), vec![vec![1.0, 2.5, -300.0], vec![4.0, 5.0, 6.0]]);
+        let opts = CsvOptions { delimiter: b';', header: true, ..CsvOptions::default() };
+        let header_only = imported("a;b\n", opts).unwrap();
+        assert_eq!((header_only.num_rows(), header_only.num_cols()), (0, 2));
+    }
+
+    #[test]
+    fn missing_values_follow_the_policy() {
+        let text = "1,,3\n4,5,\n";
+        match imported(text, CsvOptions::default()) {
+            Err(OoclaError::Parse { line: 1, col: 2, .. }) => {}
+            other => panic!("expected a missing value at 1:2, got {:?}", other.map(|_| ())),
+        }
+        let a = imported(text, CsvOptions { missing: MissingValues::Nan, ..CsvOptions::default() }).unwrap();
+        assert!(a.get(0, 1).is_nan() && a.get(1, 2).is_nan());
+        assert_eq!([a.get(0, 0), a.get(0, 2), a.get(1, 0), a.get(1, 1)], [1.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn malformed_input_is_located_precisely() {
+        let cases: [(&str, u64, u64); 4] = [
+            ("1,2,3\n4,5\n", 2, 3),
+            ("1,2\n3,4,5\n", 2, 3),
+            ("1,2\n\n3,x\n", 3, 2),
+            ("h1,h2\n1,2\n3,4e\n", 3, 2),
+        ];
+        for &(text, line, col) in cases.iter() {
+            let opts = CsvOptions { header: text.starts_with('h'), ..CsvOptions::default() };
+            match imported(text, opts) {
+                Err(OoclaError::Parse { line: l, col: c, .. }) => assert_eq!((l, c), (line, col), "{:?}", text),
+                other => panic!("expected {:?} to fail to parse, got {:?}", text, other.map(|_| ())),
+            }
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::mem;
+
+// Back-references reach at most this far into the output.
+const WINDOW_SIZE: usize = 1 << 15;
+const MAX_MATCH: usize = 258;
+const MIN_MATCH: usize = 3;
+const MAX_CODE_BITS: usize = 15;
+// The deflater compresses its input in blocks of this many bytes, which also suits stored blocks.
+const DEFLATE_BLOCK_SIZE: usize = 1 << 15;
+const HASH_BITS: u32 = 15;
+const MAX_CHAIN: usize = 64;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99,
+                                115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025,
+                              1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+                              13, 13];
+// The order in which a dynamic block lists the code lengths of its code length alphabet.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt deflate stream: {}", msg))
+}
+
+// A canonical Huffman code, decoded a bit at a time by counting codes of each length.
+struct Huffman {
+    counts: [u16; MAX_CODE_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    // Incomplete codes are accepted, since a single distance code is legal.
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0u16; MAX_CODE_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(corrupt("over-subscribed Huffman code"));
+            }
+        }
+        let mut offsets = [0u16; MAX_CODE_BITS + 2];
+        for len in 1..=MAX_CODE_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; offsets[MAX_CODE_BITS + 1] as usize];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn fixed() -> (Huffman, Huffman) {
+        let mut lengths = [8u8; 288];
+        lengths[144..256].iter_mut().for_each(|len| *len = 9);
+        lengths[256..280].iter_mut().for_each(|len| *len = 7);
+        (Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 30]).unwrap())
+    }
+}
+
+enum Block {
+    Header,
+    Stored(usize),
+    Codes(Huffman, Huffman),
+    Done,
+}
+
+// Decompresses a raw deflate stream as it is read, holding only the window that back-references
+// can reach plus the output not yet consumed.
+pub(crate) struct Inflater<R> {
+    input: R,
+    bit_buf: u32,
+    bit_count: u32,
+    output: Vec<u8>,
+    read_pos: usize,
+    block: Block,
+    last_block: bool,
+}
+
+impl<R: Read> Inflater<R> {
+    pub(crate) fn new(input: R) -> Inflater<R> {
+        Inflater {
+            input,
+            bit_buf: 0,
+            bit_count: 0,
+            output: Vec::new(),
+            read_pos: 0,
+            block: Block::Header,
+            last_block: false,
+        }
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.input.read_exact(&mut byte).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => corrupt("unexpected end of input"),
+            _ => e,
+        })?;
+        Ok(byte[0])
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.bit_count < n {
+            self.bit_buf |= (self.byte()? as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1 << n) - 1);
+        self.bit_buf >>= n;
+        self.bit_count -= n;
+        Ok(value)
+    }
+
+    fn decode(&mut self, code: &Huffman) -> io::Result<u16> {
+        let (mut value, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &code.counts[1..] {
+            value |= self.bits(1)? as i32;
+            let count = count as i32;
+            if value - first < count {
+                return Ok(code.symbols[(index + value - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            value <<= 1;
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+
+    fn dynamic_codes(&mut self) -> io::Result<(Huffman, Huffman)> {
+        let literals = self.bits(5)? as usize + 257;
+        let distances = self.bits(5)? as usize + 1;
+        let code_lengths = self.bits(4)? as usize + 4;
+        let mut lengths = [0u8; 19];
+        for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+            lengths[symbol] = self.bits(3)? as u8;
+        }
+        let length_code = Huffman::new(&lengths)?;
+        let mut lengths = vec![0u8; literals + distances];
+        let mut i = 0;
+        while i < lengths.len() {
+            let symbol = self.decode(&length_code)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.bits(2)? as usize),
+                16 => return Err(corrupt("length repeat with no previous length")),
+                17 => (0, 3 + self.bits(3)? as usize),
+                _ => (0, 11 + self.bits(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err(corrupt("code lengths overrun"));
+            }
+            lengths[i..i + repeat].iter_mut().for_each(|len| *len = value);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(corrupt("no end-of-block code"));
+        }
+        Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?))
+    }
+
+    // Decodes some more output, returning false once the final block has ended.
+    fn step(&mut self) -> io::Result<bool> {
+        match mem::replace(&mut self.block, Block::Done) {
+            Block::Done => Ok(false),
+            Block::Header if self.last_block => Ok(false),
+            Block::Header => {
+                self.last_block = self.bits(1)? == 1;
+                self.block = match self.bits(2)? {
+                    0 => {
+                        self.bit_buf = 0;
+                        self.bit_count = 0;
+                        let mut lens = [0; 4];
+                        for byte in lens.iter_mut() {
+                            *byte = self.byte()?;
+                        }
+                        let (len, nlen) = (u16::from_le_bytes([lens[0], lens[1]]), u16::from_le_bytes([lens[2], lens[3]]));
+                        if len != !nlen {
+                            return Err(corrupt("stored block length check failed"));
+                        }
+                        Block::Stored(len as usize)
+                    }
+                    1 => {
+                        let (literals, distances) = Huffman::fixed();
+                        Block::Codes(literals, distances)
+                    }
+                    2 => {
+                        let (literals, distances) = self.dynamic_codes()?;
+                        Block::Codes(literals, distances)
+                    }
+                    _ => return Err(corrupt("invalid block type")),
+                };
+                Ok(true)
+            }
+            Block::Stored(remaining) => {
+                let start = self.output.len();
+                self.output.resize(start + remaining, 0);
+                self.input.read_exact(&mut self.output[start..]).map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => corrupt("unexpected end of input"),
+                    _ => e,
+                })?;
+                self.block = Block::Header;
+                Ok(true)
+            }
+            Block::Codes(literals, distances) => {
+                let start = self.output.len();
+                while self.output.len() - start < WINDOW_SIZE {
+                    let symbol = self.decode(&literals)? as usize;
+                    if symbol < 256 {
+                        self.output.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        self.block = Block::Header;
+                        return Ok(true);
+                    }
+                    let code = symbol - 257;
+                    if code >= LENGTH_BASE.len() {
+                        return Err(corrupt("invalid length code"));
+                    }
+                    let len = LENGTH_BASE[code] as usize + self.bits(LENGTH_EXTRA[code] as u32)? as usize;
+                    let code = self.decode(&distances)? as usize;
+                    if code >= DIST_BASE.len() {
+                        return Err(corrupt("invalid distance code"));
+                    }
+                    let dist = DIST_BASE[code] as usize + self.bits(DIST_EXTRA[code] as u32)? as usize;
+                    if dist > self.output.len() {
+                        return Err(corrupt("distance reaches before the start of the output"));
+                    }
+                    for _ in 0..len {
+                        let byte = self.output[self.output.len() - dist];
+                        self.output.push(byte);
+                    }
+                }
+                self.block = Block::Codes(literals, distances);
+                Ok(true)
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Inflater<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_pos == self.output.len() && self.read_pos > WINDOW_SIZE {
+            self.output.drain(..self.read_pos - WINDOW_SIZE);
+            self.read_pos = WINDOW_SIZE;
+        }
+        while self.read_pos == self.output.len() {
+            if !self.step()? {
+                return Ok(0);
+            }
+        }
+        let len = cmp::min(buf.len(), self.output.len() - self.read_pos);
+        buf[..len].copy_from_slice(&self.output[self.read_pos..self.read_pos + len]);
+        self.read_pos += len;
+        Ok(len)
+    }
+}
+
+enum Token {
+    Literal(u8),
+    Match { len: usize, dist: usize },
+}
+
+// The code and bit count of a symbol in the fixed literal/length code.
+fn fixed_literal_code(symbol: usize) -> (u32, u32) {
+    match symbol {
+        0..=143 => (0x30 + symbol as u32, 8),
+        144..=255 => (0x190 + symbol as u32 - 144, 9),
+        256..=279 => (symbol as u32 - 256, 7),
+        _ => (0xc0 + symbol as u32 - 280, 8),
+    }
+}
+
+fn length_code(len: usize) -> usize {
+    LENGTH_BASE.iter().rposition(|&base| base as usize <= len).unwrap()
+}
+
+fn dist_code(dist: usize) -> usize {
+    DIST_BASE.iter().rposition(|&base| base as usize <= dist).unwrap()
+}
+
+impl Token {
+    fn fixed_cost(&self) -> usize {
+        match *self {
+            Token::Literal(byte) => fixed_literal_code(byte as usize).1 as usize,
+            Token::Match { len, dist } => {
+                let (l, d) = (length_code(len), dist_code(dist));
+                fixed_literal_code(257 + l).1 as usize + LENGTH_EXTRA[l] as usize + 5 + DIST_EXTRA[d] as usize
+            }
+        }
+    }
+}
+
+fn hash_at(data: &[u8], i: usize) -> usize {
+    let key = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0]);
+    (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+// Greedy LZ77 parse of data[start..], with data[..start] available as history.
+fn find_matches(data: &[u8], start: usize) -> Vec<Token> {
+    const NONE: usize = usize::MAX;
+    let mut head = vec![NONE; 1 << HASH_BITS];
+    let mut prev = vec![NONE; data.len()];
+    let insert = |i: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        if i + MIN_MATCH <= data.len() {
+            let hash = hash_at(data, i);
+            prev[i] = head[hash];
+            head[hash] = i;
+        }
+    };
+    for i in 0..start {
+        insert(i, &mut head, &mut prev);
+    }
+    let mut tokens = Vec::new();
+    let mut i = start;
+    while i < data.len() {
+        let max_len = cmp::min(MAX_MATCH, data.len() - i);
+        let (mut best_len, mut best_dist) = (0, 0);
+        if max_len >= MIN_MATCH {
+            let mut candidate = head[hash_at(data, i)];
+            let mut chain = 0;
+            while candidate != NONE && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let len = data[candidate..].iter().zip(&data[i..i + max_len]).take_while(|(a, b)| a == b).count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+        if best_len >= MIN_MATCH {
+            tokens.push(Token::Match { len: best_len, dist: best_dist });
+            for j in i..i + best_len {
+                insert(j, &mut head, &mut prev);
+            }
+            i += best_len;
+        } else {
+            tokens.push(Token::Literal(data[i]));
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    tokens
+}
+
+// Compresses everything written to it as a raw deflate stream, using the fixed Huffman code or
+// stored blocks, whichever is smaller for each block. `finish` must be called to end the stream.
+pub(crate) struct Deflater<W: Write> {
+    output: W,
+    bit_buf: u64,
+    bit_count: u32,
+    staged: Vec<u8>,
+    // The previous block, as history for matches, followed by the input not yet compressed.
+    window: Vec<u8>,
+    history: usize,
+}
+
+impl<W: Write> Deflater<W> {
+    pub(crate) fn new(output: W) -> Deflater<W> {
+        Deflater {
+            output,
+            bit_buf: 0,
+            bit_count: 0,
+            staged: Vec::new(),
+            window: Vec::with_capacity(2 * DEFLATE_BLOCK_SIZE),
+            history: 0,
+        }
+    }
+
+    fn put_bits(&mut self, value: u32, count: u32) {
+        self.bit_buf |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.staged.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    // Huffman codes are packed starting from their most significant bit.
+    fn put_code(&mut self, code: u32, len: u32) {
+        self.put_bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.put_bits(0, 8 - self.bit_count);
+        }
+    }
+
+    fn compress_block(&mut self, last: bool) -> io::Result<()> {
+        let tokens = find_matches(&self.window, self.history);
+        let input = &self.window[self.history..];
+        let fixed_bits = 3 + tokens.iter().map(Token::fixed_cost).sum::<usize>() + 7;
+        let stored_bits = 3 + 7 + 32 + 8 * input.len();
+        if fixed_bits < stored_bits {
+            self.put_bits(last as u32 | 1 << 1, 3);
+            for token in &tokens {
+                match *token {
+                    Token::Literal(byte) => {
+                        let (code, len) = fixed_literal_code(byte as usize);
+                        self.put_code(code, len);
+                    }
+                    Token::Match { len, dist } => {
+                        let (l, d) = (length_code(len), dist_code(dist));
+                        let (code, bits) = fixed_literal_code(257 + l);
+                        self.put_code(code, bits);
+                        self.put_bits((len - LENGTH_BASE[l] as usize) as u32, LENGTH_EXTRA[l] as u32);
+                        self.put_code(d as u32, 5);
+                        self.put_bits((dist - DIST_BASE[d] as usize) as u32, DIST_EXTRA[d] as u32);
+                    }
+                }
+            }
+            self.put_code(0, 7);
+        } else {
+            let len = (self.window.len() - self.history) as u32;
+            self.put_bits(last as u32, 3);
+            self.align();
+            self.put_bits(len, 16);
+            self.put_bits(!len & 0xffff, 16);
+            self.staged.extend_from_slice(&self.window[self.history..]);
+        }
+        self.output.write_all(&self.staged)?;
+        self.staged.clear();
+        let keep = cmp::min(self.window.len(), WINDOW_SIZE);
+        let drop = self.window.len() - keep;
+        self.window.drain(..drop);
+        self.history = self.window.len();
+        Ok(())
+    }
+
+    // Ends the stream and returns the underlying writer.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.compress_block(true)?;
+        self.align();
+        self.output.write_all(&self.staged)?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+impl<W: Write> Write for Deflater<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len(), self.history + DEFLATE_BLOCK_SIZE - self.window.len());
+        self.window.extend_from_slice(&buf[..len]);
+        if self.window.len() == self.history + DEFLATE_BLOCK_SIZE {
+            self.compress_block(false)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
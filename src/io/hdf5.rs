@@ -0,0 +1,216 @@
+use dense_matrix::{Dense, FloatType, SupportedType};
+use error::OoclaError;
+use hdf5::types::{FloatSize, TypeDescriptor};
+use hdf5::{self, Dataset, Group, H5Type, Hyperslab, Selection, SliceOrIndex};
+use ndarray::ArrayView2;
+use std::cmp;
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+// The memory spent on each band of rows moved between a dataset and a matrix.
+const HDF5_BAND_BYTES: usize = 64 << 20;
+
+// A dataset found by list, named by its path within the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hdf5Dataset {
+    pub name: String,
+    pub shape: Vec<u64>,
+    pub dtype: String,
+}
+
+fn unsupported(name: &str, reason: String) -> OoclaError {
+    OoclaError::UnsupportedDataset { name: name.to_string(), reason }
+}
+
+fn representation(dataset: &Dataset, name: &str) -> Result<FloatType, OoclaError> {
+    match dataset.dtype()?.to_descriptor()? {
+        TypeDescriptor::Float(FloatSize::U4) => Ok(FloatType::Single),
+        TypeDescriptor::Float(FloatSize::U8) => Ok(FloatType::Double),
+        other => Err(unsupported(name, format!("element type {} is not float32 or float64", other))),
+    }
+}
+
+// Rows per band: a whole number of chunks, or as many as the budget allows when the dataset
+// isn't chunked, so that each chunk is read or written exactly once.
+fn band_rows(rows: usize, cols: usize, chunk_rows: Option<usize>, element_size: usize) -> usize {
+    let row_bytes = cmp::max(cols * element_size, 1);
+    match chunk_rows {
+        Some(chunk_rows) => chunk_rows * cmp::max(1, HDF5_BAND_BYTES / (chunk_rows * row_bytes)),
+        None => cmp::max(1, HDF5_BAND_BYTES / row_bytes),
+    }.min(cmp::max(rows, 1))
+}
+
+fn rows_selection(first: usize, last: usize) -> Selection {
+    Hyperslab::from(vec![SliceOrIndex::from(first..last), SliceOrIndex::from(..)]).into()
+}
+
+fn read_bands<T: SupportedType + H5Type>(source: &Dataset, result: &mut Dense<T>) -> Result<(), OoclaError> {
+    let (rows, cols) = (result.num_rows() as usize, result.num_cols() as usize);
+    if cols == 0 {
+        return Ok(());
+    }
+    let band = band_rows(rows, cols, source.chunk().map(|chunk| chunk[0]), mem::size_of::<T>());
+    for first in (0..rows).step_by(band) {
+        let last = cmp::min(first + band, rows);
+        let values = source.read_slice_2d::<T, _>(rows_selection(first, last))?.into_raw_vec();
+        for (i, row) in values.chunks(cols).enumerate() {
+            result.write_row((first + i) as u64, row);
+        }
+    }
+    Ok(())
+}
+
+// Imports the 2-D float dataset `dataset` of an HDF5 file into a new matrix at `dst`, reading
+// bands of whole chunks. The dataset's element type must match T.
+pub fn import<T: SupportedType + H5Type>(file: &Path, dataset: &str, dst: &Path) -> Result<Dense<T>, OoclaError> {
+    let source_file = hdf5::File::open(file)?;
+    let source = source_file.dataset(dataset)?;
+    let shape = source.shape();
+    if shape.len() != 2 {
+        return Err(unsupported(dataset, format!("{} dimensions, not 2", shape.len())));
+    }
+    let found = representation(&source, dataset)?;
+    if found != T::get_float_type() {
+        return Err(OoclaError::TypeMismatch { expected: T::get_float_type(), found });
+    }
+    let mut result = Dense::create(dst, shape[0] as u64, shape[1] as u64)?;
+    if let Err(e) = read_bands(&source, &mut result) {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+// Writes A as a 2-D dataset of an HDF5 file, which is created if it doesn't exist. Chunks of
+// `chunk_shape` are clipped to the matrix, and the rows are written a band of chunks at a time.
+pub fn export<T: SupportedType + H5Type>(a: &Dense<T>, file: &Path, dataset: &str, chunk_shape: (usize, usize))
+    -> Result<(), OoclaError> {
+    if chunk_shape.0 == 0 || chunk_shape.1 == 0 {
+        return Err(OoclaError::InvalidArgument(format!("chunk shape {:?} has a zero dimension", chunk_shape)));
+    }
+    let (rows, cols) = (a.num_rows() as usize, a.num_cols() as usize);
+    let target = hdf5::File::append(file)?;
+    // Chunks can't be larger than a fixed-size dataset, so an empty one is stored contiguously.
+    let chunked = rows > 0 && cols > 0;
+    let chunk = (cmp::min(chunk_shape.0, rows), cmp::min(chunk_shape.1, cols));
+    let builder = target.new_dataset::<T>();
+    let builder = if chunked { builder.chunk(chunk) } else { builder };
+    let dest = builder.shape((rows, cols)).create(dataset)?;
+    if !chunked {
+        return Ok(());
+    }
+    let band = band_rows(rows, cols, Some(chunk.0), mem::size_of::<T>());
+    let mut values = Vec::with_capacity(band * cols);
+    let mut scratch = vec![T::default(); cols];
+    for first in (0..rows).step_by(band) {
+        let last = cmp::min(first + band, rows);
+        values.clear();
+        for row in first..last {
+            values.extend_from_slice(a.row_view(row as u64, &mut scratch));
+        }
+        let view = ArrayView2::from_shape((last - first, cols), &values)
+            .expect("band holds a whole number of rows");
+        dest.write_slice(view, rows_selection(first, last))?;
+    }
+    Ok(())
+}
+
+fn collect_datasets(group: &Group, found: &mut Vec<Hdf5Dataset>) -> Result<(), OoclaError> {
+    for dataset in group.datasets()? {
+        let dtype = match dataset.dtype().and_then(|dtype| dtype.to_descriptor()) {
+            Ok(descriptor) => descriptor.to_string(),
+            Err(_) => "unknown".to_string(),
+        };
+        found.push(Hdf5Dataset {
+            name: dataset.name(),
+            shape: dataset.shape().iter().map(|&d| d as u64).collect(),
+            dtype,
+        });
+    }
+    for child in group.groups()? {
+        collect_datasets(&child, found)?;
+    }
+    Ok(())
+}
+
+// Lists every dataset in an HDF5 file, whatever its rank or element type, descending into
+// groups.
+pub fn list(file: &Path) -> Result<Vec<Hdf5Dataset>, OoclaError> {
+    let mut found = Vec::new();
+    let source = hdf5::File::open(file)?;
+    collect_datasets(&source, &mut found)?;
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    fn elements<T: SupportedType>(a: &Dense<T>) -> Vec<Vec<T>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    fn round_trip<T: SupportedType + H5Type>(a: &Dense<T>, chunk_shape: (usize, usize)) -> Dense<T> {
+        let (file, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        export(a, file.path(), "m", chunk_shape).unwrap();
+        let b = import::<T>(file.path(), "m", dst.path()).unwrap();
+        assert_eq!((b.num_rows(), b.num_cols()), (a.num_rows(), a.num_cols()));
+        b
+    }
+
+    #[test]
+    fn chunked_datasets_round_trip() {
+        let mut a = Dense::<f64>::create_anonymous(50, 30).unwrap();
+        a.randomise_seeded(6);
+        assert_eq!(elements(&round_trip(&a, (7, 4))), elements(&a));
+        // A chunk larger than the matrix is clipped to it.
+        let b = Dense::<f32>::anonymous_from_fn(3, 5, |i, j| (10 * i + j) as f32);
+        assert_eq!(elements(&round_trip(&b, (100, 100))), elements(&b));
+    }
+
+    #[test]
+    fn transposed_and_empty_matrices_round_trip() {
+        let mut a = Dense::<f32>::anonymous_from_fn(9, 4, |i, j| (i * j) as f32 - 3.0);
+        a.transpose();
+        let b = round_trip(&a, (2, 3));
+        assert!(!b.is_transposed());
+        assert_eq!(elements(&b), elements(&a));
+        let empty = Dense::<f64>::create_anonymous(0, 6).unwrap();
+        round_trip(&empty, (4, 4));
+    }
+
+    #[test]
+    fn list_finds_datasets_in_groups_and_import_rejects_unsuitable_ones() {
+        let file = TempMatrixPath::new();
+        let a = Dense::<f64>::anonymous_from_fn(4, 2, |i, j| (i + j) as f64);
+        export(&a, file.path(), "m", (2, 2)).unwrap();
+        {
+            let h5 = hdf5::File::append(file.path()).unwrap();
+            let group = h5.create_group("g").unwrap();
+            group.new_dataset::<i32>().shape(5).create("v").unwrap();
+            group.new_dataset::<i32>().shape((2, 2)).create("ints").unwrap();
+        }
+        let mut found: Vec<(String, Vec<u64>)> = list(file.path()).unwrap().into_iter()
+            .map(|d| (d.name, d.shape))
+            .collect();
+        found.sort();
+        assert_eq!(found, vec![("/g/ints".to_string(), vec![2, 2]), ("/g/v".to_string(), vec![5]),
+                               ("/m".to_string(), vec![4, 2])]);
+        let dst = TempMatrixPath::new();
+        match import::<f32>(file.path(), "m", dst.path()) {
+            Err(OoclaError::TypeMismatch { .. }) => {}
+            other => panic!("expected a type mismatch, got {:?}", other.map(|_| ())),
+        }
+        for name in ["g/v", "g/ints"].iter() {
+            match import::<f64>(file.path(), name, dst.path()) {
+                Err(OoclaError::UnsupportedDataset { .. }) => {}
+                other => panic!("expected {} to be unsupported, got {:?}", name, other.map(|_| ())),
+            }
+        }
+        assert!(!dst.path().exists());
+        assert!(export(&a, file.path(), "n", (0, 2)).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use io::npy::read_exact_or_truncated;
+#[cfg(feature = "png")]
+use png;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+fn is_png(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+}
+
+fn invalid(msg: String) -> OoclaError {
+    OoclaError::InvalidFormat(format!("PGM: {}", msg))
+}
+
+// Reads the next whitespace-separated token of a PGM header, skipping comments. The single
+// whitespace byte that ends the token is consumed, as the format requires after the maximum value.
+fn read_token<R: BufRead>(r: &mut R) -> Result<String, OoclaError> {
+    let mut token = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        read_exact_or_truncated(r, &mut byte, "PGM header")?;
+        match byte[0] {
+            b'#' => {
+                let mut comment = Vec::new();
+                r.read_until(b'\n', &mut comment)?;
+                if !token.is_empty() {
+                    break;
+                }
+            }
+            b if b.is_ascii_whitespace() => {
+                if !token.is_empty() {
+                    break;
+                }
+            }
+            b => token.push(b),
+        }
+    }
+    Ok(String::from_utf8_lossy(&token).into_owned())
+}
+
+fn read_number<R: BufRead>(r: &mut R, what: &str) -> Result<u64, OoclaError> {
+    let token = read_token(r)?;
+    token.parse().map_err(|_| invalid(format!("expected {}, found '{}'", what, token)))
+}
+
+fn fill_rows<F>(result: &mut Dense<f32>, mut next_row: F) -> Result<(), OoclaError>
+    where F: FnMut(&mut [f32]) -> Result<(), OoclaError> {
+    let mut row = vec![0.0f32; result.num_cols() as usize];
+    for i in 0..result.num_rows() {
+        next_row(&mut row)?;
+        result.write_row(i, &row);
+    }
+    Ok(())
+}
+
+fn import_pgm(src: &Path, dst: &Path, normalize: bool) -> Result<Dense<f32>, OoclaError> {
+    let mut reader = BufReader::with_capacity(1 << 20, File::open(src)?);
+    let binary = match read_token(&mut reader)?.as_str() {
+        "P5" => true,
+        "P2" => false,
+        magic => return Err(invalid(format!("'{}' is not the magic number of a grayscale PGM", magic))),
+    };
+    let width = read_number(&mut reader, "the width")?;
+    let height = read_number(&mut reader, "the height")?;
+    let max = read_number(&mut reader, "the maximum value")?;
+    if max == 0 || max > 65535 {
+        return Err(invalid(format!("maximum value {} is not between 1 and 65535", max)));
+    }
+    let divisor = if normalize { max as f32 } else { 1.0 };
+    let wide = max > 255;
+    let mut result = Dense::create(dst, height, width)?;
+    let mut bytes = vec![0u8; width as usize * if wide { 2 } else { 1 }];
+    let filled = fill_rows(&mut result, |row| {
+        if !binary {
+            for value in row.iter_mut() {
+                let sample = read_number(&mut reader, "a sample")?;
+                if sample > max {
+                    return Err(invalid(format!("sample {} exceeds the maximum value {}", sample, max)));
+                }
+                *value = sample as f32 / divisor;
+            }
+        } else if wide {
+            // Samples wider than a byte are big-endian.
+            read_exact_or_truncated(&mut reader, &mut bytes, "PGM pixels")?;
+            for (value, pair) in row.iter_mut().zip(bytes.chunks(2)) {
+                *value = f32::from(u16::from_be_bytes([pair[0], pair[1]])) / divisor;
+            }
+        } else {
+            read_exact_or_truncated(&mut reader, &mut bytes, "PGM pixels")?;
+            for (value, &sample) in row.iter_mut().zip(&bytes) {
+                *value = f32::from(sample) / divisor;
+            }
+        }
+        Ok(())
+    });
+    if let Err(e) = filled {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+#[cfg(feature = "png")]
+fn png_error<E: ::std::fmt::Display>(e: E) -> OoclaError {
+    OoclaError::InvalidFormat(format!("PNG: {}", e))
+}
+
+#[cfg(feature = "png")]
+fn import_png(src: &Path, dst: &Path, normalize: bool) -> Result<Dense<f32>, OoclaError> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(src)?));
+    // Expands palettes and grayscale below eight bits, leaving sixteen-bit samples alone.
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(png_error)?;
+    let (color, depth) = reader.output_color_type();
+    let channels = match color {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        other => {
+            return Err(OoclaError::UnsupportedDataset {
+                name: src.display().to_string(),
+                reason: format!("{:?} images are not grayscale", other),
+            });
+        }
+    };
+    let wide = depth == png::BitDepth::Sixteen;
+    let mut pixels = vec![0u8; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut pixels).map_err(png_error)?;
+    let divisor = match (normalize, wide) {
+        (false, _) => 1.0,
+        (true, false) => 255.0,
+        (true, true) => 65535.0,
+    };
+    let sample_bytes = if wide { 2 } else { 1 };
+    let mut result = Dense::create(dst, frame.height as u64, frame.width as u64)?;
+    let mut lines = pixels.chunks(frame.line_size);
+    fill_rows(&mut result, |row| {
+        let line = lines.next().expect("frame holds every row");
+        // The alpha channel of a grayscale-alpha image is dropped.
+        for (value, pixel) in row.iter_mut().zip(line.chunks(channels * sample_bytes)) {
+            let sample = if wide { u16::from_be_bytes([pixel[0], pixel[1]]) } else { u16::from(pixel[0]) };
+            *value = f32::from(sample) / divisor;
+        }
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+// Imports a grayscale image into a new matrix at `dst` with a row per line of pixels. Files
+// ending in .png are read as PNG, which needs the png feature, and anything else as binary (P5)
+// or plain (P2) PGM, including 16-bit PGM. Intensities are scaled to [0, 1] by the maximum value
+// when `normalize` is set and kept as raw sample values otherwise.
+pub fn import(src: &Path, dst: &Path, normalize: bool) -> Result<Dense<f32>, OoclaError> {
+    if !is_png(src) {
+        return import_pgm(src, dst, normalize);
+    }
+    #[cfg(feature = "png")]
+    return import_png(src, dst, normalize);
+    #[cfg(not(feature = "png"))]
+    Err(OoclaError::InvalidArgument(format!("reading {} needs the png feature", src.display())))
+}
+
+// Converts a normalised intensity to an 8-bit sample, rounding to the nearest level. With
+// `clamp` set, values below zero become black, values above one white and NaNs black; otherwise
+// any of them is an error naming the pixel.
+fn to_sample(value: f32, row: u64, col: usize, clamp: bool) -> Result<u8, OoclaError> {
+    if !(0.0..=1.0).contains(&value) && !clamp {
+        return Err(OoclaError::InvalidArgument(format!("pixel ({}, {}) has value {}, outside [0, 1]", row, col,
+                                                       value)));
+    }
+    let value = if value.is_nan() { 0.0 } else { value.clamp(0.0, 1.0) };
+    Ok((value * 255.0).round() as u8)
+}
+
+fn write_samples<F>(a: &Dense<f32>, clamp: bool, mut write_row: F) -> Result<(), OoclaError>
+    where F: FnMut(&[u8]) -> Result<(), OoclaError> {
+    let mut scratch = vec![0.0f32; a.num_cols() as usize];
+    let mut samples = Vec::with_capacity(scratch.len());
+    for row in 0..a.num_rows() {
+        samples.clear();
+        for (col, &value) in a.row_view(row, &mut scratch).iter().enumerate() {
+            samples.push(to_sample(value, row, col, clamp)?);
+        }
+        write_row(&samples)?;
+    }
+    Ok(())
+}
+
+fn export_pgm(a: &Dense<f32>, dst: &Path, clamp: bool) -> Result<(), OoclaError> {
+    let mut out = BufWriter::with_capacity(1 << 20, File::create(dst)?);
+    write!(out, "P5\n{} {}\n255\n", a.num_cols(), a.num_rows())?;
+    write_samples(a, clamp, |samples| Ok(out.write_all(samples)?))?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "png")]
+fn export_png(a: &Dense<f32>, dst: &Path, clamp: bool) -> Result<(), OoclaError> {
+    let dimension = |n: u64| if n <= u32::MAX as u64 {
+        Ok(n as u32)
+    } else {
+        Err(OoclaError::SizeOverflow(format!("dimension {} is too large for a PNG", n)))
+    };
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(dst)?), dimension(a.num_cols())?,
+                                        dimension(a.num_rows())?);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    {
+        let mut stream = writer.stream_writer().map_err(png_error)?;
+        write_samples(a, clamp, |samples| Ok(stream.write_all(samples)?))?;
+        stream.finish().map_err(png_error)?;
+    }
+    writer.finish().map_err(png_error)
+}
+
+// Writes A as an 8-bit grayscale image, PNG when `dst` ends in .png and binary PGM otherwise.
+// Values are taken as intensities in [0, 1] and scaled to 0..255; `clamp` decides whether values
+// outside that range and NaNs are clamped (NaN to black) or rejected. A partly written file is
+// removed on failure.
+pub fn export(a: &Dense<f32>, dst: &Path, clamp: bool) -> Result<(), OoclaError> {
+    #[cfg(not(feature = "png"))]
+    {
+        if is_png(dst) {
+            return Err(OoclaError::InvalidArgument(format!("writing {} needs the png feature", dst.display())));
+        }
+    }
+    #[cfg(feature = "png")]
+    let written = if is_png(dst) { export_png(a, dst, clamp) } else { export_pgm(a, dst, clamp) };
+    #[cfg(not(feature = "png"))]
+    let written = export_pgm(a, dst, clamp);
+    written.inspect_err(|_| {
+        let _ = fs::remove_file(dst);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{TempMatrixPath, temp_matrix_path};
+    use std::f32;
+    use std::path::PathBuf;
+
+    // A temporary path with the given extension, removed when dropped.
+    struct TempImage(PathBuf);
+
+    impl TempImage {
+        fn new(extension: &str) -> TempImage {
+            TempImage(temp_matrix_path().with_extension(extension))
+        }
+    }
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn elements(a: &Dense<f32>) -> Vec<Vec<f32>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    fn imported(bytes: &[u8], normalize: bool) -> Result<Dense<f32>, OoclaError> {
+        let (src, dst) = (TempImage::new("pgm"), TempMatrixPath::new());
+        fs::write(&src.0, bytes).unwrap();
+        let result = import(&src.0, dst.path(), normalize);
+        if result.is_err() {
+            assert!(!dst.path().exists());
+        }
+        result
+    }
+
+    fn round_trip(a: &Dense<f32>, extension: &str) -> Dense<f32> {
+        let (image, dst) = (TempImage::new(extension), TempMatrixPath::new());
+        export(a, &image.0, false).unwrap();
+        import(&image.0, dst.path(), true).unwrap()
+    }
+
+    // Every 8-bit level across each row, normalised.
+    fn gradient() -> Dense<f32> {
+        Dense::anonymous_from_fn(16, 256, |_, j| j as f32 / 255.0)
+    }
+
+    #[test]
+    fn gradients_round_trip_through_pgm() {
+        let a = gradient();
+        assert_eq!(elements(&round_trip(&a, "pgm")), elements(&a));
+        let (image, dst) = (TempImage::new("pgm"), TempMatrixPath::new());
+        export(&a, &image.0, false).unwrap();
+        let raw = import(&image.0, dst.path(), false).unwrap();
+        assert_eq!(raw.get(3, 200), 200.0);
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn gradients_round_trip_through_png() {
+        let a = gradient();
+        assert_eq!(elements(&round_trip(&a, "png")), elements(&a));
+    }
+
+    #[test]
+    fn two_valued_images_survive_normalization_exactly() {
+        let a = Dense::anonymous_from_fn(9, 7, |i, j| if (i + j) % 2 == 0 { 0.0 } else { 1.0 });
+        assert_eq!(elements(&round_trip(&a, "pgm")), elements(&a));
+        let b = Dense::anonymous_from_fn(5, 5, |i, j| if i == j { 51.0 / 255.0 } else { 204.0 / 255.0 });
+        assert_eq!(elements(&round_trip(&b, "pgm")), elements(&b));
+    }
+
+    #[test]
+    fn plain_and_sixteen_bit_pgms_are_read() {
+        let plain = b"P2\n# a comment\n3 2 # width and height\n10\n0 5 10\n10 5 0\n";
+        assert_eq!(elements(&imported(plain, false).unwrap()), vec![vec![0.0, 5.0, 10.0], vec![10.0, 5.0, 0.0]]);
+        assert_eq!(elements(&imported(plain, true).unwrap()), vec![vec![0.0, 0.5, 1.0], vec![1.0, 0.5, 0.0]]);
+        let mut wide = b"P5 2 1 65535\n".to_vec();
+        wide.extend_from_slice(&[0x01, 0x00, 0xff, 0xff]);
+        assert_eq!(elements(&imported(&wide, false).unwrap()), vec![vec![256.0, 65535.0]]);
+        assert_eq!(imported(&wide, true).unwrap().get(0, 1), 1.0);
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped_or_rejected() {
+        let a = Dense::anonymous_from_fn(1, 4, |_, j| [f32::NAN, -1.0, 2.0, 0.5][j as usize]);
+        let image = TempImage::new("pgm");
+        assert!(export(&a, &image.0, false).is_err());
+        assert!(!image.0.exists());
+        export(&a, &image.0, true).unwrap();
+        let bytes = fs::read(&image.0).unwrap();
+        assert_eq!(&bytes[bytes.len() - 4..], &[0, 0, 255, 128]);
+    }
+
+    #[test]
+    fn malformed_pgms_are_rejected() {
+        let cases: [&[u8]; 5] = [b"P6\n1 1\n255\n\0\0\0", b"P2\n2 1\n10\n3 11\n", b"P5\n2 2\n255\n\0\0\0",
+                                 b"P5\n1 1\n0\n\0", b"P5\n1"];
+        for bytes in cases.iter() {
+            match imported(bytes, true) {
+                Err(OoclaError::InvalidFormat(_)) => {}
+                other => panic!("expected {:?} to be rejected, got {:?}", bytes, other.map(|_| ())),
+            }
+        }
+    }
+}
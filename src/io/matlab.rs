@@ -0,0 +1,290 @@
+use dense_matrix::{Dense, as_bytes};
+use error::OoclaError;
+use io::npy::read_exact_or_truncated;
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
+
+// The memory export spends gathering columns of a matrix stored by rows.
+const MAT_EXPORT_BUDGET_BYTES: usize = 64 << 20;
+// MATLAB's namelengthmax.
+const MAX_NAME_LEN: usize = 63;
+// The machine digit of the type field: IEEE little-endian or big-endian.
+const MACHINE_LITTLE_ENDIAN: i32 = 0;
+const MACHINE_BIG_ENDIAN: i32 = 1;
+const NATIVE_MACHINE: i32 = if cfg!(target_endian = "little") { MACHINE_LITTLE_ENDIAN } else { MACHINE_BIG_ENDIAN };
+// The precision digit of the type field.
+const PRECISION_DOUBLE: i32 = 0;
+const PRECISION_SINGLE: i32 = 1;
+
+// The fixed part of a Level 4 variable, five integers in the byte order the machine digit names.
+struct MatHeader {
+    big_endian: bool,
+    precision: i32,
+    rows: u64,
+    cols: u64,
+    name: String,
+}
+
+fn invalid(msg: String) -> OoclaError {
+    OoclaError::InvalidFormat(format!("MAT-file: {}", msg))
+}
+
+fn decode_i32(bytes: &[u8], big_endian: bool) -> i32 {
+    let word = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    if big_endian { i32::from_be_bytes(word) } else { i32::from_le_bytes(word) }
+}
+
+fn read_header<R: Read>(r: &mut R) -> Result<MatHeader, OoclaError> {
+    let mut fixed = [0u8; 20];
+    read_exact_or_truncated(r, &mut fixed, "MAT-file header")?;
+    // Level 5 and 7.3 files open with a text description such as "MATLAB 5.0 MAT-file".
+    if fixed.starts_with(b"MATLAB") {
+        return Err(invalid(format!("'{}' is a Level 5 or 7.3 file; only Level 4 files (saved with -v4) can be \
+                                    imported", String::from_utf8_lossy(&fixed[..19]))));
+    }
+    // The thousands digit of the type, which is below 5000, gives the byte order, so exactly one
+    // reading of it is plausible.
+    let big_endian = match (decode_i32(&fixed, false), decode_i32(&fixed, true)) {
+        (little, _) if (0..1000).contains(&little) => false,
+        (_, big) if (1000..2000).contains(&big) => true,
+        (little, big) if (2000..5000).contains(&little) || (2000..5000).contains(&big) => {
+            return Err(invalid("VAX and Cray number formats are not supported".to_string()));
+        }
+        _ => return Err(invalid("not a Level 4 MAT-file: bad type field".to_string())),
+    };
+    let field = |i: usize| decode_i32(&fixed[4 * i..], big_endian);
+    let kind = field(0);
+    let (precision, matrix_type) = ((kind / 10) % 10, kind % 10);
+    if kind / 100 % 10 != 0 {
+        return Err(invalid(format!("type field {} is malformed", kind)));
+    }
+    if matrix_type != 0 {
+        return Err(invalid(format!("only full numeric matrices are supported, not {}",
+                                   if matrix_type == 1 { "text" } else { "sparse" })));
+    }
+    if precision != PRECISION_DOUBLE && precision != PRECISION_SINGLE {
+        return Err(invalid(format!("only double and single precision are supported, not precision {}", precision)));
+    }
+    let (rows, cols, imaginary, name_len) = (field(1), field(2), field(3), field(4));
+    if rows < 0 || cols < 0 || name_len < 1 {
+        return Err(invalid(format!("bad dimensions {}x{} or name length {}", rows, cols, name_len)));
+    }
+    if imaginary != 0 {
+        return Err(invalid("complex matrices are not supported".to_string()));
+    }
+    let mut name = vec![0u8; name_len as usize];
+    read_exact_or_truncated(r, &mut name, "MAT-file variable name")?;
+    if name.pop() != Some(0) {
+        return Err(invalid("variable name is not NUL-terminated".to_string()));
+    }
+    Ok(MatHeader {
+        big_endian,
+        precision,
+        rows: rows as u64,
+        cols: cols as u64,
+        name: String::from_utf8_lossy(&name).into_owned(),
+    })
+}
+
+fn decode_value(bytes: &[u8], precision: i32, big_endian: bool) -> f64 {
+    if precision == PRECISION_SINGLE {
+        f32::from_bits(decode_i32(bytes, big_endian) as u32) as f64
+    } else {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(bytes);
+        f64::from_bits(if big_endian { u64::from_be_bytes(word) } else { u64::from_le_bytes(word) })
+    }
+}
+
+// Imports the first variable of a Level 4 MAT-file into a new matrix at `dst`, stored by columns
+// as the file is. Single-precision values are widened; text, sparse and complex variables are
+// rejected, as are Level 5 and 7.3 files.
+pub fn import(src: &Path, dst: &Path) -> Result<Dense<f64>, OoclaError> {
+    let mut reader = BufReader::with_capacity(1 << 20, File::open(src)?);
+    let header = read_header(&mut reader)?;
+    let mut result = Dense::create(dst, header.cols, header.rows)?;
+    result.transpose();
+    let width = if header.precision == PRECISION_SINGLE { 4 } else { 8 };
+    let mut bytes = vec![0u8; header.rows as usize * width];
+    let what = format!("MAT-file variable {}", header.name);
+    let copied = (0..header.cols).try_for_each(|col| {
+        read_exact_or_truncated(&mut reader, &mut bytes, &what)?;
+        for (value, encoded) in result.major_slice_mut(col).iter_mut().zip(bytes.chunks(width)) {
+            *value = decode_value(encoded, header.precision, header.big_endian);
+        }
+        Ok(())
+    });
+    if let Err(e) = copied {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+fn validate_name(name: &str) -> Result<(), OoclaError> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && name.len() <= MAX_NAME_LEN;
+    if valid {
+        Ok(())
+    } else {
+        Err(OoclaError::InvalidArgument(format!(
+            "'{}' is not a MATLAB variable name: it must start with a letter, contain only letters, digits and \
+             underscores, and be at most {} characters long", name, MAX_NAME_LEN)))
+    }
+}
+
+fn write_file(a: &Dense<f64>, header: &[i32; 5], var_name: &str, path: &Path) -> Result<(), OoclaError> {
+    let mut out = BufWriter::with_capacity(1 << 20, File::create(path)?);
+    for field in header {
+        out.write_all(&field.to_ne_bytes())?;
+    }
+    out.write_all(var_name.as_bytes())?;
+    out.write_all(&[0])?;
+    let (rows, cols) = (a.num_rows() as usize, a.num_cols() as usize);
+    if a.is_transposed() {
+        for col in 0..a.major_len() {
+            out.write_all(as_bytes(a.major_slice(col)))?;
+        }
+    } else if rows > 0 {
+        let band = cmp::max(1, MAT_EXPORT_BUDGET_BYTES / (rows * mem::size_of::<f64>()));
+        let mut gathered = Vec::with_capacity(rows * cmp::min(band, cols));
+        for first in (0..cols).step_by(band) {
+            let width = cmp::min(band, cols - first);
+            gathered.clear();
+            gathered.resize(rows * width, 0.0);
+            for row in 0..rows {
+                for (k, &value) in a.major_slice(row as u64)[first..first + width].iter().enumerate() {
+                    gathered[k * rows + row] = value;
+                }
+            }
+            out.write_all(as_bytes(&gathered))?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Writes A as the double-precision variable `var_name` of a Level 4 MAT-file, which MATLAB and
+// Octave load directly. Values are written down each column in turn, so a matrix stored by rows is
+// gathered a band of columns at a time. A partly written file is removed on failure.
+pub fn export(a: &Dense<f64>, path: &Path, var_name: &str) -> Result<(), OoclaError> {
+    validate_name(var_name)?;
+    let dimension = |n: u64| if n <= i32::MAX as u64 {
+        Ok(n as i32)
+    } else {
+        Err(OoclaError::SizeOverflow(format!("dimension {} exceeds the 32-bit fields of a MAT-file", n)))
+    };
+    let header = [NATIVE_MACHINE * 1000 + PRECISION_DOUBLE * 10, dimension(a.num_rows())?, dimension(a.num_cols())?,
+                  0, var_name.len() as i32 + 1];
+    write_file(a, &header, var_name, path).inspect_err(|_| {
+        let _ = fs::remove_file(path);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // A Level 4 variable: the five header integers and the name, then the encoded values.
+    fn level4(header: [i32; 5], name: &str, values: &[u8], big_endian: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for field in header.iter() {
+            bytes.extend_from_slice(&if big_endian { field.to_be_bytes() } else { field.to_le_bytes() });
+        }
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(values);
+        bytes
+    }
+
+    // What Octave writes for x = [1 2 3; 4 5 6]; save -v4 x.mat x on a little-endian machine:
+    // type 0 (little-endian IEEE, double, full), 2 rows, 3 columns, no imaginary part, a name
+    // of two bytes with its NUL, then the values down each column.
+    fn octave_fixture() -> Vec<u8> {
+        let values: Vec<u8> = [1.0f64, 4.0, 2.0, 5.0, 3.0, 6.0].iter().flat_map(|x| x.to_le_bytes()).collect();
+        level4([0, 2, 3, 0, 2], "x", &values, false)
+    }
+
+    fn imported(bytes: &[u8]) -> Result<Dense<f64>, OoclaError> {
+        let (src, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        fs::write(src.path(), bytes).unwrap();
+        let result = import(src.path(), dst.path());
+        if result.is_err() {
+            assert!(!dst.path().exists());
+        }
+        result
+    }
+
+    fn exported(a: &Dense<f64>, name: &str) -> Vec<u8> {
+        let path = TempMatrixPath::new();
+        export(a, path.path(), name).unwrap();
+        fs::read(path.path()).unwrap()
+    }
+
+    fn elements(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn octave_files_round_trip_byte_for_byte() {
+        let fixture = octave_fixture();
+        let a = imported(&fixture).unwrap();
+        assert_eq!(elements(&a), vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]);
+        assert_eq!(exported(&a, "x"), fixture);
+        let by_rows = Dense::anonymous_from_fn(2, 3, |i, j| (3 * i + j + 1) as f64);
+        assert_eq!(exported(&by_rows, "x"), fixture);
+    }
+
+    #[test]
+    fn big_endian_single_precision_files_are_widened() {
+        let values: Vec<u8> = [0.5f32, -1.25, 3.0].iter().flat_map(|x| x.to_be_bytes()).collect();
+        let a = imported(&level4([1010, 1, 3, 0, 5], "vals", &values, true)).unwrap();
+        assert_eq!(elements(&a), vec![vec![0.5, -1.25, 3.0]]);
+    }
+
+    #[test]
+    fn random_matrices_round_trip() {
+        let mut a = Dense::<f64>::create_anonymous(23, 41).unwrap();
+        a.randomise_seeded(8);
+        assert_eq!(elements(&imported(&exported(&a, "data_1")).unwrap()), elements(&a));
+        a.transpose();
+        assert_eq!(elements(&imported(&exported(&a, "data_2")).unwrap()), elements(&a));
+    }
+
+    #[test]
+    fn unsupported_files_are_rejected() {
+        let mut level5 = b"MATLAB 5.0 MAT-file, Platform: GLNXA64, Created on: Mon Jan  1 00:00:00 2024".to_vec();
+        level5.resize(128, b' ');
+        let text = level4([1, 1, 2, 0, 2], "s", &[0; 16], false);
+        let sparse = level4([2, 1, 3, 0, 2], "s", &[0; 24], false);
+        let complex = level4([0, 1, 1, 1, 2], "z", &[0; 16], false);
+        let vax = level4([2000, 1, 1, 0, 2], "v", &[0; 8], false);
+        let fixture = octave_fixture();
+        let truncated = &fixture[..fixture.len() - 3];
+        for bytes in [&level5[..], &text, &sparse, &complex, &vax, truncated].iter() {
+            match imported(bytes) {
+                Err(OoclaError::InvalidFormat(_)) => {}
+                other => panic!("expected a format error, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn variable_names_are_validated() {
+        let a = Dense::<f64>::create_anonymous(1, 1).unwrap();
+        let path = TempMatrixPath::new();
+        for name in ["", "1x", "_x", "a-b", "é", &"x".repeat(64)].iter() {
+            assert!(export(&a, path.path(), name).is_err(), "{:?}", name);
+        }
+        assert!(!path.path().exists());
+        export(&a, path.path(), &"x".repeat(63)).unwrap();
+    }
+}
@@ -0,0 +1,419 @@
+use dense_matrix::{AccessPattern, Dense, SupportedType};
+use error::OoclaError;
+use sparse::CsrMatrix;
+use std::cmp;
+use std::fmt::LowerExp;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::mem;
+use std::path::Path;
+
+const BANNER: &str = "%%MatrixMarket";
+
+// The memory export_dense spends gathering columns of a matrix stored by rows.
+const MM_EXPORT_BUDGET_BYTES: usize = 64 << 20;
+
+fn write_value<T: LowerExp, W: Write>(out: &mut W, value: T) -> Result<(), OoclaError> {
+    writeln!(out, "{:e}", value)?;
+    Ok(())
+}
+
+// Writes A as a Matrix Market array file of real values. The format lists values down each column
+// in turn, so a matrix stored by rows is gathered a band of columns at a time.
+pub fn export_dense<T: SupportedType + LowerExp>(a: &Dense<T>, path: &Path) -> Result<(), OoclaError> {
+    let mut out = BufWriter::with_capacity(1 << 20, File::create(path)?);
+    writeln!(out, "{} matrix array real general", BANNER)?;
+    let (rows, cols) = (a.num_rows() as usize, a.num_cols() as usize);
+    writeln!(out, "{} {}", rows, cols)?;
+    // Each band is gathered in one pass along the storage.
+    let _advice = a.advise_scoped(AccessPattern::Sequential);
+    if a.is_transposed() {
+        for col in 0..a.major_len() {
+            for &value in a.major_slice(col) {
+                write_value(&mut out, value)?;
+            }
+        }
+    } else if rows > 0 {
+        let band = cmp::max(1, MM_EXPORT_BUDGET_BYTES / (rows * mem::size_of::<T>()));
+        let mut gathered = Vec::with_capacity(rows * cmp::min(band, cols));
+        for first in (0..cols).step_by(band) {
+            let width = cmp::min(band, cols - first);
+            gathered.clear();
+            gathered.resize(rows * width, T::default());
+            for row in 0..rows {
+                for (k, &value) in a.major_slice(row as u64)[first..first + width].iter().enumerate() {
+                    gathered[k * rows + row] = value;
+                }
+            }
+            for &value in &gathered {
+                write_value(&mut out, value)?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn invalid(line: usize, msg: String) -> OoclaError {
+    OoclaError::InvalidFormat(format!("Matrix Market line {}: {}", line, msg))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Real,
+    Integer,
+    Pattern,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Symmetry {
+    General,
+    Symmetric,
+    SkewSymmetric,
+}
+
+struct Banner {
+    coordinate: bool,
+    field: Field,
+    symmetry: Symmetry,
+}
+
+fn parse_banner(banner: &str) -> Result<Banner, OoclaError> {
+    let fields = banner.split_whitespace().map(|f| f.to_lowercase()).collect::<Vec<_>>();
+    if fields.len() != 5 || fields[0] != BANNER.to_lowercase() || fields[1] != "matrix" {
+        return Err(invalid(1, format!("expected '{} matrix <format> <field> <symmetry>'", BANNER)));
+    }
+    let coordinate = match fields[2].as_str() {
+        "array" => false,
+        "coordinate" => true,
+        other => return Err(invalid(1, format!("unknown format '{}'", other))),
+    };
+    let field = match fields[3].as_str() {
+        "real" | "double" => Field::Real,
+        "integer" => Field::Integer,
+        "pattern" => Field::Pattern,
+        "complex" => return Err(invalid(1, "complex fields are not supported".to_string())),
+        other => return Err(invalid(1, format!("unknown field '{}'", other))),
+    };
+    let symmetry = match fields[4].as_str() {
+        "general" => Symmetry::General,
+        "symmetric" => Symmetry::Symmetric,
+        "skew-symmetric" => Symmetry::SkewSymmetric,
+        other => return Err(invalid(1, format!("{} matrices are not supported", other))),
+    };
+    Ok(Banner { coordinate, field, symmetry })
+}
+
+// Parses the banner and returns it with the numbered lines that follow, less comments and blank
+// lines.
+fn read_banner<R: BufRead>(src: R) -> Result<(Banner, impl Iterator<Item = (usize, io::Result<String>)>), OoclaError> {
+    let mut lines = src.lines().enumerate().map(|(i, line)| (i + 1, line));
+    let banner = match lines.next() {
+        Some((_, line)) => parse_banner(&line?)?,
+        None => return Err(invalid(1, "file is empty".to_string())),
+    };
+    let content = lines.filter_map(|(i, line)| match line {
+        Ok(ref text) if text.trim().is_empty() || text.starts_with('%') => None,
+        line => Some((i, line)),
+    });
+    Ok((banner, content))
+}
+
+// Reads the size line, which holds `count` non-negative integers.
+fn read_size<I>(content: &mut I, count: usize, what: &str) -> Result<Vec<u64>, OoclaError>
+    where I: Iterator<Item = (usize, io::Result<String>)> {
+    let (i, size) = match content.next() {
+        Some((i, line)) => (i, line?),
+        None => return Err(invalid(1, "missing size line".to_string())),
+    };
+    match size.split_whitespace().map(|d| d.parse::<u64>()).collect::<Result<Vec<_>, _>>() {
+        Ok(dims) if dims.len() == count => Ok(dims),
+        _ => Err(invalid(i, format!("expected {}, found '{}'", what, size))),
+    }
+}
+
+// Streams the values of a Matrix Market array file into a new matrix at `dst`, which is removed
+// again if the values don't match the declared size. Values arrive column by column, so the
+// result is stored by columns and is transposed.
+fn read_values<T: SupportedType, R: BufRead>(src: R, dst: &Path) -> Result<Dense<T>, OoclaError> {
+    let (banner, mut content) = read_banner(src)?;
+    if banner.coordinate {
+        return Err(invalid(1, "coordinate files are sparse; use import_coordinate".to_string()));
+    }
+    match banner.field {
+        Field::Real => {}
+        Field::Integer => return Err(invalid(1, "integer fields are not supported, only real".to_string())),
+        Field::Pattern => return Err(invalid(1, "pattern fields are not supported, only real".to_string())),
+    }
+    if banner.symmetry != Symmetry::General {
+        return Err(invalid(1, "symmetric array files are not supported, only general".to_string()));
+    }
+    let dims = read_size(&mut content, 2, "the row and column counts")?;
+    let (rows, cols) = (dims[0], dims[1]);
+    let mut result = Dense::<T>::create(dst, cols, rows)?;
+    result.transpose();
+    let filled = fill(&mut result, content);
+    if let Err(e) = filled {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+fn fill<T, I>(result: &mut Dense<T>, content: I) -> Result<(), OoclaError>
+    where T: SupportedType, I: Iterator<Item = (usize, io::Result<String>)> {
+    let (rows, cols) = (result.num_rows(), result.num_cols());
+    let expected = rows * cols;
+    let mut count = 0u64;
+    for (i, line) in content {
+        for token in line?.split_whitespace() {
+            let value = token.parse::<f64>()
+                .map_err(|_| invalid(i, format!("'{}' is not a real number", token)))?;
+            if count == expected {
+                return Err(invalid(i, format!("more than the {} values a {}x{} matrix holds", expected, rows, cols)));
+            }
+            result.major_slice_mut(count / rows)[(count % rows) as usize] = T::from_f64(value);
+            count += 1;
+        }
+    }
+    if count != expected {
+        return Err(OoclaError::InvalidFormat(format!("Matrix Market file declares a {}x{} matrix but holds {} values",
+                                                     rows, cols, count)));
+    }
+    Ok(())
+}
+
+// Imports a Matrix Market array file of real values into a new matrix at `dst`. Coordinate
+// files, integer, pattern and complex fields and symmetric storage are rejected.
+pub fn import_dense<T: SupportedType>(src: &Path, dst: &Path) -> Result<Dense<T>, OoclaError> {
+    read_values(BufReader::with_capacity(1 << 20, File::open(src)?), dst)
+}
+
+// The storage import_coordinate fills.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoordinateTarget {
+    // A zero-initialised dense matrix with the entries scattered into it.
+    Dense,
+    Csr,
+}
+
+pub enum CoordinateMatrix<T> {
+    Dense(Dense<T>),
+    Csr(CsrMatrix<T>),
+}
+
+// Parses the entry on line `i`, converting its indices from 1-based.
+fn parse_entry(text: &str, i: usize, field: Field, rows: u64, cols: u64) -> Result<(u64, u64, f64), OoclaError> {
+    let mut tokens = text.split_whitespace();
+    let mut index = || tokens.next().and_then(|t| t.parse::<u64>().ok());
+    let (row, col) = match (index(), index()) {
+        (Some(row), Some(col)) => (row, col),
+        _ => return Err(invalid(i, format!("expected a row and column index, found '{}'", text))),
+    };
+    if row == 0 || col == 0 || row > rows || col > cols {
+        return Err(invalid(i, format!("entry ({}, {}) lies outside the {}x{} matrix", row, col, rows, cols)));
+    }
+    let value = match field {
+        Field::Pattern => 1.0,
+        Field::Real | Field::Integer => {
+            let token = tokens.next().ok_or_else(|| invalid(i, "missing value".to_string()))?;
+            token.parse::<f64>().map_err(|_| invalid(i, format!("'{}' is not a real number", token)))?
+        }
+    };
+    if let Some(extra) = tokens.next() {
+        return Err(invalid(i, format!("unexpected '{}' after the entry", extra)));
+    }
+    Ok((row - 1, col - 1, value))
+}
+
+// Calls `f` with each entry, and with its mirror image when the file stores only one triangle
+// of a symmetric or skew-symmetric matrix.
+fn for_each_entry<I, F>(content: I, banner: &Banner, rows: u64, cols: u64, nnz: u64, mut f: F) -> Result<(), OoclaError>
+    where I: Iterator<Item = (usize, io::Result<String>)>, F: FnMut(u64, u64, f64) {
+    let mut count = 0;
+    for (i, line) in content {
+        if count == nnz {
+            return Err(invalid(i, format!("more than the {} declared entries", nnz)));
+        }
+        let (row, col, value) = parse_entry(&line?, i, banner.field, rows, cols)?;
+        f(row, col, value);
+        if row != col {
+            match banner.symmetry {
+                Symmetry::General => {}
+                Symmetry::Symmetric => f(col, row, value),
+                Symmetry::SkewSymmetric => f(col, row, -value),
+            }
+        }
+        count += 1;
+    }
+    if count != nnz {
+        return Err(OoclaError::InvalidFormat(format!("Matrix Market file declares {} entries but holds {}", nnz, count)));
+    }
+    Ok(())
+}
+
+fn read_coordinate<T: SupportedType, R: BufRead>(src: R, dst: &Path, target: CoordinateTarget)
+    -> Result<CoordinateMatrix<T>, OoclaError> {
+    let (banner, mut content) = read_banner(src)?;
+    if !banner.coordinate {
+        return Err(invalid(1, "array files are dense; use import_dense".to_string()));
+    }
+    let dims = read_size(&mut content, 3, "the row, column and entry counts")?;
+    let (rows, cols, nnz) = (dims[0], dims[1], dims[2]);
+    if banner.symmetry != Symmetry::General && rows != cols {
+        return Err(OoclaError::InvalidFormat(format!("Matrix Market file declares a {}x{} matrix as symmetric",
+                                                     rows, cols)));
+    }
+    assemble(dst, rows, cols, target, |add| for_each_entry(content, &banner, rows, cols, nnz, add))
+}
+
+// Builds a new matrix at `dst` from the entries `entries` passes to its callback, summing any that
+// repeat. Entries bound for CSR are collected and sorted in memory.
+pub(crate) fn assemble<T, F>(dst: &Path, rows: u64, cols: u64, target: CoordinateTarget, entries: F)
+    -> Result<CoordinateMatrix<T>, OoclaError>
+    where T: SupportedType, F: FnOnce(&mut dyn FnMut(u64, u64, f64)) -> Result<(), OoclaError> {
+    match target {
+        CoordinateTarget::Dense => {
+            let mut result = Dense::<T>::create(dst, rows, cols)?;
+            let filled = entries(&mut |row, col, value| {
+                let sum = result.get(row, col).to_f64() + value;
+                result.set(row, col, T::from_f64(sum));
+            });
+            if let Err(e) = filled {
+                drop(result);
+                let _ = fs::remove_file(dst);
+                return Err(e);
+            }
+            Ok(CoordinateMatrix::Dense(result))
+        }
+        CoordinateTarget::Csr => {
+            let mut collected = Vec::new();
+            entries(&mut |row, col, value| collected.push((row, col, value)))?;
+            collected.sort_unstable_by_key(|&(row, col, _)| (row, col));
+            collected.dedup_by(|next, kept| {
+                let duplicate = (next.0, next.1) == (kept.0, kept.1);
+                if duplicate {
+                    kept.2 += next.2;
+                }
+                duplicate
+            });
+            let mut result = CsrMatrix::create(dst, rows, cols, collected.len() as u64)?;
+            {
+                let (row_ptr, col_idx, values) = result.parts_mut();
+                for (k, &(row, col, value)) in collected.iter().enumerate() {
+                    row_ptr[row as usize + 1] = k as u64 + 1;
+                    col_idx[k] = col;
+                    values[k] = T::from_f64(value);
+                }
+                for r in 0..rows as usize {
+                    row_ptr[r + 1] = row_ptr[r + 1].max(row_ptr[r]);
+                }
+            }
+            Ok(CoordinateMatrix::Csr(result))
+        }
+    }
+}
+
+// Imports a Matrix Market coordinate file into a new matrix at `dst`, converting its 1-based
+// indices. Entries repeated in the file are summed, as scipy.io.mmread does, and the triangle a
+// symmetric or skew-symmetric file stores is mirrored. Pattern entries have the value one. The
+// file is streamed, except that entries bound for CSR are sorted in memory.
+pub fn import_coordinate<T: SupportedType>(src: &Path, dst: &Path, target: CoordinateTarget)
+    -> Result<CoordinateMatrix<T>, OoclaError> {
+    read_coordinate(BufReader::with_capacity(1 << 20, File::open(src)?), dst, target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // What scipy.io.mmwrite writes for np.array([[1.5, -2.0, 0.0], [3e-5, 4.0, 1e300]]): the
+    // values run down each column, in scientific notation with 16 digits.
+    const SCIPY_ARRAY: &str = "%%MatrixMarket matrix array real general
+%
+2 3
+1.5000000000000000e+00
+3.0000000000000001e-05
+-2.0000000000000000e+00
+4.0000000000000000e+00
+0.0000000000000000e+00
+1.0000000000000000e+300
+";
+
+    const EXPECTED: [[f64; 3]; 2] = [[1.5, -2.0, 0.0], [3e-5, 4.0, 1e300]];
+
+    fn imported(text: &str) -> Result<Dense<f64>, OoclaError> {
+        let (src, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        fs::write(src.path(), text).unwrap();
+        let result = import_dense(src.path(), dst.path());
+        if result.is_err() {
+            assert!(!dst.path().exists());
+        }
+        result
+    }
+
+    fn check_expected(a: &Dense<f64>) {
+        assert_eq!((a.num_rows(), a.num_cols()), (2, 3));
+        for (i, row) in EXPECTED.iter().enumerate() {
+            for (j, &value) in row.iter().enumerate() {
+                assert_eq!(a.get(i as u64, j as u64), value, "({}, {})", i, j);
+            }
+        }
+    }
+
+    fn exported(a: &Dense<f64>) -> String {
+        let path = TempMatrixPath::new();
+        export_dense(a, path.path()).unwrap();
+        fs::read_to_string(path.path()).unwrap()
+    }
+
+    #[test]
+    fn scipy_array_files_round_trip() {
+        let a = imported(SCIPY_ARRAY).unwrap();
+        check_expected(&a);
+        let text = exported(&a);
+        assert!(text.starts_with("%%MatrixMarket matrix array real general\n2 3\n"));
+        check_expected(&imported(&text).unwrap());
+    }
+
+    #[test]
+    fn matrices_stored_by_rows_are_written_down_the_columns() {
+        let a = Dense::anonymous_from_fn(2, 3, |i, j| EXPECTED[i as usize][j as usize]);
+        assert!(!a.is_transposed());
+        let text = exported(&a);
+        let values: Vec<f64> = text.lines().skip(2).map(|v| v.parse().unwrap()).collect();
+        assert_eq!(values, vec![1.5, 3e-5, -2.0, 4.0, 0.0, 1e300]);
+        check_expected(&imported(&text).unwrap());
+    }
+
+    #[test]
+    fn crlf_endings_comments_and_several_values_to_a_line_are_accepted() {
+        let text = concat!("%%MatrixMarket matrix array real general\r\n% a comment\r\n\r\n2 3\r\n",
+                           "1.5 3e-5\r\n-2E0 4\r\n% more\r\n0 1e+300\r\n");
+        check_expected(&imported(text).unwrap());
+    }
+
+    #[test]
+    fn unsupported_fields_and_miscounted_values_are_rejected() {
+        let cases = [
+            "%%MatrixMarket matrix array integer general\n1 1\n1\n",
+            "%%MatrixMarket matrix array pattern general\n1 1\n",
+            "%%MatrixMarket matrix array complex general\n1 1\n1 0\n",
+            "%%MatrixMarket matrix array real symmetric\n1 1\n1\n",
+            "%%MatrixMarket matrix coordinate real general\n1 1 1\n1 1 1\n",
+            "%%MatrixMarket matrix array real general\n2 2\n1\n2\n3\n",
+            "%%MatrixMarket matrix array real general\n1 2\n1\n2\n3\n",
+            "%%MatrixMarket matrix array real general\n1 1\none\n",
+            "%%MatrixMarket matrix array real general\n",
+            "",
+        ];
+        for text in cases.iter() {
+            match imported(text) {
+                Err(OoclaError::InvalidFormat(_)) => {}
+                other => panic!("expected {:?} to be rejected, got {:?}", text, other.map(|_| ())),
+            }
+        }
+    }
+}
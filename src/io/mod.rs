@@ -0,0 +1,18 @@
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod csv;
+mod deflate;
+#[cfg(feature = "hdf5")]
+pub mod hdf5;
+pub mod image;
+pub mod matlab;
+pub mod matrix_market;
+pub mod npy;
+pub mod npz;
+pub mod petsc;
+pub mod raw;
+pub mod rb;
+pub mod safetensors;
+pub mod sidecar;
+#[cfg(feature = "protobuf")]
+pub mod tensorproto;
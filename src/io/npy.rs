@@ -0,0 +1,503 @@
+use dense_matrix::{AccessPattern, Dense, DiscardBehind, FloatType, StorageType, StreamOptions, as_bytes, as_bytes_mut};
+use direct::{DirectReader, DirectWriter};
+use error::OoclaError;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+// NumPy pads the header so that the payload starts on this alignment.
+const NPY_ALIGNMENT: usize = 64;
+const NATIVE_ORDER: char = if cfg!(target_endian = "little") { '<' } else { '>' };
+
+// The dtype string NumPy uses for each element representation in native byte order. Single
+// bytes have no byte order.
+fn descr(representation: FloatType) -> String {
+    match representation {
+        FloatType::Single => format!("{}f4", NATIVE_ORDER),
+        FloatType::Double => format!("{}f8", NATIVE_ORDER),
+        FloatType::UInt32 => format!("{}u4", NATIVE_ORDER),
+        FloatType::UInt64 => format!("{}u8", NATIVE_ORDER),
+        FloatType::Int8 => "|i1".to_string(),
+    }
+}
+
+fn parse_descr(s: &str) -> Option<FloatType> {
+    [FloatType::Single, FloatType::Double, FloatType::UInt32, FloatType::UInt64, FloatType::Int8].iter()
+        .cloned()
+        .find(|&representation| descr(representation) == s)
+}
+
+// The subset of Python literal syntax that appears in .npy headers, which also covers the JSON
+// of sidecar files and safetensors headers.
+#[derive(Debug)]
+pub(crate) enum Literal {
+    Str(String),
+    Bool(bool),
+    Int(u64),
+    Tuple(Vec<Literal>),
+    Dict(Vec<(String, Literal)>),
+}
+
+pub(crate) struct LiteralParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LiteralParser<'a> {
+    pub(crate) fn new(text: &'a [u8]) -> LiteralParser<'a> {
+        LiteralParser { text, pos: 0 }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        self.text.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        match self.peek() {
+            Some(found) if found == c => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(format!("expected '{}' at offset {}", c as char, self.pos)),
+        }
+    }
+
+    // Items up to `close`, separated by commas with an optional trailing one.
+    fn items<F, I>(&mut self, close: u8, mut item: F) -> Result<Vec<I>, String>
+        where F: FnMut(&mut LiteralParser<'a>) -> Result<I, String> {
+        let mut result = Vec::new();
+        loop {
+            if self.peek() == Some(close) {
+                self.pos += 1;
+                return Ok(result);
+            }
+            result.push(item(self)?);
+            if self.peek() == Some(b',') {
+                self.pos += 1;
+            } else {
+                self.expect(close)?;
+                return Ok(result);
+            }
+        }
+    }
+
+    // The rest of a string opened by `quote`, with the escapes Python and JSON share decoded.
+    fn string(&mut self, quote: u8) -> Result<String, String> {
+        let mut bytes = Vec::new();
+        loop {
+            let c = *self.text.get(self.pos).ok_or_else(|| "unterminated string".to_string())?;
+            self.pos += 1;
+            if c == quote {
+                return Ok(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            if c != b'\\' {
+                bytes.push(c);
+                continue;
+            }
+            let escaped = *self.text.get(self.pos).ok_or_else(|| "unterminated string".to_string())?;
+            self.pos += 1;
+            match escaped {
+                b'n' => bytes.push(b'\n'),
+                b't' => bytes.push(b'\t'),
+                b'r' => bytes.push(b'\r'),
+                b'b' => bytes.push(8),
+                b'f' => bytes.push(12),
+                b'u' => {
+                    let digits = self.text.get(self.pos..self.pos + 4)
+                        .and_then(|d| std::str::from_utf8(d).ok())
+                        .and_then(|d| u32::from_str_radix(d, 16).ok())
+                        .ok_or_else(|| format!("bad \\u escape at offset {}", self.pos))?;
+                    self.pos += 4;
+                    let c = char::from_u32(digits).unwrap_or(char::REPLACEMENT_CHARACTER);
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                other => bytes.push(other),
+            }
+        }
+    }
+
+    pub(crate) fn value(&mut self) -> Result<Literal, String> {
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let entries = self.items(b'}', |p| {
+                    let key = match p.value()? {
+                        Literal::Str(key) => key,
+                        other => return Err(format!("dictionary key {:?} is not a string", other)),
+                    };
+                    p.expect(b':')?;
+                    Ok((key, p.value()?))
+                })?;
+                Ok(Literal::Dict(entries))
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                Ok(Literal::Tuple(self.items(b')', |p| p.value())?))
+            }
+            // JSON arrays hold the same things as tuples.
+            Some(b'[') => {
+                self.pos += 1;
+                Ok(Literal::Tuple(self.items(b']', |p| p.value())?))
+            }
+            Some(quote) if quote == b'\'' || quote == b'"' => {
+                self.pos += 1;
+                self.string(quote).map(Literal::Str)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.pos < self.text.len() && self.text[self.pos].is_ascii_digit() {
+                    self.pos += 1;
+                }
+                let digits = String::from_utf8_lossy(&self.text[start..self.pos]).into_owned();
+                // Headers written under Python 2 may mark long integers.
+                if self.text.get(self.pos) == Some(&b'L') {
+                    self.pos += 1;
+                }
+                digits.parse().map(Literal::Int).map_err(|_| format!("integer {} is out of range", digits))
+            }
+            _ => {
+                for &(word, value) in &[("True", true), ("False", false), ("true", true), ("false", false)] {
+                    if self.text[self.pos..].starts_with(word.as_bytes()) {
+                        self.pos += word.len();
+                        return Ok(Literal::Bool(value));
+                    }
+                }
+                Err(format!("unexpected input at offset {}", self.pos))
+            }
+        }
+    }
+}
+
+// The description of an array that precedes its elements in a .npy file.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct NpyHeader {
+    pub descr: String,
+    pub fortran_order: bool,
+    pub shape: Vec<u64>,
+}
+
+impl NpyHeader {
+    fn parse(text: &[u8]) -> Result<NpyHeader, OoclaError> {
+        let invalid = |msg: String| OoclaError::InvalidFormat(format!("npy header: {}", msg));
+        let mut parser = LiteralParser::new(text);
+        let entries = match parser.value().map_err(invalid)? {
+            Literal::Dict(entries) => entries,
+            other => return Err(invalid(format!("expected a dictionary, found {:?}", other))),
+        };
+        let (mut descr, mut fortran_order, mut shape) = (None, None, None);
+        for (key, value) in entries {
+            match (key.as_str(), value) {
+                ("descr", Literal::Str(s)) => descr = Some(s),
+                ("fortran_order", Literal::Bool(b)) => fortran_order = Some(b),
+                ("shape", Literal::Tuple(dims)) => {
+                    let dims = dims.into_iter()
+                        .map(|d| match d {
+                            Literal::Int(n) => Ok(n),
+                            other => Err(invalid(format!("non-integer dimension {:?}", other))),
+                        })
+                        .collect::<Result<Vec<u64>, OoclaError>>()?;
+                    shape = Some(dims);
+                }
+                (key, value) => return Err(invalid(format!("unexpected entry '{}': {:?}", key, value))),
+            }
+        }
+        match (descr, fortran_order, shape) {
+            (Some(descr), Some(fortran_order), Some(shape)) => Ok(NpyHeader { descr, fortran_order, shape }),
+            _ => Err(invalid("missing one of descr, fortran_order and shape".to_string())),
+        }
+    }
+
+    pub(crate) fn read<R: Read>(r: &mut R) -> Result<NpyHeader, OoclaError> {
+        let mut preamble = [0; 8];
+        read_exact_or_truncated(r, &mut preamble, "npy header")?;
+        if &preamble[..6] != NPY_MAGIC {
+            return Err(OoclaError::InvalidFormat("not an npy file".to_string()));
+        }
+        let header_len = match preamble[6] {
+            1 => {
+                let mut len = [0; 2];
+                read_exact_or_truncated(r, &mut len, "npy header")?;
+                u16::from_le_bytes(len) as usize
+            }
+            2 | 3 => {
+                let mut len = [0; 4];
+                read_exact_or_truncated(r, &mut len, "npy header")?;
+                u32::from_le_bytes(len) as usize
+            }
+            major => {
+                return Err(OoclaError::InvalidFormat(format!("unsupported npy version {}.{}", major, preamble[7])));
+            }
+        };
+        let mut text = vec![0; header_len];
+        read_exact_or_truncated(r, &mut text, "npy header")?;
+        NpyHeader::parse(&text)
+    }
+
+    pub(crate) fn representation(&self) -> Result<FloatType, OoclaError> {
+        parse_descr(&self.descr)
+            .ok_or_else(|| OoclaError::InvalidFormat(format!("unsupported npy dtype '{}'", self.descr)))
+    }
+
+    pub(crate) fn dims(&self) -> Result<(u64, u64), OoclaError> {
+        match self.shape[..] {
+            [rows, cols] => Ok((rows, cols)),
+            _ => Err(OoclaError::InvalidFormat(format!("npy array has {} dimensions, not 2", self.shape.len()))),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        // Python's tuple repr, as NumPy writes it.
+        let mut shape = self.shape.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(", ");
+        if self.shape.len() == 1 {
+            shape.push(',');
+        }
+        let dict = format!("{{'descr': '{}', 'fortran_order': {}, 'shape': ({}), }}", self.descr,
+                           if self.fortran_order { "True" } else { "False" }, shape);
+        let padded = |prefix: usize| (prefix + dict.len() + 1).div_ceil(NPY_ALIGNMENT) * NPY_ALIGNMENT - prefix;
+        // The version 1.0 length field is 16 bits; 2.0 widens it to 32.
+        let (version, prefix) = if padded(10) <= u16::MAX as usize { (1, 10) } else { (2, 12) };
+        let header_len = padded(prefix);
+        let total = prefix + header_len;
+        let mut result = Vec::with_capacity(total);
+        result.extend_from_slice(NPY_MAGIC);
+        result.extend_from_slice(&[version, 0]);
+        if version == 1 {
+            result.extend_from_slice(&(header_len as u16).to_le_bytes());
+        } else {
+            result.extend_from_slice(&(header_len as u32).to_le_bytes());
+        }
+        result.extend_from_slice(dict.as_bytes());
+        result.resize(total - 1, b' ');
+        result.push(b'\n');
+        result
+    }
+}
+
+pub(crate) fn read_exact_or_truncated<R: Read>(r: &mut R, buf: &mut [u8], what: &str) -> Result<(), OoclaError> {
+    r.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => OoclaError::InvalidFormat(format!("{} is truncated", what)),
+        _ => e.into(),
+    })
+}
+
+// Streams the elements described by `header` from `r` into a new matrix at `dst`. A
+// Fortran-order array becomes a transposed matrix rather than being reordered.
+pub(crate) fn read_payload<T: StorageType, R: Read>(r: &mut R, header: &NpyHeader, dst: &Path)
+    -> Result<Dense<T>, OoclaError> {
+    read_payload_with(r, header, dst, StreamOptions::default())
+}
+
+// As read_payload, giving the new matrix `options`, which it is written under.
+fn read_payload_with<T: StorageType, R: Read>(r: &mut R, header: &NpyHeader, dst: &Path, options: StreamOptions)
+    -> Result<Dense<T>, OoclaError> {
+    let found = header.representation()?;
+    if found != T::get_float_type() {
+        return Err(OoclaError::TypeMismatch { expected: T::get_float_type(), found });
+    }
+    let (rows, cols) = header.dims()?;
+    let (major, minor) = if header.fortran_order { (cols, rows) } else { (rows, cols) };
+    let mut result = Dense::create(dst, major, minor)?;
+    if header.fortran_order {
+        result.transpose();
+    }
+    result.set_stream_options(options);
+    let mut pass = DiscardBehind::new(&result);
+    let copied = (0..major).try_for_each(|line| {
+        read_exact_or_truncated(r, as_bytes_mut(result.major_slice_mut(line)), "npy payload")?;
+        pass.advance(&result, line + 1)
+    });
+    if let Err(e) = copied {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+// Imports a 2-D .npy array whose dtype matches T into a new matrix at `dst`.
+pub fn import<T: StorageType>(src: &Path, dst: &Path) -> Result<Dense<T>, OoclaError> {
+    import_with(src, dst, StreamOptions::default())
+}
+
+// As import, with the options the import follows and the new matrix is given. With direct_io
+// the file is read with O_DIRECT.
+pub fn import_with<T: StorageType>(src: &Path, dst: &Path, options: StreamOptions) -> Result<Dense<T>, OoclaError> {
+    if options.direct_io {
+        let mut reader = DirectReader::open(src)?;
+        let header = NpyHeader::read(&mut reader)?;
+        return read_payload_with(&mut reader, &header, dst, options);
+    }
+    let mut reader = BufReader::with_capacity(1 << 20, File::open(src)?);
+    let header = NpyHeader::read(&mut reader)?;
+    read_payload_with(&mut reader, &header, dst, options)
+}
+
+fn header_for<T: StorageType>(a: &Dense<T>) -> NpyHeader {
+    NpyHeader {
+        descr: descr(T::get_float_type()),
+        fortran_order: a.is_transposed(),
+        shape: vec![a.num_rows(), a.num_cols()],
+    }
+}
+
+// The number of bytes `write` produces for A.
+pub(crate) fn encoded_len<T: StorageType>(a: &Dense<T>) -> u64 {
+    header_for(a).encode().len() as u64 + a.major_len() * a.minor_len() * mem::size_of::<T>() as u64
+}
+
+// Writes A in .npy format, streaming the elements straight from the mapping. A transposed matrix
+// is written in Fortran order, so its storage is never reordered.
+pub(crate) fn write<T: StorageType, W: Write>(a: &Dense<T>, w: &mut W) -> Result<(), OoclaError> {
+    let _advice = a.advise_scoped(AccessPattern::Sequential);
+    w.write_all(&header_for(a).encode())?;
+    let mut pass = DiscardBehind::new(a);
+    a.stream_major(0..a.major_len(), |line, values| {
+        w.write_all(as_bytes(values))?;
+        pass.advance(a, line + 1)
+    })
+}
+
+// Exports A as a .npy file readable by numpy.load. With direct_io among A's stream options, the
+// file is written with O_DIRECT.
+pub fn export<T: StorageType>(a: &Dense<T>, path: &Path) -> Result<(), OoclaError> {
+    if a.stream_options().direct_io {
+        let mut writer = DirectWriter::create(path)?;
+        write(a, &mut writer)?;
+        writer.finish()?;
+        return Ok(());
+    }
+    let mut writer = BufWriter::with_capacity(1 << 20, File::create(path)?);
+    write(a, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // A file as numpy.save writes one with a short header: a version 1.0 preamble, then the
+    // header dictionary padded with spaces and a newline to 128 bytes in all, then the payload.
+    fn numpy_file(dict: &str, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = b"\x93NUMPY\x01\x00\x76\x00".to_vec();
+        bytes.extend_from_slice(dict.as_bytes());
+        bytes.resize(127, b' ');
+        bytes.push(b'\n');
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    // What numpy.save writes for np.arange(1, 7, dtype='<f4').reshape(2, 3).
+    fn c_order_fixture() -> Vec<u8> {
+        let payload: Vec<u8> = (1..7).flat_map(|x| (x as f32).to_le_bytes()).collect();
+        numpy_file("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }", &payload)
+    }
+
+    // What numpy.save writes for np.asfortranarray(np.arange(1, 7, dtype='<f8').reshape(3, 2)),
+    // whose payload runs down the columns.
+    fn fortran_order_fixture() -> Vec<u8> {
+        let payload: Vec<u8> = [1.0f64, 3.0, 5.0, 2.0, 4.0, 6.0].iter().flat_map(|x| x.to_le_bytes()).collect();
+        numpy_file("{'descr': '<f8', 'fortran_order': True, 'shape': (3, 2), }", &payload)
+    }
+
+    fn written<T: StorageType>(a: &Dense<T>) -> Vec<u8> {
+        let path = TempMatrixPath::new();
+        export(a, path.path()).unwrap();
+        let bytes = fs::read(path.path()).unwrap();
+        assert_eq!(bytes.len() as u64, encoded_len(a));
+        bytes
+    }
+
+    fn imported<T: StorageType>(bytes: &[u8]) -> Result<Dense<T>, OoclaError> {
+        let (src, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        fs::write(src.path(), bytes).unwrap();
+        let result = import(src.path(), dst.path());
+        if result.is_err() {
+            assert!(!dst.path().exists());
+        }
+        result
+    }
+
+    #[test]
+    fn c_order_export_matches_numpy_byte_for_byte() {
+        let fixture = c_order_fixture();
+        assert_eq!(fixture.len(), 128 + 24);
+        let a = Dense::<f32>::anonymous_from_fn(2, 3, |i, j| (3 * i + j + 1) as f32);
+        assert_eq!(written(&a), fixture);
+        let b = imported::<f32>(&fixture).unwrap();
+        assert!(!b.is_transposed());
+        assert_eq!(written(&b), fixture);
+    }
+
+    #[test]
+    fn fortran_order_maps_onto_the_transposed_flag() {
+        let fixture = fortran_order_fixture();
+        let a = imported::<f64>(&fixture).unwrap();
+        assert!(a.is_transposed());
+        assert_eq!((a.num_rows(), a.num_cols()), (3, 2));
+        for i in 0..3 {
+            for j in 0..2 {
+                assert_eq!(a.get(i, j), (2 * i + j + 1) as f64);
+            }
+        }
+        assert_eq!(written(&a), fixture);
+    }
+
+    #[test]
+    fn version_2_headers_are_read() {
+        let fixture = c_order_fixture();
+        let mut bytes = b"\x93NUMPY\x02\x00\x76\x00\x00\x00".to_vec();
+        bytes.extend_from_slice(&fixture[10..]);
+        let a = imported::<f32>(&bytes).unwrap();
+        assert_eq!([a.get(0, 0), a.get(0, 2), a.get(1, 0), a.get(1, 2)], [1.0, 3.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn long_headers_are_written_as_version_2() {
+        let header = NpyHeader { descr: descr(FloatType::Double), fortran_order: false, shape: vec![1; 25_000] };
+        let encoded = header.encode();
+        assert_eq!(&encoded[..8], b"\x93NUMPY\x02\x00");
+        assert_eq!(encoded.len() % NPY_ALIGNMENT, 0);
+        let decoded = NpyHeader::read(&mut &encoded[..]).unwrap();
+        assert_eq!(decoded.shape, header.shape);
+    }
+
+    #[test]
+    fn random_matrices_round_trip() {
+        let mut a = Dense::<f64>::create_anonymous(37, 300).unwrap();
+        a.randomise_seeded(5);
+        let b = imported::<f64>(&written(&a)).unwrap();
+        for i in 0..37 {
+            for j in 0..300 {
+                assert_eq!(a.get(i, j).to_bits(), b.get(i, j).to_bits());
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported_and_damaged_files_are_rejected() {
+        let fixture = c_order_fixture();
+        match imported::<f64>(&fixture) {
+            Err(OoclaError::TypeMismatch { .. }) => {}
+            other => panic!("expected a type mismatch, got {:?}", other.map(|_| ())),
+        }
+        match imported::<f32>(&fixture[..fixture.len() - 1]) {
+            Err(OoclaError::InvalidFormat(_)) => {}
+            other => panic!("expected a truncated payload, got {:?}", other.map(|_| ())),
+        }
+        let one_dimensional = numpy_file("{'descr': '<f4', 'fortran_order': False, 'shape': (6,), }", &fixture[128..]);
+        match imported::<f32>(&one_dimensional) {
+            Err(OoclaError::InvalidFormat(_)) => {}
+            other => panic!("expected a dimension error, got {:?}", other.map(|_| ())),
+        }
+        let mut bad_magic = fixture.clone();
+        bad_magic[1] = b'X';
+        assert!(imported::<f32>(&bad_magic).is_err());
+    }
+}
@@ -0,0 +1,601 @@
+use dense_matrix::{Dense, FloatType, StorageType};
+use error::OoclaError;
+use io::deflate::{Deflater, Inflater};
+use io::npy::{self, NpyHeader};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const END_SIG: u32 = 0x0605_4b50;
+const ZIP64_END_SIG: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const LOCAL_HEADER_SIZE: u64 = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const END_SIZE: usize = 22;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+// Members at least this large get zip64 size fields, leaving room for deflate to expand them.
+const ZIP64_MEMBER_THRESHOLD: u64 = 1 << 31;
+// 1980-01-01, the earliest date a zip file can record.
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+// A member of an archive that can be imported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpzEntry {
+    pub name: String,
+    pub shape: (u64, u64),
+    pub dtype: FloatType,
+}
+
+// The members of an archive, with the reason each one that can't be imported was passed over.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NpzListing {
+    pub entries: Vec<NpzEntry>,
+    pub skipped: Vec<(String, String)>,
+}
+
+pub(crate) struct Crc32 {
+    table: [u32; 256],
+    value: u32,
+}
+
+impl Crc32 {
+    pub(crate) fn new() -> Crc32 {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = (0..8).fold(i as u32, |c, _| if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 });
+        }
+        Crc32 { table, value: !0 }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.value = self.table[((self.value ^ b as u32) & 0xff) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    pub(crate) fn value(&self) -> u32 {
+        !self.value
+    }
+}
+
+// Checksums and counts the bytes passing through to or from `inner`.
+struct Checked<S> {
+    inner: S,
+    crc: Crc32,
+    len: u64,
+}
+
+impl<S> Checked<S> {
+    fn new(inner: S) -> Checked<S> {
+        Checked { inner, crc: Crc32::new(), len: 0 }
+    }
+}
+
+impl<R: Read> Read for Checked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.crc.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for Checked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.crc.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    (u32_at(bytes, at) as u64) | (u32_at(bytes, at + 4) as u64) << 32
+}
+
+fn invalid(msg: String) -> OoclaError {
+    OoclaError::InvalidFormat(format!("npz archive: {}", msg))
+}
+
+// A member as the central directory describes it.
+struct Member {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>, OoclaError> {
+    let mut bytes = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut bytes).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("file is truncated".to_string()),
+        _ => e.into(),
+    })?;
+    Ok(bytes)
+}
+
+fn read_directory(file: &mut File) -> Result<Vec<Member>, OoclaError> {
+    let file_len = file.metadata()?.len();
+    // The end record is followed only by a comment of at most 64KiB.
+    let tail_len = file_len.min(END_SIZE as u64 + 0xffff);
+    let tail = read_at(file, file_len - tail_len, tail_len as usize)?;
+    let end = (0..(tail.len() + 1).saturating_sub(END_SIZE)).rev()
+        .find(|&at| u32_at(&tail, at) == END_SIG)
+        .ok_or_else(|| invalid("no end of central directory record".to_string()))?;
+    let mut count = u16_at(&tail, end + 10) as u64;
+    let mut dir_size = u32_at(&tail, end + 12) as u64;
+    let mut dir_offset = u32_at(&tail, end + 16) as u64;
+    if count == 0xffff || dir_size == 0xffff_ffff || dir_offset == 0xffff_ffff {
+        let end_offset = file_len - tail_len + end as u64;
+        let locator = read_at(file, end_offset.checked_sub(20).ok_or_else(|| invalid("no zip64 locator".to_string()))?,
+                              20)?;
+        if u32_at(&locator, 0) != ZIP64_LOCATOR_SIG {
+            return Err(invalid("no zip64 locator".to_string()));
+        }
+        let record = read_at(file, u64_at(&locator, 8), 56)?;
+        if u32_at(&record, 0) != ZIP64_END_SIG {
+            return Err(invalid("bad zip64 end of central directory record".to_string()));
+        }
+        count = u64_at(&record, 32);
+        dir_size = u64_at(&record, 40);
+        dir_offset = u64_at(&record, 48);
+    }
+    if dir_offset.checked_add(dir_size).is_none_or(|end| end > file_len) {
+        return Err(invalid("central directory lies outside the file".to_string()));
+    }
+    let dir = read_at(file, dir_offset, dir_size as usize)?;
+    let mut members = Vec::new();
+    let mut at = 0;
+    for _ in 0..count {
+        if at + CENTRAL_HEADER_SIZE > dir.len() || u32_at(&dir, at) != CENTRAL_HEADER_SIG {
+            return Err(invalid("corrupt central directory".to_string()));
+        }
+        let (name_len, extra_len, comment_len) =
+            (u16_at(&dir, at + 28) as usize, u16_at(&dir, at + 30) as usize, u16_at(&dir, at + 32) as usize);
+        let name_start = at + CENTRAL_HEADER_SIZE;
+        if name_start + name_len + extra_len > dir.len() {
+            return Err(invalid("corrupt central directory".to_string()));
+        }
+        let mut member = Member {
+            name: String::from_utf8_lossy(&dir[name_start..name_start + name_len]).into_owned(),
+            method: u16_at(&dir, at + 10),
+            crc: u32_at(&dir, at + 16),
+            compressed_size: u32_at(&dir, at + 20) as u64,
+            size: u32_at(&dir, at + 24) as u64,
+            offset: u32_at(&dir, at + 42) as u64,
+        };
+        if u16_at(&dir, at + 8) & 1 != 0 {
+            return Err(invalid(format!("member {} is encrypted", member.name)));
+        }
+        // Zip64 extra data holds, in order, whichever of the fields above overflowed.
+        let mut extra = &dir[name_start + name_len..name_start + name_len + extra_len];
+        while extra.len() >= 4 {
+            let (id, len) = (u16_at(extra, 0), u16_at(extra, 2) as usize);
+            let data = &extra[4..(4 + len).min(extra.len())];
+            if id == ZIP64_EXTRA_ID {
+                let mut fields = data.chunks_exact(8).map(|field| u64_at(field, 0));
+                for value in [&mut member.size, &mut member.compressed_size, &mut member.offset] {
+                    if *value == 0xffff_ffff {
+                        *value = fields.next().ok_or_else(|| invalid("short zip64 extra field".to_string()))?;
+                    }
+                }
+            }
+            extra = &extra[(4 + len).min(extra.len())..];
+        }
+        members.push(member);
+        at = name_start + name_len + extra_len + comment_len;
+    }
+    Ok(members)
+}
+
+// Reads the uncompressed contents of `member` straight out of the archive.
+fn open_member<'a>(file: &'a mut File, member: &Member) -> Result<Checked<Box<dyn Read + 'a>>, OoclaError> {
+    let header = read_at(file, member.offset, LOCAL_HEADER_SIZE as usize)?;
+    if u32_at(&header, 0) != LOCAL_HEADER_SIG {
+        return Err(invalid(format!("bad local header for member {}", member.name)));
+    }
+    let data_offset = member.offset + LOCAL_HEADER_SIZE + u16_at(&header, 26) as u64 + u16_at(&header, 28) as u64;
+    file.seek(SeekFrom::Start(data_offset))?;
+    let raw = BufReader::with_capacity(1 << 20, file.take(member.compressed_size));
+    let reader: Box<dyn Read + 'a> = match member.method {
+        METHOD_STORED => Box::new(raw),
+        METHOD_DEFLATE => Box::new(Inflater::new(raw)),
+        method => return Err(invalid(format!("member {} uses unsupported compression method {}", member.name, method))),
+    };
+    Ok(Checked::new(reader))
+}
+
+// The array name NumPy gives a member, which is its file name without the .npy suffix.
+fn array_name(member: &Member) -> Option<&str> {
+    if member.name.ends_with(".npy") {
+        Some(&member.name[..member.name.len() - 4])
+    } else {
+        None
+    }
+}
+
+fn describe(file: &mut File, member: &Member) -> Result<NpzEntry, String> {
+    let name = array_name(member).ok_or_else(|| "not an .npy member".to_string())?;
+    let message = |e: OoclaError| match e {
+        OoclaError::InvalidFormat(msg) => msg,
+        e => e.to_string(),
+    };
+    let header = NpyHeader::read(&mut open_member(file, member).map_err(message)?).map_err(message)?;
+    Ok(NpzEntry {
+        name: name.to_string(),
+        shape: header.dims().map_err(message)?,
+        dtype: header.representation().map_err(message)?,
+    })
+}
+
+// Lists the 2-D arrays in a .npz archive that import_member can read.
+pub fn list(path: &Path) -> Result<NpzListing, OoclaError> {
+    let mut file = File::open(path)?;
+    let mut listing = NpzListing::default();
+    for member in read_directory(&mut file)? {
+        match describe(&mut file, &member) {
+            Ok(entry) => listing.entries.push(entry),
+            Err(reason) => listing.skipped.push((member.name, reason)),
+        }
+    }
+    Ok(listing)
+}
+
+// Imports the array `member` of a .npz archive, named with or without its .npy suffix, into a
+// new matrix at `dst`. The member is decompressed as it is read and its checksum verified.
+pub fn import_member<T: StorageType>(path: &Path, member: &str, dst: &Path) -> Result<Dense<T>, OoclaError> {
+    let mut file = File::open(path)?;
+    let found = read_directory(&mut file)?.into_iter()
+        .find(|m| m.name == member || array_name(m) == Some(member))
+        .ok_or_else(|| OoclaError::InvalidArgument(format!("no member named {} in {}", member, path.display())))?;
+    let mut reader = open_member(&mut file, &found)?;
+    let header = NpyHeader::read(&mut reader)?;
+    let result = npy::read_payload(&mut reader, &header, dst)?;
+    let checked = io::copy(&mut reader, &mut io::sink()).map_err(OoclaError::from)
+        .and_then(|_| if reader.len != found.size || reader.crc.value() != found.crc {
+            Err(invalid(format!("member {} fails its checksum", found.name)))
+        } else {
+            Ok(())
+        });
+    if let Err(e) = checked {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+// The fields of a written member needed again for the central directory.
+struct Written {
+    name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+    zip64: bool,
+}
+
+impl Written {
+    fn version_needed(&self) -> u16 {
+        if self.zip64 { 45 } else { 20 }
+    }
+
+    // Sizes and offset as 32-bit fields, saturated when the zip64 extra field carries them.
+    fn narrow(&self, value: u64) -> u32 {
+        if self.zip64 { 0xffff_ffff } else { value as u32 }
+    }
+}
+
+fn write_member<W: Write + Seek, T: StorageType>(out: &mut W, name: &str, a: &Dense<T>, compressed: bool)
+    -> Result<Written, OoclaError> {
+    let offset = out.stream_position()?;
+    let size = npy::encoded_len(a);
+    let mut member = Written {
+        name: format!("{}.npy", name),
+        method: if compressed { METHOD_DEFLATE } else { METHOD_STORED },
+        crc: 0,
+        compressed_size: 0,
+        size,
+        offset,
+        zip64: size >= ZIP64_MEMBER_THRESHOLD || offset >= 0xffff_ffff,
+    };
+    // The checksum and sizes are filled in once the data has been written.
+    let mut header = Vec::with_capacity(LOCAL_HEADER_SIZE as usize + member.name.len() + 20);
+    header.extend_from_slice(&LOCAL_HEADER_SIG.to_le_bytes());
+    header.extend_from_slice(&member.version_needed().to_le_bytes());
+    header.extend_from_slice(&[0; 2]);
+    header.extend_from_slice(&member.method.to_le_bytes());
+    header.extend_from_slice(&[0; 2]);
+    header.extend_from_slice(&DOS_DATE.to_le_bytes());
+    header.extend_from_slice(&[0; 12]);
+    header.extend_from_slice(&(member.name.len() as u16).to_le_bytes());
+    header.extend_from_slice(&(if member.zip64 { 20u16 } else { 0 }).to_le_bytes());
+    header.extend_from_slice(member.name.as_bytes());
+    if member.zip64 {
+        header.extend_from_slice(&ZIP64_EXTRA_ID.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(&[0; 16]);
+    }
+    out.write_all(&header)?;
+    let data_offset = out.stream_position()?;
+    let (crc, written) = if compressed {
+        let mut writer = Checked::new(Deflater::new(&mut *out));
+        npy::write(a, &mut writer)?;
+        writer.inner.finish()?;
+        (writer.crc.value(), writer.len)
+    } else {
+        let mut writer = Checked::new(&mut *out);
+        npy::write(a, &mut writer)?;
+        (writer.crc.value(), writer.len)
+    };
+    debug_assert_eq!(written, size);
+    let end = out.stream_position()?;
+    member.crc = crc;
+    member.compressed_size = end - data_offset;
+    header[14..18].copy_from_slice(&crc.to_le_bytes());
+    header[18..22].copy_from_slice(&member.narrow(member.compressed_size).to_le_bytes());
+    header[22..26].copy_from_slice(&member.narrow(size).to_le_bytes());
+    if member.zip64 {
+        let extra = LOCAL_HEADER_SIZE as usize + member.name.len() + 4;
+        header[extra..extra + 8].copy_from_slice(&size.to_le_bytes());
+        header[extra + 8..extra + 16].copy_from_slice(&member.compressed_size.to_le_bytes());
+    }
+    out.seek(SeekFrom::Start(offset))?;
+    out.write_all(&header)?;
+    out.seek(SeekFrom::Start(end))?;
+    Ok(member)
+}
+
+fn write_directory<W: Write + Seek>(out: &mut W, members: &[Written]) -> Result<(), OoclaError> {
+    let dir_offset = out.stream_position()?;
+    for member in members {
+        let mut record = Vec::with_capacity(CENTRAL_HEADER_SIZE + member.name.len() + 28);
+        record.extend_from_slice(&CENTRAL_HEADER_SIG.to_le_bytes());
+        // Made by a Unix host, so that the permissions in the external attributes apply.
+        record.extend_from_slice(&(3 << 8 | member.version_needed()).to_le_bytes());
+        record.extend_from_slice(&member.version_needed().to_le_bytes());
+        record.extend_from_slice(&[0; 2]);
+        record.extend_from_slice(&member.method.to_le_bytes());
+        record.extend_from_slice(&[0; 2]);
+        record.extend_from_slice(&DOS_DATE.to_le_bytes());
+        record.extend_from_slice(&member.crc.to_le_bytes());
+        record.extend_from_slice(&member.narrow(member.compressed_size).to_le_bytes());
+        record.extend_from_slice(&member.narrow(member.size).to_le_bytes());
+        record.extend_from_slice(&(member.name.len() as u16).to_le_bytes());
+        record.extend_from_slice(&(if member.zip64 { 28u16 } else { 0 }).to_le_bytes());
+        record.extend_from_slice(&[0; 6]);
+        record.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+        record.extend_from_slice(&member.narrow(member.offset).to_le_bytes());
+        record.extend_from_slice(member.name.as_bytes());
+        if member.zip64 {
+            record.extend_from_slice(&ZIP64_EXTRA_ID.to_le_bytes());
+            record.extend_from_slice(&24u16.to_le_bytes());
+            for value in &[member.size, member.compressed_size, member.offset] {
+                record.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        out.write_all(&record)?;
+    }
+    let end_offset = out.stream_position()?;
+    let (count, dir_size) = (members.len() as u64, end_offset - dir_offset);
+    let zip64 = count >= 0xffff || dir_size >= 0xffff_ffff || dir_offset >= 0xffff_ffff;
+    let mut end = Vec::with_capacity(56 + 20 + END_SIZE);
+    if zip64 {
+        end.extend_from_slice(&ZIP64_END_SIG.to_le_bytes());
+        end.extend_from_slice(&44u64.to_le_bytes());
+        end.extend_from_slice(&(3u16 << 8 | 45).to_le_bytes());
+        end.extend_from_slice(&45u16.to_le_bytes());
+        end.extend_from_slice(&[0; 8]);
+        for value in &[count, count, dir_size, dir_offset] {
+            end.extend_from_slice(&value.to_le_bytes());
+        }
+        end.extend_from_slice(&ZIP64_LOCATOR_SIG.to_le_bytes());
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&end_offset.to_le_bytes());
+        end.extend_from_slice(&1u32.to_le_bytes());
+    }
+    let narrow = |value: u64, max: u64| if zip64 { max } else { value };
+    end.extend_from_slice(&END_SIG.to_le_bytes());
+    end.extend_from_slice(&[0; 4]);
+    for _ in 0..2 {
+        end.extend_from_slice(&(narrow(count, 0xffff) as u16).to_le_bytes());
+    }
+    end.extend_from_slice(&(narrow(dir_size, 0xffff_ffff) as u32).to_le_bytes());
+    end.extend_from_slice(&(narrow(dir_offset, 0xffff_ffff) as u32).to_le_bytes());
+    end.extend_from_slice(&[0; 2]);
+    out.write_all(&end)?;
+    Ok(())
+}
+
+fn write_archive<T: StorageType>(entries: &[(&str, &Dense<T>)], path: &Path, compressed: bool)
+    -> Result<(), OoclaError> {
+    let mut out = BufWriter::with_capacity(1 << 20, File::create(path)?);
+    let mut members = Vec::with_capacity(entries.len());
+    for &(name, a) in entries {
+        members.push(write_member(&mut out, name, a, compressed)?);
+    }
+    write_directory(&mut out, &members)?;
+    out.flush()?;
+    Ok(())
+}
+
+// Writes each matrix as a member of a .npz archive readable by numpy.load, as numpy.savez does,
+// or numpy.savez_compressed when `compressed` is set. Elements are streamed from the mapping, so
+// no member is materialised in memory. A partly written archive is removed on failure.
+pub fn export<T: StorageType>(entries: &[(&str, &Dense<T>)], path: &Path, compressed: bool)
+    -> Result<(), OoclaError> {
+    let mut names = HashSet::new();
+    if let Some(&(name, _)) = entries.iter().find(|&&(name, _)| !names.insert(name)) {
+        return Err(OoclaError::InvalidArgument(format!("duplicate archive member {}", name)));
+    }
+    write_archive(entries, path, compressed).inspect_err(|_| {
+        let _ = fs::remove_file(path);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // A stored zip archive of the given members, written independently of write_archive.
+    fn stored_zip(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let (mut zip, mut dir) = (Vec::new(), Vec::new());
+        for &(name, data) in members {
+            let mut crc = Crc32::new();
+            crc.update(data);
+            let offset = zip.len() as u32;
+            // Method, time, date, checksum, sizes and name and extra lengths, which the local and
+            // central headers share.
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&METHOD_STORED.to_le_bytes());
+            fields.extend_from_slice(&[0; 2]);
+            fields.extend_from_slice(&DOS_DATE.to_le_bytes());
+            fields.extend_from_slice(&crc.value().to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&[0; 2]);
+            zip.extend_from_slice(&LOCAL_HEADER_SIG.to_le_bytes());
+            zip.extend_from_slice(&[20, 0, 0, 0]);
+            zip.extend_from_slice(&fields);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(data);
+            dir.extend_from_slice(&CENTRAL_HEADER_SIG.to_le_bytes());
+            dir.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            dir.extend_from_slice(&fields);
+            dir.extend_from_slice(&[0; 10]);
+            dir.extend_from_slice(&offset.to_le_bytes());
+            dir.extend_from_slice(name.as_bytes());
+        }
+        let dir_offset = zip.len() as u32;
+        zip.extend_from_slice(&dir);
+        zip.extend_from_slice(&END_SIG.to_le_bytes());
+        zip.extend_from_slice(&[0; 4]);
+        zip.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(members.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(dir.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&dir_offset.to_le_bytes());
+        zip.extend_from_slice(&[0; 2]);
+        zip
+    }
+
+    fn npy_bytes<T: StorageType>(a: &Dense<T>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        npy::write(a, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn elements(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    fn members() -> Vec<(String, Dense<f64>)> {
+        let mut random = Dense::<f64>::create_anonymous(40, 17).unwrap();
+        random.randomise_seeded(9);
+        let mut transposed = Dense::anonymous_from_fn(3, 5, |i, j| (10 * i + j) as f64);
+        transposed.transpose();
+        let single = Dense::anonymous_from_fn(1, 1, |_, _| -2.5);
+        vec![("random".to_string(), random), ("transposed".to_string(), transposed), ("single".to_string(), single)]
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.value(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn multi_member_archives_round_trip() {
+        let members = members();
+        let entries: Vec<(&str, &Dense<f64>)> = members.iter().map(|(name, a)| (name.as_str(), a)).collect();
+        for &compressed in &[false, true] {
+            let archive = TempMatrixPath::new();
+            export(&entries, archive.path(), compressed).unwrap();
+            let listing = list(archive.path()).unwrap();
+            assert!(listing.skipped.is_empty());
+            let expected: Vec<NpzEntry> = members.iter().map(|(name, a)| NpzEntry {
+                name: name.clone(),
+                shape: (a.num_rows(), a.num_cols()),
+                dtype: FloatType::Double,
+            }).collect();
+            assert_eq!(listing.entries, expected);
+            for (name, a) in members.iter() {
+                let dst = TempMatrixPath::new();
+                let b = import_member::<f64>(archive.path(), name, dst.path()).unwrap();
+                assert_eq!(b.is_transposed(), a.is_transposed());
+                assert_eq!(elements(&b), elements(a), "member {} compressed {}", name, compressed);
+            }
+            let dst = TempMatrixPath::new();
+            let b = import_member::<f64>(archive.path(), "single.npy", dst.path()).unwrap();
+            assert_eq!(b.get(0, 0), -2.5);
+        }
+    }
+
+    #[test]
+    fn archives_from_another_writer_are_read_and_odd_members_skipped() {
+        let a = Dense::<f32>::anonymous_from_fn(2, 3, |i, j| (i + j) as f32);
+        let matrix = npy_bytes(&a);
+        let mut vector = matrix.clone();
+        let shape_at = vector.windows(6).position(|w| w == b"(2, 3)").unwrap();
+        vector[shape_at..shape_at + 6].copy_from_slice(b"(6,)  ");
+        let archive = TempMatrixPath::new();
+        fs::write(archive.path(), stored_zip(&[("notes.txt", b"hello"), ("a.npy", &matrix), ("v.npy", &vector)]))
+            .unwrap();
+        let listing = list(archive.path()).unwrap();
+        assert_eq!(listing.entries, vec![NpzEntry { name: "a".to_string(), shape: (2, 3), dtype: FloatType::Single }]);
+        let skipped: Vec<&str> = listing.skipped.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(skipped, vec!["notes.txt", "v.npy"]);
+        let dst = TempMatrixPath::new();
+        let b = import_member::<f32>(archive.path(), "a", dst.path()).unwrap();
+        assert_eq!([b.get(0, 0), b.get(1, 2)], [0.0, 3.0]);
+    }
+
+    #[test]
+    fn corrupt_members_fail_their_checksum() {
+        let a = Dense::<f64>::anonymous_from_fn(8, 8, |i, j| (i * j) as f64);
+        let mut zip = stored_zip(&[("a.npy", &npy_bytes(&a))]);
+        // A byte of the payload, past the 30-byte local header, the name and the npy header.
+        zip[30 + 5 + 128 + 100] ^= 0x10;
+        let (archive, dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        fs::write(archive.path(), &zip).unwrap();
+        match import_member::<f64>(archive.path(), "a", dst.path()) {
+            Err(OoclaError::InvalidFormat(_)) => {}
+            other => panic!("expected a checksum failure, got {:?}", other.map(|_| ())),
+        }
+        assert!(!dst.path().exists());
+    }
+
+    #[test]
+    fn missing_and_duplicate_members_are_rejected() {
+        let members = members();
+        let archive = TempMatrixPath::new();
+        assert!(export(&[("x", &members[0].1), ("x", &members[2].1)], archive.path(), false).is_err());
+        export(&[("x", &members[0].1)], archive.path(), false).unwrap();
+        let dst = TempMatrixPath::new();
+        assert!(import_member::<f64>(archive.path(), "y", dst.path()).is_err());
+        assert!(import_member::<f32>(archive.path(), "x", dst.path()).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use dense_matrix::{Dense, as_bytes_mut};
+use error::OoclaError;
+use io::npy::read_exact_or_truncated;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// The first integer of every matrix written by PETSc's binary viewer.
+const MAT_FILE_CLASSID: i64 = 1_211_216;
+// The nonzero count MatView records for a dense matrix, whose values follow the header directly.
+const MATRIX_BINARY_FORMAT_DENSE: i64 = -1;
+
+// The header of a PETSc binary matrix. Integers are 32 bits wide unless PETSc was configured
+// with 64-bit indices, which the class id reveals.
+struct PetscHeader {
+    rows: u64,
+    cols: u64,
+    nonzeros: i64,
+}
+
+fn read_header<R: Read>(r: &mut R) -> Result<PetscHeader, OoclaError> {
+    let mut first = [0u8; 8];
+    read_exact_or_truncated(r, &mut first, "PETSc header")?;
+    let wide = if i64::from(i32::from_be_bytes([first[0], first[1], first[2], first[3]])) == MAT_FILE_CLASSID {
+        false
+    } else if i64::from_be_bytes(first) == MAT_FILE_CLASSID {
+        true
+    } else {
+        return Err(OoclaError::InvalidFormat("not a PETSc binary matrix: bad class id".to_string()));
+    };
+    let mut fields = [0i64; 3];
+    if wide {
+        let mut buf = [0u8; 24];
+        read_exact_or_truncated(r, &mut buf, "PETSc header")?;
+        for (field, bytes) in fields.iter_mut().zip(buf.chunks(8)) {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(bytes);
+            *field = i64::from_be_bytes(word);
+        }
+    } else {
+        let mut buf = [0u8; 8];
+        read_exact_or_truncated(r, &mut buf, "PETSc header")?;
+        let words = [&first[4..8], &buf[0..4], &buf[4..8]];
+        for (field, bytes) in fields.iter_mut().zip(words.iter()) {
+            *field = i64::from(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        }
+    }
+    if fields[0] < 0 || fields[1] < 0 {
+        return Err(OoclaError::InvalidFormat(format!("negative PETSc matrix dimensions {}x{}", fields[0],
+                                                     fields[1])));
+    }
+    Ok(PetscHeader { rows: fields[0] as u64, cols: fields[1] as u64, nonzeros: fields[2] })
+}
+
+// Imports a dense matrix written by PETSc's binary viewer into a new matrix at `dst`, a row at a
+// time. Sparse (AIJ) matrices are rejected.
+pub fn import(src: &Path, dst: &Path) -> Result<Dense<f64>, OoclaError> {
+    let mut reader = BufReader::with_capacity(1 << 20, File::open(src)?);
+    let header = read_header(&mut reader)?;
+    if header.nonzeros != MATRIX_BINARY_FORMAT_DENSE {
+        return Err(OoclaError::InvalidFormat(format!(
+            "{}x{} PETSc matrix with {} nonzeros is sparse (AIJ); only dense matrices can be imported until \
+             CSR import is supported", header.rows, header.cols, header.nonzeros)));
+    }
+    let mut result = Dense::create(dst, header.rows, header.cols)?;
+    let copied = (0..result.major_len()).try_for_each(|row| {
+        let values: &mut [f64] = result.major_slice_mut(row);
+        read_exact_or_truncated(&mut reader, as_bytes_mut(values), "PETSc matrix values")?;
+        for value in values.iter_mut() {
+            *value = f64::from_bits(u64::from_be(value.to_bits()));
+        }
+        Ok(())
+    });
+    if let Err(e) = copied {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+fn write_file(a: &Dense<f64>, header: &[i32; 4], dst: &Path) -> Result<(), OoclaError> {
+    let mut writer = BufWriter::with_capacity(1 << 20, File::create(dst)?);
+    for field in header {
+        writer.write_all(&field.to_be_bytes())?;
+    }
+    let mut scratch = vec![0.0; a.num_cols() as usize];
+    let mut bytes = Vec::with_capacity(scratch.len() * 8);
+    for row in 0..a.num_rows() {
+        bytes.clear();
+        for value in a.row_view(row, &mut scratch) {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        writer.write_all(&bytes)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Writes A in PETSc's binary viewer format as a dense matrix that MatLoad can read, with the
+// big-endian 32-bit header and row-major values PETSc expects. A partly written file is removed
+// on failure.
+pub fn export(a: &Dense<f64>, dst: &Path) -> Result<(), OoclaError> {
+    let dimension = |n: u64| if n <= i32::MAX as u64 {
+        Ok(n as i32)
+    } else {
+        Err(OoclaError::SizeOverflow(format!("dimension {} exceeds PETSc's 32-bit indices", n)))
+    };
+    let header = [MAT_FILE_CLASSID as i32, dimension(a.num_rows())?, dimension(a.num_cols())?,
+                  MATRIX_BINARY_FORMAT_DENSE as i32];
+    write_file(a, &header, dst).inspect_err(|_| {
+        let _ = fs::remove_file(dst);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use std::path::PathBuf;
+
+    const DENSE: [[f64; 4]; 3] = [[1.5, -2.0, 0.0, 3.25], [1e-3, 4.0, -5.5, 6.0], [7.0, 8.125, 9.0, -1e10]];
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/petsc").join(name)
+    }
+
+    fn rows(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        a.row_iter().map(|r| r.to_vec()).collect()
+    }
+
+    fn expect_invalid_format(src: &Path) {
+        let dst = TempMatrixPath::new();
+        match import(src, dst.path()) {
+            Err(OoclaError::InvalidFormat(_)) => {}
+            other => panic!("expected an invalid format, got {:?}", other.map(|_| ())),
+        }
+        assert!(!dst.path().exists());
+    }
+
+    #[test]
+    fn import_reads_a_dense_matrix() {
+        let dst = TempMatrixPath::new();
+        let a = import(&fixture("dense_3x4.bin"), dst.path()).unwrap();
+        assert_eq!((a.num_rows(), a.num_cols()), (3, 4));
+        assert_eq!(rows(&a), DENSE.iter().map(|r| r.to_vec()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn import_reads_a_header_with_64_bit_indices() {
+        let dst = TempMatrixPath::new();
+        let a = import(&fixture("dense_2x3_int64.bin"), dst.path()).unwrap();
+        assert_eq!(rows(&a), vec![vec![0.5, -1.0, 2.0], vec![3.0, 4.5, -6.0]]);
+    }
+
+    #[test]
+    fn export_writes_what_petsc_writes_and_round_trips() {
+        let expected = fs::read(fixture("dense_3x4.bin")).unwrap();
+        // The same matrix stored transposed is written row by row all the same.
+        let straight = Dense::anonymous_from_fn(3, 4, |i, j| DENSE[i as usize][j as usize]);
+        let mut transposed = Dense::anonymous_from_fn(4, 3, |i, j| DENSE[j as usize][i as usize]);
+        transposed.transpose();
+        for a in [straight, transposed].iter() {
+            let file = TempMatrixPath::new();
+            export(a, file.path()).unwrap();
+            assert_eq!(fs::read(file.path()).unwrap(), expected);
+            let dst = TempMatrixPath::new();
+            assert_eq!(rows(&import(file.path(), dst.path()).unwrap()), rows(a));
+        }
+    }
+
+    #[test]
+    fn sparse_matrices_are_rejected() {
+        expect_invalid_format(&fixture("aij_3x3.bin"));
+    }
+
+    #[test]
+    fn bad_class_ids_and_truncated_files_leave_nothing_behind() {
+        let bytes = fs::read(fixture("dense_3x4.bin")).unwrap();
+        let src = TempMatrixPath::new();
+        let mut corrupt = bytes.clone();
+        corrupt[3] ^= 1;
+        fs::write(src.path(), &corrupt).unwrap();
+        expect_invalid_format(src.path());
+        for &len in [12, bytes.len() - 1].iter() {
+            fs::write(src.path(), &bytes[..len]).unwrap();
+            expect_invalid_format(src.path());
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use dense_matrix::{Dense, StorageType, as_bytes_mut};
+use describe::Endianness;
+use error::OoclaError;
+use row_writer::RowWriter;
+use std::cmp;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read};
+use std::mem;
+use std::path::Path;
+
+// Bytes read from the stream at a time.
+const STREAM_CHUNK_BYTES: usize = 1 << 20;
+
+// Reads until `buf` is full or the stream ends, returning the number of bytes read.
+fn fill<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<usize, OoclaError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+fn swap_if_foreign(bytes: &mut [u8], element_size: usize, endianness: Endianness) {
+    let foreign = match endianness {
+        Endianness::Little => cfg!(target_endian = "big"),
+        Endianness::Big => cfg!(target_endian = "little"),
+        Endianness::Unknown => unreachable!(),
+    };
+    if foreign && element_size > 1 {
+        for element in bytes.chunks_mut(element_size) {
+            element.reverse();
+        }
+    }
+}
+
+fn read_known_rows<T: StorageType, R: Read>(r: &mut R, result: &mut Dense<T>, endianness: Endianness)
+    -> Result<(), OoclaError> {
+    let size = mem::size_of::<T>();
+    let bytes = as_bytes_mut(result.storage_mut());
+    let expected = bytes.len();
+    let mut received = 0;
+    for chunk in bytes.chunks_mut(STREAM_CHUNK_BYTES / size * size) {
+        let filled = fill(r, chunk)?;
+        received += filled;
+        if filled < chunk.len() {
+            return Err(OoclaError::InvalidFormat(format!("raw stream ended after {} of {} bytes", received,
+                                                         expected)));
+        }
+        swap_if_foreign(chunk, size, endianness);
+    }
+    Ok(())
+}
+
+fn read_until_eof<T: StorageType, R: Read>(r: &mut R, dst: &Path, cols: u64, endianness: Endianness)
+    -> Result<Dense<T>, OoclaError> {
+    let size = mem::size_of::<T>();
+    let row_len = usize::try_from(cols).ok().filter(|&n| n.checked_mul(size).is_some())
+        .ok_or_else(|| OoclaError::SizeOverflow(format!("a row of {} elements", cols)))?;
+    let mut rows = vec![T::default(); cmp::max(1, STREAM_CHUNK_BYTES / (row_len * size)) * row_len];
+    let mut writer = RowWriter::create(dst, cols)?;
+    loop {
+        let filled = fill(r, as_bytes_mut(&mut rows))?;
+        if filled % (row_len * size) != 0 {
+            let received = writer.rows_written() * (row_len * size) as u64 + filled as u64;
+            return Err(OoclaError::InvalidFormat(format!("raw stream ended after {} bytes, part way through a \
+                                                          row of {} bytes", received, row_len * size)));
+        }
+        let complete = filled / size;
+        swap_if_foreign(&mut as_bytes_mut(&mut rows)[..filled], size, endianness);
+        for row in rows[..complete].chunks(row_len) {
+            writer.write_row(row)?;
+        }
+        if complete < rows.len() {
+            return writer.finish();
+        }
+    }
+}
+
+// Imports raw elements of T in row-major order from `r`, such as a pipe, into a new matrix at
+// `dst`, swapping their bytes when `endianness` isn't this machine's. With `rows` given,
+// exactly that many rows are read and a stream that ends early is an error; without, whole
+// rows are read until the stream ends. The stream is read in large chunks straight into the
+// destination, and a partly written `dst` is removed on failure.
+pub fn import_stream<T: StorageType, R: Read>(mut r: R, dst: &Path, rows: Option<u64>, cols: u64,
+                                              endianness: Endianness) -> Result<Dense<T>, OoclaError> {
+    if endianness == Endianness::Unknown {
+        return Err(OoclaError::InvalidArgument("the byte order of a raw stream must be given".to_string()));
+    }
+    let rows = match rows {
+        Some(rows) => rows,
+        None if cols == 0 => {
+            return Err(OoclaError::InvalidArgument("rows of no columns can't be counted from a stream"
+                .to_string()));
+        }
+        None => return read_until_eof(&mut r, dst, cols, endianness),
+    };
+    let mut result = Dense::create(dst, rows, cols)?;
+    if let Err(e) = read_known_rows(&mut r, &mut result, endianness) {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use std::io::{Cursor, Write};
+    use std::thread;
+
+    fn elements<T: StorageType>(a: &Dense<T>) -> Vec<Vec<T>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    fn expected(rows: u64, cols: u64) -> Vec<Vec<f64>> {
+        (0..rows).map(|i| (0..cols).map(|j| (i * cols + j) as f64 * 0.5).collect()).collect()
+    }
+
+    fn stream(rows: u64, cols: u64, endianness: Endianness) -> Vec<u8> {
+        expected(rows, cols).iter().flatten().flat_map(|v| match endianness {
+            Endianness::Big => v.to_be_bytes(),
+            _ => v.to_le_bytes(),
+        }).collect()
+    }
+
+    fn rejected(result: Result<Dense<f64>, OoclaError>, dst: &Path) -> String {
+        assert!(!dst.exists());
+        match result {
+            Err(OoclaError::InvalidFormat(msg)) => msg,
+            other => panic!("expected an invalid format, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn known_shapes_are_read_from_a_cursor_in_either_byte_order() {
+        for &endianness in &[Endianness::Little, Endianness::Big] {
+            for &(rows, cols) in &[(3, 4), (1, 1), (0, 5), (50_000, 7)] {
+                let dst = TempMatrixPath::new();
+                let bytes = stream(rows, cols, endianness);
+                let a = import_stream::<f64, _>(Cursor::new(bytes), dst.path(), Some(rows), cols, endianness).unwrap();
+                assert_eq!(elements(&a), expected(rows, cols), "{}x{} {:?}", rows, cols, endianness);
+            }
+        }
+        // Trailing bytes beyond the shape are left unread.
+        let mut cursor = Cursor::new(stream(2, 2, Endianness::Little));
+        let dst = TempMatrixPath::new();
+        let a = import_stream::<f64, _>(&mut cursor, dst.path(), Some(1), 2, Endianness::Little).unwrap();
+        assert_eq!((elements(&a), cursor.position()), (expected(1, 2), 16));
+    }
+
+    #[test]
+    fn rows_are_counted_until_the_stream_ends() {
+        // Enough rows to take several reads.
+        for &rows in &[0, 1, 100_000] {
+            let dst = TempMatrixPath::new();
+            let bytes = stream(rows, 3, Endianness::Big);
+            let a = import_stream::<f64, _>(Cursor::new(bytes), dst.path(), None, 3, Endianness::Big).unwrap();
+            assert_eq!(elements(&a), expected(rows, 3));
+            assert_eq!(elements(&Dense::<f64>::open(dst.path()).unwrap()), expected(rows, 3));
+        }
+    }
+
+    #[test]
+    fn short_streams_report_the_bytes_received() {
+        let bytes = stream(4, 3, Endianness::Little);
+        let dst = TempMatrixPath::new();
+        let result = import_stream::<f64, _>(&bytes[..90], dst.path(), Some(4), 3, Endianness::Little);
+        assert_eq!(rejected(result, dst.path()), "raw stream ended after 90 of 96 bytes");
+        let result = import_stream::<f64, _>(&bytes[..90], dst.path(), None, 3, Endianness::Little);
+        assert_eq!(rejected(result, dst.path()), "raw stream ended after 90 bytes, part way through a row of 24 bytes");
+    }
+
+    #[test]
+    fn streams_are_read_from_a_pipe() {
+        let (reader, mut writer) = io::pipe().unwrap();
+        let bytes = stream(20_000, 9, Endianness::Little);
+        let sender = thread::spawn(move || {
+            // In uneven pieces, so reads return part of what was asked for.
+            for piece in bytes.chunks(1000 * 8 + 3) {
+                writer.write_all(piece).unwrap();
+            }
+        });
+        let dst = TempMatrixPath::new();
+        let a = import_stream::<f64, _>(reader, dst.path(), None, 9, Endianness::Little).unwrap();
+        sender.join().unwrap();
+        assert_eq!(elements(&a), expected(20_000, 9));
+
+        // A writer that gives up early.
+        let (reader, mut writer) = io::pipe().unwrap();
+        let sender = thread::spawn(move || writer.write_all(&stream(3, 9, Endianness::Little)).unwrap());
+        let dst = TempMatrixPath::new();
+        let result = import_stream::<f64, _>(reader, dst.path(), Some(4), 9, Endianness::Little);
+        sender.join().unwrap();
+        assert_eq!(rejected(result, dst.path()), "raw stream ended after 216 of 288 bytes");
+    }
+
+    #[test]
+    fn unknown_byte_orders_and_uncountable_rows_are_rejected() {
+        let dst = TempMatrixPath::new();
+        for &(rows, cols, endianness) in &[(Some(1), 1, Endianness::Unknown), (None, 0, Endianness::Little)] {
+            match import_stream::<f64, _>(&[0u8; 8][..], dst.path(), rows, cols, endianness) {
+                Err(OoclaError::InvalidArgument(_)) => {}
+                other => panic!("expected an invalid argument, got {:?}", other.map(|_| ())),
+            }
+            assert!(!dst.path().exists());
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use dense_matrix::SupportedType;
+use error::OoclaError;
+use io::matrix_market::{CoordinateMatrix, CoordinateTarget, assemble};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+fn invalid(line: usize, msg: String) -> OoclaError {
+    OoclaError::InvalidFormat(format!("Rutherford-Boeing line {}: {}", line, msg))
+}
+
+// The shape of a data section's Fortran edit descriptor, such as (16I5) or (1P,4E20.12): how
+// many fields fill a line and how wide each one is.
+#[derive(Clone, Copy, Debug)]
+struct FortranFormat {
+    per_line: usize,
+    width: usize,
+}
+
+fn parse_format(text: &str, line: usize) -> Result<FortranFormat, OoclaError> {
+    let bad = || invalid(line, format!("unsupported Fortran format '{}'", text));
+    let inner = text.trim().trim_start_matches('(').trim_end_matches(')').to_ascii_uppercase();
+    // A scale factor only affects fields without an exponent, which SuiteSparse never writes.
+    let inner = match inner.find('P') {
+        Some(p) => inner[p + 1..].trim_start_matches(',').to_string(),
+        None => inner,
+    };
+    let letter = inner.find(|c: char| "IEDFG".contains(c)).ok_or_else(bad)?;
+    let per_line = if letter == 0 { 1 } else { inner[..letter].parse().map_err(|_| bad())? };
+    let width_text = inner[letter + 1..].split('.').next().unwrap_or("");
+    let width = width_text.parse().map_err(|_| bad())?;
+    if per_line == 0 || width == 0 {
+        return Err(bad());
+    }
+    Ok(FortranFormat { per_line, width })
+}
+
+// Parses a Fortran real, whose exponent may be marked with D or, when it has three digits, by its
+// sign alone, as in 1.0D+00 or 0.1-100.
+fn parse_real(text: &str) -> Option<f64> {
+    let mut text = text.replace(['D', 'd'], "E");
+    if !text.contains(['E', 'e']) {
+        if let Some(sign) = text.rfind(['+', '-']).filter(|&i| i > 0) {
+            text.insert(sign, 'E');
+        }
+    }
+    text.parse().ok()
+}
+
+struct Lines<R> {
+    inner: io::Lines<R>,
+    number: usize,
+}
+
+impl<R: BufRead> Lines<R> {
+    fn next(&mut self, what: &str) -> Result<(usize, String), OoclaError> {
+        self.number += 1;
+        match self.inner.next() {
+            Some(line) => Ok((self.number, line?)),
+            None => Err(OoclaError::InvalidFormat(format!("Rutherford-Boeing file is truncated in {}", what))),
+        }
+    }
+
+    // Passes the first `count` fields of the section that starts on the next line to `f`, which
+    // parses each one. Sections start on a fresh line and fields are split by column, since
+    // adjacent fields needn't be separated by spaces.
+    fn read_fields<F>(&mut self, format: FortranFormat, count: u64, what: &str, mut f: F) -> Result<(), OoclaError>
+        where F: FnMut(usize, &str) -> Result<(), OoclaError> {
+        let mut read = 0;
+        while read < count {
+            let (i, line) = self.next(what)?;
+            let line = line.as_bytes();
+            for field in line.chunks(format.width).take(format.per_line) {
+                let field = String::from_utf8_lossy(field);
+                if read == count || field.trim().is_empty() {
+                    break;
+                }
+                f(i, field.trim())?;
+                read += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header_ints(line: usize, text: &str, at_least: usize, what: &str) -> Result<Vec<i64>, OoclaError> {
+    let values = text.split_whitespace().map(|t| t.parse::<i64>()).collect::<Result<Vec<_>, _>>();
+    match values {
+        Ok(ref values) if values.len() >= at_least && values.iter().all(|&v| v >= 0) => Ok(values.clone()),
+        _ => Err(invalid(line, format!("expected {}, found '{}'", what, text.trim()))),
+    }
+}
+
+// Imports a Rutherford-Boeing or Harwell-Boeing file of an assembled real, integer or pattern
+// matrix into a new matrix at `dst`. The column pointers and row indices are held in memory while
+// the values are streamed. Symmetric and skew-symmetric storage is expanded, and pattern matrices
+// are read with every entry one only when `fill_pattern` is set.
+pub fn import<T: SupportedType>(src: &Path, dst: &Path, target: CoordinateTarget, fill_pattern: bool)
+    -> Result<CoordinateMatrix<T>, OoclaError> {
+    let mut lines = Lines { inner: BufReader::with_capacity(1 << 20, File::open(src)?).lines(), number: 0 };
+    let (_, title) = lines.next("the header")?;
+    let key = title.get(72..).unwrap_or("").trim().to_string();
+    let (i, counts) = lines.next("the header")?;
+    let counts = header_ints(i, &counts, 4, "the card counts")?;
+    // Only Harwell-Boeing has a fifth count, of right-hand side lines, and a header line
+    // describing them when there are any.
+    let has_rhs = counts.get(4).is_some_and(|&rhs| rhs > 0);
+
+    let (i, kind) = lines.next("the header")?;
+    let code = kind.get(..3).unwrap_or("").to_ascii_uppercase();
+    let dims = header_ints(i, kind.get(3..).unwrap_or(""), 3, "the row, column and entry counts")?;
+    let (rows, cols, nnz) = (dims[0] as u64, dims[1] as u64, dims[2] as u64);
+    let mxtype: Vec<char> = code.chars().collect();
+    if mxtype.len() != 3 {
+        return Err(invalid(i, format!("'{}' is not a matrix type", kind.trim())));
+    }
+    let pattern = match mxtype[0] {
+        'R' | 'I' => false,
+        'P' if fill_pattern => true,
+        'P' => {
+            return Err(OoclaError::InvalidArgument(format!(
+                "{} is a pattern matrix without values; import it with fill_pattern to read its entries as 1.0",
+                if key.is_empty() { "the file" } else { key.as_str() })));
+        }
+        'C' => return Err(invalid(i, "complex matrices are not supported".to_string())),
+        _ => return Err(invalid(i, format!("unknown value type in matrix type {}", code))),
+    };
+    let mirror = match mxtype[1] {
+        'U' | 'R' => None,
+        'S' => Some(1.0),
+        'Z' => Some(-1.0),
+        _ => return Err(invalid(i, format!("unsupported symmetry in matrix type {}", code))),
+    };
+    if mxtype[2] != 'A' {
+        return Err(invalid(i, "only assembled matrices are supported, not elemental ones".to_string()));
+    }
+    if mirror.is_some() && rows != cols {
+        return Err(invalid(i, format!("a {}x{} matrix can't be symmetric", rows, cols)));
+    }
+
+    let (i, formats) = lines.next("the header")?;
+    let formats = formats.split('(').skip(1)
+        .map(|f| parse_format(f.split(')').next().unwrap_or(""), i))
+        .collect::<Result<Vec<_>, _>>()?;
+    if formats.len() < if pattern { 2 } else { 3 } {
+        return Err(invalid(i, "missing a pointer, index or value format".to_string()));
+    }
+    if has_rhs {
+        lines.next("the header")?;
+    }
+
+    let mut col_ptr = Vec::with_capacity(cols as usize + 1);
+    lines.read_fields(formats[0], cols + 1, "the column pointers", |i, field| {
+        let ptr = field.parse::<u64>().map_err(|_| invalid(i, format!("'{}' is not a column pointer", field)))?;
+        if ptr == 0 || ptr > nnz + 1 || col_ptr.last().is_some_and(|&last| ptr - 1 < last) {
+            return Err(invalid(i, format!("column pointer {} is out of order or range", ptr)));
+        }
+        col_ptr.push(ptr - 1);
+        Ok(())
+    })?;
+    if col_ptr[0] != 0 || col_ptr[cols as usize] != nnz {
+        return Err(OoclaError::InvalidFormat(format!(
+            "Rutherford-Boeing column pointers span entries {} to {}, not the {} declared", col_ptr[0] + 1,
+            col_ptr[cols as usize], nnz)));
+    }
+    let mut row_idx = Vec::with_capacity(nnz as usize);
+    lines.read_fields(formats[1], nnz, "the row indices", |i, field| {
+        match field.parse::<u64>() {
+            Ok(row) if row >= 1 && row <= rows => {
+                row_idx.push(row - 1);
+                Ok(())
+            }
+            _ => Err(invalid(i, format!("'{}' is not a row index of the {}x{} matrix", field, rows, cols))),
+        }
+    })?;
+
+    assemble(dst, rows, cols, target, |add| {
+        let mut k = 0;
+        let mut col = 0;
+        let mut emit = |value: f64| {
+            while col_ptr[col as usize + 1] == k {
+                col += 1;
+            }
+            let row = row_idx[k as usize];
+            add(row, col, value);
+            if let Some(sign) = mirror.filter(|_| row != col) {
+                add(col, row, sign * value);
+            }
+            k += 1;
+        };
+        if pattern {
+            (0..nnz).for_each(|_| emit(1.0));
+            return Ok(());
+        }
+        lines.read_fields(formats[2], nnz, "the values", |i, field| {
+            let value = parse_real(field).ok_or_else(|| invalid(i, format!("'{}' is not a number", field)))?;
+            emit(value);
+            Ok(())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/rb").join(name)
+    }
+
+    fn dense(src: &Path, fill_pattern: bool) -> Result<Vec<Vec<f64>>, OoclaError> {
+        let dst = TempMatrixPath::new();
+        let result = import::<f64>(src, dst.path(), CoordinateTarget::Dense, fill_pattern);
+        if result.is_err() {
+            assert!(!dst.path().exists());
+        }
+        match result? {
+            CoordinateMatrix::Dense(a) => Ok(a.row_iter().map(|r| r.to_vec()).collect()),
+            CoordinateMatrix::Csr(_) => panic!("asked for a dense matrix"),
+        }
+    }
+
+    fn csr(src: &Path, fill_pattern: bool) -> (u64, u64, Vec<(u64, u64, f64)>) {
+        let dst = TempMatrixPath::new();
+        match import::<f64>(src, dst.path(), CoordinateTarget::Csr, fill_pattern).unwrap() {
+            CoordinateMatrix::Csr(a) => {
+                let entries = a.iter_nonzero().map(|(i, j, &v)| (i, j, v)).collect::<Vec<_>>();
+                assert_eq!(a.nnz(), entries.len() as u64);
+                (a.num_rows(), a.num_cols(), entries)
+            }
+            CoordinateMatrix::Dense(_) => panic!("asked for a CSR matrix"),
+        }
+    }
+
+    #[test]
+    fn unsymmetric_matrices_are_read_column_by_column() {
+        let expected = vec![
+            vec![1.5, 0.0, 3.25],
+            vec![0.0, 0.0, 0.004],
+            vec![-2.0, 0.0, 0.0],
+            vec![0.0, 0.0, -7.0],
+        ];
+        assert_eq!(dense(&fixture("unsym_4x3.rb"), false).unwrap(), expected);
+        let (rows, cols, entries) = csr(&fixture("unsym_4x3.rb"), false);
+        assert_eq!((rows, cols, entries.len()), (4, 3, 5));
+        assert_eq!(entries[1], (0, 2, 3.25));
+        assert_eq!(entries[4], (3, 2, -7.0));
+    }
+
+    #[test]
+    fn symmetric_storage_is_expanded_and_d_exponents_parsed() {
+        let expected = vec![
+            vec![150.0, -0.25, 0.0],
+            vec![-0.25, 0.0, 4.0],
+            vec![0.0, 4.0, 1.0],
+        ];
+        assert_eq!(dense(&fixture("sym_3x3.rb"), false).unwrap(), expected);
+        // Entries off the diagonal are stored once but appear twice.
+        let (rows, cols, entries) = csr(&fixture("sym_3x3.rb"), false);
+        assert_eq!((rows, cols, entries.len()), (3, 3, 6));
+        assert_eq!(entries[0], (0, 0, 150.0));
+        // Skew-symmetric storage mirrors with the sign flipped.
+        let skew = TempMatrixPath::new();
+        fs::write(skew.path(), fs::read_to_string(fixture("sym_3x3.rb")).unwrap().replace("RSA", "RZA")).unwrap();
+        let skewed = dense(skew.path(), false).unwrap();
+        assert_eq!((skewed[0][1], skewed[1][0], skewed[1][2], skewed[2][1]), (0.25, -0.25, -4.0, 4.0));
+    }
+
+    #[test]
+    fn fortran_reals_are_parsed() {
+        let cases = [("1.5D+02", 150.0), ("1.5d-02", 0.015), ("-2.5E+00", -2.5), ("0.1-100", 0.1e-100),
+                     ("1.0+100", 1.0e100), ("-3.0", -3.0), ("42", 42.0)];
+        for &(text, expected) in cases.iter() {
+            assert_eq!(parse_real(text), Some(expected), "{}", text);
+        }
+        assert_eq!(parse_real("1.5X02"), None);
+    }
+
+    #[test]
+    fn pattern_matrices_need_fill_pattern() {
+        let expected = vec![
+            vec![0.0, 1.0, 0.0, 0.0],
+            vec![1.0, 0.0, 0.0, 0.0],
+            vec![0.0, 1.0, 0.0, 1.0],
+        ];
+        assert_eq!(dense(&fixture("pattern_3x4.rb"), true).unwrap(), expected);
+        assert_eq!(csr(&fixture("pattern_3x4.rb"), true).2.len(), 4);
+        match dense(&fixture("pattern_3x4.rb"), false) {
+            Err(OoclaError::InvalidArgument(ref msg)) if msg.contains("OOC/pat") => {}
+            other => panic!("expected the pattern matrix to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_and_out_of_range_data_is_rejected() {
+        let text = fs::read_to_string(fixture("unsym_4x3.rb")).unwrap();
+        let src = TempMatrixPath::new();
+        let truncated = &text[..text.trim_end().rfind('\n').unwrap()];
+        let cases = [truncated.to_string(), text.replacen("    4\n", "    5\n", 1), text.replace("RUA", "RUE"),
+                     text.replace("RUA", "CUA"), text.replace("(16I5)          (16I5)", "(16Q5)          (16I5)")];
+        for (k, case) in cases.iter().enumerate() {
+            fs::write(src.path(), case).unwrap();
+            match dense(src.path(), false) {
+                Err(OoclaError::InvalidFormat(_)) => {}
+                other => panic!("case {}: expected an invalid format, got {:?}", k, other),
+            }
+        }
+    }
+}
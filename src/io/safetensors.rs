@@ -0,0 +1,363 @@
+use dense_matrix::{Dense, FloatType, StorageType, as_bytes, as_bytes_mut};
+use error::OoclaError;
+use io::npy::{Literal, LiteralParser, read_exact_or_truncated};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+
+// The largest header the reference implementation accepts.
+const MAX_HEADER_LEN: u64 = 100 << 20;
+// The header is padded with spaces so the data starts on this alignment.
+const HEADER_ALIGNMENT: usize = 8;
+const METADATA_KEY: &str = "__metadata__";
+
+fn dtype(representation: FloatType) -> &'static str {
+    match representation {
+        FloatType::Single => "F32",
+        FloatType::Double => "F64",
+        FloatType::UInt32 => "U32",
+        FloatType::UInt64 => "U64",
+        FloatType::Int8 => "I8",
+    }
+}
+
+fn parse_dtype(s: &str) -> Option<FloatType> {
+    [FloatType::Single, FloatType::Double, FloatType::UInt32, FloatType::UInt64, FloatType::Int8].iter()
+        .cloned()
+        .find(|&representation| dtype(representation) == s)
+}
+
+// safetensors data is little-endian whatever the machine, so elements are swapped in place on
+// big-endian ones, in both directions.
+fn swap_to_little_endian(bytes: &mut [u8], element_size: usize) {
+    if cfg!(target_endian = "big") {
+        for element in bytes.chunks_mut(element_size) {
+            element.reverse();
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The location of a tensor's bytes within the data that follows the header.
+struct TensorEntry {
+    representation: Result<FloatType, String>,
+    shape: Vec<u64>,
+    begin: u64,
+    end: u64,
+}
+
+fn parse_entry(name: &str, value: Literal) -> Result<TensorEntry, OoclaError> {
+    let invalid = |msg: String| OoclaError::InvalidFormat(format!("safetensors entry '{}': {}", name, msg));
+    let integers = |value: Literal, what: &str| match value {
+        Literal::Tuple(items) => items.into_iter()
+            .map(|item| match item {
+                Literal::Int(n) => Ok(n),
+                other => Err(invalid(format!("non-integer {} {:?}", what, other))),
+            })
+            .collect::<Result<Vec<u64>, OoclaError>>(),
+        other => Err(invalid(format!("{} is {:?}, not a list", what, other))),
+    };
+    let fields = match value {
+        Literal::Dict(fields) => fields,
+        other => return Err(invalid(format!("expected an object, found {:?}", other))),
+    };
+    let (mut representation, mut shape, mut offsets) = (None, None, None);
+    for (key, value) in fields {
+        match (key.as_str(), value) {
+            ("dtype", Literal::Str(s)) => representation = Some(parse_dtype(&s).ok_or(s)),
+            ("shape", value) => shape = Some(integers(value, "dimension")?),
+            ("data_offsets", value) => offsets = Some(integers(value, "data offset")?),
+            (key, value) => return Err(invalid(format!("unexpected field '{}': {:?}", key, value))),
+        }
+    }
+    match (representation, shape, offsets) {
+        (Some(representation), Some(shape), Some(offsets)) => match offsets[..] {
+            [begin, end] if begin <= end => Ok(TensorEntry { representation, shape, begin, end }),
+            _ => Err(invalid(format!("invalid data offsets {:?}", offsets))),
+        },
+        _ => Err(invalid("missing one of dtype, shape and data_offsets".to_string())),
+    }
+}
+
+// Imports the 2-D tensor `name` from a safetensors file into a new matrix at `dst`. Its offsets
+// are checked against the file before anything is copied, and tensors of other ranks are
+// rejected with their shape.
+pub fn import<T: StorageType>(src: &Path, name: &str, dst: &Path) -> Result<Dense<T>, OoclaError> {
+    let mut reader = BufReader::with_capacity(1 << 20, File::open(src)?);
+    let file_len = reader.get_ref().metadata()?.len();
+    let mut len = [0u8; 8];
+    read_exact_or_truncated(&mut reader, &mut len, "safetensors header")?;
+    let header_len = u64::from_le_bytes(len);
+    if header_len > MAX_HEADER_LEN || header_len > file_len - 8 {
+        return Err(OoclaError::InvalidFormat(format!("safetensors header length {} is invalid for a {} byte file",
+                                                     header_len, file_len)));
+    }
+    let mut text = vec![0u8; header_len as usize];
+    read_exact_or_truncated(&mut reader, &mut text, "safetensors header")?;
+    let entries = match LiteralParser::new(&text).value() {
+        Ok(Literal::Dict(entries)) => entries,
+        Ok(other) => {
+            return Err(OoclaError::InvalidFormat(format!("safetensors header is {:?}, not an object", other)));
+        }
+        Err(e) => return Err(OoclaError::InvalidFormat(format!("safetensors header: {}", e))),
+    };
+    let value = entries.into_iter().find(|entry| entry.0 == name && name != METADATA_KEY).map(|entry| entry.1)
+        .ok_or_else(|| OoclaError::InvalidArgument(format!("no tensor named '{}' in {}", name, src.display())))?;
+    let entry = parse_entry(name, value)?;
+    let (rows, cols) = match entry.shape[..] {
+        [rows, cols] => (rows, cols),
+        _ => {
+            return Err(OoclaError::UnsupportedDataset {
+                name: name.to_string(),
+                reason: format!("shape {:?} is not 2-D", entry.shape),
+            });
+        }
+    };
+    let found = entry.representation.map_err(|s| OoclaError::UnsupportedDataset {
+        name: name.to_string(),
+        reason: format!("unsupported dtype {}", s),
+    })?;
+    if found != T::get_float_type() {
+        return Err(OoclaError::TypeMismatch { expected: T::get_float_type(), found });
+    }
+    let data_start = 8 + header_len;
+    let expected = rows.checked_mul(cols).and_then(|n| n.checked_mul(mem::size_of::<T>() as u64));
+    if expected != Some(entry.end - entry.begin) || entry.end > file_len - data_start {
+        return Err(OoclaError::InvalidFormat(format!(
+            "safetensors entry '{}': data offsets [{}, {}] don't hold a {}x{} tensor within the {} bytes of data",
+            name, entry.begin, entry.end, rows, cols, file_len - data_start)));
+    }
+    reader.seek(SeekFrom::Start(data_start + entry.begin))?;
+    let mut result = Dense::create(dst, rows, cols)?;
+    let copied = (0..rows).try_for_each(|row| {
+        let bytes = as_bytes_mut(result.major_slice_mut(row));
+        read_exact_or_truncated(&mut reader, bytes, "safetensors data")?;
+        swap_to_little_endian(bytes, mem::size_of::<T>());
+        Ok(())
+    });
+    if let Err(e) = copied {
+        drop(result);
+        let _ = fs::remove_file(dst);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+fn write_file<T: StorageType>(entries: &[(&str, &Dense<T>)], header: &[u8], path: &Path) -> Result<(), OoclaError> {
+    let mut writer = BufWriter::with_capacity(1 << 20, File::create(path)?);
+    writer.write_all(&(header.len() as u64).to_le_bytes())?;
+    writer.write_all(header)?;
+    for &(_, a) in entries {
+        let _advice = a.advise_row_scan();
+        let mut scratch = vec![T::default(); a.num_cols() as usize];
+        let mut bytes = Vec::with_capacity(scratch.len() * mem::size_of::<T>());
+        for row in 0..a.num_rows() {
+            bytes.clear();
+            bytes.extend_from_slice(as_bytes(a.row_view(row, &mut scratch)));
+            swap_to_little_endian(&mut bytes, mem::size_of::<T>());
+            writer.write_all(&bytes)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Writes the named matrices to a safetensors file, each as a 2-D tensor of its logical row-major
+// elements, streamed a row at a time. A partly written file is removed on failure.
+pub fn export<T: StorageType>(entries: &[(&str, &Dense<T>)], path: &Path) -> Result<(), OoclaError> {
+    let mut json = String::from("{");
+    let mut offset = 0u64;
+    for (i, &(name, a)) in entries.iter().enumerate() {
+        if name == METADATA_KEY || entries[..i].iter().any(|entry| entry.0 == name) {
+            return Err(OoclaError::InvalidArgument(format!("tensor name '{}' is reserved or repeated", name)));
+        }
+        let end = a.num_rows().checked_mul(a.num_cols())
+            .and_then(|n| n.checked_mul(mem::size_of::<T>() as u64))
+            .and_then(|n| n.checked_add(offset))
+            .ok_or_else(|| OoclaError::SizeOverflow(format!("tensor '{}' overflows the file", name)))?;
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str(&format!("{}:{{\"dtype\":\"{}\",\"shape\":[{},{}],\"data_offsets\":[{},{}]}}",
+                               json_string(name), dtype(T::get_float_type()), a.num_rows(), a.num_cols(), offset,
+                               end));
+        offset = end;
+    }
+    json.push('}');
+    let mut header = json.into_bytes();
+    header.resize(header.len().div_ceil(HEADER_ALIGNMENT) * HEADER_ALIGNMENT, b' ');
+    write_file(entries, &header, path).inspect_err(|_| {
+        let _ = fs::remove_file(path);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // A safetensors file as the Python library writes one: the header's length, the compact JSON
+    // header padded with spaces to a multiple of eight bytes, then the data.
+    fn safetensors_file(header: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = header.as_bytes().to_vec();
+        header.resize(header.len().div_ceil(8) * 8, b' ');
+        let mut file = (header.len() as u64).to_le_bytes().to_vec();
+        file.extend_from_slice(&header);
+        file.extend_from_slice(data);
+        file
+    }
+
+    fn le_bytes_f64(values: &[f64]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+    }
+
+    fn le_bytes_f32(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+    }
+
+    fn elements<T: StorageType>(a: &Dense<T>) -> Vec<Vec<T>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    // What safetensors.numpy.save_file writes for {"weight": np.arange(6.0).reshape(2, 3) / 4,
+    // "bias": np.float32([1.5, -2, 3]), "cube": np.zeros((1, 1, 2), np.float32),
+    // "half": np.float16([[1]])} with metadata {"format": "np"}: tensors ordered by decreasing
+    // alignment then name, the metadata first in the header.
+    fn library_fixture() -> Vec<u8> {
+        let header = concat!(
+            r#"{"__metadata__":{"format":"np"},"#,
+            r#""weight":{"dtype":"F64","shape":[2,3],"data_offsets":[0,48]},"#,
+            r#""bias":{"dtype":"F32","shape":[3],"data_offsets":[48,60]},"#,
+            r#""cube":{"dtype":"F32","shape":[1,1,2],"data_offsets":[60,68]},"#,
+            r#""half":{"dtype":"F16","shape":[1,1],"data_offsets":[68,70]}}"#);
+        let mut data = le_bytes_f64(&[0.0, 0.25, 0.5, 0.75, 1.0, 1.25]);
+        data.extend(le_bytes_f32(&[1.5, -2.0, 3.0]));
+        data.extend(le_bytes_f32(&[0.0, 0.0]));
+        data.extend_from_slice(&[0x00, 0x3c]);
+        safetensors_file(header, &data)
+    }
+
+    fn fixture_path(bytes: &[u8]) -> TempMatrixPath {
+        let path = TempMatrixPath::new();
+        fs::write(path.path(), bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn tensors_written_by_the_library_are_imported() {
+        let src = fixture_path(&library_fixture());
+        let dst = TempMatrixPath::new();
+        let weight = import::<f64>(src.path(), "weight", dst.path()).unwrap();
+        assert_eq!(elements(&weight), vec![vec![0.0, 0.25, 0.5], vec![0.75, 1.0, 1.25]]);
+        assert_eq!(elements(&Dense::<f64>::open(dst.path()).unwrap()), elements(&weight));
+    }
+
+    #[test]
+    fn other_ranks_dtypes_and_names_are_rejected() {
+        let src = fixture_path(&library_fixture());
+        for &(name, reason) in &[("bias", "shape [3] is not 2-D"), ("cube", "shape [1, 1, 2] is not 2-D"),
+                                 ("half", "unsupported dtype F16")] {
+            let dst = TempMatrixPath::new();
+            match import::<f32>(src.path(), name, dst.path()) {
+                Err(OoclaError::UnsupportedDataset { name: found, reason: why }) => {
+                    assert_eq!(found, name);
+                    assert_eq!(why, reason);
+                }
+                other => panic!("expected {} to be unsupported, got {:?}", name, other.map(|_| ())),
+            }
+            assert!(!dst.path().exists());
+        }
+        let dst = TempMatrixPath::new();
+        match import::<f32>(src.path(), "weight", dst.path()) {
+            Err(OoclaError::TypeMismatch { expected: FloatType::Single, found: FloatType::Double }) => {}
+            other => panic!("expected a type mismatch, got {:?}", other.map(|_| ())),
+        }
+        for name in &["missing", METADATA_KEY] {
+            match import::<f64>(src.path(), name, dst.path()) {
+                Err(OoclaError::InvalidArgument(_)) => {}
+                other => panic!("expected {} to be missing, got {:?}", name, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn exports_match_the_library_byte_for_byte() {
+        // safetensors.numpy.save_file({"x": np.float32([[1, 2], [3, 4]])}).
+        let expected = safetensors_file(r#"{"x":{"dtype":"F32","shape":[2,2],"data_offsets":[0,16]}}"#,
+                                        &le_bytes_f32(&[1.0, 2.0, 3.0, 4.0]));
+        let a = Dense::<f32>::anonymous_from_fn(2, 2, |i, j| (2 * i + j + 1) as f32);
+        let path = TempMatrixPath::new();
+        export(&[("x", &a)], path.path()).unwrap();
+        assert_eq!(fs::read(path.path()).unwrap(), expected);
+        // Stored transposed, the logical elements are still written by rows.
+        let mut t = Dense::<f32>::anonymous_from_fn(2, 2, |i, j| (2 * j + i + 1) as f32);
+        t.transpose();
+        export(&[("x", &t)], path.path()).unwrap();
+        assert_eq!(fs::read(path.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn several_tensors_round_trip() {
+        let mut a = Dense::<f64>::create_anonymous(17, 5).unwrap();
+        a.randomise_seeded(1);
+        let mut b = Dense::<f64>::create_anonymous(3, 40).unwrap();
+        b.randomise_seeded(2);
+        b.transpose();
+        let empty = Dense::<f64>::create_anonymous(0, 4).unwrap();
+        let path = TempMatrixPath::new();
+        export(&[("a", &a), ("b \"quoted\"", &b), ("empty", &empty)], path.path()).unwrap();
+        let mut len = [0u8; 8];
+        len.copy_from_slice(&fs::read(path.path()).unwrap()[..8]);
+        assert_eq!(u64::from_le_bytes(len) % HEADER_ALIGNMENT as u64, 0);
+        for &(name, matrix) in &[("a", &a), ("b \"quoted\"", &b), ("empty", &empty)] {
+            let dst = TempMatrixPath::new();
+            let imported = import::<f64>(path.path(), name, dst.path()).unwrap();
+            assert_eq!((imported.num_rows(), imported.num_cols()), (matrix.num_rows(), matrix.num_cols()));
+            assert_eq!(elements(&imported), elements(matrix));
+        }
+        match export(&[("a", &a), ("a", &b)], path.path()) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected a repeated name to be rejected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn offsets_outside_the_file_are_rejected() {
+        let data = le_bytes_f64(&[1.0, 2.0]);
+        let cases = [
+            r#"{"x":{"dtype":"F64","shape":[1,2],"data_offsets":[0,24]}}"#,
+            r#"{"x":{"dtype":"F64","shape":[1,2],"data_offsets":[8,24]}}"#,
+            r#"{"x":{"dtype":"F64","shape":[1,2],"data_offsets":[16,0]}}"#,
+            r#"{"x":{"dtype":"F64","shape":[1,2]}}"#,
+        ];
+        for header in &cases {
+            let src = fixture_path(&safetensors_file(header, &data));
+            let dst = TempMatrixPath::new();
+            match import::<f64>(src.path(), "x", dst.path()) {
+                Err(OoclaError::InvalidFormat(_)) => {}
+                other => panic!("expected {} to be rejected, got {:?}", header, other.map(|_| ())),
+            }
+            assert!(!dst.path().exists());
+        }
+        let mut file = safetensors_file(r#"{"x":{"dtype":"F64","shape":[1,2],"data_offsets":[0,16]}}"#, &data);
+        let len = file.len() as u64;
+        file[..8].copy_from_slice(&len.to_le_bytes());
+        let src = fixture_path(&file);
+        let dst = TempMatrixPath::new();
+        assert!(matches!(import::<f64>(src.path(), "x", dst.path()), Err(OoclaError::InvalidFormat(_))));
+    }
+}
@@ -0,0 +1,188 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use io::npy::{Literal, LiteralParser};
+use io::npz::Crc32;
+use std::ffi::OsString;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, Metadata};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SIDECAR_VERSION: u64 = 1;
+
+// A field of a sidecar that doesn't match the matrix it describes. A field missing from the
+// sidecar is recorded as "missing".
+#[derive(Clone, Debug, PartialEq)]
+pub struct SidecarDivergence {
+    pub field: String,
+    pub recorded: String,
+    pub found: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Str(String),
+    Int(u64),
+    Bool(bool),
+}
+
+impl Value {
+    fn from_literal(literal: &Literal) -> Option<Value> {
+        match *literal {
+            Literal::Str(ref s) => Some(Value::Str(s.clone())),
+            Literal::Int(i) => Some(Value::Int(i)),
+            Literal::Bool(b) => Some(Value::Bool(b)),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        match *self {
+            Value::Str(ref s) => {
+                let mut quoted = String::from("\"");
+                for c in s.chars() {
+                    match c {
+                        '"' => quoted.push_str("\\\""),
+                        '\\' => quoted.push_str("\\\\"),
+                        c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+                        c => quoted.push(c),
+                    }
+                }
+                quoted.push('"');
+                quoted
+            }
+            Value::Int(i) => i.to_string(),
+            Value::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+// A sidecar field, and whether verification compares it. Times of modification and of writing
+// the sidecar change legitimately and are only informative.
+struct Field {
+    name: &'static str,
+    value: Value,
+    checked: bool,
+}
+
+fn seconds(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+// The path the matrix was opened from. Anonymous matrices have none.
+fn matrix_path<T>(a: &Dense<T>) -> Option<PathBuf> {
+    let link = fs::read_link(format!("/proc/self/fd/{}", a.file().as_raw_fd())).ok()?;
+    if link.to_string_lossy().ends_with(" (deleted)") {
+        None
+    } else {
+        Some(link)
+    }
+}
+
+fn sidecar_path<T>(a: &Dense<T>, path: Option<&Path>) -> Result<PathBuf, OoclaError> {
+    match (path, matrix_path(a)) {
+        (Some(path), _) => Ok(path.to_path_buf()),
+        (None, Some(matrix)) => {
+            let mut name = OsString::from(matrix.as_os_str());
+            name.push(".json");
+            Ok(PathBuf::from(name))
+        }
+        (None, None) => Err(OoclaError::InvalidArgument("the matrix has no file to put a sidecar next to; give a \
+                                                          sidecar path".to_string())),
+    }
+}
+
+pub(crate) fn crc32(bytes: &[u8]) -> u64 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    u64::from(crc.value())
+}
+
+// The integer field `name` of the sidecar at `path`, if it has one.
+pub(crate) fn recorded_int(path: &Path, name: &str) -> Result<Option<u64>, OoclaError> {
+    let text = fs::read(path)?;
+    match LiteralParser::new(&text).value() {
+        Ok(Literal::Dict(entries)) => Ok(entries.into_iter().find(|entry| entry.0 == name).and_then(|entry| {
+            match entry.1 {
+                Literal::Int(i) => Some(i),
+                _ => None,
+            }
+        })),
+        Ok(_) => Err(OoclaError::InvalidFormat(format!("sidecar {} is not an object", path.display()))),
+        Err(e) => Err(OoclaError::InvalidFormat(format!("sidecar {}: {}", path.display(), e))),
+    }
+}
+
+fn describe<T: StorageType>(a: &Dense<T>, file: &Metadata) -> Vec<Field> {
+    let field = |name, value, checked| Field { name, value, checked };
+    let mut fields = vec![
+        field("version", Value::Int(SIDECAR_VERSION), true),
+        field("rows", Value::Int(a.num_rows()), true),
+        field("cols", Value::Int(a.num_cols()), true),
+        field("dtype", Value::Str(format!("{:?}", T::get_float_type())), true),
+        field("lda", Value::Int(a.lda()), true),
+        field("transposed", Value::Bool(a.is_transposed()), true),
+        field("file_len", Value::Int(file.len()), true),
+        field("header_crc32", Value::Int(crc32(a.header_bytes())), true),
+        field("metadata_len", Value::Int(a.metadata().len() as u64), true),
+        field("metadata_crc32", Value::Int(crc32(a.metadata())), true),
+    ];
+    if let Some(name) = matrix_path(a).as_ref().and_then(|p| p.file_name()) {
+        fields.push(field("file_name", Value::Str(name.to_string_lossy().into_owned()), true));
+    }
+    if let Some(created) = file.created().ok().and_then(seconds) {
+        fields.push(field("created", Value::Int(created), true));
+    }
+    if let Some(modified) = file.modified().ok().and_then(seconds) {
+        fields.push(field("modified", Value::Int(modified), false));
+    }
+    fields
+}
+
+impl<T: StorageType> Dense<T> {
+    // Writes a JSON description of the matrix to `path`, or next to its file with .json appended
+    // to the name, and returns where it went. The sidecar is advisory: opening a matrix never
+    // reads it, but verify_against_sidecar can use it to catch a damaged header or a file swapped
+    // for another.
+    pub fn write_sidecar(&self, path: Option<&Path>) -> Result<PathBuf, OoclaError> {
+        let path = sidecar_path(self, path)?;
+        let mut fields = describe(self, &self.file().metadata()?);
+        if let Some(written) = seconds(SystemTime::now()) {
+            fields.push(Field { name: "written", value: Value::Int(written), checked: false });
+        }
+        let mut json = String::from("{\n");
+        for (i, field) in fields.iter().enumerate() {
+            let separator = if i + 1 < fields.len() { "," } else { "" };
+            writeln!(json, "  \"{}\": {}{}", field.name, field.value.to_json(), separator).unwrap();
+        }
+        json.push_str("}\n");
+        fs::write(&path, json)?;
+        Ok(path)
+    }
+
+    // Compares the matrix with the sidecar at `path`, or next to its file, returning every field
+    // that differs. An empty result means the sidecar still describes the matrix.
+    pub fn verify_against_sidecar(&self, path: Option<&Path>) -> Result<Vec<SidecarDivergence>, OoclaError> {
+        let path = sidecar_path(self, path)?;
+        let text = fs::read(&path)?;
+        let invalid = |msg: String| OoclaError::InvalidFormat(format!("sidecar {}: {}", path.display(), msg));
+        let recorded = match LiteralParser::new(&text).value().map_err(invalid)? {
+            Literal::Dict(entries) => entries,
+            other => return Err(invalid(format!("expected an object, found {:?}", other))),
+        };
+        let mut divergences = Vec::new();
+        for field in describe(self, &self.file().metadata()?).into_iter().filter(|f| f.checked) {
+            let value = recorded.iter().find(|entry| entry.0 == field.name)
+                .and_then(|entry| Value::from_literal(&entry.1));
+            if value.as_ref() != Some(&field.value) {
+                divergences.push(SidecarDivergence {
+                    field: field.name.to_string(),
+                    recorded: value.map_or_else(|| "missing".to_string(), |v| v.to_json()),
+                    found: field.value.to_json(),
+                });
+            }
+        }
+        Ok(divergences)
+    }
+}
@@ -0,0 +1,446 @@
+use dense_matrix::{Dense, as_bytes_mut};
+use error::OoclaError;
+use io::npy::read_exact_or_truncated;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
+
+// Field numbers of TensorProto, TensorShapeProto and TensorShapeProto.Dim in TensorFlow's
+// tensor.proto and tensor_shape.proto.
+const TENSOR_DTYPE: u64 = 1;
+const TENSOR_SHAPE: u64 = 2;
+const TENSOR_CONTENT: u64 = 4;
+const TENSOR_FLOAT_VAL: u64 = 5;
+const SHAPE_DIM: u64 = 2;
+const SHAPE_UNKNOWN_RANK: u64 = 3;
+const DIM_SIZE: u64 = 1;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+// The DataType enum value of DT_FLOAT.
+const DT_FLOAT: u64 = 1;
+// Protobuf implementations refuse to parse messages of 2 GiB or more.
+const MAX_MESSAGE_LEN: u64 = i32::MAX as u64;
+// Larger shapes than this can't be legitimate, so they are rejected before being read.
+const MAX_SHAPE_LEN: u64 = 1 << 10;
+
+fn varint_len(mut value: u64) -> u64 {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, field << 3 | wire_type);
+}
+
+// Reads a varint, or returns None if the stream ends before its first byte.
+fn read_varint<R: Read>(r: &mut R) -> Result<Option<u64>, OoclaError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        match r.read(&mut byte) {
+            Ok(0) if shift == 0 => return Ok(None),
+            Ok(0) => return Err(OoclaError::InvalidFormat("TensorProto varint is truncated".to_string())),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(OoclaError::InvalidFormat("TensorProto varint is longer than 10 bytes".to_string()))
+}
+
+fn expect_varint<R: Read>(r: &mut R) -> Result<u64, OoclaError> {
+    read_varint(r)?.ok_or_else(|| OoclaError::InvalidFormat("TensorProto field is truncated".to_string()))
+}
+
+fn skip_field<R: Read>(r: &mut R, wire_type: u64) -> Result<(), OoclaError> {
+    let len = match wire_type {
+        WIRE_VARINT => return expect_varint(r).map(|_| ()),
+        WIRE_FIXED64 => 8,
+        WIRE_LEN => expect_varint(r)?,
+        WIRE_FIXED32 => 4,
+        _ => return Err(OoclaError::InvalidFormat(format!("unsupported protobuf wire type {}", wire_type))),
+    };
+    if io::copy(&mut r.take(len), &mut io::sink())? != len {
+        return Err(OoclaError::InvalidFormat("TensorProto field is truncated".to_string()));
+    }
+    Ok(())
+}
+
+// The encoded TensorShapeProto of a rows x cols tensor.
+fn encode_shape(rows: u64, cols: u64) -> Vec<u8> {
+    let mut shape = Vec::new();
+    for &size in &[rows, cols] {
+        let mut dim = Vec::new();
+        put_key(&mut dim, DIM_SIZE, WIRE_VARINT);
+        put_varint(&mut dim, size);
+        put_key(&mut shape, SHAPE_DIM, WIRE_LEN);
+        put_varint(&mut shape, dim.len() as u64);
+        shape.extend_from_slice(&dim);
+    }
+    shape
+}
+
+fn parse_shape(bytes: &[u8]) -> Result<Vec<u64>, OoclaError> {
+    let invalid = |msg: &str| OoclaError::InvalidFormat(format!("TensorProto shape: {}", msg));
+    let mut r = bytes;
+    let mut dims = Vec::new();
+    while let Some(key) = read_varint(&mut r)? {
+        match (key >> 3, key & 7) {
+            (SHAPE_DIM, WIRE_LEN) => {
+                let len = expect_varint(&mut r)?;
+                if len > r.len() as u64 {
+                    return Err(invalid("dimension is truncated"));
+                }
+                let (mut dim, rest) = r.split_at(len as usize);
+                r = rest;
+                let mut size = 0;
+                while let Some(key) = read_varint(&mut dim)? {
+                    match (key >> 3, key & 7) {
+                        (DIM_SIZE, WIRE_VARINT) => size = expect_varint(&mut dim)?,
+                        (_, wire_type) => skip_field(&mut dim, wire_type)?,
+                    }
+                }
+                if size > i64::MAX as u64 {
+                    return Err(invalid(&format!("dimension of unknown size {}", size as i64)));
+                }
+                dims.push(size);
+            }
+            (SHAPE_UNKNOWN_RANK, WIRE_VARINT) => {
+                if expect_varint(&mut r)? != 0 {
+                    return Err(invalid("the rank is unknown"));
+                }
+            }
+            (_, wire_type) => skip_field(&mut r, wire_type)?,
+        }
+    }
+    Ok(dims)
+}
+
+// Writes A as a serialized TensorFlow TensorProto of dtype DT_FLOAT and shape [rows, cols], with
+// the elements in row-major order in tensor_content, streamed a row at a time. Matrices too
+// large for a single protobuf message are rejected before anything is written.
+pub fn export<W: Write>(a: &Dense<f32>, w: W) -> Result<(), OoclaError> {
+    let shape = encode_shape(a.num_rows(), a.num_cols());
+    let content_len = a.num_rows().checked_mul(a.num_cols()).and_then(|n| n.checked_mul(4));
+    let mut head = Vec::new();
+    put_key(&mut head, TENSOR_DTYPE, WIRE_VARINT);
+    put_varint(&mut head, DT_FLOAT);
+    put_key(&mut head, TENSOR_SHAPE, WIRE_LEN);
+    put_varint(&mut head, shape.len() as u64);
+    head.extend_from_slice(&shape);
+    let message_len = content_len.and_then(|len| len.checked_add(head.len() as u64 + 1 + varint_len(len)));
+    let content_len = match (content_len, message_len) {
+        (Some(len), Some(message_len)) if message_len <= MAX_MESSAGE_LEN => len,
+        _ => {
+            return Err(OoclaError::SizeOverflow(format!(
+                "a {}x{} float matrix exceeds the 2 GiB limit of a protobuf message; export it in blocks of rows, \
+                 or use npy or safetensors", a.num_rows(), a.num_cols())));
+        }
+    };
+    put_key(&mut head, TENSOR_CONTENT, WIRE_LEN);
+    put_varint(&mut head, content_len);
+    let mut writer = BufWriter::with_capacity(1 << 20, w);
+    writer.write_all(&head)?;
+    let _advice = a.advise_row_scan();
+    let mut scratch = vec![0f32; a.num_cols() as usize];
+    let mut bytes = Vec::with_capacity(scratch.len() * mem::size_of::<f32>());
+    for row in 0..a.num_rows() {
+        bytes.clear();
+        for value in a.row_view(row, &mut scratch) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        writer.write_all(&bytes)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Where the elements of the tensor being imported have got to.
+struct Values {
+    result: Dense<f32>,
+    filled: u64,
+    from_content: bool,
+}
+
+impl Values {
+    fn push(&mut self, value: f32) -> Result<(), OoclaError> {
+        let cols = self.result.num_cols();
+        if self.filled == self.result.num_rows() * cols {
+            return Err(OoclaError::InvalidFormat("TensorProto holds more values than its shape".to_string()));
+        }
+        self.result.set(self.filled / cols, self.filled % cols, value);
+        self.filled += 1;
+        Ok(())
+    }
+}
+
+fn read_tensor<R: Read>(r: &mut R, dst: &Path, values: &mut Option<Values>) -> Result<(), OoclaError> {
+    let mut dtype = 0;
+    let mut dims = None;
+    while let Some(key) = read_varint(r)? {
+        let (field, wire_type) = (key >> 3, key & 7);
+        match (field, wire_type) {
+            (TENSOR_DTYPE, WIRE_VARINT) => dtype = expect_varint(r)?,
+            (TENSOR_SHAPE, WIRE_LEN) => {
+                let len = expect_varint(r)?;
+                if len > MAX_SHAPE_LEN {
+                    return Err(OoclaError::InvalidFormat(format!("TensorProto shape of {} bytes is too long",
+                                                                 len)));
+                }
+                let mut shape = vec![0u8; len as usize];
+                read_exact_or_truncated(r, &mut shape, "TensorProto shape")?;
+                dims = Some(parse_shape(&shape)?);
+            }
+            (TENSOR_CONTENT, WIRE_LEN) | (TENSOR_FLOAT_VAL, WIRE_LEN) | (TENSOR_FLOAT_VAL, WIRE_FIXED32) => {
+                if dtype != DT_FLOAT {
+                    return Err(OoclaError::UnsupportedDataset {
+                        name: "TensorProto".to_string(),
+                        reason: format!("dtype {} is not DT_FLOAT, or follows the values", dtype),
+                    });
+                }
+                let from_content = field == TENSOR_CONTENT;
+                if values.is_none() {
+                    let (rows, cols) = match dims.as_ref().map(|dims| &dims[..]) {
+                        Some(&[rows, cols]) => (rows, cols),
+                        Some(dims) => {
+                            return Err(OoclaError::UnsupportedDataset {
+                                name: "TensorProto".to_string(),
+                                reason: format!("shape {:?} is not 2-D", dims),
+                            });
+                        }
+                        None => {
+                            return Err(OoclaError::InvalidFormat("TensorProto values precede its shape, so \
+                                                                  they can't be streamed".to_string()));
+                        }
+                    };
+                    *values = Some(Values { result: Dense::create(dst, rows, cols)?, filled: 0, from_content });
+                }
+                let values = values.as_mut().unwrap();
+                if values.from_content != from_content || (from_content && values.filled > 0) {
+                    return Err(OoclaError::InvalidFormat("TensorProto holds both tensor_content and float_val, \
+                                                          or tensor_content twice".to_string()));
+                }
+                let len = if wire_type == WIRE_FIXED32 { 4 } else { expect_varint(r)? };
+                if !len.is_multiple_of(4) {
+                    return Err(OoclaError::InvalidFormat(format!("{} bytes of TensorProto values is not a whole \
+                                                                  number of floats", len)));
+                }
+                if from_content {
+                    let expected = values.result.num_rows() * values.result.num_cols() * 4;
+                    if len != expected {
+                        return Err(OoclaError::InvalidFormat(format!("TensorProto tensor_content of {} bytes, \
+                                                                      expected {}", len, expected)));
+                    }
+                    let bytes = as_bytes_mut(values.result.storage_mut());
+                    read_exact_or_truncated(r, bytes, "TensorProto tensor_content")?;
+                    if cfg!(target_endian = "big") {
+                        for element in bytes.chunks_mut(4) {
+                            element.reverse();
+                        }
+                    }
+                    values.filled = len / 4;
+                } else {
+                    let mut word = [0u8; 4];
+                    for _ in 0..len / 4 {
+                        read_exact_or_truncated(r, &mut word, "TensorProto float_val")?;
+                        values.push(f32::from_le_bytes(word))?;
+                    }
+                }
+            }
+            (_, wire_type) => skip_field(r, wire_type)?,
+        }
+    }
+    if values.is_none() && dtype == DT_FLOAT {
+        if let Some(&[rows, cols]) = dims.as_ref().map(|dims| &dims[..]) {
+            // A tensor without values holds zeros, which a new matrix already does.
+            *values = Some(Values { result: Dense::create(dst, rows, cols)?, filled: 0, from_content: false });
+            return Ok(());
+        }
+    }
+    let values = values.as_mut().ok_or_else(|| OoclaError::UnsupportedDataset {
+        name: "TensorProto".to_string(),
+        reason: format!("dtype {} and shape {:?} don't describe a 2-D DT_FLOAT tensor", dtype, dims),
+    })?;
+    // As in TensorFlow, a float_val shorter than the tensor is padded with its last value.
+    let len = values.result.num_rows() * values.result.num_cols();
+    if values.filled > 0 && values.filled < len {
+        let cols = values.result.num_cols();
+        let last = values.result.get((values.filled - 1) / cols, (values.filled - 1) % cols);
+        while values.filled < len {
+            values.push(last)?;
+        }
+    }
+    Ok(())
+}
+
+// Imports a serialized TensorProto of dtype DT_FLOAT and a 2-D shape, read until the end of
+// `r`, into a new matrix at `dst`. The values may be in tensor_content or float_val, packed or
+// not. tensor_content is streamed into the matrix, which requires the shape to come first as
+// TensorFlow writes it.
+pub fn import<R: Read>(r: R, dst: &Path) -> Result<Dense<f32>, OoclaError> {
+    let mut reader = BufReader::with_capacity(1 << 20, r);
+    let mut values = None;
+    match read_tensor(&mut reader, dst, &mut values) {
+        Ok(()) => Ok(values.unwrap().result),
+        Err(e) => {
+            if values.take().is_some() {
+                let _ = fs::remove_file(dst);
+            }
+            Err(e)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    const VALUES: [[f32; 3]; 2] = [[1.0, -2.5, 3.25], [0.0, 1e-3, 6e7]];
+
+    fn fixture(name: &str) -> Vec<u8> {
+        fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/tensorproto").join(name)).unwrap()
+    }
+
+    fn rows(a: &Dense<f32>) -> Vec<Vec<f32>> {
+        a.row_iter().map(|r| r.to_vec()).collect()
+    }
+
+    fn imported(bytes: &[u8]) -> Result<Vec<Vec<f32>>, OoclaError> {
+        let dst = TempMatrixPath::new();
+        let result = import(bytes, dst.path());
+        if result.is_err() {
+            assert!(!dst.path().exists());
+        }
+        result.map(|a| rows(&a))
+    }
+
+    fn expected() -> Vec<Vec<f32>> {
+        VALUES.iter().map(|r| r.to_vec()).collect()
+    }
+
+    // The dtype and shape fields of a 2x3 DT_FLOAT tensor, as they start each fixture.
+    fn head() -> Vec<u8> {
+        fixture("content_2x3.pb")[..12].to_vec()
+    }
+
+    fn expect_invalid_format(bytes: &[u8]) {
+        match imported(bytes) {
+            Err(OoclaError::InvalidFormat(_)) => {}
+            other => panic!("expected an invalid format, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tensor_content_round_trips() {
+        let bytes = fixture("content_2x3.pb");
+        assert_eq!(imported(&bytes).unwrap(), expected());
+        // Written just as TensorFlow writes it, from either storage order.
+        let straight = Dense::anonymous_from_fn(2, 3, |i, j| VALUES[i as usize][j as usize]);
+        let mut transposed = Dense::anonymous_from_fn(3, 2, |i, j| VALUES[j as usize][i as usize]);
+        transposed.transpose();
+        for a in [straight, transposed].iter() {
+            let mut written = Vec::new();
+            export(a, &mut written).unwrap();
+            assert_eq!(written, bytes);
+        }
+    }
+
+    #[test]
+    fn packed_and_unpacked_float_val_are_read() {
+        assert_eq!(imported(&fixture("float_val_2x3.pb")).unwrap(), expected());
+        let mut unpacked = head();
+        for value in VALUES.iter().flat_map(|r| r.iter()) {
+            put_key(&mut unpacked, TENSOR_FLOAT_VAL, WIRE_FIXED32);
+            unpacked.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(imported(&unpacked).unwrap(), expected());
+    }
+
+    #[test]
+    fn short_float_val_is_padded_with_its_last_value() {
+        assert_eq!(imported(&fixture("scalar_2x3.pb")).unwrap(), vec![vec![1.5; 3]; 2]);
+        let mut bytes = head();
+        for &value in [7.0f32, -1.0].iter() {
+            put_key(&mut bytes, TENSOR_FLOAT_VAL, WIRE_FIXED32);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(imported(&bytes).unwrap(), vec![vec![7.0, -1.0, -1.0], vec![-1.0; 3]]);
+        // And with no values at all the tensor is zero.
+        assert_eq!(imported(&head()).unwrap(), vec![vec![0.0; 3]; 2]);
+    }
+
+    #[test]
+    fn truncated_and_inconsistent_messages_are_rejected() {
+        let content = fixture("content_2x3.pb");
+        let mut both = content.clone();
+        both.extend_from_slice(&fixture("scalar_2x3.pb")[12..]);
+        let mut too_many = fixture("float_val_2x3.pb");
+        too_many.extend_from_slice(&fixture("scalar_2x3.pb")[12..]);
+        let cases = [
+            // A varint cut off after a byte with its continuation bit set.
+            vec![0x08, 0x81],
+            head()[..5].to_vec(),
+            content[..content.len() - 1].to_vec(),
+            both,
+            too_many,
+            // Values before the shape, which can't be streamed.
+            [&content[..2], &content[12..]].concat(),
+        ];
+        for bytes in cases.iter() {
+            expect_invalid_format(bytes);
+        }
+        let mut doubles = content.clone();
+        doubles[1] = 2;
+        let mut three_d = vec![0x08, 0x01];
+        put_key(&mut three_d, TENSOR_SHAPE, WIRE_LEN);
+        let mut shape = encode_shape(2, 3);
+        shape.extend_from_slice(&shape.clone()[..4]);
+        put_varint(&mut three_d, shape.len() as u64);
+        three_d.extend_from_slice(&shape);
+        three_d.extend_from_slice(&content[12..]);
+        for bytes in [doubles, three_d].iter() {
+            match imported(bytes) {
+                Err(OoclaError::UnsupportedDataset { .. }) => {}
+                other => panic!("expected an unsupported dataset, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn matrices_over_the_message_limit_are_rejected_before_writing() {
+        // The first holds 4 bytes under 2 GiB of elements, but not the fields before them.
+        for &(rows, cols) in [(1, (1 << 29) - 1), (1 << 15, 1 << 14)].iter() {
+            let path = TempMatrixPath::new();
+            let a = Dense::<f32>::create(path.path(), rows, cols).unwrap();
+            let mut written = Vec::new();
+            match export(&a, &mut written) {
+                Err(OoclaError::SizeOverflow(_)) => {}
+                other => panic!("expected a size overflow for {}x{}, got {:?}", rows, cols, other),
+            }
+            assert!(written.is_empty());
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use nix::libc;
+use std::cell::Cell;
+use std::mem;
+use std::time::{Duration, Instant};
+
+// What the crate did while running a closure given to measure. Bytes count the elements that
+// block loops moved through whole lines, rows and tiles of matrices, including rows written to
+// new matrix files, the elements convert_endianness copies between files, the elements
+// transpose_inplace swaps and the compressed bytes of tiles read from compressed matrices.
+// Single-element accesses such as get and set aren't counted, nor is text written by exports.
+// Faults are those taken by the calling thread, so work handed to other threads, as by the par_
+// operations, isn't covered.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IoStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // Faults that had to wait for the disk, and faults satisfied from memory such as the page
+    // cache.
+    pub major_faults: u64,
+    pub minor_faults: u64,
+    pub msyncs: u64,
+    pub wall_time: Duration,
+}
+
+thread_local! {
+    // How many calls to measure the current thread is inside. Nothing is counted outside them,
+    // so unmeasured operations pay only this check.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static READ: Cell<u64> = const { Cell::new(0) };
+    static WRITTEN: Cell<u64> = const { Cell::new(0) };
+    static MSYNCS: Cell<u64> = const { Cell::new(0) };
+}
+
+fn add(counter: &'static ::std::thread::LocalKey<Cell<u64>>, amount: u64) {
+    if DEPTH.with(Cell::get) > 0 {
+        counter.with(|count| count.set(count.get() + amount));
+    }
+}
+
+pub(crate) fn count_read(bytes: usize) {
+    add(&READ, bytes as u64);
+}
+
+pub(crate) fn count_written(bytes: usize) {
+    add(&WRITTEN, bytes as u64);
+}
+
+pub(crate) fn count_msync() {
+    add(&MSYNCS, 1);
+}
+
+// The calling thread's major and minor fault counts.
+fn faults() -> (u64, u64) {
+    let mut usage: libc::rusage = unsafe {
+        mem::zeroed()
+    };
+    let ret = unsafe {
+        libc::getrusage(libc::RUSAGE_THREAD, &mut usage)
+    };
+    if ret != 0 {
+        return (0, 0);
+    }
+    (usage.ru_majflt as u64, usage.ru_minflt as u64)
+}
+
+struct Counters {
+    read: u64,
+    written: u64,
+    msyncs: u64,
+    major_faults: u64,
+    minor_faults: u64,
+    began: Instant,
+}
+
+impl Counters {
+    fn sample() -> Counters {
+        let (major_faults, minor_faults) = faults();
+        Counters {
+            read: READ.with(Cell::get),
+            written: WRITTEN.with(Cell::get),
+            msyncs: MSYNCS.with(Cell::get),
+            major_faults,
+            minor_faults,
+            began: Instant::now(),
+        }
+    }
+
+    fn since(&self, before: &Counters) -> IoStats {
+        IoStats {
+            bytes_read: self.read - before.read,
+            bytes_written: self.written - before.written,
+            major_faults: self.major_faults.saturating_sub(before.major_faults),
+            minor_faults: self.minor_faults.saturating_sub(before.minor_faults),
+            msyncs: self.msyncs - before.msyncs,
+            wall_time: self.began - before.began,
+        }
+    }
+}
+
+// Runs `f`, returning its result with what it did. Calls may be nested, each measuring
+// everything within it.
+pub fn measure<R, F: FnOnce() -> R>(f: F) -> (R, IoStats) {
+    struct Depth;
+    impl Drop for Depth {
+        fn drop(&mut self) {
+            DEPTH.with(|depth| depth.set(depth.get() - 1));
+        }
+    }
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let _depth = Depth;
+    let before = Counters::sample();
+    let result = f();
+    (result, Counters::sample().since(&before))
+}
@@ -1,3 +1,58 @@
+#[cfg(feature = "arrow")]
+extern crate arrow;
+#[cfg(feature = "hdf5")]
+extern crate hdf5;
+#[cfg(feature = "io-uring")]
+extern crate io_uring;
+#[cfg(feature = "nalgebra")]
+extern crate nalgebra;
+#[cfg(feature = "ndarray")]
+extern crate ndarray;
 extern crate nix;
+#[cfg(feature = "png")]
+extern crate png;
 extern crate rand;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "tokio")]
+pub mod async_tiles;
+pub mod budget;
+pub mod chunked;
+#[cfg(feature = "zstd")]
+pub mod compressed;
 pub mod dense_matrix;
+pub mod dense_vector;
+pub mod describe;
+mod direct;
+pub mod endian;
+pub mod error;
+pub mod ffi;
+pub mod interop;
+pub mod io;
+pub mod io_stats;
+pub mod net;
+#[cfg(feature = "numa")]
+pub mod numa;
+pub mod ops;
+pub mod prefetch;
+pub mod progress;
+pub mod protect;
+pub mod row_writer;
+pub mod shared;
+pub mod sparse;
+pub mod tile;
+pub mod tuning;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+
+pub use describe::describe;
+pub use endian::{convert_endianness, convert_endianness_inplace, convert_endianness_progress, convert_endianness_with};
@@ -0,0 +1,294 @@
+use dense_matrix::{Dense, FloatType, StorageType, as_bytes, as_bytes_mut};
+use error::OoclaError;
+use io::npy::read_exact_or_truncated;
+use ops::hash::{Xxh64, xxh64};
+use std::cmp;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// A stream is a header, then frames of at most FRAME_BYTES of the elements in row-major order,
+// each a little-endian u32 length, the bytes and their XXH64, then an empty frame and a trailer
+// of the payload length and its XXH64. The header ends with the XXH64 of its other fields.
+const STREAM_MAGIC: &[u8; 8] = b"OOCBLOCK";
+const STREAM_VERSION: u32 = 1;
+const HEADER_LEN: usize = 8 + 4 + 4 + 8 + 8;
+const FRAME_BYTES: usize = 1 << 20;
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(word)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+struct FrameWriter<W: Write> {
+    out: W,
+    frame: Vec<u8>,
+    payload: Xxh64,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn write(&mut self, mut bytes: &[u8]) -> Result<(), OoclaError> {
+        while !bytes.is_empty() {
+            let take = cmp::min(FRAME_BYTES - self.frame.len(), bytes.len());
+            self.frame.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if self.frame.len() == FRAME_BYTES {
+                self.flush_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_frame(&mut self) -> Result<(), OoclaError> {
+        self.out.write_all(&(self.frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(&self.frame)?;
+        self.out.write_all(&xxh64(&self.frame, 0).to_le_bytes())?;
+        self.payload.update(&self.frame);
+        self.frame.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), OoclaError> {
+        if !self.frame.is_empty() {
+            self.flush_frame()?;
+        }
+        self.out.write_all(&0u32.to_le_bytes())?;
+        self.out.write_all(&self.payload.len().to_le_bytes())?;
+        self.out.write_all(&self.payload.digest().to_le_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// Sends A over `w` in checksummed frames, a row at a time, for receive to rebuild on another
+// machine.
+pub fn send<T: StorageType, W: Write>(a: &Dense<T>, w: W) -> Result<(), OoclaError> {
+    let mut header = Vec::with_capacity(HEADER_LEN + 8);
+    header.extend_from_slice(STREAM_MAGIC);
+    header.extend_from_slice(&STREAM_VERSION.to_le_bytes());
+    header.extend_from_slice(&(T::get_float_type() as u32).to_le_bytes());
+    header.extend_from_slice(&a.num_rows().to_le_bytes());
+    header.extend_from_slice(&a.num_cols().to_le_bytes());
+    let checksum = xxh64(&header, 0);
+    header.extend_from_slice(&checksum.to_le_bytes());
+    let mut out = BufWriter::with_capacity(1 << 20, w);
+    out.write_all(&header)?;
+    let mut frames = FrameWriter { out, frame: Vec::with_capacity(FRAME_BYTES), payload: Xxh64::new(0) };
+    let mut scratch = vec![T::default(); a.num_cols() as usize];
+    for row in 0..a.num_rows() {
+        frames.write(as_bytes(a.row_view(row, &mut scratch)))?;
+    }
+    frames.finish()
+}
+
+fn invalid(msg: String) -> OoclaError {
+    OoclaError::InvalidFormat(format!("matrix stream: {}", msg))
+}
+
+// Reads the frames that follow the header into `dst`, checking every checksum and that the
+// payload exactly fills it.
+fn read_frames<R: Read>(r: &mut R, dst: &mut [u8]) -> Result<(), OoclaError> {
+    let mut filled = 0;
+    let mut payload = Xxh64::new(0);
+    let mut word = [0u8; 8];
+    for frame in 0.. {
+        read_exact_or_truncated(r, &mut word[..4], "matrix stream")?;
+        let len = read_u32(&word) as usize;
+        if len == 0 {
+            break;
+        }
+        if len > FRAME_BYTES || len > dst.len() - filled {
+            return Err(invalid(format!("frame {} of {} bytes overruns the matrix", frame, len)));
+        }
+        let bytes = &mut dst[filled..filled + len];
+        read_exact_or_truncated(r, bytes, "matrix stream")?;
+        read_exact_or_truncated(r, &mut word, "matrix stream")?;
+        if xxh64(bytes, 0) != u64::from_le_bytes(word) {
+            return Err(invalid(format!("frame {} fails its checksum", frame)));
+        }
+        payload.update(bytes);
+        filled += len;
+    }
+    if filled != dst.len() {
+        return Err(invalid(format!("{} bytes of elements received, expected {}", filled, dst.len())));
+    }
+    let mut trailer = [0u8; 16];
+    read_exact_or_truncated(r, &mut trailer, "matrix stream")?;
+    if read_u64(&trailer) != payload.len() || read_u64(&trailer[8..]) != payload.digest() {
+        return Err(invalid("payload fails its checksum".to_string()));
+    }
+    Ok(())
+}
+
+fn partial_path(dst: &Path) -> PathBuf {
+    let mut name = OsString::from(dst.as_os_str());
+    name.push(format!(".partial-{}", process::id()));
+    PathBuf::from(name)
+}
+
+// Receives a matrix sent by send into a new file at `dst`. Frames are written straight into the
+// mapping of a temporary file beside `dst`, which replaces `dst` only once every checksum has
+// passed; on any failure, including a short read, it is removed and `dst` is left untouched.
+pub fn receive<T: StorageType, R: Read>(mut r: R, dst: &Path) -> Result<Dense<T>, OoclaError> {
+    let mut header = [0u8; HEADER_LEN + 8];
+    read_exact_or_truncated(&mut r, &mut header, "matrix stream header")?;
+    if &header[..8] != STREAM_MAGIC {
+        return Err(invalid("bad magic number".to_string()));
+    }
+    if xxh64(&header[..HEADER_LEN], 0) != read_u64(&header[HEADER_LEN..]) {
+        return Err(invalid("header fails its checksum".to_string()));
+    }
+    let version = read_u32(&header[8..]);
+    if version != STREAM_VERSION {
+        return Err(invalid(format!("unsupported version {}", version)));
+    }
+    let code = read_u32(&header[12..]);
+    let found = FloatType::from_code(code).ok_or_else(|| invalid(format!("unknown element type {}", code)))?;
+    if found != T::get_float_type() {
+        return Err(OoclaError::TypeMismatch { expected: T::get_float_type(), found });
+    }
+    let (rows, cols) = (read_u64(&header[16..]), read_u64(&header[24..]));
+    let partial = partial_path(dst);
+    let mut result = Dense::create(&partial, rows, cols)?;
+    let received = read_frames(&mut r, as_bytes_mut(result.storage_mut()))
+        .and_then(|_| Ok(fs::rename(&partial, dst)?));
+    if let Err(e) = received {
+        drop(result);
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn assert_same<T: StorageType + PartialEq>(a: &Dense<T>, b: &Dense<T>) {
+        assert_eq!((a.num_rows(), a.num_cols()), (b.num_rows(), b.num_cols()));
+        for i in 0..a.num_rows() {
+            for j in 0..a.num_cols() {
+                assert!(a.get(i, j) == b.get(i, j), "({}, {}) differs", i, j);
+            }
+        }
+    }
+
+    fn sent<T: StorageType>(a: &Dense<T>) -> Vec<u8> {
+        let mut stream = Vec::new();
+        send(a, &mut stream).unwrap();
+        stream
+    }
+
+    // Spans several frames, the last of them partial.
+    fn multi_frame() -> Dense<f64> {
+        let mut a = Dense::create_anonymous(300, 500).unwrap();
+        a.randomise_seeded(4);
+        a
+    }
+
+    #[test]
+    fn round_trip_over_a_duplex_pipe() {
+        let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+        let sender = thread::spawn(move || {
+            let a = multi_frame();
+            send(&a, &mut theirs).unwrap();
+        });
+        let dst = TempMatrixPath::new();
+        let b = receive::<f64, _>(&mut ours, dst.path()).unwrap();
+        sender.join().unwrap();
+        assert_same(&multi_frame(), &b);
+        assert_same(&b, &Dense::open(dst.path()).unwrap());
+    }
+
+    #[test]
+    fn round_trip_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            let a = multi_frame();
+            send(&a, TcpStream::connect(address).unwrap()).unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let dst = TempMatrixPath::new();
+        let b = receive::<f64, _>(stream, dst.path()).unwrap();
+        sender.join().unwrap();
+        assert_same(&multi_frame(), &b);
+    }
+
+    #[test]
+    fn empty_and_transposed_matrices_round_trip() {
+        let mut a = Dense::<f32>::create_anonymous(5, 7).unwrap();
+        a.randomise_seeded(9);
+        a.transpose();
+        let dst = TempMatrixPath::new();
+        assert_same(&a, &receive::<f32, _>(&sent(&a)[..], dst.path()).unwrap());
+        let empty = Dense::<f32>::create_anonymous(0, 3).unwrap();
+        let dst = TempMatrixPath::new();
+        assert_same(&empty, &receive::<f32, _>(&sent(&empty)[..], dst.path()).unwrap());
+    }
+
+    // Expects receiving `stream` to fail, leaving neither the destination nor a partial file.
+    fn assert_rejected(stream: &[u8]) -> OoclaError {
+        let dst = TempMatrixPath::new();
+        let e = match receive::<f64, _>(stream, dst.path()) {
+            Err(e) => e,
+            Ok(_) => panic!("a corrupt stream was received"),
+        };
+        assert!(!dst.path().exists());
+        assert!(!partial_path(dst.path()).exists());
+        e
+    }
+
+    #[test]
+    fn bit_flips_are_detected() {
+        let stream = sent(&multi_frame());
+        // One bit of an element in each frame, of a frame's checksum, of the header and trailer.
+        let first = HEADER_LEN + 8 + 4;
+        let second = first + FRAME_BYTES + 8 + 4;
+        for &(byte, bit) in &[(first + 12345, 3), (second + 7, 0), (first + FRAME_BYTES + 2, 6), (30, 1),
+                              (stream.len() - 3, 5)] {
+            let mut corrupt = stream.clone();
+            corrupt[byte] ^= 1 << bit;
+            match assert_rejected(&corrupt) {
+                OoclaError::InvalidFormat(msg) => assert!(msg.contains("checksum"), "{}", msg),
+                other => panic!("expected a checksum failure, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn short_reads_and_mismatches_are_rejected() {
+        let stream = sent(&multi_frame());
+        for &len in &[0, HEADER_LEN, HEADER_LEN + 8 + 100, stream.len() - 1] {
+            assert_rejected(&stream[..len]);
+        }
+        let mut b = Dense::<f32>::create_anonymous(2, 2).unwrap();
+        b.randomise_seeded(1);
+        match assert_rejected(&sent(&b)) {
+            OoclaError::TypeMismatch { .. } => {}
+            other => panic!("expected a type mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_failed_receive_leaves_an_existing_destination_alone() {
+        let dst = TempMatrixPath::new();
+        let mut old = Dense::<f64>::create(dst.path(), 2, 3).unwrap();
+        old.randomise_seeded(2);
+        old.flush().unwrap();
+        let stream = sent(&multi_frame());
+        assert!(receive::<f64, _>(&stream[..stream.len() / 2], dst.path()).is_err());
+        assert_same(&old, &Dense::open(dst.path()).unwrap());
+    }
+}
@@ -0,0 +1,392 @@
+use dense_matrix::page_size;
+use error::OoclaError;
+use nix::libc::{self, c_int, c_long, c_uint, c_ulong, c_void};
+use std::{cmp, fs, io, mem, ptr};
+#[cfg(feature = "rayon")]
+use std::cell::Cell;
+
+// Flags of mbind and get_mempolicy that libc doesn't define.
+const MPOL_MF_MOVE: c_uint = 1 << 1;
+#[cfg(feature = "rayon")]
+const MPOL_F_ADDR: c_ulong = 1 << 1;
+
+// The bits of a node mask passed to the kernel, enough for the most nodes Linux supports.
+const NODE_MASK_BITS: usize = 1024;
+
+// Pages whose node a single move_pages call reports, bounding the vectors it fills.
+const MOVE_PAGES_CHUNK: usize = 1 << 16;
+
+// The granularity at which a parallel pass finds which node holds its storage.
+#[cfg(feature = "rayon")]
+const PIN_BLOCK_BYTES: usize = 2 << 20;
+
+// The nodes in a list from sysfs such as "0-3,8,10-11", or none if it can't be read.
+fn read_node_list(path: &str) -> Vec<usize> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => return Vec::new(),
+    };
+    let mut ids = Vec::new();
+    for part in text.trim().split(',').filter(|part| !part.is_empty()) {
+        let mut bounds = part.splitn(2, '-').map(|bound| bound.parse::<usize>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(id)), None) => ids.push(id),
+            (Some(Ok(first)), Some(Ok(last))) => ids.extend(first..=last),
+            _ => return Vec::new(),
+        }
+    }
+    ids
+}
+
+// The nodes that are online. A kernel built without NUMA support has no node directory in
+// sysfs, and counts as having the single node 0.
+pub fn online_nodes() -> Vec<usize> {
+    let nodes = read_node_list("/sys/devices/system/node/online");
+    if nodes.is_empty() { vec![0] } else { nodes }
+}
+
+// One more than the highest online node, which is the length of Dense::numa_residency's result.
+pub fn num_nodes() -> usize {
+    online_nodes().iter().max().map_or(1, |&node| node + 1)
+}
+
+// Whether there is more than one node to place memory or threads on. Everything NUMA-aware does
+// nothing when there isn't.
+pub fn is_numa() -> bool {
+    online_nodes().len() > 1
+}
+
+// The nodes with memory of their own, which are those pages can be interleaved across.
+pub(crate) fn nodes_with_memory() -> Vec<usize> {
+    let nodes = read_node_list("/sys/devices/system/node/has_memory");
+    if nodes.is_empty() { online_nodes() } else { nodes }
+}
+
+// The CPUs of `node`, which for a node with only memory is none, for placing the threads of a
+// scan partitioned with Dense::bind_range_to_node.
+pub fn node_cpus(node: usize) -> Vec<usize> {
+    read_node_list(&format!("/sys/devices/system/node/node{}/cpulist", node))
+}
+
+pub(crate) fn check_node(node: usize) -> Result<(), OoclaError> {
+    if !online_nodes().contains(&node) {
+        return Err(OoclaError::InvalidArgument(format!("NUMA node {} is not online", node)));
+    }
+    Ok(())
+}
+
+fn node_mask(nodes: &[usize]) -> Vec<c_ulong> {
+    let word_bits = 8 * mem::size_of::<c_ulong>();
+    let mut mask = vec![0; NODE_MASK_BITS / word_bits];
+    for &node in nodes {
+        mask[node / word_bits] |= 1 << (node % word_bits);
+    }
+    mask
+}
+
+fn syscall_result(ret: c_long) -> io::Result<c_long> {
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(ret) }
+}
+
+// Sets the policy `mode` over `nodes` for the page-aligned bytes from `addr`, migrating pages
+// already in memory that the policy doesn't allow where the kernel can.
+pub(crate) fn mbind(addr: *const c_void, len: usize, mode: c_int, nodes: &[usize]) -> io::Result<()> {
+    let mask = node_mask(nodes);
+    syscall_result(unsafe {
+        libc::syscall(libc::SYS_mbind, addr, len, mode, mask.as_ptr(), NODE_MASK_BITS + 1, MPOL_MF_MOVE)
+    })?;
+    Ok(())
+}
+
+// The single node the policy at `addr` places pages on, if it places them on just one.
+#[cfg(feature = "rayon")]
+pub(crate) fn policy_node(addr: *const c_void) -> io::Result<Option<usize>> {
+    let mut mode: c_int = 0;
+    let mut mask = node_mask(&[]);
+    syscall_result(unsafe {
+        libc::syscall(libc::SYS_get_mempolicy, &mut mode as *mut c_int, mask.as_mut_ptr(), NODE_MASK_BITS + 1, addr,
+                      MPOL_F_ADDR)
+    })?;
+    if mode != libc::MPOL_BIND && mode != libc::MPOL_PREFERRED {
+        return Ok(None);
+    }
+    let word_bits = 8 * mem::size_of::<c_ulong>();
+    let mut nodes = (0..NODE_MASK_BITS).filter(|&node| mask[node / word_bits] & (1 << (node % word_bits)) != 0);
+    Ok(match (nodes.next(), nodes.next()) {
+        (Some(node), None) => Some(node),
+        _ => None,
+    })
+}
+
+// The node of each page at `pages`, or None for those not in memory through this mapping,
+// from move_pages asked only to report.
+pub(crate) fn page_nodes(pages: &[*const c_void]) -> io::Result<Vec<Option<usize>>> {
+    let mut nodes = Vec::with_capacity(pages.len());
+    let mut status: Vec<c_int> = vec![0; cmp::min(pages.len(), MOVE_PAGES_CHUNK)];
+    for chunk in pages.chunks(MOVE_PAGES_CHUNK) {
+        syscall_result(unsafe {
+            libc::syscall(libc::SYS_move_pages, 0, chunk.len(), chunk.as_ptr(), ptr::null::<c_int>(),
+                          status.as_mut_ptr(), 0)
+        })?;
+        nodes.extend(status[..chunk.len()].iter().map(|&node| if node < 0 { None } else { Some(node as usize) }));
+    }
+    Ok(nodes)
+}
+
+// The number of pages of the page-aligned bytes from `addr` in memory on each node.
+pub(crate) fn count_pages_by_node(addr: *const c_void, len: usize) -> io::Result<Vec<u64>> {
+    let page = page_size();
+    let mut counts = vec![0; num_nodes()];
+    for first in (0..len).step_by(MOVE_PAGES_CHUNK * page) {
+        let pages: Vec<*const c_void> = (first..cmp::min(first + MOVE_PAGES_CHUNK * page, len)).step_by(page)
+            .map(|offset| (addr as usize + offset) as *const c_void)
+            .collect();
+        for node in page_nodes(&pages)?.into_iter().flatten() {
+            if node >= counts.len() {
+                counts.resize(node + 1, 0);
+            }
+            counts[node] += 1;
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(feature = "rayon")]
+thread_local! {
+    // The node the current worker is pinned to, and its affinity before it was first pinned.
+    static PINNED: Cell<Option<(usize, libc::cpu_set_t)>> = const { Cell::new(None) };
+}
+
+// Pins the rayon workers of a parallel pass over a matrix to the node holding each line as they
+// reach it, so that they read memory local to them. Which node holds a block of the storage is
+// found when the pass starts, from where its first page is or, for a block not yet in memory,
+// from the node a range it lies in was bound to. Lines in blocks on no single node leave a worker
+// where it was. Dropping the pinner restores the affinity of every worker it pinned.
+#[cfg(feature = "rayon")]
+pub(crate) struct NodePinner {
+    base: usize,
+    block_bytes: usize,
+    blocks: Vec<Option<usize>>,
+    cpus: Vec<Option<libc::cpu_set_t>>,
+}
+
+#[cfg(feature = "rayon")]
+impl NodePinner {
+    // A pinner for a pass over `storage`, which does nothing unless `enabled` and there is more
+    // than one node.
+    pub(crate) fn new<T>(storage: &[T], enabled: bool) -> NodePinner {
+        let mut pinner = NodePinner { base: storage.as_ptr() as usize, block_bytes: PIN_BLOCK_BYTES, blocks: Vec::new(),
+                                      cpus: Vec::new() };
+        if !enabled || !is_numa() || storage.is_empty() {
+            return pinner;
+        }
+        let page = page_size();
+        let begin = pinner.base / page * page;
+        let end = pinner.base + mem::size_of_val(storage);
+        let starts: Vec<*const c_void> = (begin..end).step_by(pinner.block_bytes)
+            .map(|addr| addr as *const c_void).collect();
+        let located = page_nodes(&starts).unwrap_or_else(|_| vec![None; starts.len()]);
+        pinner.blocks = starts.iter().zip(located).map(|(&addr, node)| {
+            node.or_else(|| policy_node(addr).ok().and_then(|node| node))
+        }).collect();
+        pinner.base = begin;
+        pinner.cpus = (0..num_nodes()).map(|node| {
+            let cpus = node_cpus(node);
+            if cpus.is_empty() {
+                return None;
+            }
+            let mut set: libc::cpu_set_t = unsafe {
+                mem::zeroed()
+            };
+            for cpu in cpus {
+                unsafe {
+                    libc::CPU_SET(cpu, &mut set)
+                };
+            }
+            Some(set)
+        }).collect();
+        pinner
+    }
+
+    // Pins the calling worker to the node holding `line`, if it isn't pinned there already.
+    pub(crate) fn pin<T>(&self, line: &[T]) {
+        if self.blocks.is_empty() {
+            return;
+        }
+        let block = (line.as_ptr() as usize - self.base) / self.block_bytes;
+        let node = match self.blocks.get(block) {
+            Some(&Some(node)) => node,
+            _ => return,
+        };
+        let set = match self.cpus.get(node) {
+            Some(Some(set)) => set,
+            _ => return,
+        };
+        PINNED.with(|pinned| {
+            let original = match pinned.get() {
+                Some((current, _)) if current == node => return,
+                Some((_, original)) => original,
+                None => {
+                    let mut original: libc::cpu_set_t = unsafe {
+                        mem::zeroed()
+                    };
+                    let ret = unsafe {
+                        libc::sched_getaffinity(0, mem::size_of_val(&original), &mut original)
+                    };
+                    if ret != 0 {
+                        return;
+                    }
+                    original
+                }
+            };
+            let ret = unsafe {
+                libc::sched_setaffinity(0, mem::size_of_val(set), set)
+            };
+            if ret == 0 {
+                pinned.set(Some((node, original)));
+            }
+        });
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl Drop for NodePinner {
+    fn drop(&mut self) {
+        if self.blocks.is_empty() {
+            return;
+        }
+        ::rayon::broadcast(|_| {
+            PINNED.with(|pinned| {
+                if let Some((_, original)) = pinned.take() {
+                    unsafe {
+                        libc::sched_setaffinity(0, mem::size_of_val(&original), &original)
+                    };
+                }
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{Dense, StreamOptions, TempMatrixPath};
+    use error::OoclaError;
+
+    fn node_list(text: &str) -> Vec<usize> {
+        let path = TempMatrixPath::new();
+        fs::write(path.path(), text).unwrap();
+        read_node_list(path.path().to_str().unwrap())
+    }
+
+    fn matrix(path: &TempMatrixPath) -> Dense<f64> {
+        Dense::from_fn(path.path(), 256, 512, |i, j| (i * 512 + j) as f64).unwrap()
+    }
+
+    #[test]
+    fn node_lists_are_parsed() {
+        assert_eq!(node_list("0\n"), vec![0]);
+        assert_eq!(node_list("0-3,8,10-11\n"), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(node_list("\n"), Vec::<usize>::new());
+        assert_eq!(node_list("0-x"), Vec::<usize>::new());
+        assert_eq!(read_node_list("/nonexistent/node/list"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn node_masks_set_one_bit_per_node() {
+        let word_bits = 8 * mem::size_of::<c_ulong>();
+        let mask = node_mask(&[0, 3, word_bits + 1]);
+        assert_eq!(mask.len() * word_bits, NODE_MASK_BITS);
+        assert_eq!(mask[0], 0b1001);
+        assert_eq!(mask[1], 0b10);
+        assert!(mask[2..].iter().all(|&word| word == 0));
+    }
+
+    #[test]
+    fn topology_is_consistent() {
+        let online = online_nodes();
+        assert!(!online.is_empty());
+        assert!(online.iter().all(|&node| node < num_nodes()));
+        assert_eq!(is_numa(), online.len() > 1);
+        assert!(nodes_with_memory().iter().all(|node| online.contains(node)));
+        assert!(online.iter().all(|&node| check_node(node).is_ok()));
+        match check_node(NODE_MASK_BITS) {
+            Err(OoclaError::InvalidArgument(message)) => assert!(message.contains("not online"), "{}", message),
+            other => panic!("expected InvalidArgument, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn placement_leaves_the_contents_alone() {
+        let path = TempMatrixPath::new();
+        let a = matrix(&path);
+        a.interleave_numa().unwrap();
+        for &node in online_nodes().iter() {
+            a.bind_range_to_node(0..128, node).unwrap();
+            a.bind_range_to_node(100..100, node).unwrap();
+            a.bind_range_to_node(128..256, node).unwrap();
+        }
+        assert!((0..256).all(|i| (0..512).all(|j| a.get(i, j) == (i * 512 + j) as f64)));
+    }
+
+    #[test]
+    fn binding_to_a_node_that_is_not_online_is_rejected() {
+        let path = TempMatrixPath::new();
+        let a = matrix(&path);
+        match a.bind_range_to_node(0..1, NODE_MASK_BITS) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected InvalidArgument, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn binding_rows_out_of_bounds_panics() {
+        let path = TempMatrixPath::new();
+        matrix(&path).bind_range_to_node(200..257, 0).unwrap();
+    }
+
+    #[test]
+    fn residency_counts_every_touched_page() {
+        let path = TempMatrixPath::new();
+        let a = matrix(&path);
+        a.prefault(0..256);
+        let counts = a.numa_residency().unwrap();
+        assert_eq!(counts.len(), num_nodes());
+        let pages = a.residency().unwrap().total_pages;
+        assert_eq!(counts.iter().sum::<u64>(), pages);
+        if !is_numa() {
+            assert_eq!(counts, vec![pages]);
+        }
+    }
+
+    // The CPUs the calling thread may run on.
+    #[cfg(feature = "rayon")]
+    fn affinity() -> Vec<usize> {
+        let mut set: libc::cpu_set_t = unsafe {
+            mem::zeroed()
+        };
+        assert_eq!(unsafe {
+            libc::sched_getaffinity(0, mem::size_of_val(&set), &mut set)
+        }, 0);
+        (0..libc::CPU_SETSIZE as usize).filter(|&cpu| unsafe {
+            libc::CPU_ISSET(cpu, &set)
+        }).collect()
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn pinned_passes_give_the_same_results_and_restore_affinity() {
+        use ops::{MathFunc, par_apply};
+        let before = ::rayon::broadcast(|_| affinity());
+        let (plain_path, pinned_path) = (TempMatrixPath::new(), TempMatrixPath::new());
+        let (mut plain, mut pinned) = (matrix(&plain_path), matrix(&pinned_path));
+        pinned.set_stream_options(StreamOptions { pin_numa_workers: true, ..StreamOptions::default() });
+        for &node in online_nodes().iter() {
+            pinned.bind_range_to_node(0..256, node).unwrap();
+        }
+        assert_eq!(par_apply(&mut pinned, MathFunc::Sqrt), par_apply(&mut plain, MathFunc::Sqrt));
+        assert!((0..256).all(|i| (0..512).all(|j| pinned.get(i, j) == plain.get(i, j))));
+        assert_eq!(::rayon::broadcast(|_| affinity()), before);
+    }
+}
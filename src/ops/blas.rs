@@ -0,0 +1,775 @@
+use budget::MemoryBudget;
+use dense_matrix::{AccessPattern, Dense, DiscardBehind, SupportedType};
+use error::OoclaError;
+use ops::simd;
+use progress::{Meter, NoProgress, Progress, ProgressUnit};
+#[cfg(feature = "rayon")]
+use rayon::{self, prelude::*};
+use std::cmp;
+use std::mem;
+use tile::{DEFAULT_TILE_SIZE, Tile};
+use tuning::{self, TileConfig, TunableOp};
+
+// Largest accumulator we are prepared to keep in memory for a single pass over the input.
+const RESIDENT_ACCUMULATOR_BYTES: usize = 256 << 20;
+
+// Rows per streamed block when the caller doesn't choose, aiming for a few megabytes per block.
+pub(crate) fn default_block_rows(cols: u64) -> usize {
+    cmp::max(1, (4 << 20) / (cmp::max(cols as usize, 1) * mem::size_of::<f64>()))
+}
+
+// Gathers rows [start, start + count) of `a` into `block` as row-major f64 values, subtracting
+// `shift` from each row and adding the shifted values into `sums`.
+fn gather_rows<T: SupportedType>(a: &Dense<T>, start: u64, count: usize, shift: Option<&[f64]>,
+                                 row: &mut [T], block: &mut Vec<f64>, sums: &mut [f64]) {
+    block.clear();
+    for r in 0..count as u64 {
+        a.read_row(start + r, row);
+        for (col, value) in row.iter().enumerate() {
+            let value = match shift {
+                Some(shift) => value.to_f64() - shift[col],
+                None => value.to_f64(),
+            };
+            sums[col] += value;
+            block.push(value);
+        }
+    }
+}
+
+// Accumulates the contribution of a block of rows to rows [panel_start, panel_end) of the
+// upper triangle of A^T·A. `acc` holds those rows restricted to columns [panel_start, d).
+fn accumulate_gram_panel(block: &[f64], d: usize, panel_start: usize, panel_end: usize, acc: &mut [f64]) {
+    let width = d - panel_start;
+    for x in block.chunks(d) {
+        for i in panel_start..panel_end {
+            let xi = x[i];
+            if xi == 0.0 {
+                continue;
+            }
+            let acc_row = &mut acc[(i - panel_start) * width..(i - panel_start + 1) * width];
+            for j in i..d {
+                acc_row[j - panel_start] += xi * x[j];
+            }
+        }
+    }
+}
+
+// Computes the upper triangle of (A - shift)^T·(A - shift) one horizontal panel at a time. When
+// the whole triangle fits in memory this is a single panel and one pass over A. Each finished
+// panel is passed to `sink` as (panel_start, panel_end, acc, column_sums) where `acc` is laid
+// out as in `accumulate_gram_panel` and `column_sums` are the sums of the shifted columns. The
+// panels are narrowed to fit within `budget`, from which each panel's buffers are reserved.
+pub(crate) fn gram_panels<T, F>(a: &Dense<T>, block_rows: usize, shift: Option<&[f64]>, budget: &MemoryBudget,
+                                mut sink: F) -> Result<(), OoclaError>
+    where T: SupportedType, F: FnMut(usize, usize, &[f64], &[f64]) -> Result<(), OoclaError> {
+    if block_rows == 0 {
+        return Err(OoclaError::InvalidArgument("block_rows must be non-zero".to_string()));
+    }
+    let d = a.num_cols() as usize;
+    let n = a.num_rows();
+    let line_bytes = cmp::max(d, 1) * mem::size_of::<f64>();
+    let fixed_bytes = block_rows.saturating_mul(line_bytes).saturating_add(2 * line_bytes);
+    let accumulator_bytes = budget.cap(RESIDENT_ACCUMULATOR_BYTES.saturating_add(fixed_bytes))
+        .saturating_sub(fixed_bytes);
+    let rows_per_panel = cmp::max(1, accumulator_bytes / line_bytes);
+    let mut panel_start = 0;
+    while panel_start < d {
+        let panel_end = cmp::min(d, panel_start + rows_per_panel);
+        let width = d - panel_start;
+        let panel_bytes = (panel_end - panel_start) * width * mem::size_of::<f64>();
+        let _reserved = budget.alloc(fixed_bytes.saturating_add(panel_bytes))?;
+        let mut row = vec![T::from_f64(0.0); d];
+        let mut block = Vec::with_capacity(block_rows * d);
+        let mut sums = vec![0.0; d];
+        let mut acc = vec![0.0; (panel_end - panel_start) * width];
+        let mut start = 0;
+        while start < n {
+            let count = cmp::min(block_rows as u64, n - start) as usize;
+            gather_rows(a, start, count, shift, &mut row, &mut block, &mut sums);
+            accumulate_gram_panel(&block, d, panel_start, panel_end, &mut acc);
+            start += count as u64;
+        }
+        sink(panel_start, panel_end, &acc, &sums)?;
+        panel_start = panel_end;
+    }
+    Ok(())
+}
+
+// Bytes of buffers gram_panels holds at least for `block_rows` rows of `cols` columns: the block,
+// a row and column sums, and an accumulator of a single row.
+pub(crate) fn syrk_footprint(block_rows: usize, cols: usize) -> Option<usize> {
+    let line_bytes = cmp::max(cols, 1).checked_mul(mem::size_of::<f64>())?;
+    block_rows.checked_add(3)?.checked_mul(line_bytes)
+}
+
+pub fn syrk<T: SupportedType>(a: &Dense<T>, g: &mut Dense<T>, block_rows: usize) -> Result<(), OoclaError> {
+    syrk_with(a, g, block_rows, &MemoryBudget::unlimited())
+}
+
+// As syrk, computing G in as many panels as it takes for each panel's accumulator and block of
+// rows to fit within `budget`, which means a pass over A for each.
+pub fn syrk_with<T: SupportedType>(a: &Dense<T>, g: &mut Dense<T>, block_rows: usize, budget: &MemoryBudget)
+    -> Result<(), OoclaError> {
+    let d = a.num_cols();
+    if g.num_rows() != d || g.num_cols() != d {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (d, d),
+            found: (g.num_rows(), g.num_cols()),
+        });
+    }
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("syrk", rows = a.num_rows(), cols = d, block_rows).entered();
+    let d = d as usize;
+    gram_panels(a, block_rows, None, budget, |panel_start, panel_end, acc, _| {
+        let width = d - panel_start;
+        for i in panel_start..panel_end {
+            for j in i..d {
+                let value = T::from_f64(acc[(i - panel_start) * width + (j - panel_start)]);
+                g.set(i as u64, j as u64, value);
+                g.set(j as u64, i as u64, value);
+            }
+        }
+        Ok(())
+    })
+}
+
+// As syrk_with, streaming blocks of `config`'s tile_size rows, or when that is None, as many as
+// the tuning cache holds for a problem of this shape, falling back to the default.
+pub fn syrk_tuned<T: SupportedType>(a: &Dense<T>, g: &mut Dense<T>, config: Option<TileConfig>, budget: &MemoryBudget)
+    -> Result<(), OoclaError> {
+    let config = tuning::resolve::<T>(TunableOp::Syrk, &[a.num_rows(), a.num_cols()], config, budget);
+    syrk_with(a, g, config.tile_size, budget)
+}
+
+fn check_update_lengths<T>(a: &Dense<T>, x: &[T], y: &[T]) -> Result<(), OoclaError> {
+    if x.len() as u64 != a.num_rows() || y.len() as u64 != a.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), a.num_cols()),
+            found: (x.len() as u64, y.len() as u64),
+        });
+    }
+    Ok(())
+}
+
+pub fn ger<T: SupportedType>(alpha: T, x: &[T], y: &[T], a: &mut Dense<T>) -> Result<(), OoclaError> {
+    rank_k_update(alpha, &[x], &[y], a)
+}
+
+// Performs A += alpha * sum_k xs[k] * ys[k]^T in a single pass over A.
+pub fn rank_k_update<T: SupportedType>(alpha: T, xs: &[&[T]], ys: &[&[T]], a: &mut Dense<T>) -> Result<(), OoclaError> {
+    if xs.len() != ys.len() {
+        return Err(OoclaError::InvalidArgument(format!("{} column vectors but {} row vectors", xs.len(), ys.len())));
+    }
+    for (x, y) in xs.iter().zip(ys.iter()) {
+        check_update_lengths(a, x, y)?;
+    }
+    // A transposed matrix is stored by logical column, so x and y swap roles.
+    let (us, vs) = if a.is_transposed() { (ys, xs) } else { (xs, ys) };
+    let alpha = alpha.to_f64();
+    let mut scales = vec![0.0; us.len()];
+    for major in 0..a.major_len() {
+        for (scale, u) in scales.iter_mut().zip(us.iter()) {
+            *scale = alpha * u[major as usize].to_f64();
+        }
+        if scales.iter().all(|&s| s == 0.0) {
+            continue;
+        }
+        let line = a.major_slice_mut(major);
+        for (minor, element) in line.iter_mut().enumerate() {
+            let update: f64 = scales.iter().zip(vs.iter()).map(|(s, v)| s * v[minor].to_f64()).sum();
+            *element = T::from_f64(element.to_f64() + update);
+        }
+    }
+    Ok(())
+}
+
+// Computes y = alpha * A·x + beta * y in a single pass over A.
+pub fn gemv<T: SupportedType>(alpha: T, a: &Dense<T>, x: &[T], beta: T, y: &mut [T]) -> Result<(), OoclaError> {
+    if x.len() as u64 != a.num_cols() || y.len() as u64 != a.num_rows() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), a.num_cols()),
+            found: (y.len() as u64, x.len() as u64),
+        });
+    }
+    let (alpha, beta) = (alpha.to_f64(), beta.to_f64());
+    let minor = a.minor_len() as usize;
+    let deterministic = a.stream_options().deterministic;
+    // Both branches walk the storage of A once, in order.
+    let _advice = a.advise_scoped(AccessPattern::Sequential);
+    let mut pass = DiscardBehind::new(a);
+    if a.is_transposed() {
+        // Storage runs down the columns of A, so accumulate y as a combination of them.
+        let mut acc = vec![0.0; y.len()];
+        for (major, xj) in x.iter().enumerate() {
+            let scale = alpha * xj.to_f64();
+            if scale != 0.0 {
+                simd::axpy(scale, &a.major_slice(major as u64)[..minor], &mut acc);
+            }
+            pass.advance(a, major as u64 + 1)?;
+        }
+        for (dst, acc) in y.iter_mut().zip(acc) {
+            let base = if beta == 0.0 { 0.0 } else { beta * dst.to_f64() };
+            *dst = T::from_f64(base + acc);
+        }
+    } else {
+        for (major, dst) in y.iter_mut().enumerate() {
+            let dot = simd::dot(&a.major_slice(major as u64)[..minor], x, deterministic);
+            // beta == 0 must not propagate NaNs already present in y.
+            let base = if beta == 0.0 { 0.0 } else { beta * dst.to_f64() };
+            *dst = T::from_f64(base + alpha * dot);
+            pass.advance(a, major as u64 + 1)?;
+        }
+    }
+    Ok(())
+}
+
+// Accumulates alpha * A·B for a pair of in-memory tiles into `acc`, a row-major
+// a.rows x b.cols buffer.
+pub(crate) fn tile_gemm_acc<T: SupportedType>(alpha: f64, a: &Tile<T>, b: &Tile<T>, acc: &mut [f64]) {
+    debug_assert_eq!(a.cols, b.rows);
+    debug_assert_eq!(acc.len(), a.rows * b.cols);
+    for i in 0..a.rows {
+        let acc_row = &mut acc[i * b.cols..(i + 1) * b.cols];
+        for (k, aik) in a.row_slice(i).iter().enumerate() {
+            let scale = alpha * aik.to_f64();
+            if scale == 0.0 {
+                continue;
+            }
+            for (c, bkj) in acc_row.iter_mut().zip(b.row_slice(k).iter()) {
+                *c += scale * bkj.to_f64();
+            }
+        }
+    }
+}
+
+fn check_gemm_shapes<T>(a: &Dense<T>, b: &Dense<T>, c: &Dense<T>) -> Result<(), OoclaError> {
+    if a.num_cols() != b.num_rows() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_cols(), b.num_cols()),
+            found: (b.num_rows(), b.num_cols()),
+        });
+    }
+    if c.num_rows() != a.num_rows() || c.num_cols() != b.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), b.num_cols()),
+            found: (c.num_rows(), c.num_cols()),
+        });
+    }
+    Ok(())
+}
+
+// Computes C = alpha * A·B + beta * C one tile_size x tile_size block of C at a time, so at
+// most three tiles plus an f64 accumulator are resident. Transposed operands are handled by
+// their storage flag rather than by separate arguments.
+pub fn gemm<T: SupportedType>(alpha: T, a: &Dense<T>, b: &Dense<T>, beta: T, c: &mut Dense<T>, tile_size: usize)
+    -> Result<(), OoclaError> {
+    gemm_with(alpha, a, b, beta, c, tile_size, &MemoryBudget::unlimited())
+}
+
+// Bytes of tile buffers gemm holds while computing one tile of C: tiles of A, B and C and an
+// f64 accumulator.
+pub(crate) fn gemm_footprint(tile: usize, element: usize) -> Result<usize, OoclaError> {
+    tile.checked_mul(tile)
+        .and_then(|per_tile| per_tile.checked_mul(3 * element + mem::size_of::<f64>()))
+        .ok_or_else(|| OoclaError::SizeOverflow(format!("{}x{} tiles are too large", tile, tile)))
+}
+
+// As gemm, reserving the tile buffers for each tile of C from `budget` while computing it.
+pub fn gemm_with<T: SupportedType>(alpha: T, a: &Dense<T>, b: &Dense<T>, beta: T, c: &mut Dense<T>, tile_size: usize,
+                                   budget: &MemoryBudget) -> Result<(), OoclaError> {
+    gemm_progress(alpha, a, b, beta, c, tile_size, budget, &NoProgress)
+}
+
+// As gemm_with, reporting the bytes of C computed to `progress`. Every tile of C takes the same
+// pass over a block row of A and block column of B, so the bytes track the work done. On
+// cancellation the tiles of C finished so far, taken a row of tiles at a time from the top,
+// hold the result and every other element of C is unchanged.
+#[allow(clippy::too_many_arguments)]
+pub fn gemm_progress<T: SupportedType>(alpha: T, a: &Dense<T>, b: &Dense<T>, beta: T, c: &mut Dense<T>,
+                                       tile_size: usize, budget: &MemoryBudget, progress: &dyn Progress)
+    -> Result<(), OoclaError> {
+    if tile_size == 0 {
+        return Err(OoclaError::InvalidArgument("tile_size must be non-zero".to_string()));
+    }
+    check_gemm_shapes(a, b, c)?;
+    let footprint = gemm_footprint(tile_size, mem::size_of::<T>())?;
+    let (m, n, k) = (a.num_rows(), b.num_cols(), a.num_cols());
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("gemm", m, n, k, tile_size).entered();
+    let (alpha, beta) = (alpha.to_f64(), beta.to_f64());
+    let step = tile_size as u64;
+    let element = mem::size_of::<T>() as u64;
+    let mut meter = Meter::new(progress, Some(m * n * element), ProgressUnit::Bytes);
+    for i0 in (0..m).step_by(tile_size) {
+        let rows = cmp::min(step, m - i0) as usize;
+        for j0 in (0..n).step_by(tile_size) {
+            let cols = cmp::min(step, n - j0) as usize;
+            // Every buffer is freed before the reservation that covers it is released.
+            let _reserved = budget.alloc(footprint)?;
+            let mut out = c.read_tile(i0, j0, rows, cols);
+            // beta == 0 must not propagate NaNs already present in C.
+            let mut acc = if beta == 0.0 {
+                vec![0.0; rows * cols]
+            } else {
+                out.data.iter().map(|x| beta * x.to_f64()).collect()
+            };
+            for k0 in (0..k).step_by(tile_size) {
+                let depth = cmp::min(step, k - k0) as usize;
+                let a_tile = a.read_tile(i0, k0, rows, depth);
+                let b_tile = b.read_tile(k0, j0, depth, cols);
+                tile_gemm_acc(alpha, &a_tile, &b_tile, &mut acc);
+            }
+            for (dst, &value) in out.data.iter_mut().zip(acc.iter()) {
+                *dst = T::from_f64(value);
+            }
+            c.write_tile(&out);
+            meter.advance((rows * cols) as u64 * element)?;
+        }
+    }
+    meter.finish();
+    Ok(())
+}
+
+// As gemm_with, with tiles of `config`'s tile_size, or when that is None, of the size the tuning
+// cache holds for a problem of this shape, falling back to the largest of the usual sizes up to
+// DEFAULT_TILE_SIZE that fits within `budget`.
+pub fn gemm_tuned<T: SupportedType>(alpha: T, a: &Dense<T>, b: &Dense<T>, beta: T, c: &mut Dense<T>,
+                                    config: Option<TileConfig>, budget: &MemoryBudget) -> Result<(), OoclaError> {
+    let shape = [a.num_rows(), b.num_cols(), a.num_cols()];
+    let config = tuning::resolve::<T>(TunableOp::Gemm, &shape, config, budget);
+    gemm_with(alpha, a, b, beta, c, config.tile_size, budget)
+}
+
+// Overwrites C with beta·C, setting it to zero when beta is zero so that NaNs already present
+// in C don't propagate.
+#[cfg(feature = "rayon")]
+fn scale_in_place<T: SupportedType>(c: &mut Dense<T>, beta: T) -> Result<(), OoclaError> {
+    if beta.to_f64() == 0.0 {
+        return c.fill(T::from_f64(0.0));
+    }
+    let minor = c.minor_len() as usize;
+    for major in 0..c.major_len() {
+        simd::scale(&mut c.major_slice_mut(major)[..minor], beta);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "rayon")]
+const PAR_GEMM_TILE_CANDIDATES: [usize; 7] = [1024, 512, 256, 128, 64, 32, 16];
+
+// Lets the loader read tiles of A and B from one thread while workers compute on another.
+// Tiles are only ever read through it, and nothing mutates either matrix during par_gemm.
+#[cfg(feature = "rayon")]
+struct SharedRead<'a, T: 'a>(&'a Dense<T>);
+
+#[cfg(feature = "rayon")]
+unsafe impl<'a, T: Sync> Sync for SharedRead<'a, T> {}
+
+// Bytes of tile buffers par_gemm holds at once with `group` C tiles of edge `tile` in flight:
+// an f64 accumulator for each, double-buffered A tiles for each, a double-buffered B tile and
+// one C tile being read or written.
+#[cfg(feature = "rayon")]
+fn par_gemm_footprint(tile: usize, group: usize, element: usize) -> Option<usize> {
+    let per_tile = tile.checked_mul(tile)?;
+    let accumulators = group.checked_mul(mem::size_of::<f64>())?;
+    let staged = group.checked_add(1)?.checked_mul(2)?.checked_add(1)?.checked_mul(element)?;
+    per_tile.checked_mul(accumulators.checked_add(staged)?)
+}
+
+// Picks the tile edge and number of concurrent C tiles for par_gemm, preferring concurrency
+// and then larger tiles, such that the footprint stays within the budget.
+#[cfg(feature = "rayon")]
+fn plan_par_gemm(m: u64, element: usize, budget: usize, threads: usize) -> Result<(usize, usize), OoclaError> {
+    let mut best: Option<(usize, usize)> = None;
+    for &tile in PAR_GEMM_TILE_CANDIDATES.iter() {
+        let row_tiles = cmp::max(1, m.div_ceil(tile as u64)) as usize;
+        let most = cmp::min(cmp::max(threads, 1), row_tiles);
+        let fitting = (1..=most).rev().find(|&group| par_gemm_footprint(tile, group, element).is_some_and(|b| b <= budget));
+        if let Some(group) = fitting {
+            if best.is_none_or(|(_, g)| group > g) {
+                best = Some((tile, group));
+            }
+        }
+    }
+    best.ok_or_else(|| {
+        let smallest = PAR_GEMM_TILE_CANDIDATES[PAR_GEMM_TILE_CANDIDATES.len() - 1];
+        OoclaError::InvalidArgument(format!("memory budget of {} bytes cannot hold the {} bytes needed for {}x{} tiles",
+                                            budget, par_gemm_footprint(smallest, 1, element).unwrap_or(usize::MAX),
+                                            smallest, smallest))
+    })
+}
+
+// As gemm, but computing several C tiles at once on the rayon pool while the next A and B
+// tiles are loaded alongside, with tile size and concurrency chosen so that the tile buffers
+// in flight never exceed `memory_budget_bytes`. Each element of C accumulates its products in
+// increasing k exactly as gemm does, so the result is bit-identical to gemm's whatever the
+// schedule.
+#[cfg(feature = "rayon")]
+pub fn par_gemm<T: SupportedType + Send + Sync>(alpha: T, a: &Dense<T>, b: &Dense<T>, beta: T, c: &mut Dense<T>,
+                                                memory_budget_bytes: usize) -> Result<(), OoclaError> {
+    par_gemm_with(alpha, a, b, beta, c, memory_budget_bytes, &MemoryBudget::unlimited())
+}
+
+// As par_gemm, planning within the smaller of `memory_budget_bytes` and the limit of `budget`,
+// and reserving the tile buffers for each group of C tiles from `budget` while computing them.
+#[cfg(feature = "rayon")]
+pub fn par_gemm_with<T: SupportedType + Send + Sync>(alpha: T, a: &Dense<T>, b: &Dense<T>, beta: T, c: &mut Dense<T>,
+                                                     memory_budget_bytes: usize, budget: &MemoryBudget)
+    -> Result<(), OoclaError> {
+    check_gemm_shapes(a, b, c)?;
+    let (m, n, k) = (a.num_rows(), b.num_cols(), a.num_cols());
+    if k == 0 {
+        // There are no products to accumulate, so C = beta·C, as gemm leaves it.
+        return scale_in_place(c, beta);
+    }
+    let (tile, group) = plan_par_gemm(m, mem::size_of::<T>(), budget.cap(memory_budget_bytes),
+                                      rayon::current_num_threads())?;
+    let footprint = par_gemm_footprint(tile, group, mem::size_of::<T>()).expect("planned footprints fit in usize");
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("par_gemm", m, n, k, tile_size = tile, group).entered();
+    let (alpha, beta) = (alpha.to_f64(), beta.to_f64());
+    let (shared_a, shared_b) = (SharedRead(a), SharedRead(b));
+    let step = tile as u64;
+    let k_starts: Vec<u64> = (0..k).step_by(tile).collect();
+    for batch_start in (0..m).step_by(tile * group) {
+        let row_tiles: Vec<(u64, usize)> = (batch_start..m).step_by(tile).take(group)
+            .map(|i0| (i0, cmp::min(step, m - i0) as usize)).collect();
+        for j0 in (0..n).step_by(tile) {
+            let cols = cmp::min(step, n - j0) as usize;
+            let _reserved = budget.alloc(footprint)?;
+            let mut accs: Vec<Vec<f64>> = row_tiles.iter().map(|&(i0, rows)| {
+                // beta == 0 must not propagate NaNs already present in C.
+                if beta == 0.0 {
+                    vec![0.0; rows * cols]
+                } else {
+                    c.read_tile(i0, j0, rows, cols).data.iter().map(|x| beta * x.to_f64()).collect()
+                }
+            }).collect();
+            let load = |k0: u64| {
+                let depth = cmp::min(step, k - k0) as usize;
+                let a_tiles: Vec<Tile<T>> = row_tiles.iter()
+                    .map(|&(i0, rows)| shared_a.0.read_tile(i0, k0, rows, depth)).collect();
+                (a_tiles, shared_b.0.read_tile(k0, j0, depth, cols))
+            };
+            let mut current = k_starts.first().map(|&k0| load(k0));
+            for next_k0 in k_starts.iter().skip(1).map(Some).chain(Some(None)) {
+                let (a_tiles, b_tile) = current.take().expect("a loaded k step precedes every compute step");
+                let (next, ()) = rayon::join(|| next_k0.map(|&k0| load(k0)), || {
+                    accs.par_iter_mut().zip(a_tiles.par_iter())
+                        .for_each(|(acc, a_tile)| tile_gemm_acc(alpha, a_tile, &b_tile, acc));
+                });
+                current = next;
+            }
+            for (acc, &(i0, rows)) in accs.iter().zip(row_tiles.iter()) {
+                c.write_tile(&Tile {
+                    row: i0,
+                    col: j0,
+                    rows,
+                    cols,
+                    data: acc.iter().map(|&x| T::from_f64(x)).collect(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// Computes Z = A^T·B for an m x n matrix A and an m x l matrix B with l small, so that each
+// row tile of Z can be accumulated in memory over a single sweep down A.
+pub(crate) fn gemm_at_b(a: &Dense<f64>, b: &Dense<f64>, z: &mut Dense<f64>) -> Result<(), OoclaError> {
+    let (m, n, l) = (a.num_rows(), a.num_cols(), b.num_cols());
+    if b.num_rows() != m || z.num_rows() != n || z.num_cols() != l {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (n, l),
+            found: (z.num_rows(), z.num_cols()),
+        });
+    }
+    let step = DEFAULT_TILE_SIZE as u64;
+    for j0 in (0..n).step_by(DEFAULT_TILE_SIZE) {
+        let cols = cmp::min(step, n - j0) as usize;
+        let mut out = Tile::zeros(j0, 0, cols, l as usize);
+        for i0 in (0..m).step_by(DEFAULT_TILE_SIZE) {
+            let rows = cmp::min(step, m - i0) as usize;
+            let at = a.read_tile(i0, j0, rows, cols).transposed();
+            let bt = b.read_tile(i0, 0, rows, l as usize);
+            tile_gemm_acc(1.0, &at, &bt, &mut out.data);
+        }
+        z.write_tile(&out);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use budget::BudgetPolicy;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    #[cfg(feature = "rayon")]
+    use rand::Rand;
+
+    fn transposed_copy(a: &Dense<f64>) -> Dense<f64> {
+        Dense::anonymous_from_fn(a.num_cols(), a.num_rows(), |i, j| a.get(j, i))
+    }
+
+    fn assert_close(a: &Dense<f64>, b: &Dense<f64>, tolerance: f64) {
+        assert_eq!((a.num_rows(), a.num_cols()), (b.num_rows(), b.num_cols()));
+        for i in 0..a.num_rows() {
+            for j in 0..a.num_cols() {
+                let (x, y) = (a.get(i, j), b.get(i, j));
+                assert!((x - y).abs() <= tolerance * (1.0 + y.abs()), "({}, {}): {} vs {}", i, j, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn syrk_matches_gemm_of_the_transpose() {
+        for &(m, d, block_rows) in &[(1, 1, 1), (7, 3, 2), (50, 9, 8), (33, 17, 100)] {
+            let mut a = Dense::<f64>::create_anonymous(m, d).unwrap();
+            a.randomise_seeded(m * 31 + d);
+            let at = transposed_copy(&a);
+            let mut expected = Dense::<f64>::create_anonymous(d, d).unwrap();
+            gemm(1.0, &at, &a, 0.0, &mut expected, 4).unwrap();
+            let mut g = Dense::<f64>::create_anonymous(d, d).unwrap();
+            syrk(&a, &mut g, block_rows).unwrap();
+            assert_close(&g, &expected, 1e-12);
+        }
+    }
+
+    #[test]
+    fn syrk_of_a_transposed_matrix_matches_gemm() {
+        let mut a = Dense::<f64>::create_anonymous(6, 20).unwrap();
+        a.randomise_seeded(5);
+        a.transpose();
+        let at = transposed_copy(&a);
+        let mut expected = Dense::<f64>::create_anonymous(6, 6).unwrap();
+        gemm(1.0, &at, &a, 0.0, &mut expected, 8).unwrap();
+        let mut g = Dense::<f64>::create_anonymous(6, 6).unwrap();
+        syrk(&a, &mut g, 3).unwrap();
+        assert_close(&g, &expected, 1e-12);
+    }
+
+    // Row i of A is 1 + e_(i mod d), so with d dividing n the Gram matrix is
+    // (n / d)·I + (n + 2n / d)·J, where J is all ones. The budget holds only a few rows of the
+    // accumulator, far less than A itself, so G is built over several passes.
+    #[test]
+    fn syrk_over_budget_matches_an_analytic_gram_matrix() {
+        let (n, d) = (40_000u64, 16u64);
+        let a = Dense::<f64>::anonymous_from_fn(n, d, |i, j| if i % d == j { 2.0 } else { 1.0 });
+        let block_rows = 64;
+        let line_bytes = d as usize * mem::size_of::<f64>();
+        let limit = syrk_footprint(block_rows, d as usize).unwrap() + 4 * line_bytes;
+        assert!(limit * 100 < (n * d) as usize * mem::size_of::<f64>());
+        let budget = MemoryBudget::new(limit, BudgetPolicy::Fail);
+        let mut g = Dense::<f64>::create_anonymous(d, d).unwrap();
+        syrk_with(&a, &mut g, block_rows, &budget).unwrap();
+        assert!(budget.peak() <= limit);
+        let (diagonal, off_diagonal) = ((n / d) as f64, (n + 2 * n / d) as f64);
+        for i in 0..d {
+            for j in 0..d {
+                let expected = if i == j { diagonal + off_diagonal } else { off_diagonal };
+                assert_eq!(g.get(i, j), expected, "({}, {})", i, j);
+            }
+        }
+    }
+
+    // Two GEMMs on threads of their own share a budget that holds the tile buffers of only one
+    // at a time, so neither can hold its buffers while the other does, as a third thread watching
+    // the bytes in use checks.
+    #[test]
+    fn concurrent_gemms_stay_within_a_shared_budget() {
+        let tile = 8;
+        let footprint = gemm_footprint(tile, mem::size_of::<f64>()).unwrap();
+        let limit = 2 * footprint - 1;
+        let budget = MemoryBudget::new(limit, BudgetPolicy::Block);
+        let done = AtomicBool::new(false);
+        let (results, most_seen) = thread::scope(|scope| {
+            let watcher = scope.spawn(|| {
+                let mut most_seen = 0;
+                while !done.load(Ordering::Acquire) {
+                    most_seen = cmp::max(most_seen, budget.in_use());
+                    thread::yield_now();
+                }
+                most_seen
+            });
+            // A matrix is used only by the thread that created it.
+            let workers: Vec<_> = (0..2u64).map(|seed| {
+                let budget = budget.clone();
+                scope.spawn(move || {
+                    let mut a = Dense::<f64>::create_anonymous(96, 80).unwrap();
+                    let mut b = Dense::<f64>::create_anonymous(80, 72).unwrap();
+                    a.randomise_seeded(2 * seed);
+                    b.randomise_seeded(2 * seed + 1);
+                    let mut c = Dense::<f64>::create_anonymous(96, 72).unwrap();
+                    for _ in 0..3 {
+                        gemm_with(1.0, &a, &b, 0.0, &mut c, tile, &budget).unwrap();
+                    }
+                    let mut expected = Dense::<f64>::create_anonymous(96, 72).unwrap();
+                    gemm(1.0, &a, &b, 0.0, &mut expected, tile).unwrap();
+                    (elements(&c), elements(&expected))
+                })
+            }).collect();
+            let results: Vec<_> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+            done.store(true, Ordering::Release);
+            (results, watcher.join().unwrap())
+        });
+        assert!(budget.peak() <= limit, "peak of {} bytes over a limit of {}", budget.peak(), limit);
+        assert_eq!(budget.peak(), footprint);
+        assert!(most_seen <= limit);
+        assert_eq!(budget.in_use(), 0);
+        for (product, expected) in results {
+            assert_eq!(product, expected);
+        }
+    }
+
+    // A + alpha * sum_k xs[k]·ys[k]^T computed element by element.
+    fn rank_k_reference(alpha: f64, xs: &[&[f64]], ys: &[&[f64]], a: &Dense<f64>) -> Vec<f64> {
+        let mut expected = Vec::new();
+        for i in 0..a.num_rows() as usize {
+            for j in 0..a.num_cols() as usize {
+                let update: f64 = xs.iter().zip(ys.iter()).map(|(x, y)| x[i] * y[j]).sum();
+                expected.push(a.get(i as u64, j as u64) + alpha * update);
+            }
+        }
+        expected
+    }
+
+    fn elements(a: &Dense<f64>) -> Vec<f64> {
+        let mut values = Vec::new();
+        for i in 0..a.num_rows() {
+            for j in 0..a.num_cols() {
+                values.push(a.get(i, j));
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn ger_matches_a_dense_reference() {
+        let x = [1.0, -2.0, 0.0, 3.5];
+        let y = [0.5, 4.0, -1.0];
+        for &transposed in &[false, true] {
+            let mut a = if transposed {
+                let mut a = Dense::<f64>::anonymous_from_fn(3, 4, |i, j| (i * 4 + j) as f64);
+                a.transpose();
+                a
+            } else {
+                Dense::<f64>::anonymous_from_fn(4, 3, |i, j| (j * 4 + i) as f64)
+            };
+            let expected = rank_k_reference(2.0, &[&x], &[&y], &a);
+            ger(2.0, &x, &y, &mut a).unwrap();
+            assert_eq!(elements(&a), expected, "transposed: {}", transposed);
+        }
+    }
+
+    #[test]
+    fn rank_k_update_matches_a_dense_reference() {
+        let mut a = Dense::<f64>::create_anonymous(5, 4).unwrap();
+        a.randomise_seeded(17);
+        let xs: Vec<Vec<f64>> = (0..3).map(|k| (0..5).map(|i| (i + k) as f64 - 2.0).collect()).collect();
+        let ys: Vec<Vec<f64>> = (0..3).map(|k| (0..4).map(|j| (j * k) as f64 * 0.25).collect()).collect();
+        let xs: Vec<&[f64]> = xs.iter().map(|x| &x[..]).collect();
+        let ys: Vec<&[f64]> = ys.iter().map(|y| &y[..]).collect();
+        let expected = rank_k_reference(-1.5, &xs, &ys, &a);
+        rank_k_update(-1.5, &xs, &ys, &mut a).unwrap();
+        for (value, expected) in elements(&a).iter().zip(expected.iter()) {
+            assert!((value - expected).abs() <= 1e-12 * (1.0 + expected.abs()));
+        }
+    }
+
+    #[test]
+    fn ger_checks_vector_lengths() {
+        let mut a = Dense::<f64>::create_anonymous(3, 2).unwrap();
+        assert!(ger(1.0, &[1.0, 2.0], &[1.0, 2.0], &mut a).is_err());
+        assert!(ger(1.0, &[1.0, 2.0, 3.0], &[1.0], &mut a).is_err());
+        assert!(rank_k_update(1.0, &[&[1.0, 2.0, 3.0][..]], &[], &mut a).is_err());
+        assert_eq!(elements(&a), vec![0.0; 6]);
+    }
+
+    #[test]
+    fn syrk_rejects_a_wrongly_shaped_output() {
+        let a = Dense::<f64>::create_anonymous(5, 3).unwrap();
+        let mut g = Dense::<f64>::create_anonymous(3, 4).unwrap();
+        match syrk(&a, &mut g, 2) {
+            Err(OoclaError::ShapeMismatch { expected, found }) => {
+                assert_eq!(expected, (3, 3));
+                assert_eq!(found, (3, 4));
+            }
+            other => panic!("expected a shape mismatch, got {:?}", other),
+        }
+        let mut g = Dense::<f64>::create_anonymous(3, 3).unwrap();
+        assert!(syrk(&a, &mut g, 0).is_err());
+    }
+
+    #[cfg(feature = "rayon")]
+    fn bits<T: SupportedType>(a: &Dense<T>) -> Vec<u64> {
+        let mut values = Vec::new();
+        for i in 0..a.num_rows() {
+            for j in 0..a.num_cols() {
+                values.push(a.get(i, j).to_f64().to_bits());
+            }
+        }
+        values
+    }
+
+    // A and B are each several times the budget, so only a few tiles of either are ever
+    // resident, and a pool of four threads computes several C tiles at once.
+    #[cfg(feature = "rayon")]
+    fn check_par_gemm_matches_gemm<T: SupportedType + Rand + Send + Sync>(beta: f64) {
+        let (m, k, n) = (300, 200, 250);
+        let budget_bytes = 32 << 10;
+        assert!((m * k) as usize * mem::size_of::<T>() > 4 * budget_bytes);
+        assert!((k * n) as usize * mem::size_of::<T>() > 4 * budget_bytes);
+        let mut a = Dense::<T>::create_anonymous(m, k).unwrap();
+        a.randomise_seeded(1);
+        let mut b = Dense::<T>::create_anonymous(k, n).unwrap();
+        b.randomise_seeded(2);
+        // Only a shared handle can be lent to the pool's threads.
+        let (a, b) = (a.into_shared().unwrap(), b.into_shared().unwrap());
+        let initial = |i: u64, j: u64| T::from_f64((i as f64 - j as f64) / 7.0);
+        let mut expected = Dense::<T>::anonymous_from_fn(m, n, initial);
+        gemm(T::from_f64(1.5), &a, &b, T::from_f64(beta), &mut expected, 64).unwrap();
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for _ in 0..3 {
+            let mut c = Dense::<T>::anonymous_from_fn(m, n, initial);
+            let budget = MemoryBudget::unlimited();
+            pool.install(|| par_gemm_with(T::from_f64(1.5), &a, &b, T::from_f64(beta), &mut c, budget_bytes, &budget))
+                .unwrap();
+            assert!(budget.peak() <= budget_bytes);
+            assert!(bits(&c) == bits(&expected), "par_gemm differs from gemm");
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_gemm_over_budget_is_bit_identical_to_gemm() {
+        check_par_gemm_matches_gemm::<f64>(0.0);
+        check_par_gemm_matches_gemm::<f64>(-0.5);
+        check_par_gemm_matches_gemm::<f32>(2.0);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_gemm_with_empty_inner_dimension_scales_c() {
+        let a = Dense::<f64>::create_anonymous(5, 0).unwrap();
+        let b = Dense::<f64>::create_anonymous(0, 3).unwrap();
+        let mut c = Dense::<f64>::anonymous_from_fn(5, 3, |i, j| (i * 3 + j) as f64);
+        par_gemm(1.0, &a, &b, 2.0, &mut c, 1 << 20).unwrap();
+        let mut expected = Dense::<f64>::anonymous_from_fn(5, 3, |i, j| (i * 3 + j) as f64);
+        gemm(1.0, &a, &b, 2.0, &mut expected, 4).unwrap();
+        assert_eq!(bits(&c), bits(&expected));
+        assert_eq!(c.get(4, 2), 28.0);
+        c.set(1, 1, f64::NAN);
+        par_gemm(1.0, &a, &b, 0.0, &mut c, 1 << 20).unwrap();
+        assert!(bits(&c).iter().all(|&x| x == 0));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_gemm_rejects_a_budget_too_small_for_any_tile() {
+        let a = Dense::<f64>::create_anonymous(4, 4).unwrap();
+        let b = Dense::<f64>::create_anonymous(4, 4).unwrap();
+        let mut c = Dense::<f64>::create_anonymous(4, 4).unwrap();
+        assert!(par_gemm(1.0, &a, &b, 0.0, &mut c, 1024).is_err());
+    }
+}
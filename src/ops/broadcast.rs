@@ -0,0 +1,81 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Along {
+    Rows,
+    Cols,
+}
+
+// Applies `f(element, v[k])` to every element, where k is the element's logical column for
+// `Along::Rows` (v is broadcast down the rows) and its logical row for `Along::Cols`.
+fn broadcast<T, V, F>(a: &mut Dense<T>, v: &[V], along: Along, f: F) -> Result<(), OoclaError>
+    where T: SupportedType, V: Copy, F: Fn(f64, V) -> f64 {
+    let expected = match along {
+        Along::Rows => a.num_cols(),
+        Along::Cols => a.num_rows(),
+    };
+    if v.len() as u64 != expected {
+        return Err(OoclaError::InvalidArgument(format!("vector of length {} cannot be broadcast over a {}x{} matrix",
+                                                       v.len(), a.num_rows(), a.num_cols())));
+    }
+    // Storage lines are logical rows unless the matrix is transposed.
+    let indexed_by_minor = (along == Along::Rows) != a.is_transposed();
+    for major in 0..a.major_len() {
+        let line = a.major_slice_mut(major);
+        if indexed_by_minor {
+            for (element, &value) in line.iter_mut().zip(v.iter()) {
+                *element = T::from_f64(f(element.to_f64(), value));
+            }
+        } else {
+            let value = v[major as usize];
+            for element in line.iter_mut() {
+                *element = T::from_f64(f(element.to_f64(), value));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn add_row_vector<T: SupportedType>(a: &mut Dense<T>, v: &[T]) -> Result<(), OoclaError> {
+    broadcast(a, v, Along::Rows, |x, y| x + y.to_f64())
+}
+
+pub fn sub_row_vector<T: SupportedType>(a: &mut Dense<T>, v: &[T]) -> Result<(), OoclaError> {
+    broadcast(a, v, Along::Rows, |x, y| x - y.to_f64())
+}
+
+pub fn mul_row_vector<T: SupportedType>(a: &mut Dense<T>, v: &[T]) -> Result<(), OoclaError> {
+    broadcast(a, v, Along::Rows, |x, y| x * y.to_f64())
+}
+
+pub fn div_row_vector<T: SupportedType>(a: &mut Dense<T>, v: &[T]) -> Result<(), OoclaError> {
+    broadcast(a, v, Along::Rows, |x, y| x / y.to_f64())
+}
+
+pub fn add_col_vector<T: SupportedType>(a: &mut Dense<T>, v: &[T]) -> Result<(), OoclaError> {
+    broadcast(a, v, Along::Cols, |x, y| x + y.to_f64())
+}
+
+pub fn sub_col_vector<T: SupportedType>(a: &mut Dense<T>, v: &[T]) -> Result<(), OoclaError> {
+    broadcast(a, v, Along::Cols, |x, y| x - y.to_f64())
+}
+
+pub fn mul_col_vector<T: SupportedType>(a: &mut Dense<T>, v: &[T]) -> Result<(), OoclaError> {
+    broadcast(a, v, Along::Cols, |x, y| x * y.to_f64())
+}
+
+pub fn div_col_vector<T: SupportedType>(a: &mut Dense<T>, v: &[T]) -> Result<(), OoclaError> {
+    broadcast(a, v, Along::Cols, |x, y| x / y.to_f64())
+}
+
+impl<T: SupportedType> Dense<T> {
+    // Columns with a zero standard deviation are constant, so they are mapped to zero.
+    pub fn standardize_columns(&mut self, means: &[f64], stds: &[f64]) -> Result<(), OoclaError> {
+        if means.len() != stds.len() {
+            return Err(OoclaError::InvalidArgument(format!("{} means but {} standard deviations", means.len(), stds.len())));
+        }
+        let params: Vec<(f64, f64)> = means.iter().cloned().zip(stds.iter().cloned()).collect();
+        broadcast(self, &params, Along::Rows, |x, (mean, std)| if std == 0.0 { 0.0 } else { (x - mean) / std })
+    }
+}
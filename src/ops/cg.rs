@@ -0,0 +1,207 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::gemv;
+
+#[derive(Clone, Debug)]
+pub struct CgResult {
+    pub x: Vec<f64>,
+    pub iters: usize,
+    pub residual_norm: f64,
+}
+
+fn dot(x: &[f64], y: &[f64]) -> f64 {
+    x.iter().zip(y.iter()).map(|(a, b)| a * b).sum()
+}
+
+fn jacobi_preconditioner(a: &Dense<f64>) -> Result<Vec<f64>, OoclaError> {
+    a.diagonal_iter().enumerate().map(|(i, &d)| {
+        if d.is_nan() || d <= 0.0 {
+            Err(OoclaError::NotPositiveDefinite { at: i as u64 })
+        } else {
+            Ok(1.0 / d)
+        }
+    }).collect()
+}
+
+// Solves A·x = b for symmetric positive-definite A by (preconditioned) conjugate gradients,
+// starting from x0 or zero. Each iteration is one gemv over A; all vectors are held in memory.
+// Iteration stops once |b - A·x| <= tol·|b|. With `jacobi` set the inverse diagonal of A is
+// used as a preconditioner. Non-convergence gives CgDidNotConverge and a non-positive
+// curvature p^T·A·p (A is not positive definite) gives CgBreakdown, both carrying the iterate
+// with the smallest residual seen.
+pub fn conjugate_gradient(a: &Dense<f64>, b: &[f64], x0: Option<&[f64]>, tol: f64, max_iters: usize, jacobi: bool)
+    -> Result<CgResult, OoclaError> {
+    let n = a.num_rows();
+    if a.num_cols() != n {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (n, n),
+            found: (a.num_rows(), a.num_cols()),
+        });
+    }
+    for v in Some(b).into_iter().chain(x0) {
+        if v.len() as u64 != n {
+            return Err(OoclaError::ShapeMismatch {
+                expected: (n, 1),
+                found: (v.len() as u64, 1),
+            });
+        }
+    }
+    let n = n as usize;
+    let inverse_diagonal = if jacobi { Some(jacobi_preconditioner(a)?) } else { None };
+    let precondition = |r: &[f64], z: &mut [f64]| match inverse_diagonal {
+        Some(ref m) => {
+            for ((z, r), m) in z.iter_mut().zip(r.iter()).zip(m.iter()) {
+                *z = r * m;
+            }
+        }
+        None => z.copy_from_slice(r),
+    };
+    let mut x = x0.map_or_else(|| vec![0.0; n], |x0| x0.to_vec());
+    let mut r = vec![0.0; n];
+    gemv(1.0, a, &x, 0.0, &mut r)?;
+    for (r, b) in r.iter_mut().zip(b.iter()) {
+        *r = b - *r;
+    }
+    let mut z = vec![0.0; n];
+    precondition(&r, &mut z);
+    let mut p = z.clone();
+    let mut q = vec![0.0; n];
+    let mut rz = dot(&r, &z);
+    let threshold = tol * dot(b, b).sqrt();
+    let mut residual_norm = dot(&r, &r).sqrt();
+    let mut best = CgResult { x: x.clone(), iters: 0, residual_norm };
+    for iter in 0..max_iters {
+        if residual_norm <= threshold {
+            return Ok(CgResult { x, iters: iter, residual_norm });
+        }
+        gemv(1.0, a, &p, 0.0, &mut q)?;
+        let curvature = dot(&p, &q);
+        if curvature.is_nan() || curvature <= 0.0 {
+            return Err(OoclaError::CgBreakdown { iteration: iter, best });
+        }
+        let alpha = rz / curvature;
+        for ((x, r), (p, q)) in x.iter_mut().zip(r.iter_mut()).zip(p.iter().zip(q.iter())) {
+            *x += alpha * p;
+            *r -= alpha * q;
+        }
+        residual_norm = dot(&r, &r).sqrt();
+        if residual_norm < best.residual_norm {
+            best.x.copy_from_slice(&x);
+            best.residual_norm = residual_norm;
+            best.iters = iter + 1;
+        }
+        precondition(&r, &mut z);
+        let rz_next = dot(&r, &z);
+        let beta = rz_next / rz;
+        rz = rz_next;
+        for (p, z) in p.iter_mut().zip(z.iter()) {
+            *p = z + beta * *p;
+        }
+    }
+    if residual_norm <= threshold {
+        return Ok(CgResult { x, iters: max_iters, residual_norm });
+    }
+    Err(OoclaError::CgDidNotConverge(best))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A symmetric, diagonally dominant matrix with a widely varying diagonal, which Jacobi
+    // preconditioning helps with.
+    fn spd(n: u64) -> Dense<f64> {
+        Dense::anonymous_from_fn(n, n, |i, j| {
+            if i == j {
+                4.0 + (i * i) as f64
+            } else if i + 1 == j || j + 1 == i {
+                -1.0
+            } else {
+                0.0
+            }
+        })
+    }
+
+    fn rhs_for(a: &Dense<f64>, x: &[f64]) -> Vec<f64> {
+        let mut b = vec![0.0; x.len()];
+        gemv(1.0, a, x, 0.0, &mut b).unwrap();
+        b
+    }
+
+    #[test]
+    fn converges_to_a_known_solution_with_and_without_jacobi() {
+        let a = spd(60);
+        let expected: Vec<f64> = (0..60).map(|i| (i as f64 * 0.3).sin()).collect();
+        let b = rhs_for(&a, &expected);
+        let plain = conjugate_gradient(&a, &b, None, 1e-12, 500, false).unwrap();
+        let jacobi = conjugate_gradient(&a, &b, None, 1e-12, 500, true).unwrap();
+        for result in &[&plain, &jacobi] {
+            assert!(result.residual_norm <= 1e-12 * dot(&b, &b).sqrt());
+            for (x, e) in result.x.iter().zip(expected.iter()) {
+                assert!((x - e).abs() < 1e-9);
+            }
+        }
+        assert!(jacobi.iters < plain.iters, "jacobi {} vs plain {}", jacobi.iters, plain.iters);
+    }
+
+    #[test]
+    fn starting_from_the_solution_takes_no_iterations() {
+        let a = spd(10);
+        let expected = vec![1.0; 10];
+        let b = rhs_for(&a, &expected);
+        let result = conjugate_gradient(&a, &b, Some(&expected), 1e-10, 100, false).unwrap();
+        assert_eq!(result.iters, 0);
+        assert_eq!(result.x, expected);
+    }
+
+    #[test]
+    fn an_indefinite_matrix_breaks_down() {
+        let a = Dense::<f64>::anonymous_from_fn(3, 3, |i, j| if i == j { [2.0, -1.0, 3.0][i as usize] } else { 0.0 });
+        match conjugate_gradient(&a, &[0.0, 1.0, 0.0], None, 1e-12, 50, false) {
+            Err(OoclaError::CgBreakdown { iteration, best }) => {
+                assert_eq!(iteration, 0);
+                assert_eq!(best.x, vec![0.0; 3]);
+                assert_eq!(best.residual_norm, 1.0);
+            }
+            other => panic!("expected a breakdown, got {:?}", other),
+        }
+        match conjugate_gradient(&a, &[1.0, 1.0, 1.0], None, 1e-12, 50, true) {
+            Err(OoclaError::NotPositiveDefinite { at }) => assert_eq!(at, 1),
+            other => panic!("expected a non-positive diagonal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn running_out_of_iterations_returns_the_best_iterate() {
+        let a = spd(40);
+        let b = vec![1.0; 40];
+        match conjugate_gradient(&a, &b, None, 1e-15, 2, false) {
+            Err(OoclaError::CgDidNotConverge(best)) => {
+                assert!(best.iters <= 2);
+                assert!(best.residual_norm < dot(&b, &b).sqrt());
+            }
+            other => panic!("expected no convergence, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wrong_vector_lengths_are_reported() {
+        let a = spd(4);
+        match conjugate_gradient(&a, &[1.0; 3], None, 1e-8, 10, false) {
+            Err(OoclaError::ShapeMismatch { expected, found }) => {
+                assert_eq!(expected, (4, 1));
+                assert_eq!(found, (3, 1));
+            }
+            other => panic!("expected a shape mismatch, got {:?}", other),
+        }
+        match conjugate_gradient(&a, &[1.0; 4], Some(&[0.0; 5]), 1e-8, 10, false) {
+            Err(OoclaError::ShapeMismatch { expected, found }) => {
+                assert_eq!(expected, (4, 1));
+                assert_eq!(found, (5, 1));
+            }
+            other => panic!("expected a shape mismatch, got {:?}", other),
+        }
+        assert!(conjugate_gradient(&Dense::<f64>::create_anonymous(3, 4).unwrap(), &[1.0; 3], None, 1e-8, 10, false)
+            .is_err());
+    }
+}
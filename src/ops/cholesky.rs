@@ -0,0 +1,186 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::tile_gemm_acc;
+use progress::{Meter, NoProgress, Progress, ProgressUnit};
+use std::{cmp, mem};
+use tile::{DEFAULT_TILE_SIZE, Tile};
+
+// Factors a panel of `width` columns whose first row is on the diagonal at `k0`. The top
+// width x width block becomes its Cholesky factor and the rows below it are solved against
+// that factor. Only the lower triangle of the top block is read or written.
+fn factor_panel(panel: &mut Tile<f64>, k0: u64) -> Result<(), OoclaError> {
+    let (height, width) = (panel.rows, panel.cols);
+    for j in 0..width {
+        let (top, below) = panel.data.split_at_mut((j + 1) * width);
+        let pivot_row = &mut top[j * width..];
+        let d = pivot_row[j] - pivot_row[..j].iter().map(|x| x * x).sum::<f64>();
+        if d.is_nan() || d <= 0.0 {
+            return Err(OoclaError::NotPositiveDefinite { at: k0 + j as u64 });
+        }
+        let d = d.sqrt();
+        pivot_row[j] = d;
+        let pivot_row = &pivot_row[..j];
+        for row in below.chunks_mut(width).take(height - j - 1) {
+            let dot: f64 = row[..j].iter().zip(pivot_row.iter()).map(|(x, y)| x * y).sum();
+            row[j] = (row[j] - dot) / d;
+        }
+    }
+    Ok(())
+}
+
+// Copies panel rows [start, start + count) into a width x count tile, i.e. the transpose of
+// that slice of L21.
+fn transposed_rows(panel: &Tile<f64>, start: usize, count: usize) -> Tile<f64> {
+    let width = panel.cols;
+    let mut tile = Tile::zeros(0, 0, width, count);
+    for c in 0..count {
+        for (t, &value) in panel.row_slice(start + c).iter().enumerate() {
+            tile.data[t * count + c] = value;
+        }
+    }
+    tile
+}
+
+// Overwrites the lower triangle of a symmetric positive-definite matrix with its Cholesky
+// factor L, using blocks of `block` columns. The strictly upper triangle is neither read nor
+// modified. The current panel, of (n - k) x block elements, is held in memory and the
+// trailing update touches one tile of A22 at a time. If a non-positive pivot appears, its
+// index is reported and A is left partially factored.
+pub fn cholesky_inplace(a: &mut Dense<f64>, block: usize) -> Result<(), OoclaError> {
+    cholesky_inplace_progress(a, block, &NoProgress)
+}
+
+// Bytes of the lower triangle from the diagonal at `k0` down, which the panel step there reads
+// and rewrites. The step's arithmetic grows with the same square of the trailing size.
+fn step_bytes(n: u64, k0: u64) -> u64 {
+    let size = n - k0;
+    size * (size + 1) / 2 * mem::size_of::<f64>() as u64
+}
+
+// As cholesky_inplace, reporting to `progress` the bytes of the trailing lower triangle read and
+// rewritten by each panel step. Cancellation takes effect between panels, leaving the columns of
+// the panels finished so far holding L and the lower triangle of the rest holding the trailing
+// matrix still to be factored, exactly as if A had been factored that far.
+pub fn cholesky_inplace_progress(a: &mut Dense<f64>, block: usize, progress: &dyn Progress) -> Result<(), OoclaError> {
+    if block == 0 {
+        return Err(OoclaError::InvalidArgument("block must be non-zero".to_string()));
+    }
+    let n = a.num_rows();
+    if a.num_cols() != n {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (n, n),
+            found: (a.num_rows(), a.num_cols()),
+        });
+    }
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("cholesky", n, block).entered();
+    let tile_size = DEFAULT_TILE_SIZE as u64;
+    let total = (0..n).step_by(block).map(|k0| step_bytes(n, k0)).sum();
+    let mut meter = Meter::new(progress, Some(total), ProgressUnit::Bytes);
+    let mut k0 = 0;
+    while k0 < n {
+        let width = cmp::min(block as u64, n - k0) as usize;
+        let mut panel = a.read_tile(k0, k0, (n - k0) as usize, width);
+        factor_panel(&mut panel, k0)?;
+        // Rewriting the panel's upper corner stores the values just read, so the upper
+        // triangle is unchanged.
+        a.write_tile(&panel);
+        let rest = k0 + width as u64;
+        let mut j0 = rest;
+        while j0 < n {
+            let cols = cmp::min(tile_size, n - j0) as usize;
+            let lt = transposed_rows(&panel, (j0 - k0) as usize, cols);
+            let mut acc = Vec::new();
+            let mut i0 = j0;
+            while i0 < n {
+                let rows = cmp::min(tile_size, n - i0) as usize;
+                let offset = (i0 - k0) as usize * width;
+                let l = Tile {
+                    row: i0,
+                    col: k0,
+                    rows,
+                    cols: width,
+                    data: panel.data[offset..offset + rows * width].to_vec(),
+                };
+                acc.clear();
+                acc.resize(rows * cols, 0.0);
+                tile_gemm_acc(-1.0, &l, &lt, &mut acc);
+                let mut c = a.read_tile(i0, j0, rows, cols);
+                for i in 0..rows {
+                    // Tiles on the diagonal are only updated on and below it.
+                    let limit = if i0 == j0 { i + 1 } else { cols };
+                    for j in 0..limit {
+                        c.data[i * cols + j] += acc[i * cols + j];
+                    }
+                }
+                a.write_tile(&c);
+                i0 += rows as u64;
+            }
+            j0 += cols as u64;
+        }
+        meter.advance(step_bytes(n, k0))?;
+        k0 = rest;
+    }
+    meter.finish();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A lower triangular factor with a diagonal comfortably away from zero.
+    fn reference_factor(n: u64, seed: u64) -> Dense<f64> {
+        let mut noise = Dense::<f64>::create_anonymous(n, n).unwrap();
+        noise.randomise_seeded(seed);
+        Dense::anonymous_from_fn(n, n, |i, j| {
+            if i == j {
+                1.0 + noise.get(i, j)
+            } else if j < i {
+                noise.get(i, j) - 0.5
+            } else {
+                0.0
+            }
+        })
+    }
+
+    #[test]
+    fn factor_of_l_lt_recovers_l() {
+        for &(n, block) in &[(1, 1), (9, 4), (40, 7), (64, 64)] {
+            let l = reference_factor(n, n);
+            // The upper triangle is filled with a marker that must survive untouched.
+            let mut a = Dense::<f64>::anonymous_from_fn(n, n, |i, j| {
+                if j <= i {
+                    (0..=j).map(|k| l.get(i, k) * l.get(j, k)).sum()
+                } else {
+                    99.0
+                }
+            });
+            cholesky_inplace(&mut a, block).unwrap();
+            for i in 0..n {
+                for j in 0..n {
+                    if j <= i {
+                        assert!((a.get(i, j) - l.get(i, j)).abs() < 1e-10, "n {} at ({}, {})", n, i, j);
+                    } else {
+                        assert_eq!(a.get(i, j), 99.0);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn indefinite_matrices_are_rejected_at_their_pivot() {
+        let rows = [[4.0, 2.0, 0.0], [2.0, 1.0, 1.0], [0.0, 1.0, 3.0]];
+        let mut a = Dense::<f64>::anonymous_from_fn(3, 3, |i, j| rows[i as usize][j as usize]);
+        match cholesky_inplace(&mut a, 2) {
+            Err(OoclaError::NotPositiveDefinite { at }) => assert_eq!(at, 1),
+            other => panic!("expected an indefinite matrix, got {:?}", other),
+        }
+        let mut a = Dense::<f64>::anonymous_from_fn(2, 2, |i, j| if i == j { -1.0 } else { 0.0 });
+        match cholesky_inplace(&mut a, 8) {
+            Err(OoclaError::NotPositiveDefinite { at }) => assert_eq!(at, 0),
+            other => panic!("expected an indefinite matrix, got {:?}", other),
+        }
+    }
+}
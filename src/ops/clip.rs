@@ -0,0 +1,86 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+#[cfg(all(feature = "numa", feature = "rayon"))]
+use numa::NodePinner;
+use ops::quantile::quantiles;
+use ops::simd;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::ops::Add;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClipCounts {
+    pub clamped: u64,
+    pub nans: u64,
+}
+
+impl Add for ClipCounts {
+    type Output = ClipCounts;
+
+    fn add(self, other: ClipCounts) -> ClipCounts {
+        ClipCounts {
+            clamped: self.clamped + other.clamped,
+            nans: self.nans + other.nans,
+        }
+    }
+}
+
+fn clip_run<T: SupportedType>(run: &mut [T], lo: T, hi: T) -> ClipCounts {
+    let (clamped, nans) = simd::clip(run, lo, hi);
+    ClipCounts { clamped, nans }
+}
+
+fn check_bounds<T: SupportedType>(lo: T, hi: T) -> Result<(), OoclaError> {
+    let (lo, hi) = (lo.to_f64(), hi.to_f64());
+    if lo.is_nan() || hi.is_nan() || lo > hi {
+        return Err(OoclaError::InvalidArgument(format!("invalid clipping range [{}, {}]", lo, hi)));
+    }
+    Ok(())
+}
+
+// NaN elements are left as they are and counted separately from those that were clamped.
+pub fn clip<T: SupportedType>(a: &mut Dense<T>, lo: T, hi: T) -> Result<ClipCounts, OoclaError> {
+    check_bounds(lo, hi)?;
+    let (lda, minor) = (a.lda() as usize, a.minor_len() as usize);
+    if lda == 0 {
+        return Ok(ClipCounts::default());
+    }
+    Ok(a.storage_mut()
+        .chunks_mut(lda)
+        .map(|line| clip_run(&mut line[..minor], lo, hi))
+        .fold(ClipCounts::default(), Add::add))
+}
+
+#[cfg(feature = "rayon")]
+pub fn par_clip<T: SupportedType + Send + Sync>(a: &mut Dense<T>, lo: T, hi: T) -> Result<ClipCounts, OoclaError> {
+    check_bounds(lo, hi)?;
+    let (lda, minor) = (a.lda() as usize, a.minor_len() as usize);
+    if lda == 0 {
+        return Ok(ClipCounts::default());
+    }
+    #[cfg(feature = "numa")]
+    let pinner = NodePinner::new(a.storage(), a.stream_options().pin_numa_workers);
+    Ok(a.storage_mut()
+        .par_chunks_mut(lda)
+        .map(|line| {
+            #[cfg(feature = "numa")]
+            pinner.pin(line);
+            clip_run(&mut line[..minor], lo, hi)
+        })
+        .reduce(ClipCounts::default, Add::add))
+}
+
+// Clips to approximate quantiles estimated with a streaming sketch in a first pass.
+pub fn winsorize<T: SupportedType>(a: &mut Dense<T>, lower_quantile: f64, upper_quantile: f64)
+    -> Result<ClipCounts, OoclaError> {
+    if !(0.0..=1.0).contains(&lower_quantile) || !(0.0..=1.0).contains(&upper_quantile)
+        || lower_quantile > upper_quantile {
+        return Err(OoclaError::InvalidArgument(format!("invalid quantile range [{}, {}]", lower_quantile, upper_quantile)));
+    }
+    let bounds = quantiles(a, &[lower_quantile, upper_quantile]);
+    if bounds[0].is_nan() {
+        // Nothing but NaNs, which clipping leaves alone anyway.
+        return clip(a, T::from_f64(0.0), T::from_f64(0.0));
+    }
+    clip(a, T::from_f64(bounds[0]), T::from_f64(bounds[1]))
+}
@@ -0,0 +1,109 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use std::path::Path;
+
+fn check_columns<T>(a: &Dense<T>, cols: &[u64]) -> Result<(), OoclaError> {
+    match cols.iter().find(|&&c| c >= a.num_cols()) {
+        Some(c) => Err(OoclaError::InvalidArgument(format!("column {} out of range for a matrix with {} columns",
+                                                           c, a.num_cols()))),
+        None => Ok(()),
+    }
+}
+
+// Writes a matrix made of the named columns of A, in the order given, to `dst`. Columns may be
+// repeated. Each output row is gathered from the storage through a table of the selected
+// columns' offsets within a row, so only the selected elements are read in either layout.
+pub fn select_columns<T: StorageType>(a: &Dense<T>, dst: &Path, cols: &[u64]) -> Result<Dense<T>, OoclaError> {
+    check_columns(a, cols)?;
+    let n = a.num_rows();
+    let mut result = Dense::create(dst, n, cols.len() as u64)?;
+    if n == 0 || cols.is_empty() {
+        return Ok(result);
+    }
+    let (row_stride, col_stride) = if a.is_transposed() { (1, a.lda()) } else { (a.lda(), 1) };
+    let offsets: Vec<usize> = cols.iter().map(|&c| (c * col_stride) as usize).collect();
+    let storage = a.storage();
+    let mut out = vec![T::default(); cols.len()];
+    for i in 0..n {
+        let base = &storage[(i * row_stride) as usize..];
+        for (value, &offset) in out.iter_mut().zip(offsets.iter()) {
+            *value = base[offset];
+        }
+        result.write_row(i, &out);
+    }
+    Ok(result)
+}
+
+// Writes A without the named columns to `dst`, keeping the order of the rest.
+pub fn drop_columns<T: StorageType>(a: &Dense<T>, dst: &Path, cols: &[u64]) -> Result<Dense<T>, OoclaError> {
+    check_columns(a, cols)?;
+    let mut dropped = vec![false; a.num_cols() as usize];
+    for &c in cols.iter() {
+        dropped[c as usize] = true;
+    }
+    let kept: Vec<u64> = (0..a.num_cols()).filter(|&c| !dropped[c as usize]).collect();
+    select_columns(a, dst, &kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // A 5 x 4 matrix with element (i, j) = 10i + j, stored column-major when `transposed` is set.
+    fn indexed(transposed: bool) -> Dense<f64> {
+        if transposed {
+            let mut a = Dense::anonymous_from_fn(4, 5, |j, i| (10 * i + j) as f64);
+            a.transpose();
+            a
+        } else {
+            Dense::anonymous_from_fn(5, 4, |i, j| (10 * i + j) as f64)
+        }
+    }
+
+    fn elements(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    #[test]
+    fn selecting_every_column_in_order_equals_a_copy() {
+        for &transposed in &[false, true] {
+            let a = indexed(transposed);
+            let dst = TempMatrixPath::new();
+            let selected = select_columns(&a, dst.path(), &[0, 1, 2, 3]).unwrap();
+            let copy = a.copy_at(None).unwrap();
+            assert_eq!(elements(&selected), elements(&copy), "transposed {}", transposed);
+        }
+    }
+
+    #[test]
+    fn columns_are_gathered_by_logical_index_in_the_order_given() {
+        for &transposed in &[false, true] {
+            let a = indexed(transposed);
+            let dst = TempMatrixPath::new();
+            let selected = select_columns(&a, dst.path(), &[3, 1, 1]).unwrap();
+            assert_eq!((selected.num_rows(), selected.num_cols()), (5, 3));
+            for (i, row) in elements(&selected).into_iter().enumerate() {
+                let base = 10.0 * i as f64;
+                assert_eq!(row, vec![base + 3.0, base + 1.0, base + 1.0], "transposed {}", transposed);
+            }
+            let dst = TempMatrixPath::new();
+            let kept = drop_columns(&a, dst.path(), &[2, 0, 2]).unwrap();
+            for (i, row) in elements(&kept).into_iter().enumerate() {
+                let base = 10.0 * i as f64;
+                assert_eq!(row, vec![base + 1.0, base + 3.0], "transposed {}", transposed);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_selections_and_out_of_range_columns() {
+        let a = indexed(false);
+        let dst = TempMatrixPath::new();
+        let selected = select_columns(&a, dst.path(), &[]).unwrap();
+        assert_eq!((selected.num_rows(), selected.num_cols()), (5, 0));
+        let dst = TempMatrixPath::new();
+        assert!(select_columns(&a, dst.path(), &[0, 4]).is_err());
+        assert!(drop_columns(&a, dst.path(), &[4]).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use dense_matrix::{Dense, StorageType, SupportedType};
+use error::OoclaError;
+use std::path::Path;
+
+pub fn kron<T: SupportedType>(a: &Dense<T>, b: &Dense<T>, dst: &Path) -> Result<Dense<T>, OoclaError> {
+    let (ra, ca) = (a.num_rows(), a.num_cols());
+    let (rb, cb) = (b.num_rows(), b.num_cols());
+    let rows = ra.checked_mul(rb)
+        .ok_or_else(|| OoclaError::SizeOverflow(format!("{} * {} rows in Kronecker product", ra, rb)))?;
+    let cols = ca.checked_mul(cb)
+        .ok_or_else(|| OoclaError::SizeOverflow(format!("{} * {} columns in Kronecker product", ca, cb)))?;
+    let mut result = Dense::create(dst, rows, cols)?;
+    if cols == 0 {
+        return Ok(result);
+    }
+
+    // Destination rows are produced in order so the writes stay sequential.
+    let mut a_row = vec![T::from_f64(0.0); ca as usize];
+    let mut b_row = vec![T::from_f64(0.0); cb as usize];
+    let mut out_row = vec![T::from_f64(0.0); cols as usize];
+    for ia in 0..ra {
+        a.read_row(ia, &mut a_row);
+        for ib in 0..rb {
+            b.read_row(ib, &mut b_row);
+            for (block, &scale) in out_row.chunks_mut(cb as usize).zip(a_row.iter()) {
+                let scale = scale.to_f64();
+                for (out, &v) in block.iter_mut().zip(b_row.iter()) {
+                    *out = T::from_f64(scale * v.to_f64());
+                }
+            }
+            result.write_row(ia * rb + ib, &out_row);
+        }
+    }
+    Ok(result)
+}
+
+fn check_parts<T>(parts: &[&Dense<T>]) -> Result<(), OoclaError> {
+    if parts.is_empty() {
+        return Err(OoclaError::InvalidArgument("nothing to concatenate".to_string()));
+    }
+    Ok(())
+}
+
+// Stacks the parts top to bottom, which must all have the same number of columns.
+pub fn vconcat<T: StorageType>(parts: &[&Dense<T>], dst: &Path) -> Result<Dense<T>, OoclaError> {
+    check_parts(parts)?;
+    let cols = parts[0].num_cols();
+    let mut rows: u64 = 0;
+    for part in parts.iter() {
+        if part.num_cols() != cols {
+            return Err(OoclaError::ShapeMismatch {
+                expected: (part.num_rows(), cols),
+                found: (part.num_rows(), part.num_cols()),
+            });
+        }
+        rows = rows.checked_add(part.num_rows())
+            .ok_or_else(|| OoclaError::SizeOverflow("total rows of vertical concatenation".to_string()))?;
+    }
+    let mut result = Dense::create(dst, rows, cols)?;
+    let mut scratch = vec![T::default(); cols as usize];
+    let mut next = 0;
+    for part in parts.iter() {
+        for i in 0..part.num_rows() {
+            result.write_row(next, part.row_view(i, &mut scratch));
+            next += 1;
+        }
+    }
+    Ok(result)
+}
+
+// Places the parts side by side, which must all have the same number of rows. Output rows are
+// assembled from the matching row of every part in turn, so the writes stay sequential.
+pub fn hconcat<T: StorageType>(parts: &[&Dense<T>], dst: &Path) -> Result<Dense<T>, OoclaError> {
+    check_parts(parts)?;
+    let rows = parts[0].num_rows();
+    let mut cols: u64 = 0;
+    for part in parts.iter() {
+        if part.num_rows() != rows {
+            return Err(OoclaError::ShapeMismatch {
+                expected: (rows, part.num_cols()),
+                found: (part.num_rows(), part.num_cols()),
+            });
+        }
+        cols = cols.checked_add(part.num_cols())
+            .ok_or_else(|| OoclaError::SizeOverflow("total columns of horizontal concatenation".to_string()))?;
+    }
+    let mut result = Dense::create(dst, rows, cols)?;
+    let mut out_row = vec![T::default(); cols as usize];
+    for i in 0..rows {
+        let mut start = 0;
+        for part in parts.iter() {
+            let width = part.num_cols() as usize;
+            part.read_row(i, &mut out_row[start..start + width]);
+            start += width;
+        }
+        result.write_row(i, &out_row);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    #[test]
+    fn kron_matches_a_hand_computed_product() {
+        let a = Dense::<f64>::anonymous_from_fn(2, 2, |i, j| [[1.0, 2.0], [3.0, 0.0]][i as usize][j as usize]);
+        let b = Dense::<f64>::anonymous_from_fn(2, 3, |i, j| [[0.0, 5.0, 1.0], [6.0, 7.0, -1.0]][i as usize][j as usize]);
+        let dst = TempMatrixPath::new();
+        let c = kron(&a, &b, dst.path()).unwrap();
+        let expected = [
+            [0.0, 5.0, 1.0, 0.0, 10.0, 2.0],
+            [6.0, 7.0, -1.0, 12.0, 14.0, -2.0],
+            [0.0, 15.0, 3.0, 0.0, 0.0, 0.0],
+            [18.0, 21.0, -3.0, 0.0, 0.0, 0.0],
+        ];
+        assert_eq!((c.num_rows(), c.num_cols()), (4, 6));
+        for (i, row) in expected.iter().enumerate() {
+            for (j, &value) in row.iter().enumerate() {
+                assert_eq!(c.get(i as u64, j as u64), value, "({}, {})", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn kron_with_a_transposed_operand_uses_logical_layout() {
+        let mut a = Dense::<f32>::anonymous_from_fn(1, 2, |_, j| (j + 1) as f32);
+        a.transpose();
+        let b = Dense::<f32>::anonymous_from_fn(1, 2, |_, j| (j + 3) as f32);
+        let dst = TempMatrixPath::new();
+        let c = kron(&a, &b, dst.path()).unwrap();
+        assert_eq!((c.num_rows(), c.num_cols()), (2, 2));
+        assert_eq!([c.get(0, 0), c.get(0, 1), c.get(1, 0), c.get(1, 1)], [3.0, 4.0, 6.0, 8.0]);
+    }
+
+    #[test]
+    fn kron_rejects_overflowing_shapes_before_creating_the_file() {
+        let a = Dense::<f32>::create_anonymous(1 << 33, 0).unwrap();
+        let b = Dense::<f32>::create_anonymous(1 << 31, 0).unwrap();
+        let dst = TempMatrixPath::new();
+        match kron(&a, &b, dst.path()) {
+            Err(OoclaError::SizeOverflow(_)) => {}
+            other => panic!("expected a size overflow, got {:?}", other),
+        }
+        assert!(!dst.path().exists());
+    }
+
+    // The value of element (i, j) of the p-th part.
+    fn part_value(p: u64, i: u64, j: u64) -> f32 {
+        (100 * p + 10 * i + j) as f32
+    }
+
+    // A rows x cols part with elements part_value(p, ..), stored column-major when `transposed`
+    // is set.
+    fn part(p: u64, rows: u64, cols: u64, transposed: bool) -> Dense<f32> {
+        if transposed {
+            let mut a = Dense::anonymous_from_fn(cols, rows, |j, i| part_value(p, i, j));
+            a.transpose();
+            a
+        } else {
+            Dense::anonymous_from_fn(rows, cols, |i, j| part_value(p, i, j))
+        }
+    }
+
+    fn elements(a: &Dense<f32>) -> Vec<Vec<f32>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    #[test]
+    fn vconcat_of_odd_shapes_matches_stacking_in_memory() {
+        let parts = [part(0, 3, 5, false), part(1, 1, 5, true), part(2, 7, 5, false)];
+        let mut expected = Vec::new();
+        for p in parts.iter() {
+            expected.extend(elements(p));
+        }
+        let dst = TempMatrixPath::new();
+        let c = vconcat(&[&parts[0], &parts[1], &parts[2]], dst.path()).unwrap();
+        assert_eq!((c.num_rows(), c.num_cols()), (11, 5));
+        assert_eq!(elements(&c), expected);
+    }
+
+    #[test]
+    fn hconcat_of_odd_shapes_matches_joining_in_memory() {
+        let parts = [part(0, 3, 2, true), part(1, 3, 5, false), part(2, 3, 1, true)];
+        let expected: Vec<Vec<f32>> = (0..3).map(|i| {
+            parts.iter().flat_map(|p| elements(p)[i].clone()).collect()
+        }).collect();
+        let dst = TempMatrixPath::new();
+        let c = hconcat(&[&parts[0], &parts[1], &parts[2]], dst.path()).unwrap();
+        assert_eq!((c.num_rows(), c.num_cols()), (3, 8));
+        assert_eq!(elements(&c), expected);
+    }
+
+    #[test]
+    fn concatenation_rejects_mismatched_and_missing_parts() {
+        let (a, b) = (part(0, 2, 3, false), part(1, 3, 2, false));
+        let dst = TempMatrixPath::new();
+        assert!(vconcat(&[&a, &b], dst.path()).is_err());
+        assert!(hconcat(&[&a, &b], dst.path()).is_err());
+        assert!(vconcat::<f32>(&[], dst.path()).is_err());
+        assert!(hconcat::<f32>(&[], dst.path()).is_err());
+        assert!(!dst.path().exists());
+    }
+
+    #[test]
+    fn concatenation_rejects_overflowing_shapes_before_creating_the_file() {
+        let tall = Dense::<f32>::create_anonymous(1 << 63, 0).unwrap();
+        let wide = Dense::<f32>::create_anonymous(0, 1 << 63).unwrap();
+        let dst = TempMatrixPath::new();
+        match vconcat(&[&tall, &tall], dst.path()) {
+            Err(OoclaError::SizeOverflow(_)) => {}
+            other => panic!("expected a size overflow, got {:?}", other),
+        }
+        match hconcat(&[&wide, &wide], dst.path()) {
+            Err(OoclaError::SizeOverflow(_)) => {}
+            other => panic!("expected a size overflow, got {:?}", other),
+        }
+        assert!(!dst.path().exists());
+    }
+}
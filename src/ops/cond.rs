@@ -0,0 +1,173 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::lu::LuFactors;
+use ops::reduce::{NormKind, column_norms};
+use std::f64;
+
+const HAGER_MAX_ITERS: usize = 5;
+
+fn l1(x: &[f64]) -> f64 {
+    x.iter().map(|v| v.abs()).sum()
+}
+
+// Hager's estimate of |B|_1, refined as in Higham's LAPACK xLACON, given solves applying B and
+// B^T in place.
+fn estimate_inverse_norm<F, G>(n: usize, mut apply: F, mut apply_transposed: G) -> Result<f64, OoclaError>
+    where F: FnMut(&mut [f64]) -> Result<(), OoclaError>, G: FnMut(&mut [f64]) -> Result<(), OoclaError> {
+    let mut x = vec![1.0 / n as f64; n];
+    let mut estimate = 0.0;
+    for iter in 0..HAGER_MAX_ITERS {
+        let mut y = x.clone();
+        apply(&mut y)?;
+        let norm = l1(&y);
+        if iter > 0 && norm <= estimate {
+            break;
+        }
+        estimate = norm;
+        let mut z: Vec<f64> = y.iter().map(|&v| if v >= 0.0 { 1.0 } else { -1.0 }).collect();
+        apply_transposed(&mut z)?;
+        let (j, zj) = z.iter().enumerate().fold((0, 0.0f64), |best, (i, &v)| if v.abs() > best.1 { (i, v.abs()) } else { best });
+        let ztx: f64 = z.iter().zip(x.iter()).map(|(a, b)| a * b).sum();
+        if iter > 0 && zj <= ztx {
+            break;
+        }
+        for v in x.iter_mut() {
+            *v = 0.0;
+        }
+        x[j] = 1.0;
+    }
+    // An alternating test vector catches matrices on which the iteration above stalls.
+    let mut w: Vec<f64> = (0..n).map(|i| {
+        let magnitude = 1.0 + if n > 1 { i as f64 / (n - 1) as f64 } else { 0.0 };
+        if i % 2 == 0 { magnitude } else { -magnitude }
+    }).collect();
+    apply(&mut w)?;
+    Ok(estimate.max(2.0 * l1(&w) / (3.0 * n as f64)))
+}
+
+fn check_norm(norm: NormKind) -> Result<(), OoclaError> {
+    match norm {
+        NormKind::L2 => Err(OoclaError::InvalidArgument("condition estimation supports the 1- and infinity-norms only"
+                                                         .to_string())),
+        _ => Ok(()),
+    }
+}
+
+fn matrix_norm(a: &Dense<f64>, norm: NormKind) -> f64 {
+    match norm {
+        NormKind::Max => {
+            let mut row = vec![0.0; a.num_cols() as usize];
+            (0..a.num_rows()).fold(0.0, |acc, i| {
+                a.read_row(i, &mut row);
+                acc.max(l1(&row))
+            })
+        }
+        _ => column_norms(a, NormKind::L1).into_iter().fold(0.0, f64::max),
+    }
+}
+
+// Estimates cond(A) = |A|·|A^-1| in the 1-norm (NormKind::L1) or infinity-norm
+// (NormKind::Max) using the factors of A, costing a handful of solves rather than forming
+// A^-1. The estimate never exceeds the true condition number and is usually within a small
+// factor of it.
+pub fn cond_estimate_factored(a: &Dense<f64>, factors: &LuFactors, norm: NormKind) -> Result<f64, OoclaError> {
+    if factors.order() != a.num_rows() || a.num_rows() != a.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (factors.order(), factors.order()),
+            found: (a.num_rows(), a.num_cols()),
+        });
+    }
+    check_norm(norm)?;
+    let a_norm = matrix_norm(a, norm);
+    let n = a.num_rows() as usize;
+    if n == 0 {
+        return Ok(0.0);
+    }
+    // |A^-1|_inf = |A^-T|_1, so the infinity-norm swaps the roles of the two solves.
+    let inverse_norm = match norm {
+        NormKind::Max => estimate_inverse_norm(n, |x| factors.solve_transposed(x), |x| factors.solve(x))?,
+        _ => estimate_inverse_norm(n, |x| factors.solve(x), |x| factors.solve_transposed(x))?,
+    };
+    Ok(a_norm * inverse_norm)
+}
+
+// As cond_estimate_factored, factoring a copy of A first. Matrices that are singular to
+// working precision give infinity.
+pub fn cond_estimate(a: &Dense<f64>, norm: NormKind) -> Result<f64, OoclaError> {
+    check_norm(norm)?;
+    match LuFactors::new(a) {
+        Ok(factors) => cond_estimate_factored(a, &factors, norm),
+        Err(OoclaError::Singular { .. }) => Ok(f64::INFINITY),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use ops::lu::inverse;
+
+    fn exact_cond(a: &Dense<f64>, norm: NormKind) -> f64 {
+        let dst = TempMatrixPath::new();
+        let inv = inverse(a, dst.path()).unwrap();
+        matrix_norm(a, norm) * matrix_norm(&inv, norm)
+    }
+
+    fn hilbert(n: u64) -> Dense<f64> {
+        Dense::anonymous_from_fn(n, n, |i, j| 1.0 / (i + j + 1) as f64)
+    }
+
+    #[test]
+    fn estimates_bound_the_exact_condition_number_from_below() {
+        let mut matrices = vec![hilbert(5)];
+        for seed in 0..6 {
+            let n = 4 + seed * 2;
+            let mut a = Dense::<f64>::create_anonymous(n, n).unwrap();
+            a.randomise_seeded(seed);
+            matrices.push(a);
+        }
+        for a in matrices.iter() {
+            for &norm in &[NormKind::L1, NormKind::Max] {
+                let exact = exact_cond(a, norm);
+                let estimate = cond_estimate(a, norm).unwrap();
+                assert!(estimate <= exact * (1.0 + 1e-9), "{:?}: {} > {}", norm, estimate, exact);
+                assert!(estimate >= exact / 3.0, "{:?}: {} vs {}", norm, estimate, exact);
+            }
+        }
+    }
+
+    #[test]
+    fn hilbert_condition_numbers_match_known_values() {
+        // cond_1 of the 4x4 Hilbert matrix is 28375, which is also its infinity-norm condition
+        // number since the matrix is symmetric.
+        let a = hilbert(4);
+        for &norm in &[NormKind::L1, NormKind::Max] {
+            let estimate = cond_estimate(&a, norm).unwrap();
+            assert!((estimate - 28375.0).abs() < 1e-6 * 28375.0, "{:?}: {}", norm, estimate);
+        }
+    }
+
+    #[test]
+    fn the_norms_differ_for_a_non_symmetric_matrix() {
+        // A and its inverse, with -1s in place of the 1s above the diagonal, have column sums
+        // of at most 2 and row sums of at most 3, so cond_1 = 4 and cond_inf = 9.
+        let rows = [[1.0, 1.0, 1.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        let a = Dense::<f64>::anonymous_from_fn(3, 3, |i, j| rows[i as usize][j as usize]);
+        assert!((exact_cond(&a, NormKind::L1) - 4.0).abs() < 1e-12);
+        assert!((exact_cond(&a, NormKind::Max) - 9.0).abs() < 1e-12);
+        assert!((cond_estimate(&a, NormKind::L1).unwrap() - 4.0).abs() < 1e-12);
+        assert!((cond_estimate(&a, NormKind::Max).unwrap() - 9.0).abs() < 1e-12);
+        let diagonal = Dense::<f64>::anonymous_from_fn(3, 3, |i, j| if i == j { [2.0, 0.5, 8.0][i as usize] } else { 0.0 });
+        assert!((cond_estimate(&diagonal, NormKind::Max).unwrap() - 16.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn singular_and_unsupported_cases() {
+        let singular = Dense::<f64>::anonymous_from_fn(2, 2, |_, j| (j + 1) as f64);
+        assert_eq!(cond_estimate(&singular, NormKind::L1).unwrap(), f64::INFINITY);
+        assert!(cond_estimate(&hilbert(3), NormKind::L2).is_err());
+        let factors = LuFactors::new(&hilbert(3)).unwrap();
+        assert!(cond_estimate_factored(&hilbert(4), &factors, NormKind::L1).is_err());
+    }
+}
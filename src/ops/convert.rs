@@ -0,0 +1,101 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use sparse::CsrMatrix;
+use std::path::Path;
+
+// An element is stored when |x| > |threshold|, so both +0.0 and -0.0 are always dropped,
+// while NaNs, which are not zero, are always kept.
+fn is_kept(x: f64, threshold: f64) -> bool {
+    x.is_nan() || x.abs() > threshold
+}
+
+// Makes one pass to count the stored entries so the file can be sized exactly, then a
+// second to fill it in order.
+pub fn to_csr<T: SupportedType>(a: &Dense<T>, dst: &Path, threshold: T) -> Result<CsrMatrix<T>, OoclaError> {
+    let threshold = threshold.to_f64().abs();
+    let mut nnz = 0;
+    a.stream_rows(0..a.num_rows(), |_, row| {
+        nnz += row.iter().filter(|x| is_kept(x.to_f64(), threshold)).count() as u64;
+        Ok(())
+    })?;
+
+    let mut result = CsrMatrix::create(dst, a.num_rows(), a.num_cols(), nnz)?;
+    {
+        let (row_ptr, col_idx, values) = result.parts_mut();
+        let mut k = 0;
+        row_ptr[0] = 0;
+        a.stream_rows(0..a.num_rows(), |r, row| {
+            for (c, &x) in row.iter().enumerate() {
+                if is_kept(x.to_f64(), threshold) {
+                    col_idx[k] = c as u64;
+                    values[k] = x;
+                    k += 1;
+                }
+            }
+            row_ptr[r as usize + 1] = k as u64;
+            Ok(())
+        })?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    #[test]
+    fn round_trip_keeps_exactly_the_elements_above_the_threshold() {
+        let source = Dense::<f64>::anonymous_from_fn(9, 7, |i, j| ((i * 7 + j) % 11) as f64 * 0.5 - 2.5);
+        for &threshold in &[0.0, 0.5, 1.25, -1.25, 10.0] {
+            let csr_path = TempMatrixPath::new();
+            let dense_path = TempMatrixPath::new();
+            let csr = to_csr(&source, csr_path.path(), threshold).unwrap();
+            let back = csr.to_dense(dense_path.path()).unwrap();
+            let mut kept = 0;
+            for i in 0..9 {
+                for j in 0..7 {
+                    let x = source.get(i, j);
+                    let expected = if x.abs() > threshold.abs() {
+                        kept += 1;
+                        x
+                    } else {
+                        0.0
+                    };
+                    assert_eq!(back.get(i, j), expected, "threshold {} at ({}, {})", threshold, i, j);
+                }
+            }
+            assert_eq!(csr.nnz(), kept);
+            assert_eq!(csr.density(), kept as f64 / 63.0);
+        }
+    }
+
+    #[test]
+    fn signed_zeros_are_dropped_and_nans_kept() {
+        let values = [0.0, -0.0, f32::NAN, 1.0];
+        let source = Dense::<f32>::anonymous_from_fn(1, 4, |_, j| values[j as usize]);
+        let csr_path = TempMatrixPath::new();
+        let dense_path = TempMatrixPath::new();
+        let csr = to_csr(&source, csr_path.path(), 0.0).unwrap();
+        assert_eq!(csr.nnz(), 2);
+        let back = csr.to_dense(dense_path.path()).unwrap();
+        assert!(back.get(0, 2).is_nan());
+        assert_eq!(back.get(0, 3), 1.0);
+        assert!(back.get(0, 1).is_sign_positive());
+    }
+
+    #[test]
+    fn round_trip_of_a_transposed_matrix() {
+        let mut source = Dense::<f64>::anonymous_from_fn(3, 5, |i, j| if (i + j) % 2 == 0 { (i + 1) as f64 } else { 0.0 });
+        source.transpose();
+        let csr_path = TempMatrixPath::new();
+        let dense_path = TempMatrixPath::new();
+        let back = to_csr(&source, csr_path.path(), 0.0).unwrap().to_dense(dense_path.path()).unwrap();
+        assert_eq!((back.num_rows(), back.num_cols()), (5, 3));
+        for i in 0..5 {
+            for j in 0..3 {
+                assert_eq!(back.get(i, j), source.get(i, j));
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use dense_matrix::{Dense, StorageType, as_bytes};
+use dense_vector::DenseVector;
+use error::OoclaError;
+use ops::hash::xxh64;
+use row_writer::RowWriter;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub struct DedupReport {
+    pub kept: u64,
+    pub removed: u64,
+    // For each row of the source, the index of the first row identical to it (itself if it was
+    // kept).
+    pub first_occurrence_map: DenseVector<u64>,
+}
+
+// Writes the rows of A to `dst` with exact duplicates removed, keeping the first occurrence of
+// each in its original position. Rows are equal when their bytes are, so rows with NaNs in the
+// same places and bit patterns match, and 0 and -0 differ. Only a hash and an index per
+// distinct row is held in memory: a row whose hash has been seen is compared byte for byte with
+// the earlier rows carrying that hash, so collisions cannot merge distinct rows.
+pub fn dedup_rows<T: StorageType>(a: &Dense<T>, dst: &Path) -> Result<(Dense<T>, DedupReport), OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols() as usize);
+    let mut writer = RowWriter::create(dst, d as u64)?;
+    let mut first_occurrence_map = DenseVector::create_anonymous(n)?;
+    let mut seen: HashMap<u64, Vec<u64>> = HashMap::new();
+    let (mut scratch, mut candidate) = (vec![T::default(); d], vec![T::default(); d]);
+    for i in 0..n {
+        let row = a.row_view(i, &mut scratch);
+        let bytes = as_bytes(row);
+        let firsts = seen.entry(xxh64(bytes, 0)).or_default();
+        let duplicate_of = firsts.iter().cloned().find(|&j| as_bytes(a.row_view(j, &mut candidate)) == bytes);
+        match duplicate_of {
+            Some(j) => first_occurrence_map.set(i, j),
+            None => {
+                firsts.push(i);
+                first_occurrence_map.set(i, i);
+                writer.write_row(row)?;
+            }
+        }
+    }
+    let kept = writer.rows_written();
+    Ok((writer.finish()?, DedupReport {
+        kept,
+        removed: n - kept,
+        first_occurrence_map,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use std::f64;
+
+    fn elements(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    // Keeps the first of each group of rows with equal bits, as dedup_rows should.
+    fn reference(rows: &[Vec<f64>]) -> (Vec<Vec<f64>>, Vec<u64>) {
+        let bits = |row: &Vec<f64>| row.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+        let mut kept = Vec::new();
+        let firsts = rows.iter().enumerate().map(|(i, row)| {
+            match rows[..i].iter().position(|earlier| bits(earlier) == bits(row)) {
+                Some(j) => j as u64,
+                None => {
+                    kept.push(row.clone());
+                    i as u64
+                }
+            }
+        }).collect();
+        (kept, firsts)
+    }
+
+    fn matrix(rows: &[Vec<f64>], transposed: bool) -> Dense<f64> {
+        let (n, d) = (rows.len() as u64, rows[0].len() as u64);
+        if transposed {
+            let mut a = Dense::anonymous_from_fn(d, n, |j, i| rows[i as usize][j as usize]);
+            a.transpose();
+            a
+        } else {
+            Dense::anonymous_from_fn(n, d, |i, j| rows[i as usize][j as usize])
+        }
+    }
+
+    #[test]
+    fn duplicates_at_the_start_middle_and_end_are_removed() {
+        let mut rows: Vec<Vec<f64>> = (0..20).map(|i| vec![i as f64, 0.5 * i as f64, -1.0]).collect();
+        rows[1] = rows[0].clone();
+        rows[10] = rows[3].clone();
+        rows[11] = rows[3].clone();
+        rows[18] = rows[0].clone();
+        rows[19] = rows[0].clone();
+        let (expected, firsts) = reference(&rows);
+        assert_eq!(expected.len(), 15);
+        for &transposed in &[false, true] {
+            let dst = TempMatrixPath::new();
+            let (deduped, report) = dedup_rows(&matrix(&rows, transposed), dst.path()).unwrap();
+            assert_eq!(elements(&deduped), expected, "transposed {}", transposed);
+            assert_eq!((report.kept, report.removed), (15, 5));
+            assert_eq!(report.first_occurrence_map.as_slice(), &firsts[..]);
+        }
+    }
+
+    #[test]
+    fn rows_compare_by_bit_pattern() {
+        let rows = vec![
+            vec![f64::NAN, 1.0],
+            vec![0.0, 1.0],
+            vec![f64::NAN, 1.0],
+            vec![-0.0, 1.0],
+            vec![0.0, 1.0],
+        ];
+        let dst = TempMatrixPath::new();
+        let (deduped, report) = dedup_rows(&matrix(&rows, false), dst.path()).unwrap();
+        assert_eq!(report.first_occurrence_map.as_slice(), &[0, 1, 0, 3, 1]);
+        assert_eq!(deduped.num_rows(), 3);
+        assert!(deduped.get(0, 0).is_nan());
+        assert_eq!(deduped.get(2, 0).to_bits(), (-0.0f64).to_bits());
+    }
+
+    #[test]
+    fn a_matrix_without_duplicates_is_unchanged() {
+        let rows: Vec<Vec<f64>> = (0..50).map(|i| vec![(i % 7) as f64, (i / 7) as f64]).collect();
+        let dst = TempMatrixPath::new();
+        let (deduped, report) = dedup_rows(&matrix(&rows, false), dst.path()).unwrap();
+        assert_eq!(elements(&deduped), rows);
+        assert_eq!((report.kept, report.removed), (50, 0));
+    }
+}
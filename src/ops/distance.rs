@@ -0,0 +1,215 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use ops::blas::tile_gemm_acc;
+use ops::simd;
+use std::cmp;
+use std::path::Path;
+use tile::{DEFAULT_TILE_SIZE, Tile};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    Euclidean,
+    SquaredEuclidean,
+}
+
+impl Metric {
+    pub(crate) fn apply_to_squared(self, squared: f64) -> f64 {
+        match self {
+            Metric::Euclidean => squared.sqrt(),
+            Metric::SquaredEuclidean => squared,
+        }
+    }
+}
+
+pub(crate) fn row_squared_norms<T: SupportedType>(a: &Dense<T>) -> Vec<f64> {
+    row_squared_norms_range(a, 0, a.num_rows() as usize)
+}
+
+pub(crate) fn row_squared_norms_range<T: SupportedType>(a: &Dense<T>, start: u64, count: usize) -> Vec<f64> {
+    let mut row = vec![T::from_f64(0.0); a.num_cols() as usize];
+    let deterministic = a.stream_options().deterministic;
+    (start..start + count as u64).map(|i| {
+        a.read_row(i, &mut row);
+        simd::dot(&row, &row, deterministic)
+    }).collect()
+}
+
+// Squared Euclidean distances between rows [i0, i0 + rows) of A and [j0, j0 + cols) of B as
+// a row-major rows x cols block, given the squared norms of just those rows. Computed as
+// |x|² + |y|² - 2·x·y with the cross terms from tile products over the shared dimension;
+// cancellation can make the expansion slightly negative, so results are clamped at zero.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn squared_distance_block<T: SupportedType>(a: &Dense<T>, a_norms: &[f64], i0: u64, rows: usize,
+                                                       b: &Dense<T>, b_norms: &[f64], j0: u64, cols: usize)
+    -> Vec<f64> {
+    let d = a.num_cols();
+    let mut acc = vec![0.0; rows * cols];
+    for k0 in (0..d).step_by(DEFAULT_TILE_SIZE) {
+        let depth = cmp::min(DEFAULT_TILE_SIZE as u64, d - k0) as usize;
+        let a_tile = a.read_tile(i0, k0, rows, depth);
+        let b_tile = b.read_tile(j0, k0, cols, depth).transposed();
+        tile_gemm_acc(-2.0, &a_tile, &b_tile, &mut acc);
+    }
+    for (r, row) in acc.chunks_mut(cmp::max(cols, 1)).enumerate() {
+        let x = a_norms[r];
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = (*value + x + b_norms[c]).max(0.0);
+        }
+    }
+    acc
+}
+
+// Writes the matrix of distances between the rows of A and the rows of B (or of A itself when
+// B is None) to `dst`, one tile at a time. Without B only the upper triangle of tiles is
+// computed, each mirrored into place, and the diagonal is exactly zero.
+pub fn pairwise_distances(a: &Dense<f32>, b: Option<&Dense<f32>>, dst: &Path, metric: Metric)
+    -> Result<Dense<f32>, OoclaError> {
+    if let Some(b) = b {
+        if b.num_cols() != a.num_cols() {
+            return Err(OoclaError::ShapeMismatch {
+                expected: (a.num_rows(), a.num_cols()),
+                found: (b.num_rows(), b.num_cols()),
+            });
+        }
+    }
+    let symmetric = b.is_none();
+    let b = b.unwrap_or(a);
+    let (n, m) = (a.num_rows(), b.num_rows());
+    let a_norms = row_squared_norms(a);
+    let b_norms = if symmetric { a_norms.clone() } else { row_squared_norms(b) };
+    let mut out = Dense::create(dst, n, m)?;
+    let step = DEFAULT_TILE_SIZE as u64;
+    for i0 in (0..n).step_by(DEFAULT_TILE_SIZE) {
+        let rows = cmp::min(step, n - i0) as usize;
+        let first_j = if symmetric { i0 } else { 0 };
+        for j0 in (first_j..m).step_by(DEFAULT_TILE_SIZE) {
+            let cols = cmp::min(step, m - j0) as usize;
+            let squared = squared_distance_block(a, &a_norms[i0 as usize..], i0, rows, b, &b_norms[j0 as usize..], j0, cols);
+            let mut tile = Tile {
+                row: i0,
+                col: j0,
+                rows,
+                cols,
+                data: squared.into_iter().map(|x| metric.apply_to_squared(x) as f32).collect(),
+            };
+            if symmetric && i0 == j0 {
+                for d in 0..rows {
+                    tile.data[d * cols + d] = 0.0;
+                }
+            }
+            out.write_tile(&tile);
+            if symmetric && i0 != j0 {
+                out.write_tile(&tile.transposed());
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{StreamOptions, TempMatrixPath};
+
+    fn rows_of(a: &Dense<f32>) -> Vec<Vec<f64>> {
+        a.row_iter().map(|row| row.iter().map(|&x| f64::from(x)).collect()).collect()
+    }
+
+    // Squared distances by summing over the shared dimension for every pair of rows.
+    fn naive_squared(a: &Dense<f32>, b: &Dense<f32>) -> Vec<Vec<f64>> {
+        let (a, b) = (rows_of(a), rows_of(b));
+        a.iter().map(|x| b.iter().map(|y| x.iter().zip(y).map(|(p, q)| (p - q) * (p - q)).sum()).collect()).collect()
+    }
+
+    fn element(i: u64, j: u64) -> f32 {
+        ((i * 31 + j * 17) % 97) as f32 / 8.0 - 6.0
+    }
+
+    fn check(a: &Dense<f32>, b: Option<&Dense<f32>>) {
+        let reference = naive_squared(a, b.unwrap_or(a));
+        for &metric in &[Metric::SquaredEuclidean, Metric::Euclidean] {
+            let dst = TempMatrixPath::new();
+            let result = pairwise_distances(a, b, dst.path(), metric).unwrap();
+            assert_eq!((result.num_rows() as usize, result.num_cols() as usize), (reference.len(), reference[0].len()));
+            for (i, (row, expected)) in result.row_iter().zip(&reference).enumerate() {
+                for (j, (&found, &squared)) in row.iter().zip(expected).enumerate() {
+                    let expected = metric.apply_to_squared(squared);
+                    let found = f64::from(found);
+                    assert!((found - expected).abs() <= 1e-4 * expected.max(1.0), "{:?} ({}, {}): {} against {}",
+                            metric, i, j, found, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn symmetric_distances_match_a_naive_reference_across_tiles() {
+        check(&Dense::anonymous_from_fn(DEFAULT_TILE_SIZE as u64 + 90, 9, element), None);
+    }
+
+    #[test]
+    fn distances_between_two_matrices_match_a_naive_reference_across_tiles() {
+        let a = Dense::anonymous_from_fn(DEFAULT_TILE_SIZE as u64 + 90, 9, element);
+        let b = Dense::anonymous_from_fn(DEFAULT_TILE_SIZE as u64 + 20, 9, |i, j| element(i + 3, j * 2));
+        check(&a, Some(&b));
+    }
+
+    #[test]
+    fn distances_match_a_naive_reference_over_several_column_tiles() {
+        let a = Dense::anonymous_from_fn(5, DEFAULT_TILE_SIZE as u64 + 18, element);
+        let b = Dense::anonymous_from_fn(3, DEFAULT_TILE_SIZE as u64 + 18, |i, j| element(i * 5 + 1, j));
+        check(&a, None);
+        check(&a, Some(&b));
+    }
+
+    // Rows far from the origin that differ by at most an ulp here and there. With the
+    // deterministic sums the norms and cross terms are accumulated in the same order, so
+    // |x|² + |y|² - 2·x·y comes out as rounding noise of either sign for such pairs.
+    fn near_equal_rows() -> Dense<f32> {
+        let mut a = Dense::anonymous_from_fn(60, 300, |i, j| {
+            let x = 1000.0 * (1.5 + (j as f32 * 0.7311).sin());
+            if (i + j) % 11 == 0 { x * (1.0 + f32::EPSILON) } else { x }
+        });
+        a.set_stream_options(StreamOptions { deterministic: true, ..StreamOptions::default() });
+        a
+    }
+
+    #[test]
+    fn the_diagonal_of_symmetric_distances_is_exactly_zero() {
+        let a = near_equal_rows();
+        let dst = TempMatrixPath::new();
+        let result = pairwise_distances(&a, None, dst.path(), Metric::Euclidean).unwrap();
+        assert!(result.diagonal_iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn near_equal_rows_are_clamped_to_zero() {
+        let (a, b) = (near_equal_rows(), near_equal_rows());
+        for &b in &[None, Some(&b)] {
+            for &metric in &[Metric::SquaredEuclidean, Metric::Euclidean] {
+                let dst = TempMatrixPath::new();
+                let result = pairwise_distances(&a, b, dst.path(), metric).unwrap();
+                // Pairs differ by an ulp, under 3e-4, in at most 56 places, so their squared
+                // distances are under 1e-5, and the expansion rounds to within 1e-6 of them.
+                let bound = metric.apply_to_squared(1e-4) as f32;
+                let outside = result.element_iter().find(|&&x| !(x >= 0.0 && x <= bound));
+                assert!(outside.is_none(), "{:?}, {}: {:?}", metric, b.is_some(), outside);
+                assert!(result.element_iter().any(|&x| x == 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn a_different_column_count_is_rejected() {
+        let a = Dense::<f32>::create_anonymous(4, 3).unwrap();
+        let b = Dense::<f32>::create_anonymous(5, 2).unwrap();
+        let dst = TempMatrixPath::new();
+        match pairwise_distances(&a, Some(&b), dst.path(), Metric::Euclidean) {
+            Err(OoclaError::ShapeMismatch { expected, found }) => {
+                assert_eq!(expected, (4, 3));
+                assert_eq!(found, (5, 2));
+            }
+            other => panic!("expected a shape mismatch, got {:?}", other.map(|_| ())),
+        }
+    }
+}
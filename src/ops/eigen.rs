@@ -0,0 +1,218 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::gemv;
+use ops::rng::{gaussian_vector, seeded_rng};
+
+fn dot(x: &[f64], y: &[f64]) -> f64 {
+    x.iter().zip(y.iter()).map(|(a, b)| a * b).sum()
+}
+
+fn normalize(x: &mut [f64]) -> f64 {
+    let norm = dot(x, x).sqrt();
+    if norm > 0.0 {
+        for v in x.iter_mut() {
+            *v /= norm;
+        }
+    }
+    norm
+}
+
+fn axpy(alpha: f64, x: &[f64], y: &mut [f64]) {
+    for (y, x) in y.iter_mut().zip(x.iter()) {
+        *y += alpha * x;
+    }
+}
+
+// A deflated eigenpair (lambda, u, w) contributing -lambda·u·w^T to the iteration matrix,
+// where w^T·u = 1.
+struct Deflation {
+    lambda: f64,
+    u: Vec<f64>,
+    w: Vec<f64>,
+}
+
+fn check_square(a: &Dense<f64>) -> Result<(), OoclaError> {
+    if a.num_rows() != a.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), a.num_rows()),
+            found: (a.num_rows(), a.num_cols()),
+        });
+    }
+    Ok(())
+}
+
+fn iterate(a: &Dense<f64>, deflations: &[Deflation], project: bool, max_iters: usize, tol: f64, seed: u64)
+    -> Result<(f64, Vec<f64>), OoclaError> {
+    let n = a.num_rows() as usize;
+    let mut rng = seeded_rng(seed);
+    let mut x = gaussian_vector(&mut rng, n);
+    let mut y = vec![0.0; n];
+    let reorthogonalize = |x: &mut [f64]| {
+        if project {
+            for d in deflations {
+                let c = dot(&d.u, x);
+                axpy(-c, &d.u, x);
+            }
+        }
+        normalize(x)
+    };
+    reorthogonalize(&mut x);
+    let mut previous = None;
+    let mut lambda = 0.0;
+    for _ in 0..max_iters {
+        gemv(1.0, a, &x, 0.0, &mut y)?;
+        for d in deflations {
+            let c = d.lambda * dot(&d.w, &x);
+            axpy(-c, &d.u, &mut y);
+        }
+        // x has unit norm, so this is the Rayleigh quotient.
+        lambda = dot(&x, &y);
+        let residual = x.iter().zip(y.iter()).map(|(x, y)| (y - lambda * x) * (y - lambda * x)).sum::<f64>().sqrt();
+        if reorthogonalize(&mut y) == 0.0 {
+            // x lies in the null space of the (deflated) matrix.
+            return Ok((0.0, x));
+        }
+        ::std::mem::swap(&mut x, &mut y);
+        if let Some(previous) = previous {
+            let change: f64 = lambda - previous;
+            // On its own the Rayleigh quotient can settle without x converging, e.g. when
+            // the dominant eigenvalues are ±λ, so the residual must be small as well.
+            if change.abs() <= tol * lambda.abs() && residual <= tol.sqrt() * lambda.abs() {
+                return Ok((lambda, x));
+            }
+        }
+        previous = Some(lambda);
+    }
+    Err(OoclaError::DidNotConverge { last_estimate: lambda, iters: max_iters })
+}
+
+// Estimates the eigenvalue of largest magnitude of a square matrix and a unit eigenvector,
+// with one gemv per iteration. Iteration stops once the Rayleigh quotient changes by at most
+// `tol` relative to its magnitude and the residual |A·x - λ·x| is within sqrt(tol)·|λ|;
+// dominant eigenvalues of equal magnitude but different value (such as ±λ) prevent this and
+// give DidNotConverge.
+pub fn power_iteration(a: &Dense<f64>, max_iters: usize, tol: f64, seed: u64) -> Result<(f64, Vec<f64>), OoclaError> {
+    check_square(a)?;
+    iterate(a, &[], false, max_iters, tol, seed)
+}
+
+// As power_iteration, but finds the dominant eigenpair remaining once the eigenpairs in
+// `found` (typically earlier results of this function, in order) are deflated away, so
+// repeated calls give the top-k pairs. With `symmetric` set, A is taken to be self-adjoint:
+// found eigenvectors are orthogonal, deflation is A - Σ λ·v·v^T and each iterate is kept
+// orthogonal to them. Otherwise Wielandt deflation is used and the returned vector is
+// mapped back to an eigenvector of A itself; this needs the found eigenvalues to be
+// non-zero and distinct from the one sought.
+pub fn power_iteration_deflated(a: &Dense<f64>, found: &[(f64, Vec<f64>)], max_iters: usize, tol: f64, seed: u64,
+                                symmetric: bool) -> Result<(f64, Vec<f64>), OoclaError> {
+    check_square(a)?;
+    let n = a.num_rows() as usize;
+    let mut deflations: Vec<Deflation> = Vec::with_capacity(found.len());
+    for &(lambda, ref v) in found {
+        if v.len() != n {
+            return Err(OoclaError::InvalidArgument(format!("eigenvector has length {} but matrix has order {}",
+                                                           v.len(), n)));
+        }
+        let mut u = v.clone();
+        if !symmetric {
+            if lambda == 0.0 {
+                return Err(OoclaError::InvalidArgument("cannot deflate a zero eigenvalue".to_string()));
+            }
+            // An eigenvector of A for lambda becomes one of each successively deflated matrix.
+            for d in deflations.iter() {
+                let c = d.lambda * dot(&d.w, &u) / lambda;
+                axpy(-c, &d.u, &mut u);
+            }
+        }
+        if normalize(&mut u) == 0.0 {
+            return Err(OoclaError::InvalidArgument("cannot deflate a zero eigenvector".to_string()));
+        }
+        let w = u.clone();
+        deflations.push(Deflation { lambda, u, w });
+    }
+    let (lambda, mut x) = iterate(a, &deflations, symmetric, max_iters, tol, seed)?;
+    if !symmetric {
+        for d in deflations.iter().rev() {
+            if lambda == d.lambda {
+                return Err(OoclaError::InvalidArgument(format!("eigenvalue {} was already deflated", lambda)));
+            }
+            let c = d.lambda * dot(&d.w, &x) / (lambda - d.lambda);
+            axpy(c, &d.u, &mut x);
+        }
+        normalize(&mut x);
+    }
+    Ok((lambda, x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_rows(rows: &[&[f64]]) -> Dense<f64> {
+        Dense::anonymous_from_fn(rows.len() as u64, rows[0].len() as u64, |i, j| rows[i as usize][j as usize])
+    }
+
+    fn diagonal(values: &[f64]) -> Dense<f64> {
+        let n = values.len() as u64;
+        Dense::anonymous_from_fn(n, n, |i, j| if i == j { values[i as usize] } else { 0.0 })
+    }
+
+    #[test]
+    fn finds_a_known_dominant_eigenpair() {
+        let a = from_rows(&[&[2.0, 1.0, 0.0], &[1.0, 2.0, 0.0], &[0.0, 0.0, 1.0]]);
+        let (lambda, x) = power_iteration(&a, 500, 1e-14, 7).unwrap();
+        assert!((lambda - 3.0).abs() < 1e-10, "lambda {}", lambda);
+        let expected = [0.5f64.sqrt(), 0.5f64.sqrt(), 0.0];
+        assert!((dot(&x, &expected).abs() - 1.0).abs() < 1e-6, "x {:?}", x);
+    }
+
+    #[test]
+    fn a_repeated_dominant_eigenvalue_converges_within_its_eigenspace() {
+        let a = diagonal(&[3.0, 1.0, 3.0, 0.5]);
+        let (lambda, x) = power_iteration(&a, 500, 1e-12, 3).unwrap();
+        assert!((lambda - 3.0).abs() < 1e-10);
+        assert!(x[1].abs() < 1e-5 && x[3].abs() < 1e-5);
+    }
+
+    #[test]
+    fn dominant_eigenvalues_of_opposite_sign_do_not_converge() {
+        let a = diagonal(&[3.0, -3.0, 1.0]);
+        match power_iteration(&a, 200, 1e-12, 3) {
+            Err(OoclaError::DidNotConverge { iters, last_estimate }) => {
+                assert_eq!(iters, 200);
+                assert!(last_estimate.abs() <= 3.0 + 1e-12);
+            }
+            other => panic!("expected no convergence, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deflation_gives_successive_eigenpairs() {
+        for &symmetric in &[true, false] {
+            let a = if symmetric {
+                diagonal(&[1.0, 6.0, -4.0, 2.0])
+            } else {
+                // Upper triangular, so the eigenvalues are its diagonal.
+                from_rows(&[&[1.0, 1.0, 0.0, 2.0], &[0.0, 6.0, 1.0, 0.0], &[0.0, 0.0, -4.0, 1.0], &[0.0, 0.0, 0.0, 2.0]])
+            };
+            let mut found = Vec::new();
+            for &expected in &[6.0, -4.0, 2.0, 1.0] {
+                let pair = power_iteration_deflated(&a, &found, 2000, 1e-14, 5, symmetric).unwrap();
+                assert!((pair.0 - expected).abs() < 1e-8, "symmetric {}: {} vs {}", symmetric, pair.0, expected);
+                let mut ax = vec![0.0; 4];
+                gemv(1.0, &a, &pair.1, 0.0, &mut ax).unwrap();
+                for (ax, x) in ax.iter().zip(pair.1.iter()) {
+                    assert!((ax - expected * x).abs() < 1e-6);
+                }
+                found.push(pair);
+            }
+        }
+    }
+
+    #[test]
+    fn non_square_matrices_are_rejected() {
+        let a = Dense::<f64>::create_anonymous(3, 2).unwrap();
+        assert!(power_iteration(&a, 10, 1e-6, 0).is_err());
+        assert!(power_iteration_deflated(&a, &[], 10, 1e-6, 0, true).is_err());
+    }
+}
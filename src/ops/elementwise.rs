@@ -0,0 +1,99 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+#[cfg(all(feature = "numa", feature = "rayon"))]
+use numa::NodePinner;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MathFunc {
+    Exp,
+    Ln,
+    Sqrt,
+    Sigmoid,
+    Relu,
+    Tanh,
+}
+
+impl MathFunc {
+    pub fn eval(self, x: f64) -> f64 {
+        match self {
+            MathFunc::Exp => x.exp(),
+            MathFunc::Ln => x.ln(),
+            MathFunc::Sqrt => x.sqrt(),
+            MathFunc::Sigmoid => 1.0 / (1.0 + (-x).exp()),
+            MathFunc::Relu => if x > 0.0 || x.is_nan() { x } else { 0.0 },
+            MathFunc::Tanh => x.tanh(),
+        }
+    }
+}
+
+// Returns the number of NaNs produced from inputs that were not already NaN.
+fn apply_run<T: SupportedType>(run: &mut [T], func: MathFunc) -> u64 {
+    let mut produced = 0;
+    for element in run.iter_mut() {
+        let x = element.to_f64();
+        let y = func.eval(x);
+        if y.is_nan() && !x.is_nan() {
+            produced += 1;
+        }
+        *element = T::from_f64(y);
+    }
+    produced
+}
+
+// Domain errors follow IEEE semantics and yield NaN; the return value counts how many NaNs
+// were produced from non-NaN inputs so callers can detect them.
+pub fn apply<T: SupportedType>(a: &mut Dense<T>, func: MathFunc) -> u64 {
+    let (lda, minor) = (a.lda() as usize, a.minor_len() as usize);
+    if lda == 0 {
+        return 0;
+    }
+    a.storage_mut()
+        .chunks_mut(lda)
+        .map(|line| apply_run(&mut line[..minor], func))
+        .sum()
+}
+
+#[cfg(feature = "rayon")]
+pub fn par_apply<T: SupportedType + Send>(a: &mut Dense<T>, func: MathFunc) -> u64 {
+    let (lda, minor) = (a.lda() as usize, a.minor_len() as usize);
+    if lda == 0 {
+        return 0;
+    }
+    #[cfg(feature = "numa")]
+    let pinner = NodePinner::new(a.storage(), a.stream_options().pin_numa_workers);
+    a.storage_mut()
+        .par_chunks_mut(lda)
+        .map(|line| {
+            #[cfg(feature = "numa")]
+            pinner.pin(line);
+            apply_run(&mut line[..minor], func)
+        })
+        .sum()
+}
+
+pub fn apply_into<T: SupportedType>(a: &Dense<T>, dst: &mut Dense<T>, func: MathFunc) -> Result<u64, OoclaError> {
+    if a.num_rows() != dst.num_rows() || a.num_cols() != dst.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), a.num_cols()),
+            found: (dst.num_rows(), dst.num_cols()),
+        });
+    }
+    let mut produced = 0;
+    if a.is_transposed() == dst.is_transposed() {
+        for major in 0..a.major_len() {
+            let line = dst.major_slice_mut(major);
+            line.copy_from_slice(a.major_slice(major));
+            produced += apply_run(line, func);
+        }
+    } else {
+        let mut row = vec![T::from_f64(0.0); a.num_cols() as usize];
+        for r in 0..a.num_rows() {
+            a.read_row(r, &mut row);
+            produced += apply_run(&mut row, func);
+            dst.write_row(r, &row);
+        }
+    }
+    Ok(produced)
+}
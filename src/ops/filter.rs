@@ -0,0 +1,106 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use row_writer::RowWriter;
+use std::path::Path;
+
+// Writes the rows of A for which `pred(index, row)` holds to `dst`, keeping their order, and
+// returns the new matrix with the number of rows kept. Rows are visited in logical order
+// whatever the storage layout and the predicate always sees a contiguous row.
+pub fn filter_rows<T: StorageType>(a: &Dense<T>, dst: &Path, mut pred: impl FnMut(u64, &[T]) -> bool)
+    -> Result<(Dense<T>, u64), OoclaError> {
+    let mut writer = RowWriter::create(dst, a.num_cols())?;
+    let mut scratch = vec![T::default(); a.num_cols() as usize];
+    for i in 0..a.num_rows() {
+        let row = a.row_view(i, &mut scratch);
+        if pred(i, row) {
+            writer.write_row(row)?;
+        }
+    }
+    let kept = writer.rows_written();
+    Ok((writer.finish()?, kept))
+}
+
+// As filter_rows, but also writes the rows failing the predicate to `rest_dst`, in the same
+// pass. Returns (matching, rest).
+pub fn partition_rows<T: StorageType>(a: &Dense<T>, matching_dst: &Path, rest_dst: &Path,
+                                      mut pred: impl FnMut(u64, &[T]) -> bool)
+    -> Result<(Dense<T>, Dense<T>), OoclaError> {
+    let mut matching = RowWriter::create(matching_dst, a.num_cols())?;
+    let mut rest = RowWriter::create(rest_dst, a.num_cols())?;
+    let mut scratch = vec![T::default(); a.num_cols() as usize];
+    for i in 0..a.num_rows() {
+        let row = a.row_view(i, &mut scratch);
+        if pred(i, row) {
+            matching.write_row(row)?;
+        } else {
+            rest.write_row(row)?;
+        }
+    }
+    Ok((matching.finish()?, rest.finish()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    fn rows_of(a: &Dense<f32>) -> Vec<Vec<f32>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    // A random 300 x 4 matrix, stored column-major when `transposed` is set.
+    fn random_matrix(transposed: bool) -> Dense<f32> {
+        let (rows, cols) = if transposed { (4, 300) } else { (300, 4) };
+        let mut a = Dense::<f32>::create_anonymous(rows, cols).unwrap();
+        a.randomise_seeded(11);
+        if transposed {
+            a.transpose();
+        }
+        a
+    }
+
+    #[test]
+    fn a_column_threshold_matches_an_in_memory_filter() {
+        for &transposed in &[false, true] {
+            let a = random_matrix(transposed);
+            let reference: Vec<Vec<f32>> = rows_of(&a).into_iter().filter(|row| row[2] > 0.5).collect();
+            let dst = TempMatrixPath::new();
+            let mut visited = Vec::new();
+            let (kept, count) = filter_rows(&a, dst.path(), |i, row| {
+                visited.push(i);
+                row[2] > 0.5
+            }).unwrap();
+            assert_eq!(visited, (0..300).collect::<Vec<_>>(), "transposed {}", transposed);
+            assert_eq!(count, reference.len() as u64);
+            assert!(count > 100 && count < 200);
+            assert_eq!(rows_of(&kept), reference, "transposed {}", transposed);
+        }
+    }
+
+    #[test]
+    fn partitions_split_rows_by_the_predicate() {
+        for &transposed in &[false, true] {
+            let a = random_matrix(transposed);
+            let (matching_dst, rest_dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+            let (matching, rest) = partition_rows(&a, matching_dst.path(), rest_dst.path(),
+                                                  |i, row| i % 3 == 0 || row[0] < 0.25).unwrap();
+            let (expected_matching, expected_rest): (Vec<_>, Vec<_>) = rows_of(&a).into_iter().enumerate()
+                .partition(|&(i, ref row)| i % 3 == 0 || row[0] < 0.25);
+            let strip = |rows: Vec<(usize, Vec<f32>)>| rows.into_iter().map(|(_, row)| row).collect::<Vec<_>>();
+            assert_eq!(rows_of(&matching), strip(expected_matching));
+            assert_eq!(rows_of(&rest), strip(expected_rest));
+        }
+    }
+
+    #[test]
+    fn filtering_everything_or_nothing() {
+        let a = random_matrix(false);
+        let dst = TempMatrixPath::new();
+        let (none, count) = filter_rows(&a, dst.path(), |_, _| false).unwrap();
+        assert_eq!((none.num_rows(), none.num_cols(), count), (0, 4, 0));
+        let dst = TempMatrixPath::new();
+        let (all, count) = filter_rows(&a, dst.path(), |_, _| true).unwrap();
+        assert_eq!(count, 300);
+        assert_eq!(rows_of(&all), rows_of(&a));
+    }
+}
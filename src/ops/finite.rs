@@ -0,0 +1,90 @@
+use dense_matrix::{AccessPattern, Dense, DiscardBehind, SupportedType};
+use error::OoclaError;
+use ops::filter::filter_rows;
+use std::collections::BinaryHeap;
+use std::path::Path;
+
+// How many offending coordinates a NonFiniteReport records.
+pub const NONFINITE_REPORT_LOCATIONS: usize = 16;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NonFiniteReport {
+    pub nans: u64,
+    pub pos_inf: u64,
+    pub neg_inf: u64,
+    // The first offending (row, col) coordinates in row-major order.
+    pub first_locations: Vec<(u64, u64)>,
+}
+
+impl NonFiniteReport {
+    pub fn total(&self) -> u64 {
+        self.nans + self.pos_inf + self.neg_inf
+    }
+}
+
+pub fn count_nonfinite<T: SupportedType>(a: &Dense<T>) -> NonFiniteReport {
+    let mut report = NonFiniteReport::default();
+    let _advice = a.advise_scoped(AccessPattern::Sequential);
+    // Keeps the smallest coordinates seen so far whatever order the storage is walked in.
+    let mut locations = BinaryHeap::with_capacity(NONFINITE_REPORT_LOCATIONS + 1);
+    let mut pass = DiscardBehind::new(a);
+    for major in 0..a.major_len() {
+        for (minor, x) in a.major_slice(major).iter().enumerate() {
+            let x = x.to_f64();
+            if x.is_finite() {
+                continue;
+            }
+            if x.is_nan() {
+                report.nans += 1;
+            } else if x > 0.0 {
+                report.pos_inf += 1;
+            } else {
+                report.neg_inf += 1;
+            }
+            let location = if a.is_transposed() {
+                (minor as u64, major)
+            } else {
+                (major, minor as u64)
+            };
+            locations.push(location);
+            if locations.len() > NONFINITE_REPORT_LOCATIONS {
+                locations.pop();
+            }
+        }
+        // Releasing the cache is only a courtesy, so the count doesn't fail if it can't be.
+        let _ = pass.advance(a, major + 1);
+    }
+    report.first_locations = locations.into_sorted_vec();
+    report
+}
+
+// Negative infinities are replaced by the negation of `inf_with`. Returns the number of
+// elements replaced.
+pub fn replace_nonfinite<T: SupportedType>(a: &mut Dense<T>, nan_with: T, inf_with: T) -> u64 {
+    let neg_inf_with = T::from_f64(-inf_with.to_f64());
+    let mut replaced = 0;
+    for major in 0..a.major_len() {
+        for element in a.major_slice_mut(major).iter_mut() {
+            let x = element.to_f64();
+            if x.is_finite() {
+                continue;
+            }
+            *element = if x.is_nan() {
+                nan_with
+            } else if x > 0.0 {
+                inf_with
+            } else {
+                neg_inf_with
+            };
+            replaced += 1;
+        }
+    }
+    replaced
+}
+
+// Writes the rows of `a` containing only finite values to `dst`, returning the new matrix and
+// the number of rows dropped.
+pub fn drop_rows_with_nonfinite<T: SupportedType>(a: &Dense<T>, dst: &Path) -> Result<(Dense<T>, u64), OoclaError> {
+    let (result, kept) = filter_rows(a, dst, |_, row| row.iter().all(|x| x.to_f64().is_finite()))?;
+    Ok((result, a.num_rows() - kept))
+}
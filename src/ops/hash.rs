@@ -0,0 +1,123 @@
+// XXH64, for hashing row contents without a dependency.
+const PRIME1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME5: u64 = 0x27d4_eb2f_1656_67c5;
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut word = [0; 8];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(word)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[..4]);
+    u32::from_le_bytes(word)
+}
+
+fn round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME2)).rotate_left(31).wrapping_mul(PRIME1)
+}
+
+fn merge_round(acc: u64, value: u64) -> u64 {
+    (acc ^ round(0, value)).wrapping_mul(PRIME1).wrapping_add(PRIME4)
+}
+
+// Streaming XXH64, for data that arrives in pieces. The digest matches xxh64 over the pieces
+// joined together.
+pub(crate) struct Xxh64 {
+    seed: u64,
+    lanes: [u64; 4],
+    buffer: [u8; 32],
+    buffered: usize,
+    total: u64,
+}
+
+impl Xxh64 {
+    pub(crate) fn new(seed: u64) -> Xxh64 {
+        Xxh64 {
+            seed,
+            lanes: [seed.wrapping_add(PRIME1).wrapping_add(PRIME2), seed.wrapping_add(PRIME2), seed,
+                    seed.wrapping_sub(PRIME1)],
+            buffer: [0; 32],
+            buffered: 0,
+            total: 0,
+        }
+    }
+
+    fn stripe(lanes: &mut [u64; 4], stripe: &[u8]) {
+        for (lane, word) in lanes.iter_mut().zip(stripe.chunks_exact(8)) {
+            *lane = round(*lane, read_u64(word));
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut bytes: &[u8]) {
+        self.total += bytes.len() as u64;
+        if self.buffered > 0 {
+            let take = (32 - self.buffered).min(bytes.len());
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&bytes[..take]);
+            self.buffered += take;
+            bytes = &bytes[take..];
+            if self.buffered < 32 {
+                return;
+            }
+            Self::stripe(&mut self.lanes, &self.buffer);
+            self.buffered = 0;
+        }
+        let mut stripes = bytes.chunks_exact(32);
+        for stripe in stripes.by_ref() {
+            Self::stripe(&mut self.lanes, stripe);
+        }
+        let tail = stripes.remainder();
+        self.buffer[..tail.len()].copy_from_slice(tail);
+        self.buffered = tail.len();
+    }
+
+    // The number of bytes hashed so far.
+    pub(crate) fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub(crate) fn digest(&self) -> u64 {
+        let v = self.lanes;
+        let mut h = if self.total >= 32 {
+            let mut h = v[0].rotate_left(1).wrapping_add(v[1].rotate_left(7))
+                .wrapping_add(v[2].rotate_left(12)).wrapping_add(v[3].rotate_left(18));
+            for &lane in v.iter() {
+                h = merge_round(h, lane);
+            }
+            h
+        } else {
+            self.seed.wrapping_add(PRIME5)
+        };
+        h = h.wrapping_add(self.total);
+        let mut tail = &self.buffer[..self.buffered];
+        while tail.len() >= 8 {
+            h ^= round(0, read_u64(tail));
+            h = h.rotate_left(27).wrapping_mul(PRIME1).wrapping_add(PRIME4);
+            tail = &tail[8..];
+        }
+        if tail.len() >= 4 {
+            h ^= (read_u32(tail) as u64).wrapping_mul(PRIME1);
+            h = h.rotate_left(23).wrapping_mul(PRIME2).wrapping_add(PRIME3);
+            tail = &tail[4..];
+        }
+        for &byte in tail.iter() {
+            h ^= (byte as u64).wrapping_mul(PRIME5);
+            h = h.rotate_left(11).wrapping_mul(PRIME1);
+        }
+        h ^= h >> 33;
+        h = h.wrapping_mul(PRIME2);
+        h ^= h >> 29;
+        h = h.wrapping_mul(PRIME3);
+        h ^ (h >> 32)
+    }
+}
+
+pub(crate) fn xxh64(bytes: &[u8], seed: u64) -> u64 {
+    let mut hash = Xxh64::new(seed);
+    hash.update(bytes);
+    hash.digest()
+}
@@ -0,0 +1,256 @@
+use dense_matrix::{AccessPattern, Dense, DiscardBehind, SupportedType};
+use error::OoclaError;
+#[cfg(all(feature = "numa", feature = "rayon"))]
+use numa::NodePinner;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use std::f64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HistogramBins {
+    // Bin i covers [edges[i], edges[i + 1]), except that the last bin also includes its upper
+    // edge. Edges must be finite and strictly increasing.
+    Edges(Vec<f64>),
+    // `bins` equal-width bins spanning `range`, or when that is None the finite values of the
+    // matrix, which takes an extra pass for the minimum and maximum.
+    Uniform { bins: usize, range: Option<(f64, f64)> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    pub edges: Vec<f64>,
+    pub counts: Vec<u64>,
+    // Values below the first edge and above the last, including infinities.
+    pub underflow: u64,
+    pub overflow: u64,
+    pub nan_count: u64,
+}
+
+impl Histogram {
+    pub fn empty(edges: Vec<f64>) -> Result<Histogram, OoclaError> {
+        if edges.len() < 2 || edges.iter().any(|e| !e.is_finite()) || edges.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(OoclaError::InvalidArgument("histogram edges must be at least two strictly increasing finite values"
+                .to_string()));
+        }
+        Ok(Histogram {
+            counts: vec![0; edges.len() - 1],
+            edges,
+            underflow: 0,
+            overflow: 0,
+            nan_count: 0,
+        })
+    }
+
+    pub fn insert(&mut self, x: f64) {
+        let last = self.edges.len() - 1;
+        if x.is_nan() {
+            self.nan_count += 1;
+        } else if x < self.edges[0] {
+            self.underflow += 1;
+        } else if x > self.edges[last] {
+            self.overflow += 1;
+        } else {
+            let bin = self.edges.partition_point(|&e| e <= x).clamp(1, last) - 1;
+            self.counts[bin] += 1;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum::<u64>() + self.underflow + self.overflow + self.nan_count
+    }
+
+    // Adds another histogram over the same edges into this one. Counts are integers, so the
+    // result does not depend on the order histograms are merged in.
+    pub fn merge(&mut self, other: &Histogram) -> Result<(), OoclaError> {
+        if self.edges != other.edges {
+            return Err(OoclaError::InvalidArgument("cannot merge histograms with different edges".to_string()));
+        }
+        for (count, &more) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += more;
+        }
+        self.underflow += other.underflow;
+        self.overflow += other.overflow;
+        self.nan_count += other.nan_count;
+        Ok(())
+    }
+}
+
+fn finite_range<T: SupportedType>(a: &Dense<T>) -> Option<(f64, f64)> {
+    let (mut lo, mut hi) = (f64::INFINITY, f64::NEG_INFINITY);
+    for major in 0..a.major_len() {
+        for x in a.major_slice(major).iter().map(|x| x.to_f64()).filter(|x| x.is_finite()) {
+            lo = lo.min(x);
+            hi = hi.max(x);
+        }
+    }
+    if lo <= hi { Some((lo, hi)) } else { None }
+}
+
+// The edges to count into. Like numpy, a range with no width is widened by a half either side of
+// its value and a matrix with no finite values gets [0, 1].
+fn resolve_edges<T: SupportedType>(a: &Dense<T>, bins: &HistogramBins) -> Result<Vec<f64>, OoclaError> {
+    match *bins {
+        HistogramBins::Edges(ref edges) => Ok(edges.clone()),
+        HistogramBins::Uniform { bins, range } => {
+            if bins == 0 {
+                return Err(OoclaError::InvalidArgument("a histogram needs at least one bin".to_string()));
+            }
+            let (mut lo, mut hi) = match range {
+                Some((lo, hi)) if !(lo.is_finite() && hi.is_finite() && lo <= hi) => {
+                    return Err(OoclaError::InvalidArgument(format!("invalid histogram range [{}, {}]", lo, hi)));
+                }
+                Some(range) => range,
+                None => finite_range(a).unwrap_or((0.0, 1.0)),
+            };
+            if lo == hi {
+                lo -= 0.5;
+                hi += 0.5;
+            }
+            let width = (hi - lo) / bins as f64;
+            let mut edges: Vec<f64> = (0..bins).map(|i| lo + i as f64 * width).collect();
+            edges.push(hi);
+            Ok(edges)
+        }
+    }
+}
+
+pub fn histogram<T: SupportedType>(a: &Dense<T>, bins: &HistogramBins) -> Result<Histogram, OoclaError> {
+    let _advice = a.advise_scoped(AccessPattern::Sequential);
+    let mut result = Histogram::empty(resolve_edges(a, bins)?)?;
+    let mut pass = DiscardBehind::new(a);
+    for major in 0..a.major_len() {
+        for x in a.major_slice(major) {
+            result.insert(x.to_f64());
+        }
+        pass.advance(a, major + 1)?;
+    }
+    Ok(result)
+}
+
+// As histogram, counting each major line in parallel and merging the per-line histograms.
+#[cfg(feature = "rayon")]
+pub fn par_histogram<T: SupportedType + Sync>(a: &Dense<T>, bins: &HistogramBins) -> Result<Histogram, OoclaError> {
+    let empty = Histogram::empty(resolve_edges(a, bins)?)?;
+    let (lda, minor) = (a.lda() as usize, a.minor_len() as usize);
+    if lda == 0 {
+        return Ok(empty);
+    }
+    #[cfg(feature = "numa")]
+    let pinner = NodePinner::new(a.storage(), a.stream_options().pin_numa_workers);
+    let merged = a.storage()
+        .par_chunks(lda)
+        .fold(|| empty.clone(), |mut h, line| {
+            #[cfg(feature = "numa")]
+            pinner.pin(line);
+            for x in line[..minor].iter() {
+                h.insert(x.to_f64());
+            }
+            h
+        })
+        .reduce(|| empty.clone(), |mut x, y| {
+            x.merge(&y).expect("partial histograms share their edges");
+            x
+        });
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values spread over [-2, 6) with edge values, infinities and NaNs mixed in.
+    fn values() -> Vec<f64> {
+        let mut values: Vec<f64> = (0..70).map(|i| (i * 37 % 64) as f64 / 8.0 - 2.0).collect();
+        values[3] = 0.0;
+        values[9] = 4.0;
+        values[17] = 2.5;
+        values[20] = f64::NAN;
+        values[33] = f64::INFINITY;
+        values[41] = f64::NEG_INFINITY;
+        values[58] = f64::NAN;
+        values
+    }
+
+    // The values as rows of seven.
+    fn matrix(values: &[f64]) -> Dense<f64> {
+        Dense::anonymous_from_fn(values.len() as u64 / 7, 7, |i, j| values[(i * 7 + j) as usize])
+    }
+
+    // Counts into bins by a linear search, as the documentation of HistogramBins reads.
+    fn reference(values: &[f64], edges: &[f64]) -> Histogram {
+        let last = edges.len() - 1;
+        let mut result = Histogram::empty(edges.to_vec()).unwrap();
+        for &x in values {
+            if x.is_nan() {
+                result.nan_count += 1;
+            } else if x < edges[0] {
+                result.underflow += 1;
+            } else if x > edges[last] {
+                result.overflow += 1;
+            } else {
+                let bin = (0..last).find(|&b| x < edges[b + 1]).unwrap_or(last - 1);
+                result.counts[bin] += 1;
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn explicit_edges_match_a_reference_count() {
+        let values = values();
+        let edges = vec![0.0, 1.0, 2.5, 4.0];
+        let result = histogram(&matrix(&values), &HistogramBins::Edges(edges.clone())).unwrap();
+        assert_eq!(result, reference(&values, &edges));
+        assert_eq!(result.nan_count, 2);
+        assert_eq!(result.total(), 70);
+    }
+
+    #[test]
+    fn single_and_two_pass_uniform_bins_agree() {
+        let values = values();
+        let a = matrix(&values);
+        let finite = values.iter().cloned().filter(|x| x.is_finite());
+        let (lo, hi) = finite.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), x| (lo.min(x), hi.max(x)));
+        let two_pass = histogram(&a, &HistogramBins::Uniform { bins: 8, range: None }).unwrap();
+        let single_pass = histogram(&a, &HistogramBins::Uniform { bins: 8, range: Some((lo, hi)) }).unwrap();
+        assert_eq!(two_pass, single_pass);
+        assert_eq!((two_pass.edges[0], two_pass.edges[8]), (lo, hi));
+        assert_eq!(two_pass, reference(&values, &two_pass.edges));
+        // Only the infinities fall outside the observed range.
+        assert_eq!((two_pass.underflow, two_pass.overflow), (1, 1));
+    }
+
+    #[test]
+    fn merged_halves_equal_the_whole() {
+        let values = values();
+        let edges = HistogramBins::Uniform { bins: 5, range: Some((-1.0, 3.0)) };
+        let whole = histogram(&matrix(&values), &edges).unwrap();
+        let mut first = histogram(&matrix(&values[..35]), &edges).unwrap();
+        let second = histogram(&matrix(&values[35..]), &edges).unwrap();
+        first.merge(&second).unwrap();
+        assert_eq!(first, whole);
+        let other = Histogram::empty(vec![0.0, 1.0]).unwrap();
+        assert!(first.merge(&other).is_err());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_histograms_equal_serial_ones() {
+        let values = values();
+        let a = matrix(&values);
+        let bins = HistogramBins::Uniform { bins: 6, range: None };
+        assert_eq!(par_histogram(&a, &bins).unwrap(), histogram(&a, &bins).unwrap());
+    }
+
+    #[test]
+    fn degenerate_and_invalid_bins() {
+        let constant = Dense::<f32>::anonymous_from_fn(3, 3, |_, _| 2.0);
+        let result = histogram(&constant, &HistogramBins::Uniform { bins: 2, range: None }).unwrap();
+        assert_eq!(result.edges, vec![1.5, 2.0, 2.5]);
+        assert_eq!(result.counts, vec![0, 9]);
+        assert!(histogram(&constant, &HistogramBins::Uniform { bins: 0, range: None }).is_err());
+        assert!(histogram(&constant, &HistogramBins::Uniform { bins: 2, range: Some((1.0, 0.0)) }).is_err());
+        assert!(histogram(&constant, &HistogramBins::Edges(vec![0.0, 0.0])).is_err());
+        assert!(histogram(&constant, &HistogramBins::Edges(vec![0.0, f64::INFINITY])).is_err());
+    }
+}
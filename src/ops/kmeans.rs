@@ -0,0 +1,240 @@
+use dense_matrix::Dense;
+use dense_vector::DenseVector;
+use error::OoclaError;
+use ops::blas::default_block_rows;
+use ops::distance::{row_squared_norms_range, squared_distance_block};
+use ops::rng::seeded_rng;
+use ops::topk::TopK;
+use rand::{Rng, XorShiftRng};
+use std::cmp;
+use std::collections::HashSet;
+use std::f64;
+use tile::DEFAULT_TILE_SIZE;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KmeansInit {
+    // k distinct rows chosen uniformly at random.
+    Random,
+    // k-means++: each further centroid is a row chosen with probability proportional to its
+    // squared distance from the nearest centroid already chosen.
+    PlusPlus,
+}
+
+pub struct KmeansResult {
+    pub centroids: Vec<Vec<f32>>,
+    // The index of the centroid nearest each row.
+    pub assignments: DenseVector<u32>,
+    // The sum of squared distances from each row to its centroid.
+    pub inertia: f64,
+    // The number of assignment passes made over the rows.
+    pub iters: usize,
+}
+
+fn read_row_f32(a: &Dense<f32>, i: u64) -> Vec<f32> {
+    let mut row = vec![0.0; a.num_cols() as usize];
+    a.read_row(i, &mut row);
+    row
+}
+
+// Floyd's algorithm, so that only the k chosen indices are ever held.
+fn random_rows(rng: &mut XorShiftRng, n: u64, k: usize) -> Vec<u64> {
+    let mut seen = HashSet::with_capacity(k);
+    let mut chosen = Vec::with_capacity(k);
+    for j in n - k as u64..n {
+        let t = rng.gen_range(0, j + 1);
+        let pick = if seen.contains(&t) { j } else { t };
+        seen.insert(pick);
+        chosen.push(pick);
+    }
+    chosen
+}
+
+// Each new centroid takes one pass to update the squared distances to the nearest centroid,
+// which are kept on disk alongside the rows, and one more to sample from them.
+fn plus_plus_rows(a: &Dense<f32>, rng: &mut XorShiftRng, k: usize) -> Result<Vec<u64>, OoclaError> {
+    let n = a.num_rows();
+    let mut nearest = DenseVector::<f64>::create_anonymous(n)?;
+    let nearest = nearest.as_mut_slice();
+    for x in nearest.iter_mut() {
+        *x = f64::INFINITY;
+    }
+    let mut chosen = vec![rng.gen_range(0, n)];
+    let mut row = vec![0.0f32; a.num_cols() as usize];
+    while chosen.len() < k {
+        let centroid = read_row_f32(a, *chosen.last().unwrap());
+        let mut total = 0.0;
+        for (i, d2) in nearest.iter_mut().enumerate() {
+            a.read_row(i as u64, &mut row);
+            let dist: f64 = row.iter().zip(centroid.iter()).map(|(&x, &c)| (x as f64 - c as f64).powi(2)).sum();
+            *d2 = d2.min(dist);
+            total += *d2;
+        }
+        // Once every row coincides with a centroid there is nothing to weight by.
+        if total == 0.0 {
+            chosen.push(rng.gen_range(0, n));
+            continue;
+        }
+        let target = rng.gen::<f64>() * total;
+        let mut cumulative = 0.0;
+        let mut pick = None;
+        for (i, &d2) in nearest.iter().enumerate() {
+            cumulative += d2;
+            if d2 > 0.0 {
+                pick = Some(i as u64);
+                if cumulative > target {
+                    break;
+                }
+            }
+        }
+        chosen.push(pick.unwrap());
+    }
+    Ok(chosen)
+}
+
+// Copies the centroids into the matrix the distance kernel reads and returns their squared norms.
+fn load_centroids(dense: &mut Dense<f32>, centroids: &[Vec<f32>]) -> Vec<f64> {
+    for (j, c) in centroids.iter().enumerate() {
+        dense.write_row(j as u64, c);
+    }
+    centroids.iter().map(|c| c.iter().map(|&x| x as f64 * x as f64).sum()).collect()
+}
+
+// Lloyd's algorithm over the rows of A. Each iteration is a single streaming pass that assigns
+// every row to its nearest centroid, blocks of rows at a time against all k centroids, while
+// accumulating the sums from which the next centroids are formed. Iteration stops once a pass
+// changes no assignment, or after `max_iters` passes; the centroids returned are those the
+// final assignments and inertia were computed against. Ties go to the lower centroid index
+// and the result depends only on the seed.
+//
+// A cluster left empty by a pass is re-seeded at the row that was farthest from its centroid
+// in that pass, the next farthest for a second empty cluster, and so on; a pass that leaves a
+// cluster empty never counts as converged.
+pub fn kmeans(a: &Dense<f32>, k: usize, max_iters: usize, seed: u64, init: KmeansInit)
+    -> Result<KmeansResult, OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols() as usize);
+    if k == 0 || k as u64 > n || k as u64 > u32::MAX as u64 {
+        return Err(OoclaError::InvalidArgument(format!("cannot form {} clusters from {} rows", k, n)));
+    }
+    if max_iters == 0 {
+        return Err(OoclaError::InvalidArgument("max_iters must be non-zero".to_string()));
+    }
+    let mut rng = seeded_rng(seed);
+    let initial = match init {
+        KmeansInit::Random => random_rows(&mut rng, n, k),
+        KmeansInit::PlusPlus => plus_plus_rows(a, &mut rng, k)?,
+    };
+    let mut centroids: Vec<Vec<f32>> = initial.iter().map(|&i| read_row_f32(a, i)).collect();
+    let mut centroid_matrix = Dense::create_anonymous(k as u64, d as u64)?;
+    let mut assignments = DenseVector::<u32>::create_anonymous(n)?;
+    let block = cmp::min(DEFAULT_TILE_SIZE, default_block_rows(d as u64)) as u64;
+    let mut row = vec![0.0f32; d];
+    let mut iters = 0;
+    loop {
+        let centroid_norms = load_centroids(&mut centroid_matrix, &centroids);
+        let mut sums = vec![0.0f64; k * d];
+        let mut counts = vec![0u64; k];
+        let mut farthest = TopK::new(k);
+        let mut inertia = 0.0;
+        let mut changed = 0u64;
+        let labels = assignments.as_mut_slice();
+        for i0 in (0..n).step_by(block as usize) {
+            let rows = cmp::min(block, n - i0) as usize;
+            let norms = row_squared_norms_range(a, i0, rows);
+            let dists = squared_distance_block(a, &norms, i0, rows, &centroid_matrix, &centroid_norms, 0, k);
+            for (r, row_dists) in dists.chunks(k).enumerate() {
+                let i = i0 + r as u64;
+                let (best, best_dist) = row_dists.iter().enumerate()
+                    .fold((0, f64::INFINITY), |acc, (j, &dist)| if dist < acc.1 { (j, dist) } else { acc });
+                inertia += best_dist;
+                farthest.push(best_dist, i);
+                if iters == 0 || labels[i as usize] != best as u32 {
+                    labels[i as usize] = best as u32;
+                    changed += 1;
+                }
+                counts[best] += 1;
+                a.read_row(i, &mut row);
+                for (s, &x) in sums[best * d..(best + 1) * d].iter_mut().zip(row.iter()) {
+                    *s += x as f64;
+                }
+            }
+        }
+        iters += 1;
+        let empty = counts.iter().filter(|&&c| c == 0).count();
+        if (changed == 0 && empty == 0) || iters >= max_iters {
+            return Ok(KmeansResult { centroids, assignments, inertia, iters });
+        }
+        let mut reseeds = farthest.into_sorted().into_iter();
+        for (j, centroid) in centroids.iter_mut().enumerate() {
+            if counts[j] == 0 {
+                let (i, _) = reseeds.next().expect("fewer rows than clusters");
+                *centroid = read_row_f32(a, i);
+            } else {
+                for (c, &s) in centroid.iter_mut().zip(sums[j * d..(j + 1) * d].iter()) {
+                    *c = (s / counts[j] as f64) as f32;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTRES: [[f32; 2]; 3] = [[0.0, 0.0], [100.0, 0.0], [0.0, 100.0]];
+
+    // 150 rows around each of the centres, interleaved, with jitter of at most 1 in each
+    // coordinate.
+    fn blobs() -> Dense<f32> {
+        let mut jitter = Dense::<f32>::create_anonymous(450, 2).unwrap();
+        jitter.randomise_seeded(8);
+        Dense::anonymous_from_fn(450, 2, |i, j| CENTRES[(i % 3) as usize][j as usize] + 2.0 * jitter.get(i, j) - 1.0)
+    }
+
+    #[test]
+    fn well_separated_blobs_are_recovered() {
+        let a = blobs();
+        for seed in 0..4 {
+            let result = kmeans(&a, 3, 50, seed, KmeansInit::PlusPlus).unwrap();
+            assert!(result.iters < 50);
+            // Each blob maps to a single cluster and no two blobs share one.
+            let labels: Vec<u32> = (0..3).map(|b| result.assignments.get(b)).collect();
+            for i in 0..450 {
+                assert_eq!(result.assignments.get(i), labels[(i % 3) as usize], "seed {} row {}", seed, i);
+            }
+            let mut distinct = labels.clone();
+            distinct.sort();
+            distinct.dedup();
+            assert_eq!(distinct.len(), 3);
+            for (blob, &label) in labels.iter().enumerate() {
+                let centroid = &result.centroids[label as usize];
+                for (c, e) in centroid.iter().zip(CENTRES[blob].iter()) {
+                    assert!((c - e).abs() < 0.25, "seed {}: centroid {:?} for blob {}", seed, centroid, blob);
+                }
+            }
+            // Every row is within sqrt(2) of its blob's centre.
+            assert!(result.inertia < 450.0 * 2.0);
+        }
+    }
+
+    #[test]
+    fn results_depend_only_on_the_seed() {
+        let a = blobs();
+        for &init in &[KmeansInit::Random, KmeansInit::PlusPlus] {
+            let first = kmeans(&a, 4, 20, 99, init).unwrap();
+            let second = kmeans(&a, 4, 20, 99, init).unwrap();
+            assert_eq!(first.centroids, second.centroids);
+            assert_eq!(first.assignments.as_slice(), second.assignments.as_slice());
+            assert_eq!(first.inertia.to_bits(), second.inertia.to_bits());
+            assert_eq!(first.iters, second.iters);
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        let a = Dense::<f32>::create_anonymous(5, 2).unwrap();
+        assert!(kmeans(&a, 0, 10, 0, KmeansInit::Random).is_err());
+        assert!(kmeans(&a, 6, 10, 0, KmeansInit::Random).is_err());
+        assert!(kmeans(&a, 2, 0, 0, KmeansInit::PlusPlus).is_err());
+    }
+}
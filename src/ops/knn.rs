@@ -0,0 +1,175 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::distance::{row_squared_norms_range, squared_distance_block};
+use ops::topk::TopK;
+use std::cmp;
+use std::f64;
+use std::path::Path;
+
+// Distances computed by the |x|² + |y|² - 2·x·y expansion can fall short of the exact value by
+// a rounding error relative to the norms; blocks are only skipped beyond this margin so that
+// pruning never changes the result.
+const PRUNE_SLACK: f64 = 1e-10;
+
+pub struct Neighbours {
+    // Row i holds the indices of the rows of B nearest row i of A, nearest first.
+    pub indices: Dense<u64>,
+    // The matching Euclidean distances.
+    pub distances: Dense<f32>,
+}
+
+// Finds, for each row of A, the k nearest rows of B by Euclidean distance, exactly, without
+// forming the distance matrix. A block of `block` rows of A is held while blocks of B stream
+// past, with per-row heaps keeping the best k so far and ties going to the lower index. A
+// block of B is skipped when, by the triangle inequality applied to row norms, none of its
+// rows could displace anything in the rows' current lists. The n x k results are written to
+// `indices_dst` and `distances_dst`, or to anonymous files where those are None.
+pub fn knn(a: &Dense<f32>, b: &Dense<f32>, k: usize, block: usize, indices_dst: Option<&Path>,
+           distances_dst: Option<&Path>) -> Result<Neighbours, OoclaError> {
+    let (n, m, d) = (a.num_rows(), b.num_rows(), a.num_cols());
+    if b.num_cols() != d {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (m, d),
+            found: (m, b.num_cols()),
+        });
+    }
+    if k == 0 || k as u64 > m {
+        return Err(OoclaError::InvalidArgument(format!("k must be in 1..={} to search {} rows", m, m)));
+    }
+    if block == 0 {
+        return Err(OoclaError::InvalidArgument("block must be non-zero".to_string()));
+    }
+    let step = block as u64;
+    // The range of row norms in each block of B, for pruning.
+    let norm_ranges: Vec<(f64, f64)> = (0..m).step_by(block).map(|j0| {
+        let norms = row_squared_norms_range(b, j0, cmp::min(step, m - j0) as usize);
+        norms.iter().fold((f64::INFINITY, 0.0f64), |(lo, hi), &s| (lo.min(s.sqrt()), hi.max(s.sqrt())))
+    }).collect();
+    let mut indices = Dense::create_at(indices_dst, n, k as u64)?;
+    let mut distances = Dense::create_at(distances_dst, n, k as u64)?;
+    let mut index_row = vec![0u64; k];
+    let mut distance_row = vec![0.0f32; k];
+    for i0 in (0..n).step_by(block) {
+        let rows = cmp::min(step, n - i0) as usize;
+        let a_norms = row_squared_norms_range(a, i0, rows);
+        let mut heaps: Vec<TopK> = (0..rows).map(|_| TopK::new(k)).collect();
+        let mut worst = vec![f64::INFINITY; rows];
+        for (jb, j0) in (0..m).step_by(block).enumerate() {
+            let cols = cmp::min(step, m - j0) as usize;
+            let (lo, hi) = norm_ranges[jb];
+            let prunable = a_norms.iter().zip(worst.iter()).all(|(&x2, &w)| {
+                let x = x2.sqrt();
+                let gap = if x < lo { lo - x } else if x > hi { x - hi } else { 0.0 };
+                gap * gap > w + PRUNE_SLACK * (x2 + hi * hi)
+            });
+            if prunable {
+                continue;
+            }
+            let b_norms = row_squared_norms_range(b, j0, cols);
+            let squared = squared_distance_block(a, &a_norms, i0, rows, b, &b_norms, j0, cols);
+            for (r, (heap, row)) in heaps.iter_mut().zip(squared.chunks(cols)).enumerate() {
+                for (c, &dist) in row.iter().enumerate() {
+                    heap.push(-dist, j0 + c as u64);
+                }
+                worst[r] = heap.worst_score().map_or(f64::INFINITY, |s| -s);
+            }
+        }
+        for (r, heap) in heaps.into_iter().enumerate() {
+            let found = heap.into_sorted();
+            // Only NaN distances leave a list short, and those slots are marked as absent.
+            for slot in 0..k {
+                let (index, distance) = found.get(slot).map_or((u64::MAX, f32::INFINITY),
+                                                                |&(j, s)| (j, (-s).sqrt() as f32));
+                index_row[slot] = index;
+                distance_row[slot] = distance;
+            }
+            indices.write_row(i0 + r as u64, &index_row);
+            distances.write_row(i0 + r as u64, &distance_row);
+        }
+    }
+    Ok(Neighbours { indices, distances })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // The k nearest rows of B to row i of A by exact distance, ties going to the lower index.
+    fn brute_force(a: &Dense<f32>, b: &Dense<f32>, i: u64, k: usize) -> Vec<(u64, f64)> {
+        let mut all: Vec<(u64, f64)> = (0..b.num_rows()).map(|j| {
+            let squared: f64 = (0..a.num_cols()).map(|c| (a.get(i, c) as f64 - b.get(j, c) as f64).powi(2)).sum();
+            (j, squared.sqrt())
+        }).collect();
+        all.sort_by(|x, y| x.1.partial_cmp(&y.1).unwrap().then(x.0.cmp(&y.0)));
+        all.truncate(k);
+        all
+    }
+
+    #[test]
+    fn neighbours_match_brute_force_for_any_block_size() {
+        let mut a = Dense::<f32>::create_anonymous(40, 5).unwrap();
+        a.randomise_seeded(3);
+        // Rows of B grow in norm down the matrix so that whole blocks get pruned.
+        let mut b = Dense::<f32>::create_anonymous(300, 5).unwrap();
+        b.randomise_seeded(4);
+        let b = Dense::anonymous_from_fn(300, 5, |j, c| b.get(j, c) + (j / 50) as f32);
+        for &block in &[1, 7, 64, 1000] {
+            let result = knn(&a, &b, 4, block, None, None).unwrap();
+            for i in 0..40 {
+                for (slot, &(j, distance)) in brute_force(&a, &b, i, 4).iter().enumerate() {
+                    assert_eq!(result.indices.get(i, slot as u64), j, "block {} row {} slot {}", block, i, slot);
+                    assert!((result.distances.get(i, slot as u64) as f64 - distance).abs() < 1e-4);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ties_go_to_the_lower_index() {
+        // Every row of B but the last is at distance one from the origin.
+        let points = [[2.0, 0.0], [0.0, 1.0], [1.0, 0.0], [0.0, -1.0], [-1.0, 0.0], [3.0, 0.0]];
+        let b = Dense::<f32>::anonymous_from_fn(6, 2, |j, c| points[j as usize][c as usize]);
+        let a = Dense::<f32>::create_anonymous(1, 2).unwrap();
+        for &block in &[1, 2, 6] {
+            let result = knn(&a, &b, 3, block, None, None).unwrap();
+            let mut found = [0u64; 3];
+            result.indices.read_row(0, &mut found);
+            assert_eq!(found, [1, 2, 3], "block {}", block);
+            let mut distances = [0.0f32; 3];
+            result.distances.read_row(0, &mut distances);
+            assert_eq!(distances, [1.0, 1.0, 1.0]);
+        }
+    }
+
+    #[test]
+    fn results_are_written_to_the_given_paths() {
+        let mut a = Dense::<f32>::create_anonymous(10, 3).unwrap();
+        a.randomise_seeded(5);
+        let (indices_path, distances_path) = (TempMatrixPath::new(), TempMatrixPath::new());
+        let result = knn(&a, &a, 2, 4, Some(indices_path.path()), Some(distances_path.path())).unwrap();
+        result.indices.flush().unwrap();
+        result.distances.flush().unwrap();
+        let indices = Dense::<u64>::open(indices_path.path()).unwrap();
+        let distances = Dense::<f32>::open(distances_path.path()).unwrap();
+        // Each row is its own nearest neighbour.
+        for i in 0..10 {
+            for slot in 0..2 {
+                assert_eq!(indices.get(i, slot), result.indices.get(i, slot));
+                assert_eq!(distances.get(i, slot), result.distances.get(i, slot));
+            }
+            assert_eq!(indices.get(i, 0), i);
+            assert_eq!(distances.get(i, 0), 0.0);
+        }
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        let a = Dense::<f32>::create_anonymous(3, 2).unwrap();
+        let b = Dense::<f32>::create_anonymous(4, 3).unwrap();
+        assert!(knn(&a, &b, 1, 4, None, None).is_err());
+        assert!(knn(&a, &a, 0, 4, None, None).is_err());
+        assert!(knn(&a, &a, 4, 4, None, None).is_err());
+        assert!(knn(&a, &a, 1, 0, None, None).is_err());
+    }
+}
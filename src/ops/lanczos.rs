@@ -0,0 +1,188 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::gemv;
+use ops::rng::{gaussian_vector, seeded_rng};
+use ops::symeig::tridiagonal_ql;
+use std::cmp;
+use std::f64;
+
+// The symmetric tridiagonal matrix T = V^T·A·V produced by the Lanczos process, with
+// diagonal `alphas` and off-diagonal `betas` (one shorter), and optionally the orthonormal
+// Lanczos vectors V stored one per row.
+pub struct Tridiag {
+    pub alphas: Vec<f64>,
+    pub betas: Vec<f64>,
+    pub basis: Option<Dense<f64>>,
+}
+
+impl Tridiag {
+    // The eigenvalues of T in increasing order, computed by implicit QL. Its extremal values
+    // approximate the extremal eigenvalues of A.
+    pub fn ritz_values(&self) -> Vec<f64> {
+        let mut d = self.alphas.clone();
+        let mut e = self.betas.clone();
+        e.resize(d.len(), 0.0);
+        tridiagonal_ql(&mut d, &mut e, None);
+        d
+    }
+}
+
+fn dot(x: &[f64], y: &[f64]) -> f64 {
+    x.iter().zip(y.iter()).map(|(a, b)| a * b).sum()
+}
+
+// Runs up to k steps of the Lanczos process on a symmetric matrix from a random start vector,
+// one gemv per step. With `reorthogonalize` each new vector is orthogonalised against all
+// earlier ones, which keeps spurious copies of converged Ritz values away at the cost of
+// reading the basis back every step. The basis, k x n, lives in an anonymous file and is
+// returned when `keep_basis` is set. If an invariant subspace is found after m < k steps the
+// process stops there and only the first m rows of the basis are meaningful.
+pub fn lanczos(a: &Dense<f64>, k: usize, seed: u64, reorthogonalize: bool, keep_basis: bool)
+    -> Result<Tridiag, OoclaError> {
+    let n = a.num_rows();
+    if a.num_cols() != n {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (n, n),
+            found: (a.num_rows(), a.num_cols()),
+        });
+    }
+    if k == 0 || n == 0 {
+        return Err(OoclaError::InvalidArgument("lanczos needs k > 0 and a non-empty matrix".to_string()));
+    }
+    let k = cmp::min(k as u64, n) as usize;
+    let n = n as usize;
+    let mut basis = if reorthogonalize || keep_basis {
+        Some(Dense::create_anonymous(k as u64, n as u64)?)
+    } else {
+        None
+    };
+    let mut rng = seeded_rng(seed);
+    let mut v = gaussian_vector(&mut rng, n);
+    let norm = dot(&v, &v).sqrt();
+    for x in v.iter_mut() {
+        *x /= norm;
+    }
+    let mut v_prev = vec![0.0; n];
+    let mut w = vec![0.0; n];
+    let (mut alphas, mut betas) = (Vec::with_capacity(k), Vec::with_capacity(k));
+    let mut beta = 0.0;
+    for j in 0..k {
+        if let Some(ref mut basis) = basis {
+            basis.write_row(j as u64, &v);
+        }
+        gemv(1.0, a, &v, 0.0, &mut w)?;
+        let alpha = dot(&w, &v);
+        for ((w, v), u) in w.iter_mut().zip(v.iter()).zip(v_prev.iter()) {
+            *w -= alpha * v + beta * u;
+        }
+        alphas.push(alpha);
+        if j + 1 == k {
+            break;
+        }
+        if reorthogonalize {
+            let basis = basis.as_ref().expect("basis is kept when reorthogonalising");
+            // Two passes of classical Gram-Schmidt are enough to restore orthogonality.
+            for _ in 0..2 {
+                for i in 0..=j {
+                    let u = &basis.major_slice(i as u64)[..n];
+                    let c = dot(u, &w);
+                    for (w, u) in w.iter_mut().zip(u.iter()) {
+                        *w -= c * u;
+                    }
+                }
+            }
+        }
+        beta = dot(&w, &w).sqrt();
+        if beta <= f64::EPSILON * alpha.abs().max(1.0) * (n as f64).sqrt() {
+            break;
+        }
+        betas.push(beta);
+        for ((u, v), w) in v_prev.iter_mut().zip(v.iter_mut()).zip(w.iter()) {
+            *u = *v;
+            *v = w / beta;
+        }
+    }
+    Ok(Tridiag {
+        alphas,
+        betas,
+        basis: if keep_basis { basis } else { None },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    // The Laplacian of the path graph on n vertices, whose eigenvalues are 2 - 2cos(kπ/n)
+    // for k = 0..n.
+    fn path_laplacian(n: u64) -> Dense<f64> {
+        Dense::anonymous_from_fn(n, n, |i, j| {
+            if i == j {
+                if i == 0 || i == n - 1 { 1.0 } else { 2.0 }
+            } else if i + 1 == j || j + 1 == i {
+                -1.0
+            } else {
+                0.0
+            }
+        })
+    }
+
+    fn path_spectrum(n: u64) -> Vec<f64> {
+        (0..n).map(|k| 2.0 - 2.0 * (k as f64 * PI / n as f64).cos()).collect()
+    }
+
+    #[test]
+    fn full_run_reproduces_the_path_laplacian_spectrum() {
+        let n = 40;
+        let tridiag = lanczos(&path_laplacian(n), n as usize, 1, true, false).unwrap();
+        assert_eq!(tridiag.alphas.len(), n as usize);
+        assert_eq!(tridiag.betas.len(), n as usize - 1);
+        for (ritz, exact) in tridiag.ritz_values().iter().zip(path_spectrum(n).iter()) {
+            assert!((ritz - exact).abs() < 1e-10, "{} vs {}", ritz, exact);
+        }
+    }
+
+    #[test]
+    fn a_short_run_approximates_the_extremal_eigenvalues() {
+        let n = 200;
+        let exact = path_spectrum(n);
+        for &reorthogonalize in &[true, false] {
+            let ritz = lanczos(&path_laplacian(n), 60, 2, reorthogonalize, false).unwrap().ritz_values();
+            let (lowest, highest) = (ritz[0], ritz[ritz.len() - 1]);
+            assert!((highest - exact[n as usize - 1]).abs() < 1e-3, "highest {}", highest);
+            assert!((-1e-12..1e-3).contains(&lowest), "lowest {}", lowest);
+            // Ritz values interlace with the spectrum, so they stay within its range.
+            assert!(ritz.iter().all(|&r| r > -1e-10 && r < 4.0));
+        }
+    }
+
+    #[test]
+    fn kept_basis_is_orthonormal() {
+        let n = 30;
+        let tridiag = lanczos(&path_laplacian(n), 12, 3, true, true).unwrap();
+        let basis = tridiag.basis.unwrap();
+        assert_eq!((basis.num_rows(), basis.num_cols()), (12, n));
+        for i in 0..12 {
+            for j in 0..12 {
+                let dot: f64 = (0..n).map(|c| basis.get(i, c) * basis.get(j, c)).sum();
+                assert!((dot - if i == j { 1.0 } else { 0.0 }).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn an_invariant_subspace_ends_the_process_early() {
+        let a = Dense::<f64>::anonymous_from_fn(10, 10, |i, j| if i == j { 2.0 } else { 0.0 });
+        let tridiag = lanczos(&a, 5, 4, false, false).unwrap();
+        assert_eq!(tridiag.alphas.len(), 1);
+        assert!(tridiag.betas.is_empty());
+        assert!((tridiag.ritz_values()[0] - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(lanczos(&Dense::<f64>::create_anonymous(3, 4).unwrap(), 2, 0, false, false).is_err());
+        assert!(lanczos(&path_laplacian(4), 0, 0, false, false).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::{default_block_rows, syrk};
+use ops::cholesky::cholesky_inplace;
+use ops::triangular::{Side, UpLo, trsm};
+use tile::Tile;
+
+// Above this estimated condition number of A^T·A the normal equations lose more than half the
+// digits of a double, and a QR-based solve such as tsqr should be preferred.
+pub const GRAM_COND_WARNING: f64 = 1e8;
+
+const CHOLESKY_BLOCK: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct LstsqResult {
+    pub x: Vec<f64>,
+    pub residual_norm: f64,
+    pub gram_cond_estimate: f64,
+    pub ill_conditioned: bool,
+}
+
+pub struct LstsqManyResult {
+    pub x: Dense<f64>,
+    pub residual_norms: Vec<f64>,
+    pub gram_cond_estimate: f64,
+    pub ill_conditioned: bool,
+}
+
+// Solves min |A·X - B| for an n x d matrix A and an n x r right-hand side whose rows are
+// produced by `b_row`, returning X (d x r), the residual norm of each column and the Gram
+// condition estimate.
+fn solve_normal<F>(a: &Dense<f64>, r: usize, mut b_row: F) -> Result<(Dense<f64>, Vec<f64>, f64), OoclaError>
+    where F: FnMut(u64, &mut [f64]) {
+    let (n, d) = (a.num_rows(), a.num_cols());
+    if n < d {
+        return Err(OoclaError::InvalidArgument(format!("least squares needs at least as many rows as columns, got {}x{}",
+                                                       n, d)));
+    }
+    let mut g = Dense::create_anonymous(d, d)?;
+    syrk(a, &mut g, default_block_rows(d))?;
+    let d = d as usize;
+    let mut row = vec![0.0; d];
+    let mut rhs_row = vec![0.0; r];
+    let mut rhs = Tile::zeros(0, 0, d, r);
+    for i in 0..n {
+        a.read_row(i, &mut row);
+        b_row(i, &mut rhs_row);
+        for (k, &aik) in row.iter().enumerate() {
+            for (acc, b) in rhs.data[k * r..(k + 1) * r].iter_mut().zip(rhs_row.iter()) {
+                *acc += aik * b;
+            }
+        }
+    }
+    let block = d.clamp(1, CHOLESKY_BLOCK);
+    cholesky_inplace(&mut g, block)?;
+    // cond(G) = cond(L)^2, and the spread of L's diagonal is a cheap lower bound for cond(L).
+    let (lo, hi) = g.diagonal_iter().fold((f64::INFINITY, 0.0f64), |(lo, hi), &x| (lo.min(x), hi.max(x)));
+    let cond = if d == 0 { 1.0 } else { (hi / lo) * (hi / lo) };
+    let mut x = Dense::create_anonymous(d as u64, r as u64)?;
+    x.write_tile(&rhs);
+    trsm(Side::Left, UpLo::Lower, false, false, 1.0, &g, &mut x, block)?;
+    trsm(Side::Left, UpLo::Lower, true, false, 1.0, &g, &mut x, block)?;
+    let solution = x.read_tile(0, 0, d, r);
+    let mut sums = vec![0.0; r];
+    for i in 0..n {
+        a.read_row(i, &mut row);
+        b_row(i, &mut rhs_row);
+        for (j, sum) in sums.iter_mut().enumerate() {
+            let fitted: f64 = row.iter().enumerate().map(|(k, aik)| aik * solution.data[k * r + j]).sum();
+            *sum += (fitted - rhs_row[j]) * (fitted - rhs_row[j]);
+        }
+    }
+    Ok((x, sums.into_iter().map(f64::sqrt).collect(), cond))
+}
+
+// Least-squares solution of A·x ≈ b through the normal equations A^T·A·x = A^T·b: the Gram
+// matrix is formed with syrk and Cholesky-factored, costing two passes over A besides the
+// syrk passes. `ill_conditioned` is set when the Gram condition estimate exceeds
+// GRAM_COND_WARNING, in which case tsqr gives a more accurate answer.
+pub fn lstsq_normal(a: &Dense<f64>, b: &[f64]) -> Result<LstsqResult, OoclaError> {
+    if b.len() as u64 != a.num_rows() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), 1),
+            found: (b.len() as u64, 1),
+        });
+    }
+    let (x, residuals, cond) = solve_normal(a, 1, |i, dst| dst[0] = b[i as usize])?;
+    let mut column = vec![0.0; x.num_rows() as usize];
+    for (i, value) in column.iter_mut().enumerate() {
+        *value = x.get(i as u64, 0);
+    }
+    Ok(LstsqResult {
+        x: column,
+        residual_norm: residuals[0],
+        gram_cond_estimate: cond,
+        ill_conditioned: cond > GRAM_COND_WARNING,
+    })
+}
+
+// As lstsq_normal for each column of B at once, sharing the Gram matrix and its factor.
+pub fn lstsq_normal_many(a: &Dense<f64>, b: &Dense<f64>) -> Result<LstsqManyResult, OoclaError> {
+    if b.num_rows() != a.num_rows() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), b.num_cols()),
+            found: (b.num_rows(), b.num_cols()),
+        });
+    }
+    let (x, residual_norms, cond) = solve_normal(a, b.num_cols() as usize, |i, dst| b.read_row(i, dst))?;
+    Ok(LstsqManyResult {
+        x,
+        residual_norms,
+        gram_cond_estimate: cond,
+        ill_conditioned: cond > GRAM_COND_WARNING,
+    })
+}
@@ -0,0 +1,523 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use ops::blas::tile_gemm_acc;
+use ops::simd;
+use ops::triangular::{Side, UpLo, trsm};
+use progress::{Meter, NoProgress, Progress, ProgressUnit};
+use std::{cmp, mem};
+use std::f64;
+use std::path::Path;
+use tile::{DEFAULT_TILE_SIZE, Tile};
+
+const DEFAULT_PANEL_COLS: usize = 64;
+
+fn max_abs<T: SupportedType>(a: &Dense<T>) -> f64 {
+    let minor = a.minor_len() as usize;
+    (0..a.major_len()).fold(0.0, |acc, major| acc.max(simd::max_abs(&a.major_slice(major)[..minor])))
+}
+
+// Unblocked LU with partial pivoting of an in-memory panel whose first row is row `p0` of
+// the matrix. Appends the (global) pivot row chosen for each panel column to `pivots`.
+fn factor_panel(panel: &mut Tile<f64>, p0: u64, tolerance: f64, pivots: &mut Vec<u64>) -> Result<(), OoclaError> {
+    let (height, width) = (panel.rows, panel.cols);
+    for jj in 0..width {
+        let mut best = jj;
+        let mut best_abs = -1.0;
+        for i in jj..height {
+            let v = panel.data[i * width + jj].abs();
+            if v > best_abs {
+                best = i;
+                best_abs = v;
+            }
+        }
+        if best != jj {
+            let (upper, lower) = panel.data.split_at_mut(best * width);
+            upper[jj * width..(jj + 1) * width].swap_with_slice(&mut lower[..width]);
+        }
+        pivots.push(p0 + best as u64);
+        let pivot = panel.data[jj * width + jj];
+        if pivot.is_nan() || pivot.abs() <= tolerance {
+            return Err(OoclaError::Singular { index: p0 + jj as u64 });
+        }
+        let (top, below) = panel.data.split_at_mut((jj + 1) * width);
+        let pivot_row = &top[jj * width..];
+        for row in below.chunks_mut(width) {
+            let l = row[jj] / pivot;
+            row[jj] = l;
+            if l == 0.0 {
+                continue;
+            }
+            for (x, u) in row[jj + 1..].iter_mut().zip(pivot_row[jj + 1..].iter()) {
+                *x -= l * u;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Overwrites a tile of A12 with L11^-1·A12, where L11 is the unit lower triangle at the top
+// of the factored panel.
+fn solve_unit_lower(panel: &Tile<f64>, tile: &mut Tile<f64>) {
+    let (width, cols) = (panel.cols, tile.cols);
+    for r in 1..width {
+        let (solved, rest) = tile.data.split_at_mut(r * cols);
+        let row = &mut rest[..cols];
+        for (k, &l) in panel.row_slice(r)[..r].iter().enumerate() {
+            if l == 0.0 {
+                continue;
+            }
+            for (x, u) in row.iter_mut().zip(solved[k * cols..(k + 1) * cols].iter()) {
+                *x -= l * u;
+            }
+        }
+    }
+}
+
+// Factors P·A = L·U in place using right-looking panels of `panel_cols` columns. L (with an
+// implicit unit diagonal) is stored below the diagonal and U on and above it. Element i of
+// the returned vector is the row that was interchanged with row i at step i, as in LAPACK's
+// getrf but zero-based.
+//
+// The current panel, of (rows - k) x panel_cols elements, is held in memory; the rest of the
+// matrix is touched a tile at a time, so the trailing update keeps at most one tile of each
+// of U12 and A22 resident. Arithmetic is in f64 but every factor is rounded to T as it is
+// stored, so f32 matrices give genuine single-precision factors. A pivot no larger than
+// max(rows, cols) * eps * max|A|, with eps that of T, is treated as singular and reported
+// with its column, in which case A is left partially factored.
+pub fn lu_inplace<T: SupportedType>(a: &mut Dense<T>, panel_cols: usize) -> Result<Vec<u64>, OoclaError> {
+    lu_inplace_progress(a, panel_cols, &NoProgress)
+}
+
+// As lu_inplace, reporting to `progress` the bytes of the trailing matrix, from the panel's
+// diagonal element to the bottom right corner, read and rewritten by each panel step. The
+// step's arithmetic grows with the size of the same block. Cancellation takes effect between
+// panels, but the rows of A have been interchanged by pivots that are lost with the error, so A
+// holds neither the input nor usable factors and must be restored from a copy.
+pub fn lu_inplace_progress<T: SupportedType>(a: &mut Dense<T>, panel_cols: usize, progress: &dyn Progress)
+    -> Result<Vec<u64>, OoclaError> {
+    let tolerance = cmp::max(a.num_rows(), a.num_cols()) as f64 * T::epsilon() * max_abs(a);
+    lu_inplace_within(a, panel_cols, tolerance, progress)
+}
+
+// As lu_inplace_progress, treating a pivot as singular when it is NaN or no larger than
+// `tolerance` in magnitude.
+fn lu_inplace_within<T: SupportedType>(a: &mut Dense<T>, panel_cols: usize, tolerance: f64, progress: &dyn Progress)
+    -> Result<Vec<u64>, OoclaError> {
+    if panel_cols == 0 {
+        return Err(OoclaError::InvalidArgument("panel_cols must be non-zero".to_string()));
+    }
+    let (m, n) = (a.num_rows(), a.num_cols());
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("lu", m, n, panel_cols).entered();
+    let steps = cmp::min(m, n);
+    let tile_size = DEFAULT_TILE_SIZE as u64;
+    let mut pivots = Vec::with_capacity(steps as usize);
+    let step_bytes = |p0: u64| (m - p0) * (n - p0) * mem::size_of::<T>() as u64;
+    let total = (0..steps).step_by(panel_cols).map(step_bytes).sum();
+    let mut meter = Meter::new(progress, Some(total), ProgressUnit::Bytes);
+    let mut p0 = 0;
+    while p0 < steps {
+        let width = cmp::min(panel_cols as u64, steps - p0) as usize;
+        let mut panel = a.read_tile(p0, p0, (m - p0) as usize, width).convert::<f64>();
+        factor_panel(&mut panel, p0, tolerance, &mut pivots)?;
+        let stored = panel.convert::<T>();
+        // Carry on with the factors as stored so that the updates below are consistent with them.
+        let panel: Tile<f64> = stored.convert();
+        // The panel already holds its rows in pivoted order, so swapping whole rows and then
+        // writing the panel back leaves every column correctly permuted.
+        for k in p0..p0 + width as u64 {
+            a.swap_rows(k, pivots[k as usize]);
+        }
+        a.write_tile(&stored);
+        let rest = p0 + width as u64;
+        let mut j0 = rest;
+        while j0 < n {
+            let cols = cmp::min(tile_size, n - j0) as usize;
+            let mut u = a.read_tile(p0, j0, width, cols).convert::<f64>();
+            solve_unit_lower(&panel, &mut u);
+            let stored = u.convert::<T>();
+            a.write_tile(&stored);
+            let u: Tile<f64> = stored.convert();
+            let mut i0 = rest;
+            while i0 < m {
+                let rows = cmp::min(tile_size, m - i0) as usize;
+                let offset = (i0 - p0) as usize * width;
+                let l = Tile {
+                    row: i0,
+                    col: p0,
+                    rows,
+                    cols: width,
+                    data: panel.data[offset..offset + rows * width].to_vec(),
+                };
+                let mut c = a.read_tile(i0, j0, rows, cols).convert::<f64>();
+                tile_gemm_acc(-1.0, &l, &u, &mut c.data);
+                a.write_tile(&c.convert::<T>());
+                i0 += rows as u64;
+            }
+            j0 += cols as u64;
+        }
+        meter.advance(step_bytes(p0))?;
+        p0 = rest;
+    }
+    meter.finish();
+    Ok(pivots)
+}
+
+fn check_square<T>(a: &Dense<T>) -> Result<(), OoclaError> {
+    if a.num_rows() != a.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), a.num_rows()),
+            found: (a.num_rows(), a.num_cols()),
+        });
+    }
+    Ok(())
+}
+
+// The factors P·A = L·U of a square matrix as produced by lu_inplace, for reuse across solves.
+// Solves take and return f64 vectors whatever the storage type of the factors.
+pub struct LuFactors<T = f64> {
+    pub lu: Dense<T>,
+    pub pivots: Vec<u64>,
+}
+
+impl<T: SupportedType> LuFactors<T> {
+    // Factors a copy of A, leaving A itself untouched.
+    pub fn new(a: &Dense<T>) -> Result<LuFactors<T>, OoclaError> {
+        check_square(a)?;
+        let mut lu = a.copy_at(None)?;
+        let pivots = lu_inplace(&mut lu, DEFAULT_PANEL_COLS)?;
+        Ok(LuFactors { lu, pivots })
+    }
+
+    // As new, but only a pivot that is exactly zero (or NaN) is singular.
+    fn new_exact(a: &Dense<T>) -> Result<LuFactors<T>, OoclaError> {
+        check_square(a)?;
+        let mut lu = a.copy_at(None)?;
+        let pivots = lu_inplace_within(&mut lu, DEFAULT_PANEL_COLS, 0.0, &NoProgress)?;
+        Ok(LuFactors { lu, pivots })
+    }
+
+    pub fn order(&self) -> u64 {
+        self.lu.num_rows()
+    }
+
+    fn read_row(&self, i: usize, stored: &mut [T], row: &mut [f64]) {
+        self.lu.read_row(i as u64, stored);
+        for (dst, src) in row.iter_mut().zip(stored.iter()) {
+            *dst = src.to_f64();
+        }
+    }
+
+    fn check_length(&self, b: &[f64]) -> Result<(), OoclaError> {
+        if b.len() as u64 != self.order() {
+            return Err(OoclaError::ShapeMismatch {
+                expected: (self.order(), 1),
+                found: (b.len() as u64, 1),
+            });
+        }
+        Ok(())
+    }
+
+    // Overwrites b with the solution of A·x = b, reading the factors one row at a time.
+    pub fn solve(&self, b: &mut [f64]) -> Result<(), OoclaError> {
+        self.check_length(b)?;
+        let n = b.len();
+        for (i, &p) in self.pivots.iter().enumerate() {
+            b.swap(i, p as usize);
+        }
+        let mut stored = vec![T::from_f64(0.0); n];
+        let mut row = vec![0.0; n];
+        for i in 0..n {
+            self.read_row(i, &mut stored, &mut row);
+            let dot: f64 = row[..i].iter().zip(b[..i].iter()).map(|(l, x)| l * x).sum();
+            b[i] -= dot;
+        }
+        for i in (0..n).rev() {
+            self.read_row(i, &mut stored, &mut row);
+            let dot: f64 = row[i + 1..].iter().zip(b[i + 1..].iter()).map(|(u, x)| u * x).sum();
+            b[i] = (b[i] - dot) / row[i];
+        }
+        Ok(())
+    }
+
+    // Overwrites b with the solution of A^T·x = b. A^T = U^T·L^T·P, so the triangular solves
+    // run in the opposite order, each a row of the factors at a time.
+    pub fn solve_transposed(&self, b: &mut [f64]) -> Result<(), OoclaError> {
+        self.check_length(b)?;
+        let n = b.len();
+        let mut stored = vec![T::from_f64(0.0); n];
+        let mut row = vec![0.0; n];
+        for i in 0..n {
+            self.read_row(i, &mut stored, &mut row);
+            b[i] /= row[i];
+            let xi = b[i];
+            for (x, u) in b[i + 1..].iter_mut().zip(row[i + 1..].iter()) {
+                *x -= u * xi;
+            }
+        }
+        for i in (0..n).rev() {
+            self.read_row(i, &mut stored, &mut row);
+            let xi = b[i];
+            for (x, l) in b[..i].iter_mut().zip(row[..i].iter()) {
+                *x -= l * xi;
+            }
+        }
+        for (i, &p) in self.pivots.iter().enumerate().rev() {
+            b.swap(i, p as usize);
+        }
+        Ok(())
+    }
+}
+
+// Writes A^-1 to `dst` by factoring a copy of A and solving L·U·X = P against the identity.
+pub fn inverse(a: &Dense<f64>, dst: &Path) -> Result<Dense<f64>, OoclaError> {
+    let factors = LuFactors::new(a)?;
+    let n = factors.order();
+    let mut x = Dense::create(dst, n, n)?;
+    for i in 0..n {
+        x.set(i, i, 1.0);
+    }
+    for (i, &p) in factors.pivots.iter().enumerate() {
+        x.swap_rows(i as u64, p);
+    }
+    trsm(Side::Left, UpLo::Lower, false, true, 1.0, &factors.lu, &mut x, DEFAULT_PANEL_COLS)?;
+    trsm(Side::Left, UpLo::Upper, false, false, 1.0, &factors.lu, &mut x, DEFAULT_PANEL_COLS)?;
+    Ok(x)
+}
+
+// Returns (sign, ln|det A|), which stays representable when det A itself would overflow or
+// underflow. Small pivots are kept rather than judged singular, since a tiny determinant may be
+// exact, as for diag(1, 1e-20); only an exactly zero pivot gives (0, -inf).
+pub fn log_determinant(a: &Dense<f64>) -> Result<(f64, f64), OoclaError> {
+    let factors = match LuFactors::new_exact(a) {
+        Ok(factors) => factors,
+        Err(OoclaError::Singular { .. }) => return Ok((0.0, f64::NEG_INFINITY)),
+        Err(e) => return Err(e),
+    };
+    let mut sign = 1.0;
+    for (i, &p) in factors.pivots.iter().enumerate() {
+        if p != i as u64 {
+            sign = -sign;
+        }
+    }
+    let mut log_abs = 0.0;
+    for &u in factors.lu.diagonal_iter() {
+        if u < 0.0 {
+            sign = -sign;
+        }
+        log_abs += u.abs().ln();
+    }
+    Ok((sign, log_abs))
+}
+
+// The determinant of A, which may be infinite or zero where log_determinant is not.
+pub fn determinant(a: &Dense<f64>) -> Result<f64, OoclaError> {
+    let (sign, log_abs) = log_determinant(a)?;
+    Ok(sign * log_abs.exp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use ops::{gemm, gemv};
+
+    // Applies the interchanges in `pivots` to the rows of a copy of `a`, giving P·A.
+    fn permuted(a: &Dense<f64>, pivots: &[u64]) -> Dense<f64> {
+        let mut pa = a.copy_at(None).unwrap();
+        for (i, &p) in pivots.iter().enumerate() {
+            pa.swap_rows(i as u64, p);
+        }
+        pa
+    }
+
+    // L·(U·x) from packed factors of an m x n matrix.
+    fn lu_times(lu: &Dense<f64>, x: &[f64]) -> Vec<f64> {
+        let (m, n) = (lu.num_rows(), lu.num_cols());
+        let k = cmp::min(m, n);
+        let ux: Vec<f64> = (0..k).map(|i| (i..n).map(|j| lu.get(i, j) * x[j as usize]).sum()).collect();
+        (0..m).map(|i| {
+            let below: f64 = (0..cmp::min(i, k)).map(|j| lu.get(i, j) * ux[j as usize]).sum();
+            below + if i < k { ux[i as usize] } else { 0.0 }
+        }).collect()
+    }
+
+    // The largest |(P·A - L·U)·x| over a few probe vectors, relative to max|A|·|x|.
+    fn probe_residual(a: &Dense<f64>, lu: &Dense<f64>, pivots: &[u64]) -> f64 {
+        let pa = permuted(a, pivots);
+        let n = a.num_cols() as usize;
+        let scale = max_abs(a);
+        let mut worst: f64 = 0.0;
+        for probe in 0..3 {
+            let x: Vec<f64> = (0..n).map(|j| ((j * 7 + probe * 13) % 17) as f64 / 17.0 - 0.5).collect();
+            let mut pax = vec![0.0; a.num_rows() as usize];
+            gemv(1.0, &pa, &x, 0.0, &mut pax).unwrap();
+            let norm = x.iter().map(|v| v.abs()).sum::<f64>();
+            for (p, q) in pax.iter().zip(lu_times(lu, &x).iter()) {
+                worst = worst.max((p - q).abs() / (scale * norm));
+            }
+        }
+        worst
+    }
+
+    #[test]
+    fn factors_reconstruct_the_permuted_matrix() {
+        for &(m, n, panel_cols) in &[(1, 1, 4), (12, 12, 5), (40, 40, 64), (37, 23, 6), (23, 37, 6)] {
+            let mut a = Dense::<f64>::create_anonymous(m, n).unwrap();
+            a.randomise_seeded(m * 100 + n);
+            let mut lu = a.copy_at(None).unwrap();
+            let pivots = lu_inplace(&mut lu, panel_cols).unwrap();
+            assert_eq!(pivots.len() as u64, cmp::min(m, n));
+            let pa = permuted(&a, &pivots);
+            let k = cmp::min(m, n);
+            for i in 0..m {
+                for j in 0..n {
+                    let reconstructed: f64 = (0..cmp::min(k, i + 1)).filter(|&p| p <= j)
+                        .map(|p| if p == i { lu.get(p, j) } else { lu.get(i, p) * lu.get(p, j) }).sum();
+                    assert!((reconstructed - pa.get(i, j)).abs() < 1e-12, "{}x{} at ({}, {})", m, n, i, j);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pivots_are_chosen_by_magnitude() {
+        let rows = [[1.0, 2.0], [-4.0, 1.0]];
+        let mut a = Dense::<f64>::anonymous_from_fn(2, 2, |i, j| rows[i as usize][j as usize]);
+        let pivots = lu_inplace(&mut a, 1).unwrap();
+        assert_eq!(pivots, vec![1, 1]);
+        assert_eq!([a.get(0, 0), a.get(0, 1), a.get(1, 0), a.get(1, 1)], [-4.0, 1.0, -0.25, 2.25]);
+    }
+
+    #[test]
+    fn f32_factors_are_accurate_to_single_precision() {
+        let mut a = Dense::<f32>::create_anonymous(30, 30).unwrap();
+        a.randomise_seeded(9);
+        let a64 = Dense::<f64>::anonymous_from_fn(30, 30, |i, j| a.get(i, j) as f64);
+        let pivots = lu_inplace(&mut a, 7).unwrap();
+        let lu = Dense::<f64>::anonymous_from_fn(30, 30, |i, j| a.get(i, j) as f64);
+        let residual = probe_residual(&a64, &lu, &pivots);
+        assert!(residual < 1e-5, "residual {}", residual);
+    }
+
+    // A is about twice the panel and the tiles of U12 and A22 that are resident at once, so the
+    // trailing updates have to stream it through several tiles in each direction.
+    #[test]
+    fn factoring_a_matrix_larger_than_the_resident_tiles() {
+        let (n, panel_cols) = (1100u64, 16);
+        let resident = (n as usize * panel_cols + panel_cols * DEFAULT_TILE_SIZE + 2 * DEFAULT_TILE_SIZE * DEFAULT_TILE_SIZE)
+            * mem::size_of::<f64>();
+        assert!((n * n) as usize * mem::size_of::<f64>() > 2 * resident);
+        let mut a = Dense::<f64>::create_anonymous(n, n).unwrap();
+        a.randomise_seeded(11);
+        let mut lu = a.copy_at(None).unwrap();
+        let pivots = lu_inplace(&mut lu, panel_cols).unwrap();
+        let residual = probe_residual(&a, &lu, &pivots);
+        assert!(residual < 1e-12, "residual {}", residual);
+    }
+
+    #[test]
+    fn singular_matrices_are_reported_with_their_column() {
+        // The third column is the sum of the first two.
+        let mut a = Dense::<f64>::anonymous_from_fn(4, 4, |i, j| {
+            let (x, y) = ((i * i + 1) as f64, (3 * i + 2) as f64);
+            [x, y, x + y, (i as f64).sin()][j as usize]
+        });
+        match lu_inplace(&mut a, 2) {
+            Err(OoclaError::Singular { index }) => assert_eq!(index, 2),
+            other => panic!("expected a singular matrix, got {:?}", other),
+        }
+        let mut zero = Dense::<f64>::create_anonymous(3, 3).unwrap();
+        match lu_inplace(&mut zero, 8) {
+            Err(OoclaError::Singular { index }) => assert_eq!(index, 0),
+            other => panic!("expected a singular matrix, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lu_factors_solve_both_systems() {
+        let mut a = Dense::<f64>::create_anonymous(25, 25).unwrap();
+        a.randomise_seeded(3);
+        let factors = LuFactors::new(&a).unwrap();
+        let x: Vec<f64> = (0..25).map(|i| i as f64 - 12.0).collect();
+        let mut b = vec![0.0; 25];
+        gemv(1.0, &a, &x, 0.0, &mut b).unwrap();
+        factors.solve(&mut b).unwrap();
+        for (found, expected) in b.iter().zip(x.iter()) {
+            assert!((found - expected).abs() < 1e-9);
+        }
+        let at = Dense::<f64>::anonymous_from_fn(25, 25, |i, j| a.get(j, i));
+        let mut b = vec![0.0; 25];
+        gemv(1.0, &at, &x, 0.0, &mut b).unwrap();
+        factors.solve_transposed(&mut b).unwrap();
+        for (found, expected) in b.iter().zip(x.iter()) {
+            assert!((found - expected).abs() < 1e-9);
+        }
+        assert!(factors.solve(&mut [0.0; 3]).is_err());
+    }
+
+    fn diagonal(values: &[f64]) -> Dense<f64> {
+        let n = values.len() as u64;
+        Dense::anonymous_from_fn(n, n, |i, j| if i == j { values[i as usize] } else { 0.0 })
+    }
+
+    #[test]
+    fn inverse_of_a_well_conditioned_matrix() {
+        let n = 40;
+        let mut noise = Dense::<f64>::create_anonymous(n, n).unwrap();
+        noise.randomise_seeded(21);
+        let a = Dense::<f64>::anonymous_from_fn(n, n, |i, j| noise.get(i, j) - 0.5 + if i == j { 4.0 } else { 0.0 });
+        let dst = TempMatrixPath::new();
+        let inv = inverse(&a, dst.path()).unwrap();
+        let mut product = Dense::<f64>::create_anonymous(n, n).unwrap();
+        gemm(1.0, &a, &inv, 0.0, &mut product, 16).unwrap();
+        let mut worst: f64 = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                worst = worst.max((product.get(i, j) - if i == j { 1.0 } else { 0.0 }).abs());
+            }
+        }
+        assert!(worst < 1e-12, "|A·inv(A) - I| = {}", worst);
+    }
+
+    #[test]
+    fn determinant_of_a_known_matrix() {
+        // det = 2·(3·4 - 1·0) - 1·(1·4 - 1·2) + 5·(1·0 - 3·2) = 24 - 2 - 30 = -8, and the first
+        // pivot comes from the last row.
+        let rows = [[2.0, 1.0, 5.0], [1.0, 3.0, 1.0], [2.0, 0.0, 4.0]];
+        let a = Dense::<f64>::anonymous_from_fn(3, 3, |i, j| rows[i as usize][j as usize]);
+        assert!((determinant(&a).unwrap() + 8.0).abs() < 1e-12);
+        let (sign, log_abs) = log_determinant(&a).unwrap();
+        assert_eq!(sign, -1.0);
+        assert!((log_abs - 8f64.ln()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn determinant_that_overflows_f64_is_finite_in_log_space() {
+        let a = diagonal(&[1e200, -1e200, 1e200, 3.0]);
+        let (sign, log_abs) = log_determinant(&a).unwrap();
+        assert_eq!(sign, -1.0);
+        assert!((log_abs - (600.0 * 10f64.ln() + 3f64.ln())).abs() < 1e-9);
+        assert_eq!(determinant(&a).unwrap(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn tiny_pivots_are_not_mistaken_for_singularity() {
+        let a = diagonal(&[1.0, 1e-20]);
+        assert!((determinant(&a).unwrap() / 1e-20 - 1.0).abs() < 1e-12);
+        let (sign, log_abs) = log_determinant(&diagonal(&[1e-300, 1e-300, 1e-300])).unwrap();
+        assert_eq!(sign, 1.0);
+        assert!((log_abs + 900.0 * 10f64.ln()).abs() < 1e-9);
+        // The factorisation itself still judges the same matrix singular to working precision.
+        assert!(LuFactors::new(&a).is_err());
+    }
+
+    #[test]
+    fn exactly_singular_matrices_have_zero_determinant() {
+        let rows = [[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 1.0, 1.0]];
+        let a = Dense::<f64>::anonymous_from_fn(3, 3, |i, j| rows[i as usize][j as usize]);
+        assert_eq!(log_determinant(&a).unwrap(), (0.0, f64::NEG_INFINITY));
+        assert_eq!(determinant(&a).unwrap(), 0.0);
+        assert!(determinant(&Dense::<f64>::create_anonymous(2, 3).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+mod blas;
+mod broadcast;
+mod cg;
+mod cholesky;
+mod clip;
+mod columns;
+mod compose;
+mod cond;
+mod convert;
+mod dedup;
+mod distance;
+mod eigen;
+mod elementwise;
+mod filter;
+mod finite;
+pub(crate) mod hash;
+mod histogram;
+mod kmeans;
+mod knn;
+mod lanczos;
+mod lstsq;
+mod lu;
+mod one_hot;
+mod pca;
+mod qr;
+mod quantile;
+mod quantize;
+mod reduce;
+mod refine;
+pub(crate) mod rng;
+mod rowwise;
+mod sample;
+mod shard;
+mod shuffle;
+mod similarity;
+pub(crate) mod simd;
+mod sketch;
+mod sort;
+mod split;
+mod stats;
+mod svd;
+mod symeig;
+mod topk;
+mod transpose;
+mod triangular;
+
+pub use self::blas::{gemm, gemm_progress, gemm_tuned, gemm_with, gemv, ger, rank_k_update, syrk, syrk_tuned, syrk_with};
+pub(crate) use self::blas::{default_block_rows, gemm_footprint, syrk_footprint};
+#[cfg(feature = "rayon")]
+pub use self::blas::{par_gemm, par_gemm_with};
+pub use self::broadcast::{add_col_vector, add_row_vector, div_col_vector, div_row_vector, mul_col_vector,
+                          mul_row_vector, sub_col_vector, sub_row_vector};
+pub use self::cg::{CgResult, conjugate_gradient};
+pub use self::cholesky::{cholesky_inplace, cholesky_inplace_progress};
+pub use self::clip::{ClipCounts, clip, winsorize};
+#[cfg(feature = "rayon")]
+pub use self::clip::par_clip;
+pub use self::columns::{drop_columns, select_columns};
+pub use self::compose::{hconcat, kron, vconcat};
+pub use self::cond::{cond_estimate, cond_estimate_factored};
+pub use self::convert::to_csr;
+pub use self::dedup::{DedupReport, dedup_rows};
+pub use self::distance::{Metric, pairwise_distances};
+pub use self::eigen::{power_iteration, power_iteration_deflated};
+pub use self::elementwise::{MathFunc, apply, apply_into};
+#[cfg(feature = "rayon")]
+pub use self::elementwise::par_apply;
+pub use self::filter::{filter_rows, partition_rows};
+pub use self::finite::{NONFINITE_REPORT_LOCATIONS, NonFiniteReport, count_nonfinite, drop_rows_with_nonfinite,
+                       replace_nonfinite};
+pub use self::histogram::{Histogram, HistogramBins, histogram};
+#[cfg(feature = "rayon")]
+pub use self::histogram::par_histogram;
+pub use self::kmeans::{KmeansInit, KmeansResult, kmeans};
+pub use self::knn::{Neighbours, knn};
+pub use self::lanczos::{Tridiag, lanczos};
+pub use self::lstsq::{GRAM_COND_WARNING, LstsqManyResult, LstsqResult, lstsq_normal, lstsq_normal_many};
+pub use self::lu::{LuFactors, determinant, inverse, log_determinant, lu_inplace, lu_inplace_progress};
+pub use self::one_hot::{OneHotPlacement, one_hot};
+pub use self::pca::{PCA_DENSE_MAX_COLS, Pca, pca};
+pub use self::qr::tsqr;
+pub use self::quantile::{quantile_sketch, quantiles, quantiles_exact};
+pub use self::quantize::{QuantParams, QuantScheme, RoundTripError, dequantize, quantize_i8};
+pub use self::reduce::{NormKind, diag_dot, trace};
+pub use self::refine::{RefinedSolve, solve_refined};
+pub use self::rowwise::{log_softmax_rows, log_softmax_rows_into, normalize_cols, normalize_cols_to, normalize_rows,
+                        normalize_rows_to, softmax_rows, softmax_rows_into};
+pub use self::sample::{SampleSize, StratifiedSample, StratumSample, sample_rows, stratified_sample};
+pub use self::shard::{Shards, shard_by_rows, shard_rows};
+pub use self::shuffle::{shuffle_rows, shuffle_rows_inplace, shuffle_rows_progress};
+pub use self::similarity::{SimilarRows, cosine_similarity, top_k_similar};
+pub use self::simd::{axpy, dot, max_abs, scale, sum};
+pub use self::sketch::{DEFAULT_SKETCH_SIZE, QuantileSketch};
+pub use self::sort::{DEFAULT_SORT_BUDGET_BYTES, sort_rows_by_column, sort_rows_by_column_budgeted,
+                     sort_rows_by_column_progress, sort_rows_by_column_with};
+pub use self::split::{StratumCounts, TrainTestSplit, train_test_split};
+pub use self::stats::{ColumnStats, Correlation, apply_standardization, column_stats, correlation, covariance, standardize};
+pub use self::svd::{Svd, randomized_svd};
+pub use self::transpose::{transpose_inplace, transpose_inplace_tuned};
+pub(crate) use self::transpose::DEFAULT_TRANSPOSE_BLOCK;
+pub use self::triangular::{Side, UpLo, trsm};
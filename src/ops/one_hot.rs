@@ -0,0 +1,161 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use row_writer::RowWriter;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OneHotPlacement {
+    // The indicator columns take the place of the label column.
+    Replace,
+    // The label column is kept and the indicator columns follow the last column.
+    Append,
+}
+
+// The label in `value`, which must be a non-negative integer below `k` if that is given.
+fn label(row: u64, value: f64, k: Option<u64>) -> Result<u64, OoclaError> {
+    let valid = value >= 0.0 && value.fract() == 0.0 && value < u64::MAX as f64
+        && k.is_none_or(|k| value < k as f64);
+    if !valid {
+        return Err(OoclaError::InvalidLabel { row, value });
+    }
+    Ok(value as u64)
+}
+
+// Writes a copy of A in which column `col`, holding labels in [0, k), is expanded into k columns
+// of which the one for each row's label is 1 and the rest 0. When `k` is None it is one more than
+// the largest label, found in a first pass; otherwise the rows are streamed once. A label that
+// is not an integer in range fails with InvalidLabel naming its row.
+pub fn one_hot<T: SupportedType>(a: &Dense<T>, col: u64, dst: &Path, k: Option<u64>, placement: OneHotPlacement)
+    -> Result<Dense<T>, OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols());
+    if col >= d {
+        return Err(OoclaError::InvalidArgument(format!("label column {} is out of range for a matrix with {} columns",
+                                                       col, d)));
+    }
+    let k = match k {
+        Some(k) => k,
+        None => {
+            let mut k = 0;
+            for i in 0..n {
+                k = k.max(label(i, a.get(i, col).to_f64(), None)? + 1);
+            }
+            k
+        }
+    };
+    let kept = match placement {
+        OneHotPlacement::Replace => d - 1,
+        OneHotPlacement::Append => d,
+    };
+    let width = kept.checked_add(k)
+        .ok_or_else(|| OoclaError::SizeOverflow(format!("{} columns plus {} indicator columns", kept, k)))?;
+    Dense::<T>::compute_length(n, width)?;
+    let (col, width) = (col as usize, width as usize);
+    let indicators = match placement {
+        OneHotPlacement::Replace => col,
+        OneHotPlacement::Append => d as usize,
+    };
+    let mut writer = RowWriter::create(dst, width as u64)?;
+    let mut scratch = vec![T::default(); d as usize];
+    let mut out = vec![T::from_f64(0.0); width];
+    for i in 0..n {
+        let row = a.row_view(i, &mut scratch);
+        let class = label(i, row[col].to_f64(), Some(k))? as usize;
+        match placement {
+            OneHotPlacement::Replace => {
+                out[..col].copy_from_slice(&row[..col]);
+                out[col + k as usize..].copy_from_slice(&row[col + 1..]);
+            }
+            OneHotPlacement::Append => out[..d as usize].copy_from_slice(row),
+        }
+        for x in out[indicators..indicators + k as usize].iter_mut() {
+            *x = T::from_f64(0.0);
+        }
+        out[indicators + class] = T::from_f64(1.0);
+        writer.write_row(&out)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    const LABELS: [f64; 6] = [2.0, 0.0, 1.0, 2.0, 0.0, 2.0];
+
+    // Rows of a 6x3 matrix whose middle column holds the labels above.
+    fn labelled() -> Dense<f64> {
+        Dense::anonymous_from_fn(6, 3, |i, j| if j == 1 { LABELS[i as usize] } else { (10 * i + j) as f64 })
+    }
+
+    fn rows(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        a.row_iter().map(|r| r.to_vec()).collect()
+    }
+
+    fn indicator(label: f64, k: usize) -> Vec<f64> {
+        (0..k).map(|c| if c as f64 == label { 1.0 } else { 0.0 }).collect()
+    }
+
+    #[test]
+    fn replace_puts_indicators_where_the_labels_were() {
+        let dst = TempMatrixPath::new();
+        let b = one_hot(&labelled(), 1, dst.path(), None, OneHotPlacement::Replace).unwrap();
+        assert_eq!((b.num_rows(), b.num_cols()), (6, 5));
+        for (i, row) in rows(&b).into_iter().enumerate() {
+            let mut expected = vec![(10 * i) as f64];
+            expected.extend(indicator(LABELS[i], 3));
+            expected.push((10 * i + 2) as f64);
+            assert_eq!(row, expected, "row {}", i);
+        }
+    }
+
+    #[test]
+    fn append_keeps_the_labels_and_honours_a_given_k() {
+        let dst = TempMatrixPath::new();
+        let a = labelled();
+        let b = one_hot(&a, 1, dst.path(), Some(5), OneHotPlacement::Append).unwrap();
+        assert_eq!((b.num_rows(), b.num_cols()), (6, 8));
+        for (i, (row, original)) in rows(&b).into_iter().zip(rows(&a)).enumerate() {
+            let mut expected = original;
+            expected.extend(indicator(LABELS[i], 5));
+            assert_eq!(row, expected, "row {}", i);
+        }
+    }
+
+    #[test]
+    fn transposed_inputs_and_a_last_label_column_are_handled() {
+        let dst = TempMatrixPath::new();
+        let mut a = Dense::<f32>::anonymous_from_fn(2, 4, |i, j| if i == 1 { (j % 2) as f32 } else { j as f32 });
+        a.transpose();
+        let b = one_hot(&a, 1, dst.path(), None, OneHotPlacement::Replace).unwrap();
+        assert_eq!((b.num_rows(), b.num_cols()), (4, 3));
+        let expected = [[0.0, 1.0, 0.0], [1.0, 0.0, 1.0], [2.0, 1.0, 0.0], [3.0, 0.0, 1.0]];
+        for (i, row) in b.row_iter().enumerate() {
+            assert_eq!(&*row, &expected[i][..], "row {}", i);
+        }
+    }
+
+    #[test]
+    fn bad_labels_are_rejected_with_their_row() {
+        let cases: [(f64, Option<u64>); 6] =
+            [(3.0, Some(3)), (1.5, None), (1.5, Some(3)), (-1.0, None), (f64::NAN, None), (f64::INFINITY, None)];
+        for &(bad, k) in cases.iter() {
+            let a = Dense::anonymous_from_fn(5, 2, |i, j| if i == 3 && j == 0 { bad } else { (i % 2) as f64 });
+            let dst = TempMatrixPath::new();
+            match one_hot(&a, 0, dst.path(), k, OneHotPlacement::Replace) {
+                Err(OoclaError::InvalidLabel { row: 3, value }) if value.to_bits() == bad.to_bits() => {}
+                other => panic!("expected label {} to be rejected, got {:?}", bad, other.map(|_| ())),
+            }
+            assert!(!dst.path().exists(), "a partial output was left for label {}", bad);
+        }
+    }
+
+    #[test]
+    fn label_column_must_exist() {
+        let dst = TempMatrixPath::new();
+        match one_hot(&labelled(), 3, dst.path(), None, OneHotPlacement::Append) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected an invalid argument, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::tile_gemm_acc;
+use ops::stats::{column_stats, covariance_with_means};
+use ops::svd::randomized_svd;
+use ops::symeig::symmetric_eigen;
+use std::cmp;
+use std::path::Path;
+use tile::{DEFAULT_TILE_SIZE, Tile};
+
+// The widest matrix whose d x d covariance is eigendecomposed in memory. Wider matrices go
+// through the randomized SVD of the centred data instead.
+pub const PCA_DENSE_MAX_COLS: u64 = 1024;
+
+const PCA_OVERSAMPLE: usize = 10;
+const PCA_POWER_ITERS: usize = 2;
+
+pub struct Pca {
+    // One principal axis per row, in decreasing order of explained variance. Each is scaled to
+    // unit length and signed so that its largest-magnitude entry is positive.
+    pub components: Dense<f64>,
+    // The variance of the data along each axis, with the sample (n - 1) normalisation.
+    pub explained_variance: Vec<f64>,
+    // The explained variances as fractions of the total variance.
+    pub explained_variance_ratio: Vec<f64>,
+    pub means: Vec<f64>,
+}
+
+fn fix_sign(axis: &mut [f64]) {
+    let largest = axis.iter().cloned().fold(0.0, |best: f64, x| if x.abs() > best.abs() { x } else { best });
+    if largest < 0.0 {
+        for x in axis.iter_mut() {
+            *x = -*x;
+        }
+    }
+}
+
+struct Axes {
+    axes: Vec<Vec<f64>>,
+    variances: Vec<f64>,
+    total_variance: f64,
+    means: Vec<f64>,
+}
+
+// The leading axes and their variances from the in-memory eigendecomposition of the covariance.
+fn dense_axes(a: &Dense<f64>, components: usize) -> Result<Axes, OoclaError> {
+    let d = a.num_cols() as usize;
+    let (cov, means) = covariance_with_means(a, 1, None)?;
+    let mut m = vec![0.0; d * d];
+    for (i, row) in m.chunks_mut(d).enumerate() {
+        cov.read_row(i as u64, row);
+    }
+    let total = (0..d).map(|i| m[i * d + i]).sum();
+    let (values, vectors) = symmetric_eigen(&m, d);
+    let axes = (0..components).map(|j| vectors[(d - 1 - j) * d..(d - j) * d].to_vec()).collect();
+    // Rounding can leave the eigenvalues of a singular covariance slightly negative.
+    let variances = (0..components).map(|j| values[d - 1 - j].max(0.0)).collect();
+    Ok(Axes { axes, variances, total_variance: total, means })
+}
+
+// As dense_axes, but from the randomized SVD of a centred copy of A, which takes a further n x d
+// of scratch space on disk but never forms the covariance.
+fn randomized_axes(a: &Dense<f64>, components: usize) -> Result<Axes, OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols());
+    let stats = column_stats(a, 1)?;
+    let mut centred = Dense::create_anonymous(n, d)?;
+    let mut row = vec![0.0; d as usize];
+    for i in 0..n {
+        a.read_row(i, &mut row);
+        for (x, mean) in row.iter_mut().zip(stats.means.iter()) {
+            *x -= mean;
+        }
+        centred.write_row(i, &row);
+    }
+    let svd = randomized_svd(&centred, components, PCA_OVERSAMPLE, PCA_POWER_ITERS)?;
+    let axes = (0..components as u64).map(|j| {
+        let mut axis = vec![0.0; d as usize];
+        svd.vt.read_row(j, &mut axis);
+        axis
+    }).collect();
+    let variances = svd.s.iter().map(|s| s * s / (n - 1) as f64).collect();
+    let total = stats.stds.iter().map(|s| s * s).sum();
+    Ok(Axes { axes, variances, total_variance: total, means: stats.means })
+}
+
+// Principal component analysis of the rows of A. Up to PCA_DENSE_MAX_COLS columns the
+// covariance comes from one streaming syrk pass and is eigendecomposed in memory; beyond that
+// the leading axes are found by randomized SVD, which is approximate and not reproducible
+// from run to run.
+pub fn pca(a: &Dense<f64>, components: usize) -> Result<Pca, OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols());
+    if n < 2 {
+        return Err(OoclaError::InvalidArgument(format!("{} rows is too few for PCA", n)));
+    }
+    let max_components = cmp::min(n, d);
+    if components == 0 || components as u64 > max_components {
+        return Err(OoclaError::InvalidArgument(format!("components must be in 1..={} for a {}x{} matrix",
+                                                       max_components, n, d)));
+    }
+    let Axes { mut axes, variances: explained_variance, total_variance, means } = if d <= PCA_DENSE_MAX_COLS {
+        dense_axes(a, components)?
+    } else {
+        randomized_axes(a, components)?
+    };
+    let mut result = Dense::create_anonymous(components as u64, d)?;
+    for (j, axis) in axes.iter_mut().enumerate() {
+        fix_sign(axis);
+        result.write_row(j as u64, axis);
+    }
+    // Rounding can put the trace a hair below the sum of the eigenvalues it should bound.
+    let total = explained_variance.iter().sum::<f64>().max(total_variance);
+    let explained_variance_ratio = explained_variance.iter()
+        .map(|v| if total > 0.0 { v / total } else { 0.0 })
+        .collect();
+    Ok(Pca {
+        components: result,
+        explained_variance,
+        explained_variance_ratio,
+        means,
+    })
+}
+
+impl Pca {
+    pub fn num_components(&self) -> u64 {
+        self.components.num_rows()
+    }
+
+    // Writes the projection of the centred rows of A onto the principal axes to `dst`, as an
+    // n x components matrix. Tiles of A are centred as they are read, so large means cost no
+    // precision, and multiplied by tiles of the transposed axes.
+    pub fn transform(&self, a: &Dense<f64>, dst: &Path) -> Result<Dense<f64>, OoclaError> {
+        let (n, d) = (a.num_rows(), a.num_cols());
+        if d != self.components.num_cols() {
+            return Err(OoclaError::ShapeMismatch {
+                expected: (n, self.components.num_cols()),
+                found: (n, d),
+            });
+        }
+        let p = self.num_components() as usize;
+        let mut out = Dense::create(dst, n, p as u64)?;
+        let step = DEFAULT_TILE_SIZE as u64;
+        for i0 in (0..n).step_by(DEFAULT_TILE_SIZE) {
+            let rows = cmp::min(step, n - i0) as usize;
+            let mut acc = Tile::zeros(i0, 0, rows, p);
+            for k0 in (0..d).step_by(DEFAULT_TILE_SIZE) {
+                let depth = cmp::min(step, d - k0) as usize;
+                let mut x = a.read_tile(i0, k0, rows, depth);
+                for row in x.data.chunks_mut(depth) {
+                    for (v, mean) in row.iter_mut().zip(self.means[k0 as usize..].iter()) {
+                        *v -= mean;
+                    }
+                }
+                let axes = self.components.read_tile(0, k0, p, depth).transposed();
+                tile_gemm_acc(1.0, &x, &axes, &mut acc.data);
+            }
+            out.write_tile(&acc);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use ops::rng::{gaussian_vector, seeded_rng};
+
+    const STD_DEVS: [f64; 3] = [5.0, 2.0, 0.5];
+    const MEANS: [f64; 3] = [10.0, -3.0, 7.0];
+
+    // The true principal axes, a rotation by 30 degrees about the third coordinate. Each has a
+    // unique largest-magnitude entry, which is positive, so fix_sign leaves it be.
+    fn axes() -> [[f64; 3]; 3] {
+        let (s, c) = (0.5f64, 0.75f64.sqrt());
+        [[c, s, 0.0], [-s, c, 0.0], [0.0, 0.0, 1.0]]
+    }
+
+    // Rows drawn from a Gaussian with mean MEANS and standard deviation STD_DEVS[k] along axes()[k].
+    fn anisotropic_sample(n: u64) -> Dense<f64> {
+        let z = gaussian_vector(&mut seeded_rng(17), 3 * n as usize);
+        let axes = axes();
+        Dense::anonymous_from_fn(n, 3, |i, j| {
+            let z = &z[3 * i as usize..];
+            MEANS[j as usize] + (0..3).map(|k| z[k] * STD_DEVS[k] * axes[k][j as usize]).sum::<f64>()
+        })
+    }
+
+    #[test]
+    fn principal_axes_of_an_anisotropic_gaussian_are_recovered() {
+        let a = anisotropic_sample(20_000);
+        let result = pca(&a, 3).unwrap();
+        assert_eq!(result.num_components(), 3);
+        for (k, axis) in axes().iter().enumerate() {
+            let mut found = [0.0; 3];
+            result.components.read_row(k as u64, &mut found);
+            let dot: f64 = found.iter().zip(axis.iter()).map(|(x, y)| x * y).sum();
+            assert!(dot > 0.999, "axis {}: found {:?}, expected {:?}", k, found, axis);
+            let norm: f64 = found.iter().map(|x| x * x).sum();
+            assert!((norm - 1.0).abs() < 1e-12);
+            let variance = STD_DEVS[k] * STD_DEVS[k];
+            assert!((result.explained_variance[k] - variance).abs() < 0.05 * variance,
+                    "axis {}: variance {} vs {}", k, result.explained_variance[k], variance);
+        }
+        for (mean, expected) in result.means.iter().zip(MEANS.iter()) {
+            assert!((mean - expected).abs() < 0.1, "mean {} vs {}", mean, expected);
+        }
+        let total: f64 = STD_DEVS.iter().map(|s| s * s).sum();
+        for (ratio, s) in result.explained_variance_ratio.iter().zip(STD_DEVS.iter()) {
+            assert!((ratio - s * s / total).abs() < 0.01);
+        }
+        // All three components explain all the variance.
+        let sum: f64 = result.explained_variance_ratio.iter().sum();
+        assert!(sum <= 1.0 + 1e-12 && sum > 1.0 - 1e-9, "ratios sum to {}", sum);
+    }
+
+    #[test]
+    fn explained_variance_ratios_of_leading_components_sum_below_one() {
+        let a = anisotropic_sample(2_000);
+        for components in 1..4 {
+            let result = pca(&a, components).unwrap();
+            assert_eq!(result.explained_variance_ratio.len(), components);
+            let sum: f64 = result.explained_variance_ratio.iter().sum();
+            assert!(sum <= 1.0 + 1e-12, "{} components: ratios sum to {}", components, sum);
+            assert!(result.explained_variance_ratio.windows(2).all(|w| w[0] >= w[1]));
+        }
+    }
+
+    #[test]
+    fn transform_projects_the_centred_rows() {
+        let a = anisotropic_sample(2_000);
+        let result = pca(&a, 2).unwrap();
+        let dst = TempMatrixPath::new();
+        let projected = result.transform(&a, dst.path()).unwrap();
+        assert_eq!((projected.num_rows(), projected.num_cols()), (2_000, 2));
+        for k in 0..2u64 {
+            let column: Vec<f64> = (0..2_000).map(|i| projected.get(i, k)).collect();
+            let mean = column.iter().sum::<f64>() / 2_000.0;
+            let variance = column.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / 1_999.0;
+            assert!(mean.abs() < 1e-9, "component {} has mean {}", k, mean);
+            let expected = result.explained_variance[k as usize];
+            assert!((variance - expected).abs() < 1e-9 * expected, "component {}: {} vs {}", k, variance, expected);
+        }
+    }
+
+    #[test]
+    fn invalid_component_counts_are_rejected() {
+        let a = anisotropic_sample(10);
+        assert!(pca(&a, 0).is_err());
+        assert!(pca(&a, 4).is_err());
+        let one_row = Dense::<f64>::create_anonymous(1, 3).unwrap();
+        assert!(pca(&one_row, 1).is_err());
+    }
+}
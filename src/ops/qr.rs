@@ -0,0 +1,188 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::tile_gemm_acc;
+use std::cmp;
+use std::path::Path;
+use tile::Tile;
+
+// In-memory Householder QR of a row-major rows x cols matrix, laid out as LAPACK's geqrf
+// leaves it: R on and above the diagonal, the essential parts of the reflectors below it.
+// Returns the min(rows, cols) reflector scales.
+pub(crate) fn householder_qr(a: &mut [f64], rows: usize, cols: usize) -> Vec<f64> {
+    let k = cmp::min(rows, cols);
+    let mut taus = vec![0.0; k];
+    for j in 0..k {
+        let norm = (j..rows).map(|i| a[i * cols + j] * a[i * cols + j]).sum::<f64>().sqrt();
+        if norm == 0.0 {
+            continue;
+        }
+        let alpha = a[j * cols + j];
+        let beta = if alpha > 0.0 { -norm } else { norm };
+        for i in j + 1..rows {
+            a[i * cols + j] /= alpha - beta;
+        }
+        a[j * cols + j] = beta;
+        let tau = (beta - alpha) / beta;
+        taus[j] = tau;
+        for c in j + 1..cols {
+            let w = a[j * cols + c] + (j + 1..rows).map(|i| a[i * cols + j] * a[i * cols + c]).sum::<f64>();
+            a[j * cols + c] -= tau * w;
+            for i in j + 1..rows {
+                a[i * cols + c] -= tau * a[i * cols + j] * w;
+            }
+        }
+    }
+    taus
+}
+
+// Forms the explicit rows x taus.len() factor Q from the output of `householder_qr`.
+pub(crate) fn householder_q(factored: &[f64], rows: usize, cols: usize, taus: &[f64]) -> Vec<f64> {
+    let k = taus.len();
+    let mut q = vec![0.0; rows * k];
+    for j in 0..k {
+        q[j * k + j] = 1.0;
+    }
+    for j in (0..k).rev() {
+        let tau = taus[j];
+        if tau == 0.0 {
+            continue;
+        }
+        for c in j..k {
+            let w = q[j * k + c] + (j + 1..rows).map(|i| factored[i * cols + j] * q[i * k + c]).sum::<f64>();
+            q[j * k + c] -= tau * w;
+            for i in j + 1..rows {
+                q[i * k + c] -= tau * factored[i * cols + j] * w;
+            }
+        }
+    }
+    q
+}
+
+// Appends the upper-trapezoidal taus.len() x cols factor R to `out`.
+fn extract_r(factored: &[f64], cols: usize, k: usize, out: &mut Vec<f64>) {
+    for i in 0..k {
+        out.extend((0..cols).map(|j| if j < i { 0.0 } else { factored[i * cols + j] }));
+    }
+}
+
+// Communication-avoiding QR of a tall-skinny n x d matrix. Each block of `block_rows` rows is
+// factored independently and the stacked R factors are then factored once more, so besides
+// one block the (n / block_rows)·d² stacked values are held in memory; block_rows should be
+// well above d. Returns R as a row-major d x d vector and, when `form_q` is set, the n x d
+// factor Q written to `dst` (or an anonymous file). Q is formed with a second pass over A.
+pub fn tsqr(a: &Dense<f64>, block_rows: usize, form_q: bool, dst: Option<&Path>)
+    -> Result<(Option<Dense<f64>>, Vec<f64>), OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols() as usize);
+    if n < d as u64 {
+        return Err(OoclaError::InvalidArgument(format!("tsqr needs at least as many rows as columns, got {}x{}", n, d)));
+    }
+    if block_rows == 0 || block_rows < d {
+        return Err(OoclaError::InvalidArgument(format!("block_rows must be at least max(1, d) = {}", cmp::max(1, d))));
+    }
+    let step = block_rows as u64;
+    let mut stacked = Vec::new();
+    let mut start = 0;
+    while start < n {
+        let rows = cmp::min(step, n - start) as usize;
+        let mut block = a.read_tile(start, 0, rows, d);
+        let taus = householder_qr(&mut block.data, rows, d);
+        extract_r(&block.data, d, taus.len(), &mut stacked);
+        start += rows as u64;
+    }
+    let stacked_rows = stacked.len() / cmp::max(d, 1);
+    let taus = householder_qr(&mut stacked, stacked_rows, d);
+    let mut r = Vec::with_capacity(d * d);
+    extract_r(&stacked, d, d, &mut r);
+    if !form_q {
+        return Ok((None, r));
+    }
+    let reduced_q = householder_q(&stacked, stacked_rows, d, &taus);
+    let mut q = Dense::create_at(dst, n, d as u64)?;
+    let (mut start, mut offset) = (0, 0);
+    while start < n {
+        let rows = cmp::min(step, n - start) as usize;
+        let mut block = a.read_tile(start, 0, rows, d);
+        let block_taus = householder_qr(&mut block.data, rows, d);
+        let k = block_taus.len();
+        let local = Tile {
+            row: start,
+            col: 0,
+            rows,
+            cols: k,
+            data: householder_q(&block.data, rows, d, &block_taus),
+        };
+        let coupling = Tile {
+            row: 0,
+            col: 0,
+            rows: k,
+            cols: d,
+            data: reduced_q[offset * d..(offset + k) * d].to_vec(),
+        };
+        let mut out = Tile::zeros(start, 0, rows, d);
+        tile_gemm_acc(1.0, &local, &coupling, &mut out.data);
+        q.write_tile(&out);
+        start += rows as u64;
+        offset += k;
+    }
+    Ok((Some(q), r))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_factorisation(n: u64, d: usize, block_rows: usize) {
+        let mut a = Dense::<f64>::create_anonymous(n, d as u64).unwrap();
+        a.randomise_seeded(n + d as u64);
+        let (q, r) = tsqr(&a, block_rows, true, None).unwrap();
+        let q = q.unwrap();
+        assert_eq!((q.num_rows(), q.num_cols()), (n, d as u64));
+        for i in 0..d {
+            for j in 0..i {
+                assert_eq!(r[i * d + j], 0.0);
+            }
+        }
+        let mut orthogonality: f64 = 0.0;
+        for i in 0..d as u64 {
+            for j in 0..d as u64 {
+                let dot: f64 = (0..n).map(|k| q.get(k, i) * q.get(k, j)).sum();
+                let identity = if i == j { 1.0 } else { 0.0 };
+                orthogonality = orthogonality.max((dot - identity).abs());
+            }
+        }
+        assert!(orthogonality < 1e-12, "{}x{}: |QtQ - I| = {}", n, d, orthogonality);
+        let mut reconstruction: f64 = 0.0;
+        for i in 0..n {
+            for j in 0..d {
+                let qr: f64 = (0..=j).map(|k| q.get(i, k as u64) * r[k * d + j]).sum();
+                reconstruction = reconstruction.max((qr - a.get(i, j as u64)).abs());
+            }
+        }
+        assert!(reconstruction < 1e-12, "{}x{}: |A - QR| = {}", n, d, reconstruction);
+        let (none, r_only) = tsqr(&a, block_rows, false, None).unwrap();
+        assert!(none.is_none());
+        assert_eq!(r_only, r);
+    }
+
+    #[test]
+    fn tall_skinny_factors_are_orthogonal_and_reconstruct_a() {
+        check_factorisation(1000, 8, 64);
+        check_factorisation(333, 5, 100);
+    }
+
+    #[test]
+    fn a_short_final_block_is_handled() {
+        // The last block has fewer rows than columns.
+        check_factorisation(130, 8, 64);
+        check_factorisation(6, 6, 6);
+    }
+
+    #[test]
+    fn wide_matrices_and_small_blocks_are_rejected() {
+        let a = Dense::<f64>::create_anonymous(3, 4).unwrap();
+        assert!(tsqr(&a, 8, false, None).is_err());
+        let a = Dense::<f64>::create_anonymous(30, 4).unwrap();
+        assert!(tsqr(&a, 3, false, None).is_err());
+        assert!(tsqr(&a, 0, false, None).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use dense_matrix::{Dense, SupportedType, temp_matrix_path};
+use error::OoclaError;
+use ops::sketch::{DEFAULT_SKETCH_SIZE, QuantileSketch};
+use ops::sort::sort_rows_by_column;
+use row_writer::RowWriter;
+use std::cmp;
+use std::f64;
+use std::fs;
+
+// Sketches every element of A in a single pass. Sketches of several matrices can be merged.
+pub fn quantile_sketch<T: SupportedType>(a: &Dense<T>) -> QuantileSketch {
+    let mut sketch = QuantileSketch::new(DEFAULT_SKETCH_SIZE);
+    for major in 0..a.major_len() {
+        for x in a.major_slice(major) {
+            sketch.insert(x.to_f64());
+        }
+    }
+    sketch
+}
+
+// Approximate quantiles of the elements of A from a single streaming pass; see QuantileSketch
+// for the error bound. NaNs are ignored, and every quantile is NaN if there is nothing else.
+pub fn quantiles<T: SupportedType>(a: &Dense<T>, qs: &[f64]) -> Vec<f64> {
+    quantile_sketch(a).quantiles(qs)
+}
+
+// Exact quantiles under the same definition as QuantileSketch::quantiles: the smallest element
+// whose rank among the non-NaN elements reaches q times their count. The non-NaN elements are
+// copied out to a single column and sorted externally, so this costs a few passes over
+// temporary files the size of A.
+pub fn quantiles_exact<T: SupportedType>(a: &Dense<T>, qs: &[f64]) -> Result<Vec<f64>, OoclaError> {
+    if let Some(&q) = qs.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+        return Err(OoclaError::InvalidArgument(format!("quantile {} is outside [0, 1]", q)));
+    }
+    let values_path = temp_matrix_path();
+    let mut writer = RowWriter::create(&values_path, 1)?;
+    for major in 0..a.major_len() {
+        for &x in a.major_slice(major).iter().filter(|x| !x.to_f64().is_nan()) {
+            writer.write_row(&[x])?;
+        }
+    }
+    let values = writer.finish();
+    let _ = fs::remove_file(&values_path);
+    let values = values?;
+    let sorted_path = temp_matrix_path();
+    let sorted = sort_rows_by_column(&values, &sorted_path, 0, false);
+    let _ = fs::remove_file(&sorted_path);
+    let sorted = sorted?;
+    let n = sorted.num_rows();
+    if n == 0 {
+        return Ok(vec![f64::NAN; qs.len()]);
+    }
+    Ok(qs.iter().map(|&q| {
+        let rank = cmp::max((q * n as f64).ceil() as u64, 1) - 1;
+        sorted.get(cmp::min(rank, n - 1), 0).to_f64()
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 50,000 skewed values, every hundredth a NaN.
+    fn skewed(seed: u64) -> Dense<f64> {
+        let mut a = Dense::<f64>::create_anonymous(200, 250).unwrap();
+        a.randomise_seeded(seed);
+        Dense::anonymous_from_fn(200, 250, |i, j| {
+            if (i * 250 + j) % 100 == 7 { f64::NAN } else { a.get(i, j).powi(3) }
+        })
+    }
+
+    fn sorted_values(a: &Dense<f64>) -> Vec<f64> {
+        let mut values: Vec<f64> = (0..a.num_rows())
+            .flat_map(|i| (0..a.num_cols()).map(move |j| (i, j)))
+            .map(|(i, j)| a.get(i, j))
+            .filter(|x| !x.is_nan())
+            .collect();
+        values.sort_by(|x, y| x.partial_cmp(y).unwrap());
+        values
+    }
+
+    // How far, as a fraction of the count, the rank of `estimate` is from that of quantile q.
+    fn rank_error(sorted: &[f64], q: f64, estimate: f64) -> f64 {
+        let below = sorted.partition_point(|&x| x < estimate);
+        let at_most = sorted.partition_point(|&x| x <= estimate);
+        let target = q * sorted.len() as f64;
+        let distance = if target < below as f64 {
+            below as f64 - target
+        } else if target > at_most as f64 {
+            target - at_most as f64
+        } else {
+            0.0
+        };
+        distance / sorted.len() as f64
+    }
+
+    const QS: [f64; 9] = [0.0, 0.01, 0.05, 0.25, 0.5, 0.75, 0.95, 0.99, 1.0];
+
+    #[test]
+    fn exact_quantiles_match_a_sorted_vec() {
+        let a = skewed(1);
+        let sorted = sorted_values(&a);
+        assert_eq!(sorted.len(), 49_500);
+        let exact = quantiles_exact(&a, &QS).unwrap();
+        for (&q, &value) in QS.iter().zip(exact.iter()) {
+            let rank = cmp::max((q * sorted.len() as f64).ceil() as usize, 1) - 1;
+            assert_eq!(value, sorted[rank], "q = {}", q);
+        }
+    }
+
+    #[test]
+    fn sketched_quantiles_are_within_the_documented_rank_error() {
+        let a = skewed(2);
+        let sorted = sorted_values(&a);
+        let sketch = quantile_sketch(&a);
+        assert_eq!((sketch.count(), sketch.nan_count()), (49_500, 500));
+        let estimates = quantiles(&a, &QS);
+        for (&q, &estimate) in QS.iter().zip(estimates.iter()) {
+            assert!(rank_error(&sorted, q, estimate) < 0.01, "q = {}: estimate {}", q, estimate);
+        }
+        assert_eq!(estimates[0], sorted[0]);
+        assert_eq!(estimates[8], sorted[sorted.len() - 1]);
+    }
+
+    #[test]
+    fn merged_sketches_cover_both_matrices() {
+        let (a, b) = (skewed(3), Dense::anonymous_from_fn(100, 100, |i, j| 1.0 + (i * 100 + j) as f64 / 1e4));
+        let mut sketch = quantile_sketch(&a);
+        sketch.merge(&quantile_sketch(&b));
+        let mut sorted = sorted_values(&a);
+        sorted.extend(sorted_values(&b));
+        sorted.sort_by(|x, y| x.partial_cmp(y).unwrap());
+        assert_eq!(sketch.count(), sorted.len() as u64);
+        for (&q, &estimate) in QS.iter().zip(sketch.quantiles(&QS).iter()) {
+            assert!(rank_error(&sorted, q, estimate) < 0.01, "q = {}: estimate {}", q, estimate);
+        }
+    }
+
+    #[test]
+    fn matrices_of_nans_have_nan_quantiles() {
+        let a = Dense::<f32>::anonymous_from_fn(4, 4, |_, _| f32::NAN);
+        assert!(quantiles(&a, &[0.5]).iter().all(|x| x.is_nan()));
+        assert!(quantiles_exact(&a, &[0.0, 1.0]).unwrap().iter().all(|x| x.is_nan()));
+        assert!(quantiles_exact(&a, &[1.5]).is_err());
+    }
+}
@@ -0,0 +1,360 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use std::f64;
+use std::path::Path;
+
+// "OOCQUANT" when read as little-endian bytes.
+const QUANT_MAGIC: u64 = 0x544e_4155_5143_4f4f;
+const QUANT_METADATA_HEADER: usize = 16;
+
+// Affine parameters mapping an i8 value q back to scale * (q - zero_point). There is either a
+// single pair for the whole matrix or one per logical column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantParams {
+    pub per_column: bool,
+    pub scales: Vec<f32>,
+    pub zero_points: Vec<i8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum QuantScheme {
+    // Parameters chosen from the minimum and maximum of the matrix or of each column.
+    PerTensor,
+    PerColumn,
+    Provided(QuantParams),
+}
+
+// How far the quantised values are from the originals once mapped back, over the finite ones.
+// Values outside the representable range, infinities included, saturate and are counted as
+// clipped. NaNs are stored as the zero point.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RoundTripError {
+    pub max_abs_error: f64,
+    pub rms_error: f64,
+    pub clipped: u64,
+    pub nan_count: u64,
+}
+
+impl QuantParams {
+    // Chooses parameters spanning [lo, hi] widened to include zero, so that zero is exact.
+    fn from_range(lo: f64, hi: f64) -> (f32, i8) {
+        let (lo, hi) = (lo.min(0.0), hi.max(0.0));
+        if hi == lo {
+            return (1.0, 0);
+        }
+        let scale = ((hi - lo) / 255.0) as f32;
+        let zero_point = (-128.0 - lo / scale as f64).round().clamp(-128.0, 127.0);
+        (scale, zero_point as i8)
+    }
+
+    fn validate(&self, cols: u64) -> Result<(), OoclaError> {
+        let expected = if self.per_column { cols as usize } else { 1 };
+        if self.scales.len() != expected || self.zero_points.len() != expected {
+            return Err(OoclaError::InvalidArgument(format!("expected {} scales and zero points, found {} and {}", expected,
+                                                           self.scales.len(), self.zero_points.len())));
+        }
+        if let Some(&scale) = self.scales.iter().find(|s| !(s.is_finite() && **s > 0.0)) {
+            return Err(OoclaError::InvalidArgument(format!("invalid quantisation scale {}", scale)));
+        }
+        Ok(())
+    }
+
+    fn metadata_len(&self) -> usize {
+        QUANT_METADATA_HEADER + self.scales.len() * 5
+    }
+
+    // Layout: magic, a per-column flag as u32, the parameter count as u32, the scales as f32 and
+    // then the zero points, all little-endian.
+    fn write_metadata(&self, bytes: &mut [u8]) {
+        bytes[..8].copy_from_slice(&QUANT_MAGIC.to_le_bytes());
+        bytes[8..12].copy_from_slice(&(self.per_column as u32).to_le_bytes());
+        bytes[12..16].copy_from_slice(&(self.scales.len() as u32).to_le_bytes());
+        let (scales, zero_points) = bytes[QUANT_METADATA_HEADER..].split_at_mut(self.scales.len() * 4);
+        for (dst, scale) in scales.chunks_mut(4).zip(self.scales.iter()) {
+            dst.copy_from_slice(&scale.to_le_bytes());
+        }
+        for (dst, &zero_point) in zero_points.iter_mut().zip(self.zero_points.iter()) {
+            *dst = zero_point as u8;
+        }
+    }
+
+    // Reads back the parameters that quantize_i8 stored in the file of `a`.
+    pub fn read(a: &Dense<i8>) -> Result<QuantParams, OoclaError> {
+        Self::from_metadata(a.metadata(), a.num_cols())?
+            .ok_or_else(|| OoclaError::InvalidFormat("matrix has no quantisation parameters".to_string()))
+    }
+
+    // Parses the parameters from the metadata of a matrix with `cols` logical columns, or returns
+    // None when the metadata holds something else.
+    pub(crate) fn from_metadata(bytes: &[u8], cols: u64) -> Result<Option<QuantParams>, OoclaError> {
+        let word = |at: usize| u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+        if bytes.len() < QUANT_METADATA_HEADER || bytes[..8] != QUANT_MAGIC.to_le_bytes() {
+            return Ok(None);
+        }
+        let (per_column, count) = (word(8), word(12) as usize);
+        if per_column > 1 || bytes.len() < QUANT_METADATA_HEADER + count * 5 {
+            return Err(OoclaError::InvalidFormat("corrupt quantisation parameters".to_string()));
+        }
+        let params = QuantParams {
+            per_column: per_column == 1,
+            scales: (0..count).map(|i| f32::from_bits(word(QUANT_METADATA_HEADER + 4 * i))).collect(),
+            zero_points: bytes[QUANT_METADATA_HEADER + 4 * count..][..count].iter().map(|&b| b as i8).collect(),
+        };
+        params.validate(cols).map_err(|e| OoclaError::InvalidFormat(e.to_string()))?;
+        Ok(Some(params))
+    }
+
+    fn index(&self, col: u64) -> usize {
+        if self.per_column { col as usize } else { 0 }
+    }
+}
+
+fn observed_params(a: &Dense<f32>, per_column: bool) -> QuantParams {
+    let count = if per_column { a.num_cols() as usize } else { 1 };
+    let (mut lo, mut hi) = (vec![f64::INFINITY; count], vec![f64::NEG_INFINITY; count]);
+    let transposed = a.is_transposed();
+    for major in 0..a.major_len() {
+        for (minor, &x) in a.major_slice(major).iter().enumerate() {
+            let x = x as f64;
+            if x.is_finite() {
+                let col = if transposed { major as usize } else { minor };
+                let i = if per_column { col } else { 0 };
+                lo[i] = lo[i].min(x);
+                hi[i] = hi[i].max(x);
+            }
+        }
+    }
+    let (scales, zero_points) = lo.iter().zip(hi.iter())
+        .map(|(&lo, &hi)| if lo <= hi { QuantParams::from_range(lo, hi) } else { (1.0, 0) })
+        .unzip();
+    QuantParams { per_column, scales, zero_points }
+}
+
+// Creates a matrix with the storage layout of `like`.
+fn create_like<T, U: StorageType>(like: &Dense<T>, dst: &Path, metadata_len: usize)
+    -> Result<Dense<U>, OoclaError> {
+    let mut out = Dense::create_with_metadata(dst, like.major_len(), like.minor_len(), metadata_len as u64)?;
+    if like.is_transposed() {
+        out.transpose();
+    }
+    Ok(out)
+}
+
+// Quantises A to i8 as q = clamp(round(x / scale) + zero_point, -128, 127). Parameters are either
+// provided or chosen in a first pass over A so that each range, widened to include zero, maps
+// onto the full i8 range. They are stored in the file after the elements, where
+// QuantParams::read finds them, so the output describes itself. The output has the storage
+// layout of A.
+pub fn quantize_i8(a: &Dense<f32>, dst: &Path, scheme: QuantScheme)
+    -> Result<(Dense<i8>, QuantParams, RoundTripError), OoclaError> {
+    let params = match scheme {
+        QuantScheme::PerTensor => observed_params(a, false),
+        QuantScheme::PerColumn => observed_params(a, true),
+        QuantScheme::Provided(params) => {
+            params.validate(a.num_cols())?;
+            params
+        }
+    };
+    let mut out = create_like(a, dst, params.metadata_len())?;
+    let transposed = a.is_transposed();
+    let mut error = RoundTripError::default();
+    let (mut squared_error, mut finite) = (0.0, 0u64);
+    for major in 0..a.major_len() {
+        let line = out.major_slice_mut(major);
+        for (minor, (q, &x)) in line.iter_mut().zip(a.major_slice(major).iter()).enumerate() {
+            let i = params.index(if transposed { major } else { minor as u64 });
+            let (scale, zero_point) = (params.scales[i] as f64, params.zero_points[i] as f64);
+            let x = x as f64;
+            if x.is_nan() {
+                *q = zero_point as i8;
+                error.nan_count += 1;
+                continue;
+            }
+            let unclamped = (x / scale).round() + zero_point;
+            let stored = unclamped.clamp(-128.0, 127.0);
+            if stored != unclamped {
+                error.clipped += 1;
+            }
+            *q = stored as i8;
+            if x.is_finite() {
+                let e = (scale * (stored - zero_point) - x).abs();
+                error.max_abs_error = error.max_abs_error.max(e);
+                squared_error += e * e;
+                finite += 1;
+            }
+        }
+    }
+    if finite > 0 {
+        error.rms_error = (squared_error / finite as f64).sqrt();
+    }
+    params.write_metadata(out.metadata_mut());
+    Ok((out, params, error))
+}
+
+// Maps each value back to scale * (q - zero_point), keeping the storage layout of A.
+pub fn dequantize(a: &Dense<i8>, params: &QuantParams, dst: &Path) -> Result<Dense<f32>, OoclaError> {
+    params.validate(a.num_cols())?;
+    let mut out = create_like(a, dst, 0)?;
+    let transposed = a.is_transposed();
+    for major in 0..a.major_len() {
+        let line = out.major_slice_mut(major);
+        for (minor, (x, &q)) in line.iter_mut().zip(a.major_slice(major).iter()).enumerate() {
+            let i = params.index(if transposed { major } else { minor as u64 });
+            *x = params.scales[i] * (q as f32 - params.zero_points[i] as f32);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // Columns over very different ranges, one of them never negative and one constant.
+    fn element(i: u64, j: u64) -> f32 {
+        let wave = ((i * 13 + j * 7) as f32 * 0.37).sin();
+        match j {
+            0 => wave * 0.01,
+            1 => wave * 100.0 - 20.0,
+            2 => 5.0 + wave.abs() * 3.0,
+            3 => 2.5,
+            _ => wave,
+        }
+    }
+
+    fn quantize(a: &Dense<f32>, scheme: QuantScheme) -> (TempMatrixPath, Dense<i8>, QuantParams, RoundTripError) {
+        let dst = TempMatrixPath::new();
+        let (q, params, error) = quantize_i8(a, dst.path(), scheme).unwrap();
+        (dst, q, params, error)
+    }
+
+    // Quantises and dequantises A, checking the stored parameters and that every element comes
+    // back to within half a step of its column's scale, and returns the largest error per column.
+    fn round_trip(a: &Dense<f32>, scheme: QuantScheme) -> Vec<f64> {
+        let (dst, q, params, error) = quantize(a, scheme);
+        assert_eq!(q.is_transposed(), a.is_transposed());
+        assert_eq!(QuantParams::read(&q).unwrap(), params);
+        drop(q);
+        let q = Dense::<i8>::open(dst.path()).unwrap();
+        assert_eq!(QuantParams::read(&q).unwrap(), params);
+        let back_dst = TempMatrixPath::new();
+        let back = dequantize(&q, &params, back_dst.path()).unwrap();
+        assert_eq!((back.num_rows(), back.num_cols(), back.is_transposed()),
+                   (a.num_rows(), a.num_cols(), a.is_transposed()));
+        let mut column_errors = vec![0.0f64; a.num_cols() as usize];
+        for i in 0..a.num_rows() {
+            for j in 0..a.num_cols() {
+                let e = (back.get(i, j) as f64 - a.get(i, j) as f64).abs();
+                let step = params.scales[params.index(j)] as f64;
+                assert!(e <= step * 0.5 * (1.0 + 1e-5), "({}, {}): error {} with scale {}", i, j, e, step);
+                column_errors[j as usize] = column_errors[j as usize].max(e);
+            }
+        }
+        let max_error = column_errors.iter().cloned().fold(0.0, f64::max);
+        assert!((error.max_abs_error - max_error).abs() <= 1e-6 * max_error.max(1e-6), "{:?} against {}", error,
+                max_error);
+        assert_eq!((error.clipped, error.nan_count), (0, 0));
+        column_errors
+    }
+
+    #[test]
+    fn per_tensor_and_per_column_round_trips_stay_within_half_a_step() {
+        let a = Dense::anonymous_from_fn(40, 5, element);
+        let per_tensor = round_trip(&a, QuantScheme::PerTensor);
+        let per_column = round_trip(&a, QuantScheme::PerColumn);
+        // The whole range spans about 220, so a step is under 1, which swamps the narrowest column.
+        assert!(per_tensor[1] < 0.5 && per_column[1] < 0.5, "{:?} {:?}", per_tensor, per_column);
+        assert!(per_column[0] * 100.0 < per_tensor[0], "{:?} {:?}", per_tensor, per_column);
+        // Zero is exact, and so is the constant column once its range is widened to include it.
+        let (_, q, params, _) = quantize(&a, QuantScheme::PerColumn);
+        assert_eq!(params.scales.len(), 5);
+        assert_eq!(params.scales[3] * (q.get(0, 3) as f32 - params.zero_points[3] as f32), 2.5);
+    }
+
+    #[test]
+    fn transposed_matrices_are_quantised_by_logical_column() {
+        let mut a = Dense::anonymous_from_fn(5, 40, |i, j| element(j, i));
+        a.transpose();
+        round_trip(&a, QuantScheme::PerColumn);
+        round_trip(&a, QuantScheme::PerTensor);
+        let (_, _, params, _) = quantize(&a, QuantScheme::PerColumn);
+        let (_, _, expected, _) = quantize(&Dense::anonymous_from_fn(40, 5, element), QuantScheme::PerColumn);
+        assert_eq!(params, expected);
+    }
+
+    #[test]
+    fn non_finite_values_are_counted() {
+        let special = [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 1.0e6, f32::NAN];
+        let a = Dense::anonymous_from_fn(4, 5, |i, j| if i == 2 { special[j as usize] } else { (i + j) as f32 });
+        // Chosen from the finite values alone, so the large one is the top of the range.
+        let (_, q, params, error) = quantize(&a, QuantScheme::PerTensor);
+        assert!(params.scales[0] > 1.0e6 / 256.0);
+        assert_eq!((error.nan_count, error.clipped), (2, 2));
+        assert_eq!((q.get(2, 1), q.get(2, 2), q.get(2, 3)), (127, -128, 127));
+        assert_eq!((q.get(2, 0), q.get(2, 4)), (params.zero_points[0], params.zero_points[0]));
+        // With parameters given, the large finite value saturates too, and its error is counted.
+        let provided = QuantParams { per_column: false, scales: vec![0.1], zero_points: vec![-10] };
+        let (_, q, _, error) = quantize(&a, QuantScheme::Provided(provided));
+        assert_eq!((error.nan_count, error.clipped), (2, 3));
+        assert_eq!((q.get(2, 0), q.get(2, 3), q.get(0, 0), q.get(1, 3)), (-10, 127, -10, 30));
+        assert!((error.max_abs_error - (1.0e6 - 13.7)).abs() < 1e-3, "{:?}", error);
+    }
+
+    #[test]
+    fn provided_parameters_must_fit_the_matrix() {
+        let a = Dense::anonymous_from_fn(3, 4, element);
+        let cases = [
+            QuantParams { per_column: true, scales: vec![1.0; 3], zero_points: vec![0; 3] },
+            QuantParams { per_column: false, scales: vec![1.0], zero_points: vec![] },
+            QuantParams { per_column: false, scales: vec![0.0], zero_points: vec![0] },
+            QuantParams { per_column: false, scales: vec![f32::NAN], zero_points: vec![0] },
+        ];
+        for params in cases.iter() {
+            let dst = TempMatrixPath::new();
+            match quantize_i8(&a, dst.path(), QuantScheme::Provided(params.clone())) {
+                Err(OoclaError::InvalidArgument(_)) => {}
+                other => panic!("expected an invalid argument for {:?}, got {:?}", params, other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_metadata_is_rejected() {
+        let params = QuantParams { per_column: true, scales: vec![0.5, 2.0, 1.0], zero_points: vec![-3, 0, 7] };
+        let mut bytes = vec![0u8; params.metadata_len()];
+        params.write_metadata(&mut bytes);
+        assert_eq!(QuantParams::from_metadata(&bytes, 3).unwrap(), Some(params.clone()));
+        let corrupt = |change: fn(&mut Vec<u8>), cols: u64| {
+            let mut bytes = bytes.clone();
+            change(&mut bytes);
+            QuantParams::from_metadata(&bytes, cols)
+        };
+        let cases: [fn(&mut Vec<u8>); 6] = [
+            |b| b[8] = 2,
+            |b| b[12] = 4,
+            |b| b[12] = 2,
+            |b| b[16..20].copy_from_slice(&0.0f32.to_le_bytes()),
+            |b| b[20..24].copy_from_slice(&(-1.0f32).to_le_bytes()),
+            |b| b.truncate(30),
+        ];
+        for (k, change) in cases.iter().enumerate() {
+            match corrupt(*change, 3) {
+                Err(OoclaError::InvalidFormat(_)) => {}
+                other => panic!("case {}: expected an invalid format, got {:?}", k, other),
+            }
+        }
+        match corrupt(|_| {}, 4) {
+            Err(OoclaError::InvalidFormat(_)) => {}
+            other => panic!("expected parameters for the wrong column count to be rejected, got {:?}", other),
+        }
+        // Metadata of some other kind isn't taken for parameters.
+        assert_eq!(corrupt(|b| b[0] ^= 1, 3).unwrap(), None);
+        assert_eq!(QuantParams::from_metadata(&bytes[..8], 3).unwrap(), None);
+        let plain = Dense::<i8>::create_anonymous(2, 3).unwrap();
+        match QuantParams::read(&plain) {
+            Err(OoclaError::InvalidFormat(_)) => {}
+            other => panic!("expected an invalid format, got {:?}", other),
+        }
+    }
+}
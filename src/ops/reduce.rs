@@ -0,0 +1,148 @@
+use dense_matrix::{AccessPattern, Dense, SupportedType};
+use error::OoclaError;
+use ops::simd;
+
+pub fn trace<T: SupportedType>(a: &Dense<T>) -> f64 {
+    // Once consecutive diagonal elements are a page or more apart, readahead only
+    // pulls in data we never look at.
+    let _advice = if a.diagonal_is_sparse_in_pages() {
+        Some(a.advise_scoped(AccessPattern::Random))
+    } else {
+        None
+    };
+    a.diagonal_iter().map(|v| v.to_f64()).sum()
+}
+
+pub fn diag_dot<T: SupportedType>(a: &Dense<T>, b: &Dense<T>) -> Result<f64, OoclaError> {
+    if a.diagonal_len() != b.diagonal_len() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), a.num_cols()),
+            found: (b.num_rows(), b.num_cols()),
+        });
+    }
+    let _advice_a = if a.diagonal_is_sparse_in_pages() {
+        Some(a.advise_scoped(AccessPattern::Random))
+    } else {
+        None
+    };
+    let _advice_b = if b.diagonal_is_sparse_in_pages() {
+        Some(b.advise_scoped(AccessPattern::Random))
+    } else {
+        None
+    };
+    let result = a.diagonal_iter()
+        .zip(b.diagonal_iter())
+        .map(|(x, y)| x.to_f64() * y.to_f64())
+        .sum();
+    Ok(result)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NormKind {
+    L1,
+    L2,
+    Max,
+}
+
+impl NormKind {
+    pub(crate) fn accumulate(self, acc: f64, x: f64) -> f64 {
+        match self {
+            NormKind::L1 => acc + x.abs(),
+            NormKind::L2 => acc + x * x,
+            NormKind::Max => acc.max(x.abs()),
+        }
+    }
+
+    pub(crate) fn finish(self, acc: f64) -> f64 {
+        match self {
+            NormKind::L2 => acc.sqrt(),
+            _ => acc,
+        }
+    }
+
+    // The norm of a contiguous run, before finish.
+    pub(crate) fn partial<T: SupportedType>(self, values: &[T], deterministic: bool) -> f64 {
+        match self {
+            NormKind::L1 => simd::sum_abs(values, deterministic),
+            NormKind::L2 => simd::dot(values, values, deterministic),
+            NormKind::Max => simd::max_abs(values),
+        }
+    }
+
+    pub(crate) fn of<T: SupportedType>(self, values: &[T], deterministic: bool) -> f64 {
+        self.finish(self.partial(values, deterministic))
+    }
+}
+
+pub(crate) fn column_norms<T: SupportedType>(a: &Dense<T>, kind: NormKind) -> Vec<f64> {
+    let _advice = a.advise_scoped(AccessPattern::Sequential);
+    let mut acc = vec![0.0; a.num_cols() as usize];
+    for major in 0..a.major_len() {
+        let line = a.major_slice(major);
+        if a.is_transposed() {
+            acc[major as usize] = kind.partial(line, a.stream_options().deterministic);
+        } else {
+            for (column, x) in acc.iter_mut().zip(line.iter()) {
+                *column = kind.accumulate(*column, x.to_f64());
+            }
+        }
+    }
+    acc.into_iter().map(|c| kind.finish(c)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::page_size;
+
+    #[test]
+    fn trace_of_identity_is_its_order() {
+        let mut a = Dense::<f64>::create_anonymous(7, 7).unwrap();
+        a.set_identity().unwrap();
+        assert_eq!(trace(&a), 7.0);
+    }
+
+    #[test]
+    fn trace_sums_a_known_diagonal() {
+        let a = Dense::<f32>::anonymous_from_fn(4, 6, |i, j| if i == j { (i + 1) as f32 } else { 100.0 });
+        assert_eq!(a.diagonal_len(), 4);
+        assert_eq!(trace(&a), 10.0);
+    }
+
+    #[test]
+    fn trace_is_unchanged_by_transpose() {
+        let mut a = Dense::<f64>::anonymous_from_fn(3, 5, |i, j| (i * 10 + j) as f64);
+        let before = trace(&a);
+        a.transpose();
+        assert_eq!(trace(&a), before);
+        assert_eq!(before, 0.0 + 11.0 + 22.0);
+    }
+
+    #[test]
+    fn trace_with_page_sized_stride_takes_the_sparse_path() {
+        let cols = (page_size() / 8 + 1) as u64;
+        let a = Dense::<f64>::anonymous_from_fn(3, cols, |i, j| if i == j { 2.5 } else { 1.0 });
+        assert!(a.diagonal_is_sparse_in_pages());
+        assert_eq!(trace(&a), 7.5);
+    }
+
+    #[test]
+    fn diag_dot_multiplies_matching_diagonals() {
+        let a = Dense::<f64>::anonymous_from_fn(3, 3, |i, j| if i == j { (i + 1) as f64 } else { -1.0 });
+        let b = Dense::<f64>::anonymous_from_fn(3, 4, |i, j| if i == j { 2.0 } else { 9.0 });
+        assert_eq!(diag_dot(&a, &b).unwrap(), 12.0);
+    }
+
+    #[test]
+    fn diag_dot_rejects_different_diagonal_lengths() {
+        let a = Dense::<f64>::create_anonymous(3, 3).unwrap();
+        let b = Dense::<f64>::create_anonymous(2, 5).unwrap();
+        match diag_dot(&a, &b) {
+            Err(OoclaError::ShapeMismatch { expected, found }) => {
+                assert_eq!(expected, (3, 3));
+                assert_eq!(found, (2, 5));
+            }
+            other => panic!("expected a shape mismatch, got {:?}", other),
+        }
+    }
+}
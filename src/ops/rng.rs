@@ -0,0 +1,69 @@
+use rand::distributions::normal::StandardNormal;
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+// One step of SplitMix64, which turns consecutive states into well-mixed outputs.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Expands a 64-bit seed with SplitMix64 so that nearby seeds give unrelated streams and the
+// all-zero state XorShift rejects cannot occur.
+pub(crate) fn seeded_rng(seed: u64) -> XorShiftRng {
+    let mut state = seed;
+    let (a, b) = (splitmix64(&mut state), splitmix64(&mut state));
+    let mut words = [a as u32, (a >> 32) as u32, b as u32, (b >> 32) as u32];
+    if words.iter().all(|&w| w == 0) {
+        words[0] = 1;
+    }
+    XorShiftRng::from_seed(words)
+}
+
+// Xoshiro256++, which is faster than XorShift and passes the statistical tests it fails.
+pub(crate) struct Xoshiro256 {
+    s: [u64; 4],
+}
+
+impl Xoshiro256 {
+    // The generator for stream `stream` of `seed`. The stream index is mixed into the seed with
+    // SplitMix64, which also expands it to the full state, so streams of one seed and streams of
+    // nearby seeds are unrelated. Distinct streams of a seed start from distinct states.
+    pub(crate) fn stream(seed: u64, stream: u64) -> Xoshiro256 {
+        let mut state = seed;
+        let mut state = splitmix64(&mut state) ^ stream;
+        let mut s = [0; 4];
+        for word in s.iter_mut() {
+            *word = splitmix64(&mut state);
+        }
+        Xoshiro256 { s }
+    }
+}
+
+impl Rng for Xoshiro256 {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let s = &mut self.s;
+        let result = s[0].wrapping_add(s[3]).rotate_left(23).wrapping_add(s[0]);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+}
+
+pub(crate) fn gaussian_vector<R: Rng>(rng: &mut R, len: usize) -> Vec<f64> {
+    (0..len).map(|_| {
+        let StandardNormal(x) = rng.gen();
+        x
+    }).collect()
+}
@@ -0,0 +1,224 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use ops::reduce::{NormKind, column_norms};
+use std::f64;
+use std::path::Path;
+
+// Stable (log-)softmax of a single row. Rows containing NaN, and rows where every entry is
+// -inf, become all NaN. If any entry is +inf, the mass is split evenly between the +inf
+// entries and every other entry gets zero probability.
+fn softmax_row(row: &mut [f64], log: bool) {
+    let max = row.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if row.iter().any(|x| x.is_nan()) || max == f64::NEG_INFINITY {
+        for x in row.iter_mut() {
+            *x = f64::NAN;
+        }
+    } else if max == f64::INFINITY {
+        let count = row.iter().filter(|&&x| x == f64::INFINITY).count() as f64;
+        for x in row.iter_mut() {
+            let inside = *x == f64::INFINITY;
+            *x = match (inside, log) {
+                (true, true) => -count.ln(),
+                (true, false) => 1.0 / count,
+                (false, true) => f64::NEG_INFINITY,
+                (false, false) => 0.0,
+            };
+        }
+    } else {
+        let sum: f64 = row.iter().map(|x| (x - max).exp()).sum();
+        if log {
+            let log_sum = sum.ln();
+            for x in row.iter_mut() {
+                *x = *x - max - log_sum;
+            }
+        } else {
+            for x in row.iter_mut() {
+                *x = (*x - max).exp() / sum;
+            }
+        }
+    }
+}
+
+fn check_same_shape<T>(a: &Dense<T>, dst: &Dense<T>) -> Result<(), OoclaError> {
+    if a.num_rows() != dst.num_rows() || a.num_cols() != dst.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), a.num_cols()),
+            found: (dst.num_rows(), dst.num_cols()),
+        });
+    }
+    Ok(())
+}
+
+// Streams one logical row at a time from `src` to `dst`, which may be the same matrix.
+fn map_rows<T, F>(src: Option<&Dense<T>>, dst: &mut Dense<T>, mut f: F)
+    where T: SupportedType, F: FnMut(u64, &mut [f64]) {
+    let cols = dst.num_cols() as usize;
+    let mut row = vec![T::from_f64(0.0); cols];
+    let mut values = vec![0.0; cols];
+    for r in 0..dst.num_rows() {
+        match src {
+            Some(src) => src.read_row(r, &mut row),
+            None => dst.read_row(r, &mut row),
+        }
+        for (value, element) in values.iter_mut().zip(row.iter()) {
+            *value = element.to_f64();
+        }
+        f(r, &mut values);
+        for (element, value) in row.iter_mut().zip(values.iter()) {
+            *element = T::from_f64(*value);
+        }
+        dst.write_row(r, &row);
+    }
+}
+
+pub fn softmax_rows<T: SupportedType>(a: &mut Dense<T>) {
+    map_rows(None, a, |_, row| softmax_row(row, false));
+}
+
+pub fn softmax_rows_into<T: SupportedType>(a: &Dense<T>, dst: &mut Dense<T>) -> Result<(), OoclaError> {
+    check_same_shape(a, dst)?;
+    map_rows(Some(a), dst, |_, row| softmax_row(row, false));
+    Ok(())
+}
+
+pub fn log_softmax_rows<T: SupportedType>(a: &mut Dense<T>) {
+    map_rows(None, a, |_, row| softmax_row(row, true));
+}
+
+pub fn log_softmax_rows_into<T: SupportedType>(a: &Dense<T>, dst: &mut Dense<T>) -> Result<(), OoclaError> {
+    check_same_shape(a, dst)?;
+    map_rows(Some(a), dst, |_, row| softmax_row(row, true));
+    Ok(())
+}
+
+// Rows or columns whose norm is zero are left unchanged; their indices are returned.
+pub fn normalize_rows<T: SupportedType>(a: &mut Dense<T>, norm: NormKind) -> Vec<u64> {
+    let mut zero_norm = Vec::new();
+    let deterministic = a.stream_options().deterministic;
+    map_rows(None, a, |r, row| normalize_row(r, row, norm, deterministic, &mut zero_norm));
+    zero_norm
+}
+
+pub fn normalize_rows_to<T: SupportedType>(a: &Dense<T>, dst: &Path, norm: NormKind)
+    -> Result<(Dense<T>, Vec<u64>), OoclaError> {
+    let mut result = Dense::create(dst, a.num_rows(), a.num_cols())?;
+    let mut zero_norm = Vec::new();
+    let deterministic = a.stream_options().deterministic;
+    map_rows(Some(a), &mut result, |r, row| normalize_row(r, row, norm, deterministic, &mut zero_norm));
+    Ok((result, zero_norm))
+}
+
+fn normalize_row(r: u64, row: &mut [f64], norm: NormKind, deterministic: bool, zero_norm: &mut Vec<u64>) {
+    let value = norm.of(row, deterministic);
+    if value == 0.0 {
+        zero_norm.push(r);
+    } else {
+        for x in row.iter_mut() {
+            *x /= value;
+        }
+    }
+}
+
+fn zero_norm_columns(norms: &[f64]) -> Vec<u64> {
+    norms.iter().enumerate().filter(|&(_, &n)| n == 0.0).map(|(c, _)| c as u64).collect()
+}
+
+fn scale_by_column_norms(row: &mut [f64], norms: &[f64]) {
+    for (x, &n) in row.iter_mut().zip(norms.iter()) {
+        if n != 0.0 {
+            *x /= n;
+        }
+    }
+}
+
+pub fn normalize_cols<T: SupportedType>(a: &mut Dense<T>, norm: NormKind) -> Vec<u64> {
+    let norms = column_norms(a, norm);
+    map_rows(None, a, |_, row| scale_by_column_norms(row, &norms));
+    zero_norm_columns(&norms)
+}
+
+pub fn normalize_cols_to<T: SupportedType>(a: &Dense<T>, dst: &Path, norm: NormKind)
+    -> Result<(Dense<T>, Vec<u64>), OoclaError> {
+    let norms = column_norms(a, norm);
+    let mut result = Dense::create(dst, a.num_rows(), a.num_cols())?;
+    map_rows(Some(a), &mut result, |_, row| scale_by_column_norms(row, &norms));
+    Ok((result, zero_norm_columns(&norms)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(a: &Dense<f64>, r: u64) -> Vec<f64> {
+        (0..a.num_cols()).map(|c| a.get(r, c)).collect()
+    }
+
+    fn from_rows(rows: &[&[f64]]) -> Dense<f64> {
+        Dense::anonymous_from_fn(rows.len() as u64, rows[0].len() as u64, |i, j| rows[i as usize][j as usize])
+    }
+
+    #[test]
+    fn softmax_of_large_magnitudes_sums_to_one() {
+        // exp(1000.0) is inf, so without subtracting the maximum every entry would be NaN.
+        let mut a = from_rows(&[&[1000.0, 999.0, 800.0, 1000.0], &[-1000.0, -1001.0, -1200.0, -1000.0]]);
+        assert!(1000.0f64.exp().is_infinite());
+        softmax_rows(&mut a);
+        for r in 0..2 {
+            let values = row(&a, r);
+            assert!(values.iter().all(|x| x.is_finite() && *x >= 0.0));
+            assert!((values.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+            assert_eq!(values[0], values[3]);
+            assert!((values[0] / values[1] - 1f64.exp()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn log_softmax_is_the_log_of_softmax() {
+        let a = from_rows(&[&[1.0, 2.0, 3.0], &[700.0, -700.0, 0.5]]);
+        let mut soft = Dense::<f64>::create_anonymous(2, 3).unwrap();
+        let mut log = Dense::<f64>::create_anonymous(2, 3).unwrap();
+        softmax_rows_into(&a, &mut soft).unwrap();
+        log_softmax_rows_into(&a, &mut log).unwrap();
+        for r in 0..2 {
+            for (s, l) in row(&soft, r).iter().zip(row(&log, r).iter()) {
+                assert!((s.ln() - l).abs() < 1e-12 || (*s == 0.0 && *l < -1000.0));
+            }
+        }
+        assert_eq!(row(&a, 0), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn softmax_follows_the_non_finite_policy() {
+        let mut a = from_rows(&[
+            &[1.0, f64::NAN, 2.0],
+            &[f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY],
+            &[f64::INFINITY, 3.0, f64::INFINITY],
+            &[f64::NEG_INFINITY, 0.0, 0.0],
+        ]);
+        softmax_rows(&mut a);
+        assert!(row(&a, 0).iter().all(|x| x.is_nan()));
+        assert!(row(&a, 1).iter().all(|x| x.is_nan()));
+        assert_eq!(row(&a, 2), vec![0.5, 0.0, 0.5]);
+        assert_eq!(row(&a, 3), vec![0.0, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn softmax_of_a_transposed_matrix_works_on_logical_rows() {
+        let mut a = from_rows(&[&[1.0, 1.0], &[2.0, 2.0], &[3.0, 3.0]]);
+        a.transpose();
+        softmax_rows(&mut a);
+        for r in 0..2 {
+            let values = row(&a, r);
+            assert!((values.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+            assert!(values[0] < values[1] && values[1] < values[2]);
+        }
+    }
+
+    #[test]
+    fn softmax_into_checks_the_destination_shape() {
+        let a = Dense::<f64>::create_anonymous(2, 3).unwrap();
+        let mut dst = Dense::<f64>::create_anonymous(3, 2).unwrap();
+        assert!(softmax_rows_into(&a, &mut dst).is_err());
+        assert!(log_softmax_rows_into(&a, &mut dst).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use dense_matrix::{Dense, StorageType, SupportedType};
+use error::OoclaError;
+use ops::rng::seeded_rng;
+use ops::split::{count_strata, stratum_key};
+use rand::Rng;
+use row_writer::RowWriter;
+use std::cmp;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleSize {
+    // Exactly this many rows.
+    Count(u64),
+    // Without replacement, each row independently with this probability, so the number of
+    // rows is binomial with mean fraction * n. With replacement, round(fraction * n) draws.
+    Fraction(f64),
+}
+
+// Selection sampling: choosing `wanted` of `remaining` items visited in order, each is taken
+// with probability wanted / remaining, which gives every subset of that size the same
+// probability, as a reservoir would, but needs no memory since the number of items is known.
+pub(crate) struct Selection {
+    remaining: u64,
+    wanted: u64,
+}
+
+impl Selection {
+    pub fn new(remaining: u64, wanted: u64) -> Selection {
+        Selection { remaining, wanted }
+    }
+
+    // Decides whether to take the next item. Draws nothing once enough have been taken.
+    pub fn take<R: Rng>(&mut self, rng: &mut R) -> bool {
+        let taken = self.wanted > 0 && rng.gen_range(0, self.remaining) < self.wanted;
+        self.remaining -= 1;
+        if taken {
+            self.wanted -= 1;
+        }
+        taken
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.wanted == 0
+    }
+}
+
+// Writes a random sample of the rows of A to `dst` in a single sequential pass over A, with the
+// sampled rows in their source order (repeated rows adjacent when sampling with replacement).
+// The sample depends only on the seed.
+//
+// A count without replacement uses selection sampling (see Selection). With replacement the row indices are
+// drawn up front and sorted, so they are held in memory.
+pub fn sample_rows<T: StorageType>(a: &Dense<T>, dst: &Path, size: SampleSize, with_replacement: bool, seed: u64)
+    -> Result<Dense<T>, OoclaError> {
+    let n = a.num_rows();
+    match size {
+        SampleSize::Count(count) if !with_replacement && count > n => {
+            return Err(OoclaError::InvalidArgument(format!("cannot sample {} of {} rows without replacement", count, n)));
+        }
+        SampleSize::Fraction(fraction) if !with_replacement && !(0.0..=1.0).contains(&fraction) => {
+            return Err(OoclaError::InvalidArgument(format!("sampling fraction {} is outside [0, 1]", fraction)));
+        }
+        SampleSize::Fraction(fraction) if !(fraction >= 0.0 && fraction.is_finite()) => {
+            return Err(OoclaError::InvalidArgument(format!("sampling fraction {} is not a non-negative number",
+                                                           fraction)));
+        }
+        _ => {}
+    }
+    let mut rng = seeded_rng(seed);
+    let mut row = vec![T::default(); a.num_cols() as usize];
+    let mut writer = RowWriter::create(dst, a.num_cols())?;
+    let mut emit = |i: u64, writer: &mut RowWriter<T>| {
+        a.read_row(i, &mut row);
+        writer.write_row(&row)
+    };
+    match size {
+        _ if with_replacement => {
+            let count = match size {
+                SampleSize::Count(count) => count,
+                SampleSize::Fraction(fraction) => (fraction * n as f64).round() as u64,
+            };
+            if n == 0 && count > 0 {
+                return Err(OoclaError::InvalidArgument("cannot sample from a matrix with no rows".to_string()));
+            }
+            let mut indices: Vec<u64> = (0..count).map(|_| rng.gen_range(0, n)).collect();
+            indices.sort_unstable();
+            for &i in indices.iter() {
+                emit(i, &mut writer)?;
+            }
+        }
+        SampleSize::Count(count) => {
+            let mut selection = Selection::new(n, count);
+            for i in 0..n {
+                if selection.is_done() {
+                    break;
+                }
+                if selection.take(&mut rng) {
+                    emit(i, &mut writer)?;
+                }
+            }
+        }
+        SampleSize::Fraction(fraction) => {
+            for i in 0..n {
+                if rng.gen::<f64>() < fraction {
+                    emit(i, &mut writer)?;
+                }
+            }
+        }
+    }
+    writer.finish()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StratumSample {
+    pub value: f64,
+    pub rows: u64,
+    pub sampled: u64,
+}
+
+pub struct StratifiedSample<T> {
+    pub sample: Dense<T>,
+    // One entry per distinct label, in increasing order of value.
+    pub strata: Vec<StratumSample>,
+    // The labels with fewer rows than were asked for, which were taken entirely.
+    pub undersized: Vec<f64>,
+}
+
+// Samples rows without replacement separately for each distinct value of column `label_col`,
+// taking per_class rows of each (a fraction is rounded per class), and writes them to `dst` in
+// their source order. A first pass counts the rows of each label, treating 0 and -0 as one
+// label and all NaNs as another, and the second selects rows by selection sampling as
+// sample_rows does. The sample depends only on the seed.
+pub fn stratified_sample<T: SupportedType>(a: &Dense<T>, label_col: u64, per_class: SampleSize, dst: &Path,
+                                           seed: u64)
+    -> Result<StratifiedSample<T>, OoclaError> {
+    let d = a.num_cols();
+    if label_col >= d {
+        return Err(OoclaError::InvalidArgument(format!("label column {} is out of range for a matrix with {} columns",
+                                                       label_col, d)));
+    }
+    if let SampleSize::Fraction(fraction) = per_class {
+        if !(0.0..=1.0).contains(&fraction) {
+            return Err(OoclaError::InvalidArgument(format!("sampling fraction {} is outside [0, 1]", fraction)));
+        }
+    }
+    let (labels, index) = count_strata(a, label_col);
+    let wanted = |rows: u64| match per_class {
+        SampleSize::Count(count) => count,
+        SampleSize::Fraction(fraction) => (fraction * rows as f64).round() as u64,
+    };
+    let mut selections: Vec<Selection> = labels.iter()
+        .map(|&(_, rows)| Selection::new(rows, cmp::min(wanted(rows), rows)))
+        .collect();
+    let mut strata: Vec<StratumSample> = labels.iter()
+        .map(|&(value, rows)| StratumSample { value, rows, sampled: 0 })
+        .collect();
+    let mut rng = seeded_rng(seed);
+    let mut writer = RowWriter::create(dst, d)?;
+    let mut scratch = vec![T::default(); d as usize];
+    for i in 0..a.num_rows() {
+        let row = a.row_view(i, &mut scratch);
+        let g = index[&stratum_key(row[label_col as usize].to_f64())];
+        if selections[g].take(&mut rng) {
+            strata[g].sampled += 1;
+            writer.write_row(row)?;
+        }
+    }
+    strata.sort_by(|x, y| x.value.total_cmp(&y.value));
+    let undersized = strata.iter().filter(|s| wanted(s.rows) > s.rows).map(|s| s.value).collect();
+    Ok(StratifiedSample {
+        sample: writer.finish()?,
+        strata,
+        undersized,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    // Row i is (i, -i), so each output row names the source row it came from.
+    fn numbered(n: u64) -> Dense<f64> {
+        Dense::anonymous_from_fn(n, 2, |i, j| if j == 0 { i as f64 } else { -(i as f64) })
+    }
+
+    // The source rows of a sample of numbered(), checking that each one exists in the source.
+    fn source_rows(sample: &Dense<f64>, n: u64) -> Vec<u64> {
+        (0..sample.num_rows()).map(|r| {
+            let i = sample.get(r, 0);
+            assert!(i >= 0.0 && i < n as f64 && i.fract() == 0.0, "row {} is {}", r, i);
+            assert_eq!(sample.get(r, 1), -i, "row {} does not exist in the source", r);
+            i as u64
+        }).collect()
+    }
+
+    fn sample(a: &Dense<f64>, size: SampleSize, with_replacement: bool, seed: u64) -> Vec<u64> {
+        let dst = TempMatrixPath::new();
+        let result = sample_rows(a, dst.path(), size, with_replacement, seed).unwrap();
+        assert_eq!(result.num_cols(), a.num_cols());
+        source_rows(&result, a.num_rows())
+    }
+
+    #[test]
+    fn counts_without_replacement_give_distinct_rows_in_source_order() {
+        let a = numbered(100);
+        let mut hits = vec![0u32; 100];
+        for seed in 0..400 {
+            let rows = sample(&a, SampleSize::Count(25), false, seed);
+            assert_eq!(rows.len(), 25);
+            assert!(rows.windows(2).all(|w| w[0] < w[1]), "seed {}: {:?}", seed, rows);
+            for &i in &rows {
+                hits[i as usize] += 1;
+            }
+        }
+        // Each row is taken with probability 1/4, so about 100 times in 400 samples; the
+        // standard deviation of the count is about 8.7.
+        for (i, &count) in hits.iter().enumerate() {
+            assert!(count > 60 && count < 140, "row {} sampled {} times", i, count);
+        }
+        assert_eq!(sample(&a, SampleSize::Count(100), false, 1), (0..100).collect::<Vec<_>>());
+        assert!(sample(&a, SampleSize::Count(0), false, 1).is_empty());
+    }
+
+    #[test]
+    fn fractions_without_replacement_give_binomial_sizes() {
+        let a = numbered(100);
+        let sizes: Vec<f64> = (0..400).map(|seed| {
+            let rows = sample(&a, SampleSize::Fraction(0.3), false, seed);
+            assert!(rows.windows(2).all(|w| w[0] < w[1]));
+            rows.len() as f64
+        }).collect();
+        // Binomial(100, 0.3) has mean 30 and variance 21.
+        let mean = sizes.iter().sum::<f64>() / 400.0;
+        let variance = sizes.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() / 399.0;
+        assert!((mean - 30.0).abs() < 1.0, "mean size {}", mean);
+        assert!(variance > 14.0 && variance < 30.0, "size variance {}", variance);
+        assert!(sample(&a, SampleSize::Fraction(0.0), false, 1).is_empty());
+        assert_eq!(sample(&a, SampleSize::Fraction(1.0), false, 1).len(), 100);
+    }
+
+    #[test]
+    fn sampling_with_replacement_gives_exact_sizes_in_source_order() {
+        let a = numbered(10);
+        let rows = sample(&a, SampleSize::Count(50), true, 3);
+        assert_eq!(rows.len(), 50);
+        assert!(rows.windows(2).all(|w| w[0] <= w[1]));
+        // 50 draws from 10 rows must repeat some.
+        let mut distinct = rows.clone();
+        distinct.dedup();
+        assert!(distinct.len() < 50);
+        // round(0.25 * 10) = 3 draws, and a fraction above one is allowed.
+        assert_eq!(sample(&a, SampleSize::Fraction(0.25), true, 3).len(), 3);
+        assert_eq!(sample(&a, SampleSize::Fraction(2.5), true, 3).len(), 25);
+    }
+
+    #[test]
+    fn samples_depend_only_on_the_seed() {
+        let a = numbered(1000);
+        for &(size, with_replacement) in &[(SampleSize::Count(100), false), (SampleSize::Fraction(0.1), false),
+                                           (SampleSize::Count(100), true), (SampleSize::Fraction(0.1), true)] {
+            let first = sample(&a, size, with_replacement, 42);
+            assert_eq!(first, sample(&a, size, with_replacement, 42), "{:?} {}", size, with_replacement);
+            assert_ne!(first, sample(&a, size, with_replacement, 43), "{:?} {}", size, with_replacement);
+        }
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        let a = numbered(10);
+        let dst = TempMatrixPath::new();
+        assert!(sample_rows(&a, dst.path(), SampleSize::Count(11), false, 0).is_err());
+        assert!(sample_rows(&a, dst.path(), SampleSize::Fraction(1.5), false, 0).is_err());
+        assert!(sample_rows(&a, dst.path(), SampleSize::Fraction(-0.1), true, 0).is_err());
+        assert!(sample_rows(&a, dst.path(), SampleSize::Fraction(f64::NAN), true, 0).is_err());
+        let empty = numbered(0);
+        assert!(sample_rows(&empty, dst.path(), SampleSize::Count(1), true, 0).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use dense_matrix::{Dense, StorageType};
+use error::OoclaError;
+use std::path::Path;
+
+// The files a matrix was split into, by rows.
+pub struct Shards<T> {
+    pub parts: Vec<Dense<T>>,
+    // Shard s holds global rows [starts[s], starts[s + 1]); the last entry is the total row count.
+    pub starts: Vec<u64>,
+}
+
+impl<T> Shards<T> {
+    // The shard holding global row `row` and its index within that shard.
+    pub fn locate(&self, row: u64) -> Option<(usize, u64)> {
+        if row >= *self.starts.last().unwrap_or(&0) {
+            return None;
+        }
+        let shard = self.starts.partition_point(|&start| start <= row) - 1;
+        Some((shard, row - self.starts[shard]))
+    }
+}
+
+// Writes consecutive runs of rows with the given (non-zero) sizes to files named by putting the
+// shard index in place of "{}" in the pattern.
+fn write_shards<T: StorageType>(a: &Dense<T>, dst_pattern: &str, sizes: &[u64]) -> Result<Shards<T>, OoclaError> {
+    if !dst_pattern.contains("{}") {
+        return Err(OoclaError::InvalidArgument(format!("shard pattern \"{}\" has no {{}} for the shard index",
+                                                       dst_pattern)));
+    }
+    let mut parts = Vec::with_capacity(sizes.len());
+    let mut starts = vec![0];
+    let mut row = vec![T::default(); a.num_cols() as usize];
+    let mut next = 0;
+    for (s, &size) in sizes.iter().enumerate() {
+        let path = dst_pattern.replace("{}", &s.to_string());
+        let mut part = Dense::create(Path::new(&path), size, a.num_cols())?;
+        for i in 0..size {
+            part.write_row(i, a.row_view(next, &mut row));
+            next += 1;
+        }
+        parts.push(part);
+        starts.push(next);
+    }
+    Ok(Shards { parts, starts })
+}
+
+// Splits the rows of A, in order, into `shards` files whose row counts differ by at most one,
+// reading A once. When A has fewer rows than shards only non-empty shards are written.
+pub fn shard_rows<T: StorageType>(a: &Dense<T>, dst_pattern: &str, shards: u64) -> Result<Shards<T>, OoclaError> {
+    if shards == 0 {
+        return Err(OoclaError::InvalidArgument("shards must be non-zero".to_string()));
+    }
+    let n = a.num_rows();
+    let (base, extra) = (n / shards, n % shards);
+    let sizes: Vec<u64> = (0..shards).map(|s| base + u64::from(s < extra)).take_while(|&size| size > 0).collect();
+    write_shards(a, dst_pattern, &sizes)
+}
+
+// Splits the rows of A, in order, into files of `rows_per_shard` rows, the last holding whatever
+// remains.
+pub fn shard_by_rows<T: StorageType>(a: &Dense<T>, dst_pattern: &str, rows_per_shard: u64)
+    -> Result<Shards<T>, OoclaError> {
+    if rows_per_shard == 0 {
+        return Err(OoclaError::InvalidArgument("rows_per_shard must be non-zero".to_string()));
+    }
+    let n = a.num_rows();
+    let sizes: Vec<u64> = (0..n).step_by(rows_per_shard as usize).map(|start| rows_per_shard.min(n - start)).collect();
+    write_shards(a, dst_pattern, &sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use ops::vconcat;
+    use std::fs;
+
+    // A shard pattern beside a temporary path, whose shards are removed when it is dropped.
+    struct ShardPattern(TempMatrixPath);
+
+    impl ShardPattern {
+        fn new() -> ShardPattern {
+            ShardPattern(TempMatrixPath::new())
+        }
+
+        fn pattern(&self) -> String {
+            format!("{}.part-{{}}", self.0.path().display())
+        }
+
+        fn shard_path(&self, s: usize) -> String {
+            self.pattern().replace("{}", &s.to_string())
+        }
+    }
+
+    impl Drop for ShardPattern {
+        fn drop(&mut self) {
+            for s in 0..32 {
+                let _ = fs::remove_file(self.shard_path(s));
+            }
+        }
+    }
+
+    fn elements(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        (0..a.num_rows()).map(|i| (0..a.num_cols()).map(|j| a.get(i, j)).collect()).collect()
+    }
+
+    fn source(transposed: bool) -> Dense<f64> {
+        if transposed {
+            let mut a = Dense::anonymous_from_fn(3, 23, |j, i| (10 * i + j) as f64);
+            a.transpose();
+            a
+        } else {
+            Dense::anonymous_from_fn(23, 3, |i, j| (10 * i + j) as f64)
+        }
+    }
+
+    fn check_reassembles(a: &Dense<f64>, shards: &Shards<f64>) {
+        let parts: Vec<&Dense<f64>> = shards.parts.iter().collect();
+        let dst = TempMatrixPath::new();
+        assert_eq!(elements(&vconcat(&parts, dst.path()).unwrap()), elements(a));
+        for row in 0..a.num_rows() {
+            let (s, local) = shards.locate(row).unwrap();
+            assert_eq!(shards.parts[s].get(local, 0), a.get(row, 0));
+        }
+        assert_eq!(shards.locate(a.num_rows()), None);
+    }
+
+    #[test]
+    fn even_shards_reassemble_to_the_source() {
+        for &transposed in &[false, true] {
+            let a = source(transposed);
+            let pattern = ShardPattern::new();
+            let shards = shard_rows(&a, &pattern.pattern(), 5).unwrap();
+            assert_eq!(shards.starts, vec![0, 5, 10, 15, 19, 23]);
+            assert!(Path::new(&pattern.shard_path(4)).exists());
+            check_reassembles(&a, &shards);
+        }
+    }
+
+    #[test]
+    fn fixed_size_shards_reassemble_to_the_source() {
+        let a = source(false);
+        let pattern = ShardPattern::new();
+        let shards = shard_by_rows(&a, &pattern.pattern(), 10).unwrap();
+        assert_eq!(shards.starts, vec![0, 10, 20, 23]);
+        check_reassembles(&a, &shards);
+        // No empty final shard when the rows divide exactly.
+        let pattern = ShardPattern::new();
+        let a = Dense::<f64>::anonymous_from_fn(20, 2, |i, j| (i + j) as f64);
+        let shards = shard_by_rows(&a, &pattern.pattern(), 10).unwrap();
+        assert_eq!(shards.parts.len(), 2);
+        assert!(!Path::new(&pattern.shard_path(2)).exists());
+    }
+
+    #[test]
+    fn more_shards_than_rows_writes_only_non_empty_shards() {
+        let a = Dense::<f64>::anonymous_from_fn(3, 2, |i, j| (i + j) as f64);
+        let pattern = ShardPattern::new();
+        let shards = shard_rows(&a, &pattern.pattern(), 8).unwrap();
+        assert_eq!(shards.starts, vec![0, 1, 2, 3]);
+        assert!(!Path::new(&pattern.shard_path(3)).exists());
+        check_reassembles(&a, &shards);
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        let a = source(false);
+        let pattern = ShardPattern::new();
+        assert!(shard_rows(&a, &pattern.pattern(), 0).is_err());
+        assert!(shard_by_rows(&a, &pattern.pattern(), 0).is_err());
+        assert!(shard_rows(&a, pattern.0.path().to_str().unwrap(), 2).is_err());
+    }
+}
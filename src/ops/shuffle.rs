@@ -0,0 +1,94 @@
+use dense_matrix::{Dense, StorageType, temp_matrix_path};
+use error::OoclaError;
+use ops::rng::seeded_rng;
+use progress::{Meter, NoProgress, Progress, ProgressUnit};
+use rand::Rng;
+use row_writer::RowWriter;
+use std::cmp;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+// Buckets are sized to be shuffled in memory at about this many bytes each.
+const SHUFFLE_BUCKET_BYTES: u64 = 64 << 20;
+const SHUFFLE_MAX_BUCKETS: u64 = 1024;
+const SHUFFLE_BUCKET_BUFFER: usize = 64 << 10;
+
+// Writes the rows of A to `dst` in an order drawn uniformly at random, determined by the seed.
+// Rows are first scattered into buckets on disk, each row independently to a uniformly chosen
+// bucket so that every bucket is written sequentially; each bucket is then shuffled in memory
+// and appended to `dst`, so A is read once in order and the output written once in order.
+//
+// The result is uniform: given the bucket sizes s_1..s_B, which rows land in which bucket is
+// uniform over the n! / (s_1!···s_B!) ways of splitting the rows, and each bucket's order is
+// uniform over its s_b! arrangements, so every permutation has probability 1 / n! whatever the
+// sizes turn out to be.
+pub fn shuffle_rows<T: StorageType>(a: &Dense<T>, dst: &Path, seed: u64) -> Result<Dense<T>, OoclaError> {
+    shuffle_rows_progress(a, dst, seed, &NoProgress)
+}
+
+// As shuffle_rows, reporting to `progress` the bytes of rows written, which counts each row
+// twice: once into its bucket and once into `dst`. On cancellation or any other error the
+// buckets and `dst` are removed.
+pub fn shuffle_rows_progress<T: StorageType>(a: &Dense<T>, dst: &Path, seed: u64, progress: &dyn Progress)
+    -> Result<Dense<T>, OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols());
+    let row_bytes = cmp::max(d * mem::size_of::<T>() as u64, 1);
+    let buckets = n.saturating_mul(row_bytes).div_ceil(SHUFFLE_BUCKET_BYTES).clamp(1, SHUFFLE_MAX_BUCKETS);
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("shuffle_rows", rows = n, cols = d, buckets).entered();
+    let mut rng = seeded_rng(seed);
+    let paths: Vec<_> = (0..buckets).map(|_| temp_matrix_path()).collect();
+    let mut writers = Vec::with_capacity(buckets as usize);
+    for path in paths.iter() {
+        writers.push(RowWriter::<T>::create_with_capacity(path, d, SHUFFLE_BUCKET_BUFFER)?);
+    }
+    let written_row_bytes = d * mem::size_of::<T>() as u64;
+    let mut meter = Meter::new(progress, Some(2 * n * written_row_bytes), ProgressUnit::Bytes);
+    let mut row = vec![T::default(); d as usize];
+    for i in 0..n {
+        a.read_row(i, &mut row);
+        writers[rng.gen_range(0, buckets) as usize].write_row(&row)?;
+        meter.advance(written_row_bytes)?;
+    }
+    let mut out = Dense::create(dst, n, d)?;
+    gather_buckets(writers, &paths, &mut rng, &mut out, &mut meter).inspect_err(|_| {
+        let _ = fs::remove_file(dst);
+    })?;
+    meter.finish();
+    Ok(out)
+}
+
+// Shuffles each bucket in turn into the rows of `out`.
+fn gather_buckets<T: StorageType, R: Rng>(writers: Vec<RowWriter<T>>, paths: &[PathBuf], rng: &mut R,
+                                         out: &mut Dense<T>, meter: &mut Meter) -> Result<(), OoclaError> {
+    let mut row = vec![T::default(); out.num_cols() as usize];
+    let row_bytes = out.num_cols() * mem::size_of::<T>() as u64;
+    let mut next = 0;
+    for (writer, path) in writers.into_iter().zip(paths.iter()) {
+        let bucket = writer.finish();
+        // The mapping keeps the data alive, so the file can go straight away.
+        let _ = fs::remove_file(path);
+        let bucket = bucket?;
+        let mut order: Vec<u64> = (0..bucket.num_rows()).collect();
+        rng.shuffle(&mut order);
+        for &r in order.iter() {
+            bucket.read_row(r, &mut row);
+            out.write_row(next, &row);
+            next += 1;
+            meter.advance(row_bytes)?;
+        }
+    }
+    Ok(())
+}
+
+// Shuffles the rows of A in place by Fisher-Yates, for when there is no room for a copy. Each
+// swap touches a random row, so on a matrix much larger than memory this costs a random read
+// and write per row; shuffle_rows is far faster when the space is available.
+pub fn shuffle_rows_inplace<T: StorageType>(a: &mut Dense<T>, seed: u64) {
+    let mut rng = seeded_rng(seed);
+    for i in (1..a.num_rows()).rev() {
+        let j = rng.gen_range(0, i + 1);
+        a.swap_rows(i, j);
+    }
+}
@@ -0,0 +1,541 @@
+use dense_matrix::{Lanes, LanesMut, SupportedType};
+use std::{cmp, mem};
+
+// Kernels over contiguous runs of elements, vectorised with AVX for f32 and f64 where the CPU
+// has it, as detected at run time, and scalar otherwise. Elementwise kernels give the same
+// results either way. Reductions asked to be deterministic add their terms one at a time in
+// order; otherwise they keep several partial sums, which reassociates the sum: the result may
+// differ from the scalar one by a few ulps of the sum of the magnitudes of the terms, and may
+// overflow where the scalar one doesn't or vice versa. Like the scalar ones, they accumulate in
+// f64 whatever the element type.
+
+#[cfg(target_arch = "x86_64")]
+fn vectorise() -> bool {
+    is_x86_feature_detected!("avx")
+}
+
+// The sum of the elements of `run`.
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+pub fn sum<T: SupportedType>(run: &[T], deterministic: bool) -> f64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if !deterministic && vectorise() {
+            match T::lanes(run) {
+                Lanes::F32(run) => return unsafe { avx::sum_f32(run, false) },
+                Lanes::F64(run) => return unsafe { avx::sum_f64(run, false) },
+                Lanes::Other(_) => {}
+            }
+        }
+    }
+    run.iter().map(|x| x.to_f64()).sum()
+}
+
+// The sum of the magnitudes of the elements of `run`.
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+pub(crate) fn sum_abs<T: SupportedType>(run: &[T], deterministic: bool) -> f64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if !deterministic && vectorise() {
+            match T::lanes(run) {
+                Lanes::F32(run) => return unsafe { avx::sum_f32(run, true) },
+                Lanes::F64(run) => return unsafe { avx::sum_f64(run, true) },
+                Lanes::Other(_) => {}
+            }
+        }
+    }
+    run.iter().map(|x| x.to_f64().abs()).sum()
+}
+
+// The dot product of two runs of the same length.
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+pub fn dot<T: SupportedType>(x: &[T], y: &[T], deterministic: bool) -> f64 {
+    assert_eq!(x.len(), y.len(), "dot product of runs of lengths {} and {}", x.len(), y.len());
+    #[cfg(target_arch = "x86_64")]
+    {
+        if !deterministic && vectorise() {
+            match (T::lanes(x), T::lanes(y)) {
+                (Lanes::F32(x), Lanes::F32(y)) => return unsafe { avx::dot_f32(x, y) },
+                (Lanes::F64(x), Lanes::F64(y)) => return unsafe { avx::dot_f64(x, y) },
+                _ => {}
+            }
+        }
+    }
+    x.iter().zip(y.iter()).map(|(x, y)| x.to_f64() * y.to_f64()).sum()
+}
+
+// Adds `alpha` times each element of `x` to the f64 accumulator alongside it in `y`.
+pub fn axpy<T: SupportedType>(alpha: f64, x: &[T], y: &mut [f64]) {
+    assert_eq!(x.len(), y.len(), "axpy of runs of lengths {} and {}", x.len(), y.len());
+    #[cfg(target_arch = "x86_64")]
+    {
+        if vectorise() {
+            match T::lanes(x) {
+                Lanes::F32(x) => return unsafe { avx::axpy_f32(alpha, x, y) },
+                Lanes::F64(x) => return unsafe { avx::axpy_f64(alpha, x, y) },
+                Lanes::Other(_) => {}
+            }
+        }
+    }
+    for (y, x) in y.iter_mut().zip(x.iter()) {
+        *y += alpha * x.to_f64();
+    }
+}
+
+// Multiplies each element of `run` by `alpha`, in the precision of the elements.
+pub fn scale<T: SupportedType>(run: &mut [T], alpha: T) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if vectorise() {
+            match T::lanes_mut(run) {
+                LanesMut::F32(run) => return unsafe { avx::scale_f32(run, alpha.to_f64() as f32) },
+                LanesMut::F64(run) => return unsafe { avx::scale_f64(run, alpha.to_f64()) },
+                LanesMut::Other(_) => {}
+            }
+        }
+    }
+    let alpha = alpha.to_f64();
+    for x in run.iter_mut() {
+        *x = T::from_f64(x.to_f64() * alpha);
+    }
+}
+
+// The largest magnitude among the elements of `run`, ignoring NaNs, or zero if there are none.
+pub fn max_abs<T: SupportedType>(run: &[T]) -> f64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if vectorise() {
+            match T::lanes(run) {
+                Lanes::F32(run) => return unsafe { avx::max_abs_f32(run) },
+                Lanes::F64(run) => return unsafe { avx::max_abs_f64(run) },
+                Lanes::Other(_) => {}
+            }
+        }
+    }
+    run.iter().fold(0.0, |acc, x| acc.max(x.to_f64().abs()))
+}
+
+fn clip_scalar<T: SupportedType>(run: &mut [T], lo: T, hi: T) -> (u64, u64) {
+    let (lo_value, hi_value) = (lo.to_f64(), hi.to_f64());
+    let (mut clamped, mut nans) = (0, 0);
+    for element in run.iter_mut() {
+        let x = element.to_f64();
+        if x.is_nan() {
+            nans += 1;
+        } else if x < lo_value {
+            *element = lo;
+            clamped += 1;
+        } else if x > hi_value {
+            *element = hi;
+            clamped += 1;
+        }
+    }
+    (clamped, nans)
+}
+
+// Clamps the elements of `run` to [lo, hi], leaving NaNs alone, and returns the number clamped
+// and the number of NaNs.
+pub(crate) fn clip<T: SupportedType>(run: &mut [T], lo: T, hi: T) -> (u64, u64) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if vectorise() {
+            match T::lanes_mut(run) {
+                LanesMut::F32(run) => return unsafe { avx::clip_f32(run, lo.to_f64() as f32, hi.to_f64() as f32) },
+                LanesMut::F64(run) => return unsafe { avx::clip_f64(run, lo.to_f64(), hi.to_f64()) },
+                LanesMut::Other(_) => {}
+            }
+        }
+    }
+    clip_scalar(run, lo, hi)
+}
+
+// Elements of a fill written at a time from a staging buffer, which stays in L1.
+const FILL_STAGE_BYTES: usize = 16 << 10;
+
+// Writes runs of elements, with non-temporal stores if asked for and the CPU has AVX, and
+// ordinary ones otherwise. Non-temporal stores are weakly ordered, so dropping the writer
+// fences them, after which they are ordered before everything that follows.
+pub(crate) struct RunWriter {
+    non_temporal: bool,
+}
+
+impl RunWriter {
+    #[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+    pub(crate) fn new(non_temporal: bool) -> RunWriter {
+        #[cfg(target_arch = "x86_64")]
+        {
+            RunWriter { non_temporal: non_temporal && vectorise() }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            RunWriter { non_temporal: false }
+        }
+    }
+
+    pub(crate) fn copy<T: Copy>(&self, src: &[T], dst: &mut [T]) {
+        assert_eq!(src.len(), dst.len(), "copy of a run of length {} to one of length {}", src.len(), dst.len());
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.non_temporal {
+                return unsafe {
+                    avx::stream_copy(src.as_ptr() as *const u8, dst.as_mut_ptr() as *mut u8, mem::size_of_val(src))
+                };
+            }
+        }
+        dst.copy_from_slice(src);
+    }
+
+    pub(crate) fn fill<T: Copy>(&self, run: &mut [T], value: T) {
+        if !self.non_temporal {
+            for x in run.iter_mut() {
+                *x = value;
+            }
+            return;
+        }
+        let stage_len = cmp::max(1, FILL_STAGE_BYTES / cmp::max(1, mem::size_of::<T>()));
+        let staged = vec![value; cmp::min(run.len(), stage_len)];
+        for chunk in run.chunks_mut(stage_len) {
+            self.copy(&staged[..chunk.len()], chunk);
+        }
+    }
+}
+
+impl Drop for RunWriter {
+    fn drop(&mut self) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.non_temporal {
+                unsafe {
+                    ::std::arch::x86_64::_mm_sfence()
+                };
+            }
+        }
+    }
+}
+
+// Each kernel handles its run in whole vectors and finishes what remains with the scalar code,
+// so it takes runs of any length and alignment. They may only be called once AVX is known to
+// be available.
+#[cfg(target_arch = "x86_64")]
+mod avx {
+    use std::arch::x86_64::*;
+    use super::clip_scalar;
+    use std::{cmp, ptr};
+
+    #[target_feature(enable = "avx")]
+    unsafe fn horizontal_sum(v: __m256d) -> f64 {
+        let pair = _mm_add_pd(_mm256_castpd256_pd128(v), _mm256_extractf128_pd(v, 1));
+        _mm_cvtsd_f64(_mm_add_sd(pair, _mm_unpackhi_pd(pair, pair)))
+    }
+
+    // With `abs` the sign bit of each element is cleared before it is added.
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn sum_f64(run: &[f64], abs: bool) -> f64 {
+        let sign = _mm256_set1_pd(if abs { -0.0 } else { 0.0 });
+        let (mut acc0, mut acc1) = (_mm256_setzero_pd(), _mm256_setzero_pd());
+        let chunks = run.chunks_exact(8);
+        let tail = chunks.remainder();
+        for chunk in chunks {
+            let p = chunk.as_ptr();
+            acc0 = _mm256_add_pd(acc0, _mm256_andnot_pd(sign, _mm256_loadu_pd(p)));
+            acc1 = _mm256_add_pd(acc1, _mm256_andnot_pd(sign, _mm256_loadu_pd(p.add(4))));
+        }
+        let total = horizontal_sum(_mm256_add_pd(acc0, acc1));
+        tail.iter().fold(total, |acc, &x| acc + if abs { x.abs() } else { x })
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn sum_f32(run: &[f32], abs: bool) -> f64 {
+        let sign = _mm256_set1_pd(if abs { -0.0 } else { 0.0 });
+        let (mut acc0, mut acc1) = (_mm256_setzero_pd(), _mm256_setzero_pd());
+        let chunks = run.chunks_exact(8);
+        let tail = chunks.remainder();
+        for chunk in chunks {
+            let p = chunk.as_ptr();
+            acc0 = _mm256_add_pd(acc0, _mm256_andnot_pd(sign, _mm256_cvtps_pd(_mm_loadu_ps(p))));
+            acc1 = _mm256_add_pd(acc1, _mm256_andnot_pd(sign, _mm256_cvtps_pd(_mm_loadu_ps(p.add(4)))));
+        }
+        let total = horizontal_sum(_mm256_add_pd(acc0, acc1));
+        tail.iter().fold(total, |acc, &x| acc + if abs { (x as f64).abs() } else { x as f64 })
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn dot_f64(x: &[f64], y: &[f64]) -> f64 {
+        let (mut acc0, mut acc1) = (_mm256_setzero_pd(), _mm256_setzero_pd());
+        let whole = x.len() / 8 * 8;
+        for i in (0..whole).step_by(8) {
+            let (p, q) = (x.as_ptr().add(i), y.as_ptr().add(i));
+            acc0 = _mm256_add_pd(acc0, _mm256_mul_pd(_mm256_loadu_pd(p), _mm256_loadu_pd(q)));
+            acc1 = _mm256_add_pd(acc1, _mm256_mul_pd(_mm256_loadu_pd(p.add(4)), _mm256_loadu_pd(q.add(4))));
+        }
+        let total = horizontal_sum(_mm256_add_pd(acc0, acc1));
+        x[whole..].iter().zip(y[whole..].iter()).fold(total, |acc, (x, y)| acc + x * y)
+    }
+
+    // Products of f32s are exact in f64, so only the order of the sum differs from the scalar
+    // kernel.
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn dot_f32(x: &[f32], y: &[f32]) -> f64 {
+        let (mut acc0, mut acc1) = (_mm256_setzero_pd(), _mm256_setzero_pd());
+        let whole = x.len() / 8 * 8;
+        for i in (0..whole).step_by(8) {
+            let (p, q) = (x.as_ptr().add(i), y.as_ptr().add(i));
+            let (x0, y0) = (_mm256_cvtps_pd(_mm_loadu_ps(p)), _mm256_cvtps_pd(_mm_loadu_ps(q)));
+            let (x1, y1) = (_mm256_cvtps_pd(_mm_loadu_ps(p.add(4))), _mm256_cvtps_pd(_mm_loadu_ps(q.add(4))));
+            acc0 = _mm256_add_pd(acc0, _mm256_mul_pd(x0, y0));
+            acc1 = _mm256_add_pd(acc1, _mm256_mul_pd(x1, y1));
+        }
+        let total = horizontal_sum(_mm256_add_pd(acc0, acc1));
+        x[whole..].iter().zip(y[whole..].iter()).fold(total, |acc, (&x, &y)| acc + x as f64 * y as f64)
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn axpy_f64(alpha: f64, x: &[f64], y: &mut [f64]) {
+        let a = _mm256_set1_pd(alpha);
+        let whole = x.len() / 4 * 4;
+        for i in (0..whole).step_by(4) {
+            let q = y.as_mut_ptr().add(i);
+            _mm256_storeu_pd(q, _mm256_add_pd(_mm256_loadu_pd(q), _mm256_mul_pd(a, _mm256_loadu_pd(x.as_ptr().add(i)))));
+        }
+        for (y, x) in y[whole..].iter_mut().zip(x[whole..].iter()) {
+            *y += alpha * x;
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn axpy_f32(alpha: f64, x: &[f32], y: &mut [f64]) {
+        let a = _mm256_set1_pd(alpha);
+        let whole = x.len() / 4 * 4;
+        for i in (0..whole).step_by(4) {
+            let q = y.as_mut_ptr().add(i);
+            let xs = _mm256_cvtps_pd(_mm_loadu_ps(x.as_ptr().add(i)));
+            _mm256_storeu_pd(q, _mm256_add_pd(_mm256_loadu_pd(q), _mm256_mul_pd(a, xs)));
+        }
+        for (y, &x) in y[whole..].iter_mut().zip(x[whole..].iter()) {
+            *y += alpha * x as f64;
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn scale_f64(run: &mut [f64], alpha: f64) {
+        let a = _mm256_set1_pd(alpha);
+        let mut chunks = run.chunks_exact_mut(4);
+        for chunk in chunks.by_ref() {
+            let p = chunk.as_mut_ptr();
+            _mm256_storeu_pd(p, _mm256_mul_pd(_mm256_loadu_pd(p), a));
+        }
+        for x in chunks.into_remainder() {
+            *x *= alpha;
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn scale_f32(run: &mut [f32], alpha: f32) {
+        let a = _mm256_set1_ps(alpha);
+        let mut chunks = run.chunks_exact_mut(8);
+        for chunk in chunks.by_ref() {
+            let p = chunk.as_mut_ptr();
+            _mm256_storeu_ps(p, _mm256_mul_ps(_mm256_loadu_ps(p), a));
+        }
+        for x in chunks.into_remainder() {
+            *x *= alpha;
+        }
+    }
+
+    // max returns its second operand when either is NaN, so NaN elements never reach the
+    // running maximum, as with f64::max.
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn max_abs_f64(run: &[f64]) -> f64 {
+        let sign = _mm256_set1_pd(-0.0);
+        let mut acc = _mm256_setzero_pd();
+        let chunks = run.chunks_exact(4);
+        let tail = chunks.remainder();
+        for chunk in chunks {
+            acc = _mm256_max_pd(_mm256_andnot_pd(sign, _mm256_loadu_pd(chunk.as_ptr())), acc);
+        }
+        let mut lanes = [0.0; 4];
+        _mm256_storeu_pd(lanes.as_mut_ptr(), acc);
+        lanes.iter().chain(tail.iter()).fold(0.0, |acc, x| acc.max(x.abs()))
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn max_abs_f32(run: &[f32]) -> f64 {
+        let sign = _mm256_set1_ps(-0.0);
+        let mut acc = _mm256_setzero_ps();
+        let chunks = run.chunks_exact(8);
+        let tail = chunks.remainder();
+        for chunk in chunks {
+            acc = _mm256_max_ps(_mm256_andnot_ps(sign, _mm256_loadu_ps(chunk.as_ptr())), acc);
+        }
+        let mut lanes = [0.0f32; 8];
+        _mm256_storeu_ps(lanes.as_mut_ptr(), acc);
+        lanes.iter().chain(tail.iter()).fold(0.0, |acc, &x| acc.max((x as f64).abs()))
+    }
+
+    // Ordered comparisons are false for NaNs, which are left as they are.
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn clip_f64(run: &mut [f64], lo: f64, hi: f64) -> (u64, u64) {
+        let (low, high) = (_mm256_set1_pd(lo), _mm256_set1_pd(hi));
+        let (mut clamped, mut nans) = (0, 0);
+        let mut chunks = run.chunks_exact_mut(4);
+        for chunk in chunks.by_ref() {
+            let p = chunk.as_mut_ptr();
+            let x = _mm256_loadu_pd(p);
+            let below = _mm256_cmp_pd(x, low, _CMP_LT_OQ);
+            let above = _mm256_cmp_pd(x, high, _CMP_GT_OQ);
+            _mm256_storeu_pd(p, _mm256_blendv_pd(_mm256_blendv_pd(x, low, below), high, above));
+            clamped += _mm256_movemask_pd(_mm256_or_pd(below, above)).count_ones() as u64;
+            nans += _mm256_movemask_pd(_mm256_cmp_pd(x, x, _CMP_UNORD_Q)).count_ones() as u64;
+        }
+        let (tail_clamped, tail_nans) = clip_scalar(chunks.into_remainder(), lo, hi);
+        (clamped + tail_clamped, nans + tail_nans)
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn clip_f32(run: &mut [f32], lo: f32, hi: f32) -> (u64, u64) {
+        let (low, high) = (_mm256_set1_ps(lo), _mm256_set1_ps(hi));
+        let (mut clamped, mut nans) = (0, 0);
+        let mut chunks = run.chunks_exact_mut(8);
+        for chunk in chunks.by_ref() {
+            let p = chunk.as_mut_ptr();
+            let x = _mm256_loadu_ps(p);
+            let below = _mm256_cmp_ps(x, low, _CMP_LT_OQ);
+            let above = _mm256_cmp_ps(x, high, _CMP_GT_OQ);
+            _mm256_storeu_ps(p, _mm256_blendv_ps(_mm256_blendv_ps(x, low, below), high, above));
+            clamped += _mm256_movemask_ps(_mm256_or_ps(below, above)).count_ones() as u64;
+            nans += _mm256_movemask_ps(_mm256_cmp_ps(x, x, _CMP_UNORD_Q)).count_ones() as u64;
+        }
+        let (tail_clamped, tail_nans) = clip_scalar(chunks.into_remainder(), lo, hi);
+        (clamped + tail_clamped, nans + tail_nans)
+    }
+
+    // Copies `len` bytes with non-temporal stores of whole 32-byte vectors, and ordinary ones
+    // for whatever lies before the first 32-byte boundary of `dst` or after the last.
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn stream_copy(src: *const u8, dst: *mut u8, len: usize) {
+        let head = cmp::min(len, dst.align_offset(32));
+        ptr::copy_nonoverlapping(src, dst, head);
+        let end = head + (len - head) / 32 * 32;
+        for i in (head..end).step_by(32) {
+            _mm256_stream_si256(dst.add(i) as *mut __m256i, _mm256_loadu_si256(src.add(i) as *const __m256i));
+        }
+        ptr::copy_nonoverlapping(src.add(end), dst.add(end), len - end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lengths around the 4- and 8-element vectors and the two accumulators of the reductions.
+    const LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 7, 8, 9, 15, 16, 17, 31, 33, 100, 1023];
+
+    // Elements of mixed sign and magnitude, starting a run `offset` elements into the buffer so
+    // that the kernels see unaligned runs too.
+    fn run<T: SupportedType>(len: usize, offset: usize, seed: usize) -> Vec<T> {
+        (0..len + offset).map(|i| {
+            let x = ((i * 7919 + seed * 104_729) % 2003) as f64 - 1001.0;
+            T::from_f64(x / 17.0 * if i % 5 == 0 { 1e3 } else { 1.0 })
+        }).collect()
+    }
+
+    fn bits<T: SupportedType>(run: &[T]) -> Vec<u64> {
+        run.iter().map(|x| x.to_f64().to_bits()).collect()
+    }
+
+    // Whether a reassociated sum of `len` terms is within the documented tolerance of the scalar
+    // one, the sum of whose magnitudes is `magnitude`.
+    fn close(vectorised: f64, scalar: f64, len: usize, magnitude: f64) -> bool {
+        (vectorised - scalar).abs() <= 4.0 * len as f64 * f64::EPSILON * magnitude
+    }
+
+    fn check_reductions<T: SupportedType>() {
+        for &len in LENGTHS.iter() {
+            for offset in 0..2 {
+                let (x, y) = (run::<T>(len, offset, 1), run::<T>(len, offset, 2));
+                let (x, y) = (&x[offset..], &y[offset..]);
+                let scalar_sum: f64 = x.iter().map(|x| x.to_f64()).sum();
+                let magnitude: f64 = x.iter().map(|x| x.to_f64().abs()).sum();
+                assert_eq!(sum(x, true).to_bits(), scalar_sum.to_bits(), "length {}", len);
+                assert_eq!(sum_abs(x, true).to_bits(), magnitude.to_bits(), "length {}", len);
+                assert!(close(sum(x, false), scalar_sum, len, magnitude), "length {}", len);
+                assert!(close(sum_abs(x, false), magnitude, len, magnitude), "length {}", len);
+                let scalar_dot: f64 = x.iter().zip(y.iter()).map(|(x, y)| x.to_f64() * y.to_f64()).sum();
+                let dot_magnitude: f64 = x.iter().zip(y.iter()).map(|(x, y)| (x.to_f64() * y.to_f64()).abs()).sum();
+                assert_eq!(dot(x, y, true).to_bits(), scalar_dot.to_bits(), "length {}", len);
+                assert!(close(dot(x, y, false), scalar_dot, len, dot_magnitude), "length {}", len);
+                if len == 0 {
+                    assert_eq!((sum(x, false), sum_abs(x, false), dot(x, y, false)), (0.0, 0.0, 0.0));
+                }
+            }
+        }
+    }
+
+    fn check_elementwise<T: SupportedType>() {
+        for &len in LENGTHS.iter() {
+            for offset in 0..2 {
+                let x = run::<T>(len, offset, 3);
+                let x = &x[offset..];
+                let mut y: Vec<f64> = run::<f64>(len, 0, 4);
+                let mut expected = y.clone();
+                for (y, x) in expected.iter_mut().zip(x.iter()) {
+                    *y += -0.375 * x.to_f64();
+                }
+                axpy(-0.375, x, &mut y);
+                assert_eq!(bits(&y), bits(&expected), "axpy of length {}", len);
+
+                let mut scaled = x.to_vec();
+                scale(&mut scaled, T::from_f64(1.0 / 3.0));
+                let expected: Vec<T> = x.iter().map(|x| T::from_f64(x.to_f64() * T::from_f64(1.0 / 3.0).to_f64()))
+                    .collect();
+                assert_eq!(bits(&scaled), bits(&expected), "scale of length {}", len);
+
+                // Every third element NaN, which max_abs ignores and clip counts and leaves alone.
+                let mut with_nans = x.to_vec();
+                for element in with_nans.iter_mut().step_by(3) {
+                    *element = T::from_f64(f64::NAN);
+                }
+                let expected = with_nans.iter().fold(0.0, |acc: f64, x| acc.max(x.to_f64().abs()));
+                assert_eq!(max_abs(&with_nans).to_bits(), expected.to_bits(), "max_abs of length {}", len);
+                assert_eq!(max_abs(x).to_bits(), x.iter().fold(0.0, |acc: f64, x| acc.max(x.to_f64().abs())).to_bits());
+
+                let (lo, hi) = (T::from_f64(-20.0), T::from_f64(25.5));
+                let mut clipped = with_nans.clone();
+                let mut expected = with_nans.clone();
+                let counts = clip(&mut clipped, lo, hi);
+                assert_eq!(counts, clip_scalar(&mut expected, lo, hi), "clip counts of length {}", len);
+                assert_eq!(counts.1, len.div_ceil(3) as u64);
+                assert_eq!(bits(&clipped), bits(&expected), "clip of length {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn reductions_match_the_scalar_kernels() {
+        check_reductions::<f32>();
+        check_reductions::<f64>();
+    }
+
+    #[test]
+    fn elementwise_kernels_match_the_scalar_kernels() {
+        check_elementwise::<f32>();
+        check_elementwise::<f64>();
+    }
+
+    #[test]
+    fn run_writers_copy_and_fill_runs_of_any_length() {
+        for &non_temporal in [false, true].iter() {
+            let writer = RunWriter::new(non_temporal);
+            for &len in LENGTHS.iter().chain([FILL_STAGE_BYTES / 8 + 3].iter()) {
+                for offset in 0..3 {
+                    let src = run::<f64>(len, 0, 5);
+                    let mut dst = vec![0.0; len + offset];
+                    writer.copy(&src, &mut dst[offset..]);
+                    assert_eq!(bits(&dst[offset..]), bits(&src), "copy of length {}", len);
+                    writer.fill(&mut dst[offset..], 2.5f64);
+                    assert!(dst[offset..].iter().all(|&x| x == 2.5), "fill of length {}", len);
+                    assert!(dst[..offset].iter().all(|&x| x == 0.0));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use ops::blas::{default_block_rows, tile_gemm_acc};
+use ops::distance::row_squared_norms;
+use ops::topk::TopK;
+use std::cmp;
+use std::path::Path;
+use tile::{DEFAULT_TILE_SIZE, Tile};
+
+// Reciprocal row norms, with zero for zero-norm rows so that they have zero similarity to
+// everything.
+fn inverse_norms<T: SupportedType>(a: &Dense<T>) -> Vec<f64> {
+    row_squared_norms(a).into_iter().map(|s| if s > 0.0 { 1.0 / s.sqrt() } else { 0.0 }).collect()
+}
+
+// Cosine similarities between the rows [i0, i0 + rows) of A and [j0, j0 + cols) of B, given
+// row tiles spanning all columns.
+fn similarity_block(a_rows: &Tile<f32>, a_inv: &[f64], b_rows_t: &Tile<f32>, b_inv: &[f64]) -> Vec<f64> {
+    let (rows, cols) = (a_rows.rows, b_rows_t.cols);
+    let mut acc = vec![0.0; rows * cols];
+    tile_gemm_acc(1.0, a_rows, b_rows_t, &mut acc);
+    for (r, row) in acc.chunks_mut(cmp::max(cols, 1)).enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = (*value * a_inv[r] * b_inv[c]).clamp(-1.0, 1.0);
+        }
+    }
+    acc
+}
+
+// Writes the matrix of cosine similarities between the rows of A and those of B (or of A
+// itself when B is None) to `dst`. Rows with zero norm have similarity zero to every row.
+pub fn cosine_similarity(a: &Dense<f32>, b: Option<&Dense<f32>>, dst: &Path) -> Result<Dense<f32>, OoclaError> {
+    let b = b.unwrap_or(a);
+    if b.num_cols() != a.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (b.num_rows(), a.num_cols()),
+            found: (b.num_rows(), b.num_cols()),
+        });
+    }
+    let (n, m, d) = (a.num_rows(), b.num_rows(), a.num_cols() as usize);
+    let (a_inv, b_inv) = (inverse_norms(a), inverse_norms(b));
+    let mut out = Dense::create(dst, n, m)?;
+    let step = DEFAULT_TILE_SIZE as u64;
+    for i0 in (0..n).step_by(DEFAULT_TILE_SIZE) {
+        let rows = cmp::min(step, n - i0) as usize;
+        let a_rows = a.read_tile(i0, 0, rows, d);
+        for j0 in (0..m).step_by(DEFAULT_TILE_SIZE) {
+            let cols = cmp::min(step, m - j0) as usize;
+            let b_rows_t = b.read_tile(j0, 0, cols, d).transposed();
+            let block = similarity_block(&a_rows, &a_inv[i0 as usize..], &b_rows_t, &b_inv[j0 as usize..]);
+            out.write_tile(&Tile {
+                row: i0,
+                col: j0,
+                rows,
+                cols,
+                data: block.into_iter().map(|x| x as f32).collect(),
+            });
+        }
+    }
+    Ok(out)
+}
+
+pub struct SimilarRows {
+    // For each row, up to k (row, similarity) pairs, most similar first.
+    pub neighbours: Vec<Vec<(u64, f32)>>,
+    // Rows with zero norm, which have no neighbours and appear in no other row's list.
+    pub zero_norm_rows: Vec<u64>,
+}
+
+// Finds the k rows most cosine-similar to each row of A, excluding the row itself, without
+// materialising the similarity matrix. Blocks of rows are compared pairwise over the upper
+// triangle of block pairs, each row block staying resident while the blocks after it stream
+// past, and every similarity is offered to the lists of both rows. Ties go to the lower row
+// index.
+pub fn top_k_similar(a: &Dense<f32>, k: usize) -> SimilarRows {
+    let (n, d) = (a.num_rows(), a.num_cols() as usize);
+    let inv = inverse_norms(a);
+    let zero_norm_rows: Vec<u64> = (0..n).filter(|&i| inv[i as usize] == 0.0).collect();
+    let mut heaps: Vec<TopK> = (0..n).map(|_| TopK::new(k)).collect();
+    let block = cmp::min(DEFAULT_TILE_SIZE, default_block_rows(d as u64)) as u64;
+    if k > 0 {
+        for i0 in (0..n).step_by(block as usize) {
+            let rows = cmp::min(block, n - i0) as usize;
+            let hot = a.read_tile(i0, 0, rows, d);
+            for j0 in (i0..n).step_by(block as usize) {
+                let cols = cmp::min(block, n - j0) as usize;
+                let streamed = a.read_tile(j0, 0, cols, d).transposed();
+                let sims = similarity_block(&hot, &inv[i0 as usize..], &streamed, &inv[j0 as usize..]);
+                for r in 0..rows {
+                    let i = i0 + r as u64;
+                    if inv[i as usize] == 0.0 {
+                        continue;
+                    }
+                    for c in 0..cols {
+                        let j = j0 + c as u64;
+                        if j <= i || inv[j as usize] == 0.0 {
+                            continue;
+                        }
+                        let s = sims[r * cols + c];
+                        heaps[i as usize].push(s, j);
+                        heaps[j as usize].push(s, i);
+                    }
+                }
+            }
+        }
+    }
+    SimilarRows {
+        neighbours: heaps.into_iter()
+            .map(|h| h.into_sorted().into_iter().map(|(j, s)| (j, s as f32)).collect())
+            .collect(),
+        zero_norm_rows,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+
+    fn brute_force_cosine(a: &Dense<f32>, i: u64, j: u64) -> f64 {
+        let d = a.num_cols();
+        let dot: f64 = (0..d).map(|c| a.get(i, c) as f64 * a.get(j, c) as f64).sum();
+        let norm = |r| (0..d).map(|c| (a.get(r, c) as f64).powi(2)).sum::<f64>().sqrt();
+        dot / (norm(i) * norm(j))
+    }
+
+    // Three tight clusters of four rows around the first three axes, then a zero row.
+    fn clustered_corpus() -> Dense<f32> {
+        Dense::anonymous_from_fn(13, 4, |i, j| {
+            if i == 12 {
+                return 0.0;
+            }
+            let (cluster, member) = (i / 4, i % 4);
+            if j == cluster {
+                (member + 1) as f32
+            } else if j == 3 {
+                0.05 * member as f32
+            } else {
+                0.0
+            }
+        })
+    }
+
+    #[test]
+    fn neighbours_in_a_clustered_corpus_are_the_rest_of_the_cluster() {
+        let a = clustered_corpus();
+        let similar = top_k_similar(&a, 3);
+        assert_eq!(similar.zero_norm_rows, vec![12]);
+        assert!(similar.neighbours[12].is_empty());
+        for i in 0..12u64 {
+            let neighbours = &similar.neighbours[i as usize];
+            let mut found: Vec<u64> = neighbours.iter().map(|&(j, _)| j).collect();
+            found.sort();
+            let expected: Vec<u64> = (i / 4 * 4..i / 4 * 4 + 4).filter(|&j| j != i).collect();
+            assert_eq!(found, expected, "row {}", i);
+            for pair in neighbours.windows(2) {
+                assert!(pair[0].1 >= pair[1].1);
+            }
+            for &(j, s) in neighbours {
+                assert!((s as f64 - brute_force_cosine(&a, i, j)).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn ties_go_to_the_lower_row() {
+        // Rows 1, 2 and 3 are identical, so each is equally similar to row 0 and to each other.
+        let a = Dense::<f32>::anonymous_from_fn(4, 2, |i, j| if i == 0 && j == 1 { 0.0 } else { 1.0 });
+        let similar = top_k_similar(&a, 2);
+        assert_eq!(similar.neighbours[0].iter().map(|&(j, _)| j).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(similar.neighbours[3].iter().map(|&(j, _)| j).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn many_blocks_match_brute_force() {
+        let mut a = Dense::<f32>::create_anonymous(1100, 3).unwrap();
+        a.randomise_seeded(4);
+        let similar = top_k_similar(&a, 4);
+        for &i in &[0u64, 1, 511, 512, 700, 1099] {
+            let mut all: Vec<(f64, u64)> = (0..1100).filter(|&j| j != i).map(|j| (brute_force_cosine(&a, i, j), j)).collect();
+            all.sort_by(|x, y| y.0.partial_cmp(&x.0).unwrap());
+            let found: Vec<u64> = similar.neighbours[i as usize].iter().map(|&(j, _)| j).collect();
+            let expected: Vec<u64> = all[..4].iter().map(|&(_, j)| j).collect();
+            assert_eq!(found, expected, "row {}", i);
+        }
+    }
+
+    #[test]
+    fn similarity_matrix_matches_brute_force() {
+        let a = clustered_corpus();
+        let dst = TempMatrixPath::new();
+        let s = cosine_similarity(&a, None, dst.path()).unwrap();
+        assert_eq!((s.num_rows(), s.num_cols()), (13, 13));
+        for i in 0..12 {
+            for j in 0..12 {
+                assert!((s.get(i, j) as f64 - brute_force_cosine(&a, i, j)).abs() < 1e-6);
+            }
+            assert_eq!(s.get(i, 12), 0.0);
+        }
+        let other = Dense::<f32>::create_anonymous(2, 3).unwrap();
+        let dst = TempMatrixPath::new();
+        assert!(cosine_similarity(&a, Some(&other), dst.path()).is_err());
+    }
+
+    #[test]
+    fn zero_k_gives_empty_lists() {
+        let similar = top_k_similar(&clustered_corpus(), 0);
+        assert!(similar.neighbours.iter().all(|n| n.is_empty()));
+    }
+}
@@ -0,0 +1,160 @@
+use std::cmp::{self, Ordering};
+use std::f64;
+
+pub const DEFAULT_SKETCH_SIZE: usize = 200;
+
+fn compare(a: &f64, b: &f64) -> Ordering {
+    a.partial_cmp(b).unwrap_or(Ordering::Equal)
+}
+
+// A KLL sketch. Level h holds items that each stand for 2^h inputs; when the sketch fills up,
+// the lowest full level is sorted and every other item (from a randomly chosen starting offset)
+// is promoted to the level above.
+//
+// The sketch keeps O(k) items however many values it sees. The rank of each estimated quantile
+// is within about 1.7/k of the requested one with high probability, so roughly 1% of the count
+// at the default size of 200. A merged sketch carries the same guarantee as one sketch over all
+// the inputs. NaNs are counted but take no part in the estimates.
+#[derive(Clone, Debug)]
+pub struct QuantileSketch {
+    k: usize,
+    levels: Vec<Vec<f64>>,
+    size: usize,
+    count: u64,
+    nan_count: u64,
+    min: f64,
+    max: f64,
+    state: u64,
+}
+
+impl QuantileSketch {
+    pub fn new(k: usize) -> QuantileSketch {
+        QuantileSketch {
+            k: cmp::max(k, 8),
+            levels: vec![Vec::new()],
+            size: 0,
+            count: 0,
+            nan_count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            state: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    fn capacity(&self, level: usize) -> usize {
+        let depth = self.levels.len() - level - 1;
+        cmp::max(2, (self.k as f64 * (2.0f64 / 3.0).powi(depth as i32)).ceil() as usize)
+    }
+
+    fn total_capacity(&self) -> usize {
+        (0..self.levels.len()).map(|h| self.capacity(h)).sum()
+    }
+
+    fn random_bit(&mut self) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 63) as usize
+    }
+
+    // The number of non-NaN values inserted.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn nan_count(&self) -> u64 {
+        self.nan_count
+    }
+
+    pub fn insert(&mut self, x: f64) {
+        if x.is_nan() {
+            self.nan_count += 1;
+            return;
+        }
+        self.count += 1;
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        self.levels[0].push(x);
+        self.size += 1;
+        if self.size >= self.total_capacity() {
+            self.compress();
+        }
+    }
+
+    fn compress(&mut self) {
+        for h in 0..self.levels.len() {
+            if self.levels[h].len() < self.capacity(h) {
+                continue;
+            }
+            if h + 1 == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            let offset = self.random_bit();
+            let mut items = ::std::mem::take(&mut self.levels[h]);
+            items.sort_by(compare);
+            // An odd item out stays behind so that total weight is preserved.
+            if items.len() % 2 == 1 {
+                let last = items.pop().unwrap();
+                self.levels[h].push(last);
+            }
+            let promoted: Vec<f64> = items.iter().skip(offset).step_by(2).cloned().collect();
+            self.size -= items.len() - promoted.len();
+            self.levels[h + 1].extend(promoted);
+            return;
+        }
+    }
+
+    // Adds the values seen by another sketch to this one, which keeps its own size parameter.
+    pub fn merge(&mut self, other: &QuantileSketch) {
+        while self.levels.len() < other.levels.len() {
+            self.levels.push(Vec::new());
+        }
+        for (h, level) in other.levels.iter().enumerate() {
+            self.levels[h].extend_from_slice(level);
+        }
+        self.size += other.size;
+        self.count += other.count;
+        self.nan_count += other.nan_count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        while self.size >= self.total_capacity() {
+            self.compress();
+        }
+    }
+
+    // The estimate for quantile q is the smallest retained value whose weighted rank reaches
+    // q times the count, with q clamped to [0, 1]; q = 0 and q = 1 give the exact minimum and
+    // maximum. Returns NaN for a NaN q, and for every quantile when no non-NaN values have been
+    // seen.
+    pub fn quantiles(&self, qs: &[f64]) -> Vec<f64> {
+        if self.count == 0 {
+            return vec![f64::NAN; qs.len()];
+        }
+        let mut weighted: Vec<(f64, u64)> = Vec::with_capacity(self.size);
+        for (h, level) in self.levels.iter().enumerate() {
+            weighted.extend(level.iter().map(|&x| (x, 1u64 << h)));
+        }
+        weighted.sort_by(|a, b| compare(&a.0, &b.0));
+        let total: u64 = weighted.iter().map(|&(_, w)| w).sum();
+        qs.iter().map(|&q| {
+            if q.is_nan() {
+                return f64::NAN;
+            }
+            if q <= 0.0 {
+                return self.min;
+            }
+            if q >= 1.0 {
+                return self.max;
+            }
+            let target = q * total as f64;
+            let mut cumulative = 0;
+            for &(x, w) in weighted.iter() {
+                cumulative += w;
+                if cumulative as f64 >= target {
+                    return x;
+                }
+            }
+            self.max
+        }).collect()
+    }
+}
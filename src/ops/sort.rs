@@ -0,0 +1,243 @@
+use budget::MemoryBudget;
+use dense_matrix::{Dense, SupportedType, temp_matrix_path};
+use error::OoclaError;
+use progress::{Meter, NoProgress, Progress, ProgressUnit};
+use row_writer::RowWriter;
+use std::cmp::{self, Ordering};
+use std::collections::BinaryHeap;
+use std::fs;
+use std::mem;
+use std::path::Path;
+
+// The memory sort_rows_by_column spends on each sorted run.
+pub const DEFAULT_SORT_BUDGET_BYTES: usize = 256 << 20;
+
+// Each run holds a file descriptor and a mapping during the merge.
+const SORT_MAX_RUNS: u64 = 512;
+
+// Orders sort keys ascending with NaNs after everything else.
+fn compare_keys(x: f64, y: f64) -> Ordering {
+    match (x.is_nan(), y.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => x.partial_cmp(&y).unwrap(),
+    }
+}
+
+// The next row of a run during the merge; ordered so that BinaryHeap's maximum is the row to
+// emit next, with earlier runs first among equal keys so that the merge is stable.
+struct Head {
+    key: f64,
+    run: usize,
+    pos: u64,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        compare_keys(other.key, self.key).then(other.run.cmp(&self.run))
+    }
+}
+
+// Writes the rows of A to `dst` ordered by the values in column `key_col`, ascending or
+// descending, with NaN keys last either way. The sort is stable. See
+// sort_rows_by_column_budgeted.
+pub fn sort_rows_by_column<T: SupportedType>(a: &Dense<T>, dst: &Path, key_col: u64, descending: bool)
+    -> Result<Dense<T>, OoclaError> {
+    sort_rows_by_column_budgeted(a, dst, key_col, descending, DEFAULT_SORT_BUDGET_BYTES)
+}
+
+// An external merge sort. A is read in chunks of rows that fit in `memory_budget_bytes`, each
+// chunk is sorted in memory and, unless it is the only one, spilled to a temporary matrix as a
+// sorted run; the runs are then merged into `dst` in one pass of sequential writes. Temporary
+// files are unlinked as soon as they are written, so nothing is left behind on error. A budget
+// so small that it would take more than 512 runs is stretched to make 512.
+pub fn sort_rows_by_column_budgeted<T: SupportedType>(a: &Dense<T>, dst: &Path, key_col: u64, descending: bool,
+                                                      memory_budget_bytes: usize)
+    -> Result<Dense<T>, OoclaError> {
+    sort_rows_by_column_with(a, dst, key_col, descending, memory_budget_bytes, &MemoryBudget::unlimited())
+}
+
+// As sort_rows_by_column_budgeted, with chunks that fit within the smaller of
+// `memory_budget_bytes` and the limit of `budget`, from which the chunk buffer is reserved until
+// the runs are written.
+pub fn sort_rows_by_column_with<T: SupportedType>(a: &Dense<T>, dst: &Path, key_col: u64, descending: bool,
+                                                  memory_budget_bytes: usize, budget: &MemoryBudget)
+    -> Result<Dense<T>, OoclaError> {
+    sort_rows_by_column_progress(a, dst, key_col, descending, memory_budget_bytes, budget, &NoProgress)
+}
+
+// As sort_rows_by_column_with, reporting to `progress` the bytes of rows written, which counts
+// each row twice when the sort spills runs: once into its run and once more by the merge. On
+// cancellation or any other error the runs and `dst` are removed.
+pub fn sort_rows_by_column_progress<T: SupportedType>(a: &Dense<T>, dst: &Path, key_col: u64, descending: bool,
+                                                      memory_budget_bytes: usize, budget: &MemoryBudget,
+                                                      progress: &dyn Progress)
+    -> Result<Dense<T>, OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols());
+    if key_col >= d {
+        return Err(OoclaError::InvalidArgument(format!("cannot sort by column {} of a matrix with {} columns", key_col, d)));
+    }
+    let key = |value: T| if descending { -value.to_f64() } else { value.to_f64() };
+    let row_bytes = d as usize * mem::size_of::<T>() + mem::size_of::<(f64, usize)>();
+    let chunk_rows = cmp::max(budget.cap(memory_budget_bytes) / row_bytes, 1) as u64;
+    let chunk_rows = cmp::min(cmp::max(chunk_rows, n.div_ceil(SORT_MAX_RUNS)), cmp::max(n, 1));
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("sort_rows_by_column", rows = n, cols = d, key_col, chunk_rows).entered();
+    let reserved = budget.alloc((chunk_rows as usize).saturating_mul(row_bytes))?;
+    let width = d as usize;
+    let written_row_bytes = d * mem::size_of::<T>() as u64;
+    let passes = if chunk_rows >= n { 1 } else { 2 };
+    let mut meter = Meter::new(progress, Some(passes * n * written_row_bytes), ProgressUnit::Bytes);
+    let mut runs = Vec::new();
+    let mut chunk = Vec::new();
+    for start in (0..n).step_by(chunk_rows as usize) {
+        let rows = cmp::min(chunk_rows, n - start) as usize;
+        chunk.resize(rows * width, T::from_f64(0.0));
+        a.stream_rows(start..start + rows as u64, |r, row| {
+            chunk[(r - start) as usize * width..][..width].copy_from_slice(row);
+            Ok(())
+        })?;
+        let mut order: Vec<(f64, usize)> = chunk.chunks(width).map(|row| key(row[key_col as usize])).zip(0..).collect();
+        order.sort_by(|x, y| compare_keys(x.0, y.0));
+        let only_run = start == 0 && rows as u64 == n;
+        let path = if only_run { dst.to_path_buf() } else { temp_matrix_path() };
+        let mut writer = RowWriter::create_with_options(&path, d, a.stream_options())?;
+        for &(_, r) in order.iter() {
+            writer.write_row(&chunk[r * width..(r + 1) * width])?;
+            meter.advance(written_row_bytes)?;
+        }
+        let run = writer.finish();
+        if only_run {
+            meter.finish();
+            return run;
+        }
+        let _ = fs::remove_file(&path);
+        runs.push(run?);
+    }
+    if runs.is_empty() {
+        meter.finish();
+        return Dense::create(dst, 0, d);
+    }
+    drop(chunk);
+    drop(reserved);
+    #[cfg(feature = "tracing")]
+    tracing::debug!(runs = runs.len(), "merging sorted runs");
+    let mut out = RowWriter::create_with_options(dst, d, a.stream_options())?;
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (run, matrix) in runs.iter().enumerate() {
+        heap.push(Head { key: key(matrix.get(0, key_col)), run, pos: 0 });
+    }
+    let mut row = vec![T::from_f64(0.0); width];
+    while let Some(Head { run, pos, .. }) = heap.pop() {
+        let matrix = &runs[run];
+        matrix.read_row(pos, &mut row);
+        out.write_row(&row)?;
+        meter.advance(written_row_bytes)?;
+        if pos + 1 < matrix.num_rows() {
+            heap.push(Head { key: key(matrix.get(pos + 1, key_col)), run, pos: pos + 1 });
+        }
+    }
+    let out = out.finish()?;
+    meter.finish();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use budget::BudgetPolicy;
+    use dense_matrix::TempMatrixPath;
+    use std::f64;
+
+    // Row i is (i, key, 2i), with keys from a small set so that many repeat, and every seventh
+    // key NaN.
+    fn keyed_rows(n: u64) -> Dense<f64> {
+        Dense::anonymous_from_fn(n, 3, |i, j| match j {
+            0 => i as f64,
+            1 if i % 7 == 3 => f64::NAN,
+            1 => ((i * 37) % 11) as f64 - 5.0,
+            _ => 2.0 * i as f64,
+        })
+    }
+
+    // The source rows in sorted order, by a stable in-memory sort.
+    fn reference_order(a: &Dense<f64>, descending: bool) -> Vec<u64> {
+        let key = |i: u64| if descending { -a.get(i, 1) } else { a.get(i, 1) };
+        let mut order: Vec<u64> = (0..a.num_rows()).collect();
+        order.sort_by(|&x, &y| compare_keys(key(x), key(y)));
+        order
+    }
+
+    fn check_sorted(sorted: &Dense<f64>, a: &Dense<f64>, descending: bool) {
+        assert_eq!((sorted.num_rows(), sorted.num_cols()), (a.num_rows(), a.num_cols()));
+        for (r, i) in reference_order(a, descending).into_iter().enumerate() {
+            let r = r as u64;
+            assert_eq!(sorted.get(r, 0), i as f64, "row {} descending {}", r, descending);
+            assert_eq!(sorted.get(r, 1).to_bits(), a.get(i, 1).to_bits());
+            assert_eq!(sorted.get(r, 2), a.get(i, 2));
+        }
+    }
+
+    #[test]
+    fn data_over_budget_is_sorted_stably_with_nans_last() {
+        let a = keyed_rows(1000);
+        // 40 bytes per row including the sort index, so 25 rows to a run and 40 runs.
+        for &descending in &[false, true] {
+            let dst = TempMatrixPath::new();
+            let sorted = sort_rows_by_column_budgeted(&a, dst.path(), 1, descending, 1000).unwrap();
+            check_sorted(&sorted, &a, descending);
+            assert!(sorted.get(999, 1).is_nan());
+        }
+    }
+
+    #[test]
+    fn a_memory_budget_bounds_the_runs() {
+        let a = keyed_rows(1000);
+        let budget = MemoryBudget::new(4000, BudgetPolicy::Fail);
+        let dst = TempMatrixPath::new();
+        let sorted = sort_rows_by_column_with(&a, dst.path(), 1, false, DEFAULT_SORT_BUDGET_BYTES, &budget).unwrap();
+        check_sorted(&sorted, &a, false);
+        assert!(budget.peak() <= 4000);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[test]
+    fn data_within_budget_is_sorted_in_a_single_run() {
+        let a = keyed_rows(100);
+        let dst = TempMatrixPath::new();
+        let sorted = sort_rows_by_column(&a, dst.path(), 1, true).unwrap();
+        check_sorted(&sorted, &a, true);
+    }
+
+    #[test]
+    fn a_budget_too_small_for_512_runs_is_stretched() {
+        let a = keyed_rows(2000);
+        let dst = TempMatrixPath::new();
+        let sorted = sort_rows_by_column_budgeted(&a, dst.path(), 1, false, 1).unwrap();
+        check_sorted(&sorted, &a, false);
+    }
+
+    #[test]
+    fn empty_matrices_and_bad_key_columns() {
+        let dst = TempMatrixPath::new();
+        let sorted = sort_rows_by_column(&keyed_rows(0), dst.path(), 1, false).unwrap();
+        assert_eq!((sorted.num_rows(), sorted.num_cols()), (0, 3));
+        let dst = TempMatrixPath::new();
+        assert!(sort_rows_by_column(&keyed_rows(5), dst.path(), 3, false).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use ops::rng::seeded_rng;
+use ops::sample::Selection;
+use row_writer::RowWriter;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StratumCounts {
+    pub value: f64,
+    pub train: u64,
+    pub test: u64,
+}
+
+pub struct TrainTestSplit<T> {
+    pub train: Dense<T>,
+    pub test: Dense<T>,
+    // Row counts for each distinct value of the stratifying column, in increasing order of
+    // value; empty when the split is not stratified.
+    pub strata: Vec<StratumCounts>,
+}
+
+// Identifies a stratum by its label, treating 0 and -0 as equal and all NaNs as one value.
+pub(crate) fn stratum_key(value: f64) -> u64 {
+    if value == 0.0 {
+        0
+    } else if value.is_nan() {
+        f64::NAN.to_bits()
+    } else {
+        value.to_bits()
+    }
+}
+
+// The distinct labels in column `col` of A in order of first appearance, each with its number
+// of rows, and the position of each label's stratum_key in that list.
+pub(crate) fn count_strata<T: SupportedType>(a: &Dense<T>, col: u64) -> (Vec<(f64, u64)>, HashMap<u64, usize>) {
+    let mut labels: Vec<(f64, u64)> = Vec::new();
+    let mut index = HashMap::new();
+    for i in 0..a.num_rows() {
+        let value = a.get(i, col).to_f64();
+        let g = *index.entry(stratum_key(value)).or_insert_with(|| {
+            labels.push((value, 0));
+            labels.len() - 1
+        });
+        labels[g].1 += 1;
+    }
+    (labels, index)
+}
+
+// The number of test rows for a group of `count` rows. Strictly between 0 and 1 every group of
+// two or more rows puts at least one row in each output, and a group of one stays in train.
+fn test_quota(count: u64, test_fraction: f64) -> u64 {
+    let quota = (test_fraction * count as f64).round() as u64;
+    if test_fraction > 0.0 && test_fraction < 1.0 {
+        if count < 2 {
+            0
+        } else {
+            quota.clamp(1, count - 1)
+        }
+    } else {
+        quota
+    }
+}
+
+// Splits the rows of A between `train_dst` and `test_dst`, keeping their order, with the test
+// set round(test_fraction * n) rows chosen uniformly at random by selection sampling. The split
+// depends only on the seed.
+//
+// With `stratify_by`, rows are grouped by the value in that column and each group is split in
+// the same way, so every label appears in both outputs in the proportions of the whole. This
+// takes an extra pass over the column to count the groups before the pass that writes the
+// rows; in row-major storage that pass touches every page of A.
+pub fn train_test_split<T: SupportedType>(a: &Dense<T>, train_dst: &Path, test_dst: &Path, test_fraction: f64,
+                                          seed: u64, stratify_by: Option<u64>)
+    -> Result<TrainTestSplit<T>, OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols());
+    if !(0.0..=1.0).contains(&test_fraction) {
+        return Err(OoclaError::InvalidArgument(format!("test fraction {} is outside [0, 1]", test_fraction)));
+    }
+    if let Some(col) = stratify_by {
+        if col >= d {
+            return Err(OoclaError::InvalidArgument(format!("cannot stratify by column {} of a matrix with {} columns",
+                                                           col, d)));
+        }
+    }
+    let (labels, index) = match stratify_by {
+        Some(col) => count_strata(a, col),
+        None => (vec![(0.0, n)], HashMap::new()),
+    };
+    let mut groups: Vec<Selection> = labels.iter().map(|&(_, count)| Selection::new(count, test_quota(count, test_fraction)))
+        .collect();
+    let mut counts: Vec<StratumCounts> = labels.iter().map(|&(value, _)| StratumCounts {
+        value,
+        train: 0,
+        test: 0,
+    }).collect();
+
+    let mut rng = seeded_rng(seed);
+    let written = RowWriter::create(train_dst, d).and_then(|mut train| {
+        let mut test = RowWriter::create(test_dst, d)?;
+        let mut row = vec![T::from_f64(0.0); d as usize];
+        for i in 0..n {
+            a.read_row(i, &mut row);
+            let g = match stratify_by {
+                Some(col) => index[&stratum_key(row[col as usize].to_f64())],
+                None => 0,
+            };
+            if groups[g].take(&mut rng) {
+                counts[g].test += 1;
+                test.write_row(&row)?;
+            } else {
+                counts[g].train += 1;
+                train.write_row(&row)?;
+            }
+        }
+        Ok((train.finish()?, test.finish()?))
+    });
+    // Neither output is left behind if the other couldn't be written in full.
+    let (train, test) = written.inspect_err(|_| {
+        let _ = fs::remove_file(train_dst);
+        let _ = fs::remove_file(test_dst);
+    })?;
+    let strata = if stratify_by.is_some() {
+        counts.sort_by(|x, y| x.value.total_cmp(&y.value));
+        counts
+    } else {
+        Vec::new()
+    };
+    Ok(TrainTestSplit {
+        train,
+        test,
+        strata,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{temp_matrix_path, TempMatrixPath};
+
+    // Row i is (i, label), so each output row names the source row it came from.
+    fn labelled(n: u64, label: impl Fn(u64) -> f64) -> Dense<f64> {
+        Dense::anonymous_from_fn(n, 2, |i, j| if j == 0 { i as f64 } else { label(i) })
+    }
+
+    // The source rows in each output of a split of `a`, with the split itself.
+    fn split(a: &Dense<f64>, test_fraction: f64, seed: u64, stratify_by: Option<u64>)
+        -> (Vec<u64>, Vec<u64>, Vec<StratumCounts>) {
+        let (train_dst, test_dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        let result = train_test_split(a, train_dst.path(), test_dst.path(), test_fraction, seed, stratify_by).unwrap();
+        let rows = |m: &Dense<f64>| (0..m.num_rows()).map(|r| m.get(r, 0) as u64).collect::<Vec<_>>();
+        let (train, test) = (rows(&result.train), rows(&result.test));
+        let mut all: Vec<u64> = train.iter().chain(&test).cloned().collect();
+        all.sort_unstable();
+        assert_eq!(all, (0..a.num_rows()).collect::<Vec<_>>(), "each row goes to exactly one output");
+        assert!(train.windows(2).all(|w| w[0] < w[1]) && test.windows(2).all(|w| w[0] < w[1]));
+        (train, test, result.strata)
+    }
+
+    #[test]
+    fn fractions_of_zero_and_one_put_every_row_in_one_output() {
+        let a = labelled(50, |i| (i % 3) as f64);
+        for &stratify_by in &[None, Some(1)] {
+            let (train, test, _) = split(&a, 0.0, 7, stratify_by);
+            assert_eq!((train.len(), test.len()), (50, 0));
+            let (train, test, _) = split(&a, 1.0, 7, stratify_by);
+            assert_eq!((train.len(), test.len()), (0, 50));
+        }
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_split() {
+        let a = labelled(200, |i| (i % 4) as f64);
+        for &stratify_by in &[None, Some(1)] {
+            let first = split(&a, 0.3, 11, stratify_by);
+            assert_eq!(split(&a, 0.3, 11, stratify_by), first);
+            assert!(split(&a, 0.3, 12, stratify_by).1 != first.1);
+            assert_eq!(first.1.len(), 60);
+        }
+    }
+
+    #[test]
+    fn strata_are_split_in_proportion_and_a_single_row_stays_in_train() {
+        // Labels 0 and 1 for 60 and 39 rows, and label 5 for row 99 alone.
+        let a = labelled(100, |i| if i == 99 { 5.0 } else if i % 5 < 3 { 0.0 } else { 1.0 });
+        let (train, test, strata) = split(&a, 0.25, 3, Some(1));
+        assert_eq!(strata, vec![
+            StratumCounts { value: 0.0, train: 45, test: 15 },
+            StratumCounts { value: 1.0, train: 29, test: 10 },
+            StratumCounts { value: 5.0, train: 1, test: 0 },
+        ]);
+        assert!(train.contains(&99) && !test.contains(&99));
+        // However small the fraction, each stratum of two or more rows has a row in each output.
+        let (_, _, strata) = split(&a, 0.001, 3, Some(1));
+        assert_eq!(strata.iter().map(|s| (s.train, s.test)).collect::<Vec<_>>(), vec![(59, 1), (38, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn bad_arguments_are_rejected() {
+        let a = labelled(10, |_| 0.0);
+        let (train_dst, test_dst) = (TempMatrixPath::new(), TempMatrixPath::new());
+        for &(fraction, stratify_by) in &[(-0.1, None), (1.5, None), (f64::NAN, None), (0.5, Some(2))] {
+            match train_test_split(&a, train_dst.path(), test_dst.path(), fraction, 1, stratify_by) {
+                Err(OoclaError::InvalidArgument(_)) => {}
+                other => panic!("expected an invalid argument, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn a_failed_split_leaves_neither_output() {
+        let a = labelled(10, |_| 0.0);
+        let train_dst = TempMatrixPath::new();
+        // The test output can't be created, after the train output has been.
+        let test_dst = temp_matrix_path().join("missing").join("test.mat");
+        assert!(train_test_split(&a, train_dst.path(), &test_dst, 0.5, 1, None).is_err());
+        assert!(fs::symlink_metadata(train_dst.path()).is_err(), "the train output was left behind");
+        assert!(fs::symlink_metadata(&test_dst).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use budget::MemoryBudget;
+use dense_matrix::{AccessPattern, Dense, DiscardBehind, SupportedType};
+use error::OoclaError;
+use ops::blas::{default_block_rows, gram_panels};
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnStats {
+    pub count: u64,
+    pub means: Vec<f64>,
+    pub stds: Vec<f64>,
+    pub constant_columns: Vec<u64>,
+}
+
+// Welford's update, so a single pass is enough without losing precision to cancellation.
+fn welford(count: u64, mean: &mut f64, m2: &mut f64, x: f64) {
+    let delta = x - *mean;
+    *mean += delta / count as f64;
+    *m2 += delta * (x - *mean);
+}
+
+pub fn column_stats<T: SupportedType>(a: &Dense<T>, ddof: u64) -> Result<ColumnStats, OoclaError> {
+    let n = a.num_rows();
+    if n <= ddof {
+        return Err(OoclaError::InvalidArgument(format!("{} rows is too few for ddof = {}", n, ddof)));
+    }
+    let d = a.num_cols() as usize;
+    let _advice = a.advise_scoped(AccessPattern::Sequential);
+    let mut means = vec![0.0; d];
+    let mut m2s = vec![0.0; d];
+    let mut pass = DiscardBehind::new(a);
+    for major in 0..a.major_len() {
+        let line = a.major_slice(major);
+        if a.is_transposed() {
+            let (mean, m2) = (&mut means[major as usize], &mut m2s[major as usize]);
+            for (count, x) in line.iter().enumerate() {
+                welford(count as u64 + 1, mean, m2, x.to_f64());
+            }
+        } else {
+            for ((mean, m2), x) in means.iter_mut().zip(m2s.iter_mut()).zip(line.iter()) {
+                welford(major + 1, mean, m2, x.to_f64());
+            }
+        }
+        pass.advance(a, major + 1)?;
+    }
+    let divisor = (n - ddof) as f64;
+    let stds: Vec<f64> = m2s.iter().map(|m2| (m2 / divisor).sqrt()).collect();
+    let constant_columns = (0..d).filter(|&c| stds[c] == 0.0).map(|c| c as u64).collect();
+    Ok(ColumnStats {
+        count: n,
+        means,
+        stds,
+        constant_columns,
+    })
+}
+
+// Rescales every column to zero mean and unit standard deviation; constant columns become zero.
+// The returned statistics can be applied to another matrix with `apply_standardization`.
+pub fn standardize<T: SupportedType>(a: &mut Dense<T>, ddof: u64) -> Result<ColumnStats, OoclaError> {
+    let stats = column_stats(a, ddof)?;
+    apply_standardization(a, &stats)?;
+    Ok(stats)
+}
+
+pub fn apply_standardization<T: SupportedType>(b: &mut Dense<T>, stats: &ColumnStats) -> Result<(), OoclaError> {
+    if b.num_cols() != stats.means.len() as u64 {
+        return Err(OoclaError::InvalidArgument(format!("statistics for {} columns cannot be applied to a matrix with {}",
+                                                       stats.means.len(), b.num_cols())));
+    }
+    b.standardize_columns(&stats.means, &stats.stds)
+}
+
+pub struct Correlation {
+    pub matrix: Dense<f64>,
+    pub zero_variance_columns: Vec<u64>,
+}
+
+pub fn covariance<T: SupportedType>(a: &Dense<T>, ddof: u64, dst: Option<&Path>) -> Result<Dense<f64>, OoclaError> {
+    covariance_with_means(a, ddof, dst).map(|(result, _)| result)
+}
+
+// The covariance matrix together with the column means, both from the same pass over A.
+pub(crate) fn covariance_with_means<T: SupportedType>(a: &Dense<T>, ddof: u64, dst: Option<&Path>)
+    -> Result<(Dense<f64>, Vec<f64>), OoclaError> {
+    let (n, d) = (a.num_rows(), a.num_cols());
+    if n <= ddof {
+        return Err(OoclaError::InvalidArgument(format!("{} rows is too few for ddof = {}", n, ddof)));
+    }
+    // Accumulating about the first row rather than the origin avoids the catastrophic
+    // cancellation of the textbook single-pass formula when the means are large.
+    let mut shift = vec![T::from_f64(0.0); d as usize];
+    a.read_row(0, &mut shift);
+    let shift: Vec<f64> = shift.iter().map(|v| v.to_f64()).collect();
+
+    let mut result = Dense::create_at(dst, d, d)?;
+    let d = d as usize;
+    let (count, divisor) = (n as f64, (n - ddof) as f64);
+    let mut means = shift.clone();
+    let budget = MemoryBudget::unlimited();
+    gram_panels(a, default_block_rows(d as u64), Some(&shift), &budget, |panel_start, panel_end, acc, sums| {
+        let width = d - panel_start;
+        for i in panel_start..panel_end {
+            for j in i..d {
+                let cross = acc[(i - panel_start) * width + (j - panel_start)];
+                let value = (cross - sums[i] * sums[j] / count) / divisor;
+                result.set(i as u64, j as u64, value);
+                result.set(j as u64, i as u64, value);
+            }
+        }
+        for ((mean, &origin), &sum) in means.iter_mut().zip(shift.iter()).zip(sums.iter()) {
+            *mean = origin + sum / count;
+        }
+        Ok(())
+    })?;
+    Ok((result, means))
+}
+
+// Columns with zero variance have undefined correlation, so their rows and columns of the
+// result are NaN and their indices are listed in `zero_variance_columns`.
+pub fn correlation<T: SupportedType>(a: &Dense<T>, ddof: u64, dst: Option<&Path>) -> Result<Correlation, OoclaError> {
+    let mut matrix = covariance(a, ddof, dst)?;
+    let d = matrix.num_rows();
+    let stds: Vec<f64> = matrix.diagonal_iter().map(|v| v.sqrt()).collect();
+    let zero_variance_columns: Vec<u64> = (0..d).filter(|&i| stds[i as usize].is_nan() || stds[i as usize] <= 0.0).collect();
+    for i in 0..d {
+        for j in 0..d {
+            let (si, sj) = (stds[i as usize], stds[j as usize]);
+            let value = if si > 0.0 && sj > 0.0 {
+                if i == j {
+                    1.0
+                } else {
+                    (matrix.get(i, j) / (si * sj)).clamp(-1.0, 1.0)
+                }
+            } else {
+                f64::NAN
+            };
+            matrix.set(i, j, value);
+        }
+    }
+    Ok(Correlation {
+        matrix,
+        zero_variance_columns,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Element (i, j) of the test matrices: columns with different means and spreads, about a
+    // large offset so that a single-pass formula about the origin would lose precision.
+    fn element(i: u64, j: u64) -> f64 {
+        1.0e6 + (j as f64) * 10.0 + ((i * 7 + j * 13) as f64).sin() * (j + 1) as f64
+    }
+
+    // The covariance by the textbook two passes: the means first, then the centred products.
+    fn reference_covariance(a: &Dense<f64>, ddof: u64) -> Vec<Vec<f64>> {
+        let (n, d) = (a.num_rows(), a.num_cols() as usize);
+        let means: Vec<f64> = (0..d).map(|j| (0..n).map(|i| a.get(i, j as u64)).sum::<f64>() / n as f64).collect();
+        (0..d).map(|j| (0..d).map(|k| {
+            (0..n).map(|i| (a.get(i, j as u64) - means[j]) * (a.get(i, k as u64) - means[k])).sum::<f64>() /
+                (n - ddof) as f64
+        }).collect()).collect()
+    }
+
+    fn assert_matches_reference(a: &Dense<f64>) {
+        for &ddof in &[0, 1, 5] {
+            let expected = reference_covariance(a, ddof);
+            let result = covariance(a, ddof, None).unwrap();
+            assert_eq!((result.num_rows(), result.num_cols()), (a.num_cols(), a.num_cols()));
+            for (j, row) in expected.iter().enumerate() {
+                for (k, &value) in row.iter().enumerate() {
+                    let found = result.get(j as u64, k as u64);
+                    assert!((found - value).abs() <= 1e-9 * value.abs().max(1.0), "ddof {}, ({}, {}): {} against {}",
+                            ddof, j, k, found, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn covariance_matches_a_two_pass_reference() {
+        assert_matches_reference(&Dense::anonymous_from_fn(300, 6, element));
+    }
+
+    #[test]
+    fn covariance_of_a_transposed_matrix_matches_a_two_pass_reference() {
+        let mut a = Dense::anonymous_from_fn(6, 300, |i, j| element(j, i));
+        a.transpose();
+        assert_eq!((a.num_rows(), a.num_cols()), (300, 6));
+        assert_matches_reference(&a);
+    }
+
+    #[test]
+    fn correlation_marks_constant_columns() {
+        let a = Dense::<f64>::anonymous_from_fn(50, 4, |i, j| if j == 2 { 3.5 } else { element(i, j) });
+        let correlation = correlation(&a, 1, None).unwrap();
+        assert_eq!(correlation.zero_variance_columns, vec![2]);
+        let covariance = covariance(&a, 1, None).unwrap();
+        for j in 0..4 {
+            for k in 0..4 {
+                let value = correlation.matrix.get(j, k);
+                if j == 2 || k == 2 {
+                    assert!(value.is_nan(), "({}, {}) is {}", j, k, value);
+                } else if j == k {
+                    assert_eq!(value, 1.0);
+                } else {
+                    let expected = covariance.get(j, k) / (covariance.get(j, j) * covariance.get(k, k)).sqrt();
+                    assert!((value - expected).abs() < 1e-12, "({}, {}): {} against {}", j, k, value, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn too_few_rows_for_ddof_is_rejected() {
+        let a = Dense::<f64>::anonymous_from_fn(3, 2, element);
+        for &ddof in &[3, 4] {
+            match covariance(&a, ddof, None) {
+                Err(OoclaError::InvalidArgument(_)) => {}
+                other => panic!("expected an invalid argument, got {:?}", other.map(|_| ())),
+            }
+            assert!(correlation(&a, ddof, None).is_err());
+        }
+        assert!(covariance(&a, 2, None).is_ok());
+    }
+}
@@ -0,0 +1,208 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::{default_block_rows, gemm, gemm_at_b};
+use ops::qr::tsqr;
+use rand::distributions::normal::StandardNormal;
+use rand::{self, Rng};
+use std::cmp::{self, Ordering};
+use std::f64;
+use tile::{DEFAULT_TILE_SIZE, Tile};
+
+const JACOBI_MAX_SWEEPS: usize = 64;
+
+// A truncated singular value decomposition A ≈ U·diag(s)·Vt with s in decreasing order.
+pub struct Svd {
+    pub u: Dense<f64>,
+    pub s: Vec<f64>,
+    pub vt: Dense<f64>,
+}
+
+// One-sided Jacobi SVD of a row-major k x k matrix, returning (U, s, V) with U and V
+// row-major and s sorted in decreasing order.
+fn jacobi_svd(m: &[f64], k: usize) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+    let mut w = m.to_vec();
+    let mut v = vec![0.0; k * k];
+    for i in 0..k {
+        v[i * k + i] = 1.0;
+    }
+    let rotate = |x: &mut [f64], p: usize, q: usize, c: f64, s: f64| {
+        for row in x.chunks_mut(k) {
+            let (xp, xq) = (row[p], row[q]);
+            row[p] = c * xp - s * xq;
+            row[q] = s * xp + c * xq;
+        }
+    };
+    for _ in 0..JACOBI_MAX_SWEEPS {
+        let mut converged = true;
+        for p in 0..k {
+            for q in p + 1..k {
+                let (mut alpha, mut beta, mut gamma) = (0.0, 0.0, 0.0);
+                for row in w.chunks(k) {
+                    alpha += row[p] * row[p];
+                    beta += row[q] * row[q];
+                    gamma += row[p] * row[q];
+                }
+                if gamma == 0.0 || gamma.abs() <= f64::EPSILON * (alpha * beta).sqrt() {
+                    continue;
+                }
+                converged = false;
+                let zeta = (beta - alpha) / (2.0 * gamma);
+                let t = zeta.signum() / (zeta.abs() + (1.0 + zeta * zeta).sqrt());
+                let c = 1.0 / (1.0 + t * t).sqrt();
+                rotate(&mut w, p, q, c, c * t);
+                rotate(&mut v, p, q, c, c * t);
+            }
+        }
+        if converged {
+            break;
+        }
+    }
+    let norms: Vec<f64> = (0..k).map(|j| w.chunks(k).map(|row| row[j] * row[j]).sum::<f64>().sqrt()).collect();
+    let mut order: Vec<usize> = (0..k).collect();
+    order.sort_by(|&x, &y| norms[y].partial_cmp(&norms[x]).unwrap_or(Ordering::Equal));
+    let mut u = vec![0.0; k * k];
+    let mut v_sorted = vec![0.0; k * k];
+    for (dst, &src) in order.iter().enumerate() {
+        let sigma = norms[src];
+        for i in 0..k {
+            u[i * k + dst] = if sigma > 0.0 { w[i * k + src] / sigma } else { 0.0 };
+            v_sorted[i * k + dst] = v[i * k + src];
+        }
+    }
+    (u, order.iter().map(|&j| norms[j]).collect(), v_sorted)
+}
+
+fn orthonormalize(y: &Dense<f64>) -> Result<Dense<f64>, OoclaError> {
+    let l = y.num_cols();
+    let block_rows = cmp::max(l as usize, default_block_rows(l));
+    let (q, _) = tsqr(y, block_rows, true, None)?;
+    Ok(q.expect("tsqr forms Q when asked to"))
+}
+
+// Copies the leading `cols` columns of a row-major k x k matrix into a new k x cols matrix.
+fn leading_columns(x: &[f64], k: usize, cols: usize) -> Result<Dense<f64>, OoclaError> {
+    let mut dense = Dense::create_anonymous(k as u64, cols as u64)?;
+    let mut tile = Tile::zeros(0, 0, k, cols);
+    for (dst, src) in tile.data.chunks_mut(cols).zip(x.chunks(k)) {
+        dst.copy_from_slice(&src[..cols]);
+    }
+    dense.write_tile(&tile);
+    Ok(dense)
+}
+
+// Rank-`rank` SVD by the Halko-Martinsson-Tropp randomized range finder. A is sketched with
+// rank + oversample Gaussian vectors and each of the `power_iters` power iterations sharpens
+// the sketch for slowly decaying spectra at the cost of two further passes over A. Only
+// matrices with rank + oversample columns are held off-core besides A; the final SVD is of
+// a small square matrix in memory. U and Vt are written to anonymous files.
+pub fn randomized_svd(a: &Dense<f64>, rank: usize, oversample: usize, power_iters: usize) -> Result<Svd, OoclaError> {
+    let (m, n) = (a.num_rows(), a.num_cols());
+    let max_rank = cmp::min(m, n);
+    if rank == 0 || rank as u64 > max_rank {
+        return Err(OoclaError::InvalidArgument(format!("rank must be in 1..={} for a {}x{} matrix", max_rank, m, n)));
+    }
+    let l = cmp::min(rank.saturating_add(oversample) as u64, max_rank);
+    let mut omega = Dense::create_anonymous(n, l)?;
+    let mut rng = rand::thread_rng();
+    for major in 0..omega.major_len() {
+        for x in omega.major_slice_mut(major).iter_mut() {
+            let StandardNormal(g) = rng.gen();
+            *x = g;
+        }
+    }
+    let mut y = Dense::create_anonymous(m, l)?;
+    gemm(1.0, a, &omega, 0.0, &mut y, DEFAULT_TILE_SIZE)?;
+    let mut q = orthonormalize(&y)?;
+    let mut z = omega;
+    for _ in 0..power_iters {
+        gemm_at_b(a, &q, &mut z)?;
+        let qz = orthonormalize(&z)?;
+        gemm(1.0, a, &qz, 0.0, &mut y, DEFAULT_TILE_SIZE)?;
+        q = orthonormalize(&y)?;
+    }
+    // B = Q^T·A is l x n with n possibly huge, so factor B^T = Qb·Rb and take the SVD of the
+    // small matrix Rb^T instead: A ≈ Q·Rb^T·Qb^T.
+    gemm_at_b(a, &q, &mut z)?;
+    let block_rows = cmp::max(l as usize, default_block_rows(l));
+    let (qb, rb) = tsqr(&z, block_rows, true, None)?;
+    let qb = qb.expect("tsqr forms Q when asked to");
+    let k = l as usize;
+    let mut rbt = vec![0.0; k * k];
+    for i in 0..k {
+        for j in 0..k {
+            rbt[j * k + i] = rb[i * k + j];
+        }
+    }
+    let (ur, s, vr) = jacobi_svd(&rbt, k);
+    let mut u = Dense::create_anonymous(m, rank as u64)?;
+    gemm(1.0, &q, &leading_columns(&ur, k, rank)?, 0.0, &mut u, DEFAULT_TILE_SIZE)?;
+    let mut vt = Dense::create_anonymous(n, rank as u64)?;
+    gemm(1.0, &qb, &leading_columns(&vr, k, rank)?, 0.0, &mut vt, DEFAULT_TILE_SIZE)?;
+    vt.transpose();
+    Ok(Svd {
+        u,
+        s: s[..rank].to_vec(),
+        vt,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn orthonormal_columns(rows: u64, cols: u64, seed: u64) -> Dense<f64> {
+        let mut a = Dense::<f64>::create_anonymous(rows, cols).unwrap();
+        a.randomise_seeded(seed);
+        tsqr(&a, rows as usize, true, None).unwrap().0.unwrap()
+    }
+
+    // Q1·diag(spectrum)·Q2^T for random Q1 and Q2 with orthonormal columns, whose singular
+    // values are exactly `spectrum`.
+    fn with_spectrum(m: u64, n: u64, spectrum: &[f64]) -> Dense<f64> {
+        let r = spectrum.len() as u64;
+        let q1 = orthonormal_columns(m, r, 1);
+        let q2 = orthonormal_columns(n, r, 2);
+        Dense::anonymous_from_fn(m, n, |i, j| (0..r).map(|k| q1.get(i, k) * spectrum[k as usize] * q2.get(j, k)).sum())
+    }
+
+    #[test]
+    fn singular_values_of_a_known_spectrum_are_recovered() {
+        let spectrum = [40.0, 9.0, 3.5, 1.0, 0.25];
+        let a = with_spectrum(300, 70, &spectrum);
+        let svd = randomized_svd(&a, 5, 5, 1).unwrap();
+        for (found, expected) in svd.s.iter().zip(spectrum.iter()) {
+            assert!((found - expected).abs() < 1e-9 * spectrum[0], "{} vs {}", found, expected);
+        }
+        assert_eq!((svd.u.num_rows(), svd.u.num_cols()), (300, 5));
+        assert_eq!((svd.vt.num_rows(), svd.vt.num_cols()), (5, 70));
+        for i in 0..300 {
+            for j in 0..70 {
+                let approx: f64 = (0..5).map(|k| svd.u.get(i, k) * svd.s[k as usize] * svd.vt.get(k, j)).sum();
+                assert!((approx - a.get(i, j)).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn truncation_keeps_the_leading_singular_values() {
+        let spectrum = [100.0, 50.0, 1e-3, 1e-4];
+        let a = with_spectrum(120, 40, &spectrum);
+        let svd = randomized_svd(&a, 2, 4, 2).unwrap();
+        assert_eq!(svd.s.len(), 2);
+        assert!((svd.s[0] - 100.0).abs() < 1e-8);
+        assert!((svd.s[1] - 50.0).abs() < 1e-8);
+        for i in 0..2 {
+            for j in 0..2 {
+                let dot: f64 = (0..120).map(|r| svd.u.get(r, i) * svd.u.get(r, j)).sum();
+                assert!((dot - if i == j { 1.0 } else { 0.0 }).abs() < 1e-10);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_ranks_are_rejected() {
+        let a = Dense::<f64>::create_anonymous(10, 4).unwrap();
+        assert!(randomized_svd(&a, 0, 2, 0).is_err());
+        assert!(randomized_svd(&a, 5, 2, 0).is_err());
+    }
+}
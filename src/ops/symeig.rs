@@ -0,0 +1,192 @@
+use std::cmp::Ordering;
+use std::f64;
+
+const QL_MAX_SWEEPS: usize = 64;
+
+// Eigenvalues of the symmetric tridiagonal matrix with diagonal `d` and off-diagonal `e`, where
+// e[i] couples i and i + 1 and the last entry is ignored, by implicit QL with Wilkinson shifts.
+// The eigenvalues are left in `d` in increasing order. If `z` is given, its rows are rotated
+// along with the matrix, so rows that start out as the basis the tridiagonal matrix is
+// expressed in end up as the corresponding eigenvectors.
+pub(crate) fn tridiagonal_ql(d: &mut [f64], e: &mut [f64], mut z: Option<&mut [f64]>) {
+    let n = d.len();
+    if n == 0 {
+        return;
+    }
+    e[n - 1] = 0.0;
+    let (mut shift, mut scale) = (0.0, 0.0f64);
+    for l in 0..n {
+        scale = scale.max(d[l].abs() + e[l].abs());
+        let mut m = l;
+        while e[m].abs() > f64::EPSILON * scale {
+            m += 1;
+        }
+        if m > l {
+            for _ in 0..QL_MAX_SWEEPS {
+                let g = d[l];
+                let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+                let mut r = p.hypot(1.0);
+                if p < 0.0 {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let h = g - d[l];
+                for x in d[l + 2..].iter_mut() {
+                    *x -= h;
+                }
+                shift += h;
+                p = d[m];
+                let (mut c, mut c2, mut c3) = (1.0, 1.0, 1.0);
+                let (mut s, mut s2) = (0.0, 0.0);
+                let el1 = e[l + 1];
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    let g = c * e[i];
+                    let h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+                    if let Some(z) = z.as_deref_mut() {
+                        let (upper, lower) = z.split_at_mut((i + 1) * n);
+                        for (zi, zj) in upper[i * n..].iter_mut().zip(lower[..n].iter_mut()) {
+                            let h = *zj;
+                            *zj = s * *zi + c * h;
+                            *zi = c * *zi - s * h;
+                        }
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+                if e[l].abs() <= f64::EPSILON * scale {
+                    break;
+                }
+            }
+        }
+        d[l] += shift;
+        e[l] = 0.0;
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&x, &y| d[x].partial_cmp(&d[y]).unwrap_or(Ordering::Equal));
+    let sorted: Vec<f64> = order.iter().map(|&i| d[i]).collect();
+    d.copy_from_slice(&sorted);
+    if let Some(z) = z {
+        let rows: Vec<f64> = order.iter().flat_map(|&i| z[i * n..(i + 1) * n].to_vec()).collect();
+        z.copy_from_slice(&rows);
+    }
+}
+
+// Householder reduction of the symmetric row-major n x n matrix held in `v` to tridiagonal
+// form Q^T·A·Q, overwriting `v` with Q. Returns the diagonal and the off-diagonal, e[i]
+// coupling i and i + 1.
+fn tridiagonalize(v: &mut [f64], n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut d: Vec<f64> = v[(n - 1) * n..].to_vec();
+    let mut e = vec![0.0; n];
+    for i in (1..n).rev() {
+        let scale: f64 = d[..i].iter().map(|x| x.abs()).sum();
+        let mut h = 0.0;
+        if scale == 0.0 {
+            e[i] = d[i - 1];
+            for j in 0..i {
+                d[j] = v[(i - 1) * n + j];
+                v[i * n + j] = 0.0;
+                v[j * n + i] = 0.0;
+            }
+        } else {
+            for x in d[..i].iter_mut() {
+                *x /= scale;
+                h += *x * *x;
+            }
+            let f = d[i - 1];
+            let g = if f > 0.0 { -h.sqrt() } else { h.sqrt() };
+            e[i] = scale * g;
+            h -= f * g;
+            d[i - 1] = f - g;
+            for x in e[..i].iter_mut() {
+                *x = 0.0;
+            }
+            for j in 0..i {
+                let f = d[j];
+                v[j * n + i] = f;
+                let mut g = e[j] + v[j * n + j] * f;
+                for k in j + 1..i {
+                    g += v[k * n + j] * d[k];
+                    e[k] += v[k * n + j] * f;
+                }
+                e[j] = g;
+            }
+            let mut f = 0.0;
+            for j in 0..i {
+                e[j] /= h;
+                f += e[j] * d[j];
+            }
+            let hh = f / (h + h);
+            for j in 0..i {
+                e[j] -= hh * d[j];
+            }
+            for j in 0..i {
+                let (f, g) = (d[j], e[j]);
+                for k in j..i {
+                    v[k * n + j] -= f * e[k] + g * d[k];
+                }
+                d[j] = v[(i - 1) * n + j];
+                v[i * n + j] = 0.0;
+            }
+        }
+        d[i] = h;
+    }
+    // Accumulate the transformations.
+    for i in 0..n - 1 {
+        v[(n - 1) * n + i] = v[i * n + i];
+        v[i * n + i] = 1.0;
+        let h = d[i + 1];
+        if h != 0.0 {
+            for k in 0..=i {
+                d[k] = v[k * n + i + 1] / h;
+            }
+            for j in 0..=i {
+                let g: f64 = (0..=i).map(|k| v[k * n + i + 1] * v[k * n + j]).sum();
+                for k in 0..=i {
+                    v[k * n + j] -= g * d[k];
+                }
+            }
+        }
+        for k in 0..=i {
+            v[k * n + i + 1] = 0.0;
+        }
+    }
+    for j in 0..n {
+        d[j] = v[(n - 1) * n + j];
+        v[(n - 1) * n + j] = 0.0;
+    }
+    v[n * n - 1] = 1.0;
+    e.remove(0);
+    e.push(0.0);
+    (d, e)
+}
+
+// Eigendecomposition of a symmetric row-major n x n matrix held in memory. Returns the
+// eigenvalues in increasing order and the matching unit eigenvectors, one per row.
+pub(crate) fn symmetric_eigen(m: &[f64], n: usize) -> (Vec<f64>, Vec<f64>) {
+    if n == 0 {
+        return (Vec::new(), Vec::new());
+    }
+    let mut q = m.to_vec();
+    let (mut d, mut e) = tridiagonalize(&mut q, n);
+    // The columns of Q are the basis the tridiagonal matrix is expressed in.
+    let mut z = vec![0.0; n * n];
+    for i in 0..n {
+        for k in 0..n {
+            z[i * n + k] = q[k * n + i];
+        }
+    }
+    tridiagonal_ql(&mut d, &mut e, Some(&mut z));
+    (d, z)
+}
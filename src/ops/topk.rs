@@ -0,0 +1,77 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// A candidate ordered so that the max-heap's top is the worst one kept: lower scores are
+// worse and, among equal scores, higher indices are.
+struct Candidate {
+    score: f64,
+    index: u64,
+}
+
+impl Candidate {
+    fn worse_than(&self, other: &Candidate) -> Ordering {
+        other.score.partial_cmp(&self.score).unwrap_or(Ordering::Equal).then(self.index.cmp(&other.index))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        self.worse_than(other)
+    }
+}
+
+// Keeps the k highest-scoring indices seen, breaking ties in favour of the lower index so
+// that results do not depend on the order candidates arrive in. NaN scores are ignored.
+pub(crate) struct TopK {
+    k: usize,
+    heap: BinaryHeap<Candidate>,
+}
+
+impl TopK {
+    pub fn new(k: usize) -> TopK {
+        TopK {
+            k,
+            heap: BinaryHeap::with_capacity(k + 1),
+        }
+    }
+
+    pub fn push(&mut self, score: f64, index: u64) {
+        if self.k == 0 || score.is_nan() {
+            return;
+        }
+        let candidate = Candidate { score, index };
+        if self.heap.len() < self.k {
+            self.heap.push(candidate);
+        } else if self.heap.peek().is_some_and(|worst| candidate < *worst) {
+            self.heap.pop();
+            self.heap.push(candidate);
+        }
+    }
+
+    // The lowest score kept once k candidates have been seen, which any newcomer must beat.
+    pub fn worst_score(&self) -> Option<f64> {
+        if self.heap.len() < self.k {
+            return None;
+        }
+        self.heap.peek().map(|c| c.score)
+    }
+
+    // The kept (index, score) pairs, best first.
+    pub fn into_sorted(self) -> Vec<(u64, f64)> {
+        self.heap.into_sorted_vec().into_iter().map(|c| (c.index, c.score)).collect()
+    }
+}
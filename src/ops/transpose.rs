@@ -0,0 +1,181 @@
+use budget::MemoryBudget;
+use dense_matrix::{Dense, StorageType, SupportedType};
+use error::OoclaError;
+use io_stats;
+use std::mem;
+use tuning::{self, TileConfig, TunableOp};
+
+// Sides at or below which a block is transposed element by element. Two such blocks of f64 fit
+// in L1 with room to spare.
+pub(crate) const DEFAULT_TRANSPOSE_BLOCK: usize = 32;
+
+// Counts `elements` elements as both read and written, as each swap reads and writes both.
+fn count_swapped<T>(elements: usize) {
+    io_stats::count_read(elements * mem::size_of::<T>());
+    io_stats::count_written(elements * mem::size_of::<T>());
+}
+
+// Transposes the n x n block on the diagonal at (start, start) of row-major storage.
+fn transpose_diagonal<T>(data: &mut [T], lda: usize, start: usize, n: usize, leaf: usize) {
+    if n <= leaf {
+        for i in start..start + n {
+            for j in i + 1..start + n {
+                data.swap(i * lda + j, j * lda + i);
+            }
+        }
+        count_swapped::<T>(n * n - n);
+        return;
+    }
+    let half = n / 2;
+    transpose_diagonal(data, lda, start, half, leaf);
+    transpose_diagonal(data, lda, start + half, n - half, leaf);
+    swap_mirrored(data, lda, start, start + half, half, n - half, leaf);
+}
+
+// Swaps the rows x cols block at (row, col), which lies wholly above the diagonal, with the
+// transpose of its mirror image below it, halving the longer side until both are small.
+fn swap_mirrored<T>(data: &mut [T], lda: usize, row: usize, col: usize, rows: usize, cols: usize, leaf: usize) {
+    if rows <= leaf && cols <= leaf {
+        for i in row..row + rows {
+            for j in col..col + cols {
+                data.swap(i * lda + j, j * lda + i);
+            }
+        }
+        count_swapped::<T>(2 * rows * cols);
+    } else if rows >= cols {
+        let half = rows / 2;
+        swap_mirrored(data, lda, row, col, half, cols, leaf);
+        swap_mirrored(data, lda, row + half, col, rows - half, cols, leaf);
+    } else {
+        let half = cols / 2;
+        swap_mirrored(data, lda, row, col, rows, half, leaf);
+        swap_mirrored(data, lda, row, col + half, rows, cols - half, leaf);
+    }
+}
+
+// Replaces a square matrix A with A^T in its own storage, so transposing it needs no more
+// disk. The blocking is cache-oblivious: each block is swapped with its mirror image once
+// both fit in whatever level of the memory hierarchy is nearest, down to the page cache, so
+// the storage is read and written about once whatever its size. A matrix marked transposed
+// already holds A^T in row-major order, so it only loses the mark. Either way the result is
+// stored untransposed.
+pub fn transpose_inplace<T: StorageType>(a: &mut Dense<T>) -> Result<(), OoclaError> {
+    transpose_with_block(a, DEFAULT_TRANSPOSE_BLOCK)
+}
+
+// As transpose_inplace, swapping elements directly in blocks of `config`'s tile_size on a side,
+// or when that is None, of the size the tuning cache holds for a matrix of this size.
+pub fn transpose_inplace_tuned<T: SupportedType>(a: &mut Dense<T>, config: Option<TileConfig>)
+    -> Result<(), OoclaError> {
+    let config = tuning::resolve::<T>(TunableOp::Transpose, &[a.num_rows()], config, &MemoryBudget::unlimited());
+    if config.tile_size == 0 {
+        return Err(OoclaError::InvalidArgument("tile_size must be non-zero".to_string()));
+    }
+    transpose_with_block(a, config.tile_size)
+}
+
+fn transpose_with_block<T>(a: &mut Dense<T>, leaf: usize) -> Result<(), OoclaError> {
+    let n = a.num_rows();
+    if a.num_cols() != n {
+        return Err(OoclaError::InvalidArgument(format!(
+            "transpose_inplace needs a square matrix, not {}x{}; Dense::transpose transposes any \
+             matrix as a view without moving its data", n, a.num_cols())));
+    }
+    if a.is_transposed() {
+        a.transpose();
+        return Ok(());
+    }
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("transpose_inplace", n, block = leaf).entered();
+    let lda = a.lda() as usize;
+    transpose_diagonal(a.storage_mut(), lda, 0, n as usize, leaf);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, StdRng};
+
+    fn random(rng: &mut StdRng, n: u64) -> Dense<f64> {
+        let values: Vec<f64> = (0..n * n).map(|_| rng.gen_range(-1.0, 1.0)).collect();
+        Dense::anonymous_from_fn(n, n, |i, j| values[(i * n + j) as usize])
+    }
+
+    fn elements(a: &Dense<f64>) -> Vec<Vec<f64>> {
+        a.row_iter().map(|r| r.to_vec()).collect()
+    }
+
+    fn reference_transpose(rows: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        (0..rows.len()).map(|j| rows.iter().map(|row| row[j]).collect()).collect()
+    }
+
+    #[test]
+    fn random_matrices_match_a_reference_transpose() {
+        let mut rng: StdRng = SeedableRng::from_seed(&[7usize][..]);
+        // Sizes on, either side of and well away from multiples of the block sides.
+        for &n in [0, 1, 2, 31, 32, 33, 65, 100, 257].iter() {
+            for &block in [1, 7, DEFAULT_TRANSPOSE_BLOCK].iter() {
+                let mut a = random(&mut rng, n);
+                let expected = reference_transpose(&elements(&a));
+                if block == DEFAULT_TRANSPOSE_BLOCK {
+                    transpose_inplace(&mut a).unwrap();
+                } else {
+                    transpose_inplace_tuned(&mut a, Some(TileConfig { tile_size: block })).unwrap();
+                }
+                assert!(!a.is_transposed());
+                assert!(elements(&a) == expected, "{}x{} in blocks of {}", n, n, block);
+            }
+        }
+    }
+
+    #[test]
+    fn transposed_matrices_only_lose_their_mark() {
+        let mut rng: StdRng = SeedableRng::from_seed(&[8usize][..]);
+        let mut a = random(&mut rng, 45);
+        a.transpose();
+        let expected = reference_transpose(&elements(&a));
+        let storage = a.storage_mut().to_vec();
+        let (_, stats) = io_stats::measure(|| transpose_inplace(&mut a).unwrap());
+        assert!(!a.is_transposed());
+        assert!(elements(&a) == expected);
+        assert!(a.storage_mut() == &storage[..]);
+        assert_eq!((stats.bytes_read, stats.bytes_written), (0, 0));
+    }
+
+    #[test]
+    fn non_square_matrices_and_zero_sized_blocks_are_rejected() {
+        let mut a = Dense::anonymous_from_fn(3, 4, |i, j| (i * 4 + j) as f64);
+        let original = elements(&a);
+        for transposed in [false, true].iter() {
+            if *transposed {
+                a.transpose();
+            }
+            match transpose_inplace(&mut a) {
+                Err(OoclaError::InvalidArgument(_)) => {}
+                other => panic!("expected an invalid argument, got {:?}", other),
+            }
+            assert_eq!(a.is_transposed(), *transposed);
+        }
+        a.transpose();
+        assert!(elements(&a) == original);
+        let mut square = Dense::anonymous_from_fn(4, 4, |i, j| (i * 4 + j) as f64);
+        match transpose_inplace_tuned(&mut square, Some(TileConfig { tile_size: 0 })) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected an invalid argument, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_storage_is_read_and_written_about_once() {
+        let mut rng: StdRng = SeedableRng::from_seed(&[9usize][..]);
+        let n = 300;
+        let mut a = random(&mut rng, n);
+        let (_, stats) = io_stats::measure(|| transpose_inplace(&mut a).unwrap());
+        // Every element off the diagonal is read and written once.
+        let swapped = n * (n - 1) * 8;
+        assert_eq!((stats.bytes_read, stats.bytes_written), (swapped, swapped));
+        let passes = (stats.bytes_read + stats.bytes_written) as f64 / (n * n * 8) as f64;
+        assert!(passes > 1.99 && passes <= 2.0, "{} passes", passes);
+    }
+}
@@ -0,0 +1,234 @@
+use dense_matrix::Dense;
+use error::OoclaError;
+use ops::blas::tile_gemm_acc;
+use ops::simd;
+use std::cmp;
+use std::f64;
+use tile::{DEFAULT_TILE_SIZE, Tile};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpLo {
+    Upper,
+    Lower,
+}
+
+// Reads the block of op(A) at (row, col).
+fn op_tile(a: &Dense<f64>, trans: bool, row: u64, col: u64, rows: usize, cols: usize) -> Tile<f64> {
+    if trans {
+        a.read_tile(col, row, cols, rows).transposed()
+    } else {
+        a.read_tile(row, col, rows, cols)
+    }
+}
+
+// Solves T·X = B in place for an in-memory triangular block T and a block of right-hand sides.
+fn solve_block(t: &Tile<f64>, lower: bool, unit_diag: bool, b: &mut Tile<f64>) {
+    let (k, cols) = (t.rows, b.cols);
+    let order: Vec<usize> = if lower { (0..k).collect() } else { (0..k).rev().collect() };
+    for r in order {
+        let solved = if lower { 0..r } else { r + 1..k };
+        for s in solved {
+            let coefficient = t.data[r * k + s];
+            if coefficient == 0.0 {
+                continue;
+            }
+            for c in 0..cols {
+                b.data[r * cols + c] -= coefficient * b.data[s * cols + c];
+            }
+        }
+        if !unit_diag {
+            let d = t.data[r * k + r];
+            for x in b.data[r * cols..(r + 1) * cols].iter_mut() {
+                *x /= d;
+            }
+        }
+    }
+}
+
+fn check_diagonal(a: &Dense<f64>) -> Result<(), OoclaError> {
+    let n = a.num_rows();
+    let largest = a.diagonal_iter().fold(0.0f64, |acc, d| acc.max(d.abs()));
+    let tolerance = n as f64 * f64::EPSILON * largest;
+    for (i, d) in a.diagonal_iter().enumerate() {
+        if d.is_nan() || d.abs() <= tolerance {
+            return Err(OoclaError::Singular { index: i as u64 });
+        }
+    }
+    Ok(())
+}
+
+fn trsm_left(lower: bool, trans: bool, unit_diag: bool, a: &Dense<f64>, b: &mut Dense<f64>, block: usize) {
+    let (m, n) = (b.num_rows(), b.num_cols());
+    let step = DEFAULT_TILE_SIZE as u64;
+    let starts: Vec<u64> = (0..m).step_by(block).collect();
+    let order: Vec<u64> = if lower { starts } else { starts.into_iter().rev().collect() };
+    for k0 in order {
+        let kb = cmp::min(block as u64, m - k0) as usize;
+        let k_end = k0 + kb as u64;
+        let diagonal = op_tile(a, trans, k0, k0, kb, kb);
+        for j0 in (0..n).step_by(DEFAULT_TILE_SIZE) {
+            let cols = cmp::min(step, n - j0) as usize;
+            let mut x = b.read_tile(k0, j0, kb, cols);
+            solve_block(&diagonal, lower, unit_diag, &mut x);
+            b.write_tile(&x);
+        }
+        // Eliminate the solved block from the rows still to be solved.
+        let (rest_start, rest_end) = if lower { (k_end, m) } else { (0, k0) };
+        for i0 in (rest_start..rest_end).step_by(DEFAULT_TILE_SIZE) {
+            let rows = cmp::min(step, rest_end - i0) as usize;
+            let aik = op_tile(a, trans, i0, k0, rows, kb);
+            for j0 in (0..n).step_by(DEFAULT_TILE_SIZE) {
+                let cols = cmp::min(step, n - j0) as usize;
+                let x = b.read_tile(k0, j0, kb, cols);
+                let mut c = b.read_tile(i0, j0, rows, cols);
+                tile_gemm_acc(-1.0, &aik, &x, &mut c.data);
+                b.write_tile(&c);
+            }
+        }
+    }
+}
+
+// Solves op(A)·X = alpha·B (Side::Left) or X·op(A) = alpha·B (Side::Right) for triangular A,
+// overwriting B with X, where op(A) is A or A^T according to `trans`. Only the triangle of A
+// named by `uplo` is read and with `unit_diag` its diagonal is taken to be one. Blocks of
+// `block` rows of X are solved with the diagonal block of A in memory and then eliminated
+// from the remaining rows a tile at a time. A diagonal entry no larger than
+// n * eps * max|diag(A)| gives Singular naming its index, before B is modified.
+#[allow(clippy::too_many_arguments)]
+pub fn trsm(side: Side, uplo: UpLo, trans: bool, unit_diag: bool, alpha: f64, a: &Dense<f64>, b: &mut Dense<f64>,
+            block: usize) -> Result<(), OoclaError> {
+    if block == 0 {
+        return Err(OoclaError::InvalidArgument("block must be non-zero".to_string()));
+    }
+    let order = match side {
+        Side::Left => b.num_rows(),
+        Side::Right => b.num_cols(),
+    };
+    if a.num_rows() != order || a.num_cols() != order {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (order, order),
+            found: (a.num_rows(), a.num_cols()),
+        });
+    }
+    if !unit_diag {
+        check_diagonal(a)?;
+    }
+    if alpha != 1.0 {
+        let minor = b.minor_len() as usize;
+        for major in 0..b.major_len() {
+            simd::scale(&mut b.major_slice_mut(major)[..minor], alpha);
+        }
+    }
+    let lower = uplo == UpLo::Lower;
+    match side {
+        Side::Left => trsm_left(lower != trans, trans, unit_diag, a, b, block),
+        Side::Right => {
+            // X·op(A) = B is op(A)^T·X^T = B^T, and transposing B only flips its layout flag.
+            b.transpose();
+            trsm_left(lower == trans, !trans, unit_diag, a, b, block);
+            b.transpose();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A triangular matrix with a well-conditioned diagonal and garbage in the other triangle,
+    // which trsm must not read.
+    fn triangular(n: u64, uplo: UpLo, seed: u64) -> Dense<f64> {
+        let mut noise = Dense::<f64>::create_anonymous(n, n).unwrap();
+        noise.randomise_seeded(seed);
+        Dense::anonymous_from_fn(n, n, |i, j| {
+            let inside = if uplo == UpLo::Lower { j <= i } else { j >= i };
+            if i == j {
+                2.0 + noise.get(i, j)
+            } else if inside {
+                (noise.get(i, j) - 0.5) / n as f64 * 4.0
+            } else {
+                1e6
+            }
+        })
+    }
+
+    // Element (i, j) of op(A) as trsm sees it.
+    fn op_element(a: &Dense<f64>, uplo: UpLo, trans: bool, unit_diag: bool, i: u64, j: u64) -> f64 {
+        let (r, c) = if trans { (j, i) } else { (i, j) };
+        let inside = if uplo == UpLo::Lower { c <= r } else { c >= r };
+        if r == c && unit_diag {
+            1.0
+        } else if inside {
+            a.get(r, c)
+        } else {
+            0.0
+        }
+    }
+
+    #[test]
+    fn solutions_reproduce_many_right_hand_sides() {
+        let (n, rhs) = (23, 37);
+        let alpha = 0.5;
+        for &side in &[Side::Left, Side::Right] {
+            for &uplo in &[UpLo::Lower, UpLo::Upper] {
+                for &trans in &[false, true] {
+                    for &unit_diag in &[false, true] {
+                        for &block in &[4, 64] {
+                            let a = triangular(n, uplo, 7);
+                            let (rows, cols) = if side == Side::Left { (n, rhs) } else { (rhs, n) };
+                            let mut x = Dense::<f64>::create_anonymous(rows, cols).unwrap();
+                            x.randomise_seeded(rows * 3 + cols);
+                            let op = |i, j| op_element(&a, uplo, trans, unit_diag, i, j);
+                            // B = op(A)·X / alpha or X·op(A) / alpha, so that solving with alpha gives X.
+                            let mut b = Dense::<f64>::anonymous_from_fn(rows, cols, |i, j| {
+                                let sum: f64 = match side {
+                                    Side::Left => (0..n).map(|k| op(i, k) * x.get(k, j)).sum(),
+                                    Side::Right => (0..n).map(|k| x.get(i, k) * op(k, j)).sum(),
+                                };
+                                sum / alpha
+                            });
+                            trsm(side, uplo, trans, unit_diag, alpha, &a, &mut b, block).unwrap();
+                            for i in 0..rows {
+                                for j in 0..cols {
+                                    assert!((b.get(i, j) - x.get(i, j)).abs() < 1e-10,
+                                            "{:?} {:?} trans {} unit {} block {} at ({}, {})",
+                                            side, uplo, trans, unit_diag, block, i, j);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_zero_diagonal_is_singular_and_leaves_b_alone() {
+        let mut a = triangular(5, UpLo::Lower, 1);
+        a.set(3, 3, 0.0);
+        let mut b = Dense::<f64>::anonymous_from_fn(5, 2, |i, j| (i + j) as f64);
+        match trsm(Side::Left, UpLo::Lower, false, false, 2.0, &a, &mut b, 2) {
+            Err(OoclaError::Singular { index }) => assert_eq!(index, 3),
+            other => panic!("expected a singular matrix, got {:?}", other),
+        }
+        assert_eq!(b.get(4, 1), 5.0);
+        // With a unit diagonal the stored zero is never read.
+        assert!(trsm(Side::Left, UpLo::Lower, false, true, 1.0, &a, &mut b, 2).is_ok());
+    }
+
+    #[test]
+    fn mismatched_shapes_are_rejected() {
+        let a = triangular(4, UpLo::Upper, 2);
+        let mut b = Dense::<f64>::create_anonymous(3, 4).unwrap();
+        assert!(trsm(Side::Left, UpLo::Upper, false, false, 1.0, &a, &mut b, 2).is_err());
+        assert!(trsm(Side::Right, UpLo::Upper, false, false, 1.0, &a, &mut b, 0).is_err());
+        assert!(trsm(Side::Right, UpLo::Upper, false, false, 1.0, &a, &mut b, 2).is_ok());
+    }
+}
@@ -0,0 +1,236 @@
+use dense_matrix::{Dense, page_size};
+use error::OoclaError;
+use io_stats;
+use nix::sys::mman::{MADV_WILLNEED, madvise};
+use nix::libc::{c_void, size_t};
+use std::borrow::Cow;
+use std::cmp;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+
+// The most the helper advises at once, so that it notices a shutdown promptly.
+const PREFETCH_BLOCK_BYTES: u64 = 1 << 20;
+
+struct Shared {
+    // Rows handed to the consumer so far.
+    cursor: AtomicU64,
+    shutdown: AtomicBool,
+    // The helper advises this many lines at a time, and is only woken once it can.
+    step: u64,
+}
+
+// The storage the helper advises, as addresses rather than a borrow of the matrix. The helper
+// only ever passes these to madvise, never reads through them, so even if the iterator were
+// leaked and the mapping dropped beneath it, the worst it could do is advise a range that is
+// gone, which the kernel refuses.
+struct Region {
+    base: usize,
+    line_bytes: u64,
+    lines: u64,
+    end: usize,
+    // The rows of a matrix stored by columns span all of its storage, which is advised in full.
+    whole: bool,
+}
+
+impl Region {
+    fn advise(&self, lines: u64, up_to: u64) {
+        let page = page_size();
+        let begin = (self.base + (lines * self.line_bytes) as usize) / page * page;
+        let end = cmp::min((self.base + (up_to * self.line_bytes) as usize).div_ceil(page) * page, self.end);
+        if begin < end {
+            let _ = unsafe {
+                madvise(begin as *const c_void, (end - begin) as size_t, MADV_WILLNEED)
+            };
+            #[cfg(feature = "tracing")]
+            tracing::trace!(lines, up_to, bytes = end - begin, "madvise(WILLNEED)");
+        }
+    }
+
+    fn run(&self, shared: &Shared, lookahead: u64) {
+        let mut advised = 0;
+        while !shared.shutdown.load(Ordering::Acquire) && advised < self.lines {
+            let target = if self.whole {
+                self.lines
+            } else {
+                cmp::min(shared.cursor.load(Ordering::Acquire).saturating_add(lookahead), self.lines)
+            };
+            if target - cmp::min(advised, target) < cmp::min(shared.step, self.lines - advised) {
+                thread::park();
+                continue;
+            }
+            let up_to = cmp::min(target, advised + shared.step);
+            self.advise(advised, up_to);
+            advised = up_to;
+        }
+    }
+}
+
+// Iterates over the rows of a matrix in order while a helper thread asks the kernel to read
+// ahead of it, up to `lookahead_rows` rows beyond the row last handed out, so that a consumer
+// doing real work on each row finds the next ones already in the page cache rather than waiting
+// on each fault. Rows stored contiguously are borrowed from the mapping and the rest gathered.
+// The helper is stopped and joined when the iterator is dropped, which the borrow of the matrix
+// ensures happens before the mapping goes away.
+pub struct PrefetchedRows<'a, T: 'a> {
+    matrix: &'a Dense<T>,
+    next: u64,
+    shared: Arc<Shared>,
+    helper: Option<JoinHandle<()>>,
+}
+
+impl<'a, T: Copy + Default> PrefetchedRows<'a, T> {
+    pub fn new(a: &'a Dense<T>, lookahead_rows: u64) -> Result<PrefetchedRows<'a, T>, OoclaError> {
+        let line_bytes = a.lda() * mem::size_of::<T>() as u64;
+        let block_lines = cmp::max(1, PREFETCH_BLOCK_BYTES / cmp::max(1, line_bytes));
+        let shared = Arc::new(Shared {
+            cursor: AtomicU64::new(0),
+            shutdown: AtomicBool::new(false),
+            step: cmp::max(1, cmp::min(block_lines, lookahead_rows / 2)),
+        });
+        let storage = a.storage();
+        let region = Region {
+            base: storage.as_ptr() as usize,
+            line_bytes,
+            lines: a.major_len(),
+            end: (storage.as_ptr() as usize + mem::size_of_val(storage)).div_ceil(page_size()) * page_size(),
+            whole: a.is_transposed(),
+        };
+        let helper = if lookahead_rows == 0 || a.num_rows() == 0 {
+            None
+        } else {
+            let shared = shared.clone();
+            Some(thread::Builder::new()
+                .name("ooc-prefetch".to_string())
+                .spawn(move || region.run(&shared, lookahead_rows))?)
+        };
+        Ok(PrefetchedRows { matrix: a, next: 0, shared, helper })
+    }
+}
+
+impl<'a, T: Copy + Default> Iterator for PrefetchedRows<'a, T> {
+    type Item = Cow<'a, [T]>;
+
+    fn next(&mut self) -> Option<Cow<'a, [T]>> {
+        if self.next == self.matrix.num_rows() {
+            return None;
+        }
+        let row = self.next;
+        self.next += 1;
+        self.shared.cursor.store(self.next, Ordering::Release);
+        if let Some(ref helper) = self.helper {
+            if self.next.is_multiple_of(self.shared.step) {
+                helper.thread().unpark();
+            }
+        }
+        Some(if self.matrix.is_transposed() {
+            let mut values = vec![T::default(); self.matrix.num_cols() as usize];
+            self.matrix.read_row(row, &mut values);
+            Cow::Owned(values)
+        } else {
+            let line = self.matrix.major_slice(row);
+            io_stats::count_read(mem::size_of_val(line));
+            Cow::Borrowed(line)
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.matrix.num_rows() - self.next) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, T> Drop for PrefetchedRows<'a, T> {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        if let Some(helper) = self.helper.take() {
+            helper.thread().unpark();
+            let _ = helper.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{AccessPattern, TempMatrixPath};
+    use io_stats::measure;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    // A file-backed matrix dropped from the page cache, so that its first reads fault from disk.
+    fn cold(path: &Path, transposed: bool) -> Dense<f64> {
+        let mut a = Dense::from_fn(path, 3000, 700, |i, j| (i * 700 + j) as f64).unwrap();
+        if transposed {
+            a.transpose();
+        }
+        a.discard_range(0..a.num_rows()).unwrap();
+        a
+    }
+
+    #[test]
+    fn rows_match_plain_iteration() {
+        for &transposed in &[false, true] {
+            let path = TempMatrixPath::new();
+            let a = cold(path.path(), transposed);
+            let plain: Vec<Vec<f64>> = a.row_iter().map(|row| row.into_owned()).collect();
+            for &lookahead in &[0, 1, 7, 64, 1 << 20] {
+                a.discard_range(0..a.num_rows()).unwrap();
+                let prefetched = PrefetchedRows::new(&a, lookahead).unwrap();
+                assert_eq!(prefetched.size_hint(), (a.num_rows() as usize, Some(a.num_rows() as usize)));
+                let rows: Vec<Vec<f64>> = prefetched.map(|row| row.into_owned()).collect();
+                assert!(rows == plain, "lookahead {}, transposed {}", lookahead, transposed);
+            }
+        }
+    }
+
+    #[test]
+    fn a_slow_consumer_sees_the_same_rows() {
+        let path = TempMatrixPath::new();
+        let a = cold(path.path(), false);
+        let mut sums = Vec::new();
+        for (i, row) in PrefetchedRows::new(&a, 200).unwrap().enumerate() {
+            if i % 100 == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            sums.push(row.iter().sum::<f64>());
+        }
+        let expected: Vec<f64> = a.row_iter().map(|row| row.iter().sum()).collect();
+        assert_eq!(sums, expected);
+    }
+
+    #[test]
+    fn dropping_part_way_stops_the_helper() {
+        let path = TempMatrixPath::new();
+        let a = cold(path.path(), false);
+        let began = Instant::now();
+        for _ in 0..100 {
+            let mut rows = PrefetchedRows::new(&a, 1 << 20).unwrap();
+            assert_eq!(rows.nth(10).unwrap()[0], 7000.0);
+        }
+        assert!(began.elapsed() < Duration::from_secs(10));
+        let empty = Dense::<f64>::create_anonymous(0, 5).unwrap();
+        assert!(PrefetchedRows::new(&empty, 10).unwrap().next().is_none());
+    }
+
+    // Without readahead a plain pass over a cold matrix waits on the disk for nearly every page,
+    // while the helper has the kernel read them before the consumer reaches them.
+    #[test]
+    fn prefetching_spares_the_consumer_major_faults() {
+        let path = TempMatrixPath::new();
+        let a = cold(path.path(), false);
+        a.advise(AccessPattern::Random).unwrap();
+        let (plain, plain_stats) = measure(|| a.row_iter().map(|row| row.iter().sum::<f64>()).collect::<Vec<_>>());
+        a.discard_range(0..a.num_rows()).unwrap();
+        let (prefetched, prefetched_stats) = measure(|| {
+            PrefetchedRows::new(&a, 1 << 20).unwrap().map(|row| row.iter().sum::<f64>()).collect::<Vec<_>>()
+        });
+        assert_eq!(prefetched, plain);
+        assert_eq!(prefetched_stats.bytes_read, plain_stats.bytes_read);
+        assert_eq!(plain_stats.bytes_read, 3000 * 700 * 8);
+        assert!(plain_stats.major_faults > 1000, "{:?}", plain_stats);
+        assert!(prefetched_stats.major_faults * 10 < plain_stats.major_faults, "{:?} prefetched, {:?} plain",
+                prefetched_stats, plain_stats);
+    }
+}
@@ -0,0 +1,228 @@
+use error::OoclaError;
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// The most often an operation reports its progress, apart from the reports it always makes as
+// it starts and finishes.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+// Bytes of work between looks at the clock, so that an operation advancing a little at a time
+// costs an addition and a comparison per step.
+const CHECK_BYTES: u64 = 1 << 20;
+
+// What the counts given to Progress::report measure. Operations whose size isn't known until
+// they finish, such as imports from a stream, count the rows they have produced instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressUnit {
+    Bytes,
+    Rows,
+}
+
+// Receives how far a long-running operation has got: `done` of `total` units, or `done` alone
+// where the total isn't known up front. An operation reports 0 as it starts, then at most once
+// per PROGRESS_INTERVAL, and if it succeeds, its final count, which equals the total when there
+// is one, followed by a call to finish. Closures taking the same arguments as report are
+// progress sinks too.
+//
+// A sink can also stop the operation: it is asked is_cancelled at the same points as it might
+// be given a report, which for every operation is between blocks of its work, and once it says
+// yes the operation cleans up and returns OoclaError::Cancelled. What an operation leaves behind
+// then is described with it.
+pub trait Progress {
+    fn report(&self, done: u64, total: Option<u64>, unit: ProgressUnit);
+
+    fn finish(&self) {}
+
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+impl<F> Progress for F where F: Fn(u64, Option<u64>, ProgressUnit) {
+    fn report(&self, done: u64, total: Option<u64>, unit: ProgressUnit) {
+        self(done, total, unit)
+    }
+}
+
+// Discards every report, for the operations that take no progress sink.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn report(&self, _done: u64, _total: Option<u64>, _unit: ProgressUnit) {}
+}
+
+// A flag shared between clones that cancels the operations it is given to, as a progress sink,
+// once any clone is cancelled. Typically a clone is kept by whatever shuts a pipeline down.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    // A sink that passes reports on to `progress` and cancels with this token.
+    pub fn with_progress<P: Progress>(&self, progress: P) -> Cancellable<P> {
+        Cancellable { progress, token: self.clone() }
+    }
+}
+
+impl Progress for CancelToken {
+    fn report(&self, _done: u64, _total: Option<u64>, _unit: ProgressUnit) {}
+
+    fn is_cancelled(&self) -> bool {
+        CancelToken::is_cancelled(self)
+    }
+}
+
+// A progress sink together with a token that cancels the operation, from
+// CancelToken::with_progress.
+#[derive(Debug)]
+pub struct Cancellable<P> {
+    progress: P,
+    token: CancelToken,
+}
+
+impl<P: Progress> Progress for Cancellable<P> {
+    fn report(&self, done: u64, total: Option<u64>, unit: ProgressUnit) {
+        self.progress.report(done, total, unit);
+    }
+
+    fn finish(&self) {
+        self.progress.finish();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled() || self.progress.is_cancelled()
+    }
+}
+
+// Prints progress to stderr on a single line, rewritten in place, with the percentage done when
+// the total is known and the throughput since the operation started. The line is ended when the
+// operation finishes.
+#[derive(Debug)]
+pub struct StderrProgress {
+    label: String,
+    started: Cell<Instant>,
+}
+
+impl StderrProgress {
+    pub fn new(label: &str) -> StderrProgress {
+        StderrProgress {
+            label: label.to_string(),
+            started: Cell::new(Instant::now()),
+        }
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+impl Progress for StderrProgress {
+    fn report(&self, done: u64, total: Option<u64>, unit: ProgressUnit) {
+        if done == 0 {
+            self.started.set(Instant::now());
+        }
+        let seconds = self.started.get().elapsed().as_secs_f64();
+        let rate = if seconds > 0.0 { done as f64 / seconds } else { 0.0 };
+        let (amount, rate) = match unit {
+            ProgressUnit::Bytes => (format_bytes(done as f64), format!("{}/s", format_bytes(rate))),
+            ProgressUnit::Rows => (format!("{} rows", done), format!("{:.0} rows/s", rate)),
+        };
+        let mut line = format!("\r{}: ", self.label);
+        if let Some(total) = total {
+            let percent = if total == 0 { 100.0 } else { 100.0 * done as f64 / total as f64 };
+            line.push_str(&format!("{:5.1}% ", percent));
+        }
+        line.push_str(&format!("({}, {})", amount, rate));
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = stderr.write_all(line.as_bytes());
+        let _ = stderr.flush();
+    }
+
+    fn finish(&self) {
+        eprintln!();
+    }
+}
+
+// Counts an operation's progress and passes it on to a sink no more often than
+// PROGRESS_INTERVAL, checking between reports whether the sink has cancelled the operation.
+pub(crate) struct Meter<'a> {
+    sink: &'a dyn Progress,
+    unit: ProgressUnit,
+    total: Option<u64>,
+    done: u64,
+    next_check: u64,
+    last_report: Instant,
+}
+
+impl<'a> Meter<'a> {
+    pub(crate) fn new(sink: &'a dyn Progress, total: Option<u64>, unit: ProgressUnit) -> Meter<'a> {
+        sink.report(0, total, unit);
+        Meter {
+            sink,
+            unit,
+            total,
+            done: 0,
+            next_check: 0,
+            last_report: Instant::now(),
+        }
+    }
+
+    // Counts `amount` more done, failing with OoclaError::Cancelled if the sink has cancelled the
+    // operation. Callers advance the meter only where stopping leaves things as they document.
+    pub(crate) fn advance(&mut self, amount: u64) -> Result<(), OoclaError> {
+        self.done += amount;
+        if self.done >= self.next_check {
+            return self.check();
+        }
+        Ok(())
+    }
+
+    #[cold]
+    fn check(&mut self) -> Result<(), OoclaError> {
+        self.next_check = self.done + match self.unit {
+            ProgressUnit::Bytes => CHECK_BYTES,
+            ProgressUnit::Rows => 1,
+        };
+        let now = Instant::now();
+        if now.duration_since(self.last_report) >= PROGRESS_INTERVAL {
+            self.last_report = now;
+            self.sink.report(self.done, self.total, self.unit);
+        }
+        if self.sink.is_cancelled() {
+            return Err(OoclaError::Cancelled);
+        }
+        Ok(())
+    }
+
+    // Reports the final count, the total if there is one, as the operation has succeeded.
+    pub(crate) fn finish(self) {
+        debug_assert!(self.total.is_none_or(|total| total == self.done), "counted {} of {:?}", self.done, self.total);
+        self.sink.report(self.total.unwrap_or(self.done), self.total, self.unit);
+        self.sink.finish();
+    }
+}
@@ -0,0 +1,322 @@
+use error::OoclaError;
+use nix::libc::{self, c_int, c_void, siginfo_t};
+use std::cell::Cell;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::mem;
+
+// The most mappings the handler can attribute a fault to. Matrices mapped beyond this many at
+// once are unprotected, and a fault in one is left to whatever handled SIGBUS before.
+const MAX_MAPPINGS: usize = 1024;
+
+// A mapping the handler can find a faulting address in, free while its length is zero.
+struct Slot {
+    start: AtomicUsize,
+    len: AtomicUsize,
+    fd: AtomicI32,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: Slot = Slot { start: AtomicUsize::new(0), len: AtomicUsize::new(0), fd: AtomicI32::new(-1) };
+
+static SLOTS: [Slot; MAX_MAPPINGS] = [FREE; MAX_MAPPINGS];
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static INSTALLING: Mutex<()> = Mutex::new(());
+static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+// The page size, read before the handler is installed since sysconf needn't be safe to call
+// from a signal handler.
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // How many guarded operations the current thread is inside, and the descriptor of the file
+    // and the offset into it of the first fault in the outermost one. Both are const-initialised
+    // and need no destructor, so the handler can use them.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static FAULT: Cell<Option<(c_int, u64)>> = const { Cell::new(None) };
+}
+
+// Registers a mapping of the file `fd` from its start, returning the slot to release it with,
+// or None if every slot is taken.
+pub(crate) fn register(start: *const c_void, len: usize, fd: c_int) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    SLOTS.iter().position(|slot| {
+        if slot.len.compare_exchange(0, len, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+            return false;
+        }
+        slot.fd.store(fd, Ordering::Release);
+        slot.start.store(start as usize, Ordering::Release);
+        true
+    })
+}
+
+pub(crate) fn deregister(slot: usize) {
+    SLOTS[slot].start.store(0, Ordering::Release);
+    SLOTS[slot].len.store(0, Ordering::Release);
+}
+
+// Whether install has been called.
+pub fn is_installed() -> bool {
+    INSTALLED.load(Ordering::Acquire)
+}
+
+// Installs a SIGBUS handler that turns faults in matrix mappings into errors, for when the file
+// behind a matrix is truncated by another process or a page of a sparse file can't be given
+// space on a full filesystem. A fault during an operation that guards its accesses, such as
+// fill, copy_at, merge_all and passes over a matrix, makes the operation return
+// OoclaError::BusError naming the file and offset. To let the operation finish, the page that
+// faulted is replaced with zeroed memory that isn't backed by the file, so what the operation
+// read or wrote there is lost and the matrix should no longer be used. A fault anywhere else in
+// a matrix, such as through get or set, is reported on stderr with its file and offset before
+// the process dies as it would have. Faults outside matrices go to the handler that was
+// installed before, so install can be called after other libraries install theirs.
+pub fn install() -> Result<(), OoclaError> {
+    let _installing = INSTALLING.lock().unwrap_or_else(PoisonError::into_inner);
+    if is_installed() {
+        return Ok(());
+    }
+    PAGE_SIZE.store(::dense_matrix::page_size(), Ordering::Release);
+    let mut action: libc::sigaction = unsafe {
+        mem::zeroed()
+    };
+    action.sa_sigaction = handle_sigbus as *const () as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    let mut previous: libc::sigaction = unsafe {
+        mem::zeroed()
+    };
+    let ret = unsafe {
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGBUS, &action, &mut previous)
+    };
+    if ret != 0 {
+        return Err(::std::io::Error::last_os_error().into());
+    }
+    let _ = PREVIOUS.set(previous);
+    INSTALLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+// Runs `f` as a guarded operation, returning OoclaError::BusError in place of its result if a
+// SIGBUS in a matrix mapping was caught while it ran.
+pub(crate) fn guarded<R, F>(f: F) -> Result<R, OoclaError> where F: FnOnce() -> Result<R, OoclaError> {
+    struct Depth;
+    impl Drop for Depth {
+        fn drop(&mut self) {
+            DEPTH.with(|depth| depth.set(depth.get() - 1));
+        }
+    }
+    DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let result = {
+        let _depth = Depth;
+        f()
+    };
+    match FAULT.with(|fault| if DEPTH.with(Cell::get) == 0 { fault.take() } else { fault.get() }) {
+        Some((fd, offset)) => Err(OoclaError::BusError {
+            path: fs::read_link(format!("/proc/self/fd/{}", fd)).ok(),
+            offset,
+        }),
+        None => result,
+    }
+}
+
+// The file descriptor and start of the registered mapping holding `addr`.
+fn find_mapping(addr: usize) -> Option<(c_int, usize)> {
+    SLOTS.iter().find_map(|slot| {
+        let start = slot.start.load(Ordering::Acquire);
+        let len = slot.len.load(Ordering::Acquire);
+        if start != 0 && addr >= start && addr - start < len {
+            Some((slot.fd.load(Ordering::Acquire), start))
+        } else {
+            None
+        }
+    })
+}
+
+fn write_stderr(bytes: &[u8]) {
+    unsafe {
+        libc::write(2, bytes.as_ptr() as *const c_void, bytes.len());
+    }
+}
+
+// Writes `value` in decimal into the end of `buf`, returning the digits.
+fn decimal(mut value: u64, buf: &mut [u8; 20]) -> &[u8] {
+    let mut at = buf.len();
+    loop {
+        at -= 1;
+        buf[at] = b'0' + (value % 10) as u8;
+        value /= 10;
+        if value == 0 {
+            return &buf[at..];
+        }
+    }
+}
+
+// Reports a fault outside a guarded operation, using only calls that are safe in a handler.
+fn report(fd: c_int, offset: u64) {
+    let mut digits = [0; 20];
+    let mut link = *b"/proc/self/fd/\0\0\0\0\0\0\0\0\0\0\0";
+    let fd_digits = decimal(fd as u64, &mut digits);
+    link[14..14 + fd_digits.len()].copy_from_slice(fd_digits);
+    let mut path = [0u8; 4096];
+    let len = unsafe {
+        libc::readlink(link.as_ptr() as *const libc::c_char, path.as_mut_ptr() as *mut libc::c_char, path.len())
+    };
+    write_stderr(b"ooc: bus error at offset ");
+    write_stderr(decimal(offset, &mut digits));
+    write_stderr(b" of matrix file ");
+    if len > 0 {
+        write_stderr(&path[..len as usize]);
+    } else {
+        write_stderr(b"(unknown)");
+    }
+    write_stderr(b": the file was truncated or its filesystem has no space for the page\n");
+}
+
+// Replaces the page holding `addr` with zeroed anonymous memory, so the access that faulted can
+// complete when the handler returns.
+fn replace_page(addr: usize) -> bool {
+    let page = PAGE_SIZE.load(Ordering::Acquire);
+    let start = addr / page * page;
+    let mapped = unsafe {
+        libc::mmap(start as *mut c_void, page, libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED, -1, 0)
+    };
+    mapped != libc::MAP_FAILED
+}
+
+// Passes a signal that isn't ours to deal with to the handler installed before, or if that was
+// the default, restores it and raises the signal again, which takes effect once this handler
+// returns.
+fn forward(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let previous = match PREVIOUS.get() {
+        Some(previous) => previous,
+        None => return,
+    };
+    if previous.sa_flags & libc::SA_SIGINFO != 0 {
+        let handler: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = unsafe {
+            mem::transmute(previous.sa_sigaction)
+        };
+        handler(signal, info, context);
+    } else if previous.sa_sigaction == libc::SIG_DFL || previous.sa_sigaction == libc::SIG_IGN {
+        // A fault that is ignored only recurs, so it is treated as the default.
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    } else {
+        let handler: extern "C" fn(c_int) = unsafe {
+            mem::transmute(previous.sa_sigaction)
+        };
+        handler(signal);
+    }
+}
+
+extern "C" fn handle_sigbus(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let addr = if info.is_null() {
+        0
+    } else {
+        unsafe {
+            (*info).si_addr() as usize
+        }
+    };
+    if let Some((fd, start)) = find_mapping(addr) {
+        let offset = (addr - start) as u64;
+        if DEPTH.with(Cell::get) > 0 && replace_page(addr) {
+            FAULT.with(|fault| {
+                if fault.get().is_none() {
+                    fault.set(Some((fd, offset)));
+                }
+            });
+            return;
+        }
+        report(fd, offset);
+    }
+    forward(signal, info, context);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{Dense, TempMatrixPath, page_size};
+    use std::fs::OpenOptions;
+    use std::path::Path;
+
+    // A matrix of 64 rows of 8 KiB whose file is then cut back to its first `kept` bytes, as
+    // another process truncating it would.
+    fn truncated(path: &Path, kept: u64) -> Dense<f64> {
+        install().unwrap();
+        let a = Dense::<f64>::create(path, 64, 1024).unwrap();
+        OpenOptions::new().write(true).open(path).unwrap().set_len(kept).unwrap();
+        a
+    }
+
+    fn check_bus_error<R>(result: Result<R, OoclaError>, path: &Path, kept: u64) {
+        match result {
+            Err(OoclaError::BusError { path: faulted, offset }) => {
+                assert_eq!(faulted, Some(fs::canonicalize(path).unwrap()));
+                let page = page_size() as u64;
+                assert!(offset >= kept.div_ceil(page) * page && offset < 64 + 64 * 8192, "offset {}", offset);
+            }
+            other => panic!("expected a bus error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn writes_beyond_a_truncated_file_are_bus_errors() {
+        let path = TempMatrixPath::new();
+        let kept = 3 * page_size() as u64 + 100;
+        let mut a = truncated(path.path(), kept);
+        check_bus_error(a.fill(1.0), path.path(), kept);
+        let path = TempMatrixPath::new();
+        let mut a = truncated(path.path(), kept);
+        check_bus_error(a.randomise(), path.path(), kept);
+        let path = TempMatrixPath::new();
+        let mut a = truncated(path.path(), kept);
+        check_bus_error(a.fill_with(|i, j| (i + j) as f64), path.path(), kept);
+    }
+
+    #[test]
+    fn reads_beyond_a_truncated_file_are_bus_errors() {
+        let path = TempMatrixPath::new();
+        let a = truncated(path.path(), page_size() as u64);
+        check_bus_error(a.copy_at(None), path.path(), page_size() as u64);
+    }
+
+    #[test]
+    fn faults_are_reported_once_by_the_outermost_operation() {
+        let path = TempMatrixPath::new();
+        let mut a = truncated(path.path(), 8192);
+        let inner = guarded(|| a.fill(2.0));
+        // The inner operation sees the fault, and the outer one reports it too.
+        let outer = guarded(|| inner);
+        check_bus_error(outer, path.path(), 8192);
+        // Having been reported, the fault doesn't outlive the operation.
+        assert_eq!(guarded(|| Ok(5)).unwrap(), 5);
+    }
+
+    #[test]
+    fn mappings_are_found_by_address_until_released() {
+        let base = 0x7000_0000_0000usize;
+        let slot = register(base as *const c_void, 4096, 1234).unwrap();
+        assert_eq!(find_mapping(base), Some((1234, base)));
+        assert_eq!(find_mapping(base + 4095), Some((1234, base)));
+        assert_eq!(find_mapping(base + 4096), None);
+        assert_eq!(find_mapping(base - 1), None);
+        deregister(slot);
+        assert_eq!(find_mapping(base), None);
+        assert_eq!(register(base as *const c_void, 0, 1234), None);
+    }
+
+    #[test]
+    fn decimals_are_written_without_allocating() {
+        let mut buf = [0; 20];
+        assert_eq!(decimal(0, &mut buf), b"0");
+        assert_eq!(decimal(4096, &mut buf), b"4096");
+        assert_eq!(decimal(u64::MAX, &mut buf), b"18446744073709551615");
+    }
+}
@@ -0,0 +1,110 @@
+use dense_matrix::{Dense, StorageType, StreamOptions, HEADER_SIZE, as_bytes};
+use direct::DirectWriter;
+use error::OoclaError;
+use io_stats;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+enum Sink {
+    Buffered(BufWriter<File>),
+    Direct(DirectWriter),
+}
+
+impl Sink {
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match *self {
+            Sink::Buffered(ref mut writer) => writer.write_all(bytes),
+            Sink::Direct(ref mut writer) => writer.write_all(bytes),
+        }
+    }
+
+    fn into_file(self) -> io::Result<File> {
+        match self {
+            Sink::Buffered(writer) => writer.into_inner().map_err(|e| e.into_error()),
+            Sink::Direct(writer) => writer.finish(),
+        }
+    }
+}
+
+// Builds a matrix whose row count isn't known up front by appending rows to the file
+// sequentially. The header is only filled in by `finish`, so a writer that is dropped
+// without finishing removes its partial output.
+pub struct RowWriter<T> {
+    path: PathBuf,
+    writer: Option<Sink>,
+    cols: u64,
+    rows: u64,
+    element: PhantomData<T>,
+}
+
+impl<T: StorageType> RowWriter<T> {
+    pub fn create(path: &Path, cols: u64) -> Result<RowWriter<T>, OoclaError> {
+        Self::create_with_capacity(path, cols, 1 << 20)
+    }
+
+    // As create, but buffering `capacity` bytes, for when many writers are open at once.
+    pub fn create_with_capacity(path: &Path, cols: u64, capacity: usize) -> Result<RowWriter<T>, OoclaError> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        Self::from_sink(path, cols, Sink::Buffered(BufWriter::with_capacity(capacity, file)))
+    }
+
+    // As create, writing the rows with O_DIRECT when `options` ask for direct_io.
+    pub fn create_with_options(path: &Path, cols: u64, options: StreamOptions) -> Result<RowWriter<T>, OoclaError> {
+        if options.direct_io {
+            Self::from_sink(path, cols, Sink::Direct(DirectWriter::create(path)?))
+        } else {
+            Self::create(path, cols)
+        }
+    }
+
+    fn from_sink(path: &Path, cols: u64, mut writer: Sink) -> Result<RowWriter<T>, OoclaError> {
+        writer.write_all(&[0; HEADER_SIZE])?;
+        Ok(RowWriter {
+            path: path.to_path_buf(),
+            writer: Some(writer),
+            cols,
+            rows: 0,
+            element: PhantomData,
+        })
+    }
+
+    pub fn num_cols(&self) -> u64 {
+        self.cols
+    }
+
+    pub fn rows_written(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn write_row(&mut self, row: &[T]) -> Result<(), OoclaError> {
+        if row.len() as u64 != self.cols {
+            return Err(OoclaError::InvalidArgument(format!("row of length {} written to a matrix with {} columns",
+                                                           row.len(), self.cols)));
+        }
+        self.writer.as_mut().expect("writer already finished").write_all(as_bytes(row))?;
+        io_stats::count_written(mem::size_of_val(row));
+        self.rows += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Dense<T>, OoclaError> {
+        let writer = self.writer.take().expect("writer already finished");
+        let file = writer.into_file()?;
+        let len = Dense::<T>::compute_length(self.rows, self.cols)?;
+        file.set_len(len)?;
+        let mut result = Dense::from_file(file, len)?;
+        result.init_header(self.rows, self.cols);
+        Ok(result)
+    }
+}
+
+impl<T> Drop for RowWriter<T> {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
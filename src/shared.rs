@@ -0,0 +1,216 @@
+use dense_matrix::{Dense, RowIter};
+use error::OoclaError;
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+// A matrix that any number of threads may read at once, from Dense::into_shared. Everything a
+// Dense offers through a shared reference is reached by deref, such as get, read_row,
+// element_iter, row_iter, read_tile and the reductions in ops, and view hands a band of rows to a
+// thread of its own. Clones share the matrix, which is unmapped when the last of them is dropped,
+// on whichever thread that is. The mapping is made read-only while it is shared, so writing
+// through a pointer from raw_parts faults, and raw_parts says as much. Changing the matrix again
+// needs the Dense back, from try_unwrap.
+pub struct SharedDense<T> {
+    matrix: Arc<Dense<T>>,
+}
+
+// A shared reference to a Dense changes nothing: its elements and header are written only through
+// &mut, which no handle gives out, what it reads of its file is read at explicit offsets rather
+// than through the file's cursor, and the state it keeps for warnings is atomic. Threads holding
+// handles may therefore all read, and whichever drops the last handle unmaps the matrix, which
+// Send for Dense allows.
+unsafe impl<T: Send + Sync> Send for SharedDense<T> {}
+unsafe impl<T: Send + Sync> Sync for SharedDense<T> {}
+
+impl<T> Dense<T> {
+    // Gives up mutable access to the matrix so that it can be read from several threads. Fails,
+    // dropping the matrix, only if its mapping can't be made read-only.
+    pub fn into_shared(mut self) -> Result<SharedDense<T>, OoclaError> {
+        self.set_read_only(true)?;
+        Ok(SharedDense { matrix: Arc::new(self) })
+    }
+}
+
+impl<T> SharedDense<T> {
+    // The matrix, writable again, if this is its only handle, or otherwise the handle unchanged.
+    pub fn try_unwrap(self) -> Result<Dense<T>, SharedDense<T>> {
+        let mut matrix = Arc::try_unwrap(self.matrix).map_err(|matrix| SharedDense { matrix })?;
+        // The mapping was writable before it was shared, so giving it back the same protection
+        // fails only if the kernel runs out of memory for it.
+        matrix.set_read_only(false).expect("restoring write access to a shared matrix");
+        Ok(matrix)
+    }
+
+    // A handle to the logical rows in `rows`, which owns a clone of this one.
+    pub fn view(&self, rows: Range<u64>) -> SharedView<T> {
+        assert!(rows.start <= rows.end && rows.end <= self.num_rows(), "rows {:?} out of bounds for {} rows", rows,
+                self.num_rows());
+        SharedView {
+            matrix: self.clone(),
+            rows,
+        }
+    }
+}
+
+impl<T> Clone for SharedDense<T> {
+    fn clone(&self) -> SharedDense<T> {
+        SharedDense { matrix: self.matrix.clone() }
+    }
+}
+
+impl<T> Deref for SharedDense<T> {
+    type Target = Dense<T>;
+
+    fn deref(&self) -> &Dense<T> {
+        &self.matrix
+    }
+}
+
+impl<T> fmt::Debug for SharedDense<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedDense").field(&*self.matrix).finish()
+    }
+}
+
+// A band of consecutive rows of a shared matrix, numbered from the first row of the band.
+pub struct SharedView<T> {
+    matrix: SharedDense<T>,
+    rows: Range<u64>,
+}
+
+impl<T> SharedView<T> {
+    pub fn matrix(&self) -> &SharedDense<T> {
+        &self.matrix
+    }
+
+    // The rows of the matrix the view covers.
+    pub fn rows(&self) -> Range<u64> {
+        self.rows.clone()
+    }
+
+    pub fn num_rows(&self) -> u64 {
+        self.rows.end - self.rows.start
+    }
+
+    pub fn num_cols(&self) -> u64 {
+        self.matrix.num_cols()
+    }
+
+    fn matrix_row(&self, row: u64) -> u64 {
+        assert!(row < self.num_rows(), "row {} out of bounds for a view of {} rows", row, self.num_rows());
+        self.rows.start + row
+    }
+
+    pub fn get(&self, row: u64, col: u64) -> T where T: Copy {
+        self.matrix.get(self.matrix_row(row), col)
+    }
+
+    pub fn read_row(&self, row: u64, dst: &mut [T]) where T: Copy {
+        self.matrix.read_row(self.matrix_row(row), dst)
+    }
+
+    pub fn row_iter(&self) -> RowIter<'_, T> where T: Copy {
+        self.matrix.row_range_iter(self.rows())
+    }
+}
+
+impl<T> Clone for SharedView<T> {
+    fn clone(&self) -> SharedView<T> {
+        SharedView {
+            matrix: self.matrix.clone(),
+            rows: self.rows(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for SharedView<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedView")
+            .field("matrix", &self.matrix)
+            .field("rows", &self.rows)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use ops::{column_stats, trace};
+    use std::path::Path;
+    use std::sync::Barrier;
+    use std::thread;
+
+    const THREADS: usize = 8;
+
+    fn shared(path: &Path) -> SharedDense<f64> {
+        Dense::from_fn(path, 300, 200, |i, j| (i * 200 + j) as f64).unwrap().into_shared().unwrap()
+    }
+
+    #[test]
+    fn threads_read_a_shared_matrix_concurrently() {
+        let path = TempMatrixPath::new();
+        let a = shared(path.path());
+        let (expected_trace, expected_stats) = (trace(&*a), column_stats(&*a, 1).unwrap());
+        let start = Arc::new(Barrier::new(THREADS));
+        let readers: Vec<_> = (0..THREADS as u64).map(|t| {
+            let (a, start) = (a.clone(), start.clone());
+            thread::spawn(move || {
+                start.wait();
+                // Each thread reads the whole matrix several ways, as well as a band of its own.
+                let elements: Vec<f64> = a.element_iter().cloned().collect();
+                assert!(elements.iter().enumerate().all(|(k, &x)| x == k as f64));
+                assert!(a.row_iter().enumerate().all(|(i, row)| row[199] == (i * 200 + 199) as f64));
+                let band = a.view(t * 30..t * 30 + 40);
+                let mut row = vec![0.0; 200];
+                for i in 0..band.num_rows() {
+                    band.read_row(i, &mut row);
+                    assert_eq!(row[7], ((t * 30 + i) * 200 + 7) as f64);
+                    assert_eq!(band.get(i, 7), row[7]);
+                }
+                assert_eq!(band.row_iter().count(), 40);
+                (trace(&*a), column_stats(&*a, 1).unwrap())
+            })
+        }).collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), (expected_trace, expected_stats.clone()));
+        }
+        assert!(a.try_unwrap().is_ok());
+    }
+
+    // Handles are dropped on the other threads as they finish, in whatever order they do, so that
+    // any of them may be the one to unmap the matrix.
+    #[test]
+    fn the_last_handle_unmaps_the_matrix_on_any_thread() {
+        for _ in 0..20 {
+            let path = TempMatrixPath::new();
+            let a = shared(path.path());
+            let start = Arc::new(Barrier::new(THREADS + 1));
+            let holders: Vec<_> = (0..THREADS).map(|t| {
+                let (a, start) = (a.clone(), start.clone());
+                thread::spawn(move || {
+                    start.wait();
+                    let sum: f64 = a.view(0..a.num_rows()).row_iter().map(|row| row[t]).sum();
+                    drop(a);
+                    sum
+                })
+            }).collect();
+            let a = a.try_unwrap().expect_err("the matrix is shared with the threads");
+            start.wait();
+            let view = a.view(10..20);
+            drop(a);
+            for (t, holder) in holders.into_iter().enumerate() {
+                assert_eq!(holder.join().unwrap(), (0..300).map(|i| (i * 200 + t) as f64).sum::<f64>());
+            }
+            // The view holds the last handle, through which the matrix is still readable.
+            assert_eq!(view.get(9, 3), (19 * 200 + 3) as f64);
+            let handle = view.matrix().clone().try_unwrap().expect_err("the view still holds a handle");
+            drop(view);
+            // Given back by the only handle, the matrix is writable again.
+            let mut matrix = handle.try_unwrap().expect("the only handle gives the matrix back");
+            matrix.set(0, 0, -1.0);
+            assert_eq!(matrix.get(0, 0), -1.0);
+        }
+    }
+}
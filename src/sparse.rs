@@ -0,0 +1,683 @@
+use dense_matrix::{Dense, FloatType, MapOptions, SupportedType, map_shared};
+use error::OoclaError;
+use nix::libc::c_void;
+use nix::sys::mman::munmap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::{mem, ptr, slice};
+
+const CSR_HEADER_SIZE: usize = 64;
+// "OOCCSR" when read as little-endian bytes.
+const CSR_MAGIC: u64 = 0x0000_5253_4343_4f4f;
+
+#[repr(C)]
+struct CsrHeader {
+    magic: u64,
+    num_rows: u64,
+    num_cols: u64,
+    nnz: u64,
+    representation: FloatType,
+    // Bytes per column index, 4 or 8.
+    index_width: u32,
+}
+
+// The column indices of some stored entries, as u32 when every index of the matrix fits.
+#[derive(Clone, Copy, Debug)]
+pub enum ColumnIndices<'a> {
+    Narrow(&'a [u32]),
+    Wide(&'a [u64]),
+}
+
+impl<'a> ColumnIndices<'a> {
+    pub fn len(&self) -> usize {
+        match *self {
+            ColumnIndices::Narrow(indices) => indices.len(),
+            ColumnIndices::Wide(indices) => indices.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, k: usize) -> u64 {
+        match *self {
+            ColumnIndices::Narrow(indices) => u64::from(indices[k]),
+            ColumnIndices::Wide(indices) => indices[k],
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u64> + 'a {
+        let (narrow, wide) = match *self {
+            ColumnIndices::Narrow(indices) => (indices, &[][..]),
+            ColumnIndices::Wide(indices) => (&[][..], indices),
+        };
+        narrow.iter().map(|&c| u64::from(c)).chain(wide.iter().cloned())
+    }
+}
+
+// Byte offsets of the three arrays following the header.
+struct CsrLayout {
+    row_ptr: usize,
+    col_idx: usize,
+    values: usize,
+    total: u64,
+}
+
+impl CsrLayout {
+    fn new<T>(rows: u64, nnz: u64, index_width: u32) -> Result<CsrLayout, OoclaError> {
+        let overflow = || OoclaError::SizeOverflow(format!("a CSR matrix with {} rows and {} non-zeros", rows, nnz));
+        let row_ptr = CSR_HEADER_SIZE as u64;
+        let col_idx = rows.checked_add(1)
+            .and_then(|n| n.checked_mul(8))
+            .and_then(|bytes| bytes.checked_add(row_ptr))
+            .ok_or_else(overflow)?;
+        let values = nnz.checked_mul(u64::from(index_width))
+            .and_then(|bytes| bytes.checked_add(col_idx))
+            .ok_or_else(overflow)?;
+        let align = mem::align_of::<T>() as u64;
+        let values = values.checked_add(align - 1).ok_or_else(overflow)? / align * align;
+        let total = nnz.checked_mul(mem::size_of::<T>() as u64)
+            .and_then(|bytes| bytes.checked_add(values))
+            .filter(|&total| total <= isize::MAX as u64)
+            .ok_or_else(overflow)?;
+        Ok(CsrLayout {
+            row_ptr: row_ptr as usize,
+            col_idx: col_idx as usize,
+            values: values as usize,
+            total,
+        })
+    }
+}
+
+pub struct CsrMatrix<T> {
+    #[allow(dead_code)]
+    file: File,
+    start: *mut c_void,
+    length: usize,
+    header: *mut CsrHeader,
+    row_ptr: *mut u64,
+    col_idx: *mut c_void,
+    values: *mut T,
+}
+
+impl<T: SupportedType> CsrMatrix<T> {
+    fn from_file(file: File, layout: &CsrLayout) -> Result<CsrMatrix<T>, OoclaError> {
+        let start = map_shared(&file, layout.total, MapOptions::default())?;
+        let base = start as *mut u8;
+        Ok(unsafe {
+            CsrMatrix {
+                file,
+                start,
+                length: layout.total as usize,
+                header: start as *mut CsrHeader,
+                row_ptr: base.add(layout.row_ptr) as *mut u64,
+                col_idx: base.add(layout.col_idx) as *mut c_void,
+                values: base.add(layout.values) as *mut T,
+            }
+        })
+    }
+
+    fn create_with_width(path: &Path, rows: u64, cols: u64, nnz: u64, index_width: u32)
+        -> Result<CsrMatrix<T>, OoclaError> {
+        let layout = CsrLayout::new::<T>(rows, nnz, index_width)?;
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len(layout.total)?;
+        let mut result = Self::from_file(file, &layout)?;
+        let header = result.get_header_mut();
+        header.magic = CSR_MAGIC;
+        header.num_rows = rows;
+        header.num_cols = cols;
+        header.nnz = nnz;
+        header.representation = T::get_float_type();
+        header.index_width = index_width;
+        Ok(result)
+    }
+
+    // Creates a matrix with u64 column indices for parts_mut to fill.
+    pub(crate) fn create(path: &Path, rows: u64, cols: u64, nnz: u64) -> Result<CsrMatrix<T>, OoclaError> {
+        Self::create_with_width(path, rows, cols, nnz, 8)
+    }
+
+    // Creates a rows x cols matrix at `path` holding the (row, column, value) entries of
+    // `triplets`, which may come in any order. Entries for the same position are summed. Column
+    // indices are stored as u32 whenever every column fits, halving their size.
+    pub fn create_from_triplets(path: &Path, rows: u64, cols: u64, triplets: &[(u64, u64, T)])
+        -> Result<CsrMatrix<T>, OoclaError> {
+        if let Some(&(row, col, _)) = triplets.iter().find(|&&(row, col, _)| row >= rows || col >= cols) {
+            return Err(OoclaError::InvalidArgument(format!("entry ({}, {}) lies outside the {}x{} matrix",
+                                                           row, col, rows, cols)));
+        }
+        let mut order: Vec<usize> = (0..triplets.len()).collect();
+        order.sort_by_key(|&k| (triplets[k].0, triplets[k].1));
+        let mut entries: Vec<(u64, u64, f64)> = Vec::with_capacity(order.len());
+        for k in order {
+            let (row, col, value) = triplets[k];
+            match entries.last_mut() {
+                Some(last) if (last.0, last.1) == (row, col) => last.2 += value.to_f64(),
+                _ => entries.push((row, col, value.to_f64())),
+            }
+        }
+        let index_width = if cols <= u64::from(u32::MAX) + 1 { 4 } else { 8 };
+        let mut result = Self::create_with_width(path, rows, cols, entries.len() as u64, index_width)?;
+        for (k, &(_, col, _)) in entries.iter().enumerate() {
+            result.set_column_index(k, col);
+        }
+        let (row_ptr, values) = result.row_ptr_and_values_mut();
+        for (k, &(row, _, value)) in entries.iter().enumerate() {
+            row_ptr[row as usize + 1] = k as u64 + 1;
+            values[k] = T::from_f64(value);
+        }
+        for r in 0..rows as usize {
+            row_ptr[r + 1] = row_ptr[r + 1].max(row_ptr[r]);
+        }
+        Ok(result)
+    }
+
+    // Opens the matrix at `path`. The row pointers and column indices are checked against the
+    // shape and number of entries first, so a damaged file is reported rather than causing reads
+    // outside the mapping.
+    pub fn open(path: &Path) -> Result<CsrMatrix<T>, OoclaError> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len();
+        if len < CSR_HEADER_SIZE as u64 {
+            return Err(OoclaError::InvalidFormat(format!("{} bytes is too short for a CSR header", len)));
+        }
+        let mut raw = [0u8; CSR_HEADER_SIZE];
+        file.read_exact_at(&mut raw, 0)?;
+        let field = |offset: usize| unsafe { ptr::read_unaligned(raw.as_ptr().add(offset) as *const u64) };
+        let word = |offset: usize| unsafe { ptr::read_unaligned(raw.as_ptr().add(offset) as *const u32) };
+        let magic = field(mem::offset_of!(CsrHeader, magic));
+        if magic != CSR_MAGIC {
+            return Err(OoclaError::InvalidFormat(format!("bad CSR magic number {:#x}", magic)));
+        }
+        let code = word(mem::offset_of!(CsrHeader, representation));
+        match FloatType::from_code(code) {
+            None => return Err(OoclaError::InvalidFormat(format!("unknown element representation {}", code))),
+            Some(found) if found != T::get_float_type() => {
+                return Err(OoclaError::TypeMismatch { expected: T::get_float_type(), found });
+            }
+            Some(_) => {}
+        }
+        let index_width = word(mem::offset_of!(CsrHeader, index_width));
+        if index_width != 4 && index_width != 8 {
+            return Err(OoclaError::InvalidFormat(format!("invalid column index width {}", index_width)));
+        }
+        let (rows, nnz) = (field(mem::offset_of!(CsrHeader, num_rows)), field(mem::offset_of!(CsrHeader, nnz)));
+        let layout = CsrLayout::new::<T>(rows, nnz, index_width)?;
+        if len < layout.total {
+            return Err(OoclaError::InvalidFormat(format!("{} byte file is too short for a CSR matrix with {} rows \
+                                                          and {} non-zeros", len, rows, nnz)));
+        }
+        let result = Self::from_file(file, &layout)?;
+        result.validate_indices()?;
+        Ok(result)
+    }
+
+    fn validate_indices(&self) -> Result<(), OoclaError> {
+        let row_ptr = self.row_ptr();
+        if row_ptr[0] != 0 || row_ptr[row_ptr.len() - 1] != self.nnz() {
+            return Err(OoclaError::InvalidFormat(format!("row pointers run from {} to {}, not 0 to the {} non-zeros",
+                                                         row_ptr[0], row_ptr[row_ptr.len() - 1], self.nnz())));
+        }
+        if let Some(i) = row_ptr.windows(2).position(|w| w[0] > w[1]) {
+            return Err(OoclaError::InvalidFormat(format!("row pointers decrease at row {}", i)));
+        }
+        let cols = self.num_cols();
+        if let Some(k) = self.column_indices(0, self.nnz() as usize).iter().position(|c| c >= cols) {
+            return Err(OoclaError::InvalidFormat(format!("stored entry {} has column {} in a matrix with {} columns",
+                                                         k, self.column_indices(k, k + 1).get(0), cols)));
+        }
+        Ok(())
+    }
+
+    fn get_header(&self) -> &CsrHeader {
+        unsafe {
+            &*self.header
+        }
+    }
+
+    fn get_header_mut(&mut self) -> &mut CsrHeader {
+        unsafe {
+            &mut *self.header
+        }
+    }
+
+    pub fn num_rows(&self) -> u64 {
+        self.get_header().num_rows
+    }
+
+    pub fn num_cols(&self) -> u64 {
+        self.get_header().num_cols
+    }
+
+    pub fn nnz(&self) -> u64 {
+        self.get_header().nnz
+    }
+
+    pub fn density(&self) -> f64 {
+        let elements = self.num_rows() as f64 * self.num_cols() as f64;
+        if elements == 0.0 {
+            0.0
+        } else {
+            self.nnz() as f64 / elements
+        }
+    }
+
+    pub fn row_ptr(&self) -> &[u64] {
+        unsafe {
+            slice::from_raw_parts(self.row_ptr, self.num_rows() as usize + 1)
+        }
+    }
+
+    fn column_indices(&self, begin: usize, end: usize) -> ColumnIndices<'_> {
+        unsafe {
+            if self.get_header().index_width == 4 {
+                ColumnIndices::Narrow(slice::from_raw_parts((self.col_idx as *const u32).add(begin), end - begin))
+            } else {
+                ColumnIndices::Wide(slice::from_raw_parts((self.col_idx as *const u64).add(begin), end - begin))
+            }
+        }
+    }
+
+    // Column indices and values of the stored entries of row `i`, borrowed from the mapping.
+    pub fn row(&self, i: u64) -> (ColumnIndices<'_>, &[T]) {
+        assert!(i < self.num_rows(), "row {} out of bounds for {} rows", i, self.num_rows());
+        let row_ptr = self.row_ptr();
+        let (begin, end) = (row_ptr[i as usize] as usize, row_ptr[i as usize + 1] as usize);
+        unsafe {
+            (self.column_indices(begin, end), slice::from_raw_parts(self.values.add(begin), end - begin))
+        }
+    }
+
+    // The stored entries as (row, column, value), in row-major order.
+    pub fn iter_nonzero(&self) -> NonzeroIter<'_, T> {
+        NonzeroIter { matrix: self, row: 0, k: 0 }
+    }
+
+    fn set_column_index(&mut self, k: usize, col: u64) {
+        assert!((k as u64) < self.nnz(), "entry {} out of bounds for {} entries", k, self.nnz());
+        unsafe {
+            if self.get_header().index_width == 4 {
+                *(self.col_idx as *mut u32).add(k) = col as u32;
+            } else {
+                *(self.col_idx as *mut u64).add(k) = col;
+            }
+        }
+    }
+
+    fn row_ptr_and_values_mut(&mut self) -> (&mut [u64], &mut [T]) {
+        let (rows, nnz) = (self.num_rows() as usize, self.nnz() as usize);
+        unsafe {
+            (slice::from_raw_parts_mut(self.row_ptr, rows + 1), slice::from_raw_parts_mut(self.values, nnz))
+        }
+    }
+
+    // The arrays of a matrix made by create, which always has u64 column indices.
+    pub(crate) fn parts_mut(&mut self) -> (&mut [u64], &mut [u64], &mut [T]) {
+        assert_eq!(self.get_header().index_width, 8, "parts_mut needs u64 column indices");
+        let (rows, nnz) = (self.num_rows() as usize, self.nnz() as usize);
+        unsafe {
+            (slice::from_raw_parts_mut(self.row_ptr, rows + 1),
+             slice::from_raw_parts_mut(self.col_idx as *mut u64, nnz),
+             slice::from_raw_parts_mut(self.values, nnz))
+        }
+    }
+
+    pub fn to_dense(&self, dst: &Path) -> Result<Dense<T>, OoclaError> {
+        let mut result = Dense::create(dst, self.num_rows(), self.num_cols())?;
+        let zero = T::from_f64(0.0);
+        let mut row = vec![zero; self.num_cols() as usize];
+        for i in 0..self.num_rows() {
+            let (cols, values) = self.row(i);
+            for (c, &v) in cols.iter().zip(values.iter()) {
+                row[c as usize] = v;
+            }
+            result.write_row(i, &row);
+            for c in cols.iter() {
+                row[c as usize] = zero;
+            }
+        }
+        Ok(result)
+    }
+}
+
+// Computes C = A·B, or C += A·B when `accumulate` is set, a row of C at a time. Each stored
+// entry of a row of A scales the matching row of B into that row of C, so B is read a row at a
+// time in the order of the row's column indices, which every constructor stores sorted.
+pub fn spmm(a: &CsrMatrix<f64>, b: &Dense<f64>, c: &mut Dense<f64>, accumulate: bool) -> Result<(), OoclaError> {
+    if a.num_cols() != b.num_rows() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_cols(), b.num_cols()),
+            found: (b.num_rows(), b.num_cols()),
+        });
+    }
+    if c.num_rows() != a.num_rows() || c.num_cols() != b.num_cols() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), b.num_cols()),
+            found: (c.num_rows(), c.num_cols()),
+        });
+    }
+    let mut acc = vec![0.0; b.num_cols() as usize];
+    let mut scratch = vec![0.0; b.num_cols() as usize];
+    for i in 0..a.num_rows() {
+        let (cols, values) = a.row(i);
+        if accumulate {
+            if cols.is_empty() {
+                continue;
+            }
+            c.read_row(i, &mut acc);
+        } else {
+            acc.iter_mut().for_each(|x| *x = 0.0);
+        }
+        for (k, &v) in cols.iter().zip(values.iter()) {
+            for (acc, &bkj) in acc.iter_mut().zip(b.row_view(k, &mut scratch).iter()) {
+                *acc += v * bkj;
+            }
+        }
+        c.write_row(i, &acc);
+    }
+    Ok(())
+}
+
+// Computes y = A·x.
+pub fn spmv(a: &CsrMatrix<f64>, x: &[f64], y: &mut [f64]) -> Result<(), OoclaError> {
+    if x.len() as u64 != a.num_cols() || y.len() as u64 != a.num_rows() {
+        return Err(OoclaError::ShapeMismatch {
+            expected: (a.num_rows(), a.num_cols()),
+            found: (y.len() as u64, x.len() as u64),
+        });
+    }
+    for (i, dst) in y.iter_mut().enumerate() {
+        let (cols, values) = a.row(i as u64);
+        *dst = cols.iter().zip(values.iter()).fold(0.0, |sum, (k, &v)| sum + v * x[k as usize]);
+    }
+    Ok(())
+}
+
+pub struct NonzeroIter<'a, T: 'a> {
+    matrix: &'a CsrMatrix<T>,
+    row: u64,
+    k: usize,
+}
+
+impl<'a, T: SupportedType> Iterator for NonzeroIter<'a, T> {
+    type Item = (u64, u64, &'a T);
+
+    fn next(&mut self) -> Option<(u64, u64, &'a T)> {
+        let row_ptr = self.matrix.row_ptr();
+        if self.k as u64 == self.matrix.nnz() {
+            return None;
+        }
+        while row_ptr[self.row as usize + 1] as usize <= self.k {
+            self.row += 1;
+        }
+        let k = self.k;
+        self.k += 1;
+        let value = unsafe { &*self.matrix.values.add(k) };
+        Some((self.row, self.matrix.column_indices(k, k + 1).get(0), value))
+    }
+}
+
+impl<T> Drop for CsrMatrix<T> {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.start, self.length)
+        }.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::TempMatrixPath;
+    use io::matrix_market::{CoordinateMatrix, CoordinateTarget, import_coordinate};
+    use ops::{gemm, gemv};
+    use rand::{Rng, SeedableRng, StdRng};
+    use std::fs;
+
+    fn imported(text: &str, target: CoordinateTarget, dst: &Path) -> CoordinateMatrix<f64> {
+        let src = TempMatrixPath::new();
+        fs::write(src.path(), text).unwrap();
+        import_coordinate(src.path(), dst, target).unwrap()
+    }
+
+    fn imported_csr(text: &str, dst: &Path) -> CsrMatrix<f64> {
+        match imported(text, CoordinateTarget::Csr, dst) {
+            CoordinateMatrix::Csr(a) => a,
+            CoordinateMatrix::Dense(_) => panic!("expected a CSR matrix"),
+        }
+    }
+
+    fn entries(a: &CsrMatrix<f64>) -> Vec<(u64, u64, f64)> {
+        a.iter_nonzero().map(|(i, j, &v)| (i, j, v)).collect()
+    }
+
+    // A coordinate file of `triplets` with 1-based indices, in the order given.
+    fn coordinate_file(rows: u64, cols: u64, triplets: &[(u64, u64, f64)]) -> String {
+        let mut text = format!("%%MatrixMarket matrix coordinate real general\n{} {} {}\n", rows, cols,
+                               triplets.len());
+        for &(i, j, v) in triplets {
+            text += &format!("{} {} {:e}\n", i + 1, j + 1, v);
+        }
+        text
+    }
+
+    #[test]
+    fn coordinate_imports_match_triplets_and_round_trip() {
+        let mut rng: StdRng = SeedableRng::from_seed(&[5usize][..]);
+        let (rows, cols) = (60, 45);
+        // About 3% dense, in no particular order, with some positions repeated.
+        let mut triplets: Vec<(u64, u64, f64)> = (0..80)
+            .map(|_| (rng.gen_range(0, rows), rng.gen_range(0, cols), rng.gen_range(-1.0, 1.0)))
+            .collect();
+        let repeated = triplets[..10].to_vec();
+        triplets.extend(repeated.iter().map(|&(i, j, _)| (i, j, 0.25)));
+        let text = coordinate_file(rows, cols, &triplets);
+
+        let path = TempMatrixPath::new();
+        let a = imported_csr(&text, path.path());
+        let from_triplets = TempMatrixPath::new();
+        let b = CsrMatrix::create_from_triplets(from_triplets.path(), rows, cols, &triplets).unwrap();
+        assert_eq!((a.num_rows(), a.num_cols(), a.nnz()), (rows, cols, b.nnz()));
+        assert_eq!(entries(&a), entries(&b));
+        assert_eq!(a.row_ptr(), b.row_ptr());
+        drop(a);
+        let a = CsrMatrix::<f64>::open(path.path()).unwrap();
+        assert_eq!(entries(&a), entries(&b));
+
+        // Against the dense import, element by element.
+        let dense_path = TempMatrixPath::new();
+        let dense = match imported(&text, CoordinateTarget::Dense, dense_path.path()) {
+            CoordinateMatrix::Dense(d) => d,
+            CoordinateMatrix::Csr(_) => panic!("expected a dense matrix"),
+        };
+        let converted = TempMatrixPath::new();
+        let c = a.to_dense(converted.path()).unwrap();
+        for i in 0..rows {
+            for j in 0..cols {
+                assert_eq!(c.get(i, j), dense.get(i, j), "({}, {})", i, j);
+            }
+        }
+        // Rows hold their entries in column order.
+        for i in 0..rows {
+            let (cols, values) = a.row(i);
+            assert_eq!(cols.len(), values.len());
+            let cols: Vec<u64> = cols.iter().collect();
+            assert!(cols.windows(2).all(|w| w[0] < w[1]), "row {} is unsorted: {:?}", i, cols);
+            for (&j, &v) in cols.iter().zip(values) {
+                assert_eq!(v, dense.get(i, j));
+            }
+        }
+    }
+
+    #[test]
+    fn empty_rows_and_matrices() {
+        let path = TempMatrixPath::new();
+        let a = imported_csr(&coordinate_file(4, 3, &[(3, 2, 1.0), (0, 0, 2.0)]), path.path());
+        assert_eq!(a.row_ptr(), &[0, 1, 1, 1, 2]);
+        assert!(a.row(1).0.is_empty() && a.row(2).1.is_empty());
+        assert_eq!(entries(&a), vec![(0, 0, 2.0), (3, 2, 1.0)]);
+        let path = TempMatrixPath::new();
+        let a = imported_csr(&coordinate_file(3, 3, &[]), path.path());
+        assert_eq!((a.nnz(), a.density()), (0, 0.0));
+        assert!(a.iter_nonzero().next().is_none());
+        drop(a);
+        assert_eq!(CsrMatrix::<f64>::open(path.path()).unwrap().row_ptr(), &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn column_indices_are_narrow_unless_they_overflow_u32() {
+        let path = TempMatrixPath::new();
+        let a = CsrMatrix::create_from_triplets(path.path(), 2, 1 << 32, &[(1, (1 << 32) - 1, 1.0)]).unwrap();
+        assert!(matches!(a.row(1).0, ColumnIndices::Narrow(_)));
+        let path = TempMatrixPath::new();
+        let a = CsrMatrix::create_from_triplets(path.path(), 2, (1 << 32) + 1, &[(1, 1 << 32, 1.0)]).unwrap();
+        assert!(matches!(a.row(1).0, ColumnIndices::Wide(_)));
+        drop(a);
+        let a = CsrMatrix::<f64>::open(path.path()).unwrap();
+        assert_eq!(entries(&a), vec![(1, 1 << 32, 1.0)]);
+    }
+
+    // Overwrites the u64 at `offset` of the file at `path`.
+    fn patch(path: &Path, offset: u64, value: u64) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.write_all_at(&value.to_le_bytes(), offset).unwrap();
+    }
+
+    fn assert_corrupt(path: &Path, reason: &str) {
+        match CsrMatrix::<f64>::open(path) {
+            Err(OoclaError::InvalidFormat(msg)) => assert!(msg.contains(reason), "{}", msg),
+            other => panic!("expected an invalid format, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn corrupt_files_are_rejected_on_open() {
+        let triplets = [(0, 1, 1.0), (1, 0, 2.0), (2, 2, 3.0)];
+        let row_ptr = CSR_HEADER_SIZE as u64;
+        let cases: [(u64, u64, &str); 4] = [
+            (row_ptr, 1, "row pointers run from"),
+            (row_ptr + 3 * 8, 2, "row pointers run from"),
+            (row_ptr + 8, 3, "decrease"),
+            // The first column index, u32 wide, patched together with the second.
+            (row_ptr + 4 * 8, 7, "has column 7"),
+        ];
+        for &(offset, value, reason) in &cases {
+            let path = TempMatrixPath::new();
+            CsrMatrix::create_from_triplets(path.path(), 3, 3, &triplets).unwrap();
+            patch(path.path(), offset, value);
+            assert_corrupt(path.path(), reason);
+        }
+        let path = TempMatrixPath::new();
+        CsrMatrix::create_from_triplets(path.path(), 3, 3, &triplets).unwrap();
+        let len = fs::metadata(path.path()).unwrap().len();
+        OpenOptions::new().write(true).open(path.path()).unwrap().set_len(len - 1).unwrap();
+        assert_corrupt(path.path(), "too short");
+        match CsrMatrix::create_from_triplets(path.path(), 3, 3, &[(3, 0, 1.0)]) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected an invalid argument, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    // A random rows x cols matrix about a quarter dense, with rows 0, 5 and the last left empty.
+    fn random_csr(rng: &mut StdRng, rows: u64, cols: u64, dst: &Path) -> CsrMatrix<f64> {
+        let triplets: Vec<(u64, u64, f64)> = (0..rows * cols / 4)
+            .map(|_| (rng.gen_range(0, rows), rng.gen_range(0, cols), rng.gen_range(-1.0, 1.0)))
+            .filter(|&(i, _, _)| i != 0 && i != 5 && i != rows - 1)
+            .collect();
+        let a = CsrMatrix::create_from_triplets(dst, rows, cols, &triplets).unwrap();
+        assert!(a.row(0).0.is_empty() && a.row(5).0.is_empty() && a.row(rows - 1).0.is_empty());
+        a
+    }
+
+    fn random_dense(rng: &mut StdRng, rows: u64, cols: u64) -> Dense<f64> {
+        let values: Vec<f64> = (0..rows * cols).map(|_| rng.gen_range(-1.0, 1.0)).collect();
+        Dense::anonymous_from_fn(rows, cols, |i, j| values[(i * cols + j) as usize])
+    }
+
+    fn assert_close(found: f64, expected: f64, what: &str) {
+        assert!((found - expected).abs() <= 1e-12 * expected.abs().max(1.0), "{}: {} against {}", what, found,
+                expected);
+    }
+
+    #[test]
+    fn spmm_matches_dense_gemm() {
+        let mut rng: StdRng = SeedableRng::from_seed(&[11usize][..]);
+        let (m, k, n) = (37, 29, 11);
+        let path = TempMatrixPath::new();
+        let a = random_csr(&mut rng, m, k, path.path());
+        let dense_path = TempMatrixPath::new();
+        let dense_a = a.to_dense(dense_path.path()).unwrap();
+        let b = random_dense(&mut rng, k, n);
+        let mut transposed_b = Dense::anonymous_from_fn(n, k, |i, j| b.get(j, i));
+        transposed_b.transpose();
+        for accumulate in [false, true].iter().cloned() {
+            for b in [&b, &transposed_b].iter() {
+                // C starts out non-zero, to be overwritten or added to, and so do empty rows.
+                let initial = random_dense(&mut rng, m, n);
+                let mut expected = Dense::anonymous_from_fn(m, n, |i, j| initial.get(i, j));
+                let beta = if accumulate { 1.0 } else { 0.0 };
+                gemm(1.0, &dense_a, b, beta, &mut expected, 8).unwrap();
+                let mut c = Dense::anonymous_from_fn(m, n, |i, j| initial.get(i, j));
+                spmm(&a, b, &mut c, accumulate).unwrap();
+                for i in 0..m {
+                    for j in 0..n {
+                        assert_close(c.get(i, j), expected.get(i, j), &format!("({}, {})", i, j));
+                    }
+                }
+                if !accumulate {
+                    assert!((0..n).all(|j| c.get(5, j) == 0.0));
+                } else {
+                    assert!((0..n).all(|j| c.get(5, j) == initial.get(5, j)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spmv_matches_dense_gemv() {
+        let mut rng: StdRng = SeedableRng::from_seed(&[12usize][..]);
+        let (m, k) = (41, 23);
+        let path = TempMatrixPath::new();
+        let a = random_csr(&mut rng, m, k, path.path());
+        let dense_path = TempMatrixPath::new();
+        let dense_a = a.to_dense(dense_path.path()).unwrap();
+        let x: Vec<f64> = (0..k).map(|_| rng.gen_range(-1.0, 1.0)).collect();
+        let mut expected = vec![0.0; m as usize];
+        gemv(1.0, &dense_a, &x, 0.0, &mut expected).unwrap();
+        let mut y = vec![f64::NAN; m as usize];
+        spmv(&a, &x, &mut y).unwrap();
+        for (i, (&found, &expected)) in y.iter().zip(expected.iter()).enumerate() {
+            assert_close(found, expected, &format!("y[{}]", i));
+        }
+        assert_eq!((y[0], y[5], y[m as usize - 1]), (0.0, 0.0, 0.0));
+    }
+
+    fn assert_shape_mismatch(result: Result<(), OoclaError>, expected: (u64, u64), found: (u64, u64)) {
+        match result {
+            Err(OoclaError::ShapeMismatch { expected: e, found: f }) => assert_eq!((e, f), (expected, found)),
+            other => panic!("expected a shape mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatched_shapes_are_rejected() {
+        let path = TempMatrixPath::new();
+        let a = CsrMatrix::create_from_triplets(path.path(), 3, 4, &[(0, 1, 1.0), (2, 3, 2.0)]).unwrap();
+        let mut c = Dense::anonymous_from_fn(3, 2, |_, _| 7.0);
+        let b = Dense::anonymous_from_fn(5, 2, |_, _| 1.0);
+        assert_shape_mismatch(spmm(&a, &b, &mut c, false), (4, 2), (5, 2));
+        let b = Dense::anonymous_from_fn(4, 2, |_, _| 1.0);
+        let mut wrong_c = Dense::anonymous_from_fn(3, 3, |_, _| 7.0);
+        assert_shape_mismatch(spmm(&a, &b, &mut wrong_c, true), (3, 2), (3, 3));
+        let mut wrong_c = Dense::anonymous_from_fn(2, 2, |_, _| 7.0);
+        assert_shape_mismatch(spmm(&a, &b, &mut wrong_c, false), (3, 2), (2, 2));
+        // Nothing was written before the shapes were checked.
+        assert!(c.element_iter().all(|&x| x == 7.0));
+        let mut y = vec![0.0; 3];
+        assert_shape_mismatch(spmv(&a, &[1.0; 3], &mut y), (3, 4), (3, 3));
+        assert_shape_mismatch(spmv(&a, &[1.0; 4], &mut y[..2]), (3, 4), (2, 4));
+    }
+}
@@ -0,0 +1,135 @@
+use dense_matrix::{Dense, StorageType, SupportedType};
+use io_stats;
+use std::mem;
+
+// Edge length of the square tiles blocked operations use when not told otherwise.
+pub const DEFAULT_TILE_SIZE: usize = 512;
+
+// An in-memory, row-major copy of the block of a matrix starting at (row, col).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tile<T> {
+    pub row: u64,
+    pub col: u64,
+    pub rows: usize,
+    pub cols: usize,
+    pub data: Vec<T>,
+}
+
+impl<T: SupportedType> Tile<T> {
+    // vec! allocates zeroed memory for a zero element, whose pages the kernel hands over already
+    // cleared, so a large tile starts without a store passing through the cache; non-temporal
+    // stores would only add writes.
+    pub fn zeros(row: u64, col: u64, rows: usize, cols: usize) -> Tile<T> {
+        Tile {
+            row,
+            col,
+            rows,
+            cols,
+            data: vec![T::from_f64(0.0); rows * cols],
+        }
+    }
+
+    pub fn convert<U: SupportedType>(&self) -> Tile<U> {
+        Tile {
+            row: self.row,
+            col: self.col,
+            rows: self.rows,
+            cols: self.cols,
+            data: self.data.iter().map(|x| U::from_f64(x.to_f64())).collect(),
+        }
+    }
+}
+
+impl<T: StorageType> Tile<T> {
+    pub fn get(&self, i: usize, j: usize) -> T {
+        assert!(i < self.rows && j < self.cols, "index ({}, {}) out of bounds for {}x{} tile", i, j, self.rows, self.cols);
+        self.data[i * self.cols + j]
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, value: T) {
+        assert!(i < self.rows && j < self.cols, "index ({}, {}) out of bounds for {}x{} tile", i, j, self.rows, self.cols);
+        self.data[i * self.cols + j] = value;
+    }
+
+    pub fn row_slice(&self, i: usize) -> &[T] {
+        &self.data[i * self.cols..(i + 1) * self.cols]
+    }
+
+    pub fn transposed(&self) -> Tile<T> {
+        let mut data = Vec::with_capacity(self.data.len());
+        for j in 0..self.cols {
+            data.extend((0..self.rows).map(|i| self.data[i * self.cols + j]));
+        }
+        Tile {
+            row: self.col,
+            col: self.row,
+            rows: self.cols,
+            cols: self.rows,
+            data,
+        }
+    }
+}
+
+impl<T: StorageType> Dense<T> {
+    fn check_block(&self, row: u64, col: u64, rows: usize, cols: usize) {
+        assert!(row.checked_add(rows as u64).is_some_and(|end| end <= self.num_rows())
+                && col.checked_add(cols as u64).is_some_and(|end| end <= self.num_cols()),
+                "{}x{} block at ({}, {}) out of bounds for {}x{} matrix",
+                rows, cols, row, col, self.num_rows(), self.num_cols());
+    }
+
+    pub fn read_tile(&self, row: u64, col: u64, rows: usize, cols: usize) -> Tile<T> {
+        self.check_block(row, col, rows, cols);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(row, col, rows, cols, bytes = rows * cols * mem::size_of::<T>(), "read tile");
+        let mut tile = Tile {
+            row,
+            col,
+            rows,
+            cols,
+            data: vec![T::default(); rows * cols],
+        };
+        if rows == 0 || cols == 0 {
+            return tile;
+        }
+        io_stats::count_read(rows * cols * mem::size_of::<T>());
+        if self.is_transposed() {
+            for j in 0..cols {
+                let line = &self.major_slice(col + j as u64)[row as usize..row as usize + rows];
+                for (i, &value) in line.iter().enumerate() {
+                    tile.data[i * cols + j] = value;
+                }
+            }
+        } else {
+            for i in 0..rows {
+                let line = &self.major_slice(row + i as u64)[col as usize..col as usize + cols];
+                tile.data[i * cols..(i + 1) * cols].copy_from_slice(line);
+            }
+        }
+        tile
+    }
+
+    pub fn write_tile(&mut self, tile: &Tile<T>) {
+        let (row, col, rows, cols) = (tile.row, tile.col, tile.rows, tile.cols);
+        self.check_block(row, col, rows, cols);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(row, col, rows, cols, bytes = rows * cols * mem::size_of::<T>(), "wrote tile");
+        if rows == 0 || cols == 0 {
+            return;
+        }
+        io_stats::count_written(rows * cols * mem::size_of::<T>());
+        if self.is_transposed() {
+            for j in 0..cols {
+                let line = &mut self.major_slice_mut(col + j as u64)[row as usize..row as usize + rows];
+                for (i, value) in line.iter_mut().enumerate() {
+                    *value = tile.data[i * cols + j];
+                }
+            }
+        } else {
+            for i in 0..rows {
+                let line = &mut self.major_slice_mut(row + i as u64)[col as usize..col as usize + cols];
+                line.copy_from_slice(&tile.data[i * cols..(i + 1) * cols]);
+            }
+        }
+    }
+}
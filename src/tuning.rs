@@ -0,0 +1,392 @@
+use budget::MemoryBudget;
+use dense_matrix::{Dense, SupportedType};
+use error::OoclaError;
+use nix::libc::{self, c_char};
+use ops;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{cmp, mem, process};
+use tile::DEFAULT_TILE_SIZE;
+
+// The blocked operations whose tile sizes can be tuned, and what TileConfig::tile_size means for
+// each: the edge of the square tiles of gemm_tuned, the rows per streamed block of syrk_tuned,
+// and the side below which transpose_inplace_tuned swaps elements directly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunableOp {
+    Gemm,
+    Syrk,
+    Transpose,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TileConfig {
+    pub tile_size: usize,
+}
+
+// The longest side of the synthetic matrices an operation is timed on, which keeps a trial
+// short while leaving several tiles of the larger candidates in each direction.
+const SYNTHETIC_MAX_EDGE: u64 = 1024;
+
+// The rows syrk is timed on, so that even the largest blocks are streamed a few times.
+const SYNTHETIC_SYRK_ROWS: u64 = 16384;
+
+const CACHE_FILE: &str = "tiles";
+
+impl TunableOp {
+    fn name(self) -> &'static str {
+        match self {
+            TunableOp::Gemm => "gemm",
+            TunableOp::Syrk => "syrk",
+            TunableOp::Transpose => "transpose",
+        }
+    }
+
+    // The sizes of `shape` an operation takes: m, n and k of gemm, the rows and columns of A for
+    // syrk, and the side of the matrix to transpose.
+    fn check_shape(self, shape: &[u64]) -> Result<(), OoclaError> {
+        let (expected, described) = match self {
+            TunableOp::Gemm => (3, "[m, n, k]"),
+            TunableOp::Syrk => (2, "[rows, cols]"),
+            TunableOp::Transpose => (1, "[n]"),
+        };
+        if shape.len() != expected {
+            return Err(OoclaError::InvalidArgument(format!("{} takes a shape of {}, not {:?}", self.name(), described,
+                                                           shape)));
+        }
+        Ok(())
+    }
+
+    // The sizes tried, the operation's default first so that it is measured whatever the time
+    // budget.
+    fn candidates(self) -> &'static [usize] {
+        match self {
+            TunableOp::Gemm => &[512, 64, 128, 256, 1024],
+            TunableOp::Syrk => &[1024, 128, 256, 512, 2048, 4096, 8192],
+            TunableOp::Transpose => &[32, 8, 16, 64, 128],
+        }
+    }
+
+    fn default_size(self, shape: &[u64]) -> usize {
+        match self {
+            TunableOp::Gemm => DEFAULT_TILE_SIZE,
+            TunableOp::Syrk => ops::default_block_rows(shape[1]),
+            TunableOp::Transpose => ops::DEFAULT_TRANSPOSE_BLOCK,
+        }
+    }
+
+    // Bytes of buffers the operation holds at once with tiles of `size`, or None if that
+    // overflows.
+    fn footprint(self, size: usize, shape: &[u64], element: usize) -> Option<usize> {
+        match self {
+            TunableOp::Gemm => ops::gemm_footprint(size, element).ok(),
+            TunableOp::Syrk => ops::syrk_footprint(size, shape[1] as usize),
+            TunableOp::Transpose => Some(0),
+        }
+    }
+
+    fn fits(self, size: usize, shape: &[u64], element: usize, budget: &MemoryBudget) -> bool {
+        self.footprint(size, shape, element).is_some_and(|bytes| budget.cap(bytes) == bytes)
+    }
+}
+
+// The directory the tuning cache is kept in: ooc under $XDG_CACHE_HOME, or under ~/.cache where
+// that isn't set.
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME").filter(|dir| !dir.is_empty())?).join(".cache"),
+    };
+    Some(base.join("ooc"))
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe {
+        libc::gethostname(buf.as_mut_ptr() as *mut c_char, buf.len())
+    };
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    if ret != 0 || len == 0 {
+        return "unknown".to_string();
+    }
+    String::from_utf8_lossy(&buf[..len]).replace(char::is_whitespace, "_")
+}
+
+// What a tuned configuration is stored under: the operation, element type, each size of the
+// shape rounded up to a power of two, and the host, each a single word.
+fn cache_key<T: SupportedType>(op: TunableOp, shape: &[u64]) -> String {
+    let buckets: Vec<String> = shape.iter().map(|&size| cmp::max(size, 1).next_power_of_two().to_string()).collect();
+    format!("{} {:?} {} {}", op.name(), T::get_float_type(), buckets.join("x"), hostname())
+}
+
+// The cached tile size for `key`, the last entry for it winning. Lines that can't be parsed
+// are ignored, so a damaged cache only costs tuning again.
+fn read_cache(key: &str) -> Option<usize> {
+    let text = fs::read_to_string(cache_dir()?.join(CACHE_FILE)).ok()?;
+    text.lines().rev().find_map(|line| {
+        let (line_key, size) = line.rsplit_once(' ')?;
+        if line_key == key { size.parse().ok().filter(|&size| size > 0) } else { None }
+    })
+}
+
+// Replaces the entry for `key`, writing a new file and renaming it over the old one so that
+// readers never see a partial cache.
+fn write_cache(key: &str, size: usize) -> Result<(), OoclaError> {
+    let dir = cache_dir().ok_or_else(|| OoclaError::InvalidArgument("no cache directory: neither XDG_CACHE_HOME nor \
+                                                                     HOME is set".to_string()))?;
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(CACHE_FILE);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let mut text: String = existing.lines()
+        .filter(|line| line.rsplit_once(' ').is_none_or(|(line_key, _)| line_key != key))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    text.push_str(&format!("{} {}\n", key, size));
+    let temp = dir.join(format!("{}.{}.tmp", CACHE_FILE, process::id()));
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp)?;
+    file.write_all(text.as_bytes())?;
+    fs::rename(&temp, &path)?;
+    Ok(())
+}
+
+// The tile configuration cached for `op` on a problem of about `shape`, provided it fits
+// within `budget`.
+pub fn cached<T: SupportedType>(op: TunableOp, shape: &[u64], budget: &MemoryBudget) -> Option<TileConfig> {
+    op.check_shape(shape).ok()?;
+    read_cache(&cache_key::<T>(op, shape))
+        .filter(|&size| op.fits(size, shape, mem::size_of::<T>(), budget))
+        .map(|tile_size| TileConfig { tile_size })
+}
+
+// The configuration a blocked operation uses: `config` if given, otherwise the cached one, and
+// otherwise the default, or if that doesn't fit within `budget`, the largest smaller candidate
+// that does.
+pub(crate) fn resolve<T: SupportedType>(op: TunableOp, shape: &[u64], config: Option<TileConfig>,
+                                        budget: &MemoryBudget) -> TileConfig {
+    if let Some(config) = config.or_else(|| cached::<T>(op, shape, budget)) {
+        return config;
+    }
+    let default = op.default_size(shape);
+    let element = mem::size_of::<T>();
+    let tile_size = Some(default).into_iter().chain(op.candidates().iter().cloned().filter(|&size| size < default))
+        .filter(|&size| op.fits(size, shape, element, budget))
+        .max()
+        .unwrap_or(default);
+    TileConfig { tile_size }
+}
+
+// Times one run of `op` with tiles of `size` on the synthetic operands.
+fn time_trial<T: SupportedType>(op: TunableOp, size: usize, operands: &mut [Dense<T>])
+    -> Result<Duration, OoclaError> {
+    let began = Instant::now();
+    match (op, operands) {
+        (TunableOp::Gemm, &mut [ref a, ref b, ref mut c]) => {
+            ops::gemm(T::from_f64(1.0), a, b, T::from_f64(0.0), c, size)?
+        }
+        (TunableOp::Syrk, &mut [ref a, ref mut g]) => ops::syrk(a, g, size)?,
+        (TunableOp::Transpose, &mut [ref mut a]) => ops::transpose_inplace_tuned(a, Some(TileConfig { tile_size: size }))?,
+        _ => unreachable!("operands are made to suit the operation"),
+    }
+    Ok(began.elapsed())
+}
+
+// Synthetic operands the shape of the caller's problem with each side capped. Their values
+// don't affect the timing, so they are all the same.
+fn synthetic_operands<T: SupportedType>(op: TunableOp, shape: &[u64]) -> Result<Vec<Dense<T>>, OoclaError> {
+    let cap = |size: u64| size.clamp(1, SYNTHETIC_MAX_EDGE);
+    let shapes = match op {
+        TunableOp::Gemm => {
+            let (m, n, k) = (cap(shape[0]), cap(shape[1]), cap(shape[2]));
+            vec![(m, k), (k, n), (m, n)]
+        }
+        TunableOp::Syrk => {
+            let (rows, cols) = (shape[0].clamp(1, SYNTHETIC_SYRK_ROWS), cap(shape[1]));
+            vec![(rows, cols), (cols, cols)]
+        }
+        TunableOp::Transpose => vec![(cap(shape[0]), cap(shape[0]))],
+    };
+    shapes.into_iter().map(|(rows, cols)| {
+        let mut operand = Dense::create_anonymous(rows, cols)?;
+        operand.fill(T::from_f64(0.5))?;
+        Ok(operand)
+    }).collect()
+}
+
+// Picks the tile size for `op` on a problem of about `shape` (as for TunableOp) by timing each
+// candidate on synthetic operands of the same shape, capped in size, until `time_budget` runs
+// out, the first candidate always being timed. Candidates whose buffers wouldn't fit within
+// `budget` are never tried. A configuration already cached for the operation, element type,
+// rough shape and host is returned without tuning, and a new one is cached unless the budget
+// ruled out some candidate, so that a tighter budget doesn't hold back later callers.
+pub fn autotune<T: SupportedType>(op: TunableOp, shape: &[u64], time_budget: Duration, budget: &MemoryBudget)
+    -> Result<TileConfig, OoclaError> {
+    op.check_shape(shape)?;
+    if let Some(config) = cached::<T>(op, shape, budget) {
+        return Ok(config);
+    }
+    let element = mem::size_of::<T>();
+    let (fitting, excluded): (Vec<usize>, Vec<usize>) = op.candidates().iter()
+        .partition(|&&size| op.fits(size, shape, element, budget));
+    if fitting.is_empty() {
+        let smallest = op.candidates().iter().cloned().min().expect("every operation has candidates");
+        return Err(OoclaError::BudgetExceeded {
+            requested_bytes: op.footprint(smallest, shape, element).unwrap_or(usize::MAX) as u64,
+            in_use_bytes: 0,
+            limit_bytes: budget.limit().unwrap_or(usize::MAX) as u64,
+        });
+    }
+    let mut operands = synthetic_operands::<T>(op, shape)?;
+    let began = Instant::now();
+    let mut best: Option<(Duration, usize)> = None;
+    for &size in fitting.iter() {
+        if best.is_some() && began.elapsed() >= time_budget {
+            break;
+        }
+        let elapsed = time_trial(op, size, &mut operands)?;
+        if best.is_none_or(|(fastest, _)| elapsed < fastest) {
+            best = Some((elapsed, size));
+        }
+    }
+    let tile_size = best.expect("at least one candidate is timed").1;
+    if excluded.is_empty() {
+        // Tuning still gave an answer, so failing to keep it isn't an error.
+        let _ = write_cache(&cache_key::<T>(op, shape), tile_size);
+    }
+    Ok(TileConfig { tile_size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use budget::BudgetPolicy;
+    use std::ffi::OsString;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    // The environment is shared by every test, so those that point the cache elsewhere take turns.
+    static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+    // XDG_CACHE_HOME pointed at an empty directory of its own until dropped.
+    struct CacheHome {
+        dir: PathBuf,
+        previous: Option<OsString>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl CacheHome {
+        fn new() -> CacheHome {
+            let lock = ENVIRONMENT.lock().unwrap_or_else(PoisonError::into_inner);
+            let dir = env::temp_dir().join(format!("ooc-tuning-test-{}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let previous = env::var_os("XDG_CACHE_HOME");
+            env::set_var("XDG_CACHE_HOME", &dir);
+            CacheHome { dir, previous, _lock: lock }
+        }
+    }
+
+    impl Drop for CacheHome {
+        fn drop(&mut self) {
+            match self.previous.take() {
+                Some(previous) => env::set_var("XDG_CACHE_HOME", previous),
+                None => env::remove_var("XDG_CACHE_HOME"),
+            }
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn the_cache_is_kept_under_xdg_cache_home() {
+        let home = CacheHome::new();
+        assert_eq!(cache_dir(), Some(home.dir.join("ooc")));
+        env::set_var("XDG_CACHE_HOME", "");
+        let user_home = env::var_os("HOME").filter(|dir| !dir.is_empty());
+        assert_eq!(cache_dir(), user_home.map(|dir| PathBuf::from(dir).join(".cache").join("ooc")));
+    }
+
+    #[test]
+    fn cached_sizes_round_trip() {
+        let _home = CacheHome::new();
+        let gemm = cache_key::<f64>(TunableOp::Gemm, &[1000, 1000, 1000]);
+        let syrk = cache_key::<f32>(TunableOp::Syrk, &[5000, 64]);
+        assert_eq!(read_cache(&gemm), None);
+        write_cache(&gemm, 256).unwrap();
+        write_cache(&syrk, 2048).unwrap();
+        write_cache(&gemm, 128).unwrap();
+        assert_eq!((read_cache(&gemm), read_cache(&syrk)), (Some(128), Some(2048)));
+        // Shapes are rounded up to powers of two, and element types kept apart.
+        let unlimited = MemoryBudget::unlimited();
+        assert_eq!(cached::<f64>(TunableOp::Gemm, &[600, 1024, 513], &unlimited), Some(TileConfig { tile_size: 128 }));
+        assert_eq!(cached::<f64>(TunableOp::Gemm, &[1025, 1000, 1000], &unlimited), None);
+        assert_eq!(cached::<f32>(TunableOp::Gemm, &[1000, 1000, 1000], &unlimited), None);
+        assert_eq!(cached::<f64>(TunableOp::Gemm, &[1000, 1000], &unlimited), None);
+        let text = fs::read_to_string(cache_dir().unwrap().join(CACHE_FILE)).unwrap();
+        assert_eq!(text.lines().count(), 2, "{}", text);
+    }
+
+    #[test]
+    fn damaged_cache_lines_are_ignored() {
+        let _home = CacheHome::new();
+        let key = cache_key::<f64>(TunableOp::Transpose, &[100]);
+        fs::create_dir_all(cache_dir().unwrap()).unwrap();
+        let text = format!("garbage\n{} 16\n{} nope\n{} 0\n\n", key, key, key);
+        fs::write(cache_dir().unwrap().join(CACHE_FILE), text).unwrap();
+        assert_eq!(read_cache(&key), Some(16));
+        write_cache(&key, 64).unwrap();
+        assert_eq!(read_cache(&key), Some(64));
+    }
+
+    #[test]
+    fn tuned_sizes_are_cached_and_reused() {
+        let _home = CacheHome::new();
+        let budget = MemoryBudget::unlimited();
+        let shape = [48];
+        let tuned = autotune::<f32>(TunableOp::Transpose, &shape, Duration::from_secs(0), &budget).unwrap();
+        // With no time to spare only the first candidate, the default, is timed.
+        assert_eq!(tuned.tile_size, TunableOp::Transpose.candidates()[0]);
+        assert_eq!(cached::<f32>(TunableOp::Transpose, &shape, &budget), Some(tuned));
+        // A cached size is returned without tuning, whatever it is.
+        write_cache(&cache_key::<f32>(TunableOp::Transpose, &shape), 8).unwrap();
+        let again = autotune::<f32>(TunableOp::Transpose, &shape, Duration::from_secs(60), &budget).unwrap();
+        assert_eq!(again, TileConfig { tile_size: 8 });
+        assert_eq!(resolve::<f32>(TunableOp::Transpose, &shape, None, &budget), again);
+        let explicit = TileConfig { tile_size: 4 };
+        assert_eq!(resolve::<f32>(TunableOp::Transpose, &shape, Some(explicit), &budget), explicit);
+    }
+
+    #[test]
+    fn tuning_never_picks_a_size_over_the_budget() {
+        let _home = CacheHome::new();
+        let (shape, element) = ([256, 256, 256], mem::size_of::<f64>());
+        let limit = ops::gemm_footprint(128, element).unwrap();
+        let budget = MemoryBudget::new(limit, BudgetPolicy::Fail);
+        let tuned = autotune::<f64>(TunableOp::Gemm, &shape, Duration::from_secs(60), &budget).unwrap();
+        assert!(tuned.tile_size == 64 || tuned.tile_size == 128, "{:?}", tuned);
+        assert!(ops::gemm_footprint(tuned.tile_size, element).unwrap() <= limit);
+        // A tuning the budget constrained isn't cached for callers without it.
+        assert_eq!(cached::<f64>(TunableOp::Gemm, &shape, &MemoryBudget::unlimited()), None);
+        // Nor is a cached size that doesn't fit used, and without one the largest that fits is.
+        write_cache(&cache_key::<f64>(TunableOp::Gemm, &shape), 512).unwrap();
+        assert_eq!(cached::<f64>(TunableOp::Gemm, &shape, &budget), None);
+        assert_eq!(resolve::<f64>(TunableOp::Gemm, &shape, None, &budget), TileConfig { tile_size: 128 });
+        let tuned = autotune::<f64>(TunableOp::Gemm, &shape, Duration::from_secs(0), &budget).unwrap();
+        assert!(tuned.tile_size <= 128, "{:?}", tuned);
+    }
+
+    #[test]
+    fn budgets_too_small_for_any_candidate_are_rejected() {
+        let _home = CacheHome::new();
+        let budget = MemoryBudget::new(ops::gemm_footprint(64, 8).unwrap() - 1, BudgetPolicy::Block);
+        match autotune::<f64>(TunableOp::Gemm, &[100, 100, 100], Duration::from_secs(1), &budget) {
+            Err(OoclaError::BudgetExceeded { requested_bytes, .. }) => {
+                assert_eq!(requested_bytes, ops::gemm_footprint(64, 8).unwrap() as u64);
+            }
+            other => panic!("expected BudgetExceeded, got {:?}", other),
+        }
+        match autotune::<f64>(TunableOp::Syrk, &[100], Duration::from_secs(1), &MemoryBudget::unlimited()) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected an invalid argument, got {:?}", other),
+        }
+    }
+}
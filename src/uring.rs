@@ -0,0 +1,244 @@
+use dense_matrix::{Dense, StorageType, page_size};
+use direct::{self, AlignedBuf};
+use error::OoclaError;
+use io_uring::{IoUring, opcode, types};
+use nix::libc;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::{io, mem};
+
+// Reads a pass over a matrix keeps in flight at once.
+pub const DEFAULT_QUEUE_DEPTH: u32 = 32;
+
+// The reads submitted to a ring and the buffers they fill, the read of range i going to slot i
+// modulo the queue depth. Dropping it waits for every read still in flight, so no buffer is
+// freed while the kernel may be writing to it, whether a pass ends early on an error or a
+// panicking callback.
+struct InFlight<'a> {
+    ring: &'a mut IoUring,
+    bufs: Vec<Option<AlignedBuf>>,
+    results: Vec<Option<i32>>,
+    count: usize,
+}
+
+impl<'a> InFlight<'a> {
+    fn reap(&mut self, want: usize) -> io::Result<()> {
+        loop {
+            match self.ring.submit_and_wait(want) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+                Ok(_) => break,
+            }
+        }
+        let depth = self.results.len();
+        for cqe in self.ring.completion() {
+            self.results[cqe.user_data() as usize % depth] = Some(cqe.result());
+            self.count -= 1;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        while self.count > 0 {
+            let count = self.count;
+            if self.reap(count).is_err() {
+                // The buffers can't be known to be finished with, so they are leaked rather than freed.
+                for buf in self.bufs.iter_mut() {
+                    mem::forget(buf.take());
+                }
+                return;
+            }
+        }
+    }
+}
+
+// Reads ranges of a file with io_uring, keeping several reads in flight and handing each range
+// to a callback in the order given as they complete.
+pub struct UringStreamer {
+    ring: IoUring,
+    queue_depth: usize,
+}
+
+impl UringStreamer {
+    // A ring that keeps up to `queue_depth` reads in flight. Kernels older than 5.6, or with
+    // io_uring disabled by sysctl or a seccomp filter, report Unsupported.
+    pub fn new(queue_depth: u32) -> Result<UringStreamer, OoclaError> {
+        if queue_depth == 0 {
+            return Err(OoclaError::InvalidArgument("queue depth must be positive".to_string()));
+        }
+        let ring = IoUring::new(queue_depth.next_power_of_two()).map_err(|e| match e.raw_os_error() {
+            Some(libc::ENOSYS) | Some(libc::EPERM) => OoclaError::Unsupported(format!("io_uring: {}", e)),
+            _ => OoclaError::Io(e),
+        })?;
+        Ok(UringStreamer { ring, queue_depth: queue_depth as usize })
+    }
+
+    // Reads each of `ranges` of `file` and passes its bytes to `f`, in the order given. Each read
+    // covers its range rounded out to whole pages so that it can bypass the page cache, and any
+    // of a range that lies beyond a short read is filled with an ordinary positioned read.
+    pub fn read_blocks<F>(&mut self, file: &File, ranges: &[Range<u64>], mut f: F) -> Result<(), OoclaError>
+        where F: FnMut(&[u8]) -> Result<(), OoclaError> {
+        let direct = direct::reopen(file)?;
+        let fd = direct.as_raw_fd();
+        let page = page_size() as u64;
+        let depth = self.queue_depth;
+        let mut flights = InFlight {
+            ring: &mut self.ring,
+            bufs: (0..depth).map(|_| None).collect(),
+            results: vec![None; depth],
+            count: 0,
+        };
+        let (mut submitted, mut delivered) = (0, 0);
+        while delivered < ranges.len() {
+            while submitted < ranges.len() && submitted < delivered + depth {
+                let range = &ranges[submitted];
+                assert!(range.start <= range.end, "invalid range {:?}", range);
+                let aligned_start = range.start / page * page;
+                let len = u32::try_from(range.end.div_ceil(page) * page - aligned_start)
+                    .map_err(|_| OoclaError::InvalidArgument(format!("range {:?} is too long to read at once", range)))?;
+                let slot = submitted % depth;
+                if flights.bufs[slot].as_ref().is_none_or(|buf| buf.len() < len as usize) {
+                    flights.bufs[slot] = Some(AlignedBuf::new(len as usize));
+                }
+                let buf = flights.bufs[slot].as_mut().unwrap().as_mut_ptr();
+                let entry = opcode::Read::new(types::Fd(fd), buf, len)
+                    .offset(aligned_start)
+                    .build()
+                    .user_data(submitted as u64);
+                unsafe {
+                    flights.ring.submission().push(&entry)
+                }.expect("the submission queue has room for every read in flight");
+                flights.count += 1;
+                submitted += 1;
+            }
+            let slot = delivered % depth;
+            while flights.results[slot].is_none() {
+                flights.reap(1)?;
+            }
+            let result = flights.results[slot].take().unwrap();
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result).into());
+            }
+            let range = &ranges[delivered];
+            let buf = flights.bufs[slot].as_mut().unwrap();
+            f(direct::complete(file, range, range.start / page * page, result as usize, buf)?)?;
+            delivered += 1;
+        }
+        Ok(())
+    }
+}
+
+// Passes the major lines `lines` of A to `f` in order, reading them through a ring in blocks
+// of whole lines.
+pub(crate) fn stream_lines<T, F>(a: &Dense<T>, lines: Range<u64>, mut f: F) -> Result<(), OoclaError>
+    where T: StorageType, F: FnMut(u64, &[T]) -> Result<(), OoclaError> {
+    if a.lda() == 0 {
+        return lines.into_iter().try_for_each(|line| f(line, &[]));
+    }
+    // Reads from the file don't see writes still only in the mapping.
+    a.flush()?;
+    let ranges = direct::line_ranges(a, lines.clone());
+    let mut line = lines.start;
+    UringStreamer::new(DEFAULT_QUEUE_DEPTH)?.read_blocks(a.file(), &ranges, |bytes| {
+        direct::deliver_lines(a, bytes, &mut line, &mut f)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dense_matrix::{Engine, StreamOptions, TempMatrixPath};
+    use io::npy;
+    use ops::hash::xxh64;
+    use std::fs;
+    use std::os::unix::fs::FileExt;
+
+    // A ring, or None where the kernel or a sandbox refuses io_uring.
+    fn streamer() -> Option<UringStreamer> {
+        match UringStreamer::new(4) {
+            Ok(streamer) => Some(streamer),
+            Err(OoclaError::Unsupported(reason)) => {
+                eprintln!("skipping: {}", reason);
+                None
+            }
+            Err(e) => panic!("creating a ring failed: {}", e),
+        }
+    }
+
+    // The .npy export of A, read with `engine`.
+    fn exported<T: StorageType>(a: &mut Dense<T>, engine: Engine) -> Vec<u8> {
+        a.set_stream_options(StreamOptions { engine, ..StreamOptions::default() });
+        let path = TempMatrixPath::new();
+        npy::export(a, path.path()).unwrap();
+        fs::read(path.path()).unwrap()
+    }
+
+    #[test]
+    fn exports_through_each_engine_are_identical() {
+        if streamer().is_none() {
+            return;
+        }
+        // Lines of 1021 elements straddle page boundaries, so reads start and end part way into pages.
+        let path = TempMatrixPath::new();
+        let mut a = Dense::<f32>::create(path.path(), 700, 1021).unwrap();
+        a.randomise_seeded(7);
+        for &transposed in [false, true].iter() {
+            if transposed {
+                a.transpose();
+            }
+            let (mmap, uring) = (exported(&mut a, Engine::Mmap), exported(&mut a, Engine::Uring));
+            assert_eq!(xxh64(&uring, 0), xxh64(&mmap, 0), "checksums differ, transposed {}", transposed);
+            assert!(uring == mmap, "exports differ, transposed {}", transposed);
+        }
+    }
+
+    #[test]
+    fn copies_through_each_engine_are_identical() {
+        if streamer().is_none() {
+            return;
+        }
+        let path = TempMatrixPath::new();
+        let mut a = Dense::<f64>::from_fn(path.path(), 333, 517, |i, j| (i * 517 + j) as f64).unwrap();
+        a.set_stream_options(StreamOptions { engine: Engine::Uring, ..StreamOptions::default() });
+        let copy_path = TempMatrixPath::new();
+        a.copy_at(Some(copy_path.path())).unwrap().flush().unwrap();
+        assert_eq!(fs::read(copy_path.path()).unwrap(), fs::read(path.path()).unwrap());
+    }
+
+    #[test]
+    fn read_blocks_matches_positioned_reads() {
+        let mut streamer = match streamer() {
+            Some(streamer) => streamer,
+            None => return,
+        };
+        let path = TempMatrixPath::new();
+        let bytes: Vec<u8> = (0..40_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        fs::write(path.path(), &bytes).unwrap();
+        let file = File::open(path.path()).unwrap();
+        // More ranges than the queue depth, unaligned, empty, overlapping and running to the end.
+        let ranges = vec![0..1, 5..4096, 4095..4097, 100..100, 1..12_289, 39_999..40_000, 8192..40_000, 3..9000];
+        let mut got = Vec::new();
+        streamer.read_blocks(&file, &ranges, |buf| {
+            got.push(buf.to_vec());
+            Ok(())
+        }).unwrap();
+        assert_eq!(got.len(), ranges.len());
+        for (range, buf) in ranges.iter().zip(got.iter()) {
+            let mut expected = vec![0; (range.end - range.start) as usize];
+            file.read_exact_at(&mut expected, range.start).unwrap();
+            assert!(*buf == expected, "range {:?} differs", range);
+        }
+    }
+
+    #[test]
+    fn zero_queue_depth_is_rejected() {
+        match UringStreamer::new(0) {
+            Err(OoclaError::InvalidArgument(_)) => {}
+            other => panic!("expected InvalidArgument, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,7 @@
+extern crate trybuild;
+
+// Misuses of the element iterators that must be rejected by the borrow checker.
+#[test]
+fn element_iterator_misuse_does_not_compile() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
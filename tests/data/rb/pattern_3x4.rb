@@ -0,0 +1,6 @@
+Pattern-only 3x4 test matrix                                            OOC/pat
+             2             1             1             0
+PUA                        3             4             4             0
+(16I5)          (16I5)
+    1    2    4    4    5
+    2    1    3    3
@@ -0,0 +1,8 @@
+Symmetric 3x3 test matrix with Fortran D exponents                      OOC/sym
+             4             1             1             2
+RSA                        3             3             4             0
+(10I8)          (10I8)          (1P,2D25.16)
+       1       3       4       5
+       1       2       3       3
+   1.5000000000000000D+02  -2.5000000000000000D-01
+   4.0000000000000000D+00   1.0000000000000000D+00
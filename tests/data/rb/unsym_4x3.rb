@@ -0,0 +1,8 @@
+Unsymmetric 4x3 test matrix with an empty column                        OOC/uns
+             4             1             1             2
+RUA                        4             3             5             0
+(16I5)          (16I5)          (4E20.12)
+    1    3    3    6
+    1    3    1    2    4
+  1.500000000000E+00 -2.000000000000E+00  3.250000000000E+00  4.000000000000E-03
+ -7.000000000000E+00
@@ -0,0 +1,45 @@
+//! Builds examples/ffi/roundtrip.c against the C interface of the shared library and runs it.
+
+use std::env;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::Command;
+
+// The directory the shared library is built into: integration tests run from target/<profile>/deps.
+fn library_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().and_then(|deps| deps.parent()).unwrap().to_path_buf()
+}
+
+#[test]
+fn c_round_trip() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let lib = library_dir();
+    assert!(lib.join("libooc.so").exists() || lib.join("libooc.dylib").exists(),
+            "no shared library in {}", lib.display());
+    let scratch = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let exe = scratch.join("ffi-roundtrip");
+    let matrix = scratch.join(format!("ffi-roundtrip-{}.mat", std::process::id()));
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let compiled = Command::new(&compiler)
+        .arg("-Wall").arg("-Werror")
+        .arg("-I").arg(root.join("include"))
+        .arg(root.join("examples/ffi/roundtrip.c"))
+        .arg("-L").arg(&lib).arg("-looc")
+        .arg(format!("-Wl,-rpath,{}", lib.display()))
+        .arg("-o").arg(&exe)
+        .status();
+    match compiled {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            eprintln!("skipping: no C compiler '{}'", compiler);
+            return;
+        }
+        compiled => assert!(compiled.unwrap().success(), "roundtrip.c failed to compile"),
+    }
+    let output = Command::new(&exe).arg(&matrix).output().unwrap();
+    let _ = std::fs::remove_file(&matrix);
+    assert!(output.status.success(), "roundtrip exited with {}: {}", output.status,
+            String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("ok: "), "unexpected output {:?}", stdout);
+}
@@ -0,0 +1,174 @@
+//! Checks that the tracing feature's spans and events fire, with the fields they document, using
+//! a subscriber that records everything it is given.
+#![cfg(feature = "tracing")]
+
+extern crate ooc;
+extern crate tracing;
+
+use ooc::dense_matrix::Dense;
+use ooc::ops;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+// A span or event as recorded: its name, or for an event its message, its level and fields,
+// and for an event the span it was emitted in.
+#[derive(Clone, Debug)]
+struct Recorded {
+    name: String,
+    level: Level,
+    fields: HashMap<String, String>,
+    parent: Option<u64>,
+}
+
+impl Recorded {
+    fn field(&self, name: &str) -> &str {
+        self.fields.get(name).map_or("", |value| value.as_str())
+    }
+}
+
+struct Fields<'a>(&'a mut HashMap<String, String>);
+
+impl<'a> Visit for Fields<'a> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+}
+
+#[derive(Default)]
+struct Log {
+    spans: Vec<Recorded>,
+    events: Vec<Recorded>,
+}
+
+// Records every span and event, numbering spans from 1 in the order they are created. The
+// tests run each operation on the thread that installed the subscriber, so one stack of
+// entered spans is enough.
+#[derive(Clone, Default)]
+struct Recorder {
+    log: Arc<Mutex<Log>>,
+    entered: Arc<Mutex<Vec<u64>>>,
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let mut fields = HashMap::new();
+        attributes.record(&mut Fields(&mut fields));
+        let mut log = self.log.lock().unwrap();
+        let metadata = attributes.metadata();
+        log.spans.push(Recorded { name: metadata.name().to_string(), level: *metadata.level(), fields, parent: None });
+        Id::from_u64(log.spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut log = self.log.lock().unwrap();
+        values.record(&mut Fields(&mut log.spans[span.into_u64() as usize - 1].fields));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = HashMap::new();
+        event.record(&mut Fields(&mut fields));
+        let name = fields.remove("message").unwrap_or_default();
+        let parent = self.entered.lock().unwrap().last().cloned();
+        let level = *event.metadata().level();
+        self.log.lock().unwrap().events.push(Recorded { name, level, fields, parent });
+    }
+
+    fn enter(&self, span: &Id) {
+        self.entered.lock().unwrap().push(span.into_u64());
+    }
+
+    fn exit(&self, span: &Id) {
+        let mut entered = self.entered.lock().unwrap();
+        let position = entered.iter().rposition(|&id| id == span.into_u64()).expect("a span is entered before it exits");
+        entered.remove(position);
+    }
+}
+
+// Runs `f` with a recorder as the thread's subscriber, returning what it recorded.
+fn recorded<F: FnOnce()>(f: F) -> Log {
+    let recorder = Recorder::default();
+    tracing::subscriber::with_default(recorder.clone(), f);
+    let mut log = recorder.log.lock().unwrap();
+    Log { spans: log.spans.drain(..).collect(), events: log.events.drain(..).collect() }
+}
+
+fn scratch_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("instrumentation-{}-{}.mat", name, std::process::id()))
+}
+
+fn events<'a>(log: &'a Log, name: &str) -> Vec<&'a Recorded> {
+    log.events.iter().filter(|event| event.name == name).collect()
+}
+
+#[test]
+fn copies_are_traced() {
+    let (source, destination) = (scratch_path("source"), scratch_path("copy"));
+    let a = Dense::<f64>::from_fn(&source, 300, 200, |i, j| (i * 200 + j) as f64).unwrap();
+    let log = recorded(|| {
+        let copy = a.copy_at(Some(&destination)).unwrap();
+        copy.flush().unwrap();
+    });
+    drop(a);
+    let _ = fs::remove_file(&source);
+    let _ = fs::remove_file(&destination);
+
+    let spans: Vec<_> = log.spans.iter().enumerate().filter(|&(_, span)| span.name == "copy_at").collect();
+    assert_eq!(spans.len(), 1, "{:?}", log.spans);
+    let (index, span) = spans[0];
+    let id = index as u64 + 1;
+    assert_eq!(span.level, Level::DEBUG);
+    assert_eq!((span.field("rows"), span.field("cols")), ("300", "200"));
+    assert_eq!(span.field("bytes"), (300 * 200 * 8).to_string());
+    assert!(span.field("path").contains(&*destination.to_string_lossy()), "{:?}", span);
+
+    // The copy is created within the span, and closed after it.
+    let created = events(&log, "created matrix");
+    assert_eq!(created.len(), 1, "{:?}", log.events);
+    assert_eq!(created[0].parent, Some(id));
+    assert_eq!((created[0].field("rows"), created[0].field("cols")), ("300", "200"));
+    assert_eq!(created[0].field("path"), destination.display().to_string());
+    let msyncs = events(&log, "msync");
+    assert!(!msyncs.is_empty() && msyncs.iter().all(|event| event.level == Level::TRACE), "{:?}", log.events);
+    let closed = events(&log, "closed matrix");
+    assert_eq!(closed.len(), 1, "{:?}", log.events);
+    assert_eq!((closed[0].parent, closed[0].field("rows")), (None, "300"));
+}
+
+#[test]
+fn blocked_operations_trace_their_tiles() {
+    let paths = [scratch_path("a"), scratch_path("b"), scratch_path("c")];
+    let a = Dense::<f64>::from_fn(&paths[0], 20, 12, |i, j| (i + j) as f64).unwrap();
+    let b = Dense::<f64>::from_fn(&paths[1], 12, 9, |i, j| (i * j) as f64).unwrap();
+    let mut c = Dense::<f64>::create(&paths[2], 20, 9).unwrap();
+    let log = recorded(|| ops::gemm(1.0, &a, &b, 0.0, &mut c, 8).unwrap());
+    for path in paths.iter() {
+        let _ = fs::remove_file(path);
+    }
+    let spans: Vec<_> = log.spans.iter().filter(|span| span.name == "gemm").collect();
+    assert_eq!(spans.len(), 1, "{:?}", log.spans);
+    let span = spans[0];
+    assert_eq!((span.field("m"), span.field("n"), span.field("k"), span.field("tile_size")), ("20", "9", "12", "8"));
+    // Three rows of tiles of C by two columns of them, each reading two tiles of A and B and
+    // one of C, and writing that one back.
+    let (read, wrote) = (events(&log, "read tile"), events(&log, "wrote tile"));
+    assert_eq!((read.len(), wrote.len()), (3 * 2 * 5, 3 * 2));
+    assert!(read.iter().chain(wrote.iter()).all(|event| event.parent == Some(1) && event.level == Level::TRACE));
+    let bytes: u64 = wrote.iter().map(|event| event.field("bytes").parse::<u64>().unwrap()).sum();
+    assert_eq!(bytes, 20 * 9 * 8);
+}
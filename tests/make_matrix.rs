@@ -0,0 +1,237 @@
+//! Runs the make-matrix binary and reads back what it writes through the library.
+
+extern crate ooc;
+
+use ooc::dense_matrix::{Dense, Distribution};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// A directory of its own for each test, removed again when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("make-matrix-{}-{}", name,
+                                                                           std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn make_matrix(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_make-matrix")).args(args).output().unwrap()
+}
+
+fn make_matrix_at(output: &Path, args: &[&str]) -> Output {
+    let mut all = args.to_vec();
+    all.extend(&["--", output.to_str().unwrap()]);
+    make_matrix(&all)
+}
+
+// Makes a matrix with `args`, failing the test if make-matrix does, and opens it.
+fn made<T: ooc::dense_matrix::SupportedType>(path: &Path, args: &[&str]) -> Dense<T> {
+    let output = make_matrix_at(path, args);
+    assert!(output.status.success(), "{:?}: {}", args, stderr(&output));
+    Dense::open(path).unwrap()
+}
+
+fn elements<T: Copy>(a: &Dense<T>) -> Vec<T> {
+    a.element_iter().cloned().collect()
+}
+
+// The mean and population standard deviation of `values`.
+fn moments(values: &[f64]) -> (f64, f64) {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / values.len() as f64;
+    (mean, variance.sqrt())
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn makes_a_random_matrix_of_the_requested_shape() {
+    let scratch = Scratch::new("shape");
+    let path = scratch.path("a.mat");
+    let output = make_matrix_at(&path, &["--rows", "30", "--cols=20", "--dtype", "f64"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let bytes = fs::metadata(&path).unwrap().len();
+    assert_eq!(stdout(&output), format!("{}: 30x20 f64 matrix of random, {} bytes\n", path.display(), bytes));
+    let a = Dense::<f64>::open(&path).unwrap();
+    assert_eq!((a.num_rows(), a.num_cols()), (30, 20));
+    assert!(a.element_iter().all(|&x| (0.0..1.0).contains(&x)));
+    // The default element type is f32, and a matrix of it can't be opened as f64.
+    let path = scratch.path("b.mat");
+    let output = make_matrix_at(&path, &["--rows", "3", "--cols", "4", "--quiet"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    let b = Dense::<f32>::open(&path).unwrap();
+    assert_eq!((b.num_rows(), b.num_cols()), (3, 4));
+    assert!(Dense::<f64>::open(&path).is_err());
+}
+
+#[test]
+fn refuses_to_replace_a_file_without_force() {
+    let scratch = Scratch::new("force");
+    let path = scratch.path("a.mat");
+    fs::write(&path, b"precious").unwrap();
+    let output = make_matrix_at(&path, &["--rows", "2", "--cols", "2"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("already exists; pass --force to replace it"), "{}", stderr(&output));
+    assert_eq!(fs::read(&path).unwrap(), b"precious");
+    let output = make_matrix_at(&path, &["--rows", "2", "--cols", "2", "--force", "--quiet"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(Dense::<f32>::open(&path).unwrap().num_rows(), 2);
+}
+
+#[test]
+fn rejects_bad_arguments_with_usage() {
+    let scratch = Scratch::new("arguments");
+    let path = scratch.path("a.mat");
+    let output_path = path.to_str().unwrap();
+    let cases: &[(&[&str], &str)] = &[
+        (&[], "no output path given"),
+        (&[output_path, "other.mat"], "unexpected argument 'other.mat'"),
+        (&["--rows", "-3", output_path], "--rows takes a non-negative integer, not '-3'"),
+        (&["--cols", output_path], "--cols takes a non-negative integer"),
+        (&[output_path, "--rows"], "--rows needs a value"),
+        (&["--dtype", "f16", output_path], "--dtype takes f32 or f64, not 'f16'"),
+        (&["--force=yes", output_path], "--force takes no value"),
+        (&["--colour", output_path], "unknown option '--colour'"),
+        (&["--"], "-- must be followed by the output path"),
+    ];
+    for &(args, message) in cases {
+        let output = make_matrix(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        let stderr = stderr(&output);
+        assert!(stderr.starts_with(&format!("make-matrix: {}", message)), "{:?}: {}", args, stderr);
+        assert!(stderr.contains("usage: make-matrix"), "{:?}: {}", args, stderr);
+        assert!(!path.exists(), "{:?}", args);
+    }
+    let output = make_matrix(&["--help"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).starts_with("usage: make-matrix"));
+}
+
+#[test]
+fn fills_each_mode() {
+    let scratch = Scratch::new("fills");
+    let path = scratch.path("a.mat");
+    let shape = ["--rows", "3", "--cols", "5", "--force", "--quiet"];
+    let with = |args: &[&'static str]| shape.iter().chain(args).cloned().collect::<Vec<_>>();
+    let zeros = made::<f32>(&path, &with(&["--fill", "zeros"]));
+    assert!(elements(&zeros).iter().all(|&x| x == 0.0));
+    let ones = made::<f64>(&path, &with(&["--fill=ones", "--dtype", "f64"]));
+    assert!(elements(&ones).iter().all(|&x| x == 1.0));
+    let constant = made::<f32>(&path, &with(&["--fill", "constant=-2.5"]));
+    assert!(elements(&constant).iter().all(|&x| x == -2.5));
+    // Ones on the leading diagonal of a matrix that isn't square.
+    let identity = made::<f64>(&path, &with(&["--fill", "identity", "--dtype", "f64"]));
+    for i in 0..3 {
+        for j in 0..5 {
+            assert_eq!(identity.get(i, j), if i == j { 1.0 } else { 0.0 }, "({}, {})", i, j);
+        }
+    }
+    let index = made::<f32>(&path, &with(&["--fill", "index"]));
+    for i in 0..3 {
+        for j in 0..5 {
+            assert_eq!(index.get(i, j), (i * 5 + j) as f32, "({}, {})", i, j);
+        }
+    }
+    // The summary names the mode.
+    let output = make_matrix_at(&path, &["--rows", "2", "--cols", "2", "--force", "--fill", "constant=7"]);
+    let bytes = fs::metadata(&path).unwrap().len();
+    assert_eq!(stdout(&output), format!("{}: 2x2 f32 matrix of constant, {} bytes\n", path.display(), bytes));
+}
+
+#[test]
+fn random_fills_follow_their_distribution() {
+    let scratch = Scratch::new("distributions");
+    let path = scratch.path("a.mat");
+    let shape = ["--rows", "200", "--cols", "50", "--dtype", "f64", "--force", "--quiet"];
+    let with = |args: &[&'static str]| shape.iter().chain(args).cloned().collect::<Vec<_>>();
+    let uniform = elements(&made::<f64>(&path, &with(&["--range", "-2,3"])));
+    assert!(uniform.iter().all(|&x| (-2.0..3.0).contains(&x)));
+    let (mean, std_dev) = moments(&uniform);
+    assert!((mean - 0.5).abs() < 0.1 && (std_dev - 5.0 / 12f64.sqrt()).abs() < 0.1, "{} {}", mean, std_dev);
+    // A range alone implies the uniform distribution, and naming it changes nothing.
+    let seeded = with(&["--seed", "5", "--range", "-2,3"]);
+    let named = with(&["--seed", "5", "--distribution", "uniform", "--range", "-2,3"]);
+    assert_eq!(elements(&made::<f64>(&path, &seeded)), elements(&made::<f64>(&path, &named)));
+    let normal = elements(&made::<f64>(&path, &with(&["--mean", "10", "--std", "0.5"])));
+    let (mean, std_dev) = moments(&normal);
+    assert!((mean - 10.0).abs() < 0.05 && (std_dev - 0.5).abs() < 0.05, "{} {}", mean, std_dev);
+    let standard = elements(&made::<f64>(&path, &with(&["--distribution", "normal"])));
+    let (mean, std_dev) = moments(&standard);
+    assert!(mean.abs() < 0.1 && (std_dev - 1.0).abs() < 0.1, "{} {}", mean, std_dev);
+    assert!(standard.iter().any(|&x| x < 0.0));
+}
+
+#[test]
+fn seeds_make_random_fills_reproducible() {
+    let scratch = Scratch::new("seeds");
+    let path = scratch.path("a.mat");
+    let shape = ["--rows", "40", "--cols", "30", "--force", "--quiet"];
+    let with = |args: &[&'static str]| shape.iter().chain(args).cloned().collect::<Vec<_>>();
+    let first = elements(&made::<f32>(&path, &with(&["--seed", "42"])));
+    assert_eq!(elements(&made::<f32>(&path, &with(&["--seed", "42"]))), first);
+    assert!(elements(&made::<f32>(&path, &with(&["--seed", "43"]))) != first);
+    // The elements are those the library draws from the same seed.
+    let mut expected = Dense::<f32>::create(&scratch.path("b.mat"), 40, 30).unwrap();
+    expected.randomise_seeded(42);
+    assert_eq!(first, elements(&expected));
+    let normal = Distribution::Normal { mean: 1.0, std_dev: 2.0 };
+    expected.randomise_seeded_with(7, normal).unwrap();
+    let drawn = elements(&made::<f32>(&path, &with(&["--seed", "7", "--mean", "1", "--std", "2"])));
+    assert_eq!(drawn, elements(&expected));
+    // Without a seed, every run draws different elements.
+    let unseeded = with(&["--distribution", "uniform"]);
+    assert!(elements(&made::<f32>(&path, &unseeded)) != elements(&made::<f32>(&path, &unseeded)));
+}
+
+#[test]
+fn rejects_inconsistent_fill_options() {
+    let scratch = Scratch::new("fill-arguments");
+    let path = scratch.path("a.mat");
+    let cases: &[(&[&str], &str)] = &[
+        (&["--fill", "twos"], "--fill takes random, zeros, ones, constant=V, identity or index, not 'twos'"),
+        (&["--fill", "constant=x"], "--fill constant takes a number, not 'x'"),
+        (&["--fill", "zeros", "--seed", "1"], "--seed only applies to --fill random, not zeros"),
+        (&["--mean", "1", "--fill", "index"], "--mean only applies to --fill random, not index"),
+        (&["--distribution", "poisson"], "--distribution takes uniform or normal, not 'poisson'"),
+        (&["--range", "1"], "--range takes A,B, not '1'"),
+        (&["--range", "3,1"], "--range needs A no greater than B, not 3,1"),
+        (&["--range", "0,1", "--std", "2"], "--range describes a uniform distribution and can't be given"),
+        (&["--distribution", "normal", "--range", "0,1"], "--range applies to the uniform distribution"),
+        (&["--distribution", "uniform", "--mean", "1"], "--mean and --std apply to the normal distribution"),
+        (&["--std", "-1"], "--std must not be negative, not -1"),
+    ];
+    for &(args, message) in cases {
+        let output = make_matrix_at(&path, args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(stderr(&output).starts_with(&format!("make-matrix: {}", message)), "{:?}: {}", args, stderr(&output));
+        assert!(!path.exists(), "{:?}", args);
+    }
+    // A distribution the library refuses fails after parsing, and leaves no file behind.
+    let output = make_matrix_at(&path, &["--range", "0,inf"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("invalid distribution"), "{}", stderr(&output));
+    assert!(!path.exists());
+}
@@ -0,0 +1,12 @@
+extern crate ooc;
+
+use ooc::dense_matrix::Dense;
+use std::path::Path;
+
+fn main() {
+    let mut a = Dense::<f64>::create(Path::new("a.mat"), 2, 2).unwrap();
+    let first = a.element_iter_mut().next().unwrap();
+    let again = a.element_iter_mut().next().unwrap();
+    *first = 1.0;
+    *again = 2.0;
+}
@@ -0,0 +1,11 @@
+extern crate ooc;
+
+use ooc::dense_matrix::ElementIterMut;
+
+// Were the iterator covariant in its element type, elements borrowed for 'static could be
+// overwritten with references that live only for 'short.
+fn shorten<'a, 'short>(elements: ElementIterMut<'a, &'static u8>) -> ElementIterMut<'a, &'short u8> {
+    elements
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+extern crate ooc;
+
+use ooc::dense_matrix::Dense;
+use std::path::Path;
+
+fn main() {
+    let mut a = Dense::<f64>::create(Path::new("a.mat"), 2, 2).unwrap();
+    let first = a.element_iter_mut();
+    let second = a.element_iter_mut();
+    for (x, y) in first.zip(second) {
+        *x = *y;
+    }
+}